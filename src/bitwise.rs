@@ -0,0 +1,603 @@
+// Word-level evaluation of parts that apply one gate to every bit of their
+// buses, such as Not16 or a generic And<N>. Designs use these chips all
+// over, and simulating them a Nand at a time is slow.
+//
+// A candidate has the ports `IN in[W]` or `IN a[W], b[W]` and `OUT out[W]`.
+// It is flattened to check that bit i of `out` is computed from bit i of
+// the inputs alone, without flip-flops or loops. Each bit is then a gate
+// on one bit of each input, so simulating the PARTS on words whose bits are
+// all equal tries every row of every bit's gate, and the gates must agree.
+// Only chips that pass are evaluated a word at a time. Results are kept per
+// chip and generic arguments, so each chip is checked once.
+//
+// Loops also make many copies of a one-bit part, such as the Xor of each
+// bit in a FOR-generate. Such a part is simulated on every row of its truth
+// table, and copies that do not read each other's outputs are evaluated
+// side by side as one gate on words, one copy per bit.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::builtin::Builtin;
+use crate::busmap::BusMap;
+use crate::expr::GenericValue;
+use crate::logic::XPolicy;
+use crate::netlist::{Cell, Netlist};
+use crate::parser::{ChipHDL, HdlProvider, PortDirection};
+use crate::simulator::{Bus, Chip, ChipOptions, Port, Simulator};
+
+const UNARY: &[&str] = &["in"];
+const BINARY: &[&str] = &["a", "b"];
+
+/// A gate applied to each bit of the inputs. Bit `row` of `table` is the
+/// output for the inputs whose bits, first input highest, spell `row`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Gate {
+    pub inputs: &'static [&'static str],
+    pub table: u8,
+}
+
+impl Gate {
+    fn apply(&self, words: &[u64]) -> u64 {
+        apply(self.table as u64, words)
+    }
+
+    // Rules out constants and gates that ignore one of their inputs.
+    fn uses_all_inputs(&self) -> bool {
+        let n = self.inputs.len();
+        (0..n).all(|i| {
+            let flip = 1 << (n - 1 - i);
+            (0..1 << n).any(|row| self.table >> row & 1 != self.table >> (row ^ flip) & 1)
+        })
+    }
+}
+
+#[derive(Clone)]
+struct Bitwise {
+    gate: Gate,
+    width: usize,
+}
+
+impl Builtin for Bitwise {
+    fn is_bitwise(&self) -> bool {
+        true
+    }
+
+    fn eval(&mut self, signals: &mut BusMap) {
+        let mut words = Vec::new();
+        let mut known = u64::MAX;
+        for name in self.gate.inputs {
+            let (w, k) = pack(&signals.get_name(name));
+            words.push(w);
+            known &= k;
+        }
+        let out = unpack(self.gate.apply(&words), known, self.width);
+        signals.insert_option(&Bus::from("out"), out);
+    }
+}
+
+// The output of a gate with truth table `table` on each bit of `words`, as
+// the OR of the minterms of the rows that are set.
+fn apply(table: u64, words: &[u64]) -> u64 {
+    let n = words.len();
+    (0..1 << n)
+        .filter(|row| table >> row & 1 == 1)
+        .map(|row| {
+            words.iter().enumerate().fold(u64::MAX, |acc, (i, &w)| {
+                acc & if row >> (n - 1 - i) & 1 == 1 { w } else { !w }
+            })
+        })
+        .fold(0, |acc, minterm| acc | minterm)
+}
+
+// Bits come first bit highest. Undefined bits are clear in the second word.
+fn pack(bits: &[Option<bool>]) -> (u64, u64) {
+    bits.iter().fold((0, 0), |(value, known), b| {
+        (
+            value << 1 | b.unwrap_or(false) as u64,
+            known << 1 | b.is_some() as u64,
+        )
+    })
+}
+
+fn unpack(value: u64, known: u64, width: usize) -> Vec<Option<bool>> {
+    (0..width)
+        .rev()
+        .map(|i| (known >> i & 1 == 1).then_some(value >> i & 1 == 1))
+        .collect()
+}
+
+fn ones(width: usize) -> u64 {
+    if width >= 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    }
+}
+
+type Key = (Option<PathBuf>, String, Vec<GenericValue>);
+
+thread_local! {
+    static GATES: RefCell<HashMap<Key, Option<Gate>>> = RefCell::new(HashMap::new());
+    static LANE_GATES: RefCell<HashMap<Key, Option<LaneGate>>> = RefCell::new(HashMap::new());
+}
+
+/// Copies of a part evaluated side by side fit in a word.
+pub const MAX_LANES: usize = 64;
+
+// The truth table of a part with more inputs does not fit in a word.
+const MAX_LANE_INPUTS: usize = 6;
+
+/// A chip with one-bit ports and a single output as a gate on its inputs.
+/// Bit `row` of `table` is the output for the inputs whose bits, in the
+/// order the inputs are declared and first input highest, spell `row`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LaneGate {
+    pub inputs: Vec<String>,
+    pub output: String,
+    pub table: u64,
+}
+
+#[derive(Clone)]
+struct Lanes {
+    gate: LaneGate,
+    lanes: usize,
+}
+
+impl Builtin for Lanes {
+    fn is_bitwise(&self) -> bool {
+        true
+    }
+
+    fn eval(&mut self, signals: &mut BusMap) {
+        let mut words = Vec::new();
+        let mut known = u64::MAX;
+        for name in &self.gate.inputs {
+            let (w, k) = pack(&signals.get_name(name));
+            words.push(w);
+            known &= k;
+        }
+        let out = unpack(apply(self.gate.table, &words), known, self.lanes);
+        signals.insert_option(&Bus::from(self.gate.output.as_str()), out);
+    }
+}
+
+/// Evaluates `lanes` copies of a gate at once. Every port of the copies is
+/// `lanes` bits wide, and bit `k` of each port belongs to copy `k`.
+pub fn lanes(gate: LaneGate, lanes: usize) -> Box<dyn Builtin> {
+    Box::new(Lanes { gate, lanes })
+}
+
+// Words make a bit unknown when any input bit is, so they only give what
+// the gates would when unknown bits spread that way.
+fn words_follow_policy(options: &ChipOptions) -> bool {
+    options.x_policy == XPolicy::Pessimistic
+}
+
+/// The gate that the chip applies to its one-bit inputs, if it has a single
+/// one-bit output and no state.
+pub fn lane_gate(
+    hdl: &ChipHDL,
+    provider: &Arc<dyn HdlProvider>,
+    generics: &[GenericValue],
+    options: &ChipOptions,
+) -> Option<LaneGate> {
+    if !words_follow_policy(options) {
+        return None;
+    }
+    let key = (hdl.path.clone(), hdl.name.clone(), generics.to_vec());
+    if let Some(gate) = LANE_GATES.with(|g| g.borrow().get(&key).cloned()) {
+        return gate;
+    }
+    let gate = find_lane_gate(hdl, provider, generics, options);
+    LANE_GATES.with(|g| g.borrow_mut().insert(key, gate.clone()));
+    gate
+}
+
+// Simulates the chip on every row of its truth table. Chips whose outputs
+// are undefined for some row, or that latch anything into a flip-flop or
+// sequential builtin on the way, are not gates.
+fn find_lane_gate(
+    hdl: &ChipHDL,
+    provider: &Arc<dyn HdlProvider>,
+    generics: &[GenericValue],
+    options: &ChipOptions,
+) -> Option<LaneGate> {
+    if !hdl.clocked.is_empty() {
+        return None;
+    }
+    let chip = probe(hdl, provider, generics, options)?;
+    if chip.ports.values().any(|p| p.width != 1) {
+        return None;
+    }
+    let names = |direction: PortDirection| -> Vec<String> {
+        hdl.ports
+            .iter()
+            .filter(|p| p.direction == direction)
+            .map(|p| p.name.value.clone())
+            .collect()
+    };
+    let (inputs, outputs) = (names(PortDirection::In), names(PortDirection::Out));
+    let [output] = &outputs[..] else {
+        return None;
+    };
+    if inputs.is_empty() || inputs.len() > MAX_LANE_INPUTS {
+        return None;
+    }
+
+    let mut simulator = Simulator::new(chip);
+    let n = inputs.len();
+    let mut table = 0;
+    for row in 0..1 << n {
+        let mut signals = BusMap::new();
+        for (i, name) in inputs.iter().enumerate() {
+            signals.create_bus(name, 1).ok()?;
+            signals.insert_option(
+                &Bus::from(name.as_str()),
+                vec![Some(row >> (n - 1 - i) & 1 == 1)],
+            );
+        }
+        let outputs = simulator.simulate(&signals).ok()?;
+        if outputs.get_name(output).first().copied().flatten()? {
+            table |= 1 << row;
+        }
+    }
+    if !simulator.dirty_dffs.is_empty() {
+        return None;
+    }
+    Some(LaneGate {
+        inputs,
+        output: output.clone(),
+        table,
+    })
+}
+
+/// A word-level implementation of the chip, if it applies one gate to
+/// every bit of its buses.
+pub fn recognize(
+    hdl: &ChipHDL,
+    provider: &Arc<dyn HdlProvider>,
+    generics: &[GenericValue],
+    ports: &HashMap<String, Port>,
+    options: &ChipOptions,
+) -> Option<Box<dyn Builtin>> {
+    if !words_follow_policy(options) {
+        return None;
+    }
+    let (inputs, width) = shape(ports)?;
+    let key = (hdl.path.clone(), hdl.name.clone(), generics.to_vec());
+    let gate = match GATES.with(|g| g.borrow().get(&key).copied()) {
+        Some(gate) => gate,
+        None => {
+            let gate = find_gate(hdl, provider, generics, inputs, width, options);
+            GATES.with(|g| g.borrow_mut().insert(key, gate));
+            gate
+        }
+    }?;
+    Some(Box::new(Bitwise { gate, width }))
+}
+
+// The inputs and width of a chip whose ports fit a gate on words. A single
+// bit is not worth the check.
+fn shape(ports: &HashMap<String, Port>) -> Option<(&'static [&'static str], usize)> {
+    let out = ports
+        .get("out")
+        .filter(|p| p.direction == PortDirection::Out)?;
+    let inputs = match ports.len() {
+        2 => UNARY,
+        3 => BINARY,
+        _ => return None,
+    };
+    let fits = inputs.iter().all(|name| {
+        ports
+            .get(*name)
+            .is_some_and(|p| p.direction == PortDirection::In && p.width == out.width)
+    });
+    (fits && (2..=64).contains(&out.width)).then_some((inputs, out.width))
+}
+
+// The chip as the part would build it, left unelaborated until simulated.
+fn probe(
+    hdl: &ChipHDL,
+    provider: &Arc<dyn HdlProvider>,
+    generics: &[GenericValue],
+    options: &ChipOptions,
+) -> Option<Chip> {
+    let options = ChipOptions {
+        elaborate: false,
+        ..options.clone()
+    };
+    Chip::with_generics(hdl, provider, generics, &options).ok()
+}
+
+// Simulates the chip's PARTS on uniform words to find its gate, once the
+// netlist shows that every bit of `out` follows a gate of its own.
+fn find_gate(
+    hdl: &ChipHDL,
+    provider: &Arc<dyn HdlProvider>,
+    generics: &[GenericValue],
+    inputs: &'static [&'static str],
+    width: usize,
+    options: &ChipOptions,
+) -> Option<Gate> {
+    let chip = probe(hdl, provider, generics, options)?;
+    if !bits_apart(&chip.flatten().ok()?, inputs) {
+        return None;
+    }
+    let mut simulator = Simulator::new(chip);
+    let ones = ones(width);
+    let mut run = |words: &[u64]| -> Option<u64> {
+        let mut signals = BusMap::new();
+        for (name, &w) in inputs.iter().zip(words) {
+            signals.create_bus(name, width).ok()?;
+            signals.insert_option(&Bus::from(*name), unpack(w, u64::MAX, width));
+        }
+        let outputs = simulator.simulate(&signals).ok()?;
+        let (value, known) = pack(&outputs.get_name("out"));
+        (known == ones).then_some(value)
+    };
+
+    let n = inputs.len();
+    let mut table = 0;
+    for row in 0..1 << n {
+        let words: Vec<u64> = (0..n)
+            .map(|i| if row >> (n - 1 - i) & 1 == 1 { ones } else { 0 })
+            .collect();
+        match run(&words)? {
+            0 => {}
+            out if out == ones => table |= 1 << row,
+            _ => return None,
+        }
+    }
+    let gate = Gate { inputs, table };
+    gate.uses_all_inputs().then_some(gate)
+}
+
+// Whether the cone of each bit of `out`, followed back through the cells
+// that drive it, reads only the same bit of the inputs and constants. A
+// bitwise builtin is followed bit by bit, any other cell through all of
+// its inputs. Flip-flops, sequential builtins and loops could make a bit
+// depend on more than its row, so they rule the chip out.
+fn bits_apart(netlist: &Netlist, inputs: &[&str]) -> bool {
+    let stateful = netlist.cells.iter().any(|cell| match cell {
+        Cell::Dff { .. } => true,
+        Cell::Builtin { builtin, .. } => builtin.is_sequential(),
+        Cell::Nand { .. } => false,
+    });
+    if stateful || netlist.levels().iter().any(|l| netlist.looped(l)) {
+        return false;
+    }
+    let mut driver = vec![None; netlist.nets()];
+    for (i, cell) in netlist.cells.iter().enumerate() {
+        for net in cell.drives() {
+            driver[net] = Some(i);
+        }
+    }
+    let port = |name: &str| {
+        netlist
+            .inputs
+            .iter()
+            .chain(&netlist.outputs)
+            .find(|(n, _)| n == name)
+            .map(|(_, nets)| nets)
+    };
+    let Some(out) = port("out") else {
+        return false;
+    };
+    let Some(input_nets) = inputs
+        .iter()
+        .map(|name| port(name))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return false;
+    };
+
+    out.iter().enumerate().all(|(bit, &net)| {
+        let own: Vec<usize> = input_nets.iter().map(|nets| nets[bit]).collect();
+        let mut seen = vec![false; netlist.nets()];
+        let mut stack = vec![net];
+        while let Some(net) = stack.pop() {
+            if std::mem::replace(&mut seen[net], true) {
+                continue;
+            }
+            let Some(cell) = driver[net] else {
+                // Only an input or a constant drives itself.
+                if own.contains(&net) || netlist.values[net].is_some() {
+                    continue;
+                }
+                return false;
+            };
+            match &netlist.cells[cell] {
+                Cell::Builtin { builtin, pins, .. } if builtin.is_bitwise() => {
+                    let k = pins
+                        .iter()
+                        .filter(|p| p.direction == PortDirection::Out)
+                        .find_map(|p| p.nets.iter().position(|&n| n == net));
+                    let Some(k) = k else {
+                        return false;
+                    };
+                    for pin in pins.iter().filter(|p| p.direction == PortDirection::In) {
+                        stack.extend(pin.nets.get(k));
+                    }
+                }
+                cell => stack.extend(cell.reads()),
+            }
+        }
+        true
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::{FileReader, Parser};
+    use crate::scanner::Scanner;
+    use std::fs;
+
+    fn recognize_in(dir: &std::path::Path, name: &str, generics: &[usize]) -> Option<Gate> {
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(dir.to_str().unwrap()));
+        let file = format!("{}.hdl", name);
+        let source = provider.get_hdl(&file).unwrap();
+        let mut scanner = Scanner::new(&source, provider.get_path(&file));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let generics: Vec<GenericValue> = generics.iter().map(|&g| g.into()).collect();
+        let chip = Chip::with_generics(&hdl, &provider, &generics, &ChipOptions::default())
+            .expect("Chip creation error");
+        let (inputs, width) = shape(&chip.ports)?;
+        find_gate(
+            &hdl,
+            &provider,
+            &generics,
+            inputs,
+            width,
+            &ChipOptions::default(),
+        )
+    }
+
+    #[test]
+    fn test_recognize() {
+        let dir = tempfile::tempdir().unwrap();
+        let solutions = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/resources/tests/nand2tetris/solutions"
+        );
+        for entry in fs::read_dir(solutions).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "hdl") {
+                fs::copy(&path, dir.path().join(path.file_name().unwrap())).unwrap();
+            }
+        }
+        fs::write(
+            dir.path().join("XorN.hdl"),
+            "CHIP XorN<N> {
+                IN a[N], b[N];
+                OUT out[N];
+                PARTS:
+                FOR i IN 0 TO N - 1 GENERATE {
+                    Xor(a=a[i], b=b[i], out=out[i]);
+                }
+            }",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("Or16.hdl"),
+            "CHIP Or16 {
+                IN a[16], b[16];
+                OUT out[16];
+                PARTS:
+                FOR i IN 0 TO 15 GENERATE {
+                    Or(a=a[i], b=b[i], out=out[i]);
+                }
+            }",
+        )
+        .unwrap();
+        // Bits 0 and 1 are swapped, so no bit follows a gate on its own.
+        fs::write(
+            dir.path().join("Swap.hdl"),
+            "CHIP Swap {
+                IN in[2];
+                OUT out[2];
+                PARTS:
+                Not(in=in[1], out=out[0]);
+                Not(in=in[0], out=out[1]);
+            }",
+        )
+        .unwrap();
+
+        // Bit 15 is Not of in[15] except on the one word in 2^15 whose other
+        // bits spell 0x7FFC, which simulating a few words would not meet.
+        fs::write(
+            dir.path().join("Rare.hdl"),
+            "CHIP Rare {
+                IN in[16];
+                OUT out[16];
+                PARTS:
+                Not16(in=in, out=n, out[0..14]=out[0..14]);
+                Or8Way(in[0]=in[0], in[1]=in[1], in[2..7]=n[2..7], out=low);
+                Or8Way(in=n[7..14], out=high);
+                Or(a=low, b=high, out=other);
+                And(a=n[15], b=other, out=out[15]);
+            }",
+        )
+        .unwrap();
+
+        let gate = |name, generics: &[usize]| recognize_in(dir.path(), name, generics);
+        assert_eq!(gate("Not16", &[]).map(|g| g.table), Some(0b01));
+        assert_eq!(gate("And16", &[]).map(|g| g.table), Some(0b1000));
+        assert_eq!(gate("Or16", &[]).map(|g| g.table), Some(0b1110));
+        assert_eq!(gate("XorN", &[64]).map(|g| g.table), Some(0b0110));
+        assert_eq!(gate("XorN", &[3]).map(|g| g.inputs), Some(BINARY));
+        assert_eq!(gate("Swap", &[]), None);
+        assert_eq!(gate("Rare", &[]), None);
+        assert_eq!(gate("Mux16", &[]), None);
+        assert_eq!(gate("Add16", &[]), None);
+        assert_eq!(gate("Not", &[]), None);
+    }
+
+    #[test]
+    fn test_lane_gate() {
+        let solutions = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/resources/tests/nand2tetris/solutions"
+        );
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(solutions));
+        let gate = |name: &str| {
+            let hdl = crate::parser::get_hdl(name, &provider).unwrap();
+            lane_gate(&hdl, &provider, &[], &ChipOptions::default())
+        };
+        let mux = gate("Mux").unwrap();
+        assert_eq!(mux.inputs, vec!["a", "b", "sel"]);
+        assert_eq!(mux.output, "out");
+        // Rows are a b sel, so out is b for the odd rows and a otherwise.
+        assert_eq!(mux.table, 0b1101_1000);
+        assert_eq!(gate("Nand").map(|g| g.table), Some(0b0111));
+        // Bit holds a value, DMux has two outputs and Not16 is a word wide.
+        assert_eq!(gate("Bit"), None);
+        assert_eq!(gate("DMux"), None);
+        assert_eq!(gate("Not16"), None);
+
+        let mut signals = BusMap::new();
+        for name in ["a", "b", "sel", "out"] {
+            signals.create_bus(name, 3).unwrap();
+        }
+        signals.insert_option(&Bus::from("a"), vec![Some(true), Some(false), Some(true)]);
+        signals.insert_option(&Bus::from("b"), vec![Some(false), Some(true), None]);
+        signals.insert_option(&Bus::from("sel"), vec![Some(true), Some(true), Some(false)]);
+        let mut muxes = lanes(mux, 3);
+        muxes.eval(&mut signals);
+        assert_eq!(signals.get_name("out"), vec![Some(false), Some(true), None]);
+    }
+
+    #[test]
+    fn test_undefined_bits() {
+        let mut signals = BusMap::new();
+        signals.create_bus("a", 4).unwrap();
+        signals.create_bus("b", 4).unwrap();
+        signals.create_bus("out", 4).unwrap();
+        signals.insert_option(
+            &Bus::from("a"),
+            vec![Some(true), Some(true), None, Some(false)],
+        );
+        signals.insert_option(
+            &Bus::from("b"),
+            vec![Some(true), Some(false), Some(true), Some(true)],
+        );
+        let mut and = Bitwise {
+            gate: Gate {
+                inputs: BINARY,
+                table: 0b1000,
+            },
+            width: 4,
+        };
+        and.eval(&mut signals);
+        assert_eq!(
+            signals.get_name("out"),
+            vec![Some(true), Some(false), None, Some(false)]
+        );
+    }
+}
@@ -0,0 +1,425 @@
+// Builders for constructing parse trees in Rust code. Code generators that
+// target whidl can build a ChipHDL directly instead of printing HDL text
+// and parsing it again. `ChipBuilder::build` applies the same checks as the
+// parser, plus checks that every generic variable in use is declared.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::error::{ErrorKind, N2VError};
+use crate::expr::{eval_expr_numeric, GenericValue, GenericWidth, Terminal};
+use crate::parser::*;
+use crate::scanner::{Scanner, TokenType};
+
+/// A generic variable or loop iterator for use in widths and indices.
+pub fn var(name: &str) -> GenericWidth {
+    GenericWidth::Terminal(Terminal::Var(Identifier::from(name)))
+}
+
+/// A whole bus or single-bit wire, e.g. `a`.
+pub fn bus(name: &str) -> BusHDL {
+    BusHDL {
+        name: String::from(name),
+        start: None,
+        end: None,
+        descending: false,
+        stride: None,
+    }
+}
+
+impl BusHDL {
+    /// Selects a single bit, e.g. `a[i]`.
+    pub fn bit(self, idx: impl Into<GenericWidth>) -> BusHDL {
+        let idx = idx.into();
+        self.range(idx.clone(), idx)
+    }
+
+    /// Selects a range of bits, e.g. `a[0..7]`. As in HDL, a range with
+    /// constant bounds written high to low is descending.
+    pub fn range(self, start: impl Into<GenericWidth>, end: impl Into<GenericWidth>) -> BusHDL {
+        let (start, end) = (start.into(), end.into());
+        let constants = HashMap::new();
+        let descending = matches!(
+            (eval_expr_numeric(&start, &constants), eval_expr_numeric(&end, &constants)),
+            (Ok(s), Ok(e)) if s > e
+        );
+        let (start, end) = if descending {
+            (end, start)
+        } else {
+            (start, end)
+        };
+        BusHDL {
+            start: Some(start),
+            end: Some(end),
+            descending,
+            ..self
+        }
+    }
+
+    /// Selects every k-th bit of a range, e.g. `a[0..14:2]`.
+    pub fn step(self, k: usize) -> BusHDL {
+        BusHDL {
+            stride: Some(k),
+            ..self
+        }
+    }
+}
+
+/// Builds a component such as `Mux16<W>(a=a, b=b, sel=sel, out=out)`.
+pub struct ComponentBuilder {
+    name: String,
+    generic_params: Vec<GenericParam>,
+    mappings: Vec<PortMapping>,
+}
+
+impl ComponentBuilder {
+    pub fn new(name: &str) -> ComponentBuilder {
+        ComponentBuilder {
+            name: String::from(name),
+            generic_params: Vec::new(),
+            mappings: Vec::new(),
+        }
+    }
+
+    /// Passes a generic argument to the component.
+    pub fn generic(mut self, width: impl Into<GenericWidth>) -> ComponentBuilder {
+        self.generic_params.push(GenericParam {
+            name: None,
+            value: GenericValue::Width(width.into()),
+        });
+        self
+    }
+
+    /// Connects a port of the component to a wire, e.g. `map(bus("in"), bus("a").bit(0))`.
+    pub fn map(mut self, port: BusHDL, wire: BusHDL) -> ComponentBuilder {
+        self.mappings.push(PortMapping {
+            wire_ident: Identifier::from(port.name.as_str()),
+            wire,
+            port,
+            comments: Comments::default(),
+        });
+        self
+    }
+
+    pub fn build(self) -> Component {
+        Component {
+            name: Identifier::from(self.name.as_str()),
+            namespace: None,
+            mappings: self.mappings,
+            generic_params: self.generic_params,
+            annotations: Vec::new(),
+            comments: Comments::default(),
+            expression: None,
+        }
+    }
+}
+
+/// Builds a chip definition.
+pub struct ChipBuilder {
+    name: String,
+    ports: Vec<GenericPort>,
+    parts: Vec<Part>,
+    generic_decls: Vec<Identifier>,
+    clocked: Vec<Identifier>,
+    builtin: Option<Identifier>,
+    path: Option<PathBuf>,
+}
+
+impl ChipBuilder {
+    pub fn new(name: &str) -> ChipBuilder {
+        ChipBuilder {
+            name: String::from(name),
+            ports: Vec::new(),
+            parts: Vec::new(),
+            generic_decls: Vec::new(),
+            clocked: Vec::new(),
+            builtin: None,
+            path: None,
+        }
+    }
+
+    /// Declares a generic variable, e.g. `W` in `CHIP Mux<W>`.
+    pub fn generic(mut self, name: &str) -> ChipBuilder {
+        self.generic_decls.push(Identifier::from(name));
+        self
+    }
+
+    pub fn input(self, name: &str, width: impl Into<GenericWidth>) -> ChipBuilder {
+        self.port(name, width.into(), PortDirection::In)
+    }
+
+    pub fn output(self, name: &str, width: impl Into<GenericWidth>) -> ChipBuilder {
+        self.port(name, width.into(), PortDirection::Out)
+    }
+
+    fn port(mut self, name: &str, width: GenericWidth, direction: PortDirection) -> ChipBuilder {
+        self.ports.push(GenericPort {
+            name: Identifier::from(name),
+            width,
+            direction,
+        });
+        self
+    }
+
+    /// Marks an input as clocked, like `CLOCKED in;`.
+    pub fn clocked(mut self, name: &str) -> ChipBuilder {
+        self.clocked.push(Identifier::from(name));
+        self
+    }
+
+    /// Uses a native implementation, like `BUILTIN Name;`.
+    pub fn builtin(mut self, name: &str) -> ChipBuilder {
+        self.builtin = Some(Identifier::from(name));
+        self
+    }
+
+    /// Path reported in errors about the chip.
+    pub fn path(mut self, path: PathBuf) -> ChipBuilder {
+        self.path = Some(path);
+        self
+    }
+
+    pub fn component(mut self, component: ComponentBuilder) -> ChipBuilder {
+        self.parts.push(Part::Component(component.build()));
+        self
+    }
+
+    /// Adds `FOR iterator IN start TO end GENERATE { body }`.
+    pub fn for_loop(
+        mut self,
+        iterator: &str,
+        start: impl Into<GenericWidth>,
+        end: impl Into<GenericWidth>,
+        body: Vec<ComponentBuilder>,
+    ) -> ChipBuilder {
+        self.parts.push(Part::Loop(Loop {
+            start: start.into(),
+            end: end.into(),
+            iterator: Identifier::from(iterator),
+            body: body.into_iter().map(|c| c.build()).collect(),
+            comments: Comments::default(),
+            array: false,
+        }));
+        self
+    }
+
+    pub fn build(self) -> Result<ChipHDL, Box<dyn Error>> {
+        let build_error = |msg: String| -> Box<dyn Error> {
+            Box::new(N2VError {
+                msg: format!("Chip {}: {}", self.name, msg),
+                kind: ErrorKind::ElaborationError,
+            })
+        };
+
+        let mut names: Vec<&str> = vec![&self.name];
+        names.extend(self.generic_decls.iter().map(|g| g.value.as_str()));
+        names.extend(self.ports.iter().map(|p| p.name.value.as_str()));
+        if let Some(b) = &self.builtin {
+            names.push(&b.value);
+        }
+        for n in names {
+            if !is_identifier(n) {
+                return Err(build_error(format!("`{}` is not a valid name.", n)));
+            }
+        }
+        for (i, p) in self.ports.iter().enumerate() {
+            if self.ports[..i].iter().any(|q| q.name.value == p.name.value) {
+                return Err(build_error(format!(
+                    "Port {} is declared twice.",
+                    p.name.value
+                )));
+            }
+        }
+        for c in &self.clocked {
+            if !self.ports.iter().any(|p| p.name.value == c.value) {
+                return Err(build_error(format!(
+                    "CLOCKED pin `{}` is not a port of this chip.",
+                    c.value
+                )));
+            }
+        }
+        if self.parts.is_empty() && self.builtin.is_none() {
+            return Err(build_error(String::from(
+                "A chip needs parts unless it is BUILTIN.",
+            )));
+        }
+
+        let declared: Vec<&str> = self
+            .generic_decls
+            .iter()
+            .map(|g| g.value.as_str())
+            .collect();
+        let check_vars = |w: &GenericWidth, iterator: Option<&str>| match w
+            .variables()
+            .iter()
+            .find(|v| !declared.contains(&v.as_str()) && Some(v.as_str()) != iterator)
+        {
+            Some(v) => Err(build_error(format!(
+                "Generic variable {} is not declared.",
+                v
+            ))),
+            None => Ok(()),
+        };
+        let check_component = |c: &Component, iterator: Option<&str>| {
+            if !is_identifier(&c.name.value) {
+                return Err(build_error(format!(
+                    "`{}` is not a valid chip name.",
+                    c.name.value
+                )));
+            }
+            for w in c.generic_params.iter().filter_map(|g| g.value.width()) {
+                check_vars(w, iterator)?;
+            }
+            for m in &c.mappings {
+                for b in [&m.port, &m.wire] {
+                    if !is_identifier(&b.name) {
+                        return Err(build_error(format!(
+                            "`{}` is not a valid name in {}.",
+                            b.name, c.name.value
+                        )));
+                    }
+                    for w in b.start.iter().chain(b.end.iter()) {
+                        check_vars(w, iterator)?;
+                    }
+                }
+            }
+            Ok(())
+        };
+
+        for p in &self.ports {
+            check_vars(&p.width, None)?;
+        }
+        for part in &self.parts {
+            match part {
+                Part::Component(c) => check_component(c, None)?,
+                Part::Loop(l) => {
+                    if !is_identifier(&l.iterator.value) {
+                        return Err(build_error(format!(
+                            "`{}` is not a valid loop iterator.",
+                            l.iterator.value
+                        )));
+                    }
+                    check_vars(&l.start, None)?;
+                    check_vars(&l.end, None)?;
+                    for c in &l.body {
+                        check_component(c, Some(&l.iterator.value))?;
+                    }
+                }
+            }
+        }
+
+        Ok(ChipHDL {
+            name: self.name,
+            interface: None,
+            imports: Vec::new(),
+            constants: Vec::new(),
+            ports: self.ports,
+            parts: self.parts,
+            path: self.path,
+            generic_decls: self.generic_decls,
+            clocked: self.clocked,
+            builtin: self.builtin,
+            stimulus: None,
+            table: None,
+            annotations: Vec::new(),
+            comments: Comments::default(),
+            body_comments: Vec::new(),
+            shadows: None,
+        })
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut scanner = Scanner::new(name, PathBuf::new());
+    match (scanner.next(), scanner.next()) {
+        (Some(Ok(t)), None) => t.token_type == TokenType::Identifier && t.lexeme == name,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::busmap::BusMap;
+    use crate::simulator::{Bus, Chip, ChipOptions, Simulator};
+    use std::env;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_build_and_simulate() {
+        // CHIP Not4 { IN in[4]; OUT out[4]; PARTS:
+        //   FOR i IN 0 TO 3 GENERATE { Not(in=in[i], out=out[i]); } }
+        let hdl = ChipBuilder::new("Not4")
+            .input("in", 4)
+            .output("out", 4)
+            .for_loop(
+                "i",
+                0,
+                3,
+                vec![ComponentBuilder::new("Not")
+                    .map(bus("in"), bus("in").bit(var("i")))
+                    .map(bus("out"), bus("out").bit(var("i")))],
+            )
+            .build()
+            .expect("Build error");
+
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+        let mut simulator = Simulator::new(chip);
+        let outputs = simulator
+            .simulate(&BusMap::try_from([("in", vec![true, false, true, true])]).unwrap())
+            .expect("Simulation error");
+        assert_eq!(
+            outputs.get_bus(&Bus::from("out")),
+            vec![Some(false), Some(true), Some(false), Some(false)]
+        );
+    }
+
+    #[test]
+    fn test_descending_range() {
+        let b = bus("a").range(7, 0);
+        assert!(b.descending);
+        assert_eq!(b.to_string(), "a[7..0]");
+        assert_eq!(
+            bus("a").range(0, var("W") - 1.into()).to_string(),
+            "a[0..W-1]"
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        let undeclared = ChipBuilder::new("Wide")
+            .input("in", var("W"))
+            .output("out", var("W"))
+            .component(ComponentBuilder::new("Not").map(bus("in"), bus("in").bit(0)))
+            .build();
+        assert!(undeclared
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("Generic variable W is not declared"));
+
+        let duplicate = ChipBuilder::new("Twice")
+            .input("a", 1)
+            .input("a", 1)
+            .builtin("Twice")
+            .build();
+        assert!(duplicate.is_err());
+
+        assert!(ChipBuilder::new("Bad Name").builtin("Bit").build().is_err());
+        assert!(ChipBuilder::new("Empty").input("a", 1).build().is_err());
+        assert!(ChipBuilder::new("Reg")
+            .input("in", 1)
+            .clocked("load")
+            .builtin("Bit")
+            .build()
+            .is_err());
+    }
+}
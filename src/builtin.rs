@@ -0,0 +1,1223 @@
+// Native implementations for chips declared with `BUILTIN Name;`.
+// A builtin replaces the structural PARTS list of a chip with Rust code,
+// which is much faster for large chips like RAM16K. Ports are still taken
+// from the HDL so the builtin only needs to read and write signals by name.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::busmap::BusMap;
+use crate::error::{ErrorKind, N2VError};
+use crate::expr::{GenericWidth, Terminal};
+use crate::logic::Logic;
+use crate::parser::{find_annotation, get_hdl, ChipHDL, HdlProvider, PortDirection, Table};
+use crate::simulator::{Bus, Chip, ChipOptions, Simulator};
+
+/// A native implementation of a chip. Builtins are cloned along with the
+/// chips that use them, so each copy of a chip has its own state.
+pub trait Builtin: BuiltinClone + Send + Sync {
+    /// Computes output signals from the current input signals.
+    /// `signals` contains both the input and output ports of the chip.
+    fn eval(&mut self, signals: &mut BusMap);
+
+    /// Advances the clock. Only sequential builtins need to implement this.
+    fn tick(&mut self, _signals: &BusMap) {}
+
+    /// Sequential builtins hold state, so their outputs cannot be cached.
+    fn is_sequential(&self) -> bool {
+        false
+    }
+
+    /// Whether bit `k` of every output follows only bit `k` of the inputs,
+    /// as for a gate applied to each bit of a word.
+    fn is_bitwise(&self) -> bool {
+        false
+    }
+
+    /// Values held by a sequential builtin, used to tell states apart.
+    fn state(&self) -> Vec<u64> {
+        Vec::new()
+    }
+
+    /// Puts back values returned by `state`, e.g. from a snapshot.
+    fn set_state(&mut self, _state: &[u64]) {}
+
+    /// Simulators of the chips the builtin runs itself, which only `Memory`
+    /// has. Snapshots save and restore them along with the builtin.
+    fn devices(&self) -> Vec<&Simulator> {
+        Vec::new()
+    }
+
+    fn devices_mut(&mut self) -> Vec<&mut Simulator> {
+        Vec::new()
+    }
+
+    /// Protocol violations found since the last call. Only monitors find any.
+    fn take_violations(&mut self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Sets the key held down. Returns whether the builtin reads the
+    /// keyboard, which only `Keyboard` does.
+    fn set_key(&mut self, _key: u16) -> bool {
+        false
+    }
+
+    /// Memory shown on the display, which only `Screen` has.
+    fn screen(&self) -> Option<&[u64]> {
+        None
+    }
+}
+
+/// Copies a builtin behind a `Box`. Implemented for every builtin that is
+/// `Clone`.
+pub trait BuiltinClone {
+    fn clone_box(&self) -> Box<dyn Builtin>;
+}
+
+impl<T: Builtin + Clone + 'static> BuiltinClone for T {
+    fn clone_box(&self) -> Box<dyn Builtin> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Builtin> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// Returns the native implementation registered for a builtin name, or
+/// None if the chip must fall back to its PARTS.
+pub fn get_builtin(name: &str) -> Option<Box<dyn Builtin>> {
+    match name {
+        "Add16" => Some(Box::new(Add16 {})),
+        "Inc16" => Some(Box::new(Inc16 {})),
+        "ALU" => Some(Box::new(Alu {})),
+        "Bit" => Some(Box::new(Register::new())),
+        "Register" => Some(Box::new(Register::new())),
+        "PC" => Some(Box::new(Pc::new())),
+        "RAM8" => Some(Box::new(Ram::new(8))),
+        "RAM64" => Some(Box::new(Ram::new(64))),
+        "RAM512" => Some(Box::new(Ram::new(512))),
+        "RAM4K" => Some(Box::new(Ram::new(4096))),
+        "RAM16K" => Some(Box::new(Ram::new(16384))),
+        "TriState" => Some(Box::new(TriState {})),
+        "HandshakeMonitor" => Some(Box::new(HandshakeMonitor::default())),
+        "MemoryMonitor" => Some(Box::new(MemoryMonitor::default())),
+        "Keyboard" => Some(Box::new(Keyboard::default())),
+        "Screen" => Some(Box::new(Screen {
+            ram: Ram::new(SCREEN_HEIGHT * SCREEN_WIDTH / 16),
+        })),
+        _ => None,
+    }
+}
+
+/// HDL for the monitor and device chips that ship with whidl. These are
+/// found by name when a chip's directory does not define a chip of the same
+/// name.
+pub fn library_hdl(name: &str) -> Option<&'static str> {
+    match name {
+        "HandshakeMonitor" => Some(include_str!("../resources/monitors/HandshakeMonitor.hdl")),
+        "MemoryMonitor" => Some(include_str!("../resources/monitors/MemoryMonitor.hdl")),
+        "Keyboard" => Some(include_str!("../resources/devices/Keyboard.hdl")),
+        "Screen" => Some(include_str!("../resources/devices/Screen.hdl")),
+        "Memory" => Some(include_str!("../resources/devices/Memory.hdl")),
+        "TriState" => Some(include_str!("../resources/primitives/TriState.hdl")),
+        _ => None,
+    }
+}
+
+/// Names of the chips in the library.
+pub const LIBRARY_CHIPS: [&str; 6] = [
+    "HandshakeMonitor",
+    "MemoryMonitor",
+    "Keyboard",
+    "Screen",
+    "Memory",
+    "TriState",
+];
+
+/// Size of the Hack screen in pixels.
+pub const SCREEN_WIDTH: usize = 512;
+pub const SCREEN_HEIGHT: usize = 256;
+
+/// Addresses of the devices in the Hack memory map. Peripherals go between
+/// the keyboard and the end of the 15 bit address space.
+pub const SCREEN_ADDRESS: u64 = 16384;
+pub const KEYBOARD_ADDRESS: u64 = 24576;
+const MEMORY_SIZE: u64 = 1 << 15;
+
+/// Whether the pixel in column `x` and row `y` of the screen memory is on.
+pub fn pixel(screen: &[u64], x: usize, y: usize) -> bool {
+    (screen[y * SCREEN_WIDTH / 16 + x / 16] >> (x % 16)) & 1 == 1
+}
+
+/// The nand2tetris code of a key named in a script, e.g. `newline` or `f1`.
+/// Printable characters are their ASCII codes and are not named.
+pub fn key_code(name: &str) -> Option<u16> {
+    let code = match name {
+        "newline" => 128,
+        "backspace" => 129,
+        "left" => 130,
+        "up" => 131,
+        "right" => 132,
+        "down" => 133,
+        "home" => 134,
+        "end" => 135,
+        "pageup" => 136,
+        "pagedown" => 137,
+        "insert" => 138,
+        "delete" => 139,
+        "esc" => 140,
+        _ => match name.strip_prefix('f').and_then(|n| n.parse::<u16>().ok()) {
+            Some(n @ 1..=12) => 140 + n,
+            _ => return None,
+        },
+    };
+    Some(code)
+}
+
+// Reads a bus as an unsigned number. None if any bit is undefined.
+fn get_num(signals: &BusMap, name: &str) -> Option<u64> {
+    signals.get_num(name)
+}
+
+// Writes a number to a bus, truncated to the width of the bus.
+// None marks every bit undefined.
+fn set_num(signals: &mut BusMap, name: &str, value: Option<u64>) {
+    signals.set_num(name, value)
+}
+
+fn mask(signals: &BusMap, name: &str) -> u64 {
+    let width = signals.get_width(name).unwrap();
+    if width >= 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    }
+}
+
+#[derive(Clone)]
+struct TriState {}
+
+impl Builtin for TriState {
+    fn eval(&mut self, signals: &mut BusMap) {
+        let input = signals.get_logic("in")[0];
+        let enable = signals.get_logic("enable")[0];
+        signals.insert_logic("out", &[input.buffer(enable)]);
+    }
+}
+
+/// Resolves one bit that the outputs of several `TriState` parts share.
+/// Bit `k` of `in` is what the `k`th part drives, which is Z while the part
+/// is disabled.
+pub fn shared_bit() -> Box<dyn Builtin> {
+    Box::new(SharedBit {})
+}
+
+#[derive(Clone)]
+struct SharedBit {}
+
+impl Builtin for SharedBit {
+    fn eval(&mut self, signals: &mut BusMap) {
+        let out = signals
+            .get_logic("in")
+            .into_iter()
+            .fold(Logic::Z, Logic::resolve);
+        signals.insert_logic("out", &[out]);
+    }
+}
+
+#[derive(Clone)]
+struct Add16 {}
+
+impl Builtin for Add16 {
+    fn eval(&mut self, signals: &mut BusMap) {
+        let out = match (get_num(signals, "a"), get_num(signals, "b")) {
+            (Some(a), Some(b)) => Some(a.wrapping_add(b)),
+            _ => None,
+        };
+        set_num(signals, "out", out);
+    }
+}
+
+#[derive(Clone)]
+struct Inc16 {}
+
+impl Builtin for Inc16 {
+    fn eval(&mut self, signals: &mut BusMap) {
+        let out = get_num(signals, "in").map(|x| x.wrapping_add(1));
+        set_num(signals, "out", out);
+    }
+}
+
+#[derive(Clone)]
+struct Alu {}
+
+impl Builtin for Alu {
+    fn eval(&mut self, signals: &mut BusMap) {
+        let m = mask(signals, "out");
+        let flag = |name| get_num(signals, name).map(|x| x == 1);
+        let out = (|| {
+            let mut x = get_num(signals, "x")?;
+            let mut y = get_num(signals, "y")?;
+            if flag("zx")? {
+                x = 0;
+            }
+            if flag("nx")? {
+                x = !x;
+            }
+            if flag("zy")? {
+                y = 0;
+            }
+            if flag("ny")? {
+                y = !y;
+            }
+            let mut out = if flag("f")? { x.wrapping_add(y) } else { x & y };
+            if flag("no")? {
+                out = !out;
+            }
+            Some(out & m)
+        })();
+
+        set_num(signals, "out", out);
+        set_num(signals, "zr", out.map(|x| (x == 0) as u64));
+        set_num(signals, "ng", out.map(|x| (x > m >> 1) as u64));
+    }
+}
+
+// Register of any width, also used for Bit.
+#[derive(Clone)]
+struct Register {
+    value: u64,
+}
+
+impl Register {
+    fn new() -> Register {
+        Register { value: 0 }
+    }
+}
+
+impl Builtin for Register {
+    fn eval(&mut self, signals: &mut BusMap) {
+        set_num(signals, "out", Some(self.value));
+    }
+
+    fn tick(&mut self, signals: &BusMap) {
+        if get_num(signals, "load") == Some(1) {
+            if let Some(x) = get_num(signals, "in") {
+                self.value = x;
+            }
+        }
+    }
+
+    fn is_sequential(&self) -> bool {
+        true
+    }
+
+    fn state(&self) -> Vec<u64> {
+        vec![self.value]
+    }
+
+    fn set_state(&mut self, state: &[u64]) {
+        self.value = state.first().copied().unwrap_or(0);
+    }
+}
+
+#[derive(Clone)]
+struct Pc {
+    value: u64,
+}
+
+impl Pc {
+    fn new() -> Pc {
+        Pc { value: 0 }
+    }
+}
+
+impl Builtin for Pc {
+    fn eval(&mut self, signals: &mut BusMap) {
+        set_num(signals, "out", Some(self.value));
+    }
+
+    fn tick(&mut self, signals: &BusMap) {
+        if get_num(signals, "reset") == Some(1) {
+            self.value = 0;
+        } else if get_num(signals, "load") == Some(1) {
+            if let Some(x) = get_num(signals, "in") {
+                self.value = x;
+            }
+        } else if get_num(signals, "inc") == Some(1) {
+            self.value = self.value.wrapping_add(1) & mask(signals, "out");
+        }
+    }
+
+    fn is_sequential(&self) -> bool {
+        true
+    }
+
+    fn state(&self) -> Vec<u64> {
+        vec![self.value]
+    }
+
+    fn set_state(&mut self, state: &[u64]) {
+        self.value = state.first().copied().unwrap_or(0);
+    }
+}
+
+#[derive(Clone)]
+struct Ram {
+    memory: Vec<u64>,
+}
+
+impl Ram {
+    fn new(size: usize) -> Ram {
+        Ram {
+            memory: vec![0; size],
+        }
+    }
+}
+
+impl Builtin for Ram {
+    fn eval(&mut self, signals: &mut BusMap) {
+        let out = get_num(signals, "address")
+            .and_then(|a| self.memory.get(a as usize))
+            .cloned();
+        set_num(signals, "out", out);
+    }
+
+    fn tick(&mut self, signals: &BusMap) {
+        if get_num(signals, "load") != Some(1) {
+            return;
+        }
+        if let (Some(a), Some(x)) = (get_num(signals, "address"), get_num(signals, "in")) {
+            if let Some(word) = self.memory.get_mut(a as usize) {
+                *word = x;
+            }
+        }
+    }
+
+    fn is_sequential(&self) -> bool {
+        true
+    }
+
+    fn state(&self) -> Vec<u64> {
+        self.memory.clone()
+    }
+
+    fn set_state(&mut self, state: &[u64]) {
+        let n = state.len().min(self.memory.len());
+        self.memory[..n].copy_from_slice(&state[..n]);
+    }
+}
+
+// Read-only memory declared with `BUILTIN ROM;`. The inputs of the chip,
+// concatenated in the order they are declared, form the address. Each word
+// of the ROM is split across the outputs the same way.
+#[derive(Clone)]
+struct Rom {
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    words: Vec<Vec<bool>>,
+}
+
+/// Loads the contents of a `BUILTIN ROM` chip from the `.rom` file next to
+/// its HDL file, or from `file` relative to it when the chip is given one
+/// with a `FILE` generic.
+pub fn get_rom(hdl: &ChipHDL, file: Option<&String>) -> Result<Box<dyn Builtin>, Box<dyn Error>> {
+    let rom_error = |msg: String| -> Box<dyn Error> {
+        Box::new(N2VError {
+            msg: format!("ROM {}: {}", hdl.name, msg),
+            kind: ErrorKind::ElaborationError,
+        })
+    };
+    let width = |direction: PortDirection| -> Result<usize, Box<dyn Error>> {
+        let mut total = 0;
+        for p in hdl.ports.iter().filter(|p| p.direction == direction) {
+            match p.width {
+                GenericWidth::Terminal(Terminal::Num(w)) => total += w,
+                _ => return Err(rom_error(String::from("ports must have numeric widths."))),
+            }
+        }
+        Ok(total)
+    };
+    let names = |direction: PortDirection| -> Vec<String> {
+        hdl.ports
+            .iter()
+            .filter(|p| p.direction == direction)
+            .map(|p| p.name.value.clone())
+            .collect()
+    };
+
+    let path = match &hdl.path {
+        Some(p) => match file {
+            Some(f) => p.with_file_name(f),
+            None => p.with_extension("rom"),
+        },
+        None => {
+            return Err(rom_error(String::from(
+                "the chip has no file to find its contents by.",
+            )))
+        }
+    };
+    let words = read_rom(&path, width(PortDirection::Out)?)?;
+    let address_bits = width(PortDirection::In)?;
+    if address_bits < usize::BITS as usize && words.len() > 1 << address_bits {
+        return Err(rom_error(format!(
+            "{} has {} words but {} address bits only reach {}.",
+            path.display(),
+            words.len(),
+            address_bits,
+            1usize << address_bits
+        )));
+    }
+
+    Ok(Box::new(Rom {
+        inputs: names(PortDirection::In),
+        outputs: names(PortDirection::Out),
+        words,
+    }))
+}
+
+/// Evaluates a chip written as a `TABLE`. Widths are taken from `signals`,
+/// the chip's signals, so a table may be given for generic widths too.
+pub fn get_table(
+    hdl: &ChipHDL,
+    table: &Table,
+    signals: &BusMap,
+) -> Result<Box<dyn Builtin>, Box<dyn Error>> {
+    let names = |direction: PortDirection| -> Vec<String> {
+        hdl.ports
+            .iter()
+            .filter(|p| p.direction == direction)
+            .map(|p| p.name.value.clone())
+            .collect()
+    };
+    let inputs = names(PortDirection::In);
+    let outputs = names(PortDirection::Out);
+    let width = |names: &[String]| -> usize {
+        names
+            .iter()
+            .map(|n| signals.get_width(n).unwrap_or(0))
+            .sum()
+    };
+    table
+        .check_widths(&hdl.name, width(&inputs), width(&outputs))
+        .map_err(|msg| N2VError {
+            msg,
+            kind: ErrorKind::SimulationError(hdl.path.clone()),
+        })?;
+
+    let bits = |s: String| -> Vec<bool> { s.chars().map(|c| c == '1').collect() };
+    let rows = table
+        .rows
+        .iter()
+        .map(|r| (bits(r.input_bits()), bits(r.output_bits())))
+        .collect();
+    Ok(Box::new(TruthTable {
+        inputs,
+        outputs,
+        rows,
+    }))
+}
+
+#[derive(Clone)]
+struct TruthTable {
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    rows: HashMap<Vec<bool>, Vec<bool>>, // Output bits by input bits.
+}
+
+impl Builtin for TruthTable {
+    fn eval(&mut self, signals: &mut BusMap) {
+        let inputs: Option<Vec<bool>> = self
+            .inputs
+            .iter()
+            .flat_map(|name| signals.get_name(name))
+            .collect();
+        let row = inputs.and_then(|i| self.rows.get(&i));
+
+        let mut offset = 0;
+        for name in &self.outputs {
+            let width = signals.get_width(name).unwrap();
+            let bits = (offset..offset + width)
+                .map(|i| row.map(|r| r[i]))
+                .collect();
+            signals.insert_option(&Bus::from(name.as_str()), bits);
+            offset += width;
+        }
+    }
+}
+
+/// Reads ROM contents, one word per line written in binary with the most
+/// significant bit first. Blank lines and `//` comments are skipped.
+pub fn read_rom(path: &Path, width: usize) -> Result<Vec<Vec<bool>>, Box<dyn Error>> {
+    let contents = fs::read_to_string(path).map_err(|e| N2VError {
+        msg: format!("Unable to read ROM contents {}. {}", path.display(), e),
+        kind: ErrorKind::IOError,
+    })?;
+    let mut words = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let word = line.split("//").next().unwrap().trim();
+        if word.is_empty() {
+            continue;
+        }
+        if word.len() != width || word.chars().any(|c| c != '0' && c != '1') {
+            return Err(Box::new(N2VError {
+                msg: format!(
+                    "{} line {}: expected a word of {} binary digits, found `{}`.",
+                    path.display(),
+                    i + 1,
+                    width,
+                    word
+                ),
+                kind: ErrorKind::ElaborationError,
+            }));
+        }
+        words.push(word.chars().map(|c| c == '1').collect());
+    }
+    Ok(words)
+}
+
+impl Builtin for Rom {
+    fn eval(&mut self, signals: &mut BusMap) {
+        let address = self.inputs.iter().try_fold(0usize, |acc, name| {
+            let width = signals.get_width(name).unwrap();
+            get_num(signals, name).map(|x| (acc << width) | x as usize)
+        });
+        // Words past the end of the contents read as zero.
+        let word = address.map(|a| self.words.get(a).cloned().unwrap_or_default());
+
+        let mut offset = 0;
+        for name in &self.outputs {
+            let width = signals.get_width(name).unwrap();
+            let bits = (offset..offset + width)
+                .map(|i| word.as_ref().map(|w| w.get(i).copied().unwrap_or(false)))
+                .collect();
+            signals.insert_option(&Bus::from(name.as_str()), bits);
+            offset += width;
+        }
+    }
+}
+
+// Monitors have no outputs. They watch signals on every clock edge and
+// record a violation whenever a protocol rule is broken.
+
+// A valid/ready handshake. A transfer is offered while valid is high and
+// taken on the first clock edge where ready is also high.
+#[derive(Clone, Default)]
+struct HandshakeMonitor {
+    cycle: u64,
+    offered: Option<Option<u64>>, // Data of a transfer still waiting for ready.
+    violations: Vec<String>,
+}
+
+impl Builtin for HandshakeMonitor {
+    fn eval(&mut self, _signals: &mut BusMap) {}
+
+    fn tick(&mut self, signals: &BusMap) {
+        self.cycle += 1;
+        let valid = get_num(signals, "valid");
+        let ready = get_num(signals, "ready");
+        let data = get_num(signals, "data");
+        if let Some(offered) = self.offered {
+            if valid == Some(0) {
+                self.violations.push(format!(
+                    "cycle {}: valid fell before ready accepted the transfer.",
+                    self.cycle
+                ));
+            } else if data != offered {
+                self.violations.push(format!(
+                    "cycle {}: data changed while waiting for ready.",
+                    self.cycle
+                ));
+            }
+        }
+        self.offered = (valid == Some(1) && ready == Some(0)).then_some(data);
+    }
+
+    fn is_sequential(&self) -> bool {
+        true
+    }
+
+    fn take_violations(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.violations)
+    }
+}
+
+// Read-after-write consistency of a memory. Keeps a copy of every word
+// written and checks `out` against it whenever that address is read.
+#[derive(Clone, Default)]
+struct MemoryMonitor {
+    cycle: u64,
+    written: HashMap<u64, u64>,
+    violations: Vec<String>,
+}
+
+impl Builtin for MemoryMonitor {
+    fn eval(&mut self, _signals: &mut BusMap) {}
+
+    fn tick(&mut self, signals: &BusMap) {
+        self.cycle += 1;
+        let address = match get_num(signals, "address") {
+            Some(a) => a,
+            None => return,
+        };
+        if let Some(expected) = self.written.get(&address) {
+            let out = get_num(signals, "out");
+            if out != Some(*expected) {
+                let out = out.map_or(String::from("an undefined value"), |o| o.to_string());
+                self.violations.push(format!(
+                    "cycle {}: read {} from address {}, but {} was written there.",
+                    self.cycle, out, address, expected
+                ));
+            }
+        }
+        if get_num(signals, "load") == Some(1) {
+            match get_num(signals, "in") {
+                Some(x) => self.written.insert(address, x),
+                None => self.written.remove(&address),
+            };
+        }
+    }
+
+    fn is_sequential(&self) -> bool {
+        true
+    }
+
+    fn take_violations(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.violations)
+    }
+}
+
+// The nand2tetris keyboard. `out` is the code of the key held down, which is
+// set by test scripts and `whidl run`, or 0 if none is.
+#[derive(Clone, Default)]
+struct Keyboard {
+    key: u16,
+}
+
+impl Builtin for Keyboard {
+    fn eval(&mut self, signals: &mut BusMap) {
+        set_num(signals, "out", Some(self.key as u64));
+    }
+
+    fn is_sequential(&self) -> bool {
+        true
+    }
+
+    fn set_key(&mut self, key: u16) -> bool {
+        self.key = key;
+        true
+    }
+}
+
+// The nand2tetris screen, a RAM whose contents are shown on the display.
+#[derive(Clone)]
+struct Screen {
+    ram: Ram,
+}
+
+impl Builtin for Screen {
+    fn eval(&mut self, signals: &mut BusMap) {
+        self.ram.eval(signals);
+    }
+
+    fn tick(&mut self, signals: &BusMap) {
+        self.ram.tick(signals);
+    }
+
+    fn is_sequential(&self) -> bool {
+        true
+    }
+
+    fn state(&self) -> Vec<u64> {
+        self.ram.state()
+    }
+
+    fn set_state(&mut self, state: &[u64]) {
+        self.ram.set_state(state);
+    }
+
+    fn screen(&self) -> Option<&[u64]> {
+        Some(&self.ram.memory)
+    }
+}
+
+/// A chip mapped into `Memory`, declared by annotating it with
+/// `@peripheral("24577")`, its first address. It has the same ports as a RAM:
+/// `address[k]` selects one of its 2^k words and may be left out for a
+/// single word, `load` is high on a write of `in[16]`, and `out[16]` is the
+/// word read.
+#[derive(Clone)]
+pub struct Peripheral {
+    pub name: String,
+    pub base: u64,
+    pub size: u64,
+}
+
+/// The peripherals among the chips `provider` can find, by address.
+pub fn peripherals(
+    provider: &Arc<dyn HdlProvider>,
+) -> Result<Vec<(Peripheral, ChipHDL)>, Box<dyn Error>> {
+    let mut found = Vec::new();
+    for name in provider.chip_names() {
+        // Only chips that mention the annotation are parsed, so a broken
+        // chip elsewhere in the directory does not stop the memory working.
+        let source = provider.get_hdl(&format!("{}.hdl", name));
+        if !source.is_ok_and(|s| s.contains("@peripheral")) {
+            continue;
+        }
+        let hdl = get_hdl(&name, provider)?;
+        if let Some(p) = peripheral(&hdl)? {
+            found.push((p, hdl));
+        }
+    }
+    found.sort_by_key(|(p, _)| p.base);
+
+    for pair in found.windows(2) {
+        let ((a, _), (b, _)) = (&pair[0], &pair[1]);
+        if a.base + a.size > b.base {
+            return Err(Box::new(N2VError {
+                msg: format!(
+                    "Peripherals {} and {} both use address {}.",
+                    a.name, b.name, b.base
+                ),
+                kind: ErrorKind::ElaborationError,
+            }));
+        }
+    }
+    Ok(found)
+}
+
+// Reads the `@peripheral` annotation of a chip and checks its ports.
+fn peripheral(hdl: &ChipHDL) -> Result<Option<Peripheral>, Box<dyn Error>> {
+    let a = match find_annotation(&hdl.annotations, "peripheral") {
+        Some(a) => a,
+        None => return Ok(None),
+    };
+    let peripheral_error = |msg: String| -> Box<dyn Error> {
+        Box::new(N2VError {
+            msg: format!("Peripheral {}: {}", hdl.name, msg),
+            kind: ErrorKind::ElaborationError,
+        })
+    };
+    let width = |name: &str, direction: PortDirection| -> Option<usize> {
+        match hdl.ports.iter().find(|p| p.name.value == name) {
+            Some(p) if p.direction == direction => match p.width {
+                GenericWidth::Terminal(Terminal::Num(w)) => Some(w),
+                _ => None,
+            },
+            _ => None,
+        }
+    };
+
+    for (name, direction, w) in [
+        ("in", PortDirection::In, 16),
+        ("load", PortDirection::In, 1),
+        ("out", PortDirection::Out, 16),
+    ] {
+        if width(name, direction) != Some(w) {
+            return Err(peripheral_error(format!(
+                "needs the ports IN in[16], load and OUT out[16], {} is missing.",
+                name
+            )));
+        }
+    }
+    let size = match hdl.ports.iter().any(|p| p.name.value == "address") {
+        false => 1,
+        true => match width("address", PortDirection::In) {
+            Some(w) if w < 15 => 1 << w,
+            _ => {
+                return Err(peripheral_error(String::from(
+                    "address must be an input narrower than 15 bits.",
+                )))
+            }
+        },
+    };
+    let base = match a.args.first().and_then(|b| b.parse::<u64>().ok()) {
+        Some(b) => b,
+        None => {
+            return Err(peripheral_error(String::from(
+                "@peripheral needs the first address, e.g. @peripheral(\"24577\").",
+            )))
+        }
+    };
+    if base <= KEYBOARD_ADDRESS || base + size > MEMORY_SIZE {
+        return Err(peripheral_error(format!(
+            "addresses {} to {} are outside {} to {}, the free part of memory.",
+            base,
+            base + size - 1,
+            KEYBOARD_ADDRESS + 1,
+            MEMORY_SIZE - 1
+        )));
+    }
+    Ok(Some(Peripheral {
+        name: hdl.name.clone(),
+        base,
+        size,
+    }))
+}
+
+/// The Hack memory with the peripherals `provider` finds mapped in.
+pub fn get_memory(
+    provider: &Arc<dyn HdlProvider>,
+    options: &ChipOptions,
+) -> Result<Box<dyn Builtin>, Box<dyn Error>> {
+    let mut devices = Vec::new();
+    for (peripheral, hdl) in peripherals(provider)? {
+        let options = ChipOptions {
+            generics: Vec::new(),
+            elaborate: false,
+            ..options.clone()
+        };
+        let chip = Chip::new(&hdl, provider, options)?;
+        // Boxed because the simulator keeps pointers into its chip.
+        let simulator = Box::new(Simulator::new(chip));
+        devices.push(Device {
+            peripheral,
+            has_address: hdl.ports.iter().any(|p| p.name.value == "address"),
+            simulator,
+            out: None,
+        });
+    }
+    Ok(Box::new(Memory {
+        ram: Ram::new(SCREEN_ADDRESS as usize),
+        screen: Ram::new(SCREEN_HEIGHT * SCREEN_WIDTH / 16),
+        key: 0,
+        devices,
+        violations: Vec::new(),
+    }))
+}
+
+#[derive(Clone)]
+struct Device {
+    peripheral: Peripheral,
+    has_address: bool,
+    simulator: Box<Simulator>,
+    out: Option<u64>, // What the peripheral read after the last eval.
+}
+
+// The memory of the Hack computer: RAM, then the screen, the keyboard and
+// any peripherals. Every peripheral is simulated each cycle, whether or not
+// it is addressed, so timers and the like keep running.
+#[derive(Clone)]
+struct Memory {
+    ram: Ram,
+    screen: Ram,
+    key: u16,
+    devices: Vec<Device>,
+    violations: Vec<String>,
+}
+
+impl Memory {
+    // Drives the ports of every peripheral. Only the one addressed sees load.
+    fn drive(&mut self, signals: &BusMap) {
+        let address = get_num(signals, "address");
+        for d in &mut self.devices {
+            let p = &d.peripheral;
+            let selected = address.filter(|a| (p.base..p.base + p.size).contains(a));
+            let mut inputs = BusMap::new();
+            inputs.create_bus("in", 16).unwrap();
+            inputs.insert_option(&Bus::from("in"), signals.get_name("in"));
+            inputs.create_bus("load", 1).unwrap();
+            let load = get_num(signals, "load").map(|l| selected.is_some() && l == 1);
+            inputs.insert_option(&Bus::from("load"), vec![load]);
+            if d.has_address {
+                let width = p.size.trailing_zeros() as usize;
+                inputs.create_bus("address", width).unwrap();
+                set_num(
+                    &mut inputs,
+                    "address",
+                    Some(selected.unwrap_or(p.base) - p.base),
+                );
+            }
+            match d.simulator.simulate(&inputs) {
+                Ok(outputs) => d.out = get_num(&outputs, "out"),
+                Err(e) => {
+                    d.out = None;
+                    self.violations
+                        .push(format!("peripheral {}: {}", p.name, e));
+                }
+            }
+        }
+    }
+}
+
+impl Builtin for Memory {
+    fn eval(&mut self, signals: &mut BusMap) {
+        self.drive(signals);
+        let out =
+            match get_num(signals, "address") {
+                None => None,
+                Some(a) if a < SCREEN_ADDRESS => Some(self.ram.memory[a as usize]),
+                Some(a) if a < KEYBOARD_ADDRESS => {
+                    Some(self.screen.memory[(a - SCREEN_ADDRESS) as usize])
+                }
+                Some(KEYBOARD_ADDRESS) => Some(self.key as u64),
+                Some(a) => match self.devices.iter().find(|d| {
+                    (d.peripheral.base..d.peripheral.base + d.peripheral.size).contains(&a)
+                }) {
+                    Some(d) => d.out,
+                    None => Some(0),
+                },
+            };
+        set_num(signals, "out", out);
+    }
+
+    fn tick(&mut self, signals: &BusMap) {
+        self.drive(signals);
+        for d in &mut self.devices {
+            if let Err(e) = d.simulator.tick() {
+                self.violations
+                    .push(format!("peripheral {}: {}", d.peripheral.name, e));
+            }
+        }
+        if get_num(signals, "load") != Some(1) {
+            return;
+        }
+        if let (Some(a), Some(x)) = (get_num(signals, "address"), get_num(signals, "in")) {
+            if a < SCREEN_ADDRESS {
+                self.ram.memory[a as usize] = x;
+            } else if a < KEYBOARD_ADDRESS {
+                self.screen.memory[(a - SCREEN_ADDRESS) as usize] = x;
+            }
+        }
+    }
+
+    fn is_sequential(&self) -> bool {
+        true
+    }
+
+    // Peripherals are summarized by a hash of their state.
+    fn state(&self) -> Vec<u64> {
+        let mut state = self.ram.state();
+        state.extend(self.screen.state());
+        state.push(self.key as u64);
+        for d in &self.devices {
+            let mut hasher = DefaultHasher::new();
+            d.simulator.chip.state().hash(&mut hasher);
+            state.push(hasher.finish());
+        }
+        state
+    }
+
+    // Peripherals are restored from their own snapshots.
+    fn set_state(&mut self, state: &[u64]) {
+        let ram = self.ram.memory.len();
+        let screen = self.screen.memory.len();
+        self.ram.set_state(&state[..ram.min(state.len())]);
+        self.screen
+            .set_state(state.get(ram..ram + screen).unwrap_or_default());
+        self.key = state.get(ram + screen).copied().unwrap_or(0) as u16;
+    }
+
+    fn devices(&self) -> Vec<&Simulator> {
+        self.devices.iter().map(|d| d.simulator.as_ref()).collect()
+    }
+
+    fn devices_mut(&mut self) -> Vec<&mut Simulator> {
+        self.devices
+            .iter_mut()
+            .map(|d| d.simulator.as_mut())
+            .collect()
+    }
+
+    fn take_violations(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.violations)
+    }
+
+    fn set_key(&mut self, key: u16) -> bool {
+        self.key = key;
+        true
+    }
+
+    fn screen(&self) -> Option<&[u64]> {
+        Some(&self.screen.memory)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_alu_builtin() {
+        let mut signals = BusMap::try_from([
+            ("x", vec![false; 16]),
+            ("y", vec![true; 16]),
+            ("zx", vec![false]),
+            ("nx", vec![false]),
+            ("zy", vec![false]),
+            ("ny", vec![false]),
+            ("f", vec![true]),
+            ("no", vec![false]),
+            ("out", vec![false; 16]),
+            ("zr", vec![false]),
+            ("ng", vec![false]),
+        ])
+        .unwrap();
+        let mut alu = get_builtin("ALU").unwrap();
+        alu.eval(&mut signals);
+        assert_eq!(signals.get_name("out"), vec![Some(true); 16]);
+        assert_eq!(signals.get_name("zr"), vec![Some(false)]);
+        assert_eq!(signals.get_name("ng"), vec![Some(true)]);
+    }
+
+    #[test]
+    fn test_tristate_builtin() {
+        let mut signals = BusMap::try_from([
+            ("in", vec![true]),
+            ("enable", vec![false]),
+            ("out", vec![false]),
+        ])
+        .unwrap();
+        let mut tristate = get_builtin("TriState").unwrap();
+        tristate.eval(&mut signals);
+        assert_eq!(signals.get_logic("out"), vec![Logic::Z]);
+        signals.set_bit("enable", 0, Some(true));
+        tristate.eval(&mut signals);
+        assert_eq!(signals.get_logic("out"), vec![Logic::One]);
+
+        // One buffer drives 0 while the other floats, then both drive.
+        let mut signals = BusMap::new();
+        signals.create_bus("in", 2).unwrap();
+        signals.create_bus("out", 1).unwrap();
+        let mut shared = shared_bit();
+        for (drivers, out) in [
+            ([Logic::Z, Logic::Zero], Logic::Zero),
+            ([Logic::One, Logic::Zero], Logic::X),
+            ([Logic::One, Logic::One], Logic::One),
+            ([Logic::Z, Logic::Z], Logic::Z),
+        ] {
+            signals.insert_logic("in", &drivers);
+            shared.eval(&mut signals);
+            assert_eq!(signals.get_logic("out"), vec![out], "{:?}", drivers);
+        }
+    }
+
+    #[test]
+    fn test_peripherals() {
+        let dir = tempfile::tempdir().unwrap();
+        let chips = [
+            (
+                "Inc16",
+                "CHIP Inc16 { IN in[16]; OUT out[16]; BUILTIN Inc16; }",
+            ),
+            (
+                "Register",
+                "CHIP Register { IN in[16], load; OUT out[16]; BUILTIN Register; }",
+            ),
+            // Counts clock cycles.
+            (
+                "Timer",
+                "@peripheral(\"24577\") CHIP Timer { IN in[16], load; OUT out[16]; PARTS:
+                Inc16(in=t, out=next);
+                Register(in=next, load=true, out=t, out=out); }",
+            ),
+            (
+                "Gpio",
+                "@peripheral(\"24578\")
+                CHIP Gpio { IN in[16], load, address[1]; OUT out[16]; PARTS:
+                Register(in=in, load=load, out=out); }",
+            ),
+        ];
+        for (name, hdl) in chips {
+            fs::write(dir.path().join(format!("{}.hdl", name)), hdl).unwrap();
+        }
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(crate::parser::FileReader::new(dir.path().to_str().unwrap()));
+        let found = peripherals(&provider).expect("Peripheral error");
+        assert_eq!(found.len(), 2);
+        assert_eq!((found[1].0.base, found[1].0.size), (24578, 2));
+
+        let hdl = get_hdl("Memory", &provider).unwrap();
+        let memory = || {
+            let chip =
+                Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+            Simulator::new(chip)
+        };
+        let step = |simulator: &mut Simulator, address: u64, value: u64, load: bool| {
+            let mut inputs = BusMap::new();
+            inputs.create_bus("address", 15).unwrap();
+            inputs.create_bus("in", 16).unwrap();
+            inputs.create_bus("load", 1).unwrap();
+            set_num(&mut inputs, "address", Some(address));
+            set_num(&mut inputs, "in", Some(value));
+            set_num(&mut inputs, "load", Some(load as u64));
+            let out = get_num(&simulator.simulate(&inputs).unwrap(), "out");
+            simulator.tick().unwrap();
+            out
+        };
+        let mut simulator = memory();
+        step(&mut simulator, 5, 7, true);
+        step(&mut simulator, 24579, 42, true);
+        assert_eq!(step(&mut simulator, 5, 0, false), Some(7));
+        assert_eq!(step(&mut simulator, 24579, 0, false), Some(42));
+        assert_eq!(step(&mut simulator, 24577, 0, false), Some(4));
+        assert_eq!(step(&mut simulator, 24600, 0, false), Some(0));
+
+        // Snapshots keep the memory and the state of peripherals.
+        let mut resumed = memory();
+        resumed.restore(&simulator.snapshot()).unwrap();
+        assert_eq!(step(&mut resumed, 5, 0, false), Some(7));
+        assert_eq!(step(&mut resumed, 24579, 0, false), Some(42));
+        assert_eq!(step(&mut resumed, 24577, 0, false), Some(8));
+
+        fs::write(
+            dir.path().join("Clash.hdl"),
+            "@peripheral(\"24579\") CHIP Clash { IN in[16], load; OUT out[16]; BUILTIN Register; }",
+        )
+        .unwrap();
+        let e = peripherals(&provider).err().unwrap();
+        assert!(e
+            .to_string()
+            .contains("Peripherals Gpio and Clash both use address 24579."));
+        fs::write(
+            dir.path().join("Clash.hdl"),
+            "@peripheral(\"24000\") CHIP Clash { IN in[16]; OUT out[16]; BUILTIN Register; }",
+        )
+        .unwrap();
+        let e = peripherals(&provider).err().unwrap();
+        assert!(e.to_string().contains("Peripheral Clash: needs the ports"));
+    }
+
+    #[test]
+    fn test_unregistered_builtin() {
+        assert!(get_builtin("Mux4Way16").is_none());
+    }
+
+    #[test]
+    fn test_handshake_monitor() {
+        let mut monitor = get_builtin("HandshakeMonitor").unwrap();
+        let mut tick = |valid: bool, ready: bool, data: u64| {
+            let mut signals = BusMap::try_from([
+                ("valid", vec![valid]),
+                ("ready", vec![ready]),
+                ("data", vec![false; 4]),
+            ])
+            .unwrap();
+            set_num(&mut signals, "data", Some(data));
+            monitor.tick(&signals);
+            monitor.take_violations()
+        };
+        assert!(tick(true, false, 3).is_empty());
+        assert!(tick(true, true, 3).is_empty());
+        assert!(tick(true, false, 5).is_empty());
+        assert_eq!(
+            tick(true, false, 6),
+            vec!["cycle 4: data changed while waiting for ready."]
+        );
+        assert_eq!(
+            tick(false, false, 6),
+            vec!["cycle 5: valid fell before ready accepted the transfer."]
+        );
+        assert!(tick(false, false, 0).is_empty());
+    }
+}
@@ -1,9 +1,11 @@
+use crate::logic::Logic;
 use crate::simulator::Bus;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::fmt::Write;
+use std::ops::Range;
 
 // Convenience for creating a bus with width 1
 impl From<String> for Bus {
@@ -82,22 +84,12 @@ impl TryFrom<HashMap<String, Vec<bool>>> for BusMap {
     }
 }
 
+// A map is less than another when the other satisfies it, see
+// `BusMap::satisfied_by`.
 impl PartialOrd for BusMap {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        let mut less = true;
-        let mut greater = true;
-
-        for k in other.buses.keys() {
-            if self.buses.get(k) != other.buses.get(k) {
-                greater = false;
-            }
-        }
-
-        for k in self.buses.keys() {
-            if self.buses.get(k) != other.buses.get(k) {
-                less = false;
-            }
-        }
+        let less = self.satisfied_by(other);
+        let greater = other.satisfied_by(self);
 
         if less && greater {
             Some(Ordering::Equal)
@@ -118,14 +110,7 @@ impl std::fmt::Debug for BusMap {
             .iter()
             .map(|(key, val)| {
                 let k = key.clone();
-                let v = val
-                    .iter()
-                    .map(|x| match x {
-                        None => String::from("?"),
-                        Some(true) => String::from("1"),
-                        Some(false) => String::from("0"),
-                    })
-                    .collect();
+                let v = val.to_logic_vec().iter().map(|x| x.to_char()).collect();
                 (k, v)
             })
             .collect();
@@ -140,14 +125,7 @@ impl std::fmt::Display for BusMap {
             .iter()
             .map(|(key, val)| {
                 let k = key.clone();
-                let v = val
-                    .iter()
-                    .map(|x| match x {
-                        None => String::from("?"),
-                        Some(true) => String::from("1"),
-                        Some(false) => String::from("0"),
-                    })
-                    .collect();
+                let v = val.to_logic_vec().iter().map(|x| x.to_char()).collect();
                 (k, v)
             })
             .collect();
@@ -161,9 +139,213 @@ impl std::fmt::Display for BusMap {
     }
 }
 
+/// The bits of a bus packed into words, with a second set of words marking
+/// which bits are known. Bit `i` of the bus is bit `i % 64` of word
+/// `i / 64`, so bus index 0 is the lowest bit. Bits past the width are
+/// always 0 in both. An unknown bit is 0 in both if it is X and set only in
+/// the first if it is Z, so each value has one form, which lets buses be
+/// compared and hashed word by word.
+#[derive(Hash, Eq, PartialEq, Clone)]
+pub struct PackedBus {
+    width: usize,
+    bits: Box<[u64]>,
+    known: Box<[u64]>,
+}
+
+// Reads `len` bits, at most 64, from `start` in `words`.
+fn read_bits(words: &[u64], start: usize, len: usize) -> u64 {
+    if len == 0 {
+        return 0;
+    }
+    let (word, offset) = (start / 64, start % 64);
+    let mut value = words[word] >> offset;
+    if offset + len > 64 {
+        value |= words[word + 1] << (64 - offset);
+    }
+    value & low_mask(len)
+}
+
+// Writes the lowest `len` bits, at most 64, of `value` at `start` in
+// `words`.
+fn write_bits(words: &mut [u64], start: usize, len: usize, value: u64) {
+    if len == 0 {
+        return;
+    }
+    let (word, offset) = (start / 64, start % 64);
+    let mask = low_mask(len);
+    let value = value & mask;
+    words[word] = (words[word] & !(mask << offset)) | (value << offset);
+    if offset + len > 64 {
+        let shift = 64 - offset;
+        words[word + 1] = (words[word + 1] & !(mask >> shift)) | (value >> shift);
+    }
+}
+
+fn low_mask(len: usize) -> u64 {
+    if len >= 64 {
+        u64::MAX
+    } else {
+        (1 << len) - 1
+    }
+}
+
+impl PackedBus {
+    /// A bus of `width` unknown bits.
+    pub fn new(width: usize) -> PackedBus {
+        let words = width.div_ceil(64);
+        PackedBus {
+            width,
+            bits: vec![0; words].into_boxed_slice(),
+            known: vec![0; words].into_boxed_slice(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn get(&self, i: usize) -> Option<bool> {
+        let (word, bit) = (i / 64, 1 << (i % 64));
+        if self.known[word] & bit == 0 {
+            None
+        } else {
+            Some(self.bits[word] & bit != 0)
+        }
+    }
+
+    pub fn set(&mut self, i: usize, value: Option<bool>) {
+        let (word, bit) = (i / 64, 1 << (i % 64));
+        match value {
+            None => {
+                self.known[word] &= !bit;
+                self.bits[word] &= !bit;
+            }
+            Some(b) => {
+                self.known[word] |= bit;
+                if b {
+                    self.bits[word] |= bit;
+                } else {
+                    self.bits[word] &= !bit;
+                }
+            }
+        }
+    }
+
+    /// Bit `i` as one of the four values a bit can take.
+    pub fn get_logic(&self, i: usize) -> Logic {
+        let (word, bit) = (i / 64, 1 << (i % 64));
+        match (self.known[word] & bit != 0, self.bits[word] & bit != 0) {
+            (true, b) => Logic::from(b),
+            (false, false) => Logic::X,
+            (false, true) => Logic::Z,
+        }
+    }
+
+    pub fn set_logic(&mut self, i: usize, value: Logic) {
+        self.set(i, Option::from(value));
+        if value == Logic::Z {
+            self.bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    /// The bits of the bus as `Logic`, highest first.
+    pub fn to_logic_vec(&self) -> Vec<Logic> {
+        (0..self.width).rev().map(|i| self.get_logic(i)).collect()
+    }
+
+    /// The bits of the bus, highest first, as `BusMap` returns them.
+    pub fn to_vec(&self) -> Vec<Option<bool>> {
+        (0..self.width).rev().map(|i| self.get(i)).collect()
+    }
+
+    /// A bus from bits given highest first.
+    pub fn from_slice(values: &[Option<bool>]) -> PackedBus {
+        let mut bus = PackedBus::new(values.len());
+        for (i, &value) in values.iter().rev().enumerate() {
+            bus.set(i, value);
+        }
+        bus
+    }
+
+    /// Bits `range` of the bus, as a bus of their own.
+    pub fn slice(&self, range: Range<usize>) -> PackedBus {
+        let mut bus = PackedBus::new(range.len());
+        bus.copy_from(0, self, range);
+        bus
+    }
+
+    /// Copies bits `range` of `source` to this bus from bit `start` on, a
+    /// word at a time. Returns whether any bit changed.
+    pub fn copy_from(&mut self, start: usize, source: &PackedBus, range: Range<usize>) -> bool {
+        let mut changed = false;
+        let mut done = 0;
+        while done < range.len() {
+            let len = (range.len() - done).min(64);
+            let bits = read_bits(&source.bits, range.start + done, len);
+            let known = read_bits(&source.known, range.start + done, len);
+            let to = start + done;
+            changed |=
+                read_bits(&self.bits, to, len) != bits || read_bits(&self.known, to, len) != known;
+            write_bits(&mut self.bits, to, len, bits);
+            write_bits(&mut self.known, to, len, known);
+            done += len;
+        }
+        changed
+    }
+
+    /// The bus as a number, or None if a bit is unknown. Only the lowest 64
+    /// bits of a wider bus are returned.
+    pub fn to_u64(&self) -> Option<u64> {
+        if self
+            .known
+            .iter()
+            .enumerate()
+            .all(|(i, &k)| k == self.word_mask(i))
+        {
+            Some(self.bits.first().copied().unwrap_or(0))
+        } else {
+            None
+        }
+    }
+
+    /// Sets the bus to a number, truncated to its width. None marks every
+    /// bit unknown, and the bits of a wider bus above 64 are 0.
+    pub fn set_u64(&mut self, value: Option<u64>) {
+        for i in 0..self.bits.len() {
+            let (bits, known) = match (i, value) {
+                (_, None) => (0, 0),
+                (0, Some(v)) => (v, u64::MAX),
+                (_, Some(_)) => (0, u64::MAX),
+            };
+            self.bits[i] = bits & self.word_mask(i);
+            self.known[i] = known & self.word_mask(i);
+        }
+    }
+
+    // Bits of word `i` that are within the width.
+    fn word_mask(&self, i: usize) -> u64 {
+        low_mask(self.width - i * 64)
+    }
+}
+
+// Buses are saved as a list of bits, highest first, as they were before
+// they were packed.
+impl Serialize for PackedBus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_vec().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PackedBus {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let values = Vec::<Option<bool>>::deserialize(deserializer)?;
+        Ok(PackedBus::from_slice(&values))
+    }
+}
+
 #[derive(Serialize, Deserialize, Hash, Eq, PartialEq, Clone)]
 pub struct BusMap {
-    buses: BTreeMap<String, Box<[Option<bool>]>>,
+    buses: BTreeMap<String, PackedBus>,
 }
 
 impl Default for BusMap {
@@ -179,43 +361,110 @@ impl BusMap {
         }
     }
 
-    // Returns a copy if we have all the values for the bus.
-    // For dry-run maps returns false values for everything requested.
-    pub fn get_bus(&self, bus: &Bus) -> Vec<Option<bool>> {
+    fn bus(&self, name: &str) -> &PackedBus {
         let msg = &format!(
             "Attempt to get wire {:?} but we don't have that name.",
-            &bus.name
+            name
         );
-        let current = self.buses.get(&bus.name).expect(msg);
+        self.buses.get(name).expect(msg)
+    }
 
+    fn bus_mut(&mut self, name: &str) -> &mut PackedBus {
+        match self.buses.get_mut(name) {
+            Some(bus) => bus,
+            None => panic!("Attempt to use a bus that has not been created yet."),
+        }
+    }
+
+    // Bits of the bus `bus` names. Panics if they are outside of it.
+    fn range(&self, bus: &Bus) -> Range<usize> {
+        let width = self.bus(&bus.name).width();
         let range = match &bus.range {
-            None => 0..current.len(),
+            None => 0..width,
             Some(r) => r.clone(),
         };
-
-        let current_length = current.len();
-        if range.end > current_length {
+        if range.end > width {
             panic!(
                 "Attempt to use range {:?} outside of the declared bus width {:?}.",
-                range.end, current_length
+                range.end, width
             );
         }
+        range
+    }
+
+    // Returns a copy if we have all the values for the bus.
+    // For dry-run maps returns false values for everything requested.
+    pub fn get_bus(&self, bus: &Bus) -> Vec<Option<bool>> {
+        let range = self.range(bus);
+        let current = self.bus(&bus.name);
+        // Highest bit first.
+        range.rev().map(|i| current.get(i)).collect()
+    }
+
+    pub fn get_name(&self, name: &str) -> Vec<Option<bool>> {
+        self.buses.get(name).unwrap().to_vec()
+    }
 
-        let mut res = vec![None; range.len()];
+    /// The bits of a bus as `Logic`, highest first, so that a bit nothing
+    /// drives can be told from one whose value is not known.
+    pub fn get_logic(&self, name: &str) -> Vec<Logic> {
+        self.bus(name).to_logic_vec()
+    }
 
-        // Bus indices are zero-indexed from the right.
-        let mut reversal = (0..current_length).collect::<Vec<usize>>();
-        reversal.reverse();
-        for i in range.clone() {
-            res[i - range.start] = current[reversal[i]];
+    /// Sets every bit of a bus, highest first.
+    pub fn insert_logic(&mut self, name: &str, values: &[Logic]) {
+        let bus = self.bus_mut(name);
+        if values.len() != bus.width() {
+            panic!("busmap insert: inconsistent widths");
+        }
+        for (i, &value) in values.iter().rev().enumerate() {
+            bus.set_logic(i, value);
         }
-        res.reverse();
+    }
 
-        res
+    /// Bit `i` of a bus, counting from the right.
+    pub fn get_bit(&self, name: &str, i: usize) -> Option<bool> {
+        self.bus(name).get(i)
     }
 
-    pub fn get_name(&self, name: &str) -> Vec<Option<bool>> {
-        self.buses.get(name).unwrap().to_vec()
+    pub fn set_bit(&mut self, name: &str, i: usize, value: Option<bool>) {
+        self.bus_mut(name).set(i, value)
+    }
+
+    /// The bits of `bus` as a packed bus of their own.
+    pub fn get_packed(&self, bus: &Bus) -> PackedBus {
+        let range = self.range(bus);
+        self.bus(&bus.name).slice(range)
+    }
+
+    /// Copies a packed bus into the bits of `bus`, a word at a time.
+    /// Returns whether any bit changed.
+    pub fn insert_packed(&mut self, bus: &Bus, values: &PackedBus) -> bool {
+        let start = match &bus.range {
+            None => 0,
+            Some(r) => {
+                if r.len() != values.width() {
+                    panic!("busmap insert: inconsistent widths");
+                }
+                r.start
+            }
+        };
+        let current = self.bus_mut(&bus.name);
+        if start + values.width() > current.width() {
+            panic!("Attempt to use range outside of the declared bus width.");
+        }
+        current.copy_from(start, values, 0..values.width())
+    }
+
+    /// A bus as a number, or None if any bit of it is unknown.
+    pub fn get_num(&self, name: &str) -> Option<u64> {
+        self.bus(name).to_u64()
+    }
+
+    /// Writes a number to a bus, truncated to the width of the bus. None
+    /// marks every bit unknown.
+    pub fn set_num(&mut self, name: &str, value: Option<u64>) {
+        self.bus_mut(name).set_u64(value)
     }
 
     pub fn insert(&mut self, bus: Bus, values: Vec<bool>) {
@@ -232,15 +481,14 @@ impl BusMap {
     /// A bus must be created before it can be used.
     pub fn create_bus(&mut self, name: &str, width: usize) -> Result<(), String> {
         if !self.buses.contains_key(name) {
-            self.buses
-                .insert(name.to_string(), vec![None; width].into_boxed_slice());
+            self.buses.insert(name.to_string(), PackedBus::new(width));
         } else {
-            let current = self.buses.get_mut(name).unwrap();
-            if current.len() != width {
+            let current = self.buses.get(name).unwrap();
+            if current.width() != width {
                 return Err(format!(
                     "Inconsistent width for signal {}. Current width: {}, asked for: {}",
                     name,
-                    current.len(),
+                    current.width(),
                     width
                 ));
             }
@@ -251,10 +499,7 @@ impl BusMap {
     // Inserts bus values. Merges with existing values
     // for bus. Overwrites wire numbers.
     pub fn insert_option(&mut self, bus: &Bus, values: Vec<Option<bool>>) {
-        if !self.buses.contains_key(&bus.name) {
-            panic!("Attempt to use a bus that has not been created yet.");
-        }
-        let current = self.buses.get_mut(&bus.name).unwrap();
+        let current = self.bus_mut(&bus.name);
 
         let range = match &bus.range {
             None => 0..values.len(),
@@ -267,37 +512,181 @@ impl BusMap {
             }
         };
 
-        let current_length = current.len();
-        if range.end > current_length {
+        if range.end > current.width() {
             panic!("Attempt to use range outside of the declared bus width.");
         }
 
-        // Bus indices are zero-indexed from the right.
-        let mut reversal = (0..current_length).collect::<Vec<usize>>();
-        reversal.reverse();
-        let mut rvalues = values;
-        rvalues.reverse();
-        for i in range.clone() {
-            current[reversal[i]] = rvalues[i - range.start];
+        // Values come highest bit first.
+        for (i, value) in range.rev().zip(values) {
+            current.set(i, value);
         }
     }
 
     pub fn get_width(&self, name: &str) -> Option<usize> {
-        self.buses.get(name).map(|x| x.len())
+        self.buses.get(name).map(|x| x.width())
     }
 
     pub fn signals(&self) -> Vec<String> {
         self.buses.keys().cloned().collect()
     }
+
+    /// Whether `actual` has every bus of this map with the same width and
+    /// the same bits. Buses only `actual` has do not matter, and an X or Z
+    /// bit only matches the same. Test scripts check the outputs of a
+    /// chip this way against the expected values, which leave out the
+    /// signals a compare file does not list.
+    pub fn satisfied_by(&self, actual: &BusMap) -> bool {
+        self.buses
+            .iter()
+            .all(|(name, bits)| actual.buses.get(name) == Some(bits))
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    use proptest::collection::{btree_map, vec};
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_logic() {
+        let mut map = BusMap::new();
+        map.create_bus("a", 4).unwrap();
+        let bits = [Logic::Z, Logic::One, Logic::X, Logic::Zero];
+        map.insert_logic("a", &bits);
+        assert_eq!(map.get_logic("a"), bits);
+        // Z and X are both unknown where bits are known or not.
+        assert_eq!(map.get_name("a"), vec![None, Some(true), None, Some(false)]);
+        assert_eq!(format!("{}", map), "a: z1?0\n");
+
+        let mut x = map.clone();
+        x.set_bit("a", 3, None);
+        assert!(!map.satisfied_by(&x));
+        let mut copy = BusMap::new();
+        copy.create_bus("a", 4).unwrap();
+        copy.insert_packed(&Bus::from("a"), &map.get_packed(&Bus::from("a")));
+        assert!(map.satisfied_by(&copy));
+    }
+
     #[test]
     fn test_busmap_from() {
         let b = BusMap::try_from([("a", false)]).expect("Error creating bus.");
         assert_eq!(b.get_bus(&Bus::from("a")), vec![Some(false)]);
     }
+
+    fn bus_map() -> impl Strategy<Value = BusMap> {
+        let bits = (1usize..17).prop_flat_map(|w| vec(any::<Option<bool>>(), w));
+        btree_map("[a-e]", bits, 0..5).prop_map(|buses| {
+            let mut map = BusMap::new();
+            for (name, bits) in buses {
+                map.create_bus(&name, bits.len()).unwrap();
+                map.insert_option(&Bus::from(name), bits);
+            }
+            map
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_satisfied_by_itself(map in bus_map()) {
+            prop_assert!(map.satisfied_by(&map));
+            prop_assert_eq!(map.partial_cmp(&map), Some(Ordering::Equal));
+        }
+
+        #[test]
+        fn test_missing_buses(map in bus_map(), keep in vec(any::<bool>(), 5)) {
+            // Leaving buses out of the expected values only checks fewer.
+            let mut expected = BusMap::new();
+            for (name, k) in map.signals().iter().zip(keep) {
+                if k {
+                    let bits = map.get_name(name);
+                    expected.create_bus(name, bits.len()).unwrap();
+                    expected.insert_option(&Bus::from(name.as_str()), bits);
+                }
+            }
+            prop_assert!(expected.satisfied_by(&map));
+            // A bus the outputs do not have is never satisfied.
+            expected.create_bus("z", 1).unwrap();
+            prop_assert!(!expected.satisfied_by(&map));
+        }
+
+        #[test]
+        fn test_widths(map in bus_map(), extra in 1usize..4) {
+            for name in map.signals() {
+                let mut wider = map.clone();
+                let mut bits = map.get_name(&name);
+                bits.extend(vec![Some(false); extra]);
+                wider.buses.insert(name.clone(), PackedBus::from_slice(&bits));
+                prop_assert!(!map.satisfied_by(&wider));
+                prop_assert!(!wider.satisfied_by(&map));
+            }
+        }
+
+        #[test]
+        fn test_slices(map in bus_map(), start in 0usize..16, len in 1usize..16, flip in any::<bool>()) {
+            for name in map.signals() {
+                let width = map.get_width(&name).unwrap();
+                let range = start.min(width - 1)..(start + len).min(width);
+                let bus = Bus { name: name.clone(), range: Some(range.clone()) };
+                let mut changed = map.clone();
+                let mut bits = map.get_bus(&bus);
+                if flip {
+                    bits[0] = match bits[0] {
+                        Some(b) => Some(!b),
+                        None => Some(false),
+                    };
+                }
+                changed.insert_option(&bus, bits);
+                prop_assert_eq!(changed.satisfied_by(&map), !flip);
+                prop_assert_eq!(map <= changed, !flip);
+            }
+        }
+
+        #[test]
+        fn test_packed_copy(
+            to in vec(any::<Option<bool>>(), 1..200),
+            from in vec(any::<Option<bool>>(), 1..200),
+            start in 0usize..200,
+            offset in 0usize..200,
+            len in 0usize..200,
+        ) {
+            // Copying words gives the same bits as copying one at a time.
+            let start = start % to.len();
+            let offset = offset % from.len();
+            let len = len.min(to.len() - start).min(from.len() - offset);
+            let mut packed = PackedBus::from_slice(&to);
+            let source = PackedBus::from_slice(&from);
+            let changed = packed.copy_from(start, &source, offset..offset + len);
+            let mut expected = PackedBus::from_slice(&to);
+            for i in 0..len {
+                expected.set(start + i, source.get(offset + i));
+            }
+            prop_assert_eq!(packed.to_vec(), expected.to_vec());
+            prop_assert!(packed == expected);
+            prop_assert_eq!(changed, expected != PackedBus::from_slice(&to));
+            prop_assert_eq!(source.to_vec(), from);
+        }
+
+        #[test]
+        fn test_packed_num(width in 1usize..100, value in any::<u64>()) {
+            let mut map = BusMap::new();
+            map.create_bus("a", width).unwrap();
+            prop_assert_eq!(map.get_num("a"), None);
+            map.set_num("a", Some(value));
+            let expected = if width >= 64 { value } else { value & ((1 << width) - 1) };
+            prop_assert_eq!(map.get_num("a"), Some(expected));
+            let bits = map.get_name("a");
+            prop_assert_eq!(bits.len(), width);
+            map.set_bit("a", width - 1, None);
+            prop_assert_eq!(map.get_num("a"), None);
+        }
+
+        #[test]
+        fn test_partial_order(a in bus_map(), b in bus_map()) {
+            prop_assert_eq!(a <= b, a.satisfied_by(&b));
+            prop_assert_eq!(a >= b, b.satisfied_by(&a));
+            prop_assert_eq!(a == b, a.satisfied_by(&b) && b.satisfied_by(&a));
+        }
+    }
 }
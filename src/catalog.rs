@@ -0,0 +1,259 @@
+// A description of every chip in a directory as one JSON document, for
+// course portals and other tools that render chip catalogs without linking
+// whidl. Each chip lists its ports, generics, documentation, the chips it
+// uses as parts, and the test scripts that load it.
+//
+// Files that fail to parse are listed under `errors` instead of stopping the
+// catalog, so one broken chip does not hide the rest.
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::parser::*;
+use crate::scanner::{Comment, Scanner};
+use crate::test_parser::TestParser;
+use crate::test_scanner::TestScanner;
+use crate::visit::{walk_component, Visitor};
+
+/// Version of the catalog format, bumped when fields change meaning.
+pub const CATALOG_VERSION: u32 = 1;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Catalog {
+    pub version: u32,
+    pub chips: Vec<ChipEntry>,
+    pub errors: Vec<CatalogError>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ChipEntry {
+    pub name: String,
+    pub file: String,
+    pub interface: bool,
+    pub implementations: Vec<String>, // Of an interface, e.g. `fast` for `Alu.fast.hdl`.
+    pub generics: Vec<String>,
+    pub ports: Vec<PortEntry>,
+    pub doc: Option<String>,
+    pub dependencies: Vec<String>, // Chips used as parts, with their library if any.
+    pub tests: Vec<String>,        // Test scripts in the directory that load the chip.
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PortEntry {
+    pub name: String,
+    pub direction: String, // `in` or `out`.
+    pub width: String,     // An expression when it depends on generics, e.g. `W*2`.
+    pub clocked: bool,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CatalogError {
+    pub file: String,
+    pub message: String,
+}
+
+/// Describes the chips in `dir`, sorted by name.
+pub fn catalog(dir: &Path) -> Result<Catalog, Box<dyn Error>> {
+    let mut errors = Vec::new();
+    let mut files: Vec<String> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok()?.file_name().into_string().ok())
+        .collect();
+    files.sort();
+
+    // Which chip each test script loads.
+    let mut tests: Vec<(String, String)> = Vec::new();
+    for file in files.iter().filter(|f| f.ends_with(".tst")) {
+        let path = dir.join(file);
+        let contents = fs::read_to_string(&path)?;
+        let mut scanner = TestScanner::new(&contents, path.clone());
+        let mut parser = TestParser {
+            scanner: &mut scanner,
+        };
+        match parser.parse() {
+            Ok(script) => {
+                let hdl_file = script.hdl_file.to_string_lossy().into_owned();
+                tests.push((hdl_file, file.clone()));
+            }
+            Err(e) => errors.push(CatalogError {
+                file: file.clone(),
+                message: e.msg,
+            }),
+        }
+    }
+
+    let provider = FileReader::new(dir.to_str().unwrap_or("."));
+    let mut chips = Vec::new();
+    let mut names = provider.chip_names();
+    names.sort();
+    for name in names {
+        let path = provider.get_path(&format!("{}.hdl", name));
+        let file = path
+            .file_name()
+            .map_or_else(String::new, |f| f.to_string_lossy().into_owned());
+        let contents = fs::read_to_string(&path)?;
+        let mut scanner = Scanner::new(&contents, path.clone());
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = match parser.parse() {
+            Ok(hdl) => hdl,
+            Err(e) => {
+                errors.push(CatalogError {
+                    message: e.msg(),
+                    file,
+                });
+                continue;
+            }
+        };
+        let tests = tests
+            .iter()
+            .filter(|(hdl_file, _)| *hdl_file == file)
+            .map(|(_, test)| test.clone())
+            .collect();
+        chips.push(chip_entry(&hdl, file, tests));
+    }
+
+    Ok(Catalog {
+        version: CATALOG_VERSION,
+        chips,
+        errors,
+    })
+}
+
+fn chip_entry(hdl: &ChipHDL, file: String, tests: Vec<String>) -> ChipEntry {
+    let mut parts = Dependencies::default();
+    parts.visit_chip(hdl);
+    ChipEntry {
+        name: hdl.name.clone(),
+        file,
+        interface: hdl.interface.is_some(),
+        implementations: if hdl.interface.is_some() {
+            implementations(hdl, &hdl.name)
+        } else {
+            Vec::new()
+        },
+        generics: hdl.generic_decls.iter().map(|g| g.value.clone()).collect(),
+        ports: hdl
+            .ports
+            .iter()
+            .map(|p| PortEntry {
+                name: p.name.value.clone(),
+                direction: String::from(match p.direction {
+                    PortDirection::In => "in",
+                    PortDirection::Out => "out",
+                }),
+                width: hdl_expr(&p.width),
+                clocked: hdl.clocked.iter().any(|c| c.value == p.name.value),
+            })
+            .collect(),
+        doc: doc(hdl),
+        dependencies: parts.names.into_iter().collect(),
+        tests,
+    }
+}
+
+#[derive(Default)]
+struct Dependencies {
+    names: BTreeSet<String>,
+}
+
+impl Visitor for Dependencies {
+    fn visit_component(&mut self, component: &Component) {
+        self.names.insert(match &component.namespace {
+            Some(ns) => format!("{}.{}", ns.value, component.name.value),
+            None => component.name.value.clone(),
+        });
+        walk_component(self, component);
+    }
+}
+
+// The `@doc` annotation of a chip, or else the `/** */` comment before it,
+// or else all of the comments before it.
+fn doc(hdl: &ChipHDL) -> Option<String> {
+    if let Some(a) = find_annotation(&hdl.annotations, "doc") {
+        return a.args.first().cloned();
+    }
+    let leading = &hdl.comments.leading;
+    let comments: Vec<&Comment> = match leading.iter().rfind(|c| c.text.starts_with("/**")) {
+        Some(c) => vec![c],
+        None => leading.iter().collect(),
+    };
+    let text = comments
+        .iter()
+        .flat_map(|c| comment_lines(&c.text))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let text = text.trim();
+    (!text.is_empty()).then(|| String::from(text))
+}
+
+// Lines of a comment without its delimiters or the `*` starting each line
+// of a block comment.
+fn comment_lines(text: &str) -> Vec<String> {
+    if let Some(line) = text.strip_prefix("//") {
+        return vec![String::from(line.trim())];
+    }
+    let body = text.trim_start_matches("/*").trim_end_matches("*/");
+    body.lines()
+        .map(|l| {
+            let l = l.trim();
+            String::from(l.strip_prefix('*').unwrap_or(l).trim())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_catalog() {
+        let dir = tempfile::tempdir().unwrap();
+        let write =
+            |name: &str, contents: &str| fs::write(dir.path().join(name), contents).unwrap();
+        write(
+            "Not.hdl",
+            "// Part of the course.\n/**\n * Not gate:\n * out = not in\n */\nCHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }",
+        );
+        write(
+            "Inv.hdl",
+            "@doc(\"Inverts a bus.\")\nCHIP Inv<W> { IN in[W]; OUT out[W]; PARTS:\n FOR i IN 0 TO W - 1 GENERATE { Not(in=in[i], out=out[i]); }\n}",
+        );
+        write("Broken.hdl", "CHIP Broken { IN in; OUT out PARTS: }");
+        write(
+            "Not.tst",
+            "load Not.hdl, output-file Not.out, compare-to Not.cmp, output-list in%B3.1.3 out%B3.1.3;\nset in 0, eval, output;",
+        );
+
+        let catalog = catalog(dir.path()).unwrap();
+        let names: Vec<&str> = catalog.chips.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["Inv", "Not"]);
+        assert_eq!(catalog.errors.len(), 1);
+        assert_eq!(catalog.errors[0].file, "Broken.hdl");
+
+        let inv = &catalog.chips[0];
+        assert_eq!(inv.generics, vec!["W"]);
+        assert_eq!(inv.doc.as_deref(), Some("Inverts a bus."));
+        assert_eq!(inv.dependencies, vec!["Not"]);
+        assert_eq!(
+            inv.ports[0],
+            PortEntry {
+                name: String::from("in"),
+                direction: String::from("in"),
+                width: String::from("W"),
+                clocked: false,
+            }
+        );
+        assert!(inv.tests.is_empty());
+
+        let not = &catalog.chips[1];
+        assert_eq!(not.doc.as_deref(), Some("Not gate:\nout = not in"));
+        assert_eq!(not.dependencies, vec!["Nand"]);
+        assert_eq!(not.ports[1].width, "1");
+        assert_eq!(not.tests, vec!["Not.tst"]);
+    }
+}
@@ -0,0 +1,513 @@
+// Files written by commands that edit a project, such as `fmt --write`,
+// `extract` and `inline`. With `--dry-run` nothing is written: each file
+// that would change is printed as a unified diff, or with `--dry-run=json`
+// listed in a JSON document, so the edit can be reviewed before it is made.
+//
+// Otherwise the files are written together or not at all. Each is written
+// next to itself under a temporary name, and only once every one is written
+// and every chip among them parses are they moved into place, with a backup
+// of each file replaced. A refactoring also runs the test scripts next to
+// the files, and if one that passed before fails after, every file is put
+// back. Backups are deleted once the change is kept, so any left behind mark
+// a change that was interrupted.
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+
+use serde::Serialize;
+
+use crate::error::{ErrorKind, N2VError};
+use crate::parser::Parser;
+use crate::scanner::Scanner;
+use crate::test_script::{run_test_report, TestStatus};
+
+// Lines of context around each hunk of a diff.
+const CONTEXT: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DryRun {
+    Diff,
+    Json,
+}
+
+impl std::str::FromStr for DryRun {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "diff" => Ok(DryRun::Diff),
+            "json" => Ok(DryRun::Json),
+            _ => Err(format!(
+                "`{}` is not a dry run format. Use `diff` or `json`.",
+                s
+            )),
+        }
+    }
+}
+
+/// A file a command writes, with its new contents.
+pub struct FileChange {
+    pub path: PathBuf,
+    pub contents: String,
+}
+
+/// A file that would change, as listed by `--dry-run=json`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ChangeEntry {
+    pub path: String,
+    pub status: String, // `created` or `modified`.
+    pub added: usize,   // Lines.
+    pub removed: usize,
+    pub diff: String,
+}
+
+#[derive(Serialize)]
+struct ChangeList {
+    changes: Vec<ChangeEntry>,
+}
+
+/// Writes the files, or prints how they would change in a dry run.
+pub fn apply(changes: &[FileChange], dry_run: Option<DryRun>) -> Result<(), Box<dyn Error>> {
+    match dry_run {
+        Some(format) => print_preview(changes, format),
+        None => Transaction::begin(changes)?.finish(),
+    }
+}
+
+/// Writes the files of a refactoring, or prints how they would change in a
+/// dry run. The change is undone if a test next to the files that passed
+/// before it fails after it.
+pub fn apply_tested(changes: &[FileChange], dry_run: Option<DryRun>) -> Result<(), Box<dyn Error>> {
+    if let Some(format) = dry_run {
+        return print_preview(changes, format);
+    }
+    let scripts = test_scripts(changes)?;
+    let passing: Vec<&PathBuf> = scripts.iter().filter(|t| passes(t)).collect();
+    let transaction = Transaction::begin(changes)?;
+    let failing: Vec<String> = passing
+        .iter()
+        .filter(|t| !passes(t))
+        .map(|t| t.display().to_string())
+        .collect();
+    if !failing.is_empty() {
+        transaction.rollback()?;
+        return Err(change_error(format!(
+            "Left every file as it was, since tests that passed before the change fail after it: {}.",
+            failing.join(", ")
+        )));
+    }
+    transaction.finish()
+}
+
+fn change_error(msg: String) -> Box<dyn Error> {
+    Box::new(N2VError {
+        msg,
+        kind: ErrorKind::Other,
+    })
+}
+
+// Test scripts in the directories of the files.
+fn test_scripts(changes: &[FileChange]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let dirs: BTreeSet<&Path> = changes
+        .iter()
+        .map(|c| c.path.parent().unwrap_or(Path::new(".")))
+        .map(|d| {
+            if d.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                d
+            }
+        })
+        .collect();
+    let mut scripts = Vec::new();
+    for dir in dirs {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "tst") {
+                scripts.push(path);
+            }
+        }
+    }
+    scripts.sort();
+    Ok(scripts)
+}
+
+fn passes(script: &Path) -> bool {
+    let report = run_test_report(&script.to_string_lossy(), None, &AtomicBool::new(false));
+    report.is_ok_and(|r| r.status == TestStatus::Passed)
+}
+
+// Files moved into place, which can be put back until the change is kept.
+struct Transaction {
+    written: Vec<(PathBuf, Option<PathBuf>)>, // Each file, with its backup if it existed.
+}
+
+impl Transaction {
+    // Writes every file, or none if a chip among them does not parse or a
+    // file cannot be written.
+    fn begin(changes: &[FileChange]) -> Result<Transaction, Box<dyn Error>> {
+        for c in changes {
+            if c.path.extension().is_some_and(|e| e == "hdl") {
+                let mut scanner = Scanner::new(&c.contents, c.path.clone());
+                let mut parser = Parser {
+                    scanner: &mut scanner,
+                };
+                if let Err(e) = parser.parse() {
+                    return Err(change_error(format!(
+                        "Wrote no files, since {} would not parse: {}",
+                        c.path.display(),
+                        e.msg()
+                    )));
+                }
+            }
+        }
+
+        let mut staged: Vec<PathBuf> = Vec::new();
+        let mut transaction = Transaction {
+            written: Vec::new(),
+        };
+        let result = (|| -> Result<(), Box<dyn Error>> {
+            for c in changes {
+                let staging = sibling(&c.path, "whidl-new");
+                fs::write(&staging, &c.contents)?;
+                staged.push(staging);
+            }
+            for (c, staging) in changes.iter().zip(&staged) {
+                let backup = if c.path.exists() {
+                    let backup = sibling(&c.path, "whidl-backup");
+                    fs::copy(&c.path, &backup)?;
+                    Some(backup)
+                } else {
+                    None
+                };
+                transaction.written.push((c.path.clone(), backup));
+                fs::rename(staging, &c.path)?;
+            }
+            Ok(())
+        })();
+        if let Err(e) = result {
+            for staging in &staged {
+                let _ = fs::remove_file(staging);
+            }
+            transaction.rollback()?;
+            return Err(e);
+        }
+        Ok(transaction)
+    }
+
+    // Puts back every file written.
+    fn rollback(self) -> Result<(), Box<dyn Error>> {
+        for (path, backup) in self.written.iter().rev() {
+            match backup {
+                Some(backup) => fs::rename(backup, path)?,
+                None if path.exists() => fs::remove_file(path)?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Keeps the change, deleting the backups.
+    fn finish(self) -> Result<(), Box<dyn Error>> {
+        for backup in self.written.iter().filter_map(|(_, b)| b.as_ref()) {
+            fs::remove_file(backup)?;
+        }
+        Ok(())
+    }
+}
+
+// A hidden file next to `path`, e.g. `.Not.hdl.whidl-backup`.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}", name, suffix))
+}
+
+fn print_preview(changes: &[FileChange], format: DryRun) -> Result<(), Box<dyn Error>> {
+    let entries = preview(changes)?;
+    match format {
+        DryRun::Diff => {
+            for e in &entries {
+                print!("{}", e.diff);
+            }
+        }
+        DryRun::Json => {
+            let list = ChangeList { changes: entries };
+            println!("{}", serde_json::to_string_pretty(&list)?);
+        }
+    }
+    Ok(())
+}
+
+/// The files that would change, leaving out those already as written.
+pub fn preview(changes: &[FileChange]) -> Result<Vec<ChangeEntry>, Box<dyn Error>> {
+    let mut entries = Vec::new();
+    for c in changes {
+        let before = match fs::read_to_string(&c.path) {
+            Ok(x) => Some(x),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(Box::new(e)),
+        };
+        if before.as_deref() == Some(c.contents.as_str()) {
+            continue;
+        }
+        let old = lines(before.as_deref().unwrap_or(""));
+        let new = lines(&c.contents);
+        let edits = diff_lines(&old, &new);
+        entries.push(ChangeEntry {
+            path: c.path.to_string_lossy().into_owned(),
+            status: String::from(if before.is_some() {
+                "modified"
+            } else {
+                "created"
+            }),
+            added: edits
+                .iter()
+                .filter(|e| matches!(e, Edit::Insert(_)))
+                .count(),
+            removed: edits
+                .iter()
+                .filter(|e| matches!(e, Edit::Delete(_)))
+                .count(),
+            diff: unified_diff(&c.path, before.is_some(), &old, &new, &edits),
+        });
+    }
+    Ok(entries)
+}
+
+// Lines of a file, each with its newline if it has one.
+fn lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Edit {
+    Keep(usize, usize), // Line of the old file, then of the new one.
+    Delete(usize),
+    Insert(usize),
+}
+
+// The edits that turn `old` into `new`, keeping a longest common
+// subsequence of lines.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<Edit> {
+    // common[i][j] is the length of the longest common subsequence of
+    // old[i..] and new[j..].
+    let mut common = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut edits = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            edits.push(Edit::Keep(i, j));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            edits.push(Edit::Delete(i));
+            i += 1;
+        } else {
+            edits.push(Edit::Insert(j));
+            j += 1;
+        }
+    }
+    edits
+}
+
+// The edits as hunks of a unified diff, each with up to `CONTEXT` unchanged
+// lines around it.
+fn unified_diff(path: &Path, exists: bool, old: &[&str], new: &[&str], edits: &[Edit]) -> String {
+    let name = path.to_string_lossy();
+    let mut out = if exists {
+        format!("--- a/{}\n+++ b/{}\n", name, name)
+    } else {
+        format!("--- /dev/null\n+++ b/{}\n", name)
+    };
+    let changed: Vec<usize> = (0..edits.len())
+        .filter(|&k| !matches!(edits[k], Edit::Keep(..)))
+        .collect();
+    let mut k = 0;
+    while k < changed.len() {
+        // Changes with no more than twice the context between them share
+        // a hunk.
+        let start = changed[k].saturating_sub(CONTEXT);
+        let mut end = changed[k];
+        while k + 1 < changed.len() && changed[k + 1] - end <= 2 * CONTEXT + 1 {
+            k += 1;
+            end = changed[k];
+        }
+        let end = (end + CONTEXT + 1).min(edits.len());
+        k += 1;
+
+        let hunk = &edits[start..end];
+        // Where the hunk starts in each file, counting from 1.
+        let (mut old_start, mut new_start) = (0, 0);
+        for e in &edits[..start] {
+            match e {
+                Edit::Keep(..) => {
+                    old_start += 1;
+                    new_start += 1;
+                }
+                Edit::Delete(_) => old_start += 1,
+                Edit::Insert(_) => new_start += 1,
+            }
+        }
+        let old_len = hunk
+            .iter()
+            .filter(|e| !matches!(e, Edit::Insert(_)))
+            .count();
+        let new_len = hunk
+            .iter()
+            .filter(|e| !matches!(e, Edit::Delete(_)))
+            .count();
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(old_start, old_len),
+            range(new_start, new_len)
+        ));
+        for e in hunk {
+            let (sign, line) = match *e {
+                Edit::Keep(i, _) => (' ', old[i]),
+                Edit::Delete(i) => ('-', old[i]),
+                Edit::Insert(j) => ('+', new[j]),
+            };
+            out.push(sign);
+            out.push_str(line);
+            if !line.ends_with('\n') {
+                out.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    out
+}
+
+// A range of lines in a hunk header. An empty range names the line before it.
+fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let chip = dir.path().join("Chip.hdl");
+        let before = "CHIP Chip {\n IN a;\n OUT out;\n PARTS:\n Not(in=a, out=x);\n Not(in=x, out=out);\n}\n";
+        fs::write(&chip, before).unwrap();
+        let changes = vec![
+            FileChange {
+                path: chip.clone(),
+                contents: before.replace(" Not(in=x, out=out);\n", " Buf(in=x, out=out);\n"),
+            },
+            FileChange {
+                path: dir.path().join("Buf.hdl"),
+                contents: String::from("CHIP Buf { IN in; OUT out; PARTS: Not(in=in, out=out); }"),
+            },
+            FileChange {
+                path: dir.path().join("Same.txt"),
+                contents: String::new(),
+            },
+        ];
+        fs::write(dir.path().join("Same.txt"), "").unwrap();
+
+        let entries = preview(&changes).unwrap();
+        assert_eq!(entries.len(), 2);
+        let name = chip.to_string_lossy();
+        assert_eq!(
+            entries[0].diff,
+            format!(
+                "--- a/{}\n+++ b/{}\n@@ -3,5 +3,5 @@\n  OUT out;\n  PARTS:\n  Not(in=a, out=x);\n- Not(in=x, out=out);\n+ Buf(in=x, out=out);\n }}\n",
+                name, name
+            )
+        );
+        assert_eq!((entries[0].added, entries[0].removed), (1, 1));
+        assert_eq!(entries[1].status, "created");
+        assert!(entries[1]
+            .diff
+            .ends_with("@@ -0,0 +1 @@\n+CHIP Buf { IN in; OUT out; PARTS: Not(in=in, out=out); }\n\\ No newline at end of file\n"));
+
+        // Nothing is written in a dry run.
+        apply(&changes, Some(DryRun::Json)).unwrap();
+        assert_eq!(fs::read_to_string(&chip).unwrap(), before);
+        assert!(!dir.path().join("Buf.hdl").exists());
+        apply(&changes, None).unwrap();
+        assert!(dir.path().join("Buf.hdl").exists());
+    }
+
+    #[test]
+    fn test_rollback() {
+        let dir = tempfile::tempdir().unwrap();
+        let write =
+            |name: &str, contents: &str| fs::write(dir.path().join(name), contents).unwrap();
+        let not = "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }";
+        write("Not.hdl", not);
+        write(
+            "Not.tst",
+            "load Not.hdl, output-file Not.out, compare-to Not.cmp, output-list in%B3.1.3 out%B3.1.3;\nset in 0, eval, output;\nset in 1, eval, output;",
+        );
+        write(
+            "Not.cmp",
+            "|  in   |  out  |\n|   0   |   1   |\n|   1   |   0   |\n",
+        );
+        let files = |contents: &str| {
+            vec![
+                FileChange {
+                    path: dir.path().join("Not.hdl"),
+                    contents: String::from(contents),
+                },
+                FileChange {
+                    path: dir.path().join("Buf.hdl"),
+                    contents: String::from("CHIP Buf { IN in; OUT out; PARTS: Not(in=in, out=x); Not(in=x, out=out); }"),
+                },
+            ]
+        };
+        let leftovers = || {
+            fs::read_dir(dir.path())
+                .unwrap()
+                .filter(|e| {
+                    e.as_ref()
+                        .unwrap()
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with('.')
+                })
+                .count()
+        };
+
+        // A chip that does not parse stops every file being written.
+        let e = apply_tested(&files("CHIP Not {"), None).err().unwrap();
+        assert!(e.to_string().contains("Not.hdl would not parse"), "{}", e);
+        assert!(!dir.path().join("Buf.hdl").exists());
+
+        // A test that passed before fails after, so both files are put back.
+        let broken = "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=true, out=x); Nand(a=x, b=x, out=out); }";
+        let e = apply_tested(&files(broken), None).err().unwrap();
+        assert!(e.to_string().contains("Not.tst"), "{}", e);
+        assert_eq!(fs::read_to_string(dir.path().join("Not.hdl")).unwrap(), not);
+        assert!(!dir.path().join("Buf.hdl").exists());
+        assert_eq!(leftovers(), 0);
+
+        let reformatted = not.replace("; ", ";\n");
+        apply_tested(&files(&reformatted), None).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("Not.hdl")).unwrap(),
+            reformatted
+        );
+        assert!(dir.path().join("Buf.hdl").exists());
+        assert_eq!(leftovers(), 0);
+    }
+}
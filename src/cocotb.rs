@@ -0,0 +1,181 @@
+// This module generates a Python cocotb test from a nand2tetris test script.
+// The test drives the generated Verilog (from FIRRTL) or VHDL with the same
+// vectors as the .tst file and checks the outputs against the .cmp file.
+
+use std::error::Error;
+use std::fmt::Write;
+
+use crate::error::{ErrorKind, N2VError};
+use crate::test_parser::*;
+use crate::test_script::{device_error, input_bits, load_test_vectors, TestVectors};
+use crate::vhdl::keyw;
+
+// Helpers shared by every generated test. Port names such as `in` are
+// Python keywords, so signals are always looked up with getattr.
+const HELPERS: &str = "import cocotb
+from cocotb.triggers import Timer
+
+
+def sig(dut, name):
+    return getattr(dut, name)
+
+
+def check(dut, step, name, expected, mask):
+    actual = sig(dut, name).value
+    if not actual.is_resolvable or (int(actual) & mask) != (expected & mask):
+        dut._log.error(f\"Step {step}: {name} expected {expected & mask:b}, got {actual}\")
+        return 1
+    return 0
+";
+
+/// Generates a cocotb test module for a nand2tetris test script.
+///
+/// `test_script_path` - Path to the .tst file.
+/// `vhdl` - Use the port names of the VHDL backend instead of the FIRRTL backend.
+/// `lib_path` - Directories to look for the chip's parts in after its own.
+pub fn synth_cocotb(
+    test_script_path: &str,
+    vhdl: bool,
+    lib_path: &[String],
+) -> Result<String, Box<dyn Error>> {
+    let vectors = load_test_vectors(test_script_path, lib_path)?;
+    synth_test(&vectors, vhdl)
+}
+
+// Python binary literal and mask for a vector of bits, most significant
+// bit first. Undefined bits are masked out.
+fn py_literal(bits: &[Option<bool>]) -> (String, String) {
+    let value: String = bits
+        .iter()
+        .map(|b| if *b == Some(true) { '1' } else { '0' })
+        .collect();
+    let mask: String = bits
+        .iter()
+        .map(|b| if b.is_some() { '1' } else { '0' })
+        .collect();
+    (format!("0b{}", value), format!("0b{}", mask))
+}
+
+fn synth_test(vectors: &TestVectors, vhdl: bool) -> Result<String, Box<dyn Error>> {
+    let port_name = |name: &str| {
+        if vhdl {
+            keyw(name)
+        } else {
+            String::from(name)
+        }
+    };
+    let clock = if vhdl { "CLOCK_50" } else { "clock" };
+
+    let mut py = String::new();
+    writeln!(
+        &mut py,
+        "# cocotb test for {} generated by whidl.",
+        vectors.hdl.name
+    )?;
+    write!(&mut py, "{}", HELPERS)?;
+    writeln!(&mut py)?;
+    writeln!(&mut py)?;
+    writeln!(&mut py, "@cocotb.test()")?;
+    writeln!(
+        &mut py,
+        "async def test_{}(dut):",
+        vectors.hdl.name.to_lowercase()
+    )?;
+    writeln!(&mut py, "    errors = 0")?;
+    writeln!(&mut py, "    sig(dut, \"{}\").value = 0", clock)?;
+
+    let mut cmp_idx = 0;
+    for step in &vectors.script.steps {
+        for instruction in &step.instructions {
+            match instruction {
+                Instruction::Set(port, value) => {
+                    let width = match vectors.ports.get(port) {
+                        Some(p) => p.width,
+                        None => {
+                            return Err(Box::new(N2VError {
+                                msg: format!(
+                                    "Test script sets port {} which the chip does not have.",
+                                    port
+                                ),
+                                kind: ErrorKind::Other,
+                            }));
+                        }
+                    };
+                    let (literal, _) = py_literal(&input_bits(value, width)?);
+                    writeln!(
+                        &mut py,
+                        "    sig(dut, \"{}\").value = {}",
+                        port_name(port),
+                        literal
+                    )?;
+                }
+                Instruction::Eval | Instruction::Tick => {
+                    writeln!(&mut py, "    await Timer(1, units=\"ns\")")?;
+                }
+                Instruction::Tock => {
+                    writeln!(&mut py, "    sig(dut, \"{}\").value = 1", clock)?;
+                    writeln!(&mut py, "    await Timer(1, units=\"ns\")")?;
+                    writeln!(&mut py, "    sig(dut, \"{}\").value = 0", clock)?;
+                    writeln!(&mut py, "    await Timer(1, units=\"ns\")")?;
+                }
+                Instruction::Press(_) => return Err(device_error("cocotb", "keyboard")),
+                Instruction::Screen(_) => return Err(device_error("cocotb", "screen")),
+                Instruction::Output => {
+                    if cmp_idx >= vectors.expected.len() {
+                        return Err(Box::new(N2VError {
+                            msg: String::from("Test script has more outputs than the .cmp file."),
+                            kind: ErrorKind::Other,
+                        }));
+                    }
+                    let step_expected = &vectors.expected[cmp_idx];
+                    for name in step_expected.signals() {
+                        let (value, mask) = py_literal(&step_expected.get_name(&name));
+                        writeln!(
+                            &mut py,
+                            "    errors += check(dut, {}, \"{}\", {}, {})",
+                            cmp_idx + 1,
+                            port_name(&name),
+                            value,
+                            mask
+                        )?;
+                    }
+                    cmp_idx += 1;
+                }
+            }
+        }
+    }
+
+    writeln!(
+        &mut py,
+        "    assert errors == 0, f\"{{errors}} checks failed\""
+    )?;
+
+    Ok(py)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::path::Path;
+
+    #[test]
+    fn test_nand2tetris_solution_bit() {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let path = manifest_dir
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions")
+            .join("Bit.tst");
+
+        let py = synth_cocotb(path.to_str().unwrap(), false, &[]).expect("cocotb error");
+        assert!(py.contains("async def test_bit(dut):"));
+        assert!(py.contains("    sig(dut, \"load\").value = 0b1"));
+        assert!(py.contains("    errors += check(dut, 1, \"out\", 0b0, 0b1)"));
+
+        let py = synth_cocotb(path.to_str().unwrap(), true, &[]).expect("cocotb error");
+        assert!(py.contains("    sig(dut, \"CLOCK_50\").value = 1"));
+        assert!(py.contains("    sig(dut, \"in_n2v\").value = 0b0"));
+    }
+}
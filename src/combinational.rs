@@ -0,0 +1,385 @@
+// Combinational loops. A signal that feeds back into itself through gates
+// alone, with no DFF on the way, has no value of its own: the simulator
+// passes over the parts until nothing changes, which a latch built from
+// Nands survives but a ring of inverters never does. This finds such loops
+// before simulation and names every wire and part on them.
+//
+// Each chip is summarized by which input bits each of its output bits
+// follows without a clock edge in between. A chip using it as a part only
+// needs the summary, so a loop through several parts is found in the chip
+// whose wires close it, and each chip is looked at once whatever its uses.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use petgraph::algo::tarjan_scc;
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+
+use crate::builtin::get_builtin;
+use crate::elaborate::{bind, elaborate, site, Warning};
+use crate::expr::GenericValue;
+use crate::lint::Lint;
+use crate::parser::*;
+
+// A bit of a signal or port.
+type Bit = (String, usize);
+
+// The input bits each output bit of a chip follows.
+type Summary = HashMap<Bit, Vec<Bit>>;
+
+// A chip with its generic arguments, told apart by file as well as name.
+type Key = (Option<PathBuf>, String, Vec<GenericValue>);
+
+/// Warnings for the combinational loops in a chip and all of its parts.
+/// A chip is followed into itself at most `max_recursion` times, as in
+/// elaboration.
+pub fn combinational_loops(
+    hdl: &ChipHDL,
+    generics: &[GenericValue],
+    provider: &Arc<dyn HdlProvider>,
+    max_recursion: usize,
+) -> Vec<Warning> {
+    let mut analysis = Analysis {
+        max_recursion,
+        ..Analysis::default()
+    };
+    analysis.summarize(hdl, generics, provider);
+    analysis.warnings
+}
+
+#[derive(Default)]
+struct Analysis {
+    // None while a chip is being summarized, or if it could not be. Errors
+    // are left for elaboration to report.
+    summaries: HashMap<Key, Option<Rc<Summary>>>,
+    warnings: Vec<Warning>,
+    stack: Vec<String>,   // Names of the chips being summarized.
+    max_recursion: usize, // Times a chip may be a part of itself.
+}
+
+impl Analysis {
+    fn summarize(
+        &mut self,
+        hdl: &ChipHDL,
+        generics: &[GenericValue],
+        provider: &Arc<dyn HdlProvider>,
+    ) -> Option<Rc<Summary>> {
+        let key = (hdl.path.clone(), hdl.name.clone(), generics.to_vec());
+        if let Some(summary) = self.summaries.get(&key) {
+            return summary.clone();
+        }
+        // A chip that is a part of itself deeper than elaboration allows is
+        // left for elaboration to refuse.
+        let depth = self.stack.iter().filter(|n| **n == hdl.name).count();
+        if depth > self.max_recursion {
+            return None;
+        }
+        self.summaries.insert(key.clone(), None);
+        self.stack.push(hdl.name.clone());
+        let summary = self.summary(hdl, generics, provider).map(Rc::new);
+        self.stack.pop();
+        self.summaries.insert(key, summary.clone());
+        summary
+    }
+
+    fn summary(
+        &mut self,
+        hdl: &ChipHDL,
+        generics: &[GenericValue],
+        provider: &Arc<dyn HdlProvider>,
+    ) -> Option<Summary> {
+        if hdl.name.to_uppercase() == "NAND" {
+            let inputs = vec![(String::from("a"), 0), (String::from("b"), 0)];
+            return Some(HashMap::from([((String::from("out"), 0), inputs)]));
+        }
+        if hdl.name.to_uppercase() == "DFF" {
+            return Some(HashMap::new());
+        }
+        let native = match hdl.builtin_name() {
+            Some(b) if b.value == "ROM" || b.value == "Memory" => true,
+            Some(b) => get_builtin(&b.value).is_some(),
+            None => false,
+        };
+        if hdl.table.is_some() || native {
+            return self.opaque(hdl, generics, provider);
+        }
+
+        let elaborated = elaborate(hdl, generics, provider).ok()?;
+        let chip = &elaborated.chip;
+
+        // Edges run from a bit a part reads to a bit it drives, weighted
+        // with the index of the part.
+        let mut graph: DiGraph<Bit, usize> = DiGraph::new();
+        let mut nodes: HashMap<Bit, NodeIndex> = HashMap::new();
+        let mut node = |graph: &mut DiGraph<Bit, usize>, bit: Bit| {
+            *nodes
+                .entry(bit.clone())
+                .or_insert_with(|| graph.add_node(bit))
+        };
+        for (i, instance) in elaborated.instances.iter().enumerate() {
+            let part = match self.summarize(&instance.hdl, &instance.generics, &chip.provider) {
+                Some(x) => x,
+                None => continue,
+            };
+            let mut reads: HashMap<Bit, Vec<Bit>> = HashMap::new();
+            for c in &instance.connections {
+                if c.direction == PortDirection::In {
+                    for &(p, w) in &c.bits {
+                        reads
+                            .entry((c.port.clone(), p))
+                            .or_default()
+                            .push((c.wire.clone(), w));
+                    }
+                }
+            }
+            for c in &instance.connections {
+                if c.direction != PortDirection::Out {
+                    continue;
+                }
+                for &(p, w) in &c.bits {
+                    let to = node(&mut graph, (c.wire.clone(), w));
+                    for input in part.get(&(c.port.clone(), p)).into_iter().flatten() {
+                        for read in reads.get(input).into_iter().flatten() {
+                            let from = node(&mut graph, read.clone());
+                            graph.update_edge(from, to, i);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Each strongly connected set of bits holds a loop. Loops through
+        // the same parts, such as one per bit of a bus, are reported once.
+        let mut reported: HashSet<Vec<usize>> = HashSet::new();
+        for scc in tarjan_scc(&graph) {
+            let looped = scc.len() > 1 || graph.contains_edge(scc[0], scc[0]);
+            if !looped {
+                continue;
+            }
+            let cycle = find_cycle(&graph, &scc);
+            let mut parts: Vec<usize> = cycle.iter().map(|&(_, i)| i).collect();
+            parts.sort_unstable();
+            parts.dedup();
+            if !reported.insert(parts) {
+                continue;
+            }
+            let name = |n: NodeIndex| {
+                let (wire, bit) = &graph[n];
+                match chip.signals.get_width(wire) {
+                    Some(w) if w > 1 => format!("{}[{}]", wire, bit),
+                    _ => wire.clone(),
+                }
+            };
+            let mut msg = format!(
+                "Combinational loop in {} with no DFF to break it:",
+                hdl.name
+            );
+            for (k, &(from, i)) in cycle.iter().enumerate() {
+                let to = cycle[(k + 1) % cycle.len()].0;
+                let part = &chip.components[i].name;
+                msg.push_str(&format!(
+                    "\n    {} feeds {}{}, which drives {}",
+                    name(from),
+                    part.value,
+                    site(part),
+                    name(to)
+                ));
+            }
+            self.warnings.push(Warning {
+                ident: chip.components[cycle[0].1].name.clone(),
+                msg,
+                lint: Lint::CombinationalLoop,
+            });
+        }
+
+        // The inputs each output follows are those it is reached from.
+        let inputs: HashSet<&str> = chip
+            .ports
+            .values()
+            .filter(|p| p.direction == PortDirection::In)
+            .map(|p| p.name.value.as_str())
+            .collect();
+        let mut summary = Summary::new();
+        for port in chip.ports.values() {
+            if port.direction != PortDirection::Out {
+                continue;
+            }
+            for b in 0..port.width {
+                let bit = (port.name.value.clone(), b);
+                let start = match nodes.get(&bit) {
+                    Some(&n) => n,
+                    None => continue,
+                };
+                let mut seen = HashSet::from([start]);
+                let mut stack = vec![start];
+                let mut followed = Vec::new();
+                while let Some(n) = stack.pop() {
+                    if inputs.contains(graph[n].0.as_str()) {
+                        followed.push(graph[n].clone());
+                    }
+                    for m in graph.neighbors_directed(n, Direction::Incoming) {
+                        if seen.insert(m) {
+                            stack.push(m);
+                        }
+                    }
+                }
+                summary.insert(bit, followed);
+            }
+        }
+        Some(summary)
+    }
+
+    // A chip simulated natively or from a table, whose outputs follow every
+    // input it does not declare CLOCKED. A sequential builtin that declares
+    // none, such as `Memory`, is taken to follow none.
+    fn opaque(
+        &mut self,
+        hdl: &ChipHDL,
+        generics: &[GenericValue],
+        provider: &Arc<dyn HdlProvider>,
+    ) -> Option<Summary> {
+        let chip = bind(hdl, generics, provider).ok()?;
+        let sequential = hdl
+            .builtin_name()
+            .and_then(|b| get_builtin(&b.value))
+            .is_some_and(|b| b.is_sequential());
+        let mut inputs = Vec::new();
+        if !(sequential && hdl.clocked.is_empty()) {
+            for port in chip.ports.values() {
+                let clocked = hdl.clocked.iter().any(|c| c.value == port.name.value);
+                if port.direction == PortDirection::In && !clocked {
+                    inputs.extend((0..port.width).map(|b| (port.name.value.clone(), b)));
+                }
+            }
+        }
+        let mut summary = Summary::new();
+        for port in chip.ports.values() {
+            if port.direction == PortDirection::Out {
+                for b in 0..port.width {
+                    summary.insert((port.name.value.clone(), b), inputs.clone());
+                }
+            }
+        }
+        Some(summary)
+    }
+}
+
+// A loop through the bits of a strongly connected set, as each bit on it
+// with the part that drives the next one from it. Starts at the bit that
+// comes first by name.
+fn find_cycle(graph: &DiGraph<Bit, usize>, scc: &[NodeIndex]) -> Vec<(NodeIndex, usize)> {
+    let members: HashSet<NodeIndex> = scc.iter().copied().collect();
+    let start = *scc.iter().min_by_key(|&&n| &graph[n]).unwrap();
+
+    // Breadth first, so that the loop found is a shortest one.
+    let mut came_from: HashMap<NodeIndex, (NodeIndex, usize)> = HashMap::new();
+    let mut queue = VecDeque::from([start]);
+    let mut last = None;
+    'search: while let Some(n) = queue.pop_front() {
+        for e in graph.edges(n) {
+            let m = e.target();
+            if !members.contains(&m) {
+                continue;
+            }
+            if m == start {
+                last = Some((n, *e.weight()));
+                break 'search;
+            }
+            if let Entry::Vacant(v) = came_from.entry(m) {
+                v.insert((n, *e.weight()));
+                queue.push_back(m);
+            }
+        }
+    }
+
+    let mut cycle = vec![last.unwrap()];
+    while cycle[0].0 != start {
+        cycle.insert(0, came_from[&cycle[0].0]);
+    }
+    cycle
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scanner::Scanner;
+
+    fn loops(source: &str) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("Not.hdl"),
+            "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("Reg.hdl"),
+            "CHIP Reg { IN in[2]; OUT out[2]; PARTS: DFF(in=in[0], out=out[0]); DFF(in=in[1], out=out[1]); }",
+        )
+        .unwrap();
+        let mut scanner = Scanner::new(source, PathBuf::from("Top.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().unwrap();
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        combinational_loops(&hdl, &[], &provider, 0)
+            .iter()
+            .map(|w| w.msg.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_combinational_loops() {
+        // A loop through a part that is combinational inside.
+        let found = loops(
+            "CHIP Top {
+                IN a;
+                OUT out;
+                PARTS:
+                Nand(a=a, b=y, out=x);
+                Not(in=x, out=y);
+                Not(in=y, out=out);
+            }",
+        );
+        assert_eq!(
+            found,
+            vec!["Combinational loop in Top with no DFF to break it:\n    x feeds Not at Top.hdl:6, which drives y\n    y feeds Nand at Top.hdl:5, which drives x"]
+        );
+
+        // Each bit of a bus loops through the same parts, and is reported once.
+        let found = loops(
+            "CHIP Top {
+                IN a[2];
+                OUT out[2];
+                PARTS:
+                Not(in=x[0], out=x[1]);
+                Not(in=x[1], out=x[0]);
+                Nand(a=a[0], b=x[0], out=out[0]);
+                Nand(a=a[1], b=x[1], out=out[1]);
+            }",
+        );
+        assert_eq!(found.len(), 1);
+        assert!(found[0].contains("x[0] feeds Not at Top.hdl:5, which drives x[1]"));
+
+        // A DFF breaks the loop, even inside a part.
+        let found = loops(
+            "CHIP Top {
+                IN a[2];
+                OUT out[2];
+                PARTS:
+                Reg(in=x, out=y);
+                Nand(a=y[0], b=a[0], out=x[0]);
+                Nand(a=y[1], b=a[1], out=x[1]);
+                Not(in=y[0], out=out[0]);
+                Not(in=y[1], out=out[1]);
+            }",
+        );
+        assert!(found.is_empty(), "{:?}", found);
+    }
+}
@@ -0,0 +1,194 @@
+// An engine that compiles a chip into a program. The chip is flattened into
+// a netlist, and its cells are laid out in the order the netlist engine
+// would evaluate them, as a list of ops run from start to end each step.
+// NANDs read and drive nets by number, so the hot loop does no lookups by
+// name and no recursion, and only combinational loops jump back.
+
+use crate::error::{ErrorKind, N2VError};
+use crate::logic::Logic;
+use crate::netlist::{flatten, Cell, FlatEngine, NetState, Netlist};
+use crate::simulator::Chip;
+use std::error::Error;
+use std::sync::Arc;
+
+/// One step of a program compiled from a netlist.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Nand {
+        a: usize,
+        b: usize,
+        out: usize,
+    },
+    /// Evaluates a builtin, by its place among the program's builtins.
+    Builtin(usize),
+    /// Runs the next `len` ops again and again until no net they drive
+    /// changes, because they are a combinational loop.
+    Loop {
+        len: usize,
+    },
+}
+
+/// Lays the cells of `netlist` out as a program, in the order the netlist
+/// engine would evaluate them. Flip-flops only change at a tick, so they
+/// are left out of the program. Returns the program and its builtins.
+pub fn compile(netlist: &Netlist) -> (Vec<Op>, Vec<Cell>) {
+    let mut program = Vec::new();
+    let mut builtins = Vec::new();
+    for cells in netlist.levels() {
+        let looped = netlist.looped(&cells);
+        let start = program.len();
+        for i in cells {
+            match &netlist.cells[i] {
+                Cell::Nand { a, b, out } => program.push(Op::Nand {
+                    a: *a,
+                    b: *b,
+                    out: *out,
+                }),
+                Cell::Dff { .. } => {}
+                cell @ Cell::Builtin { .. } => {
+                    program.push(Op::Builtin(builtins.len()));
+                    builtins.push(cell.clone());
+                }
+            }
+        }
+        if looped {
+            let len = program.len() - start;
+            program.insert(start, Op::Loop { len });
+        }
+    }
+    (program, builtins)
+}
+
+/// Runs a chip by running a program compiled from its netlist, which
+/// evaluates every cell once per step in an order where each cell's inputs
+/// are computed before it.
+#[derive(Clone)]
+pub struct CompiledEngine {
+    state: NetState, // Its cells are the program's builtins.
+    // Shared by copies of the engine.
+    program: Arc<Vec<Op>>,
+}
+
+impl CompiledEngine {
+    /// Flattens `chip`, elaborating all of it, and compiles it to run with
+    /// the settle limit and X policy the chip was built with. The chip
+    /// itself is left as it was.
+    pub fn new(chip: &Chip) -> Result<CompiledEngine, Box<dyn Error>> {
+        let mut engine = CompiledEngine::from_netlist(&chip.name, flatten(chip)?);
+        engine.state.follow(chip.options());
+        Ok(engine)
+    }
+
+    /// Compiles a netlist already flattened from the chip `name`.
+    pub fn from_netlist(name: &str, netlist: Netlist) -> CompiledEngine {
+        let (program, builtins) = compile(&netlist);
+        CompiledEngine {
+            state: NetState::new(name, netlist, builtins),
+            program: Arc::new(program),
+        }
+    }
+
+    // Runs one op other than a loop, returning whether a net it drives
+    // changed.
+    fn run(&mut self, op: Op, changed: &mut Vec<(usize, Logic)>) -> bool {
+        match op {
+            Op::Nand { a, b, out } => {
+                let value = self.state.values[a].nand(self.state.values[b], self.state.x_policy);
+                let changing = self.state.values[out] != value;
+                self.state.values[out] = value;
+                changing
+            }
+            Op::Builtin(i) => {
+                self.state.cells[i].evaluate(&self.state.values, self.state.x_policy, changed);
+                let mut changing = false;
+                for (net, value) in changed.drain(..) {
+                    changing |= self.state.values[net] != value;
+                    self.state.values[net] = value;
+                }
+                changing
+            }
+            Op::Loop { .. } => unreachable!("loops are run by settle"),
+        }
+    }
+}
+
+impl FlatEngine for CompiledEngine {
+    type Value = Logic;
+
+    fn state(&self) -> &NetState {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut NetState {
+        &mut self.state
+    }
+
+    // Runs the program once through.
+    fn settle(&mut self) -> Result<(), Box<dyn Error>> {
+        let program = Arc::clone(&self.program);
+        let mut changed = Vec::new();
+        let mut pc = 0;
+        while pc < program.len() {
+            match program[pc] {
+                Op::Loop { len } => {
+                    let ops = &program[pc + 1..pc + 1 + len];
+                    let mut passes = 0;
+                    while ops
+                        .iter()
+                        .fold(false, |changing, &op| self.run(op, &mut changed) | changing)
+                    {
+                        passes += 1;
+                        if passes > self.state.settle_limit {
+                            return Err(Box::new(N2VError {
+                                msg: format!(
+                                    "Chip {} did not settle after {} passes over a loop of {} gates and builtins, so the loop is oscillating. The limit is set with --settle-limit.",
+                                    self.state.name,
+                                    self.state.settle_limit,
+                                    len
+                                ),
+                                kind: ErrorKind::SimulationError(None),
+                            }));
+                        }
+                    }
+                    pc += 1 + len;
+                }
+                op => {
+                    self.run(op, &mut changed);
+                    pc += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engine::SimEngine;
+    use crate::event::test::{check_same, make_chip};
+
+    #[test]
+    fn test_compile() {
+        let netlist = flatten(&make_chip("Bit.hdl")).unwrap();
+        let engine = CompiledEngine::from_netlist("Bit", netlist);
+        // The flip-flop is left out of the program, and nothing loops.
+        assert_eq!(engine.state.dffs().len(), 1);
+        assert!(engine
+            .program
+            .iter()
+            .all(|op| matches!(op, Op::Nand { .. })));
+    }
+
+    #[test]
+    fn test_compiled_engine() {
+        let engine =
+            |chip: &Chip| -> Box<dyn SimEngine> { Box::new(CompiledEngine::new(chip).unwrap()) };
+        for chip in ["Xor.hdl", "Mux8Way16.hdl", "DMux8Way.hdl", "ALU.hdl"] {
+            check_same(chip, 20, engine);
+        }
+        for chip in ["Bit.hdl", "Register.hdl", "PC.hdl", "RAM8.hdl", "CPU.hdl"] {
+            check_same(chip, 60, engine);
+        }
+    }
+}
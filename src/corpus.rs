@@ -0,0 +1,132 @@
+// The chips and test scripts under resources/tests, for downstream tools and
+// benchmarks that want the same fixtures as whidl's own tests. Only built
+// with the `corpus` feature, since it embeds every file of the corpus.
+//
+// Chips are grouped into suites by directory, e.g. `arm` or
+// `nand2tetris/solutions`. The `bad` suite holds chips that are wrong on
+// purpose.
+
+use rust_embed::RustEmbed;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(RustEmbed)]
+#[folder = "resources/tests"]
+struct Corpus;
+
+/// A chip in the corpus, e.g. `Mux` in the `nand2tetris/solutions` suite.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CorpusChip {
+    pub suite: String,
+    pub name: String,
+}
+
+/// A test script and the compare file it checks the chip's outputs against.
+#[derive(Clone, Debug)]
+pub struct CorpusTest {
+    pub script: String,
+    pub compare: Option<String>,
+}
+
+fn file(path: &str) -> Option<String> {
+    Corpus::get(path).map(|f| String::from_utf8_lossy(&f.data).into_owned())
+}
+
+/// Every chip in the corpus, sorted by suite and name.
+pub fn chips() -> Vec<CorpusChip> {
+    let mut chips: Vec<CorpusChip> = Corpus::iter()
+        .filter_map(|path| {
+            let (suite, file) = path.rsplit_once('/')?;
+            let name = file.strip_suffix(".hdl")?;
+            Some(CorpusChip {
+                suite: String::from(suite),
+                name: String::from(name),
+            })
+        })
+        .collect();
+    chips.sort();
+    chips
+}
+
+/// Names of the suites, sorted.
+pub fn suites() -> Vec<String> {
+    let mut suites: Vec<String> = chips().into_iter().map(|c| c.suite).collect();
+    suites.dedup();
+    suites
+}
+
+impl CorpusChip {
+    fn path(&self, extension: &str) -> String {
+        format!("{}/{}.{}", self.suite, self.name, extension)
+    }
+
+    pub fn hdl(&self) -> String {
+        file(&self.path("hdl")).unwrap_or_default()
+    }
+
+    /// The chip's test script, if the suite has one.
+    pub fn test(&self) -> Option<CorpusTest> {
+        Some(CorpusTest {
+            script: file(&self.path("tst"))?,
+            compare: file(&self.path("cmp")),
+        })
+    }
+}
+
+/// Writes every file of a suite to `dir`, so its tests can be run from disk
+/// with the chips they load next to them.
+pub fn extract(suite: &str, dir: &Path) -> io::Result<()> {
+    let prefix = format!("{}/", suite);
+    let mut found = false;
+    for path in Corpus::iter() {
+        let name = match path.strip_prefix(&prefix) {
+            Some(n) if !n.contains('/') => n,
+            _ => continue,
+        };
+        if let Some(f) = Corpus::get(&path) {
+            fs::write(dir.join(name), f.data)?;
+            found = true;
+        }
+    }
+    if !found {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("The corpus has no suite {}.", suite),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_script::run_test;
+
+    #[test]
+    fn test_corpus() {
+        let suites = suites();
+        assert!(suites.contains(&String::from("arm")));
+        assert!(suites.contains(&String::from("nand2tetris/solutions")));
+
+        let chips = chips();
+        let mux = chips
+            .iter()
+            .find(|c| c.suite == "nand2tetris/solutions" && c.name == "Mux")
+            .expect("Mux is missing");
+        assert!(mux.hdl().contains("CHIP Mux"));
+        let test = mux.test().expect("Mux has no test");
+        assert!(test.script.contains("Mux.hdl"));
+        assert!(test.compare.is_some());
+        let bad = chips.iter().find(|c| c.suite == "bad").unwrap();
+        assert!(bad.test().is_none());
+    }
+
+    #[test]
+    fn test_extract() {
+        let dir = tempfile::tempdir().unwrap();
+        extract("nand2tetris/solutions", dir.path()).unwrap();
+        assert!(run_test(dir.path().join("Mux.tst").to_str().unwrap()).is_ok());
+        assert!(extract("missing", dir.path()).is_err());
+    }
+}
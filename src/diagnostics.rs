@@ -0,0 +1,744 @@
+// Errors printed for people: the message, the file, line and column it is
+// about, and that line of source with the span underlined, in color when
+// stderr is a terminal. With `--message-format=json`, errors and warnings
+// are printed to stdout as one JSON object per line instead. `check --dir`
+// collects them for every chip in a directory.
+//
+//   error: Signal x cannot be driven by a part.
+//    --> ./Loop.hdl:6:19
+//     |
+//   6 |     Not(in=a, out=x);
+//     |                   ^
+
+use crate::elaborate::{elaborate, Warning};
+use crate::error::{ErrorKind, N2VError, WhidlError};
+use crate::exit::Failure;
+use crate::lint::{Level, Levels, Lint};
+use crate::parser::{
+    chip_provider, dialect_errors, CachingProvider, Dialect, HdlProvider, Parser, HDL_EXTENSIONS,
+};
+use crate::scanner::{Scanner, Span, TokenType};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto, // Color on a terminal, unless NO_COLOR is set.
+    Always,
+    Never,
+}
+
+impl std::str::FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!(
+                "`{}` is not a color choice. Use `auto`, `always`, or `never`.",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageFormat {
+    Human,
+    Json, // One diagnostic per line on stdout.
+}
+
+impl std::str::FromStr for MessageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(MessageFormat::Human),
+            "json" => Ok(MessageFormat::Json),
+            _ => Err(format!(
+                "`{}` is not a message format. Use `human` or `json`.",
+                s
+            )),
+        }
+    }
+}
+
+/// How errors and warnings are printed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Output {
+    /// Whether they are in color, `--color` on the command line.
+    pub color: ColorChoice,
+    /// Whether they are for people or JSON, `--message-format`.
+    pub format: MessageFormat,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            color: ColorChoice::Auto,
+            format: MessageFormat::Human,
+        }
+    }
+}
+
+impl Output {
+    /// Whether diagnostics printed to stderr are in color.
+    pub fn color(&self) -> bool {
+        match self.color {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none() && io::stderr().is_terminal()
+            }
+        }
+    }
+
+    /// Prints an error in the message format: rendered on stderr, or as
+    /// JSON on stdout.
+    pub fn emit_error(&self, e: &(dyn Error + 'static)) {
+        match self.format {
+            MessageFormat::Human => eprint!("{}", render_error(e, self.color())),
+            MessageFormat::Json => diagnostics(e).iter().for_each(print_json),
+        }
+    }
+
+    /// Prints a warning in the message format: rendered like an error on
+    /// stderr, with its lint after the first line, or as JSON on stdout.
+    pub fn emit_warning(&self, d: Diagnostic) {
+        match self.format {
+            MessageFormat::Human => eprint!("{}", render_warning(&d, self.color())),
+            MessageFormat::Json => print_json(&d),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// An error or warning for editors and graders to read.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The kind of error, e.g. `parse` or `elaboration`, or the lint of
+    /// a warning, e.g. `unused-wire`.
+    pub code: String,
+    pub message: String,
+    pub file: Option<PathBuf>,
+    pub line: Option<usize>,
+    pub span: Option<Span>,
+}
+
+impl Diagnostic {
+    pub fn from_error(e: &N2VError) -> Diagnostic {
+        let location = location(e);
+        Diagnostic {
+            severity: Severity::Error,
+            code: String::from(code(&e.kind)),
+            message: e.msg.clone(),
+            line: location.as_ref().and_then(|l| l.line),
+            span: location.as_ref().and_then(|l| l.span),
+            file: location.map(|l| l.path),
+        }
+    }
+
+    /// A warning about a whole file, or about no file.
+    pub fn warning(lint: Lint, file: Option<PathBuf>, message: &str) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            code: lint.to_string(),
+            message: message.to_string(),
+            file,
+            line: None,
+            span: None,
+        }
+    }
+}
+
+impl From<&Warning> for Diagnostic {
+    fn from(w: &Warning) -> Self {
+        Diagnostic {
+            code: w.lint.to_string(),
+            line: w.ident.line.map(|l| l as usize),
+            span: w.ident.span,
+            ..Diagnostic::warning(w.lint, w.ident.path.clone(), &w.msg)
+        }
+    }
+}
+
+// The code of an error of this kind in JSON diagnostics.
+fn code(kind: &ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::ParseError(t) if t.token_type == TokenType::Invalid => "scan",
+        ErrorKind::ParseError(_) => "parse",
+        ErrorKind::TestParseError(_) => "test-parse",
+        ErrorKind::ParseIdentError(..) | ErrorKind::ElaborationError => "elaboration",
+        ErrorKind::SimulationError(_) => "simulation",
+        ErrorKind::IOError => "io",
+        ErrorKind::NonNumeric => "non-numeric",
+        ErrorKind::TestFailure => "test-failure",
+        ErrorKind::Internal => "internal",
+        ErrorKind::Other => "other",
+    }
+}
+
+/// The diagnostics for an error a command failed with. A failure whose
+/// details the command has already printed has none.
+pub fn diagnostics(e: &(dyn Error + 'static)) -> Vec<Diagnostic> {
+    if e.downcast_ref::<Failure>().is_some() {
+        return Vec::new();
+    }
+    if let Some(e) = e.downcast_ref::<N2VError>() {
+        return vec![Diagnostic::from_error(e)];
+    }
+    match e.downcast_ref::<WhidlError>() {
+        Some(WhidlError::Other(e)) => diagnostics(e.as_ref()),
+        Some(e) => e.n2v().map(Diagnostic::from_error).into_iter().collect(),
+        None => vec![Diagnostic {
+            severity: Severity::Error,
+            code: String::from("other"),
+            message: e.to_string(),
+            file: None,
+            line: None,
+            span: None,
+        }],
+    }
+}
+
+// A warning for people, named by its lint so it can be allowed or denied.
+fn render_warning(d: &Diagnostic, color: bool) -> String {
+    let message = match d.message.split_once('\n') {
+        Some((first, rest)) => format!("{} [{}]\n{}", first, d.code, rest),
+        None => format!("{} [{}]", d.message, d.code),
+    };
+    Diagnostic {
+        message,
+        ..d.clone()
+    }
+    .render(color)
+}
+
+fn print_json(d: &Diagnostic) {
+    println!("{}", serde_json::to_string(d).unwrap());
+}
+
+/// Parses and elaborates every chip under `dir` in the whidl dialect, with
+/// lints at their default levels and no library path. See
+/// `check_directory_as`.
+pub fn check_directory(dir: &Path) -> Vec<Diagnostic> {
+    check_directory_as(dir, Dialect::Whidl, &Levels::default(), &[])
+}
+
+/// Parses every `.hdl` and `.whidl` file under `dir` and its subdirectories, and
+/// elaborates the chips that parse, going on past errors. Chips with
+/// generics and interfaces cannot be elaborated on their own, so they are
+/// only parsed. Returns the errors and the warnings that are not allowed,
+/// sorted by file and line. Warnings of lints `levels` denies are errors.
+/// Parts are looked for through `lib_path` too, as `chip_provider` does.
+pub fn check_directory_as(
+    dir: &Path,
+    dialect: Dialect,
+    levels: &Levels,
+    lib_path: &[String],
+) -> Vec<Diagnostic> {
+    let mut found = Vec::new();
+    // Chips in the same directory share their parts' files.
+    let mut providers: HashMap<PathBuf, Arc<dyn HdlProvider>> = HashMap::new();
+    for path in hdl_files(dir, &mut found) {
+        let dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+        let provider = providers.entry(dir).or_insert_with_key(|dir| {
+            let files = chip_provider(dir.to_str().unwrap_or("."), lib_path);
+            Arc::new(CachingProvider::new(files))
+        });
+        for d in check_file(&path, dialect, provider, levels) {
+            // A part's errors are found again in each chip that uses it.
+            if !found.contains(&d) {
+                found.push(d);
+            }
+        }
+    }
+    found.sort_by_key(|d| (d.file.clone(), d.line, d.span.map(|s| s.start_col)));
+    found
+}
+
+// The chip files under a directory, sorted. Directories that cannot be
+// read are added to `found`.
+fn hdl_files(dir: &Path, found: &mut Vec<Diagnostic>) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                found.push(io_error(&dir, &e));
+                continue;
+            }
+        };
+        for path in entries.filter_map(|e| Some(e.ok()?.path())) {
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path
+                .extension()
+                .is_some_and(|x| HDL_EXTENSIONS.iter().any(|h| x == *h))
+            {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+fn io_error(path: &Path, e: &io::Error) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Error,
+        code: String::from("io"),
+        message: format!("Unable to read {}. {}", path.display(), e),
+        file: Some(path.to_path_buf()),
+        line: None,
+        span: None,
+    }
+}
+
+// Every problem with one chip.
+fn check_file(
+    path: &Path,
+    dialect: Dialect,
+    provider: &Arc<dyn HdlProvider>,
+    levels: &Levels,
+) -> Vec<Diagnostic> {
+    match fs::read_to_string(path) {
+        Ok(source) => check_source(&source, path, dialect, provider, levels),
+        Err(e) => vec![io_error(path, &e)],
+    }
+}
+
+/// Parses and elaborates every chip `provider` has, such as the chips of
+/// a directory in a `ZipReader`, like `check_directory_as`.
+pub fn check_provider(
+    provider: &Arc<dyn HdlProvider>,
+    dialect: Dialect,
+    levels: &Levels,
+) -> Vec<Diagnostic> {
+    let mut names = provider.chip_names();
+    names.sort();
+    let mut found = Vec::new();
+    for name in names {
+        let file = format!("{}.hdl", name);
+        let path = provider.get_path(&file);
+        let checked = match provider.get_hdl(&file) {
+            Ok(source) => check_source(&source, &path, dialect, provider, levels),
+            Err(e) => vec![Diagnostic {
+                severity: Severity::Error,
+                code: String::from("io"),
+                message: e.to_string(),
+                file: Some(path),
+                line: None,
+                span: None,
+            }],
+        };
+        for d in checked {
+            if !found.contains(&d) {
+                found.push(d);
+            }
+        }
+    }
+    found.sort_by_key(|d| (d.file.clone(), d.line, d.span.map(|s| s.start_col)));
+    found
+}
+
+// Every problem with the chip in `source`, read from `path`.
+fn check_source(
+    source: &str,
+    path: &Path,
+    dialect: Dialect,
+    provider: &Arc<dyn HdlProvider>,
+    levels: &Levels,
+) -> Vec<Diagnostic> {
+    let mut scanner = Scanner::new(source, path.to_path_buf());
+    let mut parser = Parser {
+        scanner: &mut scanner,
+    };
+    let (hdl, mut errors) = parser.parse_recovering();
+    if let Some(hdl) = &hdl {
+        errors.extend(dialect_errors(hdl, dialect));
+    }
+    let hdl = match hdl {
+        Some(hdl) if errors.is_empty() => hdl,
+        _ => return errors.iter().map(Diagnostic::from_error).collect(),
+    };
+    if !hdl.generic_decls.is_empty() || hdl.interface.is_some() {
+        return Vec::new();
+    }
+
+    match elaborate(&hdl, &[], provider) {
+        Ok(chip) => chip
+            .warnings
+            .iter()
+            .filter_map(|w| match levels.level(w.lint) {
+                Level::Allow => None,
+                Level::Warn => Some(Diagnostic::from(w)),
+                Level::Deny => Some(Diagnostic {
+                    severity: Severity::Error,
+                    ..Diagnostic::from(w)
+                }),
+            })
+            .collect(),
+        Err(e) => diagnostics(e.as_ref()),
+    }
+}
+
+/// Where in the source an error is.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Location {
+    pub path: PathBuf,
+    pub line: Option<usize>,
+    pub span: Option<Span>,
+    /// The source line, if the file can be read.
+    pub text: Option<String>,
+}
+
+impl Location {
+    /// `path:line:column`, leaving out what is not known.
+    pub fn position(&self) -> String {
+        match (self.line, &self.span) {
+            (Some(line), Some(span)) => {
+                format!("{}:{}:{}", self.path.display(), line, span.start_col)
+            }
+            (Some(line), None) => format!("{}:{}", self.path.display(), line),
+            _ => self.path.display().to_string(),
+        }
+    }
+}
+
+/// The location of an error, if it is about a file.
+pub fn location(e: &N2VError) -> Option<Location> {
+    let (path, line, span, text) = match &e.kind {
+        ErrorKind::ParseError(t) => {
+            let line = t.line as usize;
+            let text = fs::read_to_string(&t.path)
+                .ok()
+                .and_then(|s| s.lines().nth(line - 1).map(String::from));
+            (t.path.clone(), Some(line), Some(t.span()), text)
+        }
+        ErrorKind::ParseIdentError(provider, ident) => {
+            let path = ident.path.clone()?;
+            let line = ident.line.map(|l| l as usize);
+            let text = path
+                .file_name()
+                .and_then(|f| provider.get_hdl(f.to_str()?).ok())
+                .zip(line)
+                .and_then(|(s, l)| s.lines().nth(l - 1).map(String::from));
+            (path, line, ident.span, text)
+        }
+        ErrorKind::TestParseError(t) => {
+            let line = t.line as usize;
+            let text = fs::read_to_string(&t.path)
+                .ok()
+                .and_then(|s| s.lines().nth(line - 1).map(String::from));
+            (t.path.clone(), Some(line), None, text)
+        }
+        ErrorKind::SimulationError(path) => (path.clone()?, None, None, None),
+        _ => return None,
+    };
+    Some(Location {
+        path,
+        line,
+        span,
+        text,
+    })
+}
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Renders an error for stderr. Errors from the parser, elaborator and
+/// simulator show the source they are about.
+pub fn render_error(e: &(dyn Error + 'static), color: bool) -> String {
+    if let Some(e) = e.downcast_ref::<N2VError>() {
+        return render(e, color);
+    }
+    match e.downcast_ref::<WhidlError>() {
+        Some(WhidlError::Other(e)) => render_error(e.as_ref(), color),
+        Some(e) => e.n2v().map_or_else(String::new, |e| render(e, color)),
+        None => header(Severity::Error, &e.to_string(), color),
+    }
+}
+
+/// Renders a whidl error with the line of source it is about.
+pub fn render(e: &N2VError, color: bool) -> String {
+    render_at(Severity::Error, &e.msg, location(e).as_ref(), color)
+}
+
+impl Diagnostic {
+    /// Renders the diagnostic like an error, with the line of source it is
+    /// about if its file can be read.
+    pub fn render(&self, color: bool) -> String {
+        let location = self.file.as_ref().map(|path| Location {
+            path: path.clone(),
+            line: self.line,
+            span: self.span,
+            text: self.line.and_then(|l| {
+                fs::read_to_string(path)
+                    .ok()
+                    .and_then(|s| s.lines().nth(l.checked_sub(1)?).map(String::from))
+            }),
+        });
+        render_at(self.severity, &self.message, location.as_ref(), color)
+    }
+}
+
+fn render_at(severity: Severity, msg: &str, location: Option<&Location>, color: bool) -> String {
+    let paint = |style: &str, s: &str| {
+        if color {
+            format!("{}{}{}", style, s, RESET)
+        } else {
+            s.to_string()
+        }
+    };
+    let mut lines = msg.lines();
+    let mut out = header(severity, lines.next().unwrap_or(""), color);
+    if let Some(loc) = location {
+        let number = loc.line.map(|l| l.to_string()).unwrap_or_default();
+        let gutter = " ".repeat(number.len() + 1);
+        out += &format!(
+            "{}{} {}\n",
+            &gutter[1..],
+            paint(BLUE, "-->"),
+            loc.position()
+        );
+        if let (Some(text), Some(line)) = (&loc.text, loc.line) {
+            out += &format!("{}{}\n", gutter, paint(BLUE, "|"));
+            out += &format!("{} {}\n", paint(BLUE, &format!("{} |", line)), text);
+            if let Some(span) = &loc.span {
+                let start = span.start_col as usize;
+                let end = if span.end_line > span.start_line {
+                    text.chars().count() + 1
+                } else {
+                    span.end_col as usize
+                };
+                let marker = match severity {
+                    Severity::Error => RED,
+                    Severity::Warning => YELLOW,
+                };
+                out += &format!(
+                    "{}{} {}{}\n",
+                    gutter,
+                    paint(BLUE, "|"),
+                    " ".repeat(start.saturating_sub(1)),
+                    paint(marker, &"^".repeat(end.saturating_sub(start).max(1)))
+                );
+            }
+        }
+    }
+    for line in lines {
+        out += line;
+        out.push('\n');
+    }
+    out
+}
+
+// The first line of a diagnostic, e.g. `error: Unknown chip Nto.`
+fn header(severity: Severity, msg: &str, color: bool) -> String {
+    let (name, style) = match severity {
+        Severity::Error => ("error", RED),
+        Severity::Warning => ("warning", YELLOW),
+    };
+    if color {
+        format!("{}{}{}{}: {}{}\n", style, name, RESET, BOLD, msg, RESET)
+    } else {
+        format!("{}: {}\n", name, msg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::{MemoryReader, Parser};
+    use crate::scanner::Scanner;
+
+    #[test]
+    fn test_output_color() {
+        let output = |color| Output {
+            color,
+            ..Output::default()
+        };
+        assert!(output(ColorChoice::Always).color());
+        assert!(!output(ColorChoice::Never).color());
+    }
+
+    #[test]
+    fn test_render() {
+        let source = "CHIP And {\n    IN a, b;\n    OUT out;\n\n    PARTS:\n    Nand(a=a b=b, out=x);\n    Not(in=x, out=out);\n}\n";
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("And.hdl");
+        fs::write(&path, source).unwrap();
+        let mut scanner = Scanner::new(source, path.clone());
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let e = parser.parse().err().unwrap();
+
+        let text = render_error(&e, false);
+        let expected = format!(
+            "error: {}\n --> {}:6:14\n  |\n6 |     Nand(a=a b=b, out=x);\n  |              ^\n",
+            e.msg(),
+            path.display()
+        );
+        assert_eq!(text, expected);
+
+        let colored = render_error(&e, true);
+        assert!(colored.starts_with("\x1b[1;31merror\x1b[0m"));
+        let plain = [RED, BLUE, BOLD, RESET]
+            .iter()
+            .fold(colored, |s, style| s.replace(style, ""));
+        assert_eq!(plain, text);
+    }
+
+    #[test]
+    fn test_render_warning() {
+        let source = "CHIP A {\n    IN a;\n    OUT out;\n    PARTS:\n    Not(in=a, out=x);\n}\n";
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("A.hdl");
+        fs::write(&path, source).unwrap();
+        let d = Diagnostic {
+            line: Some(5),
+            span: Some(Span {
+                start_line: 5,
+                start_col: 19,
+                end_line: 5,
+                end_col: 20,
+            }),
+            ..Diagnostic::warning(
+                Lint::UnusedWire,
+                Some(path.clone()),
+                "Nothing reads x.\nIt can be left out.",
+            )
+        };
+        assert_eq!(
+            render_warning(&d, false),
+            format!(
+                "warning: Nothing reads x. [unused-wire]\n --> {}:5:19\n  |\n5 |     Not(in=a, out=x);\n  |                   ^\nIt can be left out.\n",
+                path.display()
+            )
+        );
+        assert!(render_warning(&d, true).starts_with("\x1b[1;33mwarning\x1b[0m"));
+    }
+
+    #[test]
+    fn test_json_diagnostics() {
+        let mut scanner =
+            Scanner::new("CHIP A { IN a; OUT out; PARTS: # }", PathBuf::from("A.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let e = parser.parse().err().unwrap();
+        let json: Vec<serde_json::Value> = diagnostics(&e)
+            .iter()
+            .map(|d| serde_json::to_value(d).unwrap())
+            .collect();
+        assert_eq!(
+            json,
+            vec![serde_json::json!({
+                "severity": "error",
+                "code": "scan",
+                "message": "Expected identifier, FOR, or right curly.",
+                "file": "A.hdl",
+                "line": 1,
+                "span": {"start_line": 1, "start_col": 32, "end_line": 1, "end_col": 33},
+            })]
+        );
+
+        let failure = Failure {
+            code: crate::exit::PARSE,
+            msg: String::from("1 errors in A.hdl"),
+        };
+        assert!(diagnostics(&failure).is_empty());
+    }
+
+    #[test]
+    fn test_check_provider() {
+        let files = MemoryReader::new(HashMap::from([
+            (
+                String::from("A.hdl"),
+                String::from("CHIP A { IN a; OUT out; PARTS: Not(in=a, out=out); }"),
+            ),
+            (
+                String::from("B.hdl"),
+                String::from("CHIP B { IN a; OUT out; PARTS: A(in=a, out=out); }"),
+            ),
+            (
+                String::from("Not.hdl"),
+                String::from("CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }"),
+            ),
+        ]));
+        let provider: Arc<dyn HdlProvider> = Arc::new(files);
+        let found = check_provider(&provider, Dialect::Whidl, &Levels::default());
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert_eq!(found[0].file, Some(PathBuf::from("B.hdl")));
+        assert_eq!(found[0].code, "elaboration");
+    }
+
+    #[test]
+    fn test_check_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let files = [
+            (
+                "A.hdl",
+                "CHIP A { IN a; OUT out; PARTS: Not(in=a out=out); }",
+            ),
+            (
+                "B.hdl",
+                "CHIP B { IN a; OUT out; PARTS: Nto(in=a, out=out); }",
+            ),
+            ("Wide.hdl", "CHIP Wide<N> { IN a[N]; OUT out[N]; PARTS: }"),
+            (
+                "sub/C.hdl",
+                "CHIP C { IN a; OUT out; PARTS: Nand(a=a, b=a, out=out); Nand(a=a, b=a, out=x); }",
+            ),
+        ];
+        for (name, source) in files {
+            fs::write(dir.path().join(name), source).unwrap();
+        }
+
+        // Every file is checked, though the first has a syntax error.
+        let found: Vec<(Severity, String, PathBuf)> = check_directory(dir.path())
+            .into_iter()
+            .map(|d| (d.severity, d.code, d.file.unwrap()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    Severity::Error,
+                    String::from("parse"),
+                    dir.path().join("A.hdl")
+                ),
+                (
+                    Severity::Error,
+                    String::from("elaboration"),
+                    dir.path().join("B.hdl")
+                ),
+                (
+                    Severity::Warning,
+                    String::from("unused-wire"),
+                    dir.path().join("sub/C.hdl")
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,496 @@
+// Crash dumps for free-running simulations. When `whidl run --dump <dir>`
+// fails, by an error in the design or a panic in the simulator, it writes a
+// bundle that can be attached to an issue:
+//
+//   manifest.json    versions, the chip, the failing cycle and the error
+//   checkpoint.json  a snapshot taken at most `window` cycles before it
+//   trace.txt        the outputs of the last `window` cycles
+//   netlist.txt      a summary of the elaborated design
+//   keys.txt         the key presses of the run, if it had any
+//   hdl/             the files of the chip's directory
+//
+// Runs hold their inputs low, so the checkpoint and the key presses are all
+// the inputs there are, and the seeds of STIMULUS blocks travel in the HDL.
+// `whidl replay <dir>` runs the chip from the checkpoint to the failing
+// cycle, reports the first cycle whose outputs differ from the trace, and
+// whether the error comes back.
+
+use crate::busmap::BusMap;
+use crate::error::{ErrorKind, N2VError};
+use crate::parser::*;
+use crate::scanner::Scanner;
+use crate::simulator::{Bus, Chip, ChipOptions, Simulator, Snapshot};
+use crate::test_parser::{KeyPress, TestParser};
+use crate::test_scanner::TestScanner;
+use crate::test_script::pressed_key;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Version of the dump format. Replay refuses dumps from newer versions.
+pub const DUMP_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Manifest {
+    pub version: u32,
+    pub whidl: String, // Version of whidl that wrote the dump.
+    pub chip: String,
+    pub hdl_file: String,   // File name of the chip in `hdl/`.
+    pub source_dir: String, // Directory `hdl/` was copied from, which errors name files in.
+    pub cycle: u64,         // Cycle the error happened in.
+    pub error: String,
+    pub keys: bool,
+}
+
+/// Keeps what a dump needs while a run is going well.
+pub struct Recorder {
+    window: u64,
+    checkpoint: Snapshot,
+    trace: VecDeque<String>,
+}
+
+impl Recorder {
+    /// Starts recording at `cycle`, checkpointing every `window` cycles.
+    pub fn new(simulator: &Simulator, cycle: u64, window: u64) -> Recorder {
+        Recorder {
+            window: window.max(1),
+            checkpoint: Snapshot {
+                cycle,
+                ..simulator.snapshot()
+            },
+            trace: VecDeque::new(),
+        }
+    }
+
+    /// Call before running each cycle.
+    pub fn start_cycle(&mut self, simulator: &Simulator, cycle: u64) {
+        if cycle.is_multiple_of(self.window) && cycle != self.checkpoint.cycle {
+            self.checkpoint = Snapshot {
+                cycle,
+                ..simulator.snapshot()
+            };
+        }
+    }
+
+    /// Records the outputs of a cycle, formatted as the run prints them.
+    pub fn record(&mut self, cycle: u64, line: &str) {
+        self.trace.push_back(format!("cycle {}: {}", cycle, line));
+        if self.trace.len() as u64 > self.window {
+            self.trace.pop_front();
+        }
+    }
+
+    /// Writes the dump of an error in `cycle` to `dir`.
+    pub fn write(
+        &self,
+        dir: &Path,
+        simulator: &Simulator,
+        hdl_file: &Path,
+        keys: Option<&Path>,
+        cycle: u64,
+        error: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let hdl_dir = dir.join("hdl");
+        fs::create_dir_all(&hdl_dir)?;
+        let source_dir = hdl_file.parent().unwrap_or(Path::new(""));
+        let read_dir = match source_dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => source_dir,
+        };
+        for entry in fs::read_dir(read_dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                fs::copy(entry.path(), hdl_dir.join(entry.file_name()))?;
+            }
+        }
+        if let Some(keys) = keys {
+            fs::copy(keys, dir.join("keys.txt"))?;
+        }
+
+        let manifest = Manifest {
+            version: DUMP_VERSION,
+            whidl: String::from(env!("CARGO_PKG_VERSION")),
+            chip: simulator.chip.name.clone(),
+            hdl_file: hdl_file
+                .file_name()
+                .and_then(|f| f.to_str())
+                .map(String::from)
+                .unwrap_or_default(),
+            source_dir: source_dir.display().to_string(),
+            cycle,
+            error: String::from(error),
+            keys: keys.is_some(),
+        };
+        fs::write(
+            dir.join("manifest.json"),
+            serde_json::to_string_pretty(&manifest)?,
+        )?;
+        self.checkpoint.write(&dir.join("checkpoint.json"))?;
+        let trace: Vec<&str> = self.trace.iter().map(|l| l.as_str()).collect();
+        fs::write(dir.join("trace.txt"), trace.join("\n") + "\n")?;
+        fs::write(dir.join("netlist.txt"), simulator.chip.netlist() + "\n")?;
+        Ok(())
+    }
+}
+
+/// Inputs of a run, every bit held low.
+pub fn held_inputs(chip: &Chip) -> Result<BusMap, Box<dyn Error>> {
+    let mut inputs = BusMap::new();
+    for (name, port) in &chip.ports {
+        if port.direction == PortDirection::In {
+            inputs.create_bus(name, port.width)?;
+            inputs.insert(Bus::from(name.as_str()), vec![false; port.width]);
+        }
+    }
+    Ok(inputs)
+}
+
+pub fn read_key_presses(path: &Path) -> Result<Vec<KeyPress>, Box<dyn Error>> {
+    let source = fs::read_to_string(path)?;
+    let mut scanner = TestScanner::new(&source, path.to_path_buf());
+    let mut parser = TestParser {
+        scanner: &mut scanner,
+    };
+    Ok(parser.key_presses()?)
+}
+
+/// Runs one clock cycle of a run. A panic in the simulator is returned as
+/// an error, so it can be dumped like any other.
+pub fn step(
+    simulator: &mut Simulator,
+    inputs: &BusMap,
+    presses: &[KeyPress],
+    cycle: u64,
+) -> Result<BusMap, Box<dyn Error>> {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        simulator.press_key(pressed_key(presses, cycle));
+        let values = simulator.simulate(inputs)?;
+        simulator.tick()?;
+        Ok(values)
+    }));
+    match result {
+        Ok(r) => r,
+        Err(p) => {
+            let msg = match (p.downcast_ref::<&str>(), p.downcast_ref::<String>()) {
+                (Some(s), _) => String::from(*s),
+                (_, Some(s)) => s.clone(),
+                _ => String::from("unknown panic"),
+            };
+            Err(Box::new(N2VError {
+                msg: format!("Internal error: {}", msg),
+                kind: ErrorKind::Internal,
+            }))
+        }
+    }
+}
+
+/// Outputs of a chip in the order a run prints them.
+pub fn output_names(chip: &Chip) -> Vec<String> {
+    let mut outputs: Vec<String> = chip
+        .ports
+        .iter()
+        .filter(|(_, p)| p.direction == PortDirection::Out)
+        .map(|(name, _)| name.clone())
+        .collect();
+    outputs.sort();
+    outputs
+}
+
+/// Outputs of a cycle as a run prints them, e.g. `out=0101 zero=0`.
+pub fn output_line(values: &BusMap, outputs: &[String]) -> String {
+    let line: Vec<String> = outputs
+        .iter()
+        .map(|o| {
+            let bits: String = values
+                .get_name(o)
+                .iter()
+                .map(|b| match b {
+                    None => '?',
+                    Some(true) => '1',
+                    Some(false) => '0',
+                })
+                .collect();
+            format!("{}={}", o, bits)
+        })
+        .collect();
+    line.join(" ")
+}
+
+// The outputs of each cycle in a trace, by cycle.
+fn read_trace(path: &Path) -> Result<HashMap<u64, String>, Box<dyn Error>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter_map(|l| {
+            let (cycle, line) = l.strip_prefix("cycle ")?.split_once(": ")?;
+            Some((cycle.parse().ok()?, String::from(line)))
+        })
+        .collect())
+}
+
+// Describes the outputs that differ between a recorded and a replayed cycle.
+fn divergence(cycle: u64, recorded: &str, replayed: &str) -> String {
+    let signals = |line: &str| -> BTreeMap<String, String> {
+        line.split_whitespace()
+            .filter_map(|s| s.split_once('='))
+            .map(|(name, bits)| (String::from(name), String::from(bits)))
+            .collect()
+    };
+    let (recorded, replayed) = (signals(recorded), signals(replayed));
+    let mut names: Vec<&String> = recorded.keys().chain(replayed.keys()).collect();
+    names.sort();
+    names.dedup();
+    let mut report = format!("Cycle {} differs from the recorded run:", cycle);
+    for name in names {
+        let (a, b) = (recorded.get(name), replayed.get(name));
+        if a != b {
+            let bits = |v: Option<&String>| v.cloned().unwrap_or_else(|| String::from("-"));
+            report += &format!("\n  {}: recorded {}, replayed {}", name, bits(a), bits(b));
+        }
+    }
+    report
+}
+
+/// Runs a dump from its checkpoint to its failing cycle, comparing outputs
+/// with its trace. Returns a report if the run behaves as recorded and the
+/// same error happens in the same cycle, and an error with the first
+/// divergence otherwise.
+pub fn replay(dir: &Path) -> Result<String, Box<dyn Error>> {
+    let manifest: Manifest = serde_json::from_str(&fs::read_to_string(dir.join("manifest.json"))?)?;
+    if manifest.version > DUMP_VERSION {
+        return Err(Box::new(N2VError {
+            msg: format!(
+                "The dump is version {}, which needs a newer whidl than {}.",
+                manifest.version,
+                env!("CARGO_PKG_VERSION")
+            ),
+            kind: ErrorKind::Other,
+        }));
+    }
+    let mut report = Vec::new();
+    if manifest.whidl != env!("CARGO_PKG_VERSION") {
+        report.push(format!(
+            "The dump was written by whidl {}; this is whidl {}.",
+            manifest.whidl,
+            env!("CARGO_PKG_VERSION")
+        ));
+    }
+
+    let hdl_dir = dir.join("hdl");
+    let path = hdl_dir.join(&manifest.hdl_file);
+    let source_code = fs::read_to_string(&path)?;
+    let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(hdl_dir.to_str().unwrap()));
+    let mut scanner = Scanner::new(&source_code, path.clone());
+    let mut parser = Parser {
+        scanner: &mut scanner,
+    };
+    let hdl = parser.parse()?;
+    let chip = Chip::new(&hdl, &provider, ChipOptions::default())?;
+    let mut simulator = Simulator::new(chip);
+    let checkpoint = Snapshot::read(&dir.join("checkpoint.json"))?;
+    let presses = match manifest.keys {
+        true => read_key_presses(&dir.join("keys.txt"))?,
+        false => Vec::new(),
+    };
+    let inputs = held_inputs(&simulator.chip)?;
+    let outputs = output_names(&simulator.chip);
+    let trace = read_trace(&dir.join("trace.txt"))?;
+
+    // Restoring elaborates the chip, which is where errors in the design
+    // show up first.
+    let mut diverged = None;
+    let mut failure = None;
+    match simulator.restore(&checkpoint) {
+        Err(e) => failure = Some((checkpoint.cycle, e)),
+        Ok(()) => {
+            for cycle in checkpoint.cycle..=manifest.cycle {
+                let values = match step(&mut simulator, &inputs, &presses, cycle) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        failure = Some((cycle, e));
+                        break;
+                    }
+                };
+                let line = output_line(&values, &outputs);
+                match trace.get(&cycle) {
+                    Some(recorded) if diverged.is_none() && *recorded != line => {
+                        diverged = Some(divergence(cycle, recorded, &line));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    report.extend(diverged.clone());
+
+    // Errors name files as they were before the dump copied them.
+    let from = hdl_dir.join("").display().to_string();
+    let to = Path::new(&manifest.source_dir)
+        .join("")
+        .display()
+        .to_string();
+    let failure = failure.map(|(cycle, e)| (cycle, e.to_string().replace(&from, &to)));
+    let reproduced = match failure {
+        Some((cycle, e)) if cycle == manifest.cycle && e == manifest.error => {
+            report.push(format!("Reproduced the error in cycle {}: {}", cycle, e));
+            true
+        }
+        Some((cycle, e)) => {
+            report.push(format!(
+                "Replay failed in cycle {} with: {}\nThe dump failed in cycle {} with: {}",
+                cycle, e, manifest.cycle, manifest.error
+            ));
+            false
+        }
+        None => {
+            report.push(format!(
+                "Cycle {} ran without the error of the dump: {}",
+                manifest.cycle, manifest.error
+            ));
+            false
+        }
+    };
+    if reproduced && diverged.is_none() {
+        Ok(report.join("\n"))
+    } else {
+        Err(Box::new(N2VError {
+            msg: report.join("\n"),
+            kind: ErrorKind::Other,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dump_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let source_dir = dir.path().join("src");
+        fs::create_dir(&source_dir).unwrap();
+        let hdl_file = source_dir.join("Broken.hdl");
+        fs::write(
+            &hdl_file,
+            "CHIP Broken { IN a; OUT out; PARTS: Nand(a=a, b=a, out=x); Inner(in=x, out=out); }",
+        )
+        .unwrap();
+        // Reads a bit its input does not have, found when it elaborates.
+        fs::write(
+            source_dir.join("Inner.hdl"),
+            "CHIP Inner { IN in; OUT out; PARTS: Nand(a=in, b=in[3], out=out); }",
+        )
+        .unwrap();
+
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(source_dir.to_str().unwrap()));
+        let source = fs::read_to_string(&hdl_file).unwrap();
+        let mut scanner = Scanner::new(&source, hdl_file.clone());
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+        let mut simulator = Simulator::new(chip);
+        let inputs = held_inputs(&simulator.chip).unwrap();
+        let recorder = Recorder::new(&simulator, 0, 10);
+        let e = step(&mut simulator, &inputs, &[], 0).expect_err("Broken chip ran");
+
+        let dump = dir.path().join("dump");
+        recorder
+            .write(&dump, &simulator, &hdl_file, None, 0, &e.to_string())
+            .unwrap();
+        for file in [
+            "manifest.json",
+            "checkpoint.json",
+            "trace.txt",
+            "netlist.txt",
+        ] {
+            assert!(dump.join(file).is_file(), "{} missing", file);
+        }
+        assert!(dump.join("hdl").join("Broken.hdl").is_file());
+        let report = replay(&dump).expect("Replay error");
+        assert!(
+            report.starts_with("Reproduced the error in cycle 0"),
+            "{}",
+            report
+        );
+
+        let manifest_path = dump.join("manifest.json");
+        let mut manifest: Manifest =
+            serde_json::from_str(&fs::read_to_string(&manifest_path).unwrap()).unwrap();
+        manifest.error = String::from("Some other error.");
+        fs::write(&manifest_path, serde_json::to_string(&manifest).unwrap()).unwrap();
+        assert!(replay(&dump).is_err());
+    }
+
+    #[test]
+    fn test_replay_divergence() {
+        // Toggles its output every cycle.
+        let dir = tempfile::tempdir().unwrap();
+        let source_dir = dir.path().join("src");
+        fs::create_dir(&source_dir).unwrap();
+        let hdl_file = source_dir.join("Toggle.hdl");
+        fs::write(
+            &hdl_file,
+            "CHIP Toggle { IN a; OUT out; PARTS: DFF(in=n, out=q); Nand(a=q, b=q, out=n); Nand(a=n, b=n, out=out); }",
+        )
+        .unwrap();
+
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(source_dir.to_str().unwrap()));
+        let source = fs::read_to_string(&hdl_file).unwrap();
+        let mut scanner = Scanner::new(&source, hdl_file.clone());
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+        let mut simulator = Simulator::new(chip);
+        let inputs = held_inputs(&simulator.chip).unwrap();
+        let outputs = output_names(&simulator.chip);
+        let mut recorder = Recorder::new(&simulator, 0, 4);
+        for cycle in 0..6 {
+            recorder.start_cycle(&simulator, cycle);
+            let values = step(&mut simulator, &inputs, &[], cycle).expect("Step error");
+            recorder.record(cycle, &output_line(&values, &outputs));
+        }
+
+        // The dump claims cycle 6 failed, which it does not.
+        let dump = dir.path().join("dump");
+        recorder
+            .write(&dump, &simulator, &hdl_file, None, 6, "Imagined.")
+            .unwrap();
+        let trace = fs::read_to_string(dump.join("trace.txt")).unwrap();
+        assert_eq!(
+            trace,
+            "cycle 2: out=0\ncycle 3: out=1\ncycle 4: out=0\ncycle 5: out=1\n"
+        );
+        let e = replay(&dump).expect_err("Replay reproduced").to_string();
+        assert!(!e.contains("differs"), "{}", e);
+        assert!(e.contains("Cycle 6 ran without the error of the dump: Imagined."));
+
+        // A trace the chip does not follow, from another version of whidl.
+        fs::write(
+            dump.join("trace.txt"),
+            trace.replace("cycle 5: out=1", "cycle 5: out=0"),
+        )
+        .unwrap();
+        let manifest_path = dump.join("manifest.json");
+        let mut manifest: Manifest =
+            serde_json::from_str(&fs::read_to_string(&manifest_path).unwrap()).unwrap();
+        manifest.whidl = String::from("0.0.1");
+        fs::write(&manifest_path, serde_json::to_string(&manifest).unwrap()).unwrap();
+        let e = replay(&dump).expect_err("Replay reproduced").to_string();
+        assert!(e.contains("The dump was written by whidl 0.0.1"), "{}", e);
+        assert!(
+            e.contains("Cycle 5 differs from the recorded run:\n  out: recorded 0, replayed 1"),
+            "{}",
+            e
+        );
+    }
+}
@@ -0,0 +1,1127 @@
+// Semantic analysis of a chip. Binding a chip resolves what its HDL leaves
+// open: the implementation of an interface, constants, generic arguments,
+// loops and the widths of internal wires. Connecting it then checks every
+// port mapping of its parts against the chips they use and works out which
+// bits each one joins. The result is the same for every backend, so the
+// simulator and the tools that check chips see the same errors.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::Arc;
+
+use crate::busmap::BusMap;
+use crate::diagnostics::Diagnostic;
+use crate::error::{ErrorKind, N2VError};
+use crate::expr::*;
+use crate::lint::{hdl_warnings, Level, Lint};
+use crate::parser::*;
+use crate::simulator::{
+    eval_bus_bits, infer_widths, part_hdl, resolve_generics, reversed_bit, ChipOptions, Port,
+};
+
+/// Width of the `true` and `false` literals.
+pub const LITERAL_WIDTH: usize = 16;
+
+/// A chip with its generics bound, its loops expanded and the widths of
+/// its signals known.
+pub struct BoundChip {
+    pub hdl: ChipHDL, // With its implementation chosen and constants resolved.
+    pub provider: Arc<dyn HdlProvider>, // Finds the parts of the chip.
+    pub variables: HashMap<String, usize>, // Generic widths.
+    pub strings: HashMap<String, String>, // Generic strings, such as a ROM's file.
+    pub ports: HashMap<String, Port>,
+    pub signals: BusMap, // Ports and internal wires, all undefined.
+    pub components: Vec<Component>,
+}
+
+/// A part of a chip, with the chip it uses and what it connects to.
+pub struct Instance {
+    pub hdl: ChipHDL,
+    pub generics: Vec<GenericValue>,
+    pub connections: Vec<Connection>,
+}
+
+/// A port mapping of a part, bit by bit.
+pub struct Connection {
+    pub port: String,
+    pub direction: PortDirection,
+    pub wire: String,
+    pub bits: Vec<(usize, usize)>, // Bit of the port, then bit of the wire.
+}
+
+/// A chip that has been bound and connected.
+pub struct ElaboratedChip {
+    pub chip: BoundChip,
+    pub instances: Vec<Instance>, // In the order of `chip.components`.
+    pub warnings: Vec<Warning>,
+}
+
+/// A problem that leaves signals unknown rather than stopping elaboration,
+/// such as an input no part connects. Its lint decides whether it is
+/// reported, and whether it is an error.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    pub ident: Identifier,
+    pub msg: String,
+    pub lint: Lint,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.ident.path, self.ident.line) {
+            (Some(path), Some(line)) => write!(f, "{}:{}: {}", path.display(), line, self.msg),
+            _ => write!(f, "{}", self.msg),
+        }
+    }
+}
+
+/// Binds and connects a chip, checking everything short of simulating it.
+pub fn elaborate(
+    hdl: &ChipHDL,
+    generics: &[GenericValue],
+    provider: &Arc<dyn HdlProvider>,
+) -> Result<ElaboratedChip, Box<dyn Error>> {
+    let chip = bind(hdl, generics, provider)?;
+    let mut warnings = hdl_warnings(hdl);
+    warnings.extend(shadowed_chip(hdl));
+    let instances = connect(
+        &chip.components,
+        &chip.ports,
+        &chip.signals,
+        &chip.variables,
+        &chip.provider,
+        &mut warnings,
+    )?;
+    Ok(ElaboratedChip {
+        chip,
+        instances,
+        warnings,
+    })
+}
+
+/// The warning for a chip whose file hides others of its name, found by
+/// `get_hdl`. It names the chip's file rather than where it is used, so it
+/// is logged once however many parts use the chip.
+pub fn shadowed_chip(hdl: &ChipHDL) -> Option<Warning> {
+    Some(Warning {
+        ident: Identifier {
+            value: hdl.name.clone(),
+            path: hdl.path.clone(),
+            line: None,
+            span: None,
+        },
+        msg: hdl.shadows.clone()?,
+        lint: Lint::ShadowedChip,
+    })
+}
+
+thread_local! {
+    // Warnings already logged, so that a part used many times warns once.
+    static WARNED: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+/// Prints the warnings whose lints warn at the levels of `options`, as its
+/// output says, or fails with the first whose lint is denied. Allowed lints
+/// are left out.
+pub fn report_warnings(
+    warnings: Vec<Warning>,
+    provider: &Arc<dyn HdlProvider>,
+    options: &ChipOptions,
+) -> Result<(), Box<dyn Error>> {
+    let levels = &options.lints;
+    if let Some(w) = warnings
+        .iter()
+        .find(|w| levels.level(w.lint) == Level::Deny)
+    {
+        return Err(Box::new(N2VError {
+            kind: ErrorKind::ParseIdentError(provider.clone(), w.ident.clone()),
+            msg: w.msg.clone(),
+        }));
+    }
+    for w in warnings
+        .into_iter()
+        .filter(|w| levels.level(w.lint) == Level::Warn)
+    {
+        let text = format!("{} [{}]", w, w.lint);
+        if WARNED.with(|s| s.borrow_mut().insert(text)) {
+            options.output.emit_warning(Diagnostic::from(&w));
+        }
+    }
+    Ok(())
+}
+
+// Values of generics by name.
+type Values<T> = HashMap<String, T>;
+
+// Values of the generics of a chip, widths and strings apart.
+fn bind_generics(
+    hdl: &ChipHDL,
+    generics: &[GenericValue],
+) -> Result<(Values<usize>, Values<String>), Box<dyn Error>> {
+    if generics.len() != hdl.generic_decls.len() {
+        return Err(Box::new(N2VError {
+            msg: format!(
+                "Chip {} declares {} generics but instantiated with {}",
+                hdl.name,
+                hdl.generic_decls.len(),
+                generics.len()
+            ),
+            kind: ErrorKind::SimulationError(hdl.path.clone()),
+        }));
+    }
+    let mut variables = HashMap::new();
+    let mut strings = HashMap::new();
+    for (decl, g) in hdl.generic_decls.iter().zip(generics) {
+        match g {
+            GenericValue::Width(w) => {
+                variables.insert(decl.value.clone(), eval_expr_numeric(w, &HashMap::new())?);
+            }
+            GenericValue::Str(s) => {
+                strings.insert(decl.value.clone(), s.clone());
+            }
+        }
+    }
+    Ok((variables, strings))
+}
+
+/// Resolves everything a chip's HDL leaves open for the given generic
+/// arguments, without looking at how its parts connect.
+pub fn bind(
+    hdl: &ChipHDL,
+    generics: &[GenericValue],
+    provider: &Arc<dyn HdlProvider>,
+) -> Result<BoundChip, Box<dyn Error>> {
+    let hdl = &*with_implementation(hdl, provider)?;
+    let provider = &search_path(hdl, provider)?;
+    let hdl = with_constants(hdl, provider)?.into_owned();
+    let (variables, strings) = bind_generics(&hdl, generics)?;
+
+    // Signals for this component.
+    // Circuit graph holds the actual signal data.
+    let mut signals = BusMap::new();
+
+    // Create port signals
+    for port in &hdl.ports {
+        let width = eval_expr_numeric(&port.width, &variables)?;
+
+        if let Err(e) = signals.create_bus(&port.name.value, width) {
+            return Err(Box::new(N2VError {
+                msg: { format!("Cannot create port {}: {}", port.name.value, e) },
+                kind: ErrorKind::ParseIdentError(provider.clone(), port.name.clone()),
+            }));
+        }
+    }
+
+    // Create component definitions (expand for-generate loops).
+    let components = crate::simulator::Chip::expand_loops(&hdl, &variables)?;
+    let inferred_widths = infer_widths(&hdl, &components, provider, generics)?;
+
+    // Create disconnected internal signals.
+    for (n, iw) in inferred_widths {
+        // Ports already have their declared width. Connecting them to a
+        // port of another width is reported when the parts are connected.
+        if hdl.ports.iter().any(|p| p.name.value == n) {
+            continue;
+        }
+        // To create a full chip we need only numeric expressions.
+        if let GenericWidth::Terminal(Terminal::Num(x)) = iw {
+            if let Err(e) = signals.create_bus(&n, x) {
+                return Err(Box::new(N2VError {
+                    msg: {
+                        format!(
+                            "{:?} Cannot create internal signal {} of width {}. {}",
+                            hdl.path, n, iw, e
+                        )
+                    },
+                    kind: ErrorKind::SimulationError(hdl.path.clone()),
+                }));
+            }
+        } else {
+            return Err(Box::new(N2VError {
+                msg: {
+                    format!(
+                        "{:?} Cannot create internal signal {} of width {}.",
+                        hdl.path, n, iw
+                    )
+                },
+                kind: ErrorKind::SimulationError(hdl.path.clone()),
+            }));
+        }
+    }
+
+    let ports = hdl
+        .ports
+        .iter()
+        .map(|x| {
+            let pw = eval_expr_numeric(&x.width, &variables)?;
+            Ok((
+                x.name.value.clone(),
+                Port {
+                    direction: x.direction,
+                    name: x.name.clone(),
+                    width: pw,
+                },
+            ))
+        })
+        .collect::<Result<HashMap<String, Port>, N2VError>>()?;
+
+    Ok(BoundChip {
+        hdl,
+        provider: provider.clone(),
+        variables,
+        strings,
+        ports,
+        signals,
+        components,
+    })
+}
+
+/// Works out what each part of a chip connects to. Every port a part maps
+/// must exist, every input bit of a part must be mapped, no bit may have
+/// two sources, and every bit read by a part or an output port must have
+/// one.
+pub fn connect(
+    components: &[Component],
+    ports: &HashMap<String, Port>,
+    signals: &BusMap,
+    variables: &HashMap<String, usize>,
+    provider: &Arc<dyn HdlProvider>,
+    warnings: &mut Vec<Warning>,
+) -> Result<Vec<Instance>, Box<dyn Error>> {
+    let ident_error = |ident: &Identifier, msg: String| -> Box<dyn Error> {
+        Box::new(N2VError {
+            kind: ErrorKind::ParseIdentError(provider.clone(), ident.clone()),
+            msg,
+        })
+    };
+
+    // Which bits of each signal have a source. Inputs of the chip drive
+    // themselves.
+    let mut driven: HashMap<String, Vec<bool>> = ports
+        .values()
+        .filter(|p| p.direction == PortDirection::In)
+        .map(|p| (p.name.value.clone(), vec![true; p.width]))
+        .collect();
+    for literal in ["true", "false"] {
+        driven.insert(String::from(literal), vec![true; LITERAL_WIDTH]);
+    }
+
+    // What drives each signal, to list every driver of a signal that has
+    // more than one.
+    let mut drivers: Vec<Driver> = ports
+        .values()
+        .filter(|p| p.direction == PortDirection::In)
+        .map(|p| Driver {
+            wire: p.name.value.clone(),
+            bits: (0..p.width).collect(),
+            ident: &p.name,
+            source: format!("input {}", p.name.value),
+            tristate: false,
+        })
+        .collect();
+
+    let mut instances = Vec::new();
+    // Inputs of parts, checked once every source is known.
+    let mut reads: Vec<(String, usize, &Identifier)> = Vec::new();
+    for part in components {
+        let part_hdl = part_hdl(part, provider)?;
+        warnings.extend(shadowed_chip(&part_hdl));
+
+        // Convert generics with vars to concrete generics for component.
+        // e.g. Mux<W> needs to become Mux<4> if W=4. At this point
+        // we need actual bus widths.
+        let generics = resolve_generics(part, &part_hdl, variables)?;
+        let (part_variables, _) = bind_generics(&part_hdl, &generics)?;
+        check_generics(&part_hdl, &part_variables, provider)
+            .map_err(|msg| ident_error(&part.name, msg))?;
+
+        let mut provided: HashMap<&str, Vec<bool>> = HashMap::new();
+        let mut connections = Vec::new();
+        for m in &part.mappings {
+            let port = match part_hdl.get_port(&m.port.name) {
+                Ok(x) => x,
+                Err(_) => {
+                    return Err(ident_error(
+                        &m.wire_ident,
+                        format!(
+                            "Attempt to get non-existent port {}.{}",
+                            &m.port.name,
+                            did_you_mean(&m.port.name, &part_hdl.port_names())
+                        ),
+                    ));
+                }
+            };
+            if m.is_open() {
+                continue;
+            }
+
+            let port_width = eval_expr_numeric(&port.width, &part_variables)?;
+            let port_bits = eval_bus_bits(&m.port, port_width, variables, provider, &m.wire_ident)?;
+            if port_bits.iter().any(|&i| i >= port_width) {
+                return Err(ident_error(
+                    &m.wire_ident,
+                    format!(
+                        "{} is out of range: port {} of {} is {} bits wide{}.",
+                        m.port,
+                        port.name.value,
+                        part_hdl.name,
+                        port_width,
+                        declared_at(&port.name)
+                    ),
+                ));
+            }
+
+            // A whole signal is as wide as its declaration; literals fill
+            // whatever they are connected to.
+            let wire_width = match signals.get_width(&m.wire.name) {
+                Some(w) if m.wire.name != "true" && m.wire.name != "false" => w,
+                _ => port_bits.len(),
+            };
+            let wire_bits = eval_bus_bits(&m.wire, wire_width, variables, provider, &m.wire_ident)?;
+            if let Some(&j) = wire_bits.iter().find(|&&j| j >= wire_width) {
+                return Err(ident_error(
+                    &m.wire_ident,
+                    format!(
+                        "Bit {} of {} is out of range: signal {} is {} bits wide.",
+                        j, m.wire, m.wire.name, wire_width
+                    ),
+                ));
+            }
+            if wire_bits.len() != port_bits.len() {
+                return Err(ident_error(
+                    &m.wire_ident,
+                    format!(
+                        "Width mismatch: {} is {} bits wide but {} of {} is {} bits wide{}.",
+                        m.wire,
+                        wire_bits.len(),
+                        m.port,
+                        part_hdl.name,
+                        port_bits.len(),
+                        declared_at(&port.name)
+                    ),
+                ));
+            }
+            let reversed = m.port.descending != m.wire.descending;
+            let bits: Vec<(usize, usize)> = port_bits
+                .iter()
+                .enumerate()
+                .map(|(k, &i)| (i, reversed_bit(&wire_bits, k, reversed)))
+                .collect();
+
+            let wire = &m.wire.name;
+            if port.direction == PortDirection::In {
+                let used = provided
+                    .entry(&port.name.value)
+                    .or_insert_with(|| vec![false; port_width]);
+                for &(i, j) in &bits {
+                    used[i] = true;
+                    reads.push((wire.clone(), j, &m.wire_ident));
+                }
+            } else {
+                if wire == "true" || wire == "false" {
+                    return Err(ident_error(
+                        &m.wire_ident,
+                        format!("Signal {} cannot be driven by a part.", wire),
+                    ));
+                }
+                let width = signals.get_width(wire).unwrap();
+                let sources = driven
+                    .entry(wire.clone())
+                    .or_insert_with(|| vec![false; width]);
+                for &(_, j) in &bits {
+                    sources[j] = true;
+                }
+                drivers.push(Driver {
+                    wire: wire.clone(),
+                    bits: bits.iter().map(|&(_, j)| j).collect(),
+                    ident: &m.wire_ident,
+                    source: format!("{}.{}", part.name.value, port.name.value),
+                    tristate: drives_tristate(&part_hdl, &port.name.value, provider),
+                });
+            }
+            connections.push(Connection {
+                port: port.name.value.clone(),
+                direction: port.direction,
+                wire: wire.clone(),
+                bits,
+            });
+        }
+
+        // Inputs left unconnected read as unknown.
+        for port in &part_hdl.ports {
+            if port.direction == PortDirection::Out {
+                continue;
+            }
+            let msg = match provided.get(port.name.value.as_str()) {
+                Some(used) => {
+                    let missing: Vec<usize> = (0..used.len()).filter(|&i| !used[i]).collect();
+                    if missing.is_empty() {
+                        continue;
+                    }
+                    format!(
+                        "Part {} does not connect {} of input {}.",
+                        part.name.value,
+                        bit_ranges(&missing),
+                        port.name.value
+                    )
+                }
+                None if eval_expr_numeric(&port.width, &part_variables)? == 0 => continue,
+                None => format!(
+                    "Part {} does not connect input {}.",
+                    part.name.value, port.name.value
+                ),
+            };
+            warnings.push(Warning {
+                ident: part.name.clone(),
+                msg,
+                lint: Lint::UnconnectedInput,
+            });
+        }
+
+        instances.push(Instance {
+            hdl: part_hdl,
+            generics,
+            connections,
+        });
+    }
+
+    // A bit with more than one source is an error in the HDL, unless every
+    // source is a tri-state buffer.
+    let mut counts: HashMap<(&str, usize), (usize, usize)> = HashMap::new();
+    for d in &drivers {
+        for &j in &d.bits {
+            let (all, tristates) = counts.entry((&d.wire, j)).or_default();
+            *all += 1;
+            *tristates += d.tristate as usize;
+        }
+    }
+    let conflict = |d: &Driver, j: usize| {
+        let (all, tristates) = counts[&(d.wire.as_str(), j)];
+        all > 1 && tristates < all
+    };
+    if let Some(first) = drivers
+        .iter()
+        .find(|d| d.bits.iter().any(|&j| conflict(d, j)))
+    {
+        let mut bits: Vec<usize> = first
+            .bits
+            .iter()
+            .copied()
+            .filter(|&j| conflict(first, j))
+            .collect();
+        bits.sort_unstable();
+        bits.dedup();
+        let sharing: Vec<&Driver> = drivers
+            .iter()
+            .filter(|d| d.wire == first.wire && d.bits.iter().any(|j| bits.contains(j)))
+            .collect();
+        let mut msg = format!(
+            "Signal {} has {} drivers for {}:",
+            first.wire,
+            sharing.len(),
+            bit_ranges(&bits)
+        );
+        for d in &sharing {
+            msg.push_str(&format!("\n    {}{}", d.source, site(d.ident)));
+        }
+        if sharing.iter().any(|d| d.tristate) {
+            msg.push_str("\nOnly the outputs of TriState parts may share a bit.");
+        }
+        return Err(ident_error(sharing[1].ident, msg));
+    }
+
+    // Every bit read must have a source. Bits without one read as unknown.
+    let mut all_reads: Vec<(&str, usize, &Identifier)> = reads
+        .iter()
+        .map(|(wire, idx, ident)| (wire.as_str(), *idx, *ident))
+        .collect();
+    for port in ports.values() {
+        if port.direction == PortDirection::Out {
+            all_reads.extend((0..port.width).map(|j| (port.name.value.as_str(), j, &port.name)));
+        }
+    }
+    let mut unsourced: Vec<(&str, &Identifier, Vec<usize>)> = Vec::new();
+    for (wire, idx, ident) in all_reads {
+        match driven.get(wire) {
+            Some(bits) if idx >= bits.len() => {
+                return Err(ident_error(
+                    ident,
+                    format!("Bit {} for signal name {} is out of range.", idx, wire),
+                ));
+            }
+            Some(bits) if bits[idx] => {}
+            _ => match unsourced.last_mut() {
+                Some((w, i, missing)) if *w == wire && std::ptr::eq(*i, ident) => missing.push(idx),
+                _ => unsourced.push((wire, ident, vec![idx])),
+            },
+        }
+    }
+    for (wire, ident, mut missing) in unsourced {
+        missing.sort_unstable();
+        missing.dedup();
+        let msg = if !driven.contains_key(wire) {
+            format!("No source for signal name {}.", wire)
+        } else {
+            format!("No source for {} of signal {}.", bit_ranges(&missing), wire)
+        };
+        warnings.push(Warning {
+            ident: ident.clone(),
+            msg,
+            lint: Lint::NoSource,
+        });
+    }
+
+    // Signals of the chip's own that parts drive but nothing reads.
+    let read: HashSet<&str> = reads.iter().map(|(wire, _, _)| wire.as_str()).collect();
+    let mut unused: HashSet<&str> = HashSet::new();
+    for d in &drivers {
+        if !ports.contains_key(&d.wire) && !read.contains(d.wire.as_str()) && unused.insert(&d.wire)
+        {
+            warnings.push(Warning {
+                ident: d.ident.clone(),
+                msg: format!(
+                    "Signal {} is driven by {} but never read.",
+                    d.wire, d.source
+                ),
+                lint: Lint::UnusedWire,
+            });
+        }
+    }
+
+    Ok(instances)
+}
+
+// Checks what generic arguments make of a chip: each port must be at least
+// a bit wide, each loop must not end before it starts, and each slice of a
+// port must be within it. The message is for the part that passed the
+// arguments, since they are what is wrong.
+fn check_generics(
+    hdl: &ChipHDL,
+    variables: &HashMap<String, usize>,
+    provider: &Arc<dyn HdlProvider>,
+) -> Result<(), String> {
+    if hdl.generic_decls.is_empty() {
+        return Ok(());
+    }
+    let args: Vec<String> = hdl
+        .generic_decls
+        .iter()
+        .filter_map(|g| Some(format!("{}={}", g.value, variables.get(&g.value)?)))
+        .collect();
+    let problem = |what: String| {
+        format!(
+            "Generic arguments {} do not fit {}: {}.",
+            args.join(", "),
+            hdl.name,
+            what
+        )
+    };
+    let reason = |e: N2VError| String::from(e.msg.trim_end_matches('.'));
+
+    let mut widths = HashMap::new();
+    for port in &hdl.ports {
+        let width = eval_expr_numeric(&port.width, variables).map_err(|e| {
+            problem(format!(
+                "the width of port {}, {}{}",
+                port.name.value,
+                reason(e),
+                declared_at(&port.name)
+            ))
+        })?;
+        if width == 0 {
+            return Err(problem(format!(
+                "port {} would be 0 bits wide{}",
+                port.name.value,
+                declared_at(&port.name)
+            )));
+        }
+        widths.insert(port.name.value.as_str(), width);
+    }
+
+    for part in &hdl.parts {
+        if let Part::Loop(l) = part {
+            let bound = |w: &GenericWidth| {
+                eval_expr_numeric(w, variables).map_err(|e| {
+                    problem(format!(
+                        "the loop over {}, {}{}",
+                        l.iterator.value,
+                        reason(e),
+                        site(&l.iterator)
+                    ))
+                })
+            };
+            let (start, end) = (bound(&l.start)?, bound(&l.end)?);
+            if end < start {
+                return Err(problem(format!(
+                    "the loop over {} would run from {} down to {}{}",
+                    l.iterator.value,
+                    start,
+                    end,
+                    site(&l.iterator)
+                )));
+            }
+        }
+    }
+
+    let components =
+        crate::simulator::Chip::expand_loops(hdl, variables).map_err(|e| problem(reason(e)))?;
+    for c in &components {
+        for m in &c.mappings {
+            let width = match widths.get(m.wire.name.as_str()) {
+                Some(&w) => w,
+                None => continue,
+            };
+            let bits = eval_bus_bits(&m.wire, width, variables, provider, &m.wire_ident)
+                .map_err(|e| problem(format!("{}{}", reason(e), site(&m.wire_ident))))?;
+            if let Some(j) = bits.iter().find(|&&j| j >= width) {
+                return Err(problem(format!(
+                    "{} would use bit {} of port {}, which is {} bits wide{}",
+                    m.wire,
+                    j,
+                    m.wire.name,
+                    width,
+                    site(&m.wire_ident)
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+// A part output or chip input driving bits of a signal.
+struct Driver<'a> {
+    wire: String,
+    bits: Vec<usize>,
+    ident: &'a Identifier,
+    source: String, // e.g. `Inv.out` or `input a`.
+    tristate: bool, // Driven only by `TriState` parts, so it may share its bits.
+}
+
+/// Whether a part is a tri-state buffer, whose output may share a wire with
+/// other tri-state buffers.
+pub fn is_tristate(hdl: &ChipHDL) -> bool {
+    hdl.builtin_name().is_some_and(|b| b.value == "TriState")
+}
+
+/// Whether output `port` of a chip is driven only by tri-state buffers,
+/// followed down through the parts that drive it, so that a chip wrapping a
+/// `TriState` may share a wire like the buffer itself.
+pub fn drives_tristate(hdl: &ChipHDL, port: &str, provider: &Arc<dyn HdlProvider>) -> bool {
+    tristate_port(hdl, port, provider, &mut Vec::new())
+}
+
+// `above` holds the chips being followed, so that a chip that is a part of
+// itself ends the search.
+fn tristate_port(
+    hdl: &ChipHDL,
+    port: &str,
+    provider: &Arc<dyn HdlProvider>,
+    above: &mut Vec<String>,
+) -> bool {
+    if is_tristate(hdl) {
+        return true;
+    }
+    if hdl.builtin.is_some() || hdl.table.is_some() || above.contains(&hdl.name) {
+        return false;
+    }
+    above.push(hdl.name.clone());
+    let mut drivers = 0;
+    let mut all = true;
+    let components = hdl.parts.iter().flat_map(|p| match p {
+        Part::Component(c) => std::slice::from_ref(c),
+        Part::Loop(l) => l.body.as_slice(),
+    });
+    for part in components {
+        let mappings: Vec<&PortMapping> = part
+            .mappings
+            .iter()
+            .filter(|m| m.wire.name == port)
+            .collect();
+        if mappings.is_empty() {
+            continue;
+        }
+        let Ok(part_hdl) = get_hdl(&part.qualified_name(), provider) else {
+            all = false;
+            break;
+        };
+        for m in mappings {
+            let is_output = part_hdl
+                .ports
+                .iter()
+                .any(|p| p.name.value == m.port.name && p.direction == PortDirection::Out);
+            if is_output {
+                drivers += 1;
+                all &= tristate_port(&part_hdl, &m.port.name, provider, above);
+            }
+        }
+    }
+    above.pop();
+    all && drivers > 0
+}
+
+/// Where a port was declared, for errors raised at the part using it.
+pub fn declared_at(port: &Identifier) -> String {
+    match (&port.path, port.line) {
+        (Some(path), Some(line)) => format!(" (declared at {}:{})", path.display(), line),
+        _ => String::new(),
+    }
+}
+
+/// Where an identifier is written, e.g. ` at Top.hdl:3`.
+pub fn site(ident: &Identifier) -> String {
+    match (&ident.path, ident.line) {
+        (Some(path), Some(line)) => format!(" at {}:{}", path.display(), line),
+        (None, Some(line)) => format!(" at line {}", line),
+        _ => String::new(),
+    }
+}
+
+// Sorted bit numbers as ranges, e.g. `bits 0..3, 6`.
+fn bit_ranges(bits: &[usize]) -> String {
+    let mut ranges: Vec<String> = Vec::new();
+    let mut k = 0;
+    while k < bits.len() {
+        let start = bits[k];
+        while k + 1 < bits.len() && bits[k + 1] == bits[k] + 1 {
+            k += 1;
+        }
+        ranges.push(if bits[k] == start {
+            start.to_string()
+        } else {
+            format!("{}..{}", start, bits[k])
+        });
+        k += 1;
+    }
+    let noun = if bits.len() == 1 { "bit" } else { "bits" };
+    format!("{} {}", noun, ranges.join(", "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scanner::Scanner;
+    use std::fs;
+
+    #[test]
+    fn test_elaborate() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Inv.hdl"),
+            "CHIP Inv<W> { IN in[W]; OUT out[W]; PARTS:
+                FOR i IN 0 TO W - 1 GENERATE { Nand(a=in[i], b=in[i], out=out[i]); }
+            }",
+        )
+        .unwrap();
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let parse = |source: &str| {
+            let mut scanner = Scanner::new(source, dir.path().join("Top.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            parser.parse().expect("Parse error")
+        };
+
+        let hdl = parse(
+            "CHIP Top<W> { IN a[W]; OUT out[W], low; PARTS:
+                Inv<W>(in[0..3]=a[3..0], out=x, out[0]=low);
+                Inv<W>(in=x, out=out);
+            }",
+        );
+        let chip = elaborate(&hdl, &[GenericValue::from(4)], &provider).expect("Elaboration error");
+        assert_eq!(chip.chip.signals.get_width("x"), Some(4));
+        assert_eq!(chip.instances.len(), 2);
+        let inv = &chip.instances[0];
+        assert_eq!(inv.hdl.name, "Inv");
+        assert_eq!(inv.generics, vec![GenericValue::from(4)]);
+        assert_eq!(inv.connections[0].wire, "a");
+        assert_eq!(
+            inv.connections[0].bits,
+            vec![(0, 3), (1, 2), (2, 1), (3, 0)]
+        );
+
+        assert!(chip.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_unconnected() {
+        let dir = tempfile::tempdir().unwrap();
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let path = dir.path().join("Top.hdl");
+        let warnings = |source: &str| {
+            let mut scanner = Scanner::new(source, path.clone());
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse().expect("Parse error");
+            let chip = elaborate(&hdl, &[], &provider).expect("Elaboration error");
+            chip.warnings
+                .iter()
+                .map(|w| w.msg.clone())
+                .collect::<Vec<String>>()
+        };
+
+        // Nothing drives y, b of the second part, or bits 2..3 of out.
+        assert_eq!(
+            warnings(
+                "CHIP Top { IN a[2]; OUT out[4]; PARTS:
+                Nand(a=a[0], b=a[1], out=x);
+                Nand(a=x, b=y, out=out[0]);
+                Nand(a=x, out=out[1]);
+            }"
+            ),
+            vec![
+                "Part Nand does not connect input b.",
+                "No source for signal name y.",
+                "No source for bits 2..3 of signal out.",
+            ]
+        );
+
+        // z is driven but nothing reads it.
+        assert_eq!(
+            warnings(
+                "CHIP Top { IN a[2]; OUT out; PARTS:
+                Nand(a=a[0], b=a[1], out=out);
+                Nand(a=a[0], b=a[1], out=z);
+            }"
+            ),
+            vec!["Signal z is driven by Nand.out but never read."]
+        );
+        let w = Warning {
+            ident: Identifier {
+                value: String::from("y"),
+                path: Some(path.clone()),
+                line: Some(3),
+                span: None,
+            },
+            msg: String::from("No source for signal name y."),
+            lint: Lint::NoSource,
+        };
+        assert_eq!(
+            w.to_string(),
+            format!("{}:3: No source for signal name y.", path.display())
+        );
+    }
+
+    #[test]
+    fn test_width_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Inv.hdl"),
+            "CHIP Inv<W> {\n    IN in[W];\n    OUT out[W];\n    PARTS:\n    FOR i IN 0 TO W - 1 GENERATE { Nand(a=in[i], b=in[i], out=out[i]); }\n}",
+        )
+        .unwrap();
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let mismatch = |source: &str| {
+            let mut scanner = Scanner::new(source, dir.path().join("Top.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse().expect("Parse error");
+            let e = elaborate(&hdl, &[], &provider).err().unwrap();
+            e.downcast::<N2VError>().unwrap().msg
+        };
+        let declared = format!(" (declared at {}:2).", dir.path().join("Inv.hdl").display());
+
+        // An 8-bit input on a 16-bit port.
+        assert_eq!(
+            mismatch("CHIP Top { IN a[8]; OUT out[16]; PARTS: Inv<16>(in=a, out=out); }"),
+            format!(
+                "Width mismatch: a is 8 bits wide but in of Inv is 16 bits wide{}",
+                declared
+            )
+        );
+
+        // Slices of different widths.
+        assert_eq!(
+            mismatch("CHIP Top { IN a[8]; OUT out[8]; PARTS: Inv<8>(in[0..3]=a[0..1], in[4..7]=a[4..7], out=out); }"),
+            format!(
+                "Chip Top component Inv inferred width of signal a is 2, not equal to width of port in range which is 4{}",
+                declared
+            )
+        );
+
+        // A slice past the end of the port.
+        assert_eq!(
+            mismatch("CHIP Top { IN a[8]; OUT out[4]; PARTS: Inv<4>(in[0..7]=a, out=out); }"),
+            format!(
+                "in[0..7] is out of range: port in of Inv is 4 bits wide{}",
+                declared
+            )
+        );
+    }
+
+    #[test]
+    fn test_generic_constraints() {
+        let dir = tempfile::tempdir().unwrap();
+        let write =
+            |name: &str, contents: &str| fs::write(dir.path().join(name), contents).unwrap();
+        write(
+            "Inv.hdl",
+            "CHIP Inv<W> {\n    IN in[W];\n    OUT out[W];\n    PARTS:\n    FOR i IN 0 TO W - 1 GENERATE { Nand(a=in[i], b=in[i], out=out[i]); }\n}",
+        );
+        write(
+            "Pick.hdl",
+            "CHIP Pick<W> {\n    IN in[W];\n    OUT out;\n    PARTS:\n    Nand(a=in[2], b=in[2], out=out);\n}",
+        );
+        write(
+            "Drop.hdl",
+            "CHIP Drop<W> {\n    IN in[W - 2];\n    OUT out;\n    PARTS:\n    Nand(a=in[0], b=in[0], out=out);\n}",
+        );
+        write(
+            "Down.hdl",
+            "CHIP Down<W> {\n    IN in;\n    OUT out;\n    PARTS:\n    FOR i IN W TO 2 GENERATE { Nand(a=in, b=in, out=out); }\n}",
+        );
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let error = |source: &str| {
+            let mut scanner = Scanner::new(source, dir.path().join("Top.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse().expect("Parse error");
+            let e = elaborate(&hdl, &[], &provider).err().unwrap();
+            e.downcast::<N2VError>().unwrap().msg
+        };
+        let at = |file: &str, line: usize| format!("{}:{}", dir.path().join(file).display(), line);
+
+        assert_eq!(
+            error("CHIP Top { IN a; OUT out; PARTS: Inv<0>(in=a, out=out); }"),
+            format!(
+                "Generic arguments W=0 do not fit Inv: port in would be 0 bits wide (declared at {}).",
+                at("Inv.hdl", 2)
+            )
+        );
+        assert_eq!(
+            error("CHIP Top { IN a[2]; OUT out; PARTS: Pick<2>(in=a, out=out); }"),
+            format!(
+                "Generic arguments W=2 do not fit Pick: in[2] would use bit 2 of port in, which is 2 bits wide at {}.",
+                at("Pick.hdl", 5)
+            )
+        );
+        assert_eq!(
+            error("CHIP Top { IN a; OUT out; PARTS: Drop<1>(in=a, out=out); }"),
+            format!(
+                "Generic arguments W=1 do not fit Drop: the width of port in, (W - 2) is negative: 1 - 2 (declared at {}).",
+                at("Drop.hdl", 2)
+            )
+        );
+        assert_eq!(
+            error("CHIP Top { IN a; OUT out; PARTS: Down<3>(in=a, out=out); }"),
+            format!(
+                "Generic arguments W=3 do not fit Down: the loop over i would run from 3 down to 2 at {}.",
+                at("Down.hdl", 5)
+            )
+        );
+    }
+
+    #[test]
+    fn test_multiple_drivers() {
+        let dir = tempfile::tempdir().unwrap();
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let path = dir.path().join("Top.hdl");
+        let parse = |source: &str| {
+            let mut scanner = Scanner::new(source, path.clone());
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            parser.parse().expect("Parse error")
+        };
+        let drivers = |source: &str| {
+            let e = elaborate(&parse(source), &[], &provider).err().unwrap();
+            e.downcast::<N2VError>().unwrap().msg
+        };
+
+        // Three parts drive bit 0 of x.
+        assert_eq!(
+            drivers(
+                "CHIP Top { IN a; OUT out; PARTS:
+                Nand(a=a, b=a, out=x[0]);
+                Nand(a=a, b=a, out=x[0], out=x[1]);
+                Nand(a=a, b=a, out=x[0]);
+                Nand(a=x[0], b=x[1], out=out);
+            }"
+            ),
+            format!(
+                "Signal x has 3 drivers for bit 0:\n    Nand.out at {p}:2\n    Nand.out at {p}:3\n    Nand.out at {p}:4",
+                p = path.display()
+            )
+        );
+
+        // A part drives an input of the chip.
+        assert_eq!(
+            drivers(
+                "CHIP Top { IN a[2]; OUT out; PARTS: Nand(a=a[0], b=a[1], out=a[1], out=out); }"
+            ),
+            format!(
+                "Signal a has 2 drivers for bit 1:\n    input a at {p}:1\n    Nand.out at {p}:1",
+                p = path.display()
+            )
+        );
+
+        // Tri-state buffers share a bit, but nothing else may share it with
+        // them.
+        let shared = "CHIP Top { IN a, b, e; OUT out; PARTS:
+                TriState(in=a, enable=e, out=out);
+                Nand(a=e, b=e, out=ne);
+                TriState(in=b, enable=ne, out=out);
+            }";
+        assert!(elaborate(&parse(shared), &[], &provider).is_ok());
+        assert_eq!(
+            drivers(
+                "CHIP Top { IN a, e; OUT out; PARTS:
+                TriState(in=a, enable=e, out=out);
+                Nand(a=a, b=e, out=out);
+            }"
+            ),
+            format!(
+                "Signal out has 2 drivers for bit 0:\n    TriState.out at {p}:2\n    Nand.out at {p}:3\nOnly the outputs of TriState parts may share a bit.",
+                p = path.display()
+            )
+        );
+
+        // A chip whose output comes from a TriState shares a bit like the
+        // buffer, all the way down, but one that computes its output does
+        // not.
+        std::fs::write(
+            dir.path().join("Driver.hdl"),
+            "CHIP Driver { IN in, enable; OUT out; PARTS: TriState(in=in, enable=enable, out=out); }",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("Outer.hdl"),
+            "CHIP Outer { IN in, enable; OUT out; PARTS: Driver(in=in, enable=enable, out=out); }",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("Gated.hdl"),
+            "CHIP Gated { IN in, enable; OUT out; PARTS:
+                TriState(in=in, enable=enable, out=x);
+                Nand(a=x, b=x, out=out);
+            }",
+        )
+        .unwrap();
+        let wrapped = "CHIP Top { IN a, b, e; OUT out; PARTS:
+                Outer(in=a, enable=e, out=out);
+                Nand(a=e, b=e, out=ne);
+                TriState(in=b, enable=ne, out=out);
+            }";
+        assert!(elaborate(&parse(wrapped), &[], &provider).is_ok());
+        assert_eq!(
+            drivers(
+                "CHIP Top { IN a, b, e; OUT out; PARTS:
+                Gated(in=a, enable=e, out=out);
+                TriState(in=b, enable=e, out=out);
+            }"
+            ),
+            format!(
+                "Signal out has 2 drivers for bit 0:\n    Gated.out at {p}:2\n    TriState.out at {p}:3\nOnly the outputs of TriState parts may share a bit.",
+                p = path.display()
+            )
+        );
+    }
+}
@@ -0,0 +1,121 @@
+// Engines run a chip for a `Simulator`. The simulator interprets the tree of
+// parts it elaborates unless it is given another engine with
+// `Simulator::with_engine`, which is then asked to do everything callers ask
+// of the simulator, such as `run_test` simulating and ticking a chip.
+
+use crate::busmap::BusMap;
+use crate::compiled::CompiledEngine;
+use crate::event::EventEngine;
+#[cfg(feature = "jit")]
+use crate::jit::JitEngine;
+use crate::netlist::NetlistEngine;
+use crate::simulator::{Chip, Simulator};
+use std::error::Error;
+
+/// A way to run a chip.
+pub trait SimEngine: EngineClone + Send + Sync {
+    /// Sets the inputs of the chip and returns its outputs once they settle.
+    /// A bit nothing drives comes out as Z.
+    fn simulate(&mut self, inputs: &BusMap) -> Result<BusMap, Box<dyn Error>>;
+
+    /// Advances the clock without changing the inputs.
+    fn tick(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// Value of a port of the chip or a wire between its parts as of the
+    /// last `simulate`, highest bit first. None if the chip has no such
+    /// signal.
+    fn probe(&self, signal: &str) -> Option<Vec<Option<bool>>>;
+
+    /// Puts the chip back in the state it started in.
+    fn reset(&mut self) -> Result<(), Box<dyn Error>>;
+}
+
+/// Copies an engine behind a `Box`, so that simulators can be copied.
+/// Implemented for every engine that is `Clone`.
+pub trait EngineClone {
+    fn clone_box(&self) -> Box<dyn SimEngine>;
+}
+
+impl<T: SimEngine + Clone + 'static> EngineClone for T {
+    fn clone_box(&self) -> Box<dyn SimEngine> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn SimEngine> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// Engines a test can be run with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EngineKind {
+    Interpreter, // Elaborates parts as they are first simulated.
+    Event,       // Flattens the chip and evaluates only what changed.
+    Netlist,     // Flattens the chip and evaluates all of it in order.
+    Compiled,    // Compiles the flattened chip into a program of ops.
+    #[cfg(feature = "jit")]
+    Jit, // Compiles the flattened chip to native code.
+}
+
+impl std::str::FromStr for EngineKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interpreter" => Ok(EngineKind::Interpreter),
+            "event" => Ok(EngineKind::Event),
+            "netlist" => Ok(EngineKind::Netlist),
+            "compiled" => Ok(EngineKind::Compiled),
+            #[cfg(feature = "jit")]
+            "jit" => Ok(EngineKind::Jit),
+            _ => Err(format!(
+                "`{}` is not an engine. Use `interpreter`, `event`, `netlist` or `compiled`.",
+                s
+            )),
+        }
+    }
+}
+
+/// How `simulator` runs a chip.
+#[derive(Clone, Copy, Debug)]
+pub struct SimOptions {
+    /// The engine to run the chip with, `--engine` on the command line.
+    pub engine: EngineKind,
+    /// Threads engines that can share out their work run on.
+    pub threads: usize,
+}
+
+impl Default for SimOptions {
+    fn default() -> Self {
+        SimOptions {
+            engine: EngineKind::Interpreter,
+            threads: 1,
+        }
+    }
+}
+
+/// A simulator of `chip` run as `options` say.
+pub fn simulator(chip: Chip, options: &SimOptions) -> Result<Simulator, Box<dyn Error>> {
+    Ok(match options.engine {
+        EngineKind::Interpreter => Simulator::new(chip),
+        EngineKind::Event => {
+            let engine = EventEngine::new(&chip)?;
+            Simulator::with_engine(chip, Box::new(engine))
+        }
+        EngineKind::Netlist => {
+            let engine = NetlistEngine::new(&chip)?.with_threads(options.threads)?;
+            Simulator::with_engine(chip, Box::new(engine))
+        }
+        EngineKind::Compiled => {
+            let engine = CompiledEngine::new(&chip)?;
+            Simulator::with_engine(chip, Box::new(engine))
+        }
+        #[cfg(feature = "jit")]
+        EngineKind::Jit => {
+            let engine = JitEngine::new(&chip)?;
+            Simulator::with_engine(chip, Box::new(engine))
+        }
+    })
+}
@@ -1,19 +1,23 @@
 use crate::parser::HdlProvider;
+use crate::scanner::Span;
 use std::error::Error;
 use std::fs::File;
 use std::io::{self, BufRead};
 use std::path::PathBuf;
-use std::rc::Rc;
+use std::sync::Arc;
 
 // Error type enum
 pub enum ErrorKind {
     ParseError(crate::scanner::Token),
-    ParseIdentError(Rc<dyn HdlProvider>, crate::parser::Identifier),
+    ParseIdentError(Arc<dyn HdlProvider>, crate::parser::Identifier),
     TestParseError(crate::test_scanner::Token),
     SimulationError(Option<PathBuf>),
+    ElaborationError, // A chip that parses but cannot be built.
     IOError,
     Other,
     NonNumeric,
+    TestFailure, // A test script ran, but some of its steps failed.
+    Internal,    // A bug in whidl, such as a panic.
 }
 
 pub struct N2VError {
@@ -27,6 +31,40 @@ impl std::fmt::Debug for N2VError {
     }
 }
 
+impl N2VError {
+    /// The source text the error is about, if it is known.
+    pub fn span(&self) -> Option<Span> {
+        match &self.kind {
+            ErrorKind::ParseError(t) => Some(t.span()),
+            ErrorKind::ParseIdentError(_, ident) => ident.span,
+            _ => None,
+        }
+    }
+}
+
+// Marks the columns of `span` under a line printed as `N| text`. Spans that
+// continue onto later lines are marked to the end of the first line.
+fn underline(
+    f: &mut std::fmt::Formatter<'_>,
+    line_num: usize,
+    text: &str,
+    span: &Span,
+) -> std::fmt::Result {
+    let margin = line_num.to_string().len() + 2;
+    let start = span.start_col as usize;
+    let end = if span.end_line > span.start_line {
+        text.chars().count() + 1
+    } else {
+        span.end_col as usize
+    };
+    write!(
+        f,
+        "{}{}",
+        " ".repeat(margin + start - 1),
+        "^".repeat(end.saturating_sub(start))
+    )
+}
+
 impl std::fmt::Display for N2VError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         #[allow(unused_must_use)]
@@ -50,17 +88,10 @@ impl std::fmt::Display for N2VError {
                 }
 
                 let l = l.unwrap().unwrap();
-                let col = t.start;
-                let digits = line_num.to_string();
 
                 writeln!(f, "-- PARSE ERROR ----------- {}", t.path.clone().display());
                 writeln!(f, "{}| {}", t.line, l);
-                for _ in 0..(col + digits.len() + 2 - t.lexeme.len()) {
-                    write!(f, " ");
-                }
-                for _ in 0..t.lexeme.len() {
-                    write!(f, "^");
-                }
+                underline(f, line_num, &l, &t.span());
                 writeln!(f, "\n\n{}", self.msg)
             }
             ErrorKind::ParseIdentError(provider, ident) => {
@@ -98,6 +129,9 @@ impl std::fmt::Display for N2VError {
                     ident.path.as_ref().unwrap().clone().display()
                 );
                 writeln!(f, "{}| {}", line_num, l);
+                if let Some(span) = &ident.span {
+                    underline(f, line_num, l, span);
+                }
                 writeln!(f, "\n\n{}", self.msg)
             }
             _ => {
@@ -116,6 +150,15 @@ impl From<std::io::Error> for N2VError {
     }
 }
 
+impl From<crate::scanner::ScanError> for N2VError {
+    fn from(e: crate::scanner::ScanError) -> Self {
+        N2VError {
+            msg: e.msg,
+            kind: ErrorKind::ParseError(e.token),
+        }
+    }
+}
+
 impl From<String> for N2VError {
     fn from(e: String) -> Self {
         N2VError {
@@ -130,3 +173,213 @@ impl Error for N2VError {
         None
     }
 }
+
+/// Why an `HdlProvider` could not give the source of a file.
+#[derive(Debug)]
+pub enum ProviderError {
+    /// There is no such file. The message says where it was looked for.
+    NotFound(String),
+    /// The file is there, but reading it failed.
+    Io(std::io::Error),
+    /// The server could not be reached, or would not send the file.
+    Network { url: String, msg: String },
+    /// The file is not the one expected: its SHA-256 hash differs from the
+    /// one the provider was given.
+    Integrity {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl ProviderError {
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, ProviderError::NotFound(_))
+    }
+}
+
+impl Clone for ProviderError {
+    fn clone(&self) -> Self {
+        match self {
+            ProviderError::NotFound(msg) => ProviderError::NotFound(msg.clone()),
+            ProviderError::Io(e) => ProviderError::Io(std::io::Error::new(e.kind(), e.to_string())),
+            ProviderError::Network { url, msg } => ProviderError::Network {
+                url: url.clone(),
+                msg: msg.clone(),
+            },
+            ProviderError::Integrity {
+                url,
+                expected,
+                actual,
+            } => ProviderError::Integrity {
+                url: url.clone(),
+                expected: expected.clone(),
+                actual: actual.clone(),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProviderError::NotFound(msg) => write!(f, "{}", msg),
+            ProviderError::Io(e) => write!(f, "{}", e),
+            ProviderError::Network { url, msg } => write!(f, "Unable to fetch {}. {}", url, msg),
+            ProviderError::Integrity {
+                url,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} is not the expected file. Its SHA-256 hash is {}, not {}.",
+                url, actual, expected
+            ),
+        }
+    }
+}
+
+impl Error for ProviderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProviderError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ProviderError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => ProviderError::NotFound(e.to_string()),
+            _ => ProviderError::Io(e),
+        }
+    }
+}
+
+impl From<ProviderError> for N2VError {
+    fn from(e: ProviderError) -> Self {
+        N2VError {
+            msg: e.to_string(),
+            kind: ErrorKind::IOError,
+        }
+    }
+}
+
+/// The errors the parser, elaborator and simulator return, sorted by the
+/// stage that failed so callers can match on the kind of failure instead
+/// of reading the message.
+pub enum WhidlError {
+    /// Text that is not a token, such as an unclosed comment.
+    Scan(N2VError),
+    /// Tokens that do not form a chip or a test script.
+    Parse(N2VError),
+    /// A chip that parses but cannot be built, e.g. an unknown part or
+    /// mismatched widths.
+    Elaboration(N2VError),
+    /// A chip that failed while it ran, e.g. a signal that never settles.
+    Simulation(N2VError),
+    /// A file that could not be read or written.
+    Io(N2VError),
+    /// A test script that ran, but some of its steps failed.
+    TestFailure(N2VError),
+    Other(Box<dyn Error + Send + Sync>),
+}
+
+impl WhidlError {
+    /// The whidl error underneath, if it is one.
+    pub fn n2v(&self) -> Option<&N2VError> {
+        match self {
+            WhidlError::Scan(e)
+            | WhidlError::Parse(e)
+            | WhidlError::Elaboration(e)
+            | WhidlError::Simulation(e)
+            | WhidlError::Io(e)
+            | WhidlError::TestFailure(e) => Some(e),
+            WhidlError::Other(_) => None,
+        }
+    }
+
+    /// The message, without the source text the error is about.
+    pub fn msg(&self) -> String {
+        match self.n2v() {
+            Some(e) => e.msg.clone(),
+            None => self.to_string(),
+        }
+    }
+}
+
+impl From<N2VError> for WhidlError {
+    fn from(e: N2VError) -> Self {
+        match &e.kind {
+            ErrorKind::ParseError(t) if t.token_type == crate::scanner::TokenType::Invalid => {
+                WhidlError::Scan(e)
+            }
+            ErrorKind::ParseError(_) | ErrorKind::TestParseError(_) => WhidlError::Parse(e),
+            ErrorKind::ParseIdentError(..)
+            | ErrorKind::ElaborationError
+            | ErrorKind::NonNumeric => WhidlError::Elaboration(e),
+            ErrorKind::SimulationError(_) => WhidlError::Simulation(e),
+            ErrorKind::IOError => WhidlError::Io(e),
+            ErrorKind::TestFailure => WhidlError::TestFailure(e),
+            ErrorKind::Other | ErrorKind::Internal => WhidlError::Other(Box::new(e)),
+        }
+    }
+}
+
+impl From<Box<dyn Error>> for WhidlError {
+    fn from(e: Box<dyn Error>) -> Self {
+        let e = match e.downcast::<N2VError>() {
+            Ok(e) => return WhidlError::from(*e),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<WhidlError>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<ProviderError>() {
+            Ok(e) => return WhidlError::Io(N2VError::from(*e)),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<std::io::Error>() {
+            Ok(e) => return WhidlError::Io(N2VError::from(*e)),
+            Err(e) => e,
+        };
+        match e.downcast::<crate::exit::Failure>() {
+            Ok(e) => WhidlError::Other(e),
+            // Kept as its message, since the error may not be sendable to
+            // another thread, as a `WhidlError` is.
+            Err(e) => WhidlError::Other(e.to_string().into()),
+        }
+    }
+}
+
+impl From<ProviderError> for WhidlError {
+    fn from(e: ProviderError) -> Self {
+        WhidlError::Io(N2VError::from(e))
+    }
+}
+
+impl From<std::io::Error> for WhidlError {
+    fn from(e: std::io::Error) -> Self {
+        WhidlError::Io(N2VError::from(e))
+    }
+}
+
+impl std::fmt::Debug for WhidlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl std::fmt::Display for WhidlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self, self.n2v()) {
+            (WhidlError::Other(e), _) => write!(f, "{}", e),
+            (_, Some(e)) => write!(f, "{}", e),
+            (_, None) => Ok(()),
+        }
+    }
+}
+
+impl Error for WhidlError {}
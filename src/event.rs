@@ -0,0 +1,214 @@
+// An engine that only evaluates what has changed. The chip is flattened
+// into a netlist of NANDs, flip-flops and builtins that read and drive
+// numbered nets of one bit each. A cell is evaluated again only when a net it reads changes,
+// instead of every part of every chip being visited until the whole tree
+// settles, as the interpreter does.
+
+use crate::error::{ErrorKind, N2VError};
+use crate::logic::Logic;
+use crate::netlist::{flatten, FlatEngine, NetState};
+use crate::simulator::Chip;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::Arc;
+
+/// Runs a chip by evaluating only the cells whose inputs have changed.
+#[derive(Clone)]
+pub struct EventEngine {
+    state: NetState,
+    // Cells each net makes evaluate again, shared by copies of the engine.
+    readers: Arc<Vec<Vec<usize>>>,
+    // Cells to evaluate, and whether each one is waiting to be.
+    queue: VecDeque<usize>,
+    queued: Vec<bool>,
+}
+
+impl EventEngine {
+    /// Flattens `chip`, elaborating all of it, to run with the settle limit
+    /// and X policy the chip was built with. The chip itself is left as it
+    /// was.
+    pub fn new(chip: &Chip) -> Result<EventEngine, Box<dyn Error>> {
+        let netlist = flatten(chip)?;
+        let readers = netlist.readers();
+        let cells = netlist.cells.clone();
+        let mut engine = EventEngine {
+            state: NetState::new(&chip.name, netlist, cells),
+            readers: Arc::new(readers),
+            queue: VecDeque::new(),
+            queued: Vec::new(),
+        };
+        engine.state.follow(chip.options());
+        engine.queue_all();
+        Ok(engine)
+    }
+
+    // Everything is evaluated once, since nothing has been yet, and a
+    // builtin such as a keyboard may have no inputs to change.
+    fn queue_all(&mut self) {
+        self.queue = (0..self.state.cells.len()).collect();
+        self.queued = vec![true; self.state.cells.len()];
+    }
+
+    fn queue(&mut self, cell: usize) {
+        if !self.queued[cell] {
+            self.queued[cell] = true;
+            self.queue.push_back(cell);
+        }
+    }
+}
+
+impl FlatEngine for EventEngine {
+    type Value = Logic;
+
+    fn state(&self) -> &NetState {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut NetState {
+        &mut self.state
+    }
+
+    // Evaluates cells until no net changes.
+    fn settle(&mut self) -> Result<(), Box<dyn Error>> {
+        let limit = self
+            .state
+            .settle_limit
+            .saturating_mul(self.state.cells.len().max(1));
+        let mut evaluations = 0;
+        let mut changed = Vec::new();
+        while let Some(i) = self.queue.pop_front() {
+            self.queued[i] = false;
+            evaluations += 1;
+            if evaluations > limit {
+                return Err(Box::new(N2VError {
+                    msg: format!(
+                        "Chip {} did not settle after {} evaluations of its {} gates and builtins, so a combinational loop is oscillating. The limit is set with --settle-limit.",
+                        self.state.name,
+                        limit,
+                        self.state.cells.len()
+                    ),
+                    kind: ErrorKind::SimulationError(None),
+                }));
+            }
+            self.state.cells[i].evaluate(&self.state.values, self.state.x_policy, &mut changed);
+            for (net, value) in changed.drain(..) {
+                self.set(net, value);
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, net: usize, value: Logic) {
+        if self.state.values[net] == value {
+            return;
+        }
+        self.state.values[net] = value;
+        let readers = Arc::clone(&self.readers);
+        for &cell in &readers[net] {
+            self.queue(cell);
+        }
+    }
+
+    fn ticked(&mut self, cell: usize) {
+        self.queue(cell);
+    }
+
+    fn restarted(&mut self) {
+        self.queue_all();
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::busmap::BusMap;
+    use crate::engine::SimEngine;
+    use crate::parser::{FileReader, HdlProvider, Parser};
+    use crate::scanner::Scanner;
+    use crate::simulator::{Bus, ChipOptions, Simulator};
+    use std::path::Path;
+
+    pub fn make_chip(file_name: &str) -> Chip {
+        let dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/tests/nand2tetris/solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(dir.to_str().unwrap()));
+        let source = provider.get_hdl(file_name).unwrap();
+        let mut scanner = Scanner::new(&source, provider.get_path(file_name));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error")
+    }
+
+    // Runs the chip on the interpreter and the engine `make` builds with the
+    // same made-up inputs, ticking after each step, and checks that every
+    // signal of the top-level chip agrees.
+    pub fn check_same(file_name: &str, steps: usize, make: impl Fn(&Chip) -> Box<dyn SimEngine>) {
+        let mut interpreter = Simulator::new(make_chip(file_name));
+        let chip = make_chip(file_name);
+        let netlist = flatten(&chip).unwrap();
+        let mut engine = make(&chip);
+        let mut seed: u64 = 12345;
+        for step in 0..steps {
+            let mut inputs = BusMap::new();
+            for (name, nets) in &netlist.inputs {
+                let bits = (0..nets.len())
+                    .map(|_| {
+                        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                        Some(seed >> 62 == 0)
+                    })
+                    .collect();
+                inputs.create_bus(name, nets.len()).unwrap();
+                inputs.insert_option(&Bus::from(name.clone()), bits);
+            }
+            let expected = interpreter.simulate(&inputs).unwrap();
+            let actual = engine.simulate(&inputs).unwrap();
+            assert!(expected == actual, "{} step {}", file_name, step);
+            for signal in netlist.signals.keys() {
+                assert_eq!(
+                    interpreter.probe(signal),
+                    engine.probe(signal),
+                    "{} step {} signal {}",
+                    file_name,
+                    step,
+                    signal
+                );
+            }
+            interpreter.tick().unwrap();
+            engine.tick().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_event_engine() {
+        let engine =
+            |chip: &Chip| -> Box<dyn SimEngine> { Box::new(EventEngine::new(chip).unwrap()) };
+        for chip in ["Xor.hdl", "Mux8Way16.hdl", "DMux8Way.hdl", "ALU.hdl"] {
+            check_same(chip, 20, engine);
+        }
+        for chip in ["Bit.hdl", "Register.hdl", "PC.hdl", "RAM8.hdl", "CPU.hdl"] {
+            check_same(chip, 60, engine);
+        }
+    }
+
+    #[test]
+    fn test_event_engine_reset() {
+        let mut engine = EventEngine::new(&make_chip("Bit.hdl")).unwrap();
+        let set = BusMap::try_from([("in", vec![true]), ("load", vec![true])]).unwrap();
+        let hold = BusMap::try_from([("in", vec![false]), ("load", vec![false])]).unwrap();
+        engine.simulate(&set).unwrap();
+        engine.tick().unwrap();
+        let outputs = engine.simulate(&hold).unwrap();
+        assert_eq!(outputs.get_name("out"), vec![Some(true)]);
+        assert_eq!(engine.probe("dffOut"), Some(vec![Some(true)]));
+
+        // Copies run on their own.
+        let mut copy = engine.clone();
+        engine.reset().unwrap();
+        let outputs = engine.simulate(&hold).unwrap();
+        assert_eq!(outputs.get_name("out"), vec![Some(false)]);
+        let outputs = copy.simulate(&hold).unwrap();
+        assert_eq!(outputs.get_name("out"), vec![Some(true)]);
+    }
+}
@@ -0,0 +1,112 @@
+// Exit codes of the whidl command, so scripts and graders can tell kinds of
+// failure apart without reading the output. The numbers are stable: a new
+// kind of failure gets a new code instead of reusing an old one.
+
+use crate::error::{ErrorKind, N2VError, WhidlError};
+use std::error::Error;
+
+pub const SUCCESS: u8 = 0;
+/// Failures without a code of their own, e.g. a file that cannot be read.
+pub const ERROR: u8 = 1;
+// 2 is for bad command-line arguments, which clap reports itself.
+/// Syntax errors in HDL, test scripts, or scripts' compare files.
+pub const PARSE: u8 = 3;
+/// The HDL parses, but the chip cannot be built, e.g. an unknown part or
+/// mismatched widths.
+pub const ELABORATION: u8 = 4;
+pub const TEST_FAILED: u8 = 5;
+/// Internal errors, such as a panic, are bugs in whidl.
+pub const INTERNAL: u8 = 6;
+/// The run took longer than `--timeout`.
+pub const TIMEOUT: u8 = 7;
+
+/// Printed at the end of `--help`.
+pub const HELP: &str = "Exit codes:
+  0  Success
+  1  Other errors, such as a missing file
+  2  Bad command-line arguments
+  3  Parse error
+  4  Elaboration error
+  5  Test failures
+  6  Internal error
+  7  Timeout";
+
+/// A failure whose details the command has already printed.
+pub struct Failure {
+    pub code: u8,
+    pub msg: String,
+}
+
+impl std::fmt::Debug for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl Error for Failure {}
+
+/// The exit code for a command that failed with `e`.
+pub fn code(e: &(dyn Error + 'static)) -> u8 {
+    if let Some(f) = e.downcast_ref::<Failure>() {
+        return f.code;
+    }
+    match e.downcast_ref::<WhidlError>() {
+        Some(WhidlError::Other(e)) => return code(e.as_ref()),
+        Some(e) => return e.n2v().map_or(ERROR, |e| code(e)),
+        None => {}
+    }
+    match e.downcast_ref::<N2VError>().map(|e| &e.kind) {
+        Some(ErrorKind::ParseError(_) | ErrorKind::TestParseError(_)) => PARSE,
+        Some(
+            ErrorKind::ParseIdentError(..)
+            | ErrorKind::ElaborationError
+            | ErrorKind::SimulationError(_),
+        ) => ELABORATION,
+        Some(ErrorKind::TestFailure) => TEST_FAILED,
+        Some(ErrorKind::Internal) => INTERNAL,
+        _ => ERROR,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scanner::{Token, TokenType};
+    use std::path::PathBuf;
+
+    fn error(kind: ErrorKind) -> Box<dyn Error> {
+        Box::new(N2VError {
+            msg: String::from("Failed."),
+            kind,
+        })
+    }
+
+    #[test]
+    fn test_codes() {
+        let token = Token {
+            token_type: TokenType::Eof,
+            lexeme: String::new(),
+            line: 1,
+            start: 0,
+            path: PathBuf::from("Test.hdl"),
+        };
+        assert_eq!(code(&*error(ErrorKind::ParseError(token))), PARSE);
+        assert_eq!(code(&*error(ErrorKind::SimulationError(None))), ELABORATION);
+        assert_eq!(code(&*error(ErrorKind::TestFailure)), TEST_FAILED);
+        assert_eq!(code(&*error(ErrorKind::Internal)), INTERNAL);
+        assert_eq!(code(&*error(ErrorKind::IOError)), ERROR);
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+        assert_eq!(code(&io), ERROR);
+        let failure = Failure {
+            code: PARSE,
+            msg: String::from("2 errors in Test.hdl"),
+        };
+        assert_eq!(code(&failure), PARSE);
+    }
+}
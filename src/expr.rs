@@ -1,12 +1,13 @@
 //! AST for expressions in HDL programs.
-//! HDL Expressions are limited to addition and subtraction operators.
-//! The `Max` operator is for supporting "MAXIMUM" in synthesized VHDL expressions.
-//! `Max` cannot be used in HDL. Quartus Lite does not support VHDL 2008... ugh.
+//! HDL Expressions are limited to addition and subtraction operators and the
+//! functions `max(a, b)`, `min(a, b)`, `log2(n)`, and `if(a > b, x, y)`.
+//! `Max` is also used to infer widths. Quartus Lite does not support VHDL 2008,
+//! so it is simplified away wherever possible... ugh.
 
 use std::cmp::Ordering;
 use std::collections::HashMap;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::error::{ErrorKind, N2VError};
 use crate::parser::Identifier;
@@ -15,17 +16,99 @@ use crate::parser::Identifier;
 // - bus indices
 // - start,end in range loops
 // - port widths
-#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum GenericWidth {
     Expr(Op, Box<GenericWidth>, Box<GenericWidth>),
+    Log2(Box<GenericWidth>), // Rounded up, e.g. the address width of a memory.
+    If(Box<Condition>, Box<GenericWidth>, Box<GenericWidth>), // `if(W > 8, 2, 1)`.
     Terminal(Terminal),
 }
 
+/// A comparison of two widths, which picks a branch of `if`.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct Condition {
+    pub cmp: Cmp,
+    pub left: GenericWidth,
+    pub right: GenericWidth,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum Cmp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl Cmp {
+    /// The operator as written in HDL.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Cmp::Lt => "<",
+            Cmp::Le => "<=",
+            Cmp::Gt => ">",
+            Cmp::Ge => ">=",
+            Cmp::Eq => "==",
+            Cmp::Ne => "!=",
+        }
+    }
+
+    pub fn holds(&self, a: usize, b: usize) -> bool {
+        match self {
+            Cmp::Lt => a < b,
+            Cmp::Le => a <= b,
+            Cmp::Gt => a > b,
+            Cmp::Ge => a >= b,
+            Cmp::Eq => a == b,
+            Cmp::Ne => a != b,
+        }
+    }
+}
+
 impl GenericWidth {
     pub fn is_numeric(&self) -> bool {
         matches!(self, GenericWidth::Terminal(Terminal::Num(_)))
     }
+
+    /// Names of the variables in the expression, in order of appearance.
+    pub fn variables(&self) -> Vec<String> {
+        match self {
+            GenericWidth::Terminal(Terminal::Var(v)) => vec![v.value.clone()],
+            GenericWidth::Terminal(Terminal::Num(_)) => Vec::new(),
+            GenericWidth::Expr(_, a, b) => {
+                let mut vars = a.variables();
+                vars.extend(b.variables());
+                vars
+            }
+            GenericWidth::Log2(a) => a.variables(),
+            GenericWidth::If(c, a, b) => {
+                let mut vars = c.left.variables();
+                vars.extend(c.right.variables());
+                vars.extend(a.variables());
+                vars.extend(b.variables());
+                vars
+            }
+        }
+    }
+}
+
+/// Bits needed to count to `n`, i.e. log2 rounded up. 0 for 0 and 1.
+pub fn log2_ceil(n: usize) -> usize {
+    if n <= 1 {
+        0
+    } else {
+        (usize::BITS - (n - 1).leading_zeros()) as usize
+    }
+}
+
+impl From<usize> for GenericWidth {
+    fn from(n: usize) -> Self {
+        GenericWidth::Terminal(Terminal::Num(n))
+    }
 }
+
 impl std::ops::Add<GenericWidth> for GenericWidth {
     type Output = GenericWidth;
 
@@ -44,6 +127,22 @@ impl std::ops::Add<&GenericWidth> for &GenericWidth {
                 return GenericWidth::Terminal(Terminal::Num(x + y));
             }
         }
+        // A negative difference plus enough to make it up, e.g. (1 - 2) + 3.
+        for (sum, other) in [(self, rhs), (rhs, self)] {
+            if let (GenericWidth::Expr(Op::Sub, x, y), GenericWidth::Terminal(Terminal::Num(z))) =
+                (sum, other)
+            {
+                if let (
+                    GenericWidth::Terminal(Terminal::Num(x)),
+                    GenericWidth::Terminal(Terminal::Num(y)),
+                ) = (&**x, &**y)
+                {
+                    if x + z >= *y {
+                        return GenericWidth::Terminal(Terminal::Num(x + z - y));
+                    }
+                }
+            }
+        }
 
         GenericWidth::Expr(Op::Add, Box::new(self.clone()), Box::new(rhs.clone()))
     }
@@ -61,10 +160,13 @@ impl std::ops::Sub<&GenericWidth> for &GenericWidth {
     type Output = GenericWidth;
 
     fn sub(self, rhs: &GenericWidth) -> GenericWidth {
-        // Handle case where we can actually perform the subtraction.
+        // Handle case where we can actually perform the subtraction. A
+        // negative difference is left as it is, to be reported.
         if let GenericWidth::Terminal(Terminal::Num(x)) = self {
             if let GenericWidth::Terminal(Terminal::Num(y)) = rhs {
-                return GenericWidth::Terminal(Terminal::Num(x - y));
+                if x >= y {
+                    return GenericWidth::Terminal(Terminal::Num(x - y));
+                }
             }
         }
 
@@ -72,17 +174,65 @@ impl std::ops::Sub<&GenericWidth> for &GenericWidth {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize)]
+// The value of a generic argument. Most are widths, but strings can be
+// passed too, e.g. the file a memory is initialized from.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum GenericValue {
+    Width(GenericWidth),
+    Str(String),
+}
+
+impl GenericValue {
+    pub fn width(&self) -> Option<&GenericWidth> {
+        match self {
+            GenericValue::Width(w) => Some(w),
+            GenericValue::Str(_) => None,
+        }
+    }
+
+    /// Applies `f` to a width, leaving strings as they are.
+    pub fn map_width(&self, f: impl FnOnce(&GenericWidth) -> GenericWidth) -> GenericValue {
+        match self {
+            GenericValue::Width(w) => GenericValue::Width(f(w)),
+            GenericValue::Str(s) => GenericValue::Str(s.clone()),
+        }
+    }
+}
+
+impl From<usize> for GenericValue {
+    fn from(n: usize) -> Self {
+        GenericValue::Width(GenericWidth::from(n))
+    }
+}
+
+impl From<GenericWidth> for GenericValue {
+    fn from(w: GenericWidth) -> Self {
+        GenericValue::Width(w)
+    }
+}
+
+// Strings are written as VHDL string literals.
+impl std::fmt::Display for GenericValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GenericValue::Width(w) => write!(f, "{}", w),
+            GenericValue::Str(s) => write!(f, "\"{}\"", s),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum Terminal {
     Var(Identifier),
     Num(usize),
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum Op {
     Add,
     Sub,
     Max,
+    Min,
 }
 
 impl std::fmt::Display for GenericWidth {
@@ -101,11 +251,37 @@ impl std::fmt::Display for GenericWidth {
                 Op::Max => {
                     write!(f, "MAXIMUM({}, {})", a, b)
                 }
+                Op::Min => {
+                    write!(f, "MINIMUM({}, {})", a, b)
+                }
             },
+            GenericWidth::Log2(a) => {
+                write!(f, "integer(ceil(log2(real({}))))", a)
+            }
+            // VHDL-93 has no conditional expressions, so the branch not
+            // taken is multiplied by 0.
+            GenericWidth::If(c, a, b) => {
+                write!(
+                    f,
+                    "(boolean'pos({}) * {} + boolean'pos(not ({})) * {})",
+                    c, a, c, b
+                )
+            }
         }
     }
 }
 
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let op = match self.cmp {
+            Cmp::Eq => "=",
+            Cmp::Ne => "/=",
+            cmp => cmp.symbol(),
+        };
+        write!(f, "{} {} {}", self.left, op, self.right)
+    }
+}
+
 impl std::fmt::Display for Terminal {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -133,6 +309,11 @@ pub fn eval_expr_numeric(
 
     if let GenericWidth::Terminal(Terminal::Num(x)) = res {
         Ok(x)
+    } else if let Some((x, y)) = negative(&res) {
+        Err(N2VError {
+            msg: format!("{} is negative: {} - {}.", expr, x, y),
+            kind: ErrorKind::NonNumeric,
+        })
     } else {
         Err(N2VError {
             msg: format!("Expression {} is non-numeric", expr),
@@ -141,6 +322,23 @@ pub fn eval_expr_numeric(
     }
 }
 
+// A subtraction of numbers in an evaluated expression that would be
+// negative, if there is one.
+fn negative(expr: &GenericWidth) -> Option<(usize, usize)> {
+    match expr {
+        GenericWidth::Expr(Op::Sub, x, y) => match (&**x, &**y) {
+            (
+                GenericWidth::Terminal(Terminal::Num(x)),
+                GenericWidth::Terminal(Terminal::Num(y)),
+            ) => Some((*x, *y)),
+            _ => negative(x).or_else(|| negative(y)),
+        },
+        GenericWidth::Expr(_, x, y) => negative(x).or_else(|| negative(y)),
+        GenericWidth::Log2(x) => negative(x),
+        _ => None,
+    }
+}
+
 /// Evaluates a width expression based on the current state of variables.
 pub fn eval_expr(expr: &GenericWidth, state: &HashMap<String, GenericWidth>) -> GenericWidth {
     let res = match expr {
@@ -148,6 +346,40 @@ pub fn eval_expr(expr: &GenericWidth, state: &HashMap<String, GenericWidth>) ->
         GenericWidth::Expr(Op::Add, t1, t2) => eval_expr(t1, state) + eval_expr(t2, state),
         GenericWidth::Expr(Op::Sub, t1, t2) => eval_expr(t1, state) - eval_expr(t2, state),
         GenericWidth::Expr(Op::Max, t1, t2) => eval_max(eval_expr(t1, state), eval_expr(t2, state)),
+        GenericWidth::Expr(Op::Min, t1, t2) => eval_min(eval_expr(t1, state), eval_expr(t2, state)),
+        GenericWidth::Log2(t) => match eval_expr(t, state) {
+            GenericWidth::Terminal(Terminal::Num(n)) => {
+                GenericWidth::Terminal(Terminal::Num(log2_ceil(n)))
+            }
+            t => GenericWidth::Log2(Box::new(t)),
+        },
+        GenericWidth::If(c, a, b) => {
+            let (left, right) = (eval_expr(&c.left, state), eval_expr(&c.right, state));
+            match (&left, &right) {
+                (
+                    GenericWidth::Terminal(Terminal::Num(l)),
+                    GenericWidth::Terminal(Terminal::Num(r)),
+                ) => match c.cmp.holds(*l, *r) {
+                    true => eval_expr(a, state),
+                    false => eval_expr(b, state),
+                },
+                _ => {
+                    let (a, b) = (eval_expr(a, state), eval_expr(b, state));
+                    if a == b {
+                        return a;
+                    }
+                    GenericWidth::If(
+                        Box::new(Condition {
+                            cmp: c.cmp,
+                            left,
+                            right,
+                        }),
+                        Box::new(a),
+                        Box::new(b),
+                    )
+                }
+            }
+        }
     };
 
     // normalize (constant + var) to (var + constant)
@@ -166,7 +398,7 @@ pub fn eval_expr(expr: &GenericWidth, state: &HashMap<String, GenericWidth>) ->
     // (N - C) + D) = N - (C - D)   if C > D
     // (N - C) + D) = N             if C = D
     if let GenericWidth::Expr(Op::Add, lhs, rhs) = &res {
-        if let GenericWidth::Expr(op, lhs_lhs, lhs_rhs) = &**lhs {
+        if let GenericWidth::Expr(op @ (Op::Add | Op::Sub), lhs_lhs, lhs_rhs) = &**lhs {
             if let n @ GenericWidth::Terminal(Terminal::Var(x)) = &**lhs_lhs {
                 if let c @ GenericWidth::Terminal(Terminal::Num(c_num)) = &**lhs_rhs {
                     if let d @ GenericWidth::Terminal(Terminal::Num(d_num)) = &**rhs {
@@ -191,7 +423,7 @@ pub fn eval_expr(expr: &GenericWidth, state: &HashMap<String, GenericWidth>) ->
                                 Box::new(c.clone()),
                                 Box::new(d.clone()),
                             ),
-                            Op::Max => panic!(), // MAX should already have been evaluated or produced an error.
+                            Op::Max | Op::Min => unreachable!(),
                         };
                         let collapsed_expr = eval_expr(&collapse_expr, state);
 
@@ -202,7 +434,7 @@ pub fn eval_expr(expr: &GenericWidth, state: &HashMap<String, GenericWidth>) ->
                                 Ordering::Equal => panic!(),
                             },
                             Op::Add => Op::Add,
-                            Op::Max => panic!(),
+                            Op::Max | Op::Min => unreachable!(),
                         };
                         let finished = GenericWidth::Expr(
                             outer_op,
@@ -221,7 +453,7 @@ pub fn eval_expr(expr: &GenericWidth, state: &HashMap<String, GenericWidth>) ->
     // (N + C) - D) = N - (D - C)   if C < D
     // (N + C) - D) = N             if C = D
     if let GenericWidth::Expr(Op::Sub, lhs, rhs) = &res {
-        if let GenericWidth::Expr(op, lhs_lhs, lhs_rhs) = &**lhs {
+        if let GenericWidth::Expr(op @ (Op::Add | Op::Sub), lhs_lhs, lhs_rhs) = &**lhs {
             if let n @ GenericWidth::Terminal(Terminal::Var(x)) = &**lhs_lhs {
                 if let c @ GenericWidth::Terminal(Terminal::Num(c_num)) = &**lhs_rhs {
                     if let d @ GenericWidth::Terminal(Terminal::Num(d_num)) = &**rhs {
@@ -246,7 +478,7 @@ pub fn eval_expr(expr: &GenericWidth, state: &HashMap<String, GenericWidth>) ->
                                 Box::new(c.clone()),
                                 Box::new(d.clone()),
                             ),
-                            Op::Max => panic!(), // MAX should already have been evaluated or produced an error.
+                            Op::Max | Op::Min => unreachable!(),
                         };
                         let collapsed_expr = eval_expr(&collapse_expr, state);
                         let outer_op = match op {
@@ -256,7 +488,7 @@ pub fn eval_expr(expr: &GenericWidth, state: &HashMap<String, GenericWidth>) ->
                                 Ordering::Equal => panic!(),
                             },
                             Op::Sub => Op::Sub,
-                            Op::Max => panic!(),
+                            Op::Max | Op::Min => unreachable!(),
                         };
                         let finished = GenericWidth::Expr(
                             outer_op,
@@ -499,9 +731,20 @@ fn eval_max(t1: GenericWidth, t2: GenericWidth) -> GenericWidth {
         }
     }
 
-    // We don't know what to do. For example MAX(X, Y) is impossible.
-    // Ideally this would be disallowed in the HDL before we hit this panic.
-    panic!("I don't know how to simplify MAX({}, {}).", t1, t2);
+    // We don't know what to do. For example MAX(X, Y) depends on both X and Y.
+    GenericWidth::Expr(Op::Max, Box::new(t1), Box::new(t2))
+}
+
+fn eval_min(t1: GenericWidth, t2: GenericWidth) -> GenericWidth {
+    if let GenericWidth::Terminal(Terminal::Num(n1)) = t1 {
+        if let GenericWidth::Terminal(Terminal::Num(n2)) = t2 {
+            return GenericWidth::Terminal(Terminal::Num(std::cmp::min(n1, n2)));
+        }
+    }
+    if t1 == t2 {
+        return t1;
+    }
+    GenericWidth::Expr(Op::Min, Box::new(t1), Box::new(t2))
 }
 
 fn eval_terminal(terminal: &Terminal, state: &HashMap<String, GenericWidth>) -> GenericWidth {
@@ -523,6 +766,16 @@ pub fn replace_expr(w: &GenericWidth, m: &String, r: &GenericWidth) -> GenericWi
             Box::new(replace_expr(w1, m, r)),
             Box::new(replace_expr(w2, m, r)),
         ),
+        GenericWidth::Log2(w1) => GenericWidth::Log2(Box::new(replace_expr(w1, m, r))),
+        GenericWidth::If(c, w1, w2) => GenericWidth::If(
+            Box::new(Condition {
+                cmp: c.cmp,
+                left: replace_expr(&c.left, m, r),
+                right: replace_expr(&c.right, m, r),
+            }),
+            Box::new(replace_expr(w1, m, r)),
+            Box::new(replace_expr(w2, m, r)),
+        ),
     }
 }
 
@@ -1308,4 +1561,73 @@ mod test {
         let actual = eval_expr(&input, &state);
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn test_expr_functions() {
+        assert_eq!(log2_ceil(1), 0);
+        assert_eq!(log2_ceil(8), 3);
+        assert_eq!(log2_ceil(9), 4);
+
+        let depth = GenericWidth::Terminal(Terminal::Var(Identifier::from("DEPTH")));
+        let input = GenericWidth::Expr(
+            Op::Min,
+            Box::new(GenericWidth::Log2(Box::new(depth.clone()))),
+            Box::new(GenericWidth::Terminal(Terminal::Num(4))),
+        );
+        let mut state = HashMap::new();
+        assert_eq!(eval_expr(&input, &state), input);
+        state.insert(
+            String::from("DEPTH"),
+            GenericWidth::Terminal(Terminal::Num(12)),
+        );
+        assert_eq!(
+            eval_expr(&input, &state),
+            GenericWidth::Terminal(Terminal::Num(4))
+        );
+        state.insert(
+            String::from("DEPTH"),
+            GenericWidth::Terminal(Terminal::Num(5)),
+        );
+        assert_eq!(
+            eval_expr(&input, &state),
+            GenericWidth::Terminal(Terminal::Num(3))
+        );
+    }
+
+    #[test]
+    fn test_expr_if() {
+        let w = GenericWidth::Terminal(Terminal::Var(Identifier::from("W")));
+        let input = GenericWidth::If(
+            Box::new(Condition {
+                cmp: Cmp::Gt,
+                left: w.clone(),
+                right: GenericWidth::from(8),
+            }),
+            Box::new(GenericWidth::from(2)),
+            Box::new(w.clone()),
+        );
+        let mut state = HashMap::new();
+        assert_eq!(eval_expr(&input, &state), input);
+        assert_eq!(input.variables(), vec!["W", "W"]);
+        assert_eq!(
+            input.to_string(),
+            "(boolean'pos(W > 8) * 2 + boolean'pos(not (W > 8)) * W)"
+        );
+        state.insert(String::from("W"), GenericWidth::from(12));
+        assert_eq!(eval_expr(&input, &state), GenericWidth::from(2));
+        state.insert(String::from("W"), GenericWidth::from(8));
+        assert_eq!(eval_expr(&input, &state), GenericWidth::from(8));
+
+        // Branches that agree make the condition irrelevant.
+        let same = GenericWidth::If(
+            Box::new(Condition {
+                cmp: Cmp::Ne,
+                left: w.clone(),
+                right: GenericWidth::from(1),
+            }),
+            Box::new(GenericWidth::from(4)),
+            Box::new(GenericWidth::from(4)),
+        );
+        assert_eq!(eval_expr(&same, &HashMap::new()), GenericWidth::from(4));
+    }
 }
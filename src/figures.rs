@@ -0,0 +1,473 @@
+// Truth tables and Karnaugh maps for small combinational chips, computed by
+// simulating every combination of inputs. Figures can be printed as text,
+// LaTeX for reports, or SVG for web pages and slides.
+
+use std::error::Error;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use crate::busmap::BusMap;
+use crate::error::{ErrorKind, N2VError};
+use crate::expr::{GenericWidth, Terminal};
+use crate::parser::*;
+use crate::simulator::{Bus, Chip, ChipOptions, Simulator};
+
+// Truth tables have one row per input combination.
+const MAX_TRUTH_TABLE_INPUTS: usize = 10;
+
+// Karnaugh maps stop being readable beyond four variables.
+const MAX_KMAP_VARIABLES: usize = 4;
+
+// Size of a table cell in SVG figures.
+const SVG_CELL_WIDTH: usize = 48;
+const SVG_CELL_HEIGHT: usize = 24;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FigureFormat {
+    Text,
+    Latex,
+    Svg,
+}
+
+pub struct Column {
+    pub name: String,
+    pub width: usize,
+    pub direction: PortDirection,
+}
+
+/// Every port of a chip for every combination of its inputs.
+pub struct TruthTable {
+    pub columns: Vec<Column>, // Ports in the order they are declared.
+    /// Rows count up with the first input as the most significant bits, so
+    /// row `i` has inputs with the bits of `i`. Every row holds the bits of
+    /// each column, least significant first.
+    pub rows: Vec<Vec<Vec<Option<bool>>>>,
+}
+
+/// One output bit as a function of two to four input bits. Rows and
+/// columns are in Gray code order so that neighboring cells differ by one
+/// input bit.
+pub struct KarnaughMap {
+    pub output: String,
+    pub row_variables: Vec<String>,
+    pub column_variables: Vec<String>,
+    pub row_labels: Vec<String>,
+    pub column_labels: Vec<String>,
+    pub cells: Vec<Vec<Option<bool>>>,
+}
+
+/// Simulates every combination of inputs of a chip without generics, built
+/// with `options`.
+pub fn truth_table(
+    hdl: &ChipHDL,
+    provider: &Arc<dyn HdlProvider>,
+    options: &ChipOptions,
+) -> Result<TruthTable, Box<dyn Error>> {
+    let mut columns = Vec::new();
+    for p in &hdl.ports {
+        match p.width {
+            GenericWidth::Terminal(Terminal::Num(width)) => columns.push(Column {
+                name: p.name.value.clone(),
+                width,
+                direction: p.direction,
+            }),
+            _ => {
+                return Err(Box::new(N2VError {
+                    msg: format!(
+                        "Chip {} has generic widths, which truth tables do not support.",
+                        hdl.name
+                    ),
+                    kind: ErrorKind::Other,
+                }))
+            }
+        }
+    }
+    let total_width: usize = inputs(&columns).map(|c| c.width).sum();
+    if total_width > MAX_TRUTH_TABLE_INPUTS {
+        return Err(Box::new(N2VError {
+            msg: format!(
+                "Chip {} has {} input bits, truth tables support at most {}.",
+                hdl.name, total_width, MAX_TRUTH_TABLE_INPUTS
+            ),
+            kind: ErrorKind::Other,
+        }));
+    }
+
+    let chip = Chip::new(hdl, provider, options.clone())?;
+    let mut simulator = Simulator::new(chip);
+    let mut rows = Vec::new();
+    for row in 0..(1usize << total_width) {
+        let mut values = BusMap::new();
+        let mut remaining = total_width;
+        for c in inputs(&columns) {
+            remaining -= c.width;
+            // Buses hold their most significant bit first.
+            let bits: Vec<bool> = (0..c.width)
+                .rev()
+                .map(|b| (row >> (remaining + b)) & 1 == 1)
+                .collect();
+            values.create_bus(&c.name, c.width)?;
+            values.insert(Bus::from(c.name.clone()), bits);
+        }
+        let outputs = simulator.simulate(&values)?;
+        rows.push(
+            columns
+                .iter()
+                .map(|c| outputs.get_name(&c.name).into_iter().rev().collect())
+                .collect(),
+        );
+    }
+    Ok(TruthTable { columns, rows })
+}
+
+fn inputs(columns: &[Column]) -> impl Iterator<Item = &Column> {
+    columns.iter().filter(|c| c.direction == PortDirection::In)
+}
+
+// Name of one bit of a column, e.g. `a` or `a[2]`.
+fn bit_name(column: &Column, bit: usize) -> String {
+    if column.width == 1 {
+        column.name.clone()
+    } else {
+        format!("{}[{}]", column.name, bit)
+    }
+}
+
+/// Karnaugh map of one output bit, e.g. `out` or `out[2]`.
+pub fn karnaugh_map(table: &TruthTable, output: &str) -> Result<KarnaughMap, Box<dyn Error>> {
+    let kmap_error = |msg: String| -> Box<dyn Error> {
+        Box::new(N2VError {
+            msg,
+            kind: ErrorKind::Other,
+        })
+    };
+
+    let found = table.columns.iter().enumerate().find_map(|(i, c)| {
+        (0..c.width)
+            .find(|b| bit_name(c, *b) == output)
+            .map(|b| (i, c, b))
+    });
+    let (output_idx, output_bit) = match found {
+        Some((i, c, b)) if c.direction == PortDirection::Out => (i, b),
+        Some(_) => {
+            return Err(kmap_error(format!(
+                "{} is an input, not an output.",
+                output
+            )))
+        }
+        None => {
+            let names: Vec<String> = table
+                .columns
+                .iter()
+                .filter(|c| c.direction == PortDirection::Out)
+                .flat_map(|c| (0..c.width).map(move |b| bit_name(c, b)))
+                .collect();
+            return Err(kmap_error(format!(
+                "No output bit named {}.{}",
+                output,
+                did_you_mean(output, &names)
+            )));
+        }
+    };
+
+    // Input bits, most significant first, matching the order of row indices.
+    let variables: Vec<String> = inputs(&table.columns)
+        .flat_map(|c| (0..c.width).rev().map(move |b| bit_name(c, b)))
+        .collect();
+    if !(2..=MAX_KMAP_VARIABLES).contains(&variables.len()) {
+        return Err(kmap_error(format!(
+            "Karnaugh maps need 2 to {} input bits, the chip has {}.",
+            MAX_KMAP_VARIABLES,
+            variables.len()
+        )));
+    }
+
+    let (row_variables, column_variables) = variables.split_at(variables.len() / 2);
+    let row_codes = gray_code(row_variables.len());
+    let column_codes = gray_code(column_variables.len());
+    let cells = row_codes
+        .iter()
+        .map(|r| {
+            column_codes
+                .iter()
+                .map(|c| {
+                    let row = (r << column_variables.len()) | c;
+                    table.rows[row][output_idx][output_bit]
+                })
+                .collect()
+        })
+        .collect();
+
+    let labels = |codes: &[usize], n: usize| -> Vec<String> {
+        codes.iter().map(|c| format!("{:0n$b}", c, n = n)).collect()
+    };
+    Ok(KarnaughMap {
+        output: String::from(output),
+        row_labels: labels(&row_codes, row_variables.len()),
+        column_labels: labels(&column_codes, column_variables.len()),
+        row_variables: row_variables.to_vec(),
+        column_variables: column_variables.to_vec(),
+        cells,
+    })
+}
+
+// Values 0 to 2^n - 1 ordered so that neighbors differ by one bit.
+fn gray_code(n: usize) -> Vec<usize> {
+    (0..(1usize << n)).map(|i| i ^ (i >> 1)).collect()
+}
+
+fn bit_char(bit: Option<bool>) -> char {
+    match bit {
+        None => '?',
+        Some(true) => '1',
+        Some(false) => '0',
+    }
+}
+
+// Bits printed most significant first.
+fn bits_string(bits: &[Option<bool>]) -> String {
+    bits.iter().rev().map(|b| bit_char(*b)).collect()
+}
+
+impl TruthTable {
+    pub fn render(&self, format: FigureFormat) -> String {
+        let header: Vec<String> = self.columns.iter().map(|c| c.name.clone()).collect();
+        let rows: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|r| r.iter().map(|bits| bits_string(bits)).collect())
+            .collect();
+        // Inputs and outputs are separated by a rule.
+        let split = self
+            .columns
+            .iter()
+            .filter(|c| c.direction == PortDirection::In)
+            .count();
+        grid(&header, &rows, split, format)
+    }
+}
+
+impl KarnaughMap {
+    pub fn render(&self, format: FigureFormat) -> String {
+        let corner = format!(
+            "{}\\{}",
+            self.row_variables.join(""),
+            self.column_variables.join("")
+        );
+        let mut header = vec![corner];
+        header.extend(self.column_labels.iter().cloned());
+        let rows: Vec<Vec<String>> = self
+            .row_labels
+            .iter()
+            .zip(&self.cells)
+            .map(|(label, cells)| {
+                let mut row = vec![label.clone()];
+                row.extend(cells.iter().map(|c| bit_char(*c).to_string()));
+                row
+            })
+            .collect();
+        let figure = grid(&header, &rows, 1, format);
+        // LaTeX and SVG figures are captioned by the document using them.
+        match format {
+            FigureFormat::Text => format!("{}\n{}", self.output, figure),
+            _ => figure,
+        }
+    }
+}
+
+// A table with a rule after the header and after the first `split` columns.
+fn grid(header: &[String], rows: &[Vec<String>], split: usize, format: FigureFormat) -> String {
+    let mut out = String::new();
+    match format {
+        FigureFormat::Text => {
+            let widths: Vec<usize> = (0..header.len())
+                .map(|i| {
+                    rows.iter()
+                        .map(|r| r[i].chars().count())
+                        .chain([header[i].chars().count()])
+                        .max()
+                        .unwrap()
+                })
+                .collect();
+            let line = |cells: &[String]| -> String {
+                let mut s = String::new();
+                for (i, cell) in cells.iter().enumerate() {
+                    if i == split {
+                        s.push_str("| ");
+                    }
+                    write!(s, "{:w$} ", cell, w = widths[i]).unwrap();
+                }
+                String::from(s.trim_end())
+            };
+            writeln!(out, "{}", line(header)).unwrap();
+            let rule: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+            writeln!(out, "{}", line(&rule).replace(' ', "-")).unwrap();
+            for r in rows {
+                writeln!(out, "{}", line(r)).unwrap();
+            }
+        }
+        FigureFormat::Latex => {
+            let spec = format!("{}|{}", "c".repeat(split), "c".repeat(header.len() - split));
+            let line = |cells: &[String]| -> String {
+                let escaped: Vec<String> = cells.iter().map(|c| latex_escape(c)).collect();
+                format!("{} \\\\", escaped.join(" & "))
+            };
+            writeln!(out, "\\begin{{tabular}}{{{}}}", spec).unwrap();
+            writeln!(out, "{}", line(header)).unwrap();
+            writeln!(out, "\\hline").unwrap();
+            for r in rows {
+                writeln!(out, "{}", line(r)).unwrap();
+            }
+            writeln!(out, "\\end{{tabular}}").unwrap();
+        }
+        FigureFormat::Svg => {
+            let width = header.len() * SVG_CELL_WIDTH;
+            let height = (rows.len() + 1) * SVG_CELL_HEIGHT;
+            writeln!(
+                out,
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-family=\"monospace\" font-size=\"14\" text-anchor=\"middle\">",
+                width, height
+            )
+            .unwrap();
+            for (y, cells) in [header.to_vec()].iter().chain(rows).enumerate() {
+                for (x, cell) in cells.iter().enumerate() {
+                    writeln!(
+                        out,
+                        "  <text x=\"{}\" y=\"{}\">{}</text>",
+                        x * SVG_CELL_WIDTH + SVG_CELL_WIDTH / 2,
+                        y * SVG_CELL_HEIGHT + SVG_CELL_HEIGHT * 3 / 4,
+                        xml_escape(cell)
+                    )
+                    .unwrap();
+                }
+            }
+            let rules = [
+                (0, SVG_CELL_HEIGHT, width, SVG_CELL_HEIGHT),
+                (split * SVG_CELL_WIDTH, 0, split * SVG_CELL_WIDTH, height),
+            ];
+            for (x1, y1, x2, y2) in rules {
+                writeln!(
+                    out,
+                    "  <line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"black\"/>",
+                    x1, y1, x2, y2
+                )
+                .unwrap();
+            }
+            writeln!(out, "</svg>").unwrap();
+        }
+    }
+    out
+}
+
+fn latex_escape(s: &str) -> String {
+    s.replace('\\', "$\\backslash$").replace('_', "\\_")
+}
+
+pub(crate) fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scanner::Scanner;
+    use std::env;
+    use std::path::{Path, PathBuf};
+
+    fn mux_table() -> TruthTable {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let contents = provider.get_hdl("Mux.hdl").unwrap();
+        let mut scanner = Scanner::new(contents.as_str(), PathBuf::from("Mux.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        truth_table(&hdl, &provider, &ChipOptions::default()).expect("Truth table error")
+    }
+
+    #[test]
+    fn test_bus_bit_order() {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let contents = provider.get_hdl("DMux4Way.hdl").unwrap();
+        let mut scanner = Scanner::new(contents.as_str(), PathBuf::from("DMux4Way.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let table =
+            truth_table(&hdl, &provider, &ChipOptions::default()).expect("Truth table error");
+        // in=1, sel=01 selects b.
+        assert_eq!(
+            table.rows[5],
+            vec![
+                vec![Some(true)],
+                vec![Some(true), Some(false)],
+                vec![Some(false)],
+                vec![Some(true)],
+                vec![Some(false)],
+                vec![Some(false)],
+            ]
+        );
+        assert!(table
+            .render(FigureFormat::Text)
+            .contains("1  01  | 0 1 0 0"));
+    }
+
+    #[test]
+    fn test_truth_table() {
+        let table = mux_table();
+        assert_eq!(table.rows.len(), 8);
+        assert_eq!(
+            table.render(FigureFormat::Text),
+            "a b sel | out
+--------|----
+0 0 0   | 0
+0 0 1   | 0
+0 1 0   | 0
+0 1 1   | 1
+1 0 0   | 1
+1 0 1   | 0
+1 1 0   | 1
+1 1 1   | 1
+"
+        );
+        let latex = table.render(FigureFormat::Latex);
+        assert!(latex.starts_with("\\begin{tabular}{ccc|c}\na & b & sel & out \\\\\n\\hline\n"));
+        let svg = table.render(FigureFormat::Svg);
+        assert_eq!(svg.matches("<text").count(), 36);
+    }
+
+    #[test]
+    fn test_karnaugh_map() {
+        let table = mux_table();
+        let kmap = karnaugh_map(&table, "out").expect("K-map error");
+        assert_eq!(kmap.row_variables, vec!["a"]);
+        assert_eq!(kmap.column_variables, vec!["b", "sel"]);
+        assert_eq!(kmap.column_labels, vec!["00", "01", "11", "10"]);
+        assert_eq!(
+            kmap.render(FigureFormat::Text),
+            "out
+a\\bsel | 00 01 11 10
+-------|------------
+0      | 0  0  1  0
+1      | 1  0  1  1
+"
+        );
+
+        assert!(karnaugh_map(&table, "sel").is_err());
+        let e = karnaugh_map(&table, "ot").err().unwrap();
+        assert!(e.to_string().contains("Did you mean `out`?"));
+    }
+}
@@ -0,0 +1,347 @@
+// This module is responsible for taking a parsed Chip as input and
+// producing equivalent FIRRTL. Unlike the VHDL backend, FIRRTL has no
+// generics, so every instantiation of a chip with distinct generic
+// arguments becomes its own module, e.g. `Mux16_4`.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use crate::error::{ErrorKind, N2VError};
+use crate::expr::{eval_expr_numeric, GenericValue, GenericWidth};
+use crate::parser::*;
+use crate::simulator::{eval_bus_bits, infer_widths, reversed_bit, Chip};
+
+// Modules that have already been generated, kept in the order they were
+// created so that dependencies are emitted before the modules using them.
+struct Modules {
+    names: HashSet<String>,
+    bodies: Vec<String>,
+}
+
+/// Synthesizes a FIRRTL circuit for a top-level chip and all of its components.
+///
+/// `hdl` - HDL for the top-level chip. It must not declare generics.
+/// `provider` - Responsible for fetching HDL files
+pub fn synth_firrtl(
+    hdl: &ChipHDL,
+    provider: &Arc<dyn HdlProvider>,
+) -> Result<String, Box<dyn Error>> {
+    if !hdl.generic_decls.is_empty() {
+        return Err(Box::new(N2VError {
+            msg: format!(
+                "Top-level chip {} declares generics, FIRRTL export needs concrete widths.",
+                hdl.name
+            ),
+            kind: ErrorKind::Other,
+        }));
+    }
+
+    let mut modules = Modules {
+        names: HashSet::new(),
+        bodies: Vec::new(),
+    };
+    let top = module(hdl, &[], provider, &mut modules)?;
+
+    let mut firrtl = String::new();
+    writeln!(&mut firrtl, "FIRRTL version 1.1.0")?;
+    writeln!(&mut firrtl, "circuit {} :", top)?;
+    for body in &modules.bodies {
+        write!(&mut firrtl, "{}", body)?;
+    }
+
+    Ok(firrtl)
+}
+
+// FIRRTL module name for a chip instantiated with the given generics.
+fn module_name(hdl: &ChipHDL, generics: &[usize]) -> String {
+    let mut name = hdl.name.replace('.', "_");
+    for g in generics {
+        write!(&mut name, "_{}", g).unwrap();
+    }
+    name
+}
+
+fn width_type(width: usize) -> String {
+    format!("UInt<{}>", width)
+}
+
+// Concatenates single bit expressions. `bits` is ordered from bit 0 upward.
+fn cat_bits(bits: &[String]) -> String {
+    let mut iter = bits.iter();
+    let mut res = iter.next().unwrap().clone();
+    for b in iter {
+        res = format!("cat({}, {})", b, res);
+    }
+    res
+}
+
+// Source of a single bit for a wire name. True and false are literals.
+fn wire_bit(wire: &str, idx: usize) -> String {
+    match wire.to_lowercase().as_str() {
+        "true" => String::from("UInt<1>(1)"),
+        "false" => String::from("UInt<1>(0)"),
+        _ => format!("s_{}[{}]", wire, idx),
+    }
+}
+
+fn nand_module() -> String {
+    let mut m = String::new();
+    writeln!(&mut m, "  module NAND :").unwrap();
+    writeln!(&mut m, "    input clock : Clock").unwrap();
+    writeln!(&mut m, "    input a : UInt<1>").unwrap();
+    writeln!(&mut m, "    input b : UInt<1>").unwrap();
+    writeln!(&mut m, "    output out : UInt<1>").unwrap();
+    writeln!(&mut m, "    out <= not(and(a, b))").unwrap();
+    m
+}
+
+fn dff_module() -> String {
+    let mut m = String::new();
+    writeln!(&mut m, "  module DFF :").unwrap();
+    writeln!(&mut m, "    input clock : Clock").unwrap();
+    writeln!(&mut m, "    input in : UInt<1>").unwrap();
+    writeln!(&mut m, "    output out : UInt<1>").unwrap();
+    writeln!(&mut m, "    reg r : UInt<1>, clock").unwrap();
+    writeln!(&mut m, "    r <= in").unwrap();
+    writeln!(&mut m, "    out <= r").unwrap();
+    m
+}
+
+// Generates the module for hdl (and its components) if it does not exist yet.
+// Returns the name of the module.
+fn module(
+    hdl: &ChipHDL,
+    generics: &[usize],
+    provider: &Arc<dyn HdlProvider>,
+    modules: &mut Modules,
+) -> Result<String, Box<dyn Error>> {
+    let name = module_name(hdl, generics);
+    if modules.names.contains(&name) {
+        return Ok(name);
+    }
+
+    if hdl.name.to_uppercase() == "NAND" {
+        modules.names.insert(name.clone());
+        modules.bodies.push(nand_module());
+        return Ok(name);
+    } else if hdl.name.to_uppercase() == "DFF" {
+        modules.names.insert(name.clone());
+        modules.bodies.push(dff_module());
+        return Ok(name);
+    }
+    let hdl = &*with_implementation(hdl, provider)?;
+    let provider = &search_path(hdl, provider)?;
+    let hdl = &*with_constants(hdl, provider)?;
+
+    if hdl.table.is_some() {
+        return Err(Box::new(N2VError {
+            msg: format!("Chip {} is a TABLE and has no parts to convert.", hdl.name),
+            kind: ErrorKind::Other,
+        }));
+    }
+    if let Some(b) = hdl.builtin_name() {
+        if hdl.parts.is_empty() {
+            return Err(Box::new(N2VError {
+                msg: format!(
+                    "Chip {} is BUILTIN {} and has no parts to synthesize.",
+                    hdl.name, b.value
+                ),
+                kind: ErrorKind::Other,
+            }));
+        }
+    }
+
+    let variables: HashMap<String, usize> = hdl
+        .generic_decls
+        .iter()
+        .map(|x| x.value.clone())
+        .zip(generics.iter().cloned())
+        .collect();
+
+    let components = Chip::generate_components(hdl, &generics.to_vec())?;
+    let general_generics: Vec<GenericValue> =
+        generics.iter().map(|x| GenericValue::from(*x)).collect();
+    let inferred_widths = infer_widths(hdl, &components, provider, &general_generics)?;
+
+    let mut ports_firrtl = String::new();
+    let mut body = String::new();
+    let mut outputs = String::new();
+
+    writeln!(&mut ports_firrtl, "    input clock : Clock")?;
+
+    // Every signal is a vector of bits so that parts of a signal can be
+    // driven by different components.
+    let mut signal_widths: Vec<(String, usize)> = Vec::new();
+    for port in &hdl.ports {
+        let width = eval_expr_numeric(&port.width, &variables)?;
+        let direction = match port.direction {
+            PortDirection::In => "input",
+            PortDirection::Out => "output",
+        };
+        writeln!(
+            &mut ports_firrtl,
+            "    {} {} : {}",
+            direction,
+            port.name.value,
+            width_type(width)
+        )?;
+        signal_widths.push((port.name.value.clone(), width));
+
+        if port.direction == PortDirection::In {
+            for i in 0..width {
+                writeln!(
+                    &mut body,
+                    "    s_{}[{}] <= bits({}, {}, {})",
+                    port.name.value, i, port.name.value, i, i
+                )?;
+            }
+        } else {
+            let bits: Vec<String> = (0..width).map(|i| wire_bit(&port.name.value, i)).collect();
+            writeln!(
+                &mut outputs,
+                "    {} <= {}",
+                port.name.value,
+                cat_bits(&bits)
+            )?;
+        }
+    }
+
+    let mut internal: Vec<(&String, &GenericWidth)> = inferred_widths
+        .iter()
+        .filter(|(n, _)| !hdl.ports.iter().any(|p| &p.name.value == *n))
+        .collect();
+    internal.sort_by(|a, b| a.0.cmp(b.0));
+    for (n, w) in internal {
+        signal_widths.push((n.clone(), eval_expr_numeric(w, &variables)?));
+    }
+
+    let mut wires = String::new();
+    for (n, w) in &signal_widths {
+        writeln!(&mut wires, "    wire s_{} : UInt<1>[{}]", n, w)?;
+        writeln!(&mut wires, "    s_{} is invalid", n)?;
+    }
+
+    for (part_idx, part) in components.iter().enumerate() {
+        let part_hdl = get_hdl(&part.qualified_name(), provider)?;
+        let mut resolved_generics: Vec<usize> = Vec::new();
+        for (decl, g) in part.generic_args(&part_hdl)? {
+            let w = g.width().ok_or_else(|| N2VError {
+                msg: format!(
+                    "String generic {} of {} cannot be synthesized to FIRRTL.",
+                    decl.value, part_hdl.name
+                ),
+                kind: ErrorKind::Other,
+            })?;
+            resolved_generics.push(eval_expr_numeric(w, &variables)?);
+        }
+        let part_variables: HashMap<String, usize> = part_hdl
+            .generic_decls
+            .iter()
+            .map(|x| x.value.clone())
+            .zip(resolved_generics.iter().cloned())
+            .collect();
+        let part_module = module(&part_hdl, &resolved_generics, provider, modules)?;
+        let inst = format!("c{}", part_idx);
+
+        writeln!(&mut body, "    inst {} of {}", inst, part_module)?;
+        writeln!(&mut body, "    {}.clock <= clock", inst)?;
+
+        // Sources for each bit of each component input port.
+        let mut input_bits: HashMap<String, Vec<String>> = HashMap::new();
+        for port in &part_hdl.ports {
+            if port.direction == PortDirection::In {
+                let width = eval_expr_numeric(&port.width, &part_variables)?;
+                input_bits.insert(
+                    port.name.value.clone(),
+                    vec![String::from("UInt<1>(0)"); width],
+                );
+            }
+        }
+
+        for m in part.mappings.iter().filter(|m| !m.is_open()) {
+            let port = part_hdl.get_port(&m.port.name)?;
+            let port_width = eval_expr_numeric(&port.width, &part_variables)?;
+            let port_bits =
+                eval_bus_bits(&m.port, port_width, &variables, provider, &m.wire_ident)?;
+            let wire_bits =
+                eval_bus_bits(&m.wire, port_width, &variables, provider, &m.wire_ident)?;
+            let reversed = m.port.descending != m.wire.descending;
+
+            for (k, &port_bit) in port_bits.iter().enumerate() {
+                let wire_idx = reversed_bit(&wire_bits, k, reversed);
+                if port.direction == PortDirection::In {
+                    input_bits.get_mut(&m.port.name).unwrap()[port_bit] =
+                        wire_bit(&m.wire.name, wire_idx);
+                } else if !matches!(m.wire.name.to_lowercase().as_str(), "true" | "false") {
+                    writeln!(
+                        &mut body,
+                        "    s_{}[{}] <= bits({}.{}, {}, {})",
+                        m.wire.name, wire_idx, inst, m.port.name, port_bit, port_bit
+                    )?;
+                }
+            }
+        }
+
+        let mut input_names: Vec<&String> = input_bits.keys().collect();
+        input_names.sort();
+        for port_name in input_names {
+            writeln!(
+                &mut body,
+                "    {}.{} <= {}",
+                inst,
+                port_name,
+                cat_bits(&input_bits[port_name])
+            )?;
+        }
+    }
+
+    let mut m = String::new();
+    writeln!(&mut m, "  module {} :", name)?;
+    write!(&mut m, "{}", ports_firrtl)?;
+    write!(&mut m, "{}", wires)?;
+    write!(&mut m, "{}", body)?;
+    write!(&mut m, "{}", outputs)?;
+
+    modules.names.insert(name.clone());
+    modules.bodies.push(m);
+    Ok(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scanner::Scanner;
+    use std::env;
+    use std::path::Path;
+
+    #[test]
+    fn test_nand2tetris_solution_bit() {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let base_path = String::from(
+            manifest_dir
+                .join("resources")
+                .join("tests")
+                .join("nand2tetris")
+                .join("solutions")
+                .to_str()
+                .unwrap(),
+        );
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(&base_path));
+        let contents = provider.get_hdl("Bit.hdl").unwrap();
+        let mut scanner = Scanner::new(contents.as_str(), provider.get_path("Bit.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let firrtl = synth_firrtl(&hdl, &provider).expect("FIRRTL error");
+
+        assert!(firrtl.contains("circuit Bit :"));
+        assert!(firrtl.contains("  module NAND :"));
+        assert!(firrtl.contains("  module DFF :"));
+        assert!(firrtl.contains("  module Bit :"));
+        // Dependencies come before the modules that use them.
+        assert!(firrtl.find("module DFF").unwrap() < firrtl.find("module Bit ").unwrap());
+    }
+}
@@ -0,0 +1,467 @@
+// Extracts the state machine of a small sequential chip by simulation. Each
+// reachable state is found by replaying the inputs that first led to it from
+// power-on, then trying every input for one clock cycle. The result can be
+// printed as a Graphviz DOT graph or drawn directly as SVG.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use crate::busmap::BusMap;
+use crate::error::{ErrorKind, N2VError};
+use crate::expr::{GenericWidth, Terminal};
+use crate::figures::xml_escape;
+use crate::parser::*;
+use crate::simulator::{Bus, Chip, ChipOptions, Simulator};
+
+// Every state is tried with every combination of inputs.
+const MAX_INPUT_BITS: usize = 6;
+
+// Diagrams with more states than this are unreadable anyway.
+const MAX_STATES: usize = 32;
+
+// Layout of SVG diagrams. States are drawn on a circle.
+const SVG_STATE_RADIUS: f64 = 28.0;
+const SVG_MARGIN: f64 = 80.0;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiagramFormat {
+    Dot,
+    Svg,
+}
+
+/// An edge of the diagram, taken on the clock cycles where the inputs
+/// satisfy `condition`, e.g. `load & !reset`.
+pub struct Transition {
+    pub from: usize,
+    pub to: usize,
+    pub condition: String,
+}
+
+pub struct StateMachine {
+    pub chip: String,
+    /// States in the order they were reached, starting from power-on. Each
+    /// is labeled by the values of its flip-flops, see `Chip::state`.
+    pub states: Vec<String>,
+    pub transitions: Vec<Transition>,
+}
+
+// One input bit of the chip, e.g. `load` or `sel[1]`.
+struct InputBit {
+    port: String,
+    width: usize,
+    bit: usize,
+}
+
+impl InputBit {
+    fn name(&self) -> String {
+        if self.width == 1 {
+            self.port.clone()
+        } else {
+            format!("{}[{}]", self.port, self.bit)
+        }
+    }
+}
+
+/// Finds every state reachable from power-on and the inputs that move the
+/// chip between them. The chip is built with `options`.
+pub fn extract(
+    hdl: &ChipHDL,
+    provider: &Arc<dyn HdlProvider>,
+    options: &ChipOptions,
+) -> Result<StateMachine, Box<dyn Error>> {
+    let fsm_error = |msg: String| -> Box<dyn Error> {
+        Box::new(N2VError {
+            msg: format!("Chip {}: {}", hdl.name, msg),
+            kind: ErrorKind::ElaborationError,
+        })
+    };
+
+    // Most significant first, so input combinations count like a table.
+    let mut bits = Vec::new();
+    for p in hdl
+        .ports
+        .iter()
+        .filter(|p| p.direction == PortDirection::In)
+    {
+        let width = match p.width {
+            GenericWidth::Terminal(Terminal::Num(w)) => w,
+            _ => return Err(fsm_error(String::from("generic widths are not supported."))),
+        };
+        for bit in (0..width).rev() {
+            bits.push(InputBit {
+                port: p.name.value.clone(),
+                width,
+                bit,
+            });
+        }
+    }
+    if bits.len() > MAX_INPUT_BITS {
+        return Err(fsm_error(format!(
+            "{} input bits is too many, at most {} are supported.",
+            bits.len(),
+            MAX_INPUT_BITS
+        )));
+    }
+
+    let combinations = 1usize << bits.len();
+    let mut simulator = new_simulator(hdl, provider, options)?;
+    simulator.simulate(&input_values(&bits, 0)?)?;
+    let mut machine = StateMachine {
+        chip: hdl.name.clone(),
+        states: vec![simulator.chip.state()],
+        transitions: Vec::new(),
+    };
+    // Inputs applied on each cycle to first reach each state.
+    let mut paths: Vec<Vec<usize>> = vec![Vec::new()];
+
+    let mut next = 0;
+    while next < machine.states.len() {
+        let mut targets: HashMap<usize, Vec<usize>> = HashMap::new();
+        for input in 0..combinations {
+            let mut simulator = new_simulator(hdl, provider, options)?;
+            for i in paths[next].iter().chain([&input]) {
+                simulator.simulate(&input_values(&bits, *i)?)?;
+                simulator.tick()?;
+            }
+            let state = simulator.chip.state();
+            let to = match machine.states.iter().position(|s| *s == state) {
+                Some(idx) => idx,
+                None => {
+                    if machine.states.len() == MAX_STATES {
+                        return Err(fsm_error(format!(
+                            "more than {} reachable states.",
+                            MAX_STATES
+                        )));
+                    }
+                    machine.states.push(state);
+                    let mut path = paths[next].clone();
+                    path.push(input);
+                    paths.push(path);
+                    machine.states.len() - 1
+                }
+            };
+            targets.entry(to).or_default().push(input);
+        }
+
+        let mut targets: Vec<(usize, Vec<usize>)> = targets.into_iter().collect();
+        targets.sort();
+        for (to, inputs) in targets {
+            machine.transitions.push(Transition {
+                from: next,
+                to,
+                condition: condition(&bits, &inputs),
+            });
+        }
+        next += 1;
+    }
+
+    Ok(machine)
+}
+
+fn new_simulator(
+    hdl: &ChipHDL,
+    provider: &Arc<dyn HdlProvider>,
+    options: &ChipOptions,
+) -> Result<Simulator, Box<dyn Error>> {
+    let chip = Chip::new(hdl, provider, options.clone())?;
+    Ok(Simulator::new(chip))
+}
+
+// Input buses for one combination of input bits.
+fn input_values(bits: &[InputBit], combination: usize) -> Result<BusMap, String> {
+    let mut values = BusMap::new();
+    for (i, b) in bits.iter().enumerate() {
+        if values.get_width(&b.port).is_none() {
+            values.create_bus(&b.port, b.width)?;
+        }
+        let set = (combination >> (bits.len() - 1 - i)) & 1 == 1;
+        values.insert(
+            Bus {
+                name: b.port.clone(),
+                range: Some(b.bit..b.bit + 1),
+            },
+            vec![set],
+        );
+    }
+    Ok(values)
+}
+
+// Describes a set of input combinations as a sum of products, merging
+// combinations that differ in a single bit.
+fn condition(bits: &[InputBit], combinations: &[usize]) -> String {
+    // Cubes hold '0', '1', or '-' for a bit that does not matter.
+    let mut cubes: Vec<Vec<char>> = combinations
+        .iter()
+        .map(|c| format!("{:0n$b}", c, n = bits.len()).chars().collect())
+        .collect();
+    loop {
+        let mut merged = Vec::new();
+        let mut used = vec![false; cubes.len()];
+        for i in 0..cubes.len() {
+            for j in (i + 1)..cubes.len() {
+                let differing: Vec<usize> = (0..bits.len())
+                    .filter(|k| cubes[i][*k] != cubes[j][*k])
+                    .collect();
+                if differing.len() == 1
+                    && cubes[i][differing[0]] != '-'
+                    && cubes[j][differing[0]] != '-'
+                {
+                    let mut cube = cubes[i].clone();
+                    cube[differing[0]] = '-';
+                    if !merged.contains(&cube) {
+                        merged.push(cube);
+                    }
+                    used[i] = true;
+                    used[j] = true;
+                }
+            }
+        }
+        if merged.is_empty() {
+            break;
+        }
+        for (i, cube) in cubes.iter().enumerate() {
+            if !used[i] && !merged.contains(cube) {
+                merged.push(cube.clone());
+            }
+        }
+        cubes = merged;
+    }
+
+    let terms: Vec<String> = cubes
+        .iter()
+        .map(|cube| {
+            let literals: Vec<String> = cube
+                .iter()
+                .zip(bits)
+                .filter(|(c, _)| **c != '-')
+                .map(|(c, b)| {
+                    if *c == '1' {
+                        b.name()
+                    } else {
+                        format!("!{}", b.name())
+                    }
+                })
+                .collect();
+            literals.join(" & ")
+        })
+        .collect();
+    if terms.iter().any(|t| t.is_empty()) {
+        // Taken whatever the inputs are.
+        return String::from("*");
+    }
+    terms.join(" | ")
+}
+
+impl StateMachine {
+    pub fn render(&self, format: DiagramFormat) -> String {
+        match format {
+            DiagramFormat::Dot => self.dot(),
+            DiagramFormat::Svg => self.svg(),
+        }
+    }
+
+    fn dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph {} {{", self.chip).unwrap();
+        writeln!(out, "    rankdir=LR;").unwrap();
+        writeln!(out, "    start [shape=point];").unwrap();
+        for (i, s) in self.states.iter().enumerate() {
+            writeln!(out, "    s{} [shape=circle, label=\"{}\"];", i, s).unwrap();
+        }
+        writeln!(out, "    start -> s0;").unwrap();
+        for t in &self.transitions {
+            writeln!(
+                out,
+                "    s{} -> s{} [label=\"{}\"];",
+                t.from, t.to, t.condition
+            )
+            .unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    fn svg(&self) -> String {
+        let n = self.states.len();
+        let ring = if n == 1 {
+            0.0
+        } else {
+            (n as f64 * SVG_STATE_RADIUS * 3.0 / PI).max(SVG_STATE_RADIUS * 3.0)
+        };
+        let size = 2.0 * (ring + SVG_MARGIN + SVG_STATE_RADIUS);
+        let center = size / 2.0;
+        let position = |i: usize| -> (f64, f64) {
+            let angle = 2.0 * PI * i as f64 / n as f64 - PI / 2.0;
+            (center + ring * angle.cos(), center + ring * angle.sin())
+        };
+
+        let mut out = String::new();
+        writeln!(
+            out,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{:.0}\" height=\"{:.0}\" font-family=\"monospace\" font-size=\"12\" text-anchor=\"middle\">",
+            size, size
+        )
+        .unwrap();
+        writeln!(out, "  <defs><marker id=\"arrow\" viewBox=\"0 0 10 10\" refX=\"10\" refY=\"5\" markerWidth=\"8\" markerHeight=\"8\" orient=\"auto\"><path d=\"M0,0 L10,5 L0,10 z\"/></marker></defs>").unwrap();
+
+        for t in &self.transitions {
+            let (x1, y1) = position(t.from);
+            let (x2, y2) = position(t.to);
+            let label = xml_escape(&t.condition);
+            if t.from == t.to {
+                // Self loops point away from the center of the ring.
+                let (dx, dy) = unit(x1 - center, y1 - center).unwrap_or((0.0, -1.0));
+                let (lx, ly) = (
+                    x1 + dx * SVG_STATE_RADIUS * 2.2,
+                    y1 + dy * SVG_STATE_RADIUS * 2.2,
+                );
+                writeln!(
+                    out,
+                    "  <circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"{:.1}\" fill=\"none\" stroke=\"black\"/>",
+                    x1 + dx * SVG_STATE_RADIUS * 1.4,
+                    y1 + dy * SVG_STATE_RADIUS * 1.4,
+                    SVG_STATE_RADIUS * 0.6
+                )
+                .unwrap();
+                writeln!(
+                    out,
+                    "  <text x=\"{:.1}\" y=\"{:.1}\">{}</text>",
+                    lx,
+                    ly + 4.0,
+                    label
+                )
+                .unwrap();
+                continue;
+            }
+            // Edges in both directions are offset so they do not overlap.
+            let (dx, dy) = unit(x2 - x1, y2 - y1).unwrap();
+            let (ox, oy) = (-dy * 6.0, dx * 6.0);
+            let (sx, sy) = (
+                x1 + dx * SVG_STATE_RADIUS + ox,
+                y1 + dy * SVG_STATE_RADIUS + oy,
+            );
+            let (ex, ey) = (
+                x2 - dx * SVG_STATE_RADIUS + ox,
+                y2 - dy * SVG_STATE_RADIUS + oy,
+            );
+            writeln!(
+                out,
+                "  <line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"black\" marker-end=\"url(#arrow)\"/>",
+                sx, sy, ex, ey
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "  <text x=\"{:.1}\" y=\"{:.1}\">{}</text>",
+                (sx + ex) / 2.0 + ox * 2.0,
+                (sy + ey) / 2.0 + oy * 2.0,
+                label
+            )
+            .unwrap();
+        }
+
+        for (i, s) in self.states.iter().enumerate() {
+            let (x, y) = position(i);
+            // The power-on state has a double border.
+            let border = if i == 0 { " stroke-width=\"3\"" } else { "" };
+            writeln!(
+                out,
+                "  <circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"{:.1}\" fill=\"white\" stroke=\"black\"{}/>",
+                x, y, SVG_STATE_RADIUS, border
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "  <text x=\"{:.1}\" y=\"{:.1}\">{}</text>",
+                x,
+                y + 4.0,
+                xml_escape(s)
+            )
+            .unwrap();
+        }
+        writeln!(out, "</svg>").unwrap();
+        out
+    }
+}
+
+// Direction of a vector, or None if it has no length.
+fn unit(x: f64, y: f64) -> Option<(f64, f64)> {
+    let length = (x * x + y * y).sqrt();
+    if length == 0.0 {
+        None
+    } else {
+        Some((x / length, y / length))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scanner::Scanner;
+    use std::env;
+    use std::path::{Path, PathBuf};
+
+    fn extract_hdl(hdl: &str) -> StateMachine {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let mut scanner = Scanner::new(hdl, PathBuf::from("Fsm.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        extract(&hdl, &provider, &ChipOptions::default()).expect("Extraction error")
+    }
+
+    #[test]
+    fn test_extract_bit() {
+        let machine = extract_hdl(
+            "CHIP MyBit { IN in, load; OUT out; PARTS:
+                Mux(a=dffOut, b=in, sel=load, out=muxOut);
+                DFF(in=muxOut, out=out, out=dffOut); }",
+        );
+        assert_eq!(machine.states, vec!["0", "1"]);
+        let edges: Vec<(usize, usize, &str)> = machine
+            .transitions
+            .iter()
+            .map(|t| (t.from, t.to, t.condition.as_str()))
+            .collect();
+        assert_eq!(
+            edges,
+            vec![
+                (0, 0, "!in | !load"),
+                (0, 1, "in & load"),
+                (1, 0, "!in & load"),
+                (1, 1, "!load | in"),
+            ]
+        );
+        let dot = machine.render(DiagramFormat::Dot);
+        assert!(dot.contains("s0 -> s1 [label=\"in & load\"];"));
+        let svg = machine.render(DiagramFormat::Svg);
+        assert_eq!(svg.matches("marker-end").count(), 2);
+    }
+
+    #[test]
+    fn test_extract_counter() {
+        // Two bit counter that counts while `en` is set.
+        let machine = extract_hdl(
+            "CHIP Count2 { IN en; OUT lo, hi; PARTS:
+                Xor(a=l, b=en, out=nl);
+                And(a=l, b=en, out=carry);
+                Xor(a=h, b=carry, out=nh);
+                DFF(in=nl, out=l, out=lo);
+                DFF(in=nh, out=h, out=hi); }",
+        );
+        assert_eq!(machine.states.len(), 4);
+        assert!(machine
+            .transitions
+            .iter()
+            .all(|t| t.condition == "en" || t.condition == "!en"));
+    }
+}
@@ -0,0 +1,703 @@
+// Compiles state machine descriptions into structural HDL. A description
+// lists the states of a machine, the transitions between them, and the
+// outputs set in each state or on each transition:
+//
+//     FSM Traffic {
+//         IN go, stop;
+//         OUT red, green, yellow, beep;
+//
+//         STATE Red OUT red {
+//             go -> Green;
+//         }
+//         STATE Green OUT green {
+//             stop -> Yellow OUT beep;
+//         }
+//         STATE Yellow OUT yellow {
+//             -> Red;
+//         }
+//     }
+//
+// The first state is the power-on state. Transitions are tried in order and
+// the machine stays in its state when none is taken. A transition without a
+// condition is always taken. Conditions combine inputs with `!`, `&`, `|`,
+// and parentheses.
+//
+// The generated chip holds a binary state code in DFFs, with the power-on
+// state encoded as zero, and builds its next-state and output logic from
+// Not, And, and Or.
+
+use std::error::Error;
+use std::fmt::Write;
+
+use crate::error::{ErrorKind, N2VError};
+use crate::scanner::{is_name_continue, is_name_start};
+
+// Generated wires start with this prefix, so user names may not.
+const RESERVED_PREFIX: &str = "fsm";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Condition {
+    Input(String),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+pub struct Transition {
+    /// None if the transition is always taken.
+    pub condition: Option<Condition>,
+    pub target: String,
+    /// Outputs set on the clock cycle the transition is taken.
+    pub outputs: Vec<String>,
+}
+
+pub struct State {
+    pub name: String,
+    /// Outputs set while the machine is in this state.
+    pub outputs: Vec<String>,
+    pub transitions: Vec<Transition>,
+}
+
+pub struct StateMachine {
+    pub name: String,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    pub states: Vec<State>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum TokenType {
+    Identifier,
+    Symbol,
+    Eof,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    token_type: TokenType,
+    lexeme: String,
+    line: usize,
+    col: usize,
+}
+
+fn fsm_error(token: &Token, msg: &str) -> Box<dyn Error> {
+    Box::new(N2VError {
+        msg: format!("line {}, column {}: {}", token.line, token.col, msg),
+        kind: ErrorKind::ElaborationError,
+    })
+}
+
+fn scan(source: &str) -> Result<Vec<Token>, Box<dyn Error>> {
+    let mut tokens = Vec::new();
+    for (line_idx, line) in source.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let col = i + 1;
+            let token = |token_type, lexeme: String| Token {
+                token_type,
+                lexeme,
+                line: line_idx + 1,
+                col,
+            };
+            if c.is_whitespace() {
+                i += 1;
+            } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+                break;
+            } else if is_name_start(c) {
+                let start = i;
+                while i < chars.len() && is_name_continue(chars[i]) {
+                    i += 1;
+                }
+                let lexeme: String = chars[start..i].iter().collect();
+                tokens.push(token(TokenType::Identifier, lexeme));
+            } else if c == '-' && chars.get(i + 1) == Some(&'>') {
+                tokens.push(token(TokenType::Symbol, String::from("->")));
+                i += 2;
+            } else if "{};,()!&|".contains(c) {
+                tokens.push(token(TokenType::Symbol, c.to_string()));
+                i += 1;
+            } else {
+                return Err(fsm_error(
+                    &token(TokenType::Symbol, c.to_string()),
+                    &format!("Unexpected character `{}`.", c),
+                ));
+            }
+        }
+    }
+    tokens.push(Token {
+        token_type: TokenType::Eof,
+        lexeme: String::new(),
+        line: source.lines().count() + 1,
+        col: 1,
+    });
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    current: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.current]
+    }
+
+    fn advance(&mut self) -> Token {
+        let t = self.tokens[self.current].clone();
+        if t.token_type != TokenType::Eof {
+            self.current += 1;
+        }
+        t
+    }
+
+    fn check(&self, lexeme: &str) -> bool {
+        self.peek().lexeme == lexeme
+    }
+
+    fn consume(&mut self, lexeme: &str) -> Result<Token, Box<dyn Error>> {
+        if self.check(lexeme) {
+            Ok(self.advance())
+        } else {
+            Err(self.unexpected(&format!("`{}`", lexeme)))
+        }
+    }
+
+    fn identifier(&mut self) -> Result<Token, Box<dyn Error>> {
+        if self.peek().token_type == TokenType::Identifier {
+            Ok(self.advance())
+        } else {
+            Err(self.unexpected("a name"))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> Box<dyn Error> {
+        let t = self.peek();
+        let found = match t.token_type {
+            TokenType::Eof => String::from("the end of the file"),
+            _ => format!("`{}`", t.lexeme),
+        };
+        fsm_error(t, &format!("I expected {} but found {}.", expected, found))
+    }
+
+    // A comma separated list of names, ending at the semicolon or curly brace
+    // after it.
+    fn names(&mut self) -> Result<Vec<Token>, Box<dyn Error>> {
+        let mut names = vec![self.identifier()?];
+        while self.check(",") {
+            self.advance();
+            names.push(self.identifier()?);
+        }
+        Ok(names)
+    }
+
+    fn state_machine(&mut self) -> Result<(StateMachine, Vec<Token>), Box<dyn Error>> {
+        self.consume("FSM")?;
+        let name = self.identifier()?.lexeme;
+        self.consume("{")?;
+
+        let mut machine = StateMachine {
+            name,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            states: Vec::new(),
+        };
+        // Every name in the description, kept for error messages.
+        let mut uses = Vec::new();
+        while self.check("IN") || self.check("OUT") {
+            let direction = self.advance();
+            let names = self.names()?;
+            self.consume(";")?;
+            let ports = if direction.lexeme == "IN" {
+                &mut machine.inputs
+            } else {
+                &mut machine.outputs
+            };
+            ports.extend(names.iter().map(|t| t.lexeme.clone()));
+            uses.extend(names);
+        }
+
+        while self.check("STATE") {
+            self.advance();
+            let name = self.identifier()?;
+            let outputs = self.outputs(&mut uses)?;
+            let mut state = State {
+                name: name.lexeme.clone(),
+                outputs,
+                transitions: Vec::new(),
+            };
+            uses.push(name);
+            if self.check(";") {
+                self.advance();
+            } else {
+                self.consume("{")?;
+                while !self.check("}") {
+                    state.transitions.push(self.transition(&mut uses)?);
+                }
+                self.advance();
+            }
+            machine.states.push(state);
+        }
+        if machine.states.is_empty() {
+            return Err(self.unexpected("IN, OUT, or STATE"));
+        }
+        if !self.check("}") {
+            return Err(self.unexpected("STATE or `}`"));
+        }
+        self.advance();
+        if self.peek().token_type != TokenType::Eof {
+            return Err(self.unexpected("the end of the file"));
+        }
+        Ok((machine, uses))
+    }
+
+    fn outputs(&mut self, uses: &mut Vec<Token>) -> Result<Vec<String>, Box<dyn Error>> {
+        if !self.check("OUT") {
+            return Ok(Vec::new());
+        }
+        self.advance();
+        let names = self.names()?;
+        let outputs = names.iter().map(|t| t.lexeme.clone()).collect();
+        uses.extend(names);
+        Ok(outputs)
+    }
+
+    fn transition(&mut self, uses: &mut Vec<Token>) -> Result<Transition, Box<dyn Error>> {
+        let condition = if self.check("->") {
+            None
+        } else {
+            Some(self.or(uses)?)
+        };
+        self.consume("->")?;
+        let target = self.identifier()?;
+        let outputs = self.outputs(uses)?;
+        self.consume(";")?;
+        let transition = Transition {
+            condition,
+            target: target.lexeme.clone(),
+            outputs,
+        };
+        uses.push(target);
+        Ok(transition)
+    }
+
+    fn or(&mut self, uses: &mut Vec<Token>) -> Result<Condition, Box<dyn Error>> {
+        let mut condition = self.and(uses)?;
+        while self.check("|") {
+            self.advance();
+            condition = Condition::Or(Box::new(condition), Box::new(self.and(uses)?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self, uses: &mut Vec<Token>) -> Result<Condition, Box<dyn Error>> {
+        let mut condition = self.not(uses)?;
+        while self.check("&") {
+            self.advance();
+            condition = Condition::And(Box::new(condition), Box::new(self.not(uses)?));
+        }
+        Ok(condition)
+    }
+
+    fn not(&mut self, uses: &mut Vec<Token>) -> Result<Condition, Box<dyn Error>> {
+        if self.check("!") {
+            self.advance();
+            Ok(Condition::Not(Box::new(self.not(uses)?)))
+        } else if self.check("(") {
+            self.advance();
+            let condition = self.or(uses)?;
+            self.consume(")")?;
+            Ok(condition)
+        } else if self.peek().token_type == TokenType::Identifier {
+            let input = self.advance();
+            uses.push(input.clone());
+            Ok(Condition::Input(input.lexeme))
+        } else {
+            Err(self.unexpected("an input, `!`, or `(`"))
+        }
+    }
+}
+
+/// Parses a state machine description and checks that every name it uses
+/// is declared.
+pub fn parse(source: &str) -> Result<StateMachine, Box<dyn Error>> {
+    let mut parser = Parser {
+        tokens: scan(source)?,
+        current: 0,
+    };
+    let (machine, uses) = parser.state_machine()?;
+
+    for t in &uses {
+        if t.lexeme.starts_with(RESERVED_PREFIX) {
+            return Err(fsm_error(
+                t,
+                &format!("Names starting with `{}` are reserved.", RESERVED_PREFIX),
+            ));
+        }
+    }
+    let mut ports: Vec<&String> = machine.inputs.iter().chain(&machine.outputs).collect();
+    ports.sort();
+    for w in ports.windows(2) {
+        if w[0] == w[1] {
+            return Err(fsm_error(
+                uses.iter().find(|t| t.lexeme == *w[0]).unwrap(),
+                &format!("Port `{}` is declared more than once.", w[0]),
+            ));
+        }
+    }
+    for (i, s) in machine.states.iter().enumerate() {
+        if machine.states[..i].iter().any(|other| other.name == s.name) {
+            return Err(fsm_error(
+                uses.iter().filter(|t| t.lexeme == s.name).nth(1).unwrap(),
+                &format!("State `{}` is declared more than once.", s.name),
+            ));
+        }
+        let always = s.transitions.iter().position(|t| t.condition.is_none());
+        if let Some(idx) = always {
+            if idx + 1 < s.transitions.len() {
+                return Err(Box::new(N2VError {
+                    msg: format!(
+                        "State `{}` has transitions after one without a condition, which can never be taken.",
+                        s.name
+                    ),
+                    kind: ErrorKind::ElaborationError,
+                }));
+            }
+        }
+    }
+
+    // Names are checked in order so the first mistake is reported.
+    let state_names: Vec<String> = machine.states.iter().map(|s| s.name.clone()).collect();
+    for s in &machine.states {
+        check_names(&s.outputs, &machine.outputs, "output", &uses)?;
+        for t in &s.transitions {
+            if let Some(c) = &t.condition {
+                check_condition(c, &machine.inputs, &uses)?;
+            }
+            check_names(
+                std::slice::from_ref(&t.target),
+                &state_names,
+                "state",
+                &uses,
+            )?;
+            check_names(&t.outputs, &machine.outputs, "output", &uses)?;
+        }
+    }
+    Ok(machine)
+}
+
+fn check_names(
+    names: &[String],
+    declared: &[String],
+    kind: &str,
+    uses: &[Token],
+) -> Result<(), Box<dyn Error>> {
+    for name in names {
+        if !declared.contains(name) {
+            return Err(fsm_error(
+                uses.iter().find(|t| t.lexeme == *name).unwrap(),
+                &format!(
+                    "There is no {} named `{}`.{}",
+                    kind,
+                    name,
+                    crate::parser::did_you_mean(name, declared)
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_condition(
+    condition: &Condition,
+    inputs: &[String],
+    uses: &[Token],
+) -> Result<(), Box<dyn Error>> {
+    match condition {
+        Condition::Input(name) => check_names(std::slice::from_ref(name), inputs, "input", uses),
+        Condition::Not(c) => check_condition(c, inputs, uses),
+        Condition::And(a, b) | Condition::Or(a, b) => {
+            check_condition(a, inputs, uses)?;
+            check_condition(b, inputs, uses)
+        }
+    }
+}
+
+// Collects the parts of the generated chip.
+struct Netlist {
+    parts: Vec<String>,
+    wires: usize,
+}
+
+impl Netlist {
+    fn wire(&mut self) -> String {
+        self.wires += 1;
+        format!("{}W{}", RESERVED_PREFIX, self.wires)
+    }
+
+    fn not(&mut self, a: &str) -> String {
+        let out = self.wire();
+        self.parts.push(format!("Not(in={}, out={});", a, out));
+        out
+    }
+
+    fn gate(&mut self, gate: &str, a: &str, b: &str) -> String {
+        let out = self.wire();
+        self.parts
+            .push(format!("{}(a={}, b={}, out={});", gate, a, b, out));
+        out
+    }
+
+    // Drives `out` with the And or Or of `terms`. Every signal gets its own
+    // gate so that outputs and named wires always have a driver.
+    fn reduce(&mut self, gate: &str, terms: &[String], out: &str) {
+        let identity = if gate == "And" { "true" } else { "false" };
+        let (last_a, last_b) = match terms {
+            [] => (identity.to_string(), identity.to_string()),
+            [t] => (t.clone(), identity.to_string()),
+            [rest @ .., last] => {
+                let mut acc = rest[0].clone();
+                for t in &rest[1..] {
+                    acc = self.gate(gate, &acc, t);
+                }
+                (acc, last.clone())
+            }
+        };
+        self.parts.push(format!(
+            "{}(a={}, b={}, out={});",
+            gate, last_a, last_b, out
+        ));
+    }
+
+    fn condition(&mut self, condition: &Condition) -> String {
+        match condition {
+            Condition::Input(name) => name.clone(),
+            Condition::Not(c) => {
+                let c = self.condition(c);
+                self.not(&c)
+            }
+            Condition::And(a, b) => {
+                let (a, b) = (self.condition(a), self.condition(b));
+                self.gate("And", &a, &b)
+            }
+            Condition::Or(a, b) => {
+                let (a, b) = (self.condition(a), self.condition(b));
+                self.gate("Or", &a, &b)
+            }
+        }
+    }
+}
+
+/// Generates a CHIP implementing the state machine.
+pub fn to_hdl(machine: &StateMachine) -> Result<String, Box<dyn Error>> {
+    let mut bits = 1;
+    while (1 << bits) < machine.states.len() {
+        bits += 1;
+    }
+    let code = |name: &str| machine.states.iter().position(|s| s.name == name).unwrap();
+    let state_bit = |k: usize| format!("{}State{}", RESERVED_PREFIX, k);
+    let next_bit = |k: usize| format!("{}Next{}", RESERVED_PREFIX, k);
+    let is_state = |name: &str| format!("{}Is{}", RESERVED_PREFIX, name);
+
+    let mut netlist = Netlist {
+        parts: Vec::new(),
+        wires: 0,
+    };
+    for k in 0..bits {
+        netlist
+            .parts
+            .push(format!("DFF(in={}, out={});", next_bit(k), state_bit(k)));
+    }
+    let inverted: Vec<String> = (0..bits).map(|k| netlist.not(&state_bit(k))).collect();
+    for (i, s) in machine.states.iter().enumerate() {
+        let literals: Vec<String> = (0..bits)
+            .map(|k| {
+                if (i >> k) & 1 == 1 {
+                    state_bit(k)
+                } else {
+                    inverted[k].clone()
+                }
+            })
+            .collect();
+        netlist.reduce("And", &literals, &is_state(&s.name));
+    }
+
+    // Signals that are set on the cycles each transition is taken, and on
+    // the cycles the machine stays in each state.
+    let mut taken: Vec<(String, &Transition)> = Vec::new();
+    let mut stays: Vec<(String, usize)> = Vec::new();
+    for (i, s) in machine.states.iter().enumerate() {
+        let mut remaining = Some(is_state(&s.name));
+        for t in &s.transitions {
+            let rest = remaining.take().unwrap();
+            match &t.condition {
+                None => taken.push((rest, t)),
+                Some(c) => {
+                    let c = netlist.condition(c);
+                    taken.push((netlist.gate("And", &rest, &c), t));
+                    let not_c = netlist.not(&c);
+                    remaining = Some(netlist.gate("And", &rest, &not_c));
+                }
+            }
+        }
+        if let Some(rest) = remaining {
+            stays.push((rest, i));
+        }
+    }
+
+    for k in 0..bits {
+        let mut terms: Vec<String> = taken
+            .iter()
+            .filter(|(_, t)| (code(&t.target) >> k) & 1 == 1)
+            .map(|(w, _)| w.clone())
+            .collect();
+        terms.extend(
+            stays
+                .iter()
+                .filter(|(_, i)| (i >> k) & 1 == 1)
+                .map(|(w, _)| w.clone()),
+        );
+        netlist.reduce("Or", &terms, &next_bit(k));
+    }
+    for o in &machine.outputs {
+        let mut terms: Vec<String> = machine
+            .states
+            .iter()
+            .filter(|s| s.outputs.contains(o))
+            .map(|s| is_state(&s.name))
+            .collect();
+        terms.extend(
+            taken
+                .iter()
+                .filter(|(_, t)| t.outputs.contains(o))
+                .map(|(w, _)| w.clone()),
+        );
+        netlist.reduce("Or", &terms, o);
+    }
+
+    let mut hdl = String::new();
+    writeln!(hdl, "// Generated from the state machine {}.", machine.name)?;
+    writeln!(hdl, "// State codes:")?;
+    for (i, s) in machine.states.iter().enumerate() {
+        writeln!(hdl, "//   {} = {:0w$b}", s.name, i, w = bits)?;
+    }
+    writeln!(hdl, "CHIP {} {{", machine.name)?;
+    if !machine.inputs.is_empty() {
+        writeln!(hdl, "    IN {};", machine.inputs.join(", "))?;
+    }
+    if !machine.outputs.is_empty() {
+        writeln!(hdl, "    OUT {};", machine.outputs.join(", "))?;
+    }
+    writeln!(hdl, "\n    PARTS:")?;
+    for p in &netlist.parts {
+        writeln!(hdl, "    {}", p)?;
+    }
+    writeln!(hdl, "}}")?;
+    Ok(hdl)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::busmap::BusMap;
+    use crate::parser::{FileReader, HdlProvider, Parser};
+    use crate::scanner::Scanner;
+    use crate::simulator::{Bus, Chip, ChipOptions, Simulator};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    const TRAFFIC: &str = "
+        // A light that turns green on `go` and beeps when it stops.
+        FSM Traffic {
+            IN go, stop;
+            OUT red, green, yellow, beep;
+
+            STATE Red OUT red {
+                go -> Green;
+            }
+            STATE Green OUT green {
+                stop -> Yellow OUT beep;
+            }
+            STATE Yellow OUT yellow {
+                -> Red;
+            }
+        }";
+
+    #[test]
+    fn test_parse() {
+        let machine = parse(TRAFFIC).unwrap();
+        assert_eq!(machine.inputs, vec!["go", "stop"]);
+        assert_eq!(machine.states.len(), 3);
+        assert_eq!(machine.states[1].transitions[0].outputs, vec!["beep"]);
+        assert_eq!(machine.states[2].transitions[0].condition, None);
+
+        let machine = parse("FSM M { IN a, b, c; STATE S { !a & (b | c) -> S; } }").unwrap();
+        let expected = Condition::And(
+            Box::new(Condition::Not(Box::new(Condition::Input(String::from(
+                "a",
+            ))))),
+            Box::new(Condition::Or(
+                Box::new(Condition::Input(String::from("b"))),
+                Box::new(Condition::Input(String::from("c"))),
+            )),
+        );
+        assert_eq!(machine.states[0].transitions[0].condition, Some(expected));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let message = |source: &str| parse(source).err().unwrap().to_string();
+        assert!(message("FSM M { IN go; STATE A { go -> B; } STATE Bb; }")
+            .contains("line 1, column 32: There is no state named `B`. Did you mean `A` or `Bb`?"));
+        assert!(message("FSM M { IN go; STATE A { og -> A; } }")
+            .contains("line 1, column 26: There is no input named `og`. Did you mean `go`?"));
+        assert!(message("FSM M { IN go; }")
+            .contains("line 1, column 16: I expected IN, OUT, or STATE but found `}`."));
+        assert!(message("FSM M { STATE A { -> A; -> A; } }").contains("can never be taken"));
+    }
+
+    #[test]
+    fn test_simulate_generated() {
+        let hdl = to_hdl(&parse(TRAFFIC).unwrap()).unwrap();
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let mut scanner = Scanner::new(&hdl, PathBuf::from("Traffic.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let chip_hdl = parser.parse().expect("Parse error");
+        let chip = Chip::new(&chip_hdl, &provider, ChipOptions::default()).unwrap();
+        let mut simulator = Simulator::new(chip);
+
+        // Inputs for each cycle, and the outputs that are set during it.
+        let cycles = [
+            (false, false, "red"),
+            (false, true, "red"),
+            (true, false, "red"),
+            (false, false, "green"),
+            (false, true, "green beep"),
+            (true, true, "yellow"),
+            (false, false, "red"),
+        ];
+        for (go, stop, expected) in cycles {
+            let mut inputs = BusMap::new();
+            inputs.create_bus("go", 1).unwrap();
+            inputs.create_bus("stop", 1).unwrap();
+            inputs.insert(Bus::from("go"), vec![go]);
+            inputs.insert(Bus::from("stop"), vec![stop]);
+            let outputs = simulator.simulate(&inputs).unwrap();
+            let set: Vec<&str> = ["red", "green", "yellow", "beep"]
+                .into_iter()
+                .filter(|o| outputs.get_name(o) == vec![Some(true)])
+                .collect();
+            assert_eq!(set.join(" "), expected);
+            simulator.tick().unwrap();
+        }
+    }
+}
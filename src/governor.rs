@@ -0,0 +1,160 @@
+// Paces free-running simulations. In real-time mode the simulator is held to
+// a target number of clock cycles per second, which interactive demos need
+// to behave the same on fast and slow machines. In max-speed mode it runs as
+// fast as it can.
+//
+// Cycles are run in batches, and the governor is consulted between batches.
+// Batches are sized to take about one frame, so the caller can redraw
+// outputs and react to speed changes at a steady rate however fast the chip
+// simulates.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long a batch should take.
+pub const FRAME: Duration = Duration::from_millis(16);
+
+// Real-time mode gives up on catching up once it is this far behind, so a
+// pause does not turn into a burst of cycles.
+const MAX_LAG: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speed {
+    RealTime(u64), // Cycles per second.
+    Max,
+}
+
+impl std::str::FromStr for Speed {
+    type Err = String;
+
+    /// `max`, or a rate in cycles per second such as `1000`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "max" => Ok(Speed::Max),
+            s => match s.parse::<u64>() {
+                Ok(hz) if hz > 0 => Ok(Speed::RealTime(hz)),
+                _ => Err(format!(
+                    "`{}` is not a speed. Use `max` or cycles per second, e.g. `1000`.",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+pub struct Governor {
+    speed: Speed,
+    batch: u64,
+    start: Instant,
+    cycles: u64,    // Cycles run since `start`.
+    last: Duration, // When the last batch finished, measured from `start`.
+}
+
+impl Governor {
+    pub fn new(speed: Speed) -> Governor {
+        let mut governor = Governor {
+            speed,
+            batch: 1,
+            start: Instant::now(),
+            cycles: 0,
+            last: Duration::ZERO,
+        };
+        governor.set_speed(speed);
+        governor
+    }
+
+    /// Changes the speed, taking effect from the next batch.
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.start = Instant::now();
+        self.cycles = 0;
+        self.last = Duration::ZERO;
+        if let Speed::RealTime(hz) = speed {
+            self.batch = cycles_per_frame(hz);
+        }
+    }
+
+    /// Cycles to run before calling `pace` again.
+    pub fn batch(&self) -> u64 {
+        self.batch
+    }
+
+    /// Records that `cycles` cycles have run and sleeps until they are due.
+    pub fn pace(&mut self, cycles: u64) {
+        let delay = self.plan(cycles, self.start.elapsed());
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+
+    // How long to wait after running `cycles` cycles, `elapsed` after the
+    // speed was set. Also sizes the next batch so that it takes about a
+    // frame, which in real-time mode is at most a frame's worth of cycles.
+    fn plan(&mut self, cycles: u64, elapsed: Duration) -> Duration {
+        self.cycles += cycles;
+        let batch_time = elapsed.saturating_sub(self.last);
+        if batch_time < FRAME / 2 {
+            self.batch = self.batch.saturating_mul(2);
+        } else if batch_time > FRAME * 2 {
+            self.batch = (self.batch / 2).max(1);
+        }
+
+        let delay = match self.speed {
+            Speed::Max => Duration::ZERO,
+            Speed::RealTime(hz) => {
+                self.batch = self.batch.min(cycles_per_frame(hz));
+                let due = Duration::from_secs_f64(self.cycles as f64 / hz as f64);
+                if elapsed > due + MAX_LAG {
+                    // Too slow to keep up. Forget the cycles that are late.
+                    let late = elapsed - due - MAX_LAG;
+                    self.start += late;
+                    self.last = elapsed - late;
+                    return Duration::ZERO;
+                }
+                due.saturating_sub(elapsed)
+            }
+        };
+        self.last = elapsed + delay;
+        delay
+    }
+}
+
+// Real-time batches are at most a frame's worth of cycles, at least one.
+fn cycles_per_frame(hz: u64) -> u64 {
+    (hz.saturating_mul(FRAME.as_millis() as u64) / 1000).max(1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let ms = Duration::from_millis;
+
+        let mut governor = Governor::new(Speed::RealTime(1000));
+        assert_eq!(governor.batch(), 16);
+        // Ahead of time, so wait for the cycles to be due.
+        assert_eq!(governor.plan(16, ms(4)), ms(12));
+        assert_eq!(governor.plan(16, ms(20)), ms(12));
+        // Slow batches get smaller.
+        assert_eq!(governor.plan(16, ms(100)), Duration::ZERO);
+        assert_eq!(governor.batch(), 8);
+        // Far behind, the late cycles are dropped.
+        assert_eq!(governor.plan(8, ms(1000)), Duration::ZERO);
+        assert_eq!(governor.cycles, 56);
+        assert_eq!(governor.plan(1, ms(400)), Duration::ZERO);
+
+        // Max speed never waits, and grows batches until they take a frame.
+        governor.set_speed(Speed::Max);
+        assert_eq!(governor.batch(), 2);
+        assert_eq!(governor.plan(2, ms(1)), Duration::ZERO);
+        assert_eq!(governor.batch(), 4);
+        assert_eq!(governor.plan(4, ms(20)), Duration::ZERO);
+        assert_eq!(governor.batch(), 4);
+
+        assert_eq!("max".parse(), Ok(Speed::Max));
+        assert_eq!(" 60 ".parse(), Ok(Speed::RealTime(60)));
+        assert!("0".parse::<Speed>().is_err());
+    }
+}
@@ -0,0 +1,246 @@
+// Chips fetched from a web server, such as a course's chip library or a
+// classroom server. Only built with the `http` feature.
+//
+// A chip `Not` is fetched from `Not.hdl` under the base URL, once: what the
+// server answers, including that it has no such file, is kept for the life
+// of the provider. A server can list the SHA-256 hashes of its files in a
+// `SHA256SUMS` file, in the format `sha256sum` prints. A provider that has
+// the hashes refuses files that do not match, and files that are not listed.
+
+use crate::error::ProviderError;
+use crate::parser::HdlProvider;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Name of the file that lists the hashes of a server's files.
+pub const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+pub struct HttpProvider {
+    base_url: String, // Ends with a slash.
+    agent: ureq::Agent,
+    checksums: Option<HashMap<String, String>>, // Hex hashes by file name.
+    fetched: Mutex<HashMap<String, Result<String, ProviderError>>>,
+}
+
+impl HttpProvider {
+    /// Fetches chips from under `base_url`, e.g.
+    /// `https://example.edu/nand2tetris/lib/`.
+    pub fn new(base_url: &str) -> HttpProvider {
+        let base_url = if base_url.ends_with('/') {
+            String::from(base_url)
+        } else {
+            format!("{}/", base_url)
+        };
+        HttpProvider {
+            base_url,
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(30))
+                .build(),
+            checksums: None,
+            fetched: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks files against these SHA-256 hashes, in hex, by file name.
+    pub fn with_checksums(mut self, checksums: HashMap<String, String>) -> HttpProvider {
+        self.checksums = Some(checksums);
+        self
+    }
+
+    /// Checks files against the hashes the server lists in `SHA256SUMS`.
+    pub fn verified(self) -> Result<HttpProvider, ProviderError> {
+        let listing = self.fetch(CHECKSUMS_FILE)?;
+        let checksums = parse_checksums(&listing);
+        Ok(self.with_checksums(checksums))
+    }
+
+    fn url(&self, file_name: &str) -> String {
+        format!("{}{}", self.base_url, file_name)
+    }
+
+    fn fetch(&self, file_name: &str) -> Result<String, ProviderError> {
+        let url = self.url(file_name);
+        match self.agent.get(&url).call() {
+            Ok(response) => response.into_string().map_err(|e| ProviderError::Network {
+                url,
+                msg: e.to_string(),
+            }),
+            Err(ureq::Error::Status(404, _)) => Err(ProviderError::NotFound(format!(
+                "Unable to get HDL for {}. The server does not have it.",
+                url
+            ))),
+            Err(ureq::Error::Status(status, response)) => Err(ProviderError::Network {
+                url,
+                msg: format!("The server answered {} {}.", status, response.status_text()),
+            }),
+            Err(e) => Err(ProviderError::Network {
+                url,
+                msg: e.to_string(),
+            }),
+        }
+    }
+
+    // The source of a file, if it is the one the checksums list.
+    fn fetch_verified(&self, file_name: &str) -> Result<String, ProviderError> {
+        let expected = match &self.checksums {
+            None => return self.fetch(file_name),
+            Some(checksums) => checksums.get(file_name).ok_or_else(|| {
+                ProviderError::NotFound(format!(
+                    "Unable to get HDL for {}. It is not listed in {}.",
+                    self.url(file_name),
+                    CHECKSUMS_FILE
+                ))
+            })?,
+        };
+        let source = self.fetch(file_name)?;
+        let actual = sha256(&source);
+        if actual != expected.to_lowercase() {
+            return Err(ProviderError::Integrity {
+                url: self.url(file_name),
+                expected: expected.clone(),
+                actual,
+            });
+        }
+        Ok(source)
+    }
+}
+
+impl HdlProvider for HttpProvider {
+    fn get_hdl(&self, file_name: &str) -> Result<String, ProviderError> {
+        if let Some(fetched) = self.fetched.lock().unwrap().get(file_name) {
+            return fetched.clone();
+        }
+        let fetched = self.fetch_verified(file_name);
+        self.fetched
+            .lock()
+            .unwrap()
+            .insert(String::from(file_name), fetched.clone());
+        fetched
+    }
+
+    fn get_path(&self, file_name: &str) -> PathBuf {
+        PathBuf::from(self.url(file_name))
+    }
+
+    /// The chips listed in `SHA256SUMS`, since a server cannot be asked
+    /// for the files it has.
+    fn chip_names(&self) -> Vec<String> {
+        self.checksums
+            .iter()
+            .flat_map(|c| c.keys())
+            .filter_map(|f| f.strip_suffix(".hdl"))
+            .filter(|f| !f.contains('.') && !f.contains('/'))
+            .map(String::from)
+            .collect()
+    }
+}
+
+fn sha256(source: &str) -> String {
+    Sha256::digest(source.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Hashes by file name from the lines `sha256sum` prints, e.g.
+// `9f86d0...  Not.hdl`. A `*` before the name marks a binary file.
+fn parse_checksums(listing: &str) -> HashMap<String, String> {
+    listing
+        .lines()
+        .filter_map(|line| {
+            let (hash, name) = line.trim().split_once(char::is_whitespace)?;
+            let name = name.trim_start().trim_start_matches('*');
+            let name = name.strip_prefix("./").unwrap_or(name);
+            Some((String::from(name), hash.to_lowercase()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Serves `files` on a local port until the test ends. Returns the base
+    // URL and the number of requests served.
+    fn serve(files: Vec<(&'static str, String)>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/lib", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                // Skip the headers.
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+                let path = request.split(' ').nth(1).unwrap_or("");
+                let body = files
+                    .iter()
+                    .find(|(name, _)| path == format!("/lib/{}", name))
+                    .map(|(_, body)| body.clone());
+                let response = match body {
+                    Some(body) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    ),
+                    None => String::from(
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    ),
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn test_http_provider() {
+        let not = String::from("CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }");
+        let buf = String::from("CHIP Buf { IN in; OUT out; PARTS: Not(in=in, out=out); }");
+        let sums = format!(
+            "{}  Not.hdl\n{}  ./Buf.hdl\n",
+            sha256(&not),
+            sha256("something else")
+        );
+        let (url, requests) = serve(vec![
+            ("Not.hdl", not.clone()),
+            ("Buf.hdl", buf.clone()),
+            (CHECKSUMS_FILE, sums),
+        ]);
+
+        let provider = HttpProvider::new(&url);
+        assert_eq!(provider.get_hdl("Buf.hdl").unwrap(), buf);
+        assert_eq!(provider.get_hdl("Buf.hdl").unwrap(), buf);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(provider.get_hdl("Missing.hdl").unwrap_err().is_not_found());
+        assert_eq!(
+            provider.get_path("Not.hdl"),
+            PathBuf::from(format!("{}/Not.hdl", url))
+        );
+
+        let provider = HttpProvider::new(&url).verified().unwrap();
+        assert_eq!(provider.get_hdl("Not.hdl").unwrap(), not);
+        assert!(matches!(
+            provider.get_hdl("Buf.hdl"),
+            Err(ProviderError::Integrity { .. })
+        ));
+        assert!(provider.get_hdl("Missing.hdl").unwrap_err().is_not_found());
+        let mut names = provider.chip_names();
+        names.sort();
+        assert_eq!(names, vec!["Buf", "Not"]);
+    }
+}
@@ -0,0 +1,271 @@
+// Incremental parsing for editors. After an edit, `Parser::reparse` scans
+// and parses only the lines of the parts the edit touched, and reuses the
+// rest of the previous parse tree with its line numbers moved. Anything it
+// cannot do incrementally is parsed from scratch, so the result is always
+// the same as parsing the whole file.
+
+use crate::error::N2VError;
+use crate::expr::{GenericValue, GenericWidth, Terminal};
+use crate::parser::*;
+use crate::scanner::{Scanner, Span};
+use std::path::Path;
+
+impl<'a, 'b> Parser<'a, 'b> {
+    /// Parses a file again after an edit, reusing the parts of `old` that
+    /// the edit did not touch. `new_text` replaced `edit_range` of the text
+    /// `old` was parsed from, giving `source`. Only the lines of the edited
+    /// parts are scanned and parsed again. An edit outside of the parts, or
+    /// one that does not parse cleanly, falls back to parsing all of
+    /// `source`. `old` must come from a parse without errors.
+    pub fn reparse(
+        old: &ChipHDL,
+        edit_range: Span,
+        new_text: &str,
+        source: &str,
+    ) -> (Option<ChipHDL>, Vec<N2VError>) {
+        let path = old.path.clone().unwrap_or_default();
+        if let Some(hdl) = reparse_parts(old, edit_range, new_text, source, &path) {
+            return (Some(hdl), Vec::new());
+        }
+        let mut scanner = Scanner::new(source, path);
+        Parser {
+            scanner: &mut scanner,
+        }
+        .parse_recovering()
+    }
+}
+
+// The chip after an edit with the parts on the edited lines parsed again,
+// or None if the edit may reach outside of them.
+fn reparse_parts(
+    old: &ChipHDL,
+    edit: Span,
+    new_text: &str,
+    source: &str,
+    path: &Path,
+) -> Option<ChipHDL> {
+    let starts = old
+        .parts
+        .iter()
+        .map(part_start)
+        .collect::<Option<Vec<u32>>>()?;
+    // Whole lines are parsed again, from the first part on the line where
+    // the edit starts, or the last part before it, to the first part after
+    // the edit.
+    let last_before = starts.iter().rposition(|&s| s <= edit.start_line)?;
+    let first = starts.iter().position(|&s| s == starts[last_before])?;
+    let next = starts.iter().position(|&s| s > edit.end_line)?;
+
+    let delta =
+        new_text.matches('\n').count() as i64 - (edit.end_line as i64 - edit.start_line as i64);
+    let section_start = starts[first] as usize;
+    let section_end = usize::try_from(starts[next] as i64 - 1 + delta).ok()?;
+    let lines: Vec<&str> = source.split('\n').collect();
+    // The closing brace ends the parts like it ends the chip's.
+    let text = format!(
+        "{}\n}}",
+        lines.get(section_start - 1..section_end)?.join("\n")
+    );
+
+    let mut scanner = Scanner::new(&text, path.to_path_buf());
+    scanner.line = starts[first];
+    let mut parser = Parser {
+        scanner: &mut scanner,
+    };
+    let mut errors = Vec::new();
+    let section = parser.parts(&mut errors);
+    // Comments left over would belong to the part after the section.
+    if !errors.is_empty() || parser.scanner.peek().is_some() || !scanner.comments.is_empty() {
+        return None;
+    }
+
+    let mut hdl = old.clone();
+    let mut rest = hdl.parts.split_off(next);
+    rest.iter_mut().for_each(|p| shift_part(p, delta));
+    hdl.parts.truncate(first);
+    hdl.parts.extend(section);
+    hdl.parts.append(&mut rest);
+    for c in hdl
+        .body_comments
+        .iter_mut()
+        .chain(hdl.comments.trailing.iter_mut())
+        .filter(|c| c.line >= starts[next])
+    {
+        c.line = shift_line(c.line, delta);
+    }
+    Some(hdl)
+}
+
+// First line of a part, counting its annotations and leading comments.
+// None for parts that were not parsed from a file.
+fn part_start(part: &Part) -> Option<u32> {
+    let mut lines = Vec::new();
+    let (comments, component) = match part {
+        Part::Component(c) => (&c.comments, Some(c)),
+        Part::Loop(l) => {
+            lines.push(l.iterator.line);
+            (&l.comments, l.body.first())
+        }
+    };
+    lines.extend(comments.leading.iter().map(|c| Some(c.line)));
+    if let Some(c) = component {
+        lines.extend(c.comments.leading.iter().map(|c| Some(c.line)));
+        lines.extend(c.annotations.iter().map(|a| a.name.line));
+        lines.extend(c.namespace.iter().map(|n| n.line));
+        lines.push(c.name.line);
+    }
+    lines
+        .into_iter()
+        .collect::<Option<Vec<u32>>>()?
+        .into_iter()
+        .min()
+}
+
+fn shift_line(line: u32, delta: i64) -> u32 {
+    (line as i64 + delta) as u32
+}
+
+fn shift_identifier(id: &mut Identifier, delta: i64) {
+    id.line = id.line.map(|l| shift_line(l, delta));
+    if let Some(span) = &mut id.span {
+        span.start_line = shift_line(span.start_line, delta);
+        span.end_line = shift_line(span.end_line, delta);
+    }
+}
+
+fn shift_width(width: &mut GenericWidth, delta: i64) {
+    match width {
+        GenericWidth::Expr(_, a, b) => {
+            shift_width(a, delta);
+            shift_width(b, delta);
+        }
+        GenericWidth::Log2(a) => shift_width(a, delta),
+        GenericWidth::If(c, a, b) => {
+            shift_width(&mut c.left, delta);
+            shift_width(&mut c.right, delta);
+            shift_width(a, delta);
+            shift_width(b, delta);
+        }
+        GenericWidth::Terminal(Terminal::Var(v)) => shift_identifier(v, delta),
+        GenericWidth::Terminal(Terminal::Num(_)) => {}
+    }
+}
+
+fn shift_comments(comments: &mut Comments, delta: i64) {
+    for c in comments
+        .leading
+        .iter_mut()
+        .chain(comments.trailing.iter_mut())
+    {
+        c.line = shift_line(c.line, delta);
+    }
+}
+
+fn shift_component(c: &mut Component, delta: i64) {
+    shift_identifier(&mut c.name, delta);
+    if let Some(n) = &mut c.namespace {
+        shift_identifier(n, delta);
+    }
+    for m in &mut c.mappings {
+        shift_identifier(&mut m.wire_ident, delta);
+        for bus in [&mut m.wire, &mut m.port] {
+            for w in [&mut bus.start, &mut bus.end].into_iter().flatten() {
+                shift_width(w, delta);
+            }
+        }
+        shift_comments(&mut m.comments, delta);
+    }
+    for g in &mut c.generic_params {
+        if let Some(n) = &mut g.name {
+            shift_identifier(n, delta);
+        }
+        if let GenericValue::Width(w) = &mut g.value {
+            shift_width(w, delta);
+        }
+    }
+    for a in &mut c.annotations {
+        shift_identifier(&mut a.name, delta);
+    }
+    shift_comments(&mut c.comments, delta);
+}
+
+// Moves a part that was parsed from a file down by `delta` lines.
+fn shift_part(part: &mut Part, delta: i64) {
+    match part {
+        Part::Component(c) => shift_component(c, delta),
+        Part::Loop(l) => {
+            shift_width(&mut l.start, delta);
+            shift_width(&mut l.end, delta);
+            shift_identifier(&mut l.iterator, delta);
+            l.body.iter_mut().for_each(|c| shift_component(c, delta));
+            shift_comments(&mut l.comments, delta);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_reparse() {
+        let parse = |source: &str| {
+            let mut scanner = Scanner::new(source, PathBuf::from("Test.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            parser.parse_recovering()
+        };
+        let json = |hdl: &Option<ChipHDL>| serde_json::to_string(hdl).unwrap();
+        let old_source = "CHIP Test {
+    IN a, b;
+    OUT out;
+    PARTS:
+    // First
+    Nand(a=a, b=b, out=x);
+    Not(in=x, out=y); // Second
+    FOR i IN 0 TO 1 GENERATE {
+        Not(in=y, out=z);
+    }
+    Or(a=z, b=y, out=out);
+    // End
+}
+";
+        let old = parse(old_source).0.unwrap();
+        let edit = |line: u32, col: u32, end_line: u32, end_col: u32| Span {
+            start_line: line,
+            start_col: col,
+            end_line,
+            end_col,
+        };
+
+        // Renames the output of Not and adds a part after it.
+        let range = edit(7, 20, 7, 21);
+        let new_text = "w);\n    And(a=w, b=w, out=y";
+        let source = old_source.replace(
+            "Not(in=x, out=y);",
+            "Not(in=x, out=w);\n    And(a=w, b=w, out=y);",
+        );
+        let hdl = reparse_parts(&old, range, new_text, &source, Path::new("Test.hdl"));
+        assert!(hdl.is_some(), "The edit should be parsed incrementally");
+        let (reparsed, errors) = Parser::reparse(&old, range, new_text, &source);
+        assert!(errors.is_empty());
+        assert_eq!(json(&reparsed), json(&parse(&source).0));
+        assert_eq!(reparsed.unwrap().parts.len(), 5);
+
+        // Errors and edits to the ports parse the whole file.
+        let broken = old_source.replace("out=y);", "out=y)");
+        let range = edit(7, 22, 7, 23);
+        assert!(reparse_parts(&old, range, "", &broken, Path::new("Test.hdl")).is_none());
+        let (reparsed, errors) = Parser::reparse(&old, range, "", &broken);
+        assert_eq!(json(&reparsed), json(&parse(&broken).0));
+        assert_eq!(errors.len(), parse(&broken).1.len());
+
+        let widened = old_source.replace("IN a, b;", "IN a, b, c;");
+        let range = edit(2, 11, 2, 11);
+        let (reparsed, errors) = Parser::reparse(&old, range, ", c", &widened);
+        assert!(errors.is_empty());
+        assert_eq!(reparsed.unwrap().ports.len(), 4);
+    }
+}
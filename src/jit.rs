@@ -0,0 +1,442 @@
+// An engine that compiles a chip to native code with Cranelift. The chip is
+// compiled into a program as for the compiled engine, and each run of NANDs
+// in the program becomes a function that reads and writes the values of
+// nets in memory, one byte each. Builtins still run through `Cell`, so a
+// chip spends its time in native code as long as it is mostly NANDs, which
+// long test scripts such as whole CPU programs are.
+
+use crate::compiled::{compile, Op};
+use crate::error::{ErrorKind, N2VError};
+use crate::logic::{Logic, XPolicy};
+use crate::netlist::{flatten, FlatEngine, NetState, NetValue, Netlist};
+use crate::simulator::Chip;
+use cranelift::codegen::ir::UserFuncName;
+use cranelift::jit::{JITBuilder, JITModule};
+use cranelift::module::{default_libcall_names, FuncId, Module};
+use cranelift::prelude::*;
+use std::error::Error;
+use std::sync::Arc;
+
+// NANDs in one function at most, so that compiling a big chip takes time in
+// proportion to its size.
+const MAX_RUN: usize = 1024;
+
+// Values of a net in memory. Z shares the bit of UNKNOWN, so native NANDs
+// read it as X.
+const FALSE: u8 = 0;
+const TRUE: u8 = 1;
+const UNKNOWN: u8 = 2;
+const FLOATING: u8 = 6;
+
+fn byte(value: Logic) -> u8 {
+    match value {
+        Logic::Zero => FALSE,
+        Logic::One => TRUE,
+        Logic::X => UNKNOWN,
+        Logic::Z => FLOATING,
+    }
+}
+
+fn value(byte: u8) -> Logic {
+    match byte {
+        FALSE => Logic::Zero,
+        TRUE => Logic::One,
+        FLOATING => Logic::Z,
+        _ => Logic::X,
+    }
+}
+
+impl NetValue for u8 {
+    fn from_logic(bit: Logic) -> Self {
+        byte(bit)
+    }
+
+    fn logic(self) -> Logic {
+        value(self)
+    }
+}
+
+// A run of NANDs in native code. Takes the values of every net and returns
+// nonzero if it changed any of them.
+type Native = unsafe extern "C" fn(*mut u8) -> u8;
+
+// One step of the program, as `Op` with runs of NANDs compiled.
+#[derive(Clone, Copy)]
+enum Step {
+    Native(Native),
+    Builtin(usize),
+    Loop { len: usize },
+}
+
+// The native code of a chip and the program that calls it.
+struct Code {
+    steps: Vec<Step>,
+    // Kept to free the code once no engine runs it.
+    module: Option<JITModule>,
+}
+
+// The module is not touched again once its code is made, and the code
+// itself only reads and writes the values it is given.
+unsafe impl Send for Code {}
+unsafe impl Sync for Code {}
+
+impl Drop for Code {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // Nothing can call the functions once the steps are gone.
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+fn jit_error(msg: impl std::fmt::Display) -> Box<dyn Error> {
+    Box::new(N2VError {
+        msg: format!("Could not compile the chip to native code: {}", msg),
+        kind: ErrorKind::SimulationError(None),
+    })
+}
+
+// Builds a function that evaluates `nands`, which are all `Op::Nand`, with
+// unknown bits spreading as `policy` says.
+fn define(
+    module: &mut JITModule,
+    ctx: &mut codegen::Context,
+    nands: &[Op],
+    policy: XPolicy,
+) -> Result<FuncId, Box<dyn Error>> {
+    let pointer = module.target_config().pointer_type();
+    ctx.func.signature.params.push(AbiParam::new(pointer));
+    ctx.func.signature.returns.push(AbiParam::new(types::I8));
+    let mut builder_context = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut builder_context);
+    let block = builder.create_block();
+    builder.append_block_params_for_function_params(block);
+    builder.switch_to_block(block);
+    builder.seal_block(block);
+    let values = builder.block_params(block)[0];
+    let flags = MemFlags::trusted();
+    let offset = |net: usize| i32::try_from(net).map_err(|_| jit_error("too many nets"));
+
+    let unknown_value = builder.ins().iconst(types::I8, UNKNOWN as i64);
+    let mut changed = builder.ins().iconst(types::I8, 0);
+    for op in nands {
+        let Op::Nand { a, b, out } = *op else {
+            unreachable!("only NANDs are compiled");
+        };
+        let a = builder.ins().load(types::I8, flags, values, offset(a)?);
+        let b = builder.ins().load(types::I8, flags, values, offset(b)?);
+        let old = builder.ins().load(types::I8, flags, values, offset(out)?);
+        // Unknown if either input is, otherwise the NAND of the two.
+        let either = builder.ins().bor(a, b);
+        let unknown = builder.ins().band_imm(either, UNKNOWN as i64);
+        let both = builder.ins().band(a, b);
+        let known = builder.ins().bxor_imm(both, TRUE as i64);
+        let mut new = builder.ins().select(unknown, unknown_value, known);
+        if policy == XPolicy::Optimistic {
+            // A known 0 on either input decides the output.
+            let a_zero = builder.ins().icmp_imm(IntCC::Equal, a, FALSE as i64);
+            let b_zero = builder.ins().icmp_imm(IntCC::Equal, b, FALSE as i64);
+            let zero = builder.ins().bor(a_zero, b_zero);
+            let one = builder.ins().iconst(types::I8, TRUE as i64);
+            new = builder.ins().select(zero, one, new);
+        }
+        builder.ins().store(flags, new, values, offset(out)?);
+        let difference = builder.ins().bxor(new, old);
+        changed = builder.ins().bor(changed, difference);
+    }
+    builder.ins().return_(&[changed]);
+    builder.finalize();
+
+    let id = module
+        .declare_anonymous_function(&ctx.func.signature)
+        .map_err(jit_error)?;
+    ctx.func.name = UserFuncName::user(0, id.as_u32());
+    module.define_function(id, ctx).map_err(jit_error)?;
+    module.clear_context(ctx);
+    Ok(id)
+}
+
+// A step, or a function that will be one once the code is finalized.
+enum Pending {
+    Function(FuncId),
+    Step(Step),
+}
+
+// Adds the steps of `ops`, which has no loops, to `pending`.
+fn translate(
+    module: &mut JITModule,
+    ctx: &mut codegen::Context,
+    ops: &[Op],
+    policy: XPolicy,
+    pending: &mut Vec<Pending>,
+) -> Result<(), Box<dyn Error>> {
+    let mut i = 0;
+    while i < ops.len() {
+        if let Op::Builtin(builtin) = ops[i] {
+            pending.push(Pending::Step(Step::Builtin(builtin)));
+            i += 1;
+        } else {
+            let run = ops[i..]
+                .iter()
+                .take(MAX_RUN)
+                .take_while(|op| matches!(op, Op::Nand { .. }))
+                .count();
+            let id = define(module, ctx, &ops[i..i + run], policy)?;
+            pending.push(Pending::Function(id));
+            i += run;
+        }
+    }
+    Ok(())
+}
+
+// Compiles the runs of NANDs in `program` to native code.
+fn jit(program: &[Op], policy: XPolicy) -> Result<Code, Box<dyn Error>> {
+    // The code is mostly loads and stores, which optimizing does little
+    // for, and compiling a big chip takes many times as long with it.
+    let mut flags = settings::builder();
+    flags.set("opt_level", "none").map_err(jit_error)?;
+    let isa = cranelift::native::builder()
+        .map_err(jit_error)?
+        .finish(settings::Flags::new(flags))
+        .map_err(jit_error)?;
+    let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+    let mut ctx = module.make_context();
+
+    let mut pending = Vec::new();
+    let mut i = 0;
+    while i < program.len() {
+        if let Op::Loop { len } = program[i] {
+            let start = pending.len();
+            pending.push(Pending::Step(Step::Loop { len: 0 }));
+            let ops = &program[i + 1..i + 1 + len];
+            translate(&mut module, &mut ctx, ops, policy, &mut pending)?;
+            pending[start] = Pending::Step(Step::Loop {
+                len: pending.len() - start - 1,
+            });
+            i += 1 + len;
+        } else {
+            let end = program[i..]
+                .iter()
+                .position(|op| matches!(op, Op::Loop { .. }))
+                .map_or(program.len(), |n| i + n);
+            translate(
+                &mut module,
+                &mut ctx,
+                &program[i..end],
+                policy,
+                &mut pending,
+            )?;
+            i = end;
+        }
+    }
+
+    module.finalize_definitions().map_err(jit_error)?;
+    let steps = pending
+        .into_iter()
+        .map(|p| match p {
+            // The function was defined with the signature of `Native`.
+            Pending::Function(id) => Step::Native(unsafe {
+                std::mem::transmute::<*const u8, Native>(module.get_finalized_function(id))
+            }),
+            Pending::Step(step) => step,
+        })
+        .collect();
+    Ok(Code {
+        steps,
+        module: Some(module),
+    })
+}
+
+/// Runs a chip by running native code compiled from its netlist, which
+/// evaluates every cell once per step in an order where each cell's inputs
+/// are computed before it.
+#[derive(Clone)]
+pub struct JitEngine {
+    state: NetState<u8>, // Its cells are the program's builtins.
+    // Shared by copies of the engine.
+    code: Arc<Code>,
+}
+
+impl JitEngine {
+    /// Flattens `chip`, elaborating all of it, and compiles it to native
+    /// code that runs with the settle limit and X policy the chip was built
+    /// with. The chip itself is left as it was.
+    pub fn new(chip: &Chip) -> Result<JitEngine, Box<dyn Error>> {
+        let options = chip.options();
+        let mut engine = JitEngine::with_policy(&chip.name, flatten(chip)?, options.x_policy)?;
+        engine.state.follow(options);
+        Ok(engine)
+    }
+
+    /// Compiles a netlist already flattened from the chip `name`, where
+    /// unknown bits spread pessimistically.
+    pub fn from_netlist(name: &str, netlist: Netlist) -> Result<JitEngine, Box<dyn Error>> {
+        JitEngine::with_policy(name, netlist, XPolicy::Pessimistic)
+    }
+
+    fn with_policy(
+        name: &str,
+        netlist: Netlist,
+        policy: XPolicy,
+    ) -> Result<JitEngine, Box<dyn Error>> {
+        let (program, builtins) = compile(&netlist);
+        let code = jit(&program, policy)?;
+        let mut state = NetState::new(name, netlist, builtins);
+        state.x_policy = policy;
+        Ok(JitEngine {
+            state,
+            code: Arc::new(code),
+        })
+    }
+
+    // Runs one step other than a loop, returning whether a net it drives
+    // changed.
+    fn run(&mut self, step: Step, changed: &mut Vec<(usize, Logic)>) -> bool {
+        match step {
+            // The code only reads and writes nets the netlist numbered,
+            // and there is a value for every one of them.
+            Step::Native(native) => unsafe { native(self.state.values.as_mut_ptr()) != 0 },
+            Step::Builtin(i) => {
+                let values = &self.state.values;
+                let policy = self.state.x_policy;
+                self.state.cells[i].evaluate_with(|n| value(values[n]), policy, changed);
+                let mut changing = false;
+                for (net, v) in changed.drain(..) {
+                    let v = byte(v);
+                    changing |= self.state.values[net] != v;
+                    self.state.values[net] = v;
+                }
+                changing
+            }
+            Step::Loop { .. } => unreachable!("loops are run by settle"),
+        }
+    }
+}
+
+impl FlatEngine for JitEngine {
+    type Value = u8;
+
+    fn state(&self) -> &NetState<u8> {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut NetState<u8> {
+        &mut self.state
+    }
+
+    // Runs the program once through.
+    fn settle(&mut self) -> Result<(), Box<dyn Error>> {
+        let code = Arc::clone(&self.code);
+        let steps = &code.steps;
+        let mut changed = Vec::new();
+        let mut pc = 0;
+        while pc < steps.len() {
+            match steps[pc] {
+                Step::Loop { len } => {
+                    let body = &steps[pc + 1..pc + 1 + len];
+                    let mut passes = 0;
+                    while body.iter().fold(false, |changing, &step| {
+                        self.run(step, &mut changed) | changing
+                    }) {
+                        passes += 1;
+                        if passes > self.state.settle_limit {
+                            return Err(Box::new(N2VError {
+                                msg: format!(
+                                    "Chip {} did not settle after {} passes over a loop of gates and builtins, so the loop is oscillating. The limit is set with --settle-limit.",
+                                    self.state.name,
+                                    self.state.settle_limit,
+                                ),
+                                kind: ErrorKind::SimulationError(None),
+                            }));
+                        }
+                    }
+                    pc += 1 + len;
+                }
+                step => {
+                    self.run(step, &mut changed);
+                    pc += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::busmap::BusMap;
+    use crate::engine::SimEngine;
+    use crate::event::test::{check_same, make_chip};
+    use crate::simulator::Bus;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_jit_unknown() {
+        let mut engine = JitEngine::new(&make_chip("And.hdl")).unwrap();
+        for (a, b, out) in [
+            (None, Some(true), None),
+            (Some(true), None, None),
+            (Some(true), Some(true), Some(true)),
+            (Some(false), Some(true), Some(false)),
+        ] {
+            let mut inputs = BusMap::new();
+            inputs.create_bus("a", 1).unwrap();
+            inputs.create_bus("b", 1).unwrap();
+            inputs.insert_option(&Bus::from("a"), vec![a]);
+            inputs.insert_option(&Bus::from("b"), vec![b]);
+            let outputs = engine.simulate(&inputs).unwrap();
+            assert_eq!(outputs.get_name("out"), vec![out]);
+        }
+    }
+
+    #[test]
+    fn test_jit_optimistic() {
+        let netlist = flatten(&make_chip("And.hdl")).unwrap();
+        let mut inputs = BusMap::new();
+        inputs.create_bus("a", 1).unwrap();
+        inputs.create_bus("b", 1).unwrap();
+        inputs.insert_option(&Bus::from("a"), vec![Some(false)]);
+        inputs.insert_option(&Bus::from("b"), vec![None]);
+        for (policy, out) in [
+            (XPolicy::Pessimistic, None),
+            (XPolicy::Optimistic, Some(false)),
+        ] {
+            let mut engine = JitEngine::with_policy("And", netlist.clone(), policy).unwrap();
+            let outputs = engine.simulate(&inputs).unwrap();
+            assert_eq!(outputs.get_name("out"), vec![out]);
+        }
+    }
+
+    // Memories too big to flatten in a debug build in good time.
+    const BIG: [&str; 3] = ["RAM512.hdl", "RAM4K.hdl", "RAM16K.hdl"];
+
+    // Runs each chip of the nand2tetris solutions that `pick` picks on the
+    // interpreter and the JIT engine, checking that they agree.
+    fn check_solutions(pick: impl Fn(&str) -> bool) {
+        let engine =
+            |chip: &Chip| -> Box<dyn SimEngine> { Box::new(JitEngine::new(chip).unwrap()) };
+        let dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/tests/nand2tetris/solutions");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            if path.extension().is_some_and(|e| e == "hdl") && pick(name) {
+                check_same(name, 20, engine);
+            }
+        }
+    }
+
+    #[test]
+    fn test_jit_engine() {
+        check_solutions(|name| !BIG.contains(&name));
+    }
+
+    // Run with `cargo test --release --features jit -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_jit_engine_big() {
+        check_solutions(|name| BIG.contains(&name));
+    }
+}
@@ -2,26 +2,58 @@
 // to warn about dead code here.
 #![allow(dead_code)]
 
+mod bitwise;
+pub mod builder;
+mod builtin;
 mod busmap;
+mod combinational;
+mod compiled;
+#[cfg(feature = "corpus")]
+pub mod corpus;
+mod diagnostics;
+mod elaborate;
+mod engine;
 mod error;
+mod event;
+mod exit;
 mod expr;
+#[cfg(feature = "http")]
+pub mod http;
+mod incremental;
+#[cfg(feature = "jit")]
+mod jit;
+mod lint;
+mod logic;
+pub mod lsp;
+mod monitor;
+mod netlist;
+mod parser;
+mod refactor;
 mod scanner;
 mod simulator;
-mod parser;
+mod test_parser;
 mod test_scanner;
+mod test_script;
+pub mod visit;
+
+pub use crate::diagnostics::{check_directory, Diagnostic, Severity};
+pub use crate::error::{ErrorKind, N2VError, ProviderError, WhidlError};
+pub use crate::parser::{
+    get_hdl, ChipHDL, FileReader, HdlProvider, MemoryReader, Parser, ZipReader,
+};
+pub use crate::scanner::{Scanner, Span};
+pub use crate::simulator::{Chip, ChipOptions};
+pub use crate::test_script::run_test;
 
 use crate::busmap::BusMap;
-use crate::error::{ErrorKind, N2VError};
 use crate::parser::*;
-use crate::simulator::{Chip, Simulator};
+use crate::simulator::Simulator;
 use expr::*;
 use rust_embed::RustEmbed;
-use scanner::Scanner;
 use std::collections::HashMap;
 use std::error::Error;
 use std::path::PathBuf;
-use std::ptr;
-use std::rc::Rc;
+use std::sync::Arc;
 
 use wasm_bindgen::prelude::*;
 
@@ -33,12 +65,12 @@ struct HdlAsset;
 pub struct EmbedReader;
 
 impl HdlProvider for EmbedReader {
-    fn get_hdl(&self, path: &str) -> Result<String, std::io::Error> {
+    fn get_hdl(&self, path: &str) -> Result<String, ProviderError> {
         match HdlAsset::get(path) {
-            None => Err(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                format!("Unable to get HDL for {}", path),
-            )),
+            None => Err(ProviderError::NotFound(format!(
+                "Unable to get HDL for {}",
+                path
+            ))),
             Some(hdl_asset) => Ok(String::from(
                 std::str::from_utf8(hdl_asset.data.as_ref()).unwrap(),
             )),
@@ -48,10 +80,36 @@ impl HdlProvider for EmbedReader {
     fn get_path(&self, file_name: &str) -> PathBuf {
         PathBuf::from(file_name)
     }
+
+    fn chip_names(&self) -> Vec<String> {
+        HdlAsset::iter()
+            .filter_map(|f| f.strip_suffix(".hdl").map(String::from))
+            .collect()
+    }
 }
 
 #[wasm_bindgen]
 pub fn simulate(s: &str, inputs: &str) -> Result<String, JsValue> {
+    simulate_with(s, inputs, Arc::new(EmbedReader))
+}
+
+/// Like `simulate`, but finds parts in `files`, a JSON object of sources by
+/// file name such as `{"Not.hdl": "CHIP Not ..."}`, instead of the chips
+/// built into whidl.
+#[wasm_bindgen]
+pub fn simulate_files(s: &str, inputs: &str, files: &str) -> Result<String, JsValue> {
+    simulate_with(s, inputs, memory_reader(files)?)
+}
+
+// The chips of a `*_files` entry point.
+fn memory_reader(files: &str) -> Result<Arc<dyn HdlProvider>, JsValue> {
+    match serde_json::from_str::<HashMap<String, String>>(files) {
+        Ok(files) => Ok(Arc::new(MemoryReader::new(files))),
+        Err(e) => Err(JsValue::from(format!("Unable to parse files: {}", e))),
+    }
+}
+
+fn simulate_with(s: &str, inputs: &str, provider: Arc<dyn HdlProvider>) -> Result<String, JsValue> {
     console_error_panic_hook::set_once();
     let mut scanner = Scanner::new(s, PathBuf::from(""));
     let mut parser = Parser {
@@ -63,22 +121,38 @@ pub fn simulate(s: &str, inputs: &str) -> Result<String, JsValue> {
         Err(e) => return Err(JsValue::from(e.to_string())),
     };
 
-    let provider: Rc<dyn HdlProvider> = Rc::new(EmbedReader);
-    let chip = match Chip::new(&hdl, ptr::null_mut(), &provider, false, &Vec::new()) {
+    let chip = match Chip::new(&hdl, &provider, ChipOptions::default()) {
         Ok(x) => x,
         Err(e) => return Err(JsValue::from(e.to_string())),
     };
     let mut simulator = Simulator::new(chip);
-    let chip_inputs: HashMap<String, Vec<bool>> = serde_json::from_str(inputs)
-        .unwrap_or_else(|_| panic!("Unable to parse inputs: {}", inputs));
+    let chip_inputs: HashMap<String, Vec<bool>> = match serde_json::from_str(inputs) {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(JsValue::from(format!(
+                "Unable to parse inputs {}: {}",
+                inputs, e
+            )))
+        }
+    };
     let outputs = simulator.simulate(&BusMap::try_from(chip_inputs)?);
     Ok(format!("{:?}", outputs))
 }
 
 #[wasm_bindgen]
 pub fn full_table(s: &str) -> Result<String, JsValue> {
+    full_table_with(s, Arc::new(EmbedReader))
+}
+
+/// Like `full_table`, but finds parts in `files`, as `simulate_files` does.
+#[wasm_bindgen]
+pub fn full_table_files(s: &str, files: &str) -> Result<String, JsValue> {
+    full_table_with(s, memory_reader(files)?)
+}
+
+fn full_table_with(s: &str, provider: Arc<dyn HdlProvider>) -> Result<String, JsValue> {
     console_error_panic_hook::set_once();
-    let table = match full_table_internal(s, Rc::new(EmbedReader)) {
+    let table = match full_table_internal(s, provider) {
         Ok(x) => x,
         Err(e) => {
             return Err(JsValue::from(e.to_string()));
@@ -92,74 +166,67 @@ type Table = Vec<Vec<Vec<Option<bool>>>>;
 // Returns (column list, row values)
 pub fn full_table_internal(
     s: &str,
-    provider: Rc<dyn HdlProvider>,
+    provider: Arc<dyn HdlProvider>,
 ) -> Result<(Vec<String>, Table), Box<dyn Error>> {
     let mut scanner = Scanner::new(s, PathBuf::from(""));
     let mut parser = Parser {
         scanner: &mut scanner,
     };
 
-    let hdl = match parser.parse() {
-        Ok(x) => x,
-        Err(e) => return Err(e),
-    };
-
-    let chip = Chip::new(&hdl, ptr::null_mut(), &provider, false, &Vec::new())?;
-    let mut simulator = Simulator::new(chip);
+    let hdl = parser.parse()?;
 
-    // get total width of input ports
-    let total_width = &hdl
+    // The width of each input port, which has to be a number.
+    let mut widths = Vec::new();
+    for p in hdl
         .ports
         .iter()
         .filter(|p| p.direction == PortDirection::In)
-        .fold(0, |acc, p| {
-            if let GenericWidth::Terminal(Terminal::Num(w)) = p.width {
-                return acc + w;
+    {
+        match p.width {
+            GenericWidth::Terminal(Terminal::Num(w)) => widths.push((p.name.value.clone(), w)),
+            _ => {
+                return Err(Box::new(N2VError {
+                    msg: format!(
+                        "Truth tables need inputs of fixed width, and `{}` is generic.",
+                        p.name.value
+                    ),
+                    kind: ErrorKind::Other,
+                }))
             }
-            panic!("Generic widths not supported");
-        });
+        }
+    }
+    let total_width: usize = widths.iter().map(|(_, w)| w).sum();
+
+    let chip = Chip::new(&hdl, &provider, ChipOptions::default())?;
+    let mut simulator = Simulator::new(chip);
 
     let column_names: Vec<String> = hdl.ports.iter().map(|p| p.name.value.clone()).collect();
 
     let mut column_values: Vec<Vec<Vec<Option<bool>>>> = vec![];
 
-    let base: u32 = 2;
-    let total_rows = base.pow(*total_width as u32);
-
-    if total_rows > 1024 {
+    // 1024 rows, which also keeps the count of rows from overflowing.
+    const MAX_INPUT_BITS: usize = 10;
+    if total_width > MAX_INPUT_BITS {
         return Err(Box::new(N2VError {
-            msg: String::from("Too many rows in truth table to display (max 32)."),
+            msg: format!(
+                "Too many rows in truth table to display ({} input bits, max {}, which is 1024 rows).",
+                total_width, MAX_INPUT_BITS
+            ),
             kind: ErrorKind::Other,
         }));
     }
 
-    for i in 0..total_rows {
-        let binary_string = format!("{:0total_width$b}", i);
-        let mut bools: Vec<bool> = binary_string
-            .chars()
-            .map(|c| match c {
-                '0' => false,
-                '1' => true,
-                _ => {
-                    panic!("expected 0 or 1");
-                }
-            })
-            .collect();
-
+    for i in 0..1usize << total_width {
+        // The first port takes the lowest bits of the row number.
         let mut m: HashMap<String, Vec<bool>> = HashMap::new();
-        let mut remaining_width = *total_width;
-        for p in &hdl.ports {
-            if p.direction == PortDirection::Out {
-                continue;
-            }
-            if let GenericWidth::Terminal(Terminal::Num(w)) = p.width {
-                let port_bools = &bools[(bools.len() - w)..];
-                m.insert(p.name.value.clone(), port_bools.to_vec());
-                remaining_width -= w;
-            } else {
-                panic!("Generic widths not supported.");
-            }
-            bools.truncate(remaining_width);
+        let mut offset = 0;
+        for (name, w) in &widths {
+            let port_bools = (0..*w)
+                .rev()
+                .map(|bit| (i >> (offset + bit)) & 1 == 1)
+                .collect();
+            m.insert(name.clone(), port_bools);
+            offset += w;
         }
 
         let inputs = match BusMap::try_from(m) {
@@ -198,8 +265,15 @@ pub fn component_graphs(s: &str) -> Result<String, JsValue> {
         }
     };
 
-    let provider: Rc<dyn HdlProvider> = Rc::new(EmbedReader);
-    let chip = match Chip::new(&hdl, ptr::null_mut(), &provider, true, &Vec::new()) {
+    let provider: Arc<dyn HdlProvider> = Arc::new(EmbedReader);
+    let chip = match Chip::new(
+        &hdl,
+        &provider,
+        ChipOptions {
+            elaborate: true,
+            ..ChipOptions::default()
+        },
+    ) {
         Ok(x) => x,
         Err(e) => {
             return Err(JsValue::from(&e.to_string()));
@@ -229,10 +303,37 @@ mod libtest {
                 .to_str()
                 .unwrap(),
         );
-        let provider = Rc::new(FileReader::new(&base_path));
+        let provider = Arc::new(FileReader::new(&base_path));
         let contents = provider.get_hdl("And.hdl").unwrap();
         let (_, table) =
-            full_table_internal(&contents, Rc::new(FileReader::new(&base_path))).unwrap();
+            full_table_internal(&contents, Arc::new(FileReader::new(&base_path))).unwrap();
         assert_eq!(table.len(), 4);
+        // a is the lowest bit of the row number.
+        assert_eq!(table[1][0], vec![Some(true)]);
+        assert_eq!(table[1][1], vec![Some(false)]);
+
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(&base_path));
+        let wide = "CHIP Wide { IN a[16], b[16]; OUT out[16]; PARTS: And16(a=a, b=b, out=out); }";
+        let err = full_table_internal(wide, Arc::clone(&provider)).unwrap_err();
+        assert!(err.to_string().contains("32 input bits, max 10"), "{}", err);
+        let generic = "CHIP Generic<N> { IN a[N]; OUT out[N]; PARTS: }";
+        let err = full_table_internal(generic, provider).unwrap_err();
+        assert!(err.to_string().contains("`a` is generic"), "{}", err);
+    }
+
+    #[test]
+    fn test_files() {
+        let files = serde_json::json!({
+            "Inverter.hdl": "CHIP Inverter { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }",
+        })
+        .to_string();
+        let source = "CHIP Buffer { IN in; OUT out; PARTS: Inverter(in=in, out=x); Inverter(in=x, out=out); }";
+        let table = full_table_files(source, &files).unwrap();
+        assert_eq!(
+            table,
+            r#"[["in","out"],[[[false],[false]],[[true],[true]]]]"#
+        );
+        let outputs = simulate_files(source, r#"{"in": [true]}"#, &files).unwrap();
+        assert!(outputs.contains("out"), "{}", outputs);
     }
 }
@@ -0,0 +1,228 @@
+// Lints are the problems whidl warns about instead of refusing the chip,
+// such as a signal that is driven but never read. Each can be allowed,
+// warned about, or denied, from the command line with `--allow`, `--warn`
+// and `--deny`, or in the `lints` of a project file, e.g.
+// `{ "lints": { "port-order": "allow", "unused-wire": "deny" } }`. The
+// command line wins over the project file.
+
+use crate::elaborate::Warning;
+use crate::parser::{ChipHDL, Part, PortDirection};
+use serde::Deserialize;
+use std::collections::HashSet;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Lint {
+    UnconnectedInput,  // A part leaves one of its inputs unconnected.
+    NoSource,          // A signal is read, but nothing drives it.
+    UnusedWire,        // A signal is driven, but nothing reads it.
+    ShadowedGeneric,   // A loop iterator or constant has the name of a generic.
+    ShadowedChip,      // Another chip of the same name is on the search path.
+    PortOrder,         // A chip declares an input after its outputs.
+    TruncatedConstant, // A test script sets a port to a value too wide for it.
+    CombinationalLoop, // A signal depends on itself with no DFF between.
+}
+
+impl Lint {
+    pub const ALL: [Lint; 8] = [
+        Lint::UnconnectedInput,
+        Lint::NoSource,
+        Lint::UnusedWire,
+        Lint::ShadowedGeneric,
+        Lint::ShadowedChip,
+        Lint::PortOrder,
+        Lint::TruncatedConstant,
+        Lint::CombinationalLoop,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::UnconnectedInput => "unconnected-input",
+            Lint::NoSource => "no-source",
+            Lint::UnusedWire => "unused-wire",
+            Lint::ShadowedGeneric => "shadowed-generic",
+            Lint::ShadowedChip => "shadowed-chip",
+            Lint::PortOrder => "port-order",
+            Lint::TruncatedConstant => "truncated-constant",
+            Lint::CombinationalLoop => "combinational-loop",
+        }
+    }
+}
+
+impl std::fmt::Display for Lint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for Lint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Lint::ALL
+            .into_iter()
+            .find(|l| l.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Lint::ALL.iter().map(|l| l.name()).collect();
+                format!("`{}` is not a lint. Use one of {}.", s, names.join(", "))
+            })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// The level of each lint, as the command line and project file set them.
+/// Lints neither sets warn, or fail under `--strict`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Levels {
+    levels: [Option<Level>; Lint::ALL.len()],
+    strict: bool,
+}
+
+impl Levels {
+    /// Makes lints that neither the command line nor the project file sets
+    /// errors, such as a chip shadowing another of the same name.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Sets the level of a lint.
+    pub fn set(&mut self, lint: Lint, level: Level) {
+        self.levels[lint as usize] = Some(level);
+    }
+
+    /// Sets the level of a lint that has none yet, such as one the command
+    /// line leaves to the project file.
+    pub fn set_default(&mut self, lint: Lint, level: Level) {
+        self.levels[lint as usize].get_or_insert(level);
+    }
+
+    /// The level the lint is at.
+    pub fn level(&self, lint: Lint) -> Level {
+        match self.levels[lint as usize] {
+            Some(level) => level,
+            None if self.strict => Level::Deny,
+            None => Level::Warn,
+        }
+    }
+}
+
+/// Warnings about how a chip is written, before it is bound: names that
+/// hide generics, and inputs declared after outputs.
+pub fn hdl_warnings(hdl: &ChipHDL) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    let generics: HashSet<&str> = hdl.generic_decls.iter().map(|g| g.value.as_str()).collect();
+    let shadowed = |what: &str, name: &crate::parser::Identifier| Warning {
+        ident: name.clone(),
+        msg: format!(
+            "The {} {} has the name of a generic of {}, which it hides.",
+            what, name.value, hdl.name
+        ),
+        lint: Lint::ShadowedGeneric,
+    };
+    for c in &hdl.constants {
+        if generics.contains(c.name.value.as_str()) {
+            warnings.push(shadowed("constant", &c.name));
+        }
+    }
+    for part in &hdl.parts {
+        if let Part::Loop(l) = part {
+            if generics.contains(l.iterator.value.as_str()) {
+                warnings.push(shadowed("loop iterator", &l.iterator));
+            }
+        }
+    }
+
+    if let Some(first_out) = hdl
+        .ports
+        .iter()
+        .position(|p| p.direction == PortDirection::Out)
+    {
+        for p in &hdl.ports[first_out..] {
+            if p.direction == PortDirection::In {
+                warnings.push(Warning {
+                    ident: p.name.clone(),
+                    msg: format!(
+                        "Input {} is declared after the outputs of {}. Inputs usually come first.",
+                        p.name.value, hdl.name
+                    ),
+                    lint: Lint::PortOrder,
+                });
+            }
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_levels() {
+        let mut levels = Levels::default();
+        assert_eq!(levels.level(Lint::UnusedWire), Level::Warn);
+        levels.set(Lint::UnusedWire, Level::Allow);
+        // The project file only sets lints the command line has not.
+        levels.set_default(Lint::UnusedWire, Level::Deny);
+        levels.set_default(Lint::PortOrder, Level::Deny);
+        assert_eq!(levels.level(Lint::UnusedWire), Level::Allow);
+        assert_eq!(levels.level(Lint::PortOrder), Level::Deny);
+        assert_eq!(Levels::default().level(Lint::PortOrder), Level::Warn);
+        levels.set_strict(true);
+        assert_eq!(levels.level(Lint::UnusedWire), Level::Allow);
+        assert_eq!(levels.level(Lint::ShadowedChip), Level::Deny);
+    }
+
+    #[test]
+    fn test_hdl_warnings() {
+        let source = "CONST W 4;
+            CHIP Shadow<W, N> {
+                OUT out[N];
+                IN in[N];
+                PARTS:
+                FOR N IN 0 TO 1 GENERATE { Not(in=in[N], out=out[N]); }
+            }";
+        let mut scanner = Scanner::new(source, PathBuf::from("Shadow.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let warnings: Vec<(Lint, String)> = hdl_warnings(&hdl)
+            .into_iter()
+            .map(|w| (w.lint, w.msg))
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![
+                (
+                    Lint::ShadowedGeneric,
+                    String::from("The constant W has the name of a generic of Shadow, which it hides.")
+                ),
+                (
+                    Lint::ShadowedGeneric,
+                    String::from(
+                        "The loop iterator N has the name of a generic of Shadow, which it hides."
+                    )
+                ),
+                (
+                    Lint::PortOrder,
+                    String::from(
+                        "Input in is declared after the outputs of Shadow. Inputs usually come first."
+                    )
+                ),
+            ]
+        );
+
+        assert_eq!("unused-wire".parse(), Ok(Lint::UnusedWire));
+        assert!("unused".parse::<Lint>().is_err());
+    }
+}
@@ -0,0 +1,131 @@
+// Diagnostic logging through `tracing`. Subsystems open spans for parsing
+// (`parse`), elaboration (`elaborate`), simulation (`simulate` and `tick`),
+// and test scripts (`test` and `test_step`). Spans are logged when they
+// close, with the time spent in them, which is enough to profile long runs.
+//
+// Only warnings are logged unless `WHIDL_LOG` asks for more, in the syntax of
+// `tracing_subscriber::EnvFilter`, e.g. `WHIDL_LOG=debug` or
+// `WHIDL_LOG=whidl::simulator=trace`. Logs go to stderr, so they never mix
+// with the output of a command.
+
+use std::error::Error;
+use std::io;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+
+/// Environment variable with the filter for log levels.
+pub const LOG_ENV: &str = "WHIDL_LOG";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json, // One JSON object per line.
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "`{}` is not a log format. Use `text` or `json`.",
+                s
+            )),
+        }
+    }
+}
+
+/// Sends logs to stderr for the rest of the process.
+pub fn init(format: LogFormat) -> Result<(), Box<dyn Error>> {
+    let filter = match std::env::var(LOG_ENV) {
+        Ok(f) => EnvFilter::try_new(&f).map_err(|e| format!("{} is invalid: {}", LOG_ENV, e))?,
+        Err(_) => EnvFilter::new("warn"),
+    };
+    tracing::subscriber::set_global_default(subscriber(filter, format, io::stderr))?;
+    Ok(())
+}
+
+fn subscriber<W>(
+    filter: EnvFilter,
+    format: LogFormat,
+    writer: W,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_span_events(FmtSpan::CLOSE);
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().finish()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::busmap::BusMap;
+    use crate::parser::*;
+    use crate::scanner::Scanner;
+    use crate::simulator::{Chip, ChipOptions, Simulator};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_spans() {
+        let buffer = Buffer(Arc::new(Mutex::new(Vec::new())));
+        let writer = buffer.clone();
+        let subscriber = subscriber(EnvFilter::new("debug"), LogFormat::Json, move || {
+            writer.clone()
+        });
+        tracing::subscriber::with_default(subscriber, || {
+            let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new("."));
+            let mut scanner = Scanner::new(
+                "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }",
+                PathBuf::from("Not.hdl"),
+            );
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse().expect("Parse error");
+            let chip =
+                Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+            let mut simulator = Simulator::new(chip);
+            let mut inputs = BusMap::new();
+            inputs.create_bus("in", 1).unwrap();
+            simulator.simulate(&inputs).expect("Simulation error");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|l| serde_json::from_str(l).expect("Not a JSON line"))
+            .collect();
+        let spans: Vec<&str> = lines
+            .iter()
+            .filter_map(|l| l["span"]["name"].as_str())
+            .collect();
+        assert!(spans.contains(&"parse"), "{}", output);
+        assert!(spans.contains(&"elaborate"), "{}", output);
+    }
+}
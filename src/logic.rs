@@ -0,0 +1,146 @@
+// The values a bit takes in the simulator: 0 and 1, X for a bit whose value
+// is not known, such as one computed from an input no test has set, and Z
+// for a bit that nothing drives, such as the output of a disabled tri-state
+// buffer. A gate reads Z as X, since a floating input could be either.
+//
+// How far X spreads through gates is set by the `XPolicy`. Flip-flops start
+// at 0 on every engine, so a chip starts in a known state, and at a tick
+// load X for an input of X or Z, since they cannot hold a bit nothing
+// drives.
+
+/// A value of one bit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Logic {
+    Zero,
+    One,
+    X, // Driven, but not known.
+    Z, // Not driven.
+}
+
+impl From<bool> for Logic {
+    fn from(b: bool) -> Logic {
+        if b {
+            Logic::One
+        } else {
+            Logic::Zero
+        }
+    }
+}
+
+impl From<Option<bool>> for Logic {
+    fn from(b: Option<bool>) -> Logic {
+        b.map_or(Logic::X, Logic::from)
+    }
+}
+
+/// The value of a bit as the parts of the simulator that only know known
+/// and unknown bits see it. Both X and Z are unknown.
+impl From<Logic> for Option<bool> {
+    fn from(l: Logic) -> Option<bool> {
+        match l {
+            Logic::Zero => Some(false),
+            Logic::One => Some(true),
+            Logic::X | Logic::Z => None,
+        }
+    }
+}
+
+impl Logic {
+    /// The NAND of two bits under `policy`.
+    pub fn nand(self, other: Logic, policy: XPolicy) -> Logic {
+        match (Option::from(self), Option::from(other)) {
+            (Some(a), Some(b)) => Logic::from(!(a && b)),
+            (Some(false), None) | (None, Some(false)) if policy == XPolicy::Optimistic => {
+                Logic::One
+            }
+            _ => Logic::X,
+        }
+    }
+
+    /// The output of a tri-state buffer whose input is `self`. A disabled
+    /// buffer drives nothing, and one driven by Z passes X on.
+    pub fn buffer(self, enable: Logic) -> Logic {
+        match enable {
+            Logic::One => Logic::from(Option::from(self)),
+            Logic::Zero => Logic::Z,
+            _ => Logic::X,
+        }
+    }
+
+    /// The value of a wire that `self` and `other` both drive. A driver of Z
+    /// gives way to the other, and drivers that disagree make X.
+    pub fn resolve(self, other: Logic) -> Logic {
+        match (self, other) {
+            (Logic::Z, b) => b,
+            (a, Logic::Z) => a,
+            (a, b) if a == b => a,
+            _ => Logic::X,
+        }
+    }
+
+    /// The bit as test reports print it.
+    pub fn to_char(self) -> char {
+        match self {
+            Logic::Zero => '0',
+            Logic::One => '1',
+            Logic::X => '?',
+            Logic::Z => 'z',
+        }
+    }
+}
+
+/// How unknown bits spread through gates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XPolicy {
+    Pessimistic, // A gate with an unknown input has an unknown output.
+    Optimistic,  // Unless another input decides the output, as a 0 does a NAND's.
+}
+
+impl std::str::FromStr for XPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pessimistic" => Ok(XPolicy::Pessimistic),
+            "optimistic" => Ok(XPolicy::Optimistic),
+            _ => Err(format!(
+                "`{}` is not an X policy. Use `pessimistic` or `optimistic`.",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Logic::*;
+
+    #[test]
+    fn test_nand() {
+        for policy in [XPolicy::Pessimistic, XPolicy::Optimistic] {
+            assert_eq!(Zero.nand(One, policy), One);
+            assert_eq!(One.nand(One, policy), Zero);
+            assert_eq!(One.nand(X, policy), X);
+            assert_eq!(Z.nand(One, policy), X);
+            assert_eq!(X.nand(Z, policy), X);
+        }
+        assert_eq!(Zero.nand(X, XPolicy::Pessimistic), X);
+        assert_eq!(Zero.nand(X, XPolicy::Optimistic), One);
+        assert_eq!(Z.nand(Zero, XPolicy::Optimistic), One);
+    }
+
+    #[test]
+    fn test_tristate() {
+        assert_eq!(One.buffer(One), One);
+        assert_eq!(Z.buffer(One), X);
+        assert_eq!(One.buffer(Zero), Z);
+        assert_eq!(Zero.buffer(X), X);
+        assert_eq!(Z.resolve(One), One);
+        assert_eq!(Zero.resolve(Z), Zero);
+        assert_eq!(Z.resolve(Z), Z);
+        assert_eq!(One.resolve(One), One);
+        assert_eq!(One.resolve(Zero), X);
+        assert_eq!(X.resolve(Z), X);
+    }
+}
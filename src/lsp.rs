@@ -0,0 +1,1910 @@
+// Editor features for HDL files. Results use the same concepts as the
+// Language Server Protocol (zero-based positions, relative token encoding)
+// so a language server can hand them to the client without translation.
+//
+// Everything here works on the token stream instead of the parse tree so
+// that highlighting and the outline keep working while a file has errors.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use crate::error::{ErrorKind, N2VError};
+use crate::expr::{
+    eval_expr, eval_expr_numeric, replace_expr, Cmp, GenericValue, GenericWidth, Op, Terminal,
+};
+use crate::parser::{ChipHDL, Component, HdlProvider, Identifier, Parser, Part};
+use crate::refactor::{extract_chip, inline_chip, part_spans};
+use crate::scanner::{Scanner, Span, Token, TokenType};
+use crate::simulator::{infer_widths, Chip};
+use crate::test_parser::TestParser;
+use crate::test_scanner::TestScanner;
+use crate::test_script::{run_test_report, TestStatus};
+use crate::visit::{walk_chip, Visitor};
+
+/// Zero-based line and character offset, like an LSP `Position`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: u32,
+    pub character: u32,
+}
+
+/// Half-open range between two positions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SemanticTokenType {
+    Keyword,
+    Chip,
+    Port,
+    Wire,
+    Generic,
+    Number,
+}
+
+impl SemanticTokenType {
+    /// Token type names in the order of their index in encoded tokens.
+    /// A language server advertises this as its semantic token legend.
+    pub const LEGEND: [&'static str; 6] = [
+        "keyword",
+        "class",
+        "property",
+        "variable",
+        "typeParameter",
+        "number",
+    ];
+
+    fn index(&self) -> u32 {
+        match self {
+            SemanticTokenType::Keyword => 0,
+            SemanticTokenType::Chip => 1,
+            SemanticTokenType::Port => 2,
+            SemanticTokenType::Wire => 3,
+            SemanticTokenType::Generic => 4,
+            SemanticTokenType::Number => 5,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SemanticToken {
+    pub range: Range,
+    pub token_type: SemanticTokenType,
+}
+
+/// A single edit to previously encoded semantic tokens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SemanticTokensEdit {
+    pub start: u32,
+    pub delete_count: u32,
+    pub data: Vec<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    Chip,
+    Port,
+    Parts,
+    Component,
+    Loop,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DocumentSymbol {
+    pub name: String,
+    pub detail: Option<String>,
+    pub kind: SymbolKind,
+    pub range: Range,
+    pub selection_range: Range, // Range of the name.
+    pub children: Vec<DocumentSymbol>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FoldingRange {
+    pub start_line: u32,
+    pub end_line: u32,
+}
+
+/// A label shown inline in the editor, like an LSP `InlayHint`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InlayHint {
+    pub position: Position,
+    pub label: String,
+}
+
+/// Markdown shown when hovering over a range, like an LSP `Hover`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hover {
+    pub range: Range,
+    pub contents: String,
+}
+
+/// A range in a file, like an LSP `Location`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub path: PathBuf,
+    pub range: Range,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextEdit {
+    pub range: Range,
+    pub new_text: String,
+}
+
+/// Edits across several files, like an LSP `WorkspaceEdit`.
+/// Renaming a chip also renames the file that declares it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WorkspaceEdit {
+    pub changes: BTreeMap<PathBuf, Vec<TextEdit>>,
+    pub renamed_files: Vec<(PathBuf, PathBuf)>,
+    pub created_files: BTreeMap<PathBuf, String>, // New files and their contents.
+}
+
+/// A refactoring offered for a selection, like an LSP `CodeAction`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CodeAction {
+    pub title: String,
+    pub edit: WorkspaceEdit,
+}
+
+/// A syntax error or a protocol violation, like an LSP `Diagnostic` with
+/// error severity.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub range: Range,
+    pub message: String,
+}
+
+/// What clicking a code lens does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LensCommand {
+    RunTest,
+    DebugStep(usize), // Runs the test up to this step, numbered from 1.
+}
+
+/// A command shown above a line of a test script, like an LSP `CodeLens`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CodeLens {
+    pub range: Range,
+    pub title: String,
+    pub command: LensCommand,
+}
+
+/// Results of a code lens, shown inline next to the steps they belong to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestRun {
+    pub hints: Vec<InlayHint>,
+    pub diagnostics: Vec<Diagnostic>, // Violations found by monitor chips.
+    pub passed: bool,
+    pub cancelled: bool,
+}
+
+// What a name in the source refers to. Ports are qualified by their chip.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Target {
+    Chip(String),
+    Port(String, String),
+}
+
+#[derive(Clone, Debug)]
+struct Reference {
+    target: Target,
+    range: Range,
+    declaration: bool,
+}
+
+struct FileIndex {
+    chip: Option<String>,
+    references: Vec<Reference>,
+    source: String,
+    hdl: Option<ChipHDL>,
+}
+
+/// Index of every HDL file reachable from a chip through its parts.
+/// Chips are loaded through the provider the same way the simulator
+/// loads them, so the workspace is the dependency graph of the chip.
+pub struct Workspace {
+    provider: Arc<dyn HdlProvider>,
+    files: BTreeMap<PathBuf, FileIndex>,
+    loaded: HashSet<String>,
+}
+
+impl Workspace {
+    /// Indexes `top` and all of the chips it depends on.
+    pub fn index(top: &str, provider: &Arc<dyn HdlProvider>) -> Workspace {
+        let mut workspace = Workspace {
+            provider: provider.clone(),
+            files: BTreeMap::new(),
+            loaded: HashSet::new(),
+        };
+        workspace.load(vec![String::from(top)]);
+        workspace
+    }
+
+    /// Re-indexes a file after it was edited, loading any new dependencies.
+    pub fn update(&mut self, path: &Path, source: &str) {
+        let dependencies = self.insert(path.to_path_buf(), source);
+        self.load(dependencies);
+    }
+
+    /// Paths of the indexed files.
+    pub fn paths(&self) -> Vec<&Path> {
+        self.files.keys().map(|p| p.as_path()).collect()
+    }
+
+    // Loads chips breadth first. Chips without a file, such as Nand and
+    // DFF, are skipped.
+    fn load(&mut self, chips: Vec<String>) {
+        let mut queue: VecDeque<String> = chips.into();
+        while let Some(name) = queue.pop_front() {
+            if !self.loaded.insert(name.clone()) {
+                continue;
+            }
+            let file_name = format!("{}.hdl", name);
+            if let Ok(source) = self.provider.get_hdl(&file_name) {
+                let path = self.provider.get_path(&file_name);
+                queue.extend(self.insert(path, &source));
+            }
+        }
+    }
+
+    // Indexes a file and returns the chips it uses.
+    fn insert(&mut self, path: PathBuf, source: &str) -> Vec<String> {
+        let references = analyze(source).references;
+        let chip = references.iter().find_map(|r| match &r.target {
+            Target::Chip(name) if r.declaration => Some(name.clone()),
+            _ => None,
+        });
+        if let Some(c) = &chip {
+            self.loaded.insert(c.clone());
+        }
+        let dependencies = references
+            .iter()
+            .filter_map(|r| match &r.target {
+                Target::Chip(name) if !r.declaration && !self.loaded.contains(name) => {
+                    Some(name.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        let hdl = parse(source, &path);
+        self.files.insert(
+            path,
+            FileIndex {
+                chip,
+                references,
+                source: String::from(source),
+                hdl,
+            },
+        );
+        dependencies
+    }
+
+    fn target_at(&self, path: &Path, position: Position) -> Option<&Target> {
+        self.files
+            .get(path)?
+            .references
+            .iter()
+            .find(|r| r.range.start <= position && position <= r.range.end)
+            .map(|r| &r.target)
+    }
+
+    fn declares(&self, chip: &str) -> bool {
+        self.files.values().any(|f| f.chip.as_deref() == Some(chip))
+    }
+
+    /// Every set of generic arguments `chip` is instantiated with in the
+    /// workspace, found by evaluating the instantiations with the bindings
+    /// of their own parents.
+    pub fn generic_bindings(&self, chip: &str) -> Vec<Vec<usize>> {
+        self.bindings(chip, &mut HashSet::new())
+    }
+
+    fn bindings(&self, chip: &str, visiting: &mut HashSet<String>) -> Vec<Vec<usize>> {
+        // Chips that instantiate themselves would never finish.
+        if !visiting.insert(String::from(chip)) {
+            return Vec::new();
+        }
+
+        let mut res = Vec::new();
+        for hdl in self.files.values().filter_map(|f| f.hdl.as_ref()) {
+            let uses: Vec<&Component> = hdl
+                .parts
+                .iter()
+                .flat_map(|p| match p {
+                    Part::Component(c) => vec![c],
+                    Part::Loop(l) => l.body.iter().collect(),
+                })
+                .filter(|c| c.name.value == chip && !c.generic_params.is_empty())
+                .collect();
+            if uses.is_empty() {
+                continue;
+            }
+
+            let parent_bindings = if hdl.generic_decls.is_empty() {
+                vec![Vec::new()]
+            } else {
+                self.bindings(&hdl.name, visiting)
+            };
+            for generics in parent_bindings {
+                let variables: HashMap<String, usize> = hdl
+                    .generic_decls
+                    .iter()
+                    .map(|g| g.value.clone())
+                    .zip(generics.iter().cloned())
+                    .collect();
+                // Parameters that depend on a loop iterator are skipped, and
+                // so are named and string parameters.
+                for c in &uses {
+                    if let Some(b) = c
+                        .generic_params
+                        .iter()
+                        .map(|g| match (&g.name, g.value.width()) {
+                            (None, Some(w)) => eval_expr_numeric(w, &variables).ok(),
+                            _ => None,
+                        })
+                        .collect::<Option<Vec<usize>>>()
+                    {
+                        res.push(b);
+                    }
+                }
+            }
+        }
+
+        visiting.remove(chip);
+        res.sort();
+        res.dedup();
+        res
+    }
+
+    /// Evaluates the generic expression at `position`, such as `W-1`, for
+    /// each binding of the chip's generics used in the workspace.
+    pub fn hover_expression(&self, path: &Path, position: Position) -> Option<Hover> {
+        let file = self.files.get(path)?;
+        let hdl = file.hdl.as_ref()?;
+        let tokens: Vec<Token> = Scanner::new(&file.source, path.to_path_buf())
+            .filter_map(Result::ok)
+            .collect();
+
+        let declared: Vec<&str> = hdl.generic_decls.iter().map(|g| g.value.as_str()).collect();
+
+        // The outermost width expression around the position that uses a
+        // generic, found through the tokens its first generic was read from.
+        let mut widths = Widths(Vec::new());
+        walk_chip(&mut widths, hdl);
+        let (expr, first, last) = widths.0.into_iter().find_map(|expr| {
+            if !expr
+                .variables()
+                .iter()
+                .any(|v| declared.contains(&v.as_str()))
+            {
+                return None;
+            }
+            let (count, var) = width_tokens(&expr);
+            let (before, var) = var?;
+            let at = tokens.iter().position(|t| Some(t.span()) == var.span)?;
+            let first = at.checked_sub(before)?;
+            let last = first + count - 1;
+            let range = span(
+                token_range(tokens.get(first)?),
+                token_range(tokens.get(last)?),
+            );
+            (range.start <= position && position <= range.end).then_some((expr, first, last))
+        })?;
+
+        let range = span(token_range(&tokens[first]), token_range(&tokens[last]));
+        let mut contents = format!("`{}`\n", text_at(&file.source, range));
+        let bindings = self.generic_bindings(&hdl.name);
+        let mut evaluated = false;
+        for generics in &bindings {
+            let variables: HashMap<String, usize> = declared
+                .iter()
+                .map(|g| String::from(*g))
+                .zip(generics.iter().cloned())
+                .collect();
+            if let Ok(value) = eval_expr_numeric(&expr, &variables) {
+                let names: Vec<String> = declared
+                    .iter()
+                    .zip(generics)
+                    .map(|(g, v)| format!("{} = {}", g, v))
+                    .collect();
+                contents.push_str(&format!("\n- {}: {}", names.join(", "), value));
+                evaluated = true;
+            }
+        }
+        if !evaluated {
+            contents.push_str(&format!(
+                "\nNo instantiation of {} in the workspace binds its generics.",
+                hdl.name
+            ));
+        }
+
+        Some(Hover { range, contents })
+    }
+
+    /// Every use of the chip or port at `position` across the workspace.
+    pub fn find_references(
+        &self,
+        path: &Path,
+        position: Position,
+        include_declaration: bool,
+    ) -> Vec<Location> {
+        let target = match self.target_at(path, position) {
+            Some(t) => t,
+            None => return Vec::new(),
+        };
+        let mut locations = Vec::new();
+        for (p, f) in &self.files {
+            for r in &f.references {
+                if &r.target == target && (include_declaration || !r.declaration) {
+                    locations.push(Location {
+                        path: p.clone(),
+                        range: r.range,
+                    });
+                }
+            }
+        }
+        locations
+    }
+
+    /// Renames the chip or port at `position` everywhere it is used.
+    pub fn rename(
+        &self,
+        path: &Path,
+        position: Position,
+        new_name: &str,
+    ) -> Result<WorkspaceEdit, Box<dyn Error>> {
+        let rename_error = |msg: String| {
+            Box::new(N2VError {
+                msg,
+                kind: ErrorKind::Other,
+            })
+        };
+
+        let tokens: Vec<Token> = Scanner::new(new_name, PathBuf::new())
+            .collect::<Result<_, _>>()
+            .unwrap_or_default();
+        if tokens.len() != 1
+            || tokens[0].token_type != TokenType::Identifier
+            || tokens[0].lexeme != new_name
+        {
+            return Err(rename_error(format!("`{}` is not a valid name.", new_name)));
+        }
+
+        let target = match self.target_at(path, position) {
+            Some(t) => t.clone(),
+            None => return Err(rename_error(String::from("Nothing to rename here."))),
+        };
+        let chip = match &target {
+            Target::Chip(c) | Target::Port(c, _) => c,
+        };
+        if !self.declares(chip) {
+            return Err(rename_error(format!(
+                "Chip {} is not defined in the workspace and cannot be renamed.",
+                chip
+            )));
+        }
+        let conflict = match &target {
+            Target::Chip(_) => self.declares(new_name),
+            Target::Port(c, _) => self.files.values().any(|f| {
+                f.references.iter().any(|r| {
+                    r.declaration && r.target == Target::Port(c.clone(), String::from(new_name))
+                })
+            }),
+        };
+        if conflict {
+            return Err(rename_error(format!("`{}` is already defined.", new_name)));
+        }
+
+        let mut edit = WorkspaceEdit::default();
+        for (p, f) in &self.files {
+            let edits: Vec<TextEdit> = f
+                .references
+                .iter()
+                .filter(|r| r.target == target)
+                .map(|r| TextEdit {
+                    range: r.range,
+                    new_text: String::from(new_name),
+                })
+                .collect();
+            if !edits.is_empty() {
+                edit.changes.insert(p.clone(), edits);
+            }
+            if let Target::Chip(c) = &target {
+                if f.chip.as_ref() == Some(c) {
+                    let renamed = p.with_file_name(format!("{}.hdl", new_name));
+                    edit.renamed_files.push((p.clone(), renamed));
+                }
+            }
+        }
+        Ok(edit)
+    }
+}
+
+/// Refactorings for the parts covered by `range`: extracting them into a
+/// new chip, or inlining a single part. Only refactorings that apply
+/// cleanly are offered. The extracted chip gets a placeholder name that
+/// can be changed with rename.
+pub fn code_actions(
+    source: &str,
+    path: &Path,
+    range: Range,
+    provider: &Arc<dyn HdlProvider>,
+) -> Vec<CodeAction> {
+    let selected: Vec<usize> = part_spans(source)
+        .iter()
+        .enumerate()
+        .filter(|(_, span)| {
+            position_at(source, span.start) <= range.end
+                && range.start <= position_at(source, span.end)
+        })
+        .map(|(i, _)| i)
+        .collect();
+    let (first, last) = match (selected.first(), selected.last()) {
+        (Some(f), Some(l)) => (*f, *l),
+        _ => return Vec::new(),
+    };
+    let whole_file = Range {
+        start: Position {
+            line: 0,
+            character: 0,
+        },
+        end: position_at(source, source.len()),
+    };
+    let replace_file = |text: String| {
+        BTreeMap::from([(
+            path.to_path_buf(),
+            vec![TextEdit {
+                range: whole_file,
+                new_text: text,
+            }],
+        )])
+    };
+
+    let mut actions = Vec::new();
+    let mut name = String::from("NewChip");
+    let mut i = 2;
+    while provider.get_hdl(&format!("{}.hdl", name)).is_ok() {
+        name = format!("NewChip{}", i);
+        i += 1;
+    }
+    if let Ok(extraction) = extract_chip(source, path, first..last + 1, &name, provider) {
+        actions.push(CodeAction {
+            title: String::from("Extract parts into a new chip"),
+            edit: WorkspaceEdit {
+                changes: replace_file(extraction.parent),
+                renamed_files: Vec::new(),
+                created_files: BTreeMap::from([(
+                    path.with_file_name(format!("{}.hdl", name)),
+                    extraction.chip,
+                )]),
+            },
+        });
+    }
+    if first == last {
+        if let Ok(inlined) = inline_chip(source, path, first, provider) {
+            actions.push(CodeAction {
+                title: String::from("Inline chip"),
+                edit: WorkspaceEdit {
+                    changes: replace_file(inlined),
+                    ..WorkspaceEdit::default()
+                },
+            });
+        }
+    }
+    actions
+}
+
+// Position of a byte offset in the source.
+fn position_at(source: &str, offset: usize) -> Position {
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    Position {
+        line: before.matches('\n').count() as u32,
+        character: before[line_start..].chars().count() as u32,
+    }
+}
+
+/// Inferred widths of internal wires, shown after each use of a wire.
+/// `generics` binds the generic parameters of the chip. Widths can only
+/// be inferred once every generic is bound, so otherwise there are no hints.
+pub fn inlay_hints(
+    source: &str,
+    path: &Path,
+    provider: &Arc<dyn HdlProvider>,
+    generics: &[usize],
+) -> Vec<InlayHint> {
+    let widths = match wire_widths(source, path, provider, generics) {
+        Some(w) => w,
+        None => return Vec::new(),
+    };
+    semantic_tokens(source)
+        .iter()
+        .filter(|t| t.token_type == SemanticTokenType::Wire)
+        .filter_map(|t| {
+            let width = widths.get(&text_at(source, t.range))?;
+            Some(InlayHint {
+                position: t.range.end,
+                label: format!(": {}", width),
+            })
+        })
+        .collect()
+}
+
+fn wire_widths(
+    source: &str,
+    path: &Path,
+    provider: &Arc<dyn HdlProvider>,
+    generics: &[usize],
+) -> Option<HashMap<String, GenericWidth>> {
+    let hdl = parse(source, path)?;
+    if hdl.generic_decls.len() != generics.len() {
+        return None;
+    }
+    let components = Chip::generate_components(&hdl, &generics.to_vec()).ok()?;
+    let bound: Vec<GenericValue> = generics.iter().map(|g| GenericValue::from(*g)).collect();
+    infer_widths(&hdl, &components, provider, &bound).ok()
+}
+
+/// Describes the FOR loop at `position`: its concrete range for the
+/// generic bindings and the first component it expands to.
+pub fn hover(source: &str, path: &Path, position: Position, generics: &[usize]) -> Option<Hover> {
+    let symbols = document_symbols(source);
+    let symbol = find_loop(&symbols, position)?;
+    let hdl = parse(source, path)?;
+    let l = hdl.parts.iter().find_map(|p| match p {
+        Part::Loop(l) if l.iterator.line == Some(symbol.selection_range.start.line + 1) => Some(l),
+        _ => None,
+    })?;
+
+    let variables: HashMap<String, usize> = hdl
+        .generic_decls
+        .iter()
+        .map(|g| g.value.clone())
+        .zip(generics.iter().cloned())
+        .collect();
+    let (start, end) = match (
+        eval_expr_numeric(&l.start, &variables),
+        eval_expr_numeric(&l.end, &variables),
+    ) {
+        (Ok(s), Ok(e)) => (s, e),
+        _ => {
+            return Some(Hover {
+                range: symbol.range,
+                contents: format!(
+                    "FOR {} IN {} TO {}\n\nBind the generics of {} to see the expansion.",
+                    l.iterator.value, l.start, l.end, hdl.name
+                ),
+            });
+        }
+    };
+
+    let mut contents = format!(
+        "FOR {} IN {} TO {} ({} iterations)",
+        l.iterator.value,
+        start,
+        end,
+        (end + 1).saturating_sub(start)
+    );
+    if start <= end {
+        let mut state: HashMap<String, GenericWidth> = variables
+            .iter()
+            .map(|(k, v)| (k.clone(), GenericWidth::Terminal(Terminal::Num(*v))))
+            .collect();
+        state.insert(
+            l.iterator.value.clone(),
+            GenericWidth::Terminal(Terminal::Num(start)),
+        );
+        contents.push_str(&format!("\n\n{} = {}:\n```\n", l.iterator.value, start));
+        for c in &l.body {
+            contents.push_str(&format!("{};\n", expand(c, &l.iterator.value, &state)));
+        }
+        contents.push_str("```");
+    }
+
+    Some(Hover {
+        range: symbol.range,
+        contents,
+    })
+}
+
+fn find_loop(symbols: &[DocumentSymbol], position: Position) -> Option<&DocumentSymbol> {
+    symbols.iter().find_map(|s| {
+        if s.kind == SymbolKind::Loop && s.range.start <= position && position <= s.range.end {
+            Some(s)
+        } else {
+            find_loop(&s.children, position)
+        }
+    })
+}
+
+// Substitutes the iterator and generics into a component of a loop.
+fn expand(c: &Component, iterator: &String, state: &HashMap<String, GenericWidth>) -> Component {
+    let replace = |w: &GenericWidth| eval_expr(&replace_expr(w, iterator, &state[iterator]), state);
+    let mut expanded = c.clone();
+    for m in &mut expanded.mappings {
+        m.port.start = m.port.start.as_ref().map(replace);
+        m.port.end = m.port.end.as_ref().map(replace);
+        m.wire.start = m.wire.start.as_ref().map(replace);
+        m.wire.end = m.wire.end.as_ref().map(replace);
+    }
+    expanded.generic_params = expanded
+        .generic_params
+        .iter()
+        .map(|g| g.map_width(replace))
+        .collect();
+    expanded
+}
+
+// Parses as much of the chip as possible, so features keep working on
+// the parts of a file that are correct.
+fn parse(source: &str, path: &Path) -> Option<ChipHDL> {
+    let mut scanner = Scanner::new(source, path.to_path_buf());
+    let mut parser = Parser {
+        scanner: &mut scanner,
+    };
+    parser.parse_recovering().0
+}
+
+/// "Run test" above the first line of a test script and "Debug step N"
+/// above each of its steps.
+pub fn test_lenses(source: &str, path: &Path) -> Vec<CodeLens> {
+    let mut scanner = TestScanner::new(source, path.to_path_buf());
+    let mut parser = TestParser {
+        scanner: &mut scanner,
+    };
+    let script = match parser.parse() {
+        Ok(s) => s,
+        Err(_) => return Vec::new(),
+    };
+    let line_start = |line: u32| {
+        let p = Position {
+            line: line - 1,
+            character: 0,
+        };
+        Range { start: p, end: p }
+    };
+
+    let mut lenses = Vec::new();
+    if let Some(first) = TestScanner::new(source, path.to_path_buf()).next() {
+        lenses.push(CodeLens {
+            range: line_start(first.line),
+            title: String::from("Run test"),
+            command: LensCommand::RunTest,
+        });
+    }
+    for (i, step) in script.steps.iter().enumerate() {
+        lenses.push(CodeLens {
+            range: line_start(step.line),
+            title: format!("Debug step {}", i + 1),
+            command: LensCommand::DebugStep(i + 1),
+        });
+    }
+    lenses
+}
+
+/// Runs the test script at `path` for a lens. Running the test marks each
+/// step as passed or shows the signals that differ; debugging a step shows
+/// every signal after it. Hints go at the end of the step's first line.
+/// The run stops between steps once `cancel` is set.
+pub fn run_lens(
+    source: &str,
+    path: &Path,
+    command: LensCommand,
+    cancel: &AtomicBool,
+) -> Result<TestRun, Box<dyn Error>> {
+    let last_step = match command {
+        LensCommand::RunTest => None,
+        LensCommand::DebugStep(n) => Some(n),
+    };
+    let report = run_test_report(&path.to_string_lossy(), last_step, cancel)?;
+
+    let mut hints = Vec::new();
+    let mut diagnostics = Vec::new();
+    for step in &report.steps {
+        let line = step.line - 1;
+        let line_text = source.lines().nth(line as usize).unwrap_or_default();
+        for violation in &step.violations {
+            let start = line_text.len() - line_text.trim_start().len();
+            diagnostics.push(Diagnostic {
+                range: Range {
+                    start: Position {
+                        line,
+                        character: line_text[..start].chars().count() as u32,
+                    },
+                    end: Position {
+                        line,
+                        character: line_text.chars().count() as u32,
+                    },
+                },
+                message: violation.clone(),
+            });
+        }
+        let label = match command {
+            LensCommand::RunTest if step.passed => String::from("✔"),
+            LensCommand::RunTest => {
+                let differences: Vec<String> = step
+                    .expected
+                    .iter()
+                    .filter(|(name, bits)| step.outputs.get(*name) != Some(bits))
+                    .map(|(name, bits)| {
+                        let actual = step.outputs.get(name).map_or("?", |b| b.as_str());
+                        format!("{}: expected {}, got {}", name, bits, actual)
+                    })
+                    .chain(step.violations.iter().cloned())
+                    .collect();
+                format!("✘ {}", differences.join("; "))
+            }
+            LensCommand::DebugStep(n) if step.step == n => {
+                let signals: Vec<String> = step
+                    .inputs
+                    .iter()
+                    .chain(
+                        step.outputs
+                            .iter()
+                            .filter(|(name, _)| !step.inputs.contains_key(*name)),
+                    )
+                    .map(|(name, bits)| format!("{}={}", name, bits))
+                    .collect();
+                signals.join(" ")
+            }
+            LensCommand::DebugStep(_) => continue,
+        };
+        hints.push(InlayHint {
+            position: Position {
+                line,
+                character: line_text.chars().count() as u32,
+            },
+            label,
+        });
+    }
+
+    Ok(TestRun {
+        hints,
+        diagnostics,
+        passed: report.status == TestStatus::Passed,
+        cancelled: report.status == TestStatus::Cancelled,
+    })
+}
+
+/// Every syntax error in the document.
+pub fn diagnostics(source: &str, path: &Path) -> Vec<Diagnostic> {
+    let mut scanner = Scanner::new(source, path.to_path_buf());
+    let mut parser = Parser {
+        scanner: &mut scanner,
+    };
+    parser
+        .parse_recovering()
+        .1
+        .into_iter()
+        .map(|e| Diagnostic {
+            range: e.span().as_ref().map(span_range).unwrap_or_default(),
+            message: e.msg,
+        })
+        .collect()
+}
+
+// Source text covered by a range on a single line.
+fn text_at(source: &str, range: Range) -> String {
+    source
+        .lines()
+        .nth(range.start.line as usize)
+        .map(|l| {
+            l.chars()
+                .skip(range.start.character as usize)
+                .take((range.end.character - range.start.character) as usize)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Classifies chips, ports, wires, and generics for highlighting.
+pub fn semantic_tokens(source: &str) -> Vec<SemanticToken> {
+    analyze(source).tokens
+}
+
+/// Outline of the chip: its ports, and the components in PARTS.
+pub fn document_symbols(source: &str) -> Vec<DocumentSymbol> {
+    analyze(source).symbols
+}
+
+/// Foldable regions for the chip body, PARTS, loops, and multi-line components.
+pub fn folding_ranges(source: &str) -> Vec<FoldingRange> {
+    let mut ranges = Vec::new();
+    collect_folds(&analyze(source).symbols, &mut ranges);
+    ranges
+}
+
+fn collect_folds(symbols: &[DocumentSymbol], ranges: &mut Vec<FoldingRange>) {
+    for s in symbols {
+        if s.range.end.line > s.range.start.line {
+            ranges.push(FoldingRange {
+                start_line: s.range.start.line,
+                end_line: s.range.end.line,
+            });
+        }
+        collect_folds(&s.children, ranges);
+    }
+}
+
+/// Encodes tokens the way LSP transmits them: five integers per token
+/// (delta line, delta start, length, token type, modifiers).
+pub fn encode_semantic_tokens(tokens: &[SemanticToken]) -> Vec<u32> {
+    let mut data = Vec::new();
+    let mut prev = Position {
+        line: 0,
+        character: 0,
+    };
+    for t in tokens {
+        let delta_line = t.range.start.line - prev.line;
+        let delta_start = if delta_line == 0 {
+            t.range.start.character - prev.character
+        } else {
+            t.range.start.character
+        };
+        data.extend([
+            delta_line,
+            delta_start,
+            t.range.end.character - t.range.start.character,
+            t.token_type.index(),
+            0,
+        ]);
+        prev = t.range.start;
+    }
+    data
+}
+
+/// Computes the edits that turn previously sent tokens into the current
+/// tokens, so only the part of the file that changed is retransmitted.
+pub fn semantic_tokens_delta(previous: &[u32], current: &[u32]) -> Vec<SemanticTokensEdit> {
+    let prefix = previous
+        .iter()
+        .zip(current)
+        .take_while(|(a, b)| a == b)
+        .count();
+    if prefix == previous.len() && prefix == current.len() {
+        return Vec::new();
+    }
+
+    let max_suffix = previous.len().min(current.len()) - prefix;
+    let suffix = previous
+        .iter()
+        .rev()
+        .zip(current.iter().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    vec![SemanticTokensEdit {
+        start: prefix as u32,
+        delete_count: (previous.len() - prefix - suffix) as u32,
+        data: current[prefix..current.len() - suffix].to_vec(),
+    }]
+}
+
+struct Analysis {
+    tokens: Vec<SemanticToken>,
+    symbols: Vec<DocumentSymbol>,
+    references: Vec<Reference>,
+}
+
+// Width expressions as written, without their subexpressions.
+struct Widths(Vec<GenericWidth>);
+
+impl Visitor for Widths {
+    fn visit_width(&mut self, width: &GenericWidth) {
+        self.0.push(width.clone());
+    }
+}
+
+// How many tokens the parser read for `width`, and the first generic var in
+// it with the number of tokens before it. Widths have no parentheses other
+// than those of function calls, so the count follows from the tree.
+fn width_tokens(width: &GenericWidth) -> (usize, Option<(usize, &Identifier)>) {
+    let token = (1, None);
+    let parts = match width {
+        GenericWidth::Terminal(Terminal::Var(v)) => return (1, Some((0, v))),
+        GenericWidth::Terminal(Terminal::Num(_)) => return token,
+        GenericWidth::Expr(Op::Add | Op::Sub, a, b) => {
+            vec![width_tokens(a), token, width_tokens(b)]
+        }
+        // `max(a, b)`
+        GenericWidth::Expr(Op::Max | Op::Min, a, b) => {
+            vec![(2, None), width_tokens(a), token, width_tokens(b), token]
+        }
+        GenericWidth::Log2(a) => vec![(2, None), width_tokens(a), token],
+        // `if(left > right, a, b)`, where `>=` and the like are two tokens.
+        GenericWidth::If(c, a, b) => {
+            let cmp = if matches!(c.cmp, Cmp::Lt | Cmp::Gt) {
+                1
+            } else {
+                2
+            };
+            vec![
+                (2, None),
+                width_tokens(&c.left),
+                (cmp, None),
+                width_tokens(&c.right),
+                token,
+                width_tokens(a),
+                token,
+                width_tokens(b),
+                token,
+            ]
+        }
+    };
+    let mut count = 0;
+    let mut first = None;
+    for (n, var) in parts {
+        if first.is_none() {
+            first = var.map(|(before, v)| (count + before, v));
+        }
+        count += n;
+    }
+    (count, first)
+}
+
+fn token_range(t: &Token) -> Range {
+    span_range(&t.span())
+}
+
+fn span_range(s: &Span) -> Range {
+    Range {
+        start: Position {
+            line: s.start_line - 1,
+            character: s.start_col - 1,
+        },
+        end: Position {
+            line: s.end_line - 1,
+            character: s.end_col - 1,
+        },
+    }
+}
+
+fn span(start: Range, end: Range) -> Range {
+    Range {
+        start: start.start,
+        end: end.end,
+    }
+}
+
+// Where we are in the chip definition.
+#[derive(PartialEq, Eq)]
+enum Section {
+    Header,
+    Ports(&'static str), // IN, OUT, CLOCKED, or BUILTIN declaration.
+    Body,
+    Parts,
+}
+
+// Whether the token at `i` is the wire an expression part such as
+// `out[2] = a AND b;` assigns to.
+fn assignment_target(tokens: &[Token], i: usize) -> bool {
+    if tokens[i].token_type != TokenType::Identifier {
+        return false;
+    }
+    let mut rest = tokens[i + 1..].iter().map(|t| t.token_type);
+    match rest.next() {
+        Some(TokenType::Equal) => true,
+        Some(TokenType::LeftBracket) => rest
+            .find(|&t| t == TokenType::RightBracket || t == TokenType::Semicolon)
+            .is_some_and(|t| t == TokenType::RightBracket && rest.next() == Some(TokenType::Equal)),
+        _ => false,
+    }
+}
+
+fn analyze(source: &str) -> Analysis {
+    let tokens: Vec<Token> = Scanner::new(source, PathBuf::new())
+        .filter_map(Result::ok)
+        .collect();
+    let mut res = Analysis {
+        tokens: Vec::new(),
+        symbols: Vec::new(),
+        references: Vec::new(),
+    };
+
+    let mut section = Section::Header;
+    let mut ports: HashSet<String> = HashSet::new();
+    let mut bracket_depth = 0;
+    let mut angle_depth = 0;
+    let mut paren_depth = 0;
+
+    // Symbols that are still open, innermost last.
+    let mut chip: Option<DocumentSymbol> = None;
+    let mut parts: Option<DocumentSymbol> = None;
+    let mut for_loop: Option<DocumentSymbol> = None;
+    let mut component: Option<DocumentSymbol> = None;
+    let mut in_loop_header = false;
+    let mut in_expression = false;
+
+    for (i, t) in tokens.iter().enumerate() {
+        let range = token_range(t);
+        let prev = if i > 0 {
+            Some(tokens[i - 1].token_type)
+        } else {
+            None
+        };
+
+        if section == Section::Parts && paren_depth == 0 && assignment_target(&tokens, i) {
+            in_expression = true;
+        }
+
+        let token_type = match t.token_type {
+            TokenType::Chip
+            | TokenType::Interface
+            | TokenType::In
+            | TokenType::Out
+            | TokenType::Parts
+            | TokenType::For
+            | TokenType::To
+            | TokenType::Generate
+            | TokenType::Clocked
+            | TokenType::Builtin
+            | TokenType::Use
+            | TokenType::Const
+            | TokenType::Stimulus
+            | TokenType::Table => Some(SemanticTokenType::Keyword),
+            TokenType::Number => Some(SemanticTokenType::Number),
+            TokenType::Identifier => Some(
+                if matches!(prev, Some(TokenType::Chip | TokenType::Interface))
+                    || prev == Some(TokenType::Builtin)
+                {
+                    SemanticTokenType::Chip
+                } else if bracket_depth > 0
+                    || angle_depth > 0
+                    || prev == Some(TokenType::For)
+                    || prev == Some(TokenType::Const)
+                    || in_loop_header
+                {
+                    SemanticTokenType::Generic
+                } else if matches!(section, Section::Ports(_)) {
+                    SemanticTokenType::Port
+                } else if in_expression && matches!(t.lexeme.as_str(), "AND" | "OR" | "XOR" | "NOT")
+                {
+                    SemanticTokenType::Keyword
+                } else if in_expression {
+                    if ports.contains(&t.lexeme) {
+                        SemanticTokenType::Port
+                    } else if matches!(t.lexeme.as_str(), "true" | "false") {
+                        SemanticTokenType::Keyword
+                    } else {
+                        SemanticTokenType::Wire
+                    }
+                } else if section == Section::Parts && paren_depth == 0 {
+                    SemanticTokenType::Chip
+                } else if section == Section::Parts && prev != Some(TokenType::Equal) {
+                    SemanticTokenType::Port
+                } else if matches!(t.lexeme.as_str(), "true" | "false" | "open") {
+                    SemanticTokenType::Keyword
+                } else if ports.contains(&t.lexeme) {
+                    SemanticTokenType::Port
+                } else {
+                    SemanticTokenType::Wire
+                },
+            ),
+            _ => None,
+        };
+        if let Some(token_type) = token_type {
+            res.tokens.push(SemanticToken { range, token_type });
+        }
+
+        // Ports in a component's argument list belong to the component,
+        // every other port belongs to the chip being defined.
+        let target = match token_type {
+            Some(SemanticTokenType::Chip) if prev != Some(TokenType::Builtin) => {
+                Some(Target::Chip(t.lexeme.clone()))
+            }
+            Some(SemanticTokenType::Port) => {
+                let owner = if section == Section::Parts && prev != Some(TokenType::Equal) {
+                    &component
+                } else {
+                    &chip
+                };
+                owner
+                    .as_ref()
+                    .map(|c| Target::Port(c.name.clone(), t.lexeme.clone()))
+            }
+            _ => None,
+        };
+        if let Some(target) = target {
+            res.references.push(Reference {
+                target,
+                range,
+                declaration: matches!(prev, Some(TokenType::Chip | TokenType::Interface))
+                    || matches!(section, Section::Ports(d) if d != "CLOCKED"),
+            });
+        }
+
+        match t.token_type {
+            TokenType::Identifier => {
+                if matches!(prev, Some(TokenType::Chip | TokenType::Interface)) {
+                    chip = Some(DocumentSymbol {
+                        name: t.lexeme.clone(),
+                        detail: None,
+                        kind: SymbolKind::Chip,
+                        range: span(token_range(&tokens[i - 1]), range),
+                        selection_range: range,
+                        children: Vec::new(),
+                    });
+                } else if let Section::Ports(direction) = section {
+                    if bracket_depth == 0 && direction != "CLOCKED" {
+                        ports.insert(t.lexeme.clone());
+                        if let Some(c) = chip.as_mut() {
+                            c.children.push(DocumentSymbol {
+                                name: t.lexeme.clone(),
+                                detail: Some(String::from(direction)),
+                                kind: SymbolKind::Port,
+                                range,
+                                selection_range: range,
+                                children: Vec::new(),
+                            });
+                        }
+                    }
+                } else if section == Section::Parts
+                    && !in_expression
+                    && paren_depth == 0
+                    && angle_depth == 0
+                    && !in_loop_header
+                    && prev != Some(TokenType::For)
+                {
+                    component = Some(DocumentSymbol {
+                        name: t.lexeme.clone(),
+                        detail: None,
+                        kind: SymbolKind::Component,
+                        range,
+                        selection_range: range,
+                        children: Vec::new(),
+                    });
+                } else if prev == Some(TokenType::For) {
+                    for_loop = Some(DocumentSymbol {
+                        name: format!("FOR {}", t.lexeme),
+                        detail: None,
+                        kind: SymbolKind::Loop,
+                        range: span(token_range(&tokens[i - 1]), range),
+                        selection_range: range,
+                        children: Vec::new(),
+                    });
+                    in_loop_header = true;
+                }
+            }
+            TokenType::In | TokenType::Out | TokenType::Clocked if !in_loop_header => {
+                section = Section::Ports(match t.token_type {
+                    TokenType::In => "IN",
+                    TokenType::Out => "OUT",
+                    _ => "CLOCKED",
+                });
+            }
+            TokenType::Builtin => {
+                section = Section::Ports("BUILTIN");
+            }
+            TokenType::Semicolon => {
+                if matches!(section, Section::Ports(_)) {
+                    section = Section::Body;
+                }
+                in_expression = false;
+            }
+            TokenType::Parts => {
+                section = Section::Parts;
+                parts = Some(DocumentSymbol {
+                    name: String::from("PARTS"),
+                    detail: None,
+                    kind: SymbolKind::Parts,
+                    range,
+                    selection_range: range,
+                    children: Vec::new(),
+                });
+            }
+            TokenType::LeftBracket => bracket_depth += 1,
+            TokenType::RightBracket => bracket_depth -= 1,
+            // Inside parentheses these compare, as in `if(W > 8, 2, 1)`.
+            TokenType::LeftAngle if paren_depth == 0 => angle_depth += 1,
+            TokenType::RightAngle if paren_depth == 0 => angle_depth -= 1,
+            TokenType::LeftParen => paren_depth += 1,
+            TokenType::RightParen => {
+                paren_depth -= 1;
+                if paren_depth == 0 {
+                    if let Some(mut c) = component.take() {
+                        c.range = span(c.range, range);
+                        match (for_loop.as_mut(), parts.as_mut()) {
+                            (Some(l), _) => l.children.push(c),
+                            (None, Some(p)) => p.children.push(c),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            TokenType::LeftCurly => {
+                if in_loop_header {
+                    in_loop_header = false;
+                } else if section == Section::Header {
+                    section = Section::Body;
+                }
+            }
+            TokenType::RightCurly => {
+                if let Some(mut l) = for_loop.take() {
+                    l.range = span(l.range, range);
+                    if let Some(p) = parts.as_mut() {
+                        p.children.push(l);
+                    }
+                } else if let Some(mut c) = chip.take() {
+                    if let Some(mut p) = parts.take() {
+                        p.range = span(p.range, range);
+                        c.children.push(p);
+                    }
+                    c.range = span(c.range, range);
+                    res.symbols.push(c);
+                    section = Section::Header;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Close anything left open by a syntax error at the end of the file.
+    if let Some(last) = tokens.last().map(token_range) {
+        if let Some(mut l) = for_loop.take() {
+            l.range = span(l.range, last);
+            if let Some(p) = parts.as_mut() {
+                p.children.push(l);
+            }
+        }
+        if let Some(mut c) = chip.take() {
+            if let Some(mut p) = parts.take() {
+                p.range = span(p.range, last);
+                c.children.push(p);
+            }
+            c.range = span(c.range, last);
+            res.symbols.push(c);
+        }
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::FileReader;
+    use std::env;
+    use std::fs;
+
+    const MUX: &str = "CHIP Mux4<W> {
+    IN a[W], b[W], sel;
+    OUT out[W];
+    PARTS:
+    FOR i IN 0 TO W-1 GENERATE {
+        Mux(a=a[i], b=b[i], sel=sel,
+            out=out[i]);
+    }
+}";
+
+    #[test]
+    fn test_semantic_tokens() {
+        let tokens = semantic_tokens(MUX);
+        let kind = |line: u32, character: u32| {
+            tokens
+                .iter()
+                .find(|t| t.range.start == Position { line, character })
+                .map(|t| t.token_type)
+        };
+
+        assert_eq!(kind(0, 0), Some(SemanticTokenType::Keyword));
+        assert_eq!(kind(0, 5), Some(SemanticTokenType::Chip));
+        assert_eq!(kind(0, 10), Some(SemanticTokenType::Generic));
+        assert_eq!(kind(1, 7), Some(SemanticTokenType::Port));
+        assert_eq!(kind(1, 9), Some(SemanticTokenType::Generic));
+        assert_eq!(kind(4, 8), Some(SemanticTokenType::Generic));
+        assert_eq!(kind(5, 8), Some(SemanticTokenType::Chip));
+        // `a=a[i]`: component port, then a port of this chip used as a wire.
+        assert_eq!(kind(5, 12), Some(SemanticTokenType::Port));
+        assert_eq!(kind(5, 14), Some(SemanticTokenType::Port));
+        assert_eq!(kind(5, 16), Some(SemanticTokenType::Generic));
+    }
+
+    #[test]
+    fn test_semantic_tokens_wire() {
+        let tokens = semantic_tokens("CHIP A { IN a; OUT b; PARTS: Not(in=a, out=x); }");
+        let wires: Vec<Range> = tokens
+            .iter()
+            .filter(|t| t.token_type == SemanticTokenType::Wire)
+            .map(|t| t.range)
+            .collect();
+        assert_eq!(wires.len(), 1);
+        assert_eq!(wires[0].start.character, 43);
+    }
+
+    #[test]
+    fn test_semantic_tokens_expression() {
+        let tokens = semantic_tokens("CHIP A { IN a, b; OUT c; PARTS: c = NOT a OR x; }");
+        let kind = |character: u32| {
+            tokens
+                .iter()
+                .find(|t| t.range.start == Position { line: 0, character })
+                .map(|t| t.token_type)
+        };
+        assert_eq!(kind(32), Some(SemanticTokenType::Port));
+        assert_eq!(kind(36), Some(SemanticTokenType::Keyword));
+        assert_eq!(kind(40), Some(SemanticTokenType::Port));
+        assert_eq!(kind(42), Some(SemanticTokenType::Keyword));
+        assert_eq!(kind(45), Some(SemanticTokenType::Wire));
+        assert!(tokens
+            .iter()
+            .all(|t| t.token_type != SemanticTokenType::Chip || t.range.start.character == 5));
+    }
+
+    #[test]
+    fn test_document_symbols() {
+        let symbols = document_symbols(MUX);
+        assert_eq!(symbols.len(), 1);
+        let chip = &symbols[0];
+        assert_eq!(chip.name, "Mux4");
+        assert_eq!(chip.range.start.line, 0);
+        assert_eq!(chip.range.end.line, 8);
+
+        let names: Vec<&str> = chip.children.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b", "sel", "out", "PARTS"]);
+        assert_eq!(chip.children[3].detail, Some(String::from("OUT")));
+
+        let parts = &chip.children[4];
+        assert_eq!(parts.children[0].name, "FOR i");
+        assert_eq!(parts.children[0].children[0].name, "Mux");
+        assert_eq!(parts.children[0].children[0].range.end.line, 6);
+    }
+
+    #[test]
+    fn test_folding_ranges() {
+        let ranges = folding_ranges(MUX);
+        assert_eq!(
+            ranges,
+            vec![
+                FoldingRange {
+                    start_line: 0,
+                    end_line: 8
+                },
+                FoldingRange {
+                    start_line: 3,
+                    end_line: 8
+                },
+                FoldingRange {
+                    start_line: 4,
+                    end_line: 7
+                },
+                FoldingRange {
+                    start_line: 5,
+                    end_line: 6
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_semantic_tokens_delta() {
+        let before = encode_semantic_tokens(&semantic_tokens(MUX));
+        let after = encode_semantic_tokens(&semantic_tokens(&MUX.replace("sel=sel", "sel=s")));
+        assert!(semantic_tokens_delta(&before, &before).is_empty());
+
+        let edits = semantic_tokens_delta(&before, &after);
+        assert_eq!(edits.len(), 1);
+        let mut patched = before.clone();
+        let e = &edits[0];
+        patched.splice(
+            e.start as usize..(e.start + e.delete_count) as usize,
+            e.data.iter().cloned(),
+        );
+        assert_eq!(patched, after);
+        assert!(e.data.len() < after.len());
+    }
+
+    #[test]
+    fn test_symbols_with_errors() {
+        let symbols = document_symbols("CHIP Broken { IN a; OUT b; PARTS: Not(in=a");
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols[0].name, "Broken");
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let source = "CHIP Broken {
+    IN a;
+    OUT b;
+    PARTS:
+    Not(in=a out=x);
+    Not(in=x, out=);
+}";
+        let diagnostics = diagnostics(source, Path::new("Broken.hdl"));
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(
+            diagnostics[0].range,
+            Range {
+                start: Position {
+                    line: 4,
+                    character: 13
+                },
+                end: Position {
+                    line: 4,
+                    character: 16
+                },
+            }
+        );
+        assert_eq!(diagnostics[1].range.start.line, 5);
+        assert!(super::diagnostics(
+            "CHIP Fine { IN a; OUT b; PARTS: Not(in=a, out=b); }",
+            Path::new("Fine.hdl")
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_test_lenses() {
+        let (_, base_path) = solutions_workspace("Not");
+        let path = base_path.join("Not.tst");
+        let source = fs::read_to_string(&path).unwrap();
+
+        let lenses = test_lenses(&source, &path);
+        let titles: Vec<&str> = lenses.iter().map(|l| l.title.as_str()).collect();
+        assert_eq!(titles, vec!["Run test", "Debug step 1", "Debug step 2"]);
+        assert_eq!(lenses[0].range.start.line, 5);
+        assert_eq!(lenses[2].range.start.line, 14);
+
+        let cancel = AtomicBool::new(false);
+        let run = run_lens(&source, &path, LensCommand::RunTest, &cancel).unwrap();
+        assert!(run.passed);
+        let labels: Vec<&str> = run.hints.iter().map(|h| h.label.as_str()).collect();
+        assert_eq!(labels, vec!["✔", "✔"]);
+        assert_eq!(
+            run.hints[1].position,
+            Position {
+                line: 14,
+                character: 9
+            }
+        );
+
+        let debug = run_lens(&source, &path, LensCommand::DebugStep(2), &cancel).unwrap();
+        assert_eq!(debug.hints.len(), 1);
+        assert_eq!(debug.hints[0].label, "in=1 out=0");
+
+        cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+        let cancelled = run_lens(&source, &path, LensCommand::RunTest, &cancel).unwrap();
+        assert!(cancelled.cancelled);
+        assert!(cancelled.hints.is_empty());
+    }
+
+    #[test]
+    fn test_monitor_diagnostics() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Checker.hdl"),
+            "CHIP Checker {
+    IN valid, ready, data[4];
+
+    PARTS:
+    HandshakeMonitor<4>(valid=valid, ready=ready, data=data);
+}
+",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("Checker.cmp"),
+            "|valid|ready|data|\n|  1  |  0  |0011|\n|  0  |  0  |0011|\n",
+        )
+        .unwrap();
+        let source = "load Checker.hdl,
+output-file Checker.out,
+compare-to Checker.cmp,
+output-list valid%B1.1.1 ready%B1.1.1 data%B1.4.1;
+
+set valid 1, set ready 0, set data %B0011,
+tick, tock,
+output;
+
+  set valid 0,
+  tick, tock,
+  output;
+";
+        let path = dir.path().join("Checker.tst");
+        fs::write(&path, source).unwrap();
+
+        let run = run_lens(source, &path, LensCommand::RunTest, &AtomicBool::new(false)).unwrap();
+        assert!(!run.passed);
+        let message = "HandshakeMonitor cycle 2: valid fell before ready accepted the transfer.";
+        assert_eq!(run.hints[1].label, format!("✘ {}", message));
+        assert_eq!(
+            run.diagnostics,
+            vec![Diagnostic {
+                range: Range {
+                    start: Position {
+                        line: 9,
+                        character: 2
+                    },
+                    end: Position {
+                        line: 9,
+                        character: 14
+                    },
+                },
+                message: String::from(message),
+            }]
+        );
+    }
+
+    fn solutions_workspace(top: &str) -> (Workspace, PathBuf) {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        (Workspace::index(top, &provider), base_path)
+    }
+
+    #[test]
+    fn test_find_references() {
+        let (workspace, base_path) = solutions_workspace("Mux");
+        let mux = base_path.join("Mux.hdl");
+        assert!(workspace
+            .paths()
+            .contains(&base_path.join("Not.hdl").as_path()));
+
+        // `Not(in=sel, out=Notsel);` in Mux.hdl.
+        let not = Position {
+            line: 17,
+            character: 5,
+        };
+        let refs = workspace.find_references(&mux, not, true);
+        let files: HashSet<&Path> = refs.iter().map(|l| l.path.as_path()).collect();
+        assert!(files.contains(base_path.join("Not.hdl").as_path()));
+        assert!(files.contains(base_path.join("And.hdl").as_path()));
+        assert!(files.contains(mux.as_path()));
+        let without_decl = workspace.find_references(&mux, not, false);
+        assert_eq!(without_decl.len(), refs.len() - 1);
+
+        // The `in` port of Not: its declaration, two wires in Not.hdl, and
+        // one mapping in each of And.hdl and Mux.hdl.
+        let port_in = Position {
+            line: 17,
+            character: 8,
+        };
+        let refs = workspace.find_references(&mux, port_in, true);
+        let in_not = refs
+            .iter()
+            .filter(|l| l.path == base_path.join("Not.hdl"))
+            .count();
+        assert_eq!(in_not, 3);
+        assert!(refs.iter().any(|l| l.path == base_path.join("And.hdl")));
+    }
+
+    #[test]
+    fn test_rename() {
+        let (workspace, base_path) = solutions_workspace("Mux");
+        let mux = base_path.join("Mux.hdl");
+        let sel = Position {
+            line: 12,
+            character: 15,
+        };
+        let edit = workspace.rename(&mux, sel, "select").expect("rename error");
+        // Declaration and two wires, only in Mux.hdl.
+        assert_eq!(edit.changes.len(), 1);
+        assert_eq!(edit.changes[&mux].len(), 3);
+        assert!(edit.changes[&mux].iter().all(|e| e.new_text == "select"));
+        assert!(edit.renamed_files.is_empty());
+
+        let chip = Position {
+            line: 11,
+            character: 6,
+        };
+        let edit = workspace.rename(&mux, chip, "Mux2").expect("rename error");
+        assert_eq!(
+            edit.renamed_files,
+            vec![(mux.clone(), base_path.join("Mux2.hdl"))]
+        );
+
+        assert!(workspace.rename(&mux, sel, "a").is_err());
+        assert!(workspace.rename(&mux, sel, "two words").is_err());
+        // Nand has no HDL file to rename.
+        let not = base_path.join("Not.hdl");
+        let nand = Position {
+            line: 5,
+            character: 5,
+        };
+        assert!(workspace.rename(&not, nand, "Nand2").is_err());
+    }
+
+    #[test]
+    fn test_inlay_hints() {
+        let (_, base_path) = solutions_workspace("Not");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let source = "CHIP Twice {
+    IN a[16];
+    OUT out[16];
+    PARTS:
+    Not16(in=a, out=x);
+    Not16(in=x, out=out);
+}";
+        let hints = inlay_hints(source, &base_path.join("Twice.hdl"), &provider, &[]);
+        assert_eq!(
+            hints,
+            vec![
+                InlayHint {
+                    position: Position {
+                        line: 4,
+                        character: 21
+                    },
+                    label: String::from(": 16"),
+                },
+                InlayHint {
+                    position: Position {
+                        line: 5,
+                        character: 14
+                    },
+                    label: String::from(": 16"),
+                },
+            ]
+        );
+        assert!(inlay_hints(source, &base_path.join("Twice.hdl"), &provider, &[4]).is_empty());
+    }
+
+    #[test]
+    fn test_loop_hover() {
+        let path = PathBuf::from("Mux4.hdl");
+        let inside = Position {
+            line: 5,
+            character: 10,
+        };
+        let hover = hover(MUX, &path, inside, &[4]).expect("No hover");
+        assert_eq!(hover.range.start.line, 4);
+        assert!(hover.contents.starts_with("FOR i IN 0 TO 3 (4 iterations)"));
+        assert!(hover
+            .contents
+            .contains("Mux(a=a[0], b=b[0], sel=sel, out=out[0]);"));
+
+        let unbound = super::hover(MUX, &path, inside, &[]).expect("No hover");
+        assert!(unbound.contents.starts_with("FOR i IN 0 TO (W - 1)"));
+
+        let outside = Position {
+            line: 1,
+            character: 5,
+        };
+        assert!(super::hover(MUX, &path, outside, &[4]).is_none());
+    }
+
+    #[test]
+    fn test_hover_expression() {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("arm");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let workspace = Workspace::index("OpsSASMC", &provider);
+        assert_eq!(workspace.generic_bindings("MuxGen"), vec![vec![3], vec![8]]);
+
+        let path = provider.get_path("MuxGen.hdl");
+        let hover = workspace
+            .hover_expression(
+                &path,
+                Position {
+                    line: 6,
+                    character: 21,
+                },
+            )
+            .expect("No hover");
+        assert_eq!(hover.range.start.character, 18);
+        assert_eq!(hover.range.end.character, 21);
+        assert_eq!(hover.contents, "`X-1`\n\n- X = 3: 2\n- X = 8: 7");
+
+        // Port names are not generic expressions.
+        let port = Position {
+            line: 1,
+            character: 8,
+        };
+        assert!(workspace.hover_expression(&path, port).is_none());
+    }
+
+    #[test]
+    fn test_hover_functions() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Gen.hdl"),
+            "CHIP Gen<A, B> {
+    IN in[max(A, B)];
+    OUT out[log2(A)], wide[if(A >= B, A - B, 1)];
+    PARTS:
+    Nand(a=in[0], b=in[0], out=out[0]);
+}",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("Top.hdl"),
+            "CHIP Top { IN in[8]; OUT out[3]; PARTS: Gen<8, 2>(in=in, out=out); }",
+        )
+        .unwrap();
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let workspace = Workspace::index("Top", &provider);
+        let path = provider.get_path("Gen.hdl");
+        let hover = |line, character| {
+            workspace
+                .hover_expression(&path, Position { line, character })
+                .map(|h| (h.range.start.character, h.range.end.character, h.contents))
+        };
+
+        // Anywhere in a call shows the whole call.
+        let max = Some((10, 19, String::from("`max(A, B)`\n\n- A = 8, B = 2: 8")));
+        assert_eq!(hover(1, 12), max);
+        assert_eq!(hover(1, 19), max);
+        assert_eq!(
+            hover(2, 19),
+            Some((12, 19, String::from("`log2(A)`\n\n- A = 8, B = 2: 3")))
+        );
+        assert_eq!(
+            hover(2, 40),
+            Some((
+                27,
+                47,
+                String::from("`if(A >= B, A - B, 1)`\n\n- A = 8, B = 2: 6")
+            ))
+        );
+        // Indices without generics are left alone.
+        assert_eq!(hover(4, 18), None);
+    }
+
+    #[test]
+    fn test_code_actions() {
+        let (_, base_path) = solutions_workspace("Not");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let path = base_path.join("Twice.hdl");
+        let source = "CHIP Twice {
+    IN a, b;
+    OUT out;
+    PARTS:
+    And(a=a, b=b, out=x);
+    Not(in=x, out=out);
+}";
+        let line = |line: u32, character: u32| Position { line, character };
+
+        let actions = code_actions(
+            source,
+            &path,
+            Range {
+                start: line(4, 6),
+                end: line(4, 6),
+            },
+            &provider,
+        );
+        let titles: Vec<&str> = actions.iter().map(|a| a.title.as_str()).collect();
+        assert_eq!(titles, vec!["Extract parts into a new chip", "Inline chip"]);
+        let extract = &actions[0].edit;
+        assert!(extract
+            .created_files
+            .contains_key(&base_path.join("NewChip.hdl")));
+        assert!(extract.changes[&path][0]
+            .new_text
+            .contains("NewChip(a=a, b=b, x=x);"));
+        assert!(actions[1].edit.changes[&path][0]
+            .new_text
+            .contains("Nand(a=a, b=b, out=and_nandout);"));
+
+        // Several parts can be extracted but not inlined.
+        let actions = code_actions(
+            source,
+            &path,
+            Range {
+                start: line(4, 0),
+                end: line(5, 10),
+            },
+            &provider,
+        );
+        assert_eq!(actions.len(), 1);
+
+        let outside = Range {
+            start: line(1, 0),
+            end: line(1, 3),
+        };
+        assert!(code_actions(source, &path, outside, &provider).is_empty());
+    }
+}
@@ -1,19 +1,62 @@
+mod bitwise;
+mod builtin;
 mod busmap;
+mod catalog;
+mod changes;
+mod cocotb;
+mod combinational;
+mod compiled;
+mod diagnostics;
+mod dump;
+mod elaborate;
+mod engine;
 mod error;
+mod event;
+mod exit;
 mod expr;
+mod figures;
+mod firrtl;
+mod fsm;
+mod fsm_compiler;
+mod governor;
+#[cfg(feature = "jit")]
+mod jit;
+mod lint;
+mod logging;
+mod logic;
+mod microcode;
+mod monitor;
+mod netlist;
+mod notebook;
 mod parser;
+mod printer;
+mod refactor;
 mod rom;
 mod scanner;
 pub mod simulator; // hack to deal with dead code warning
+mod stimulus;
+mod sv_testbench;
 mod test_parser;
 mod test_scanner;
 mod test_script;
 mod vhdl;
+mod visit;
 
+use crate::changes::{DryRun, FileChange};
+use crate::diagnostics::{ColorChoice, Diagnostic, MessageFormat, Output, Severity};
+use crate::dump::Recorder;
+use crate::engine::{EngineKind, SimOptions};
 use crate::error::{ErrorKind, N2VError};
+use crate::governor::{Governor, Speed};
+use crate::lint::{Level, Lint};
+use crate::logging::LogFormat;
+use crate::logic::XPolicy;
+use crate::monitor::{Monitor, Progress};
 use crate::parser::*;
-use crate::simulator::{Bus, Chip, Simulator};
-use crate::test_script::run_test;
+use crate::simulator::{Bus, Chip, ChipOptions, Simulator, Snapshot};
+use crate::test_script::{
+    load_test_vectors_from, print_report, run_test_progress, run_vectors, TestOptions, TestStatus,
+};
 use clap::Parser as ArgParser;
 use clap::Subcommand;
 use object::{Object, ObjectSection};
@@ -21,15 +64,94 @@ use parser::Parser;
 use scanner::Scanner;
 use std::error::Error;
 use std::fs;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::ptr;
-use std::rc::Rc;
+use std::process::{self, ExitCode};
+use std::sync::atomic::AtomicBool;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+use std::{io, thread};
 
 #[derive(ArgParser)]
-#[clap(version)]
+#[clap(version, after_help = exit::HELP)]
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+    /// Format of the logs `WHIDL_LOG` enables, `text` or `json`
+    #[clap(long, global = true, default_value = "text")]
+    log_format: LogFormat,
+    /// Give up after this many seconds
+    #[clap(long, global = true)]
+    timeout: Option<f64>,
+    /// Passes over its parts a chip may take to settle before it is
+    /// reported as oscillating
+    #[clap(long, global = true, default_value_t = simulator::DEFAULT_SETTLE_LIMIT)]
+    settle_limit: usize,
+    /// Engine that runs chips under test: `interpreter`; `event`, which
+    /// flattens the chip and only evaluates the gates whose inputs change;
+    /// `netlist`, which flattens it and evaluates every gate in order; or
+    /// `compiled`, which compiles the flattened chip into a program of ops.
+    /// Built with the `jit` feature, `jit` compiles it to native code
+    #[clap(long, global = true, default_value = "interpreter")]
+    engine: EngineKind,
+    /// How unknown bits spread through gates: `pessimistic`, where a gate
+    /// with an unknown input has an unknown output; or `optimistic`, where
+    /// an input that decides the output, such as a 0 into a NAND, wins
+    #[clap(long, global = true, default_value = "pessimistic")]
+    x_policy: XPolicy,
+    /// Threads for the `netlist` engine to evaluate gates that do not read
+    /// each other on, with the same results as on one
+    #[clap(long, global = true, default_value_t = 1)]
+    threads: usize,
+    /// Parts a chip may elaborate to, counting the parts of its parts,
+    /// before it is refused
+    #[clap(long, global = true, default_value_t = simulator::DEFAULT_MAX_INSTANCES)]
+    max_instances: usize,
+    /// Bits of signals a chip may elaborate to, counted the same way
+    #[clap(long, global = true, default_value_t = simulator::DEFAULT_MAX_BUS_BITS)]
+    max_bus_bits: usize,
+    /// Times a chip may be a part of itself, with different generic
+    /// arguments each time, such as a tree that halves its width
+    #[clap(long, global = true, default_value_t = 0)]
+    max_recursion: usize,
+    /// Look for the parts of chips in this directory after their own, and
+    /// before whidl's library. Directories given more than once are
+    /// searched in order
+    #[clap(long, global = true, value_name = "DIR")]
+    lib_path: Vec<String>,
+    /// Fail on problems that are otherwise warnings, such as a chip
+    /// shadowing another of the same name on the search path
+    #[clap(long, global = true, action)]
+    strict: bool,
+    /// Print how the files a command writes would change instead of
+    /// writing them, as a unified diff or, with `--dry-run=json`, a JSON
+    /// list of changes
+    #[clap(
+        long,
+        global = true,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "diff"
+    )]
+    dry_run: Option<DryRun>,
+    /// Color errors: `auto` on a terminal unless NO_COLOR is set,
+    /// `always`, or `never`
+    #[clap(long, global = true, default_value = "auto")]
+    color: ColorChoice,
+    /// Print errors and warnings as `human` text, or as `json`, one
+    /// object per line on stdout
+    #[clap(long, global = true, default_value = "human")]
+    message_format: MessageFormat,
+    /// Leave out warnings of a lint, such as `unused-wire`
+    #[clap(short = 'A', long, global = true, value_name = "LINT")]
+    allow: Vec<Lint>,
+    /// Warn about a lint, even if the project file or --strict says
+    /// otherwise
+    #[clap(short = 'W', long, global = true, value_name = "LINT")]
+    warn: Vec<Lint>,
+    /// Fail on a lint instead of warning about it
+    #[clap(short = 'D', long, global = true, value_name = "LINT")]
+    deny: Vec<Lint>,
 }
 
 #[derive(Subcommand)]
@@ -42,16 +164,181 @@ enum Commands {
         top_level_file: String,
     },
 
+    /// Prints FIRRTL for a chip and all of its components.
+    SynthFIRRTL { top_level_file: String },
+
+    /// Prints the parse tree of a chip as JSON.
+    Ast { hdl_file: String },
+
+    /// Prints a JSON description of every chip in a directory: ports,
+    /// generics, documentation, parts used, and test scripts.
+    Catalog { dir: String },
+
+    /// Prints a chip as canonically formatted HDL.
+    Fmt {
+        /// Overwrite the file instead of printing it. With --dry-run, prints
+        /// how it would change
+        #[clap(short, long, action)]
+        write: bool,
+        hdl_file: String,
+    },
+
+    /// Renders a markdown document with HDL and test blocks, adding the
+    /// truth tables, test output tables, and waveforms the blocks ask for.
+    Render {
+        /// Write the result to a file instead of printing it
+        #[clap(short, long, action)]
+        output: Option<PathBuf>,
+        document: String,
+    },
+
+    /// Prints the truth table of a small combinational chip, or the
+    /// Karnaugh map of one of its output bits.
+    Figure {
+        /// Output bit to map, e.g. `out` or `out[2]`
+        #[clap(long)]
+        kmap: Option<String>,
+        #[clap(long, value_enum, default_value = "text")]
+        format: crate::figures::FigureFormat,
+        hdl_file: String,
+    },
+
+    /// Runs a testbench chip for every cycle of its STIMULUS block and
+    /// prints the value of each signal per cycle.
+    Bench { hdl_file: String },
+
+    /// Prints the state-transition diagram of a small sequential chip,
+    /// with edges labeled by the inputs that take them.
+    Fsm {
+        #[clap(long, value_enum, default_value = "dot")]
+        format: crate::fsm::DiagramFormat,
+        hdl_file: String,
+    },
+
+    /// Compiles a state machine description into a CHIP built from DFF,
+    /// Not, And, and Or.
+    CompileFsm {
+        /// Write the HDL to a file instead of printing it
+        #[clap(short, long, action)]
+        output: Option<PathBuf>,
+        fsm_file: String,
+    },
+
+    /// Compiles a microcode table into a ROM chip. Writes <Name>.hdl and
+    /// <Name>.rom, named after the table file.
+    Microcode {
+        /// Directory to write to, by default the table's directory
+        #[clap(short, long, action)]
+        output_dir: Option<PathBuf>,
+        table_file: String,
+    },
+
+    /// Moves parts of a chip into a new chip in the same directory.
+    /// Parts are numbered from 1 in the order they appear in PARTS.
+    Extract {
+        /// Name of the new chip
+        #[clap(long)]
+        name: String,
+        /// First part to move
+        #[clap(long)]
+        first: usize,
+        /// Last part to move
+        #[clap(long)]
+        last: usize,
+        hdl_file: String,
+    },
+
+    /// Replaces a part with the parts of the chip it instantiates.
+    Inline {
+        /// Part to inline, numbered from 1
+        #[clap(long)]
+        part: usize,
+        hdl_file: String,
+    },
+
+    /// Runs a chip with its inputs held low, printing its outputs whenever
+    /// they change. Enter a new speed while it runs to change it, or `save`
+    /// to write a snapshot.
+    Run {
+        /// Cycles per second, or `max` to run as fast as possible
+        #[clap(long, default_value = "max")]
+        speed: Speed,
+        /// Stop after this many cycles
+        #[clap(long)]
+        cycles: Option<u64>,
+        /// File of key presses for the keyboard, such as
+        /// `at tick 100 press 'A' for 5 ticks;`
+        #[clap(long)]
+        keys: Option<String>,
+        /// Snapshot to write when the run stops or on `save`
+        #[clap(long)]
+        save: Option<String>,
+        /// Snapshot to resume the run from
+        #[clap(long)]
+        resume: Option<String>,
+        /// Directory to write a crash dump to if the simulation fails
+        #[clap(long)]
+        dump: Option<String>,
+        /// Cycles of outputs a dump keeps, and how often it checkpoints
+        #[clap(long, default_value = "100")]
+        dump_window: u64,
+        /// Show a live status line with the speed, memory use, and cache
+        /// hit rate of the run
+        #[clap(long, action)]
+        status: bool,
+        hdl_file: String,
+    },
+
+    /// Replays a crash dump written by `run --dump`, checking that the
+    /// outputs match the recorded ones and the error happens again.
+    Replay { dump_dir: String },
+
     /// Parses chip and simulates a single input, for catching errors.
     Check {
-        #[clap(short, long, action)]
-        top_level_file: String,
+        #[clap(short, long, action, required_unless_present_any = &["dir", "archive"])]
+        top_level_file: Option<String>,
+        /// Parse and elaborate every chip under this directory instead,
+        /// reporting every error and warning at once. With `--archive`, the
+        /// directory in the archive.
+        #[clap(long, conflicts_with = "top-level-file")]
+        dir: Option<String>,
+        /// Check the chips of a zip archive, those at its top or in `--dir`
+        #[clap(long, conflicts_with = "top-level-file")]
+        archive: Option<String>,
+        /// `nand2tetris` rejects whidl extensions the official tools do not
+        /// accept, such as generics and loops.
+        #[clap(long, default_value = "whidl")]
+        dialect: Dialect,
+    },
+
+    /// Prints a SystemVerilog testbench for a nand2tetris test script.
+    SynthSVTestbench { test_file: String },
+
+    /// Prints a cocotb test module for a nand2tetris test script.
+    SynthCocotb {
+        /// Use VHDL port names instead of FIRRTL/Verilog port names
+        #[clap(long, action)]
+        vhdl: bool,
+        test_file: String,
     },
 
     /// Runs a nand2tetris test
     Test {
         #[clap(short, long, action)]
         test_file: String,
+        /// Read the test script, its chips and .cmp file from a zip archive
+        #[clap(long)]
+        archive: Option<String>,
+        /// The directory of the archive the test script is in
+        #[clap(long, requires = "archive")]
+        dir: Option<String>,
+        /// Print the result of every step as JSON
+        #[clap(long, action)]
+        json: bool,
+        /// Show a live status line with the step the test is on, the speed,
+        /// memory use, and cache hit rate
+        #[clap(long, action)]
+        status: bool,
     },
 
     /// Synthesizes CS 314 ROM from .text section of ELF binary
@@ -62,8 +349,57 @@ enum Commands {
     Decode { thumb_binary: String },
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> ExitCode {
     let cli = Cli::parse();
+    if let Some(seconds) = cli.timeout {
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs_f64(seconds));
+            eprintln!("Error: Timed out after {} seconds.", seconds);
+            process::exit(exit::TIMEOUT.into());
+        });
+    }
+    // The panic hook has already printed the message of a panic.
+    let code = match panic::catch_unwind(AssertUnwindSafe(|| run(&cli))) {
+        Ok(Ok(())) => exit::SUCCESS,
+        Ok(Err(e)) => {
+            output(&cli).emit_error(&*e);
+            exit::code(&*e)
+        }
+        Err(_) => exit::INTERNAL,
+    };
+    ExitCode::from(code)
+}
+
+// How errors and warnings are printed, as the command line says.
+fn output(cli: &Cli) -> Output {
+    Output {
+        color: cli.color,
+        format: cli.message_format,
+    }
+}
+
+fn run(cli: &Cli) -> Result<(), Box<dyn Error>> {
+    logging::init(cli.log_format)?;
+    // Lints set on the command line win over those of project files.
+    let mut options = ChipOptions {
+        max_instances: cli.max_instances,
+        max_bus_bits: cli.max_bus_bits,
+        max_recursion: cli.max_recursion,
+        settle_limit: cli.settle_limit,
+        x_policy: cli.x_policy,
+        output: output(cli),
+        ..ChipOptions::default()
+    };
+    options.lints.set_strict(cli.strict);
+    for (lints, level) in [
+        (&cli.allow, Level::Allow),
+        (&cli.warn, Level::Warn),
+        (&cli.deny, Level::Deny),
+    ] {
+        for &lint in lints {
+            options.lints.set(lint, level);
+        }
+    }
 
     match &cli.command {
         Commands::SynthVHDL {
@@ -85,20 +421,412 @@ fn main() -> Result<(), Box<dyn Error>> {
                     .to_str()
                     .unwrap(),
             );
-            let provider: Rc<dyn HdlProvider> = Rc::new(FileReader::new(&base_path));
-            let entities = crate::vhdl::synth_vhdl(&hdl, &provider).unwrap();
+            let provider = chip_provider(&base_path, &cli.lib_path);
+            // Generics of the top chip stay generics in VHDL, so only a chip
+            // without them can be checked before it is translated.
+            if hdl.generic_decls.is_empty() {
+                let elaborated = elaborate::elaborate(&hdl, &[], &provider)?;
+                elaborate::report_warnings(elaborated.warnings, &provider, &options)?;
+                let loops =
+                    combinational::combinational_loops(&hdl, &[], &provider, options.max_recursion);
+                elaborate::report_warnings(loops, &provider, &options)?;
+            }
+            let entities = crate::vhdl::synth_vhdl(&hdl, &provider)?;
             let quartus_dir = Path::new(&output_dir);
             crate::vhdl::create_quartus_project(&hdl, entities, quartus_dir)
                 .expect("Unable to create project");
         }
-        Commands::Check { top_level_file } => {
+        Commands::SynthFIRRTL { top_level_file } => {
+            let source_code = fs::read_to_string(top_level_file)?;
+            let mut scanner = Scanner::new(&source_code, PathBuf::from(&top_level_file));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse()?;
+            let base_path = String::from(
+                hdl.path
+                    .as_ref()
+                    .unwrap()
+                    .parent()
+                    .unwrap()
+                    .to_str()
+                    .unwrap(),
+            );
+            let provider = chip_provider(&base_path, &cli.lib_path);
+            println!("{}", crate::firrtl::synth_firrtl(&hdl, &provider)?);
+        }
+        Commands::Ast { hdl_file } => {
+            let source_code = fs::read_to_string(hdl_file)?;
+            let mut scanner = Scanner::new(&source_code, PathBuf::from(&hdl_file));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse()?;
+            println!("{}", serde_json::to_string_pretty(&hdl)?);
+        }
+        Commands::Catalog { dir } => {
+            let catalog = catalog::catalog(Path::new(dir))?;
+            println!("{}", serde_json::to_string_pretty(&catalog)?);
+        }
+        Commands::Fmt { write, hdl_file } => {
+            let source_code = fs::read_to_string(hdl_file)?;
+            let mut scanner = Scanner::new(&source_code, PathBuf::from(&hdl_file));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse()?;
+            let tokens: Vec<_> = Scanner::new(&source_code, PathBuf::from(&hdl_file))
+                .filter_map(Result::ok)
+                .collect();
+            let formatted = crate::printer::format(&hdl, &tokens);
+            if *write || cli.dry_run.is_some() {
+                let change = FileChange {
+                    path: PathBuf::from(hdl_file),
+                    contents: formatted,
+                };
+                changes::apply(&[change], cli.dry_run)?;
+            } else {
+                print!("{}", formatted);
+            }
+        }
+        Commands::Render { output, document } => {
+            let path = PathBuf::from(document);
+            let source = fs::read_to_string(&path)?;
+            let rendered = crate::notebook::render(&source, &path)?;
+            match output {
+                Some(o) => {
+                    let change = FileChange {
+                        path: o.clone(),
+                        contents: rendered,
+                    };
+                    changes::apply(&[change], cli.dry_run)?;
+                }
+                None => print!("{}", rendered),
+            }
+        }
+        Commands::Figure {
+            kmap,
+            format,
+            hdl_file,
+        } => {
+            let path = PathBuf::from(hdl_file);
+            let source_code = fs::read_to_string(&path)?;
+            let provider = chip_provider(
+                path.parent().unwrap_or(Path::new(".")).to_str().unwrap(),
+                &cli.lib_path,
+            );
+            let mut scanner = Scanner::new(&source_code, path.clone());
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse()?;
+            let table = crate::figures::truth_table(&hdl, &provider, &options)?;
+            match kmap {
+                Some(output) => {
+                    let kmap = crate::figures::karnaugh_map(&table, output)?;
+                    print!("{}", kmap.render(*format));
+                }
+                None => print!("{}", table.render(*format)),
+            }
+        }
+        Commands::CompileFsm { output, fsm_file } => {
+            let source = fs::read_to_string(fsm_file)?;
+            let machine = crate::fsm_compiler::parse(&source)?;
+            let hdl = crate::fsm_compiler::to_hdl(&machine)?;
+            match output {
+                Some(o) => {
+                    let change = FileChange {
+                        path: o.clone(),
+                        contents: hdl,
+                    };
+                    changes::apply(&[change], cli.dry_run)?;
+                }
+                None => print!("{}", hdl),
+            }
+        }
+        Commands::Microcode {
+            output_dir,
+            table_file,
+        } => {
+            let path = PathBuf::from(table_file);
+            let source = fs::read_to_string(&path)?;
+            let name = path.file_stem().unwrap().to_str().unwrap();
+            let microcode = crate::microcode::parse(name, &source)?;
+            let dir = match output_dir {
+                Some(d) => d.clone(),
+                None => path.parent().unwrap_or(Path::new(".")).to_path_buf(),
+            };
+            let files = [
+                FileChange {
+                    path: dir.join(format!("{}.rom", name)),
+                    contents: microcode.rom()?,
+                },
+                FileChange {
+                    path: dir.join(format!("{}.hdl", name)),
+                    contents: microcode.hdl(),
+                },
+            ];
+            changes::apply(&files, cli.dry_run)?;
+        }
+        Commands::Bench { hdl_file } => {
+            let path = PathBuf::from(hdl_file);
+            let source_code = fs::read_to_string(&path)?;
+            let provider = chip_provider(
+                path.parent().unwrap_or(Path::new(".")).to_str().unwrap(),
+                &cli.lib_path,
+            );
+            let mut scanner = Scanner::new(&source_code, path.clone());
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse()?;
+            let cycles = crate::stimulus::run(&hdl, &provider, &options)?;
+            print!("{}", crate::stimulus::table(&hdl, &cycles));
+        }
+        Commands::Fsm { format, hdl_file } => {
+            let path = PathBuf::from(hdl_file);
+            let source_code = fs::read_to_string(&path)?;
+            let provider = chip_provider(
+                path.parent().unwrap_or(Path::new(".")).to_str().unwrap(),
+                &cli.lib_path,
+            );
+            let mut scanner = Scanner::new(&source_code, path.clone());
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse()?;
+            let machine = crate::fsm::extract(&hdl, &provider, &options)?;
+            print!("{}", machine.render(*format));
+        }
+        Commands::Extract {
+            name,
+            first,
+            last,
+            hdl_file,
+        } => {
+            let path = PathBuf::from(hdl_file);
+            let source_code = fs::read_to_string(&path)?;
+            let provider: Arc<dyn HdlProvider> =
+                Arc::new(FileReader::new(path.parent().unwrap().to_str().unwrap()));
+            let extraction = crate::refactor::extract_chip(
+                &source_code,
+                &path,
+                first.saturating_sub(1)..*last,
+                name,
+                &provider,
+            )?;
+            let files = [
+                FileChange {
+                    path: provider.get_path(&format!("{}.hdl", name)),
+                    contents: extraction.chip,
+                },
+                FileChange {
+                    path,
+                    contents: extraction.parent,
+                },
+            ];
+            changes::apply_tested(&files, cli.dry_run)?;
+        }
+        Commands::Inline { part, hdl_file } => {
+            let path = PathBuf::from(hdl_file);
+            let source_code = fs::read_to_string(&path)?;
+            let provider: Arc<dyn HdlProvider> =
+                Arc::new(FileReader::new(path.parent().unwrap().to_str().unwrap()));
+            let inlined = crate::refactor::inline_chip(
+                &source_code,
+                &path,
+                part.saturating_sub(1),
+                &provider,
+            )?;
+            let change = FileChange {
+                path,
+                contents: inlined,
+            };
+            changes::apply_tested(&[change], cli.dry_run)?;
+        }
+        Commands::Run {
+            speed,
+            cycles,
+            keys,
+            save,
+            resume,
+            dump,
+            dump_window,
+            status,
+            hdl_file,
+        } => {
+            let presses = match keys {
+                Some(keys) => dump::read_key_presses(Path::new(keys))?,
+                None => Vec::new(),
+            };
+            let path = PathBuf::from(hdl_file);
+            let source_code = fs::read_to_string(&path)?;
+            let provider = chip_provider(
+                path.parent().unwrap_or(Path::new(".")).to_str().unwrap(),
+                &cli.lib_path,
+            );
+            let mut scanner = Scanner::new(&source_code, path.clone());
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse()?;
+            let chip = Chip::new(&hdl, &provider, options.clone())?;
+            let mut simulator = Simulator::new(chip);
+            let mut cycle = 0;
+            if let Some(resume) = resume {
+                let snapshot = Snapshot::read(Path::new(resume))?;
+                simulator.restore(&snapshot)?;
+                cycle = snapshot.cycle;
+            }
+            let end = cycles.map(|c| cycle + c);
+            let save_snapshot = |simulator: &Simulator, cycle: u64| -> Result<(), Box<dyn Error>> {
+                if let Some(save) = save {
+                    let snapshot = Snapshot {
+                        cycle,
+                        ..simulator.snapshot()
+                    };
+                    snapshot.write(Path::new(save))?;
+                    eprintln!("Saved cycle {} to {}.", cycle, save);
+                }
+                Ok(())
+            };
+            let inputs = dump::held_inputs(&simulator.chip)?;
+            let outputs = dump::output_names(&simulator.chip);
+            let mut recorder = dump
+                .as_ref()
+                .map(|_| Recorder::new(&simulator, cycle, *dump_window));
+
+            // Speed changes are read from stdin without blocking the simulation.
+            let (sender, speeds) = mpsc::channel();
+            thread::spawn(move || {
+                for line in io::stdin().lines().map_while(Result::ok) {
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            let progress = Arc::new(Progress::default());
+            let _monitor = status.then(|| Monitor::start(progress.clone()));
+            let mut governor = Governor::new(*speed);
+            let mut last = String::new();
+            loop {
+                for line in speeds.try_iter().filter(|l| !l.trim().is_empty()) {
+                    if line.trim() == "save" {
+                        save_snapshot(&simulator, cycle)?;
+                        continue;
+                    }
+                    match line.parse() {
+                        Ok(s) => governor.set_speed(s),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+                let batch = match end {
+                    Some(e) => governor.batch().min(e - cycle),
+                    None => governor.batch(),
+                };
+                if batch == 0 {
+                    break;
+                }
+                for _ in 0..batch {
+                    if let Some(r) = &mut recorder {
+                        r.start_cycle(&simulator, cycle);
+                    }
+                    let values = match dump::step(&mut simulator, &inputs, &presses, cycle) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            if let (Some(r), Some(dir)) = (&recorder, dump) {
+                                r.write(
+                                    Path::new(dir),
+                                    &simulator,
+                                    &path,
+                                    keys.as_deref().map(Path::new),
+                                    cycle,
+                                    &e.to_string(),
+                                )?;
+                                eprintln!("Wrote a crash dump of cycle {} to {}.", cycle, dir);
+                            }
+                            return Err(e);
+                        }
+                    };
+                    let line = dump::output_line(&values, &outputs);
+                    if let Some(r) = &mut recorder {
+                        r.record(cycle, &line);
+                    }
+                    if line != last {
+                        println!("cycle {}: {}", cycle, line);
+                        last = line;
+                    }
+                    cycle += 1;
+                }
+                progress.update(&simulator, cycle);
+                tracing::debug!(cycle, batch, "ran batch");
+                governor.pace(batch);
+            }
+            save_snapshot(&simulator, cycle)?;
+        }
+        Commands::Replay { dump_dir } => {
+            println!("{}", dump::replay(Path::new(dump_dir))?);
+        }
+        Commands::Check {
+            archive: Some(archive),
+            dir,
+            dialect,
+            ..
+        } => {
+            let dir = dir.as_deref().unwrap_or("");
+            let provider: Arc<dyn HdlProvider> =
+                Arc::new(ZipReader::open(Path::new(archive), dir)?);
+            let found = diagnostics::check_provider(&provider, *dialect, &options.lints);
+            let place = Path::new(archive).join(dir).display().to_string();
+            report_diagnostics(&found, &place, &options.output)?;
+        }
+        Commands::Check {
+            dir: Some(dir),
+            dialect,
+            ..
+        } => {
+            configure_lints(Path::new(dir), &mut options.lints)?;
+            let found = diagnostics::check_directory_as(
+                Path::new(dir),
+                *dialect,
+                &options.lints,
+                &cli.lib_path,
+            );
+            report_diagnostics(&found, dir, &options.output)?;
+        }
+        Commands::Check {
+            top_level_file,
+            dialect,
+            ..
+        } => {
+            let top_level_file = top_level_file
+                .as_ref()
+                .ok_or("Give --top-level-file or --dir.")?;
             let source_code = fs::read_to_string(&top_level_file)?;
             let mut scanner = Scanner::new(&source_code, PathBuf::from(&top_level_file));
             let mut parser = Parser {
                 scanner: &mut scanner,
             };
 
-            let hdl = parser.parse()?;
+            // Report every syntax error at once, and every extension the
+            // dialect does not have.
+            let (hdl, mut errors) = parser.parse_recovering();
+            if let Some(hdl) = &hdl {
+                errors.extend(dialect_errors(hdl, *dialect));
+            }
+            let hdl = match (hdl, errors) {
+                (Some(hdl), errors) if errors.is_empty() => hdl,
+                (_, errors) => {
+                    for e in &errors {
+                        options.output.emit_error(e);
+                    }
+                    return Err(Box::new(exit::Failure {
+                        code: exit::code(&errors[0]),
+                        msg: format!("{} errors in {}", errors.len(), top_level_file),
+                    }));
+                }
+            };
 
             let base_path = String::from(
                 hdl.path
@@ -109,8 +837,16 @@ fn main() -> Result<(), Box<dyn Error>> {
                     .to_str()
                     .unwrap(),
             );
-            let provider: Rc<dyn HdlProvider> = Rc::new(FileReader::new(&base_path));
-            let chip = Chip::new(&hdl, ptr::null_mut(), &provider, false, &Vec::new())?;
+            configure_lints(Path::new(&base_path), &mut options.lints)?;
+            let layers = lib_path_layers(&base_path, &cli.lib_path).map(Arc::new);
+            let files: Arc<dyn HdlProvider> = match &layers {
+                Some(layers) => layers.clone(),
+                None => Arc::new(FileReader::new(&base_path)),
+            };
+            let provider: Arc<dyn HdlProvider> = Arc::new(CachingProvider::new(files));
+            let elaborated = elaborate::elaborate(&hdl, &[], &provider)?;
+            elaborate::report_warnings(elaborated.warnings, &provider, &options)?;
+            let chip = Chip::new(&hdl, &provider, options.clone())?;
             let mut simulator = Simulator::new(chip);
 
             // Get all input ports.
@@ -152,9 +888,90 @@ fn main() -> Result<(), Box<dyn Error>> {
                 };
                 println!("\t{}: Width={}", &signal_name, &sig_width);
             }
+            // The layer of the library path each part was found in.
+            if let Some(layers) = &layers {
+                println!("Parts:");
+                let mut names: Vec<&str> = elaborated
+                    .instances
+                    .iter()
+                    .map(|i| i.hdl.name.as_str())
+                    .collect();
+                names.sort();
+                names.dedup();
+                for name in names {
+                    let layer = layers.layer(&format!("{}.hdl", name)).unwrap_or("whidl");
+                    println!("\t{}: {}", name, layer);
+                }
+            }
+        }
+        Commands::SynthSVTestbench { test_file } => {
+            println!(
+                "{}",
+                crate::sv_testbench::synth_sv_testbench(test_file, &cli.lib_path)?
+            );
+        }
+        Commands::SynthCocotb { vhdl, test_file } => {
+            println!(
+                "{}",
+                crate::cocotb::synth_cocotb(test_file, *vhdl, &cli.lib_path)?
+            );
         }
-        Commands::Test { test_file } => {
-            run_test(test_file)?;
+        Commands::Test {
+            test_file,
+            archive,
+            dir,
+            json,
+            status,
+        } => {
+            let progress = Arc::new(Progress::default());
+            let mut options = TestOptions {
+                chip: options,
+                lib_path: cli.lib_path.clone(),
+                sim: SimOptions {
+                    engine: cli.engine,
+                    threads: cli.threads,
+                },
+            };
+            let monitor = status.then(|| Monitor::start(progress.clone()));
+            let report = match archive {
+                Some(archive) => {
+                    let dir = dir.as_deref().unwrap_or("");
+                    let provider = Arc::new(ZipReader::open(Path::new(archive), dir)?);
+                    let path = provider.get_path(test_file);
+                    let vectors = load_test_vectors_from(test_file, provider)?;
+                    run_vectors(
+                        vectors,
+                        &path,
+                        None,
+                        &AtomicBool::new(false),
+                        &progress,
+                        &options,
+                    )?
+                }
+                None => {
+                    let dir = Path::new(test_file).parent().unwrap_or(Path::new("."));
+                    configure_lints(dir, &mut options.chip.lints)?;
+                    run_test_progress(
+                        test_file,
+                        None,
+                        &AtomicBool::new(false),
+                        &progress,
+                        &options,
+                    )?
+                }
+            };
+            drop(monitor);
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                if report.status != TestStatus::Passed {
+                    return Err(Box::new(N2VError {
+                        msg: String::from("Test failed."),
+                        kind: ErrorKind::TestFailure,
+                    }));
+                }
+            } else {
+                print_report(&report)?;
+            }
         }
         Commands::Rom { thumb_binary } => {
             let bin_data = fs::read(thumb_binary)?;
@@ -203,3 +1020,40 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
     Ok(())
 }
+
+// Prints the diagnostics `check` found in `place`, failing if any is an
+// error.
+fn report_diagnostics(
+    found: &[Diagnostic],
+    place: &str,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let errors: Vec<&Diagnostic> = found
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .collect();
+    for d in found {
+        match output.format {
+            MessageFormat::Human => eprint!("{}", d.render(output.color())),
+            MessageFormat::Json => println!("{}", serde_json::to_string(d)?),
+        }
+    }
+    if !errors.is_empty() {
+        let parse = errors.iter().any(|d| ["scan", "parse"].contains(&&*d.code));
+        return Err(Box::new(exit::Failure {
+            code: if parse {
+                exit::PARSE
+            } else {
+                exit::ELABORATION
+            },
+            msg: format!(
+                "{} errors and {} warnings in {}",
+                errors.len(),
+                found.len() - errors.len(),
+                place
+            ),
+        }));
+    }
+    println!("✔️️️    Check Passed with {} warnings", found.len());
+    Ok(())
+}
@@ -0,0 +1,340 @@
+// Compiles microcode tables into ROM-backed decoder chips. A table is a CSV
+// file whose header names the input and output columns, separated by a `|`
+// column:
+//
+//     # Control unit for a two bit opcode.
+//     op[2], zero, |, alu[3], write
+//     00,    -,    |, 011,    1
+//     01,    1,    |, 100,    0
+//
+// Each row gives the outputs for every address its inputs match. Inputs are
+// written in binary, most significant bit first, and `-` marks a bit that
+// does not matter; a lone `-` covers the whole column. Addresses no row
+// matches output zero.
+//
+// The result is a `BUILTIN ROM` chip and the `.rom` file it loads its
+// contents from, which the simulator reads directly and VHDL synthesis turns
+// into a constant array.
+
+use std::error::Error;
+use std::fmt::Write;
+
+use crate::error::{ErrorKind, N2VError};
+use crate::scanner::{is_name_continue, is_name_start};
+
+// ROM files list every address, so keep them a reasonable size.
+const MAX_ADDRESS_BITS: usize = 16;
+
+pub struct Column {
+    pub name: String,
+    pub width: usize,
+}
+
+struct Row {
+    line: usize,
+    pattern: Vec<Option<bool>>, // Every input bit, most significant first.
+    word: Vec<bool>,            // Every output bit, most significant first.
+}
+
+pub struct Microcode {
+    pub name: String,
+    pub inputs: Vec<Column>,
+    pub outputs: Vec<Column>,
+    rows: Vec<Row>,
+}
+
+fn table_error(line: usize, msg: String) -> Box<dyn Error> {
+    Box::new(N2VError {
+        msg: format!("line {}: {}", line, msg),
+        kind: ErrorKind::ElaborationError,
+    })
+}
+
+// A header cell such as `op` or `alu[3]`.
+fn column(line: usize, cell: &str) -> Result<Column, Box<dyn Error>> {
+    let (name, width) = match cell.strip_suffix(']').and_then(|c| c.split_once('[')) {
+        Some((name, width)) => (name.trim(), width.trim().parse::<usize>().ok()),
+        None => (cell, Some(1)),
+    };
+    let valid_name = name.starts_with(is_name_start) && name.chars().all(is_name_continue);
+    match width {
+        Some(width) if valid_name && width > 0 => Ok(Column {
+            name: String::from(name),
+            width,
+        }),
+        _ => Err(table_error(
+            line,
+            format!("`{}` is not a column such as `op` or `op[2]`.", cell),
+        )),
+    }
+}
+
+// The bits of one cell, checked against the width of its column.
+fn cell_bits(
+    line: usize,
+    cell: &str,
+    column: &Column,
+    dont_care: bool,
+) -> Result<Vec<Option<bool>>, Box<dyn Error>> {
+    if cell == "-" {
+        return Ok(vec![None; column.width]);
+    }
+    let bits: Option<Vec<Option<bool>>> = cell
+        .chars()
+        .map(|c| match c {
+            '0' => Some(Some(false)),
+            '1' => Some(Some(true)),
+            '-' if dont_care => Some(None),
+            _ => None,
+        })
+        .collect();
+    match bits {
+        Some(bits) if bits.len() == column.width => Ok(bits),
+        _ => Err(table_error(
+            line,
+            format!(
+                "`{}` is not a {} bit binary value for column {}.",
+                cell, column.width, column.name
+            ),
+        )),
+    }
+}
+
+/// Parses a microcode table. `name` becomes the name of the chip.
+pub fn parse(name: &str, source: &str) -> Result<Microcode, Box<dyn Error>> {
+    let mut lines = source
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.trim()))
+        .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'));
+    let split = |l: &str| -> Vec<String> { l.split(',').map(|c| c.trim().to_string()).collect() };
+
+    let (header_line, header) = match lines.next() {
+        Some((i, l)) => (i, split(l)),
+        None => return Err(table_error(1, String::from("The table has no header."))),
+    };
+    let separator = match header.iter().position(|c| c == "|") {
+        Some(s) => s,
+        None => {
+            return Err(table_error(
+                header_line,
+                String::from("The header needs a `|` column between the inputs and outputs."),
+            ))
+        }
+    };
+    let columns = |cells: &[String]| -> Result<Vec<Column>, Box<dyn Error>> {
+        cells.iter().map(|c| column(header_line, c)).collect()
+    };
+    let mut microcode = Microcode {
+        name: String::from(name),
+        inputs: columns(&header[..separator])?,
+        outputs: columns(&header[separator + 1..])?,
+        rows: Vec::new(),
+    };
+    let address_bits: usize = microcode.inputs.iter().map(|c| c.width).sum();
+    if address_bits > MAX_ADDRESS_BITS {
+        return Err(table_error(
+            header_line,
+            format!(
+                "{} input bits is too many, at most {} are supported.",
+                address_bits, MAX_ADDRESS_BITS
+            ),
+        ));
+    }
+    if microcode.outputs.is_empty() {
+        return Err(table_error(
+            header_line,
+            String::from("The table has no output columns."),
+        ));
+    }
+
+    for (line, l) in lines {
+        let cells = split(l);
+        if cells.len() != header.len() || cells[separator] != "|" {
+            return Err(table_error(
+                line,
+                format!(
+                    "Expected {} cells with `|` in column {} like the header.",
+                    header.len(),
+                    separator + 1
+                ),
+            ));
+        }
+        let mut pattern = Vec::new();
+        for (cell, c) in cells[..separator].iter().zip(&microcode.inputs) {
+            pattern.extend(cell_bits(line, cell, c, true)?);
+        }
+        let mut word = Vec::new();
+        for (cell, c) in cells[separator + 1..].iter().zip(&microcode.outputs) {
+            let bits = cell_bits(line, cell, c, false)?;
+            word.extend(bits.into_iter().map(|b| b.unwrap_or(false)));
+        }
+        microcode.rows.push(Row {
+            line,
+            pattern,
+            word,
+        });
+    }
+    Ok(microcode)
+}
+
+impl Microcode {
+    /// The decoder chip. Its contents are loaded from `<name>.rom`.
+    pub fn hdl(&self) -> String {
+        let ports = |columns: &[Column]| -> String {
+            columns
+                .iter()
+                .map(|c| {
+                    if c.width == 1 {
+                        c.name.clone()
+                    } else {
+                        format!("{}[{}]", c.name, c.width)
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        let mut hdl = String::new();
+        writeln!(
+            hdl,
+            "// Generated from a microcode table. The contents are in {}.rom.",
+            self.name
+        )
+        .unwrap();
+        writeln!(hdl, "CHIP {} {{", self.name).unwrap();
+        if !self.inputs.is_empty() {
+            writeln!(hdl, "    IN {};", ports(&self.inputs)).unwrap();
+        }
+        writeln!(hdl, "    OUT {};", ports(&self.outputs)).unwrap();
+        writeln!(hdl, "\n    BUILTIN ROM;").unwrap();
+        writeln!(hdl, "}}").unwrap();
+        hdl
+    }
+
+    /// One word per address. Rows that match the same address must agree
+    /// on its outputs.
+    pub fn rom(&self) -> Result<String, Box<dyn Error>> {
+        let address_bits: usize = self.inputs.iter().map(|c| c.width).sum();
+        let word_bits: usize = self.outputs.iter().map(|c| c.width).sum();
+        let mut words: Vec<Option<&Row>> = vec![None; 1 << address_bits];
+        for row in &self.rows {
+            for (address, word) in words.iter_mut().enumerate() {
+                let matches = row.pattern.iter().enumerate().all(|(i, b)| {
+                    b.is_none_or(|b| b == ((address >> (address_bits - 1 - i)) & 1 == 1))
+                });
+                if !matches {
+                    continue;
+                }
+                match word {
+                    Some(other) if other.word != row.word => {
+                        return Err(table_error(
+                            row.line,
+                            format!(
+                                "Address {:0w$b} has different outputs on lines {} and {}.",
+                                address,
+                                other.line,
+                                row.line,
+                                w = address_bits
+                            ),
+                        ));
+                    }
+                    _ => *word = Some(row),
+                }
+            }
+        }
+
+        let mut rom = String::new();
+        for word in words {
+            let bits: String = match word {
+                Some(row) => row
+                    .word
+                    .iter()
+                    .map(|b| if *b { '1' } else { '0' })
+                    .collect(),
+                None => "0".repeat(word_bits),
+            };
+            writeln!(rom, "{}", bits)?;
+        }
+        Ok(rom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::busmap::BusMap;
+    use crate::parser::{FileReader, HdlProvider, Parser};
+    use crate::scanner::Scanner;
+    use crate::simulator::{Bus, Chip, ChipOptions, Simulator};
+    use std::fs;
+    use std::sync::Arc;
+
+    const CONTROL: &str = "# Control unit for a two bit opcode.
+op[2], zero, |, alu[3], write
+00,    -,    |, 011,    1
+01,    1,    |, 100,    0
+1-,    0,    |, 111,    -
+";
+
+    #[test]
+    fn test_rom() {
+        let microcode = parse("Control", CONTROL).expect("Table error");
+        assert_eq!(microcode.inputs.len(), 2);
+        assert_eq!(
+            microcode.rom().unwrap(),
+            "0111\n0111\n0000\n1000\n1110\n0000\n1110\n0000\n"
+        );
+        assert!(microcode
+            .hdl()
+            .contains("    IN op[2], zero;\n    OUT alu[3], write;\n\n    BUILTIN ROM;"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let message = |source: &str| match parse("T", source).and_then(|m| m.rom()) {
+            Ok(_) => panic!("Expected an error"),
+            Err(e) => e.to_string(),
+        };
+        assert!(message("a, b\n").contains("line 1: The header needs a `|` column"));
+        assert!(message("a, |, out[2]\n1, |, 2\n").contains("line 2: `2` is not a 2 bit"));
+        assert!(message("a, |, out\n-, |, 1\n1, |, 0\n")
+            .contains("line 3: Address 1 has different outputs on lines 2 and 3."));
+    }
+
+    #[test]
+    fn test_simulate_and_synthesize() {
+        let dir = tempfile::tempdir().unwrap();
+        let microcode = parse("Control", CONTROL).unwrap();
+        fs::write(dir.path().join("Control.hdl"), microcode.hdl()).unwrap();
+        fs::write(dir.path().join("Control.rom"), microcode.rom().unwrap()).unwrap();
+
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let hdl = microcode.hdl();
+        let mut scanner = Scanner::new(&hdl, dir.path().join("Control.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+        let mut simulator = Simulator::new(chip);
+        let mut inputs = BusMap::new();
+        inputs.create_bus("op", 2).unwrap();
+        inputs.create_bus("zero", 1).unwrap();
+        inputs.insert(Bus::from("op"), vec![false, true]);
+        inputs.insert(Bus::from("zero"), vec![true]);
+        let outputs = simulator.simulate(&inputs).expect("Simulation error");
+        assert_eq!(
+            outputs.get_name("alu"),
+            vec![Some(true), Some(false), Some(false)]
+        );
+        assert_eq!(outputs.get_name("write"), vec![Some(false)]);
+
+        let entities = crate::vhdl::synth_vhdl(&hdl, &provider).expect("VHDL error");
+        let vhdl = &entities["Control"];
+        assert!(vhdl.contains("type rom_t is array (0 to 7) of std_logic_vector(3 downto 0);"));
+        assert!(vhdl.contains("3 => \"1000\""));
+        assert!(vhdl.contains("address(2 downto 1) <= op;"));
+        assert!(vhdl.contains("write <= word(0);"));
+    }
+}
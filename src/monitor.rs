@@ -0,0 +1,239 @@
+// Live status of long runs. `whidl run --status` and `whidl test --status`
+// keep a line on stderr showing how fast the simulation goes, how much
+// memory it holds, how often the input cache saves elaborating a part, and
+// how far a test script has got, so a hung run can be told from a slow one.
+//
+// The simulation publishes its counters in a `Progress` as it goes. A
+// separate thread draws them, so the line keeps updating, and says how long
+// nothing has changed, even while a single step never finishes.
+
+use crate::simulator::Simulator;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// How often the line is redrawn on a terminal.
+const REDRAW: Duration = Duration::from_millis(250);
+
+// How often a line is written when stderr is a file or a pipe.
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+// Rates are measured over this window, so they follow changes in speed.
+const RATE_WINDOW: Duration = Duration::from_secs(2);
+
+// A run is reported as stalled after this long without progress.
+const STALL: Duration = Duration::from_secs(3);
+
+/// Counters a simulation updates for the monitor to read.
+#[derive(Default)]
+pub struct Progress {
+    pub cycles: AtomicU64,
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+    pub step: AtomicUsize,  // Steps of a test script finished.
+    pub steps: AtomicUsize, // Steps in the script, or 0 if not running one.
+}
+
+impl Progress {
+    /// Publishes the cycle count and cache counters of a simulator.
+    pub fn update(&self, simulator: &Simulator, cycles: u64) {
+        self.cycles.store(cycles, Ordering::Relaxed);
+        self.cache_hits
+            .store(simulator.input_cache.hits, Ordering::Relaxed);
+        self.cache_misses
+            .store(simulator.input_cache.misses, Ordering::Relaxed);
+    }
+}
+
+/// What the status line shows at one moment.
+#[derive(Debug, PartialEq)]
+pub struct Status {
+    pub elapsed: Duration,
+    pub cycles: u64,
+    pub cycles_per_sec: f64,
+    pub memory: Option<u64>, // Resident bytes, where the platform tells.
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub step: usize,
+    pub steps: usize,
+    pub stalled: Option<Duration>, // How long nothing has changed.
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} | cycle {}", duration(self.elapsed), self.cycles)?;
+        write!(f, " | {:.0} cycles/s", self.cycles_per_sec)?;
+        if let Some(bytes) = self.memory {
+            write!(f, " | {:.1} MB", bytes as f64 / 1e6)?;
+        }
+        let lookups = self.cache_hits + self.cache_misses;
+        if lookups > 0 {
+            let rate = 100.0 * self.cache_hits as f64 / lookups as f64;
+            write!(f, " | cache {:.0}% hits", rate)?;
+        }
+        if self.steps > 0 {
+            write!(f, " | step {}/{}", self.step, self.steps)?;
+        }
+        if let Some(stalled) = self.stalled {
+            write!(f, " | no progress for {}", duration(stalled))?;
+        }
+        Ok(())
+    }
+}
+
+fn duration(d: Duration) -> String {
+    let s = d.as_secs();
+    if s >= 3600 {
+        format!("{}h{:02}m{:02}s", s / 3600, s / 60 % 60, s % 60)
+    } else if s >= 60 {
+        format!("{}m{:02}s", s / 60, s % 60)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+/// Bytes of memory the process holds, on Linux.
+pub fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
+
+// Turns samples of the counters into statuses.
+struct Sampler {
+    start: Instant,
+    samples: Vec<(Instant, u64, usize)>, // Time, cycles and step, within the rate window.
+    changed: Instant,                    // When the cycles or step last changed.
+}
+
+impl Sampler {
+    fn new(now: Instant) -> Sampler {
+        Sampler {
+            start: now,
+            samples: vec![(now, 0, 0)],
+            changed: now,
+        }
+    }
+
+    fn sample(&mut self, progress: &Progress, now: Instant) -> Status {
+        let cycles = progress.cycles.load(Ordering::Relaxed);
+        let step = progress.step.load(Ordering::Relaxed);
+        let &(_, last_cycles, last_step) = self.samples.last().unwrap();
+        if cycles != last_cycles || step != last_step {
+            self.changed = now;
+        }
+        self.samples.push((now, cycles, step));
+        while self.samples.len() > 2 && now - self.samples[1].0 >= RATE_WINDOW {
+            self.samples.remove(0);
+        }
+        let (first, first_cycles, _) = self.samples[0];
+        let window = (now - first).as_secs_f64();
+        let cycles_per_sec = if window > 0.0 {
+            (cycles - first_cycles.min(cycles)) as f64 / window
+        } else {
+            0.0
+        };
+        let stalled = now - self.changed;
+        Status {
+            elapsed: now - self.start,
+            cycles,
+            cycles_per_sec,
+            memory: resident_memory(),
+            cache_hits: progress.cache_hits.load(Ordering::Relaxed),
+            cache_misses: progress.cache_misses.load(Ordering::Relaxed),
+            step,
+            steps: progress.steps.load(Ordering::Relaxed),
+            stalled: Some(stalled).filter(|&s| s >= STALL),
+        }
+    }
+}
+
+/// Draws the status line until dropped.
+pub struct Monitor {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Monitor {
+    /// Starts drawing `progress` on stderr.
+    pub fn start(progress: Arc<Progress>) -> Monitor {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            let terminal = io::stderr().is_terminal();
+            let interval = if terminal { REDRAW } else { LOG_INTERVAL };
+            let mut sampler = Sampler::new(Instant::now());
+            let mut drawn = Instant::now();
+            while !stopped.load(Ordering::Relaxed) {
+                thread::sleep(REDRAW);
+                let now = Instant::now();
+                let status = sampler.sample(&progress, now);
+                if now - drawn < interval {
+                    continue;
+                }
+                drawn = now;
+                let mut stderr = io::stderr().lock();
+                // Errors writing the status are not worth stopping the run for.
+                let _ = if terminal {
+                    write!(stderr, "\r\x1b[K{}", status)
+                } else {
+                    writeln!(stderr, "{}", status)
+                };
+                let _ = stderr.flush();
+            }
+            if terminal {
+                let _ = write!(io::stderr(), "\r\x1b[K");
+            }
+        });
+        Monitor {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let progress = Progress::default();
+        let start = Instant::now();
+        let mut sampler = Sampler::new(start);
+
+        progress.cycles.store(1000, Ordering::Relaxed);
+        progress.cache_hits.store(3, Ordering::Relaxed);
+        progress.cache_misses.store(1, Ordering::Relaxed);
+        progress.steps.store(40, Ordering::Relaxed);
+        progress.step.store(12, Ordering::Relaxed);
+        let status = sampler.sample(&progress, start + Duration::from_secs(1));
+        assert_eq!(status.cycles_per_sec, 1000.0);
+        assert_eq!(status.stalled, None);
+        let status = Status {
+            memory: Some(12_345_678),
+            ..status
+        };
+        assert_eq!(
+            status.to_string(),
+            "1s | cycle 1000 | 1000 cycles/s | 12.3 MB | cache 75% hits | step 12/40"
+        );
+
+        // Nothing changes for a while.
+        let status = sampler.sample(&progress, start + Duration::from_secs(5));
+        assert_eq!(status.stalled, Some(Duration::from_secs(4)));
+        assert_eq!(status.cycles_per_sec, 0.0);
+        assert!(status.to_string().ends_with("no progress for 4s"));
+    }
+}
@@ -0,0 +1,713 @@
+// A netlist is a chip flattened all the way down: NANDs, flip-flops and the
+// builtins that have no parts to expand, reading and driving numbered nets
+// of one bit each. The hierarchy of chips and the copying of signals from
+// each level to the next are gone, which makes a netlist the thing to hand
+// to engines, exporters, and anything that compares two chips.
+
+use crate::builtin::Builtin;
+use crate::busmap::BusMap;
+use crate::engine::SimEngine;
+use crate::error::{ErrorKind, N2VError};
+use crate::logic::{Logic, XPolicy};
+use crate::parser::PortDirection;
+use crate::simulator::{Chip, ChipOptions, DEFAULT_SETTLE_LIMIT};
+use petgraph::algo::tarjan_scc;
+use petgraph::graph::DiGraph;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::collections::HashMap;
+use std::error::Error;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// A NAND, flip-flop or builtin of a flattened chip.
+#[derive(Clone)]
+pub enum Cell {
+    Nand {
+        a: usize,
+        b: usize,
+        out: usize,
+    },
+    Dff {
+        d: usize,
+        q: usize,
+    },
+    Builtin {
+        builtin: Box<dyn Builtin>,
+        signals: BusMap, // Every port of the builtin.
+        pins: Vec<Pin>,
+    },
+}
+
+/// A port of a builtin and the net of each of its bits.
+#[derive(Clone)]
+pub struct Pin {
+    pub name: String,
+    pub direction: PortDirection,
+    pub nets: Vec<usize>, // By bit, lowest first.
+}
+
+impl Cell {
+    fn nets_mut(&mut self) -> Vec<&mut usize> {
+        match self {
+            Cell::Nand { a, b, out } => vec![a, b, out],
+            Cell::Dff { d, q } => vec![d, q],
+            Cell::Builtin { pins, .. } => pins.iter_mut().flat_map(|p| &mut p.nets).collect(),
+        }
+    }
+
+    /// Nets whose changes the cell's outputs follow before the next tick.
+    /// A flip-flop only reads its input at a tick, so it has none.
+    pub fn reads(&self) -> Vec<usize> {
+        match self {
+            Cell::Nand { a, b, .. } => vec![*a, *b],
+            Cell::Dff { .. } => Vec::new(),
+            Cell::Builtin { pins, .. } => pins
+                .iter()
+                .filter(|p| p.direction == PortDirection::In)
+                .flat_map(|p| p.nets.iter().copied())
+                .collect(),
+        }
+    }
+
+    /// Nets the cell drives.
+    pub fn drives(&self) -> Vec<usize> {
+        match self {
+            Cell::Nand { out, .. } => vec![*out],
+            Cell::Dff { q, .. } => vec![*q],
+            Cell::Builtin { pins, .. } => pins
+                .iter()
+                .filter(|p| p.direction == PortDirection::Out)
+                .flat_map(|p| p.nets.iter().copied())
+                .collect(),
+        }
+    }
+
+    /// Computes the cell from the nets in `values`, adding the nets it
+    /// drives and their new values to `changed`. Unknown bits spread
+    /// through a NAND as `policy` says. A flip-flop only changes at a tick,
+    /// so it adds nothing.
+    pub fn evaluate(
+        &mut self,
+        values: &[Logic],
+        policy: XPolicy,
+        changed: &mut Vec<(usize, Logic)>,
+    ) {
+        self.evaluate_with(|n| values[n], policy, changed)
+    }
+
+    /// Like `evaluate`, for an engine that keeps the values of nets some
+    /// other way. `values` gives the value of a net.
+    pub fn evaluate_with(
+        &mut self,
+        values: impl Fn(usize) -> Logic,
+        policy: XPolicy,
+        changed: &mut Vec<(usize, Logic)>,
+    ) {
+        match self {
+            Cell::Nand { a, b, out } => changed.push((*out, values(*a).nand(values(*b), policy))),
+            Cell::Dff { .. } => {}
+            Cell::Builtin {
+                builtin,
+                signals,
+                pins,
+            } => {
+                for pin in pins.iter().filter(|p| p.direction == PortDirection::In) {
+                    let value: Vec<Logic> = pin.nets.iter().rev().map(|&n| values(n)).collect();
+                    signals.insert_logic(&pin.name, &value);
+                }
+                builtin.eval(signals);
+                for pin in pins.iter().filter(|p| p.direction == PortDirection::Out) {
+                    let value = signals.get_logic(&pin.name);
+                    changed.extend(pin.nets.iter().copied().zip(value.into_iter().rev()));
+                }
+            }
+        }
+    }
+}
+
+/// Cells wired together by nets, as a chip is flattened into them.
+#[derive(Clone, Default)]
+pub struct Netlist {
+    pub cells: Vec<Cell>,
+    /// Value of each net before anything is simulated. Literals and
+    /// flip-flops are known, other nets are not.
+    pub values: Vec<Option<bool>>,
+    // The net each net has been joined to, up to one joined to itself.
+    joined: Vec<usize>,
+    /// Nets of the input and output ports of the top-level chip.
+    pub inputs: Vec<(String, Vec<usize>)>,
+    pub outputs: Vec<(String, Vec<usize>)>,
+    /// Nets of every port of the top-level chip and wire between its parts.
+    /// A bit that nothing drives has none.
+    pub signals: HashMap<String, Vec<Option<usize>>>,
+}
+
+/// Expands `chip` down to NANDs, flip-flops and builtins. The chip itself is
+/// left as it was.
+pub fn flatten(chip: &Chip) -> Result<Netlist, Box<dyn Error>> {
+    chip.flatten()
+}
+
+impl Netlist {
+    /// A new net, starting at `value`.
+    pub fn net(&mut self, value: Option<bool>) -> usize {
+        self.values.push(value);
+        self.joined.push(self.joined.len());
+        self.joined.len() - 1
+    }
+
+    /// Makes two nets one, because a wire connects them.
+    pub fn join(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.joined[a] = b;
+        self.values[b] = self.values[b].or(self.values[a]);
+    }
+
+    fn find(&mut self, net: usize) -> usize {
+        let mut root = net;
+        while self.joined[root] != root {
+            root = self.joined[root];
+        }
+        let mut net = net;
+        while self.joined[net] != root {
+            net = std::mem::replace(&mut self.joined[net], root);
+        }
+        root
+    }
+
+    /// Numbers the nets again, so that nets joined into one have one
+    /// number, and those numbers are the only ones used. Nets nothing
+    /// reads or drives any more, such as those made for inputs before a
+    /// wire reached them, are dropped.
+    pub fn finish(&mut self) {
+        let roots: Vec<usize> = (0..self.joined.len()).map(|n| self.find(n)).collect();
+        let mut number = vec![usize::MAX; self.joined.len()];
+        let mut values = Vec::new();
+        let mut renumber = |net: &mut usize| {
+            let root = roots[*net];
+            if number[root] == usize::MAX {
+                number[root] = values.len();
+                values.push(self.values[root]);
+            }
+            *net = number[root];
+        };
+        for cell in &mut self.cells {
+            for net in cell.nets_mut() {
+                renumber(net);
+            }
+        }
+        for (_, nets) in self.inputs.iter_mut().chain(&mut self.outputs) {
+            nets.iter_mut().for_each(&mut renumber);
+        }
+        for nets in self.signals.values_mut() {
+            nets.iter_mut().flatten().for_each(&mut renumber);
+        }
+        self.joined = (0..values.len()).collect();
+        self.values = values;
+    }
+
+    /// Number of nets, once the netlist is finished.
+    pub fn nets(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Cells that read each net, by net.
+    pub fn readers(&self) -> Vec<Vec<usize>> {
+        let mut readers = vec![Vec::new(); self.values.len()];
+        for (i, cell) in self.cells.iter().enumerate() {
+            for net in cell.reads() {
+                readers[net].push(i);
+            }
+        }
+        readers
+    }
+
+    /// Cells in groups that can be evaluated one after another, each group
+    /// reading only nets that earlier groups drive. A group of more than
+    /// one cell, or of a cell that reads itself, is a combinational loop,
+    /// which has to be evaluated until it settles.
+    pub fn levels(&self) -> Vec<Vec<usize>> {
+        let mut graph = DiGraph::<(), ()>::new();
+        let nodes: Vec<_> = self.cells.iter().map(|_| graph.add_node(())).collect();
+        let readers = self.readers();
+        for (i, cell) in self.cells.iter().enumerate() {
+            for net in cell.drives() {
+                for &reader in &readers[net] {
+                    graph.add_edge(nodes[i], nodes[reader], ());
+                }
+            }
+        }
+        // Components come out with those that read others first.
+        let mut levels: Vec<Vec<usize>> = tarjan_scc(&graph)
+            .into_iter()
+            .map(|c| c.into_iter().map(|n| n.index()).collect())
+            .collect();
+        levels.reverse();
+        levels
+    }
+
+    /// The groups of `levels` gathered into waves, each group in the first
+    /// wave after those of the groups it reads. No group reads a net another
+    /// group of its wave drives, so the groups of a wave can be evaluated at
+    /// the same time.
+    pub fn waves(&self) -> Vec<Vec<Vec<usize>>> {
+        let mut waves: Vec<Vec<Vec<usize>>> = Vec::new();
+        // The wave of the group that drives each net, once it is placed.
+        let mut driven_in = vec![None; self.values.len()];
+        for cells in self.levels() {
+            // Groups come in order, so only a loop reads a net whose
+            // driver is not placed yet, and then the driver is itself.
+            let wave = cells
+                .iter()
+                .flat_map(|&i| self.cells[i].reads())
+                .filter_map(|net| driven_in[net])
+                .map(|w: usize| w + 1)
+                .max()
+                .unwrap_or(0);
+            for &i in &cells {
+                for net in self.cells[i].drives() {
+                    driven_in[net] = Some(wave);
+                }
+            }
+            if wave == waves.len() {
+                waves.push(Vec::new());
+            }
+            waves[wave].push(cells);
+        }
+        waves
+    }
+
+    /// Whether a group of cells from `levels` is a combinational loop.
+    pub fn looped(&self, cells: &[usize]) -> bool {
+        cells.len() > 1 || {
+            let cell = &self.cells[cells[0]];
+            let reads = cell.reads();
+            cell.drives().iter().any(|n| reads.contains(n))
+        }
+    }
+}
+
+/// The value of a net as an engine keeps it, which has to tell all four
+/// values of a `Logic` apart, so that a bit nothing drives comes out as Z
+/// on every engine.
+pub trait NetValue: Copy + PartialEq + Send + Sync + 'static {
+    fn from_logic(bit: Logic) -> Self;
+
+    fn logic(self) -> Logic;
+}
+
+impl NetValue for Logic {
+    fn from_logic(bit: Logic) -> Self {
+        bit
+    }
+
+    fn logic(self) -> Logic {
+        self
+    }
+}
+
+/// What every engine that runs a netlist keeps: the value of each net, the
+/// cells the engine evaluates, and the nets of the chip's flip-flops, ports
+/// and signals.
+#[derive(Clone)]
+pub struct NetState<V = Logic> {
+    pub name: String,
+    pub cells: Vec<Cell>, // Those the engine evaluates, in its order.
+    pub values: Vec<V>,
+    pub settle_limit: usize,
+    pub x_policy: XPolicy, // How unknown bits spread through NANDs.
+    // Shared by copies of the engine.
+    dffs: Arc<Vec<(usize, usize)>>, // Input and output net of each flip-flop.
+    inputs: Arc<Vec<(String, Vec<usize>)>>,
+    outputs: Arc<Vec<(String, Vec<usize>)>>,
+    signals: Arc<HashMap<String, Vec<Option<usize>>>>,
+    // Cells and nets as they were before anything was simulated.
+    start: Arc<(Vec<Cell>, Vec<V>)>,
+}
+
+impl<V: NetValue> NetState<V> {
+    /// Settles within the limit and spreads unknown bits as `options`, the
+    /// options of the chip flattened, say.
+    pub fn follow(&mut self, options: &ChipOptions) {
+        self.settle_limit = options.settle_limit;
+        self.x_policy = options.x_policy;
+    }
+
+    /// The state of the chip `name`, flattened into `netlist`, for an
+    /// engine that evaluates `cells`.
+    pub fn new(name: &str, netlist: Netlist, cells: Vec<Cell>) -> NetState<V> {
+        let dffs = netlist
+            .cells
+            .iter()
+            .filter_map(|cell| match cell {
+                Cell::Dff { d, q } => Some((*d, *q)),
+                _ => None,
+            })
+            .collect();
+        let values: Vec<V> = netlist
+            .values
+            .iter()
+            .map(|&v| V::from_logic(Logic::from(v)))
+            .collect();
+        NetState {
+            name: String::from(name),
+            cells: cells.clone(),
+            values: values.clone(),
+            settle_limit: DEFAULT_SETTLE_LIMIT,
+            x_policy: XPolicy::Pessimistic,
+            dffs: Arc::new(dffs),
+            inputs: Arc::new(netlist.inputs),
+            outputs: Arc::new(netlist.outputs),
+            signals: Arc::new(netlist.signals),
+            start: Arc::new((cells, values)),
+        }
+    }
+
+    /// Input and output net of each flip-flop.
+    pub fn dffs(&self) -> &[(usize, usize)] {
+        &self.dffs
+    }
+
+    fn port_values(&self) -> BusMap {
+        let mut values = BusMap::new();
+        for (name, nets) in self.inputs.iter().chain(self.outputs.iter()) {
+            values.create_bus(name, nets.len()).unwrap();
+            let bits: Vec<Logic> = nets.iter().rev().map(|&n| self.values[n].logic()).collect();
+            values.insert_logic(name, &bits);
+        }
+        values
+    }
+}
+
+/// An engine that runs a netlist, keeping a `NetState`. Engines differ only
+/// in how they settle; setting inputs, ticking, probing and resetting are
+/// the same for each, which makes every one a `SimEngine`.
+pub trait FlatEngine: Clone + Send + Sync + 'static {
+    type Value: NetValue;
+
+    fn state(&self) -> &NetState<Self::Value>;
+
+    fn state_mut(&mut self) -> &mut NetState<Self::Value>;
+
+    /// Evaluates cells until no net changes.
+    fn settle(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// Sets a net that no cell drives just then: an input, or the output of
+    /// a flip-flop at a tick.
+    fn set(&mut self, net: usize, value: Self::Value) {
+        self.state_mut().values[net] = value;
+    }
+
+    /// Called when the sequential builtin `cell` has ticked.
+    fn ticked(&mut self, _cell: usize) {}
+
+    /// Called when the state has been put back as it started.
+    fn restarted(&mut self) {}
+}
+
+impl<E: FlatEngine> SimEngine for E {
+    fn simulate(&mut self, inputs: &BusMap) -> Result<BusMap, Box<dyn Error>> {
+        let ports = Arc::clone(&self.state().inputs);
+        for (name, nets) in ports.iter() {
+            let value = inputs.get_logic(name);
+            for (&net, bit) in nets.iter().zip(value.into_iter().rev()) {
+                self.set(net, E::Value::from_logic(bit));
+            }
+        }
+        self.settle()?;
+        Ok(self.state().port_values())
+    }
+
+    fn tick(&mut self) -> Result<(), Box<dyn Error>> {
+        // Every flip-flop reads its input before any of them changes.
+        let state = self.state_mut();
+        let latched: Vec<_> = state
+            .dffs
+            .iter()
+            .map(|&(d, q)| {
+                // A flip-flop loads X when nothing drives its input.
+                let bit: Option<bool> = state.values[d].logic().into();
+                (q, NetValue::from_logic(Logic::from(bit)))
+            })
+            .collect();
+        let mut ticked = Vec::new();
+        for (i, cell) in state.cells.iter_mut().enumerate() {
+            if let Cell::Builtin {
+                builtin, signals, ..
+            } = cell
+            {
+                if builtin.is_sequential() {
+                    builtin.tick(signals);
+                    ticked.push(i);
+                }
+            }
+        }
+        for (net, value) in latched {
+            self.set(net, value);
+        }
+        for i in ticked {
+            self.ticked(i);
+        }
+        self.settle()
+    }
+
+    fn probe(&self, signal: &str) -> Option<Vec<Option<bool>>> {
+        let state = self.state();
+        let nets = state.signals.get(signal)?;
+        Some(
+            nets.iter()
+                .rev()
+                .map(|n| n.and_then(|n| state.values[n].logic().into()))
+                .collect(),
+        )
+    }
+
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        let state = self.state_mut();
+        state.cells = state.start.0.clone();
+        state.values = state.start.1.clone();
+        self.restarted();
+        Ok(())
+    }
+}
+
+// Cells evaluated a wave at a time, laid out one after another: those of
+// groups that are not loops, then each loop.
+#[derive(Clone)]
+struct Wave {
+    cells: Range<usize>,
+    loops: Vec<Range<usize>>,
+}
+
+// Cells a thread evaluates at a time, so that handing them out costs little
+// next to evaluating them.
+const CHUNK: usize = 256;
+
+/// Runs a chip by evaluating every cell of its netlist once per step, in
+/// an order where each cell's inputs are computed before it. Only
+/// combinational loops are evaluated more than once. Given more than one
+/// thread, cells that do not read each other are evaluated at once, with
+/// the same results as on one.
+#[derive(Clone)]
+pub struct NetlistEngine {
+    state: NetState,
+    // Shared by copies of the engine.
+    waves: Arc<Vec<Wave>>,
+    pool: Option<Arc<ThreadPool>>,
+}
+
+impl NetlistEngine {
+    /// Flattens `chip`, elaborating all of it, to run on one thread with
+    /// the settle limit and X policy the chip was built with. The chip
+    /// itself is left as it was.
+    pub fn new(chip: &Chip) -> Result<NetlistEngine, Box<dyn Error>> {
+        let mut engine = NetlistEngine::from_netlist(&chip.name, flatten(chip)?);
+        engine.state.follow(chip.options());
+        Ok(engine)
+    }
+
+    /// Runs a netlist already flattened from the chip `name`, on one
+    /// thread.
+    pub fn from_netlist(name: &str, netlist: Netlist) -> NetlistEngine {
+        let mut cells = Vec::new();
+        let mut waves = Vec::new();
+        for groups in netlist.waves() {
+            let (loops, plain): (Vec<_>, Vec<_>) =
+                groups.into_iter().partition(|g| netlist.looped(g));
+            let mut lay_out = |group: Vec<usize>| {
+                let start = cells.len();
+                cells.extend(group.into_iter().map(|i| netlist.cells[i].clone()));
+                start..cells.len()
+            };
+            let plain = lay_out(plain.into_iter().flatten().collect());
+            let loops = loops.into_iter().map(&mut lay_out).collect();
+            waves.push(Wave {
+                cells: plain,
+                loops,
+            });
+        }
+        NetlistEngine {
+            state: NetState::new(name, netlist, cells),
+            waves: Arc::new(waves),
+            pool: None,
+        }
+    }
+
+    /// Runs the engine on `threads` threads, or on the calling thread if
+    /// that is one.
+    pub fn with_threads(mut self, threads: usize) -> Result<NetlistEngine, Box<dyn Error>> {
+        self.pool = if threads > 1 {
+            Some(Arc::new(
+                ThreadPoolBuilder::new().num_threads(threads).build()?,
+            ))
+        } else {
+            None
+        };
+        Ok(self)
+    }
+
+    // Evaluates the cells of a loop until none of them changes.
+    fn settle_loop(
+        &mut self,
+        cells: Range<usize>,
+        changed: &mut Vec<(usize, Logic)>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut passes = 0;
+        loop {
+            let mut changing = false;
+            for i in cells.clone() {
+                self.state.cells[i].evaluate(&self.state.values, self.state.x_policy, changed);
+                for (net, value) in changed.drain(..) {
+                    changing |= self.state.values[net] != value;
+                    self.state.values[net] = value;
+                }
+            }
+            if !changing {
+                return Ok(());
+            }
+            passes += 1;
+            if passes > self.state.settle_limit {
+                return Err(Box::new(N2VError {
+                    msg: format!(
+                        "Chip {} did not settle after {} passes over a loop of {} gates and builtins, so the loop is oscillating. The limit is set with --settle-limit.",
+                        self.state.name,
+                        self.state.settle_limit,
+                        cells.len()
+                    ),
+                    kind: ErrorKind::SimulationError(None),
+                }));
+            }
+        }
+    }
+}
+
+impl FlatEngine for NetlistEngine {
+    type Value = Logic;
+
+    fn state(&self) -> &NetState {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut NetState {
+        &mut self.state
+    }
+
+    // Evaluates every cell, in order.
+    fn settle(&mut self) -> Result<(), Box<dyn Error>> {
+        let waves = Arc::clone(&self.waves);
+        let mut changed = Vec::new();
+        for wave in waves.iter() {
+            match &self.pool {
+                Some(pool) if wave.cells.len() > CHUNK => {
+                    // Changes are kept in the order of the cells, so they
+                    // are made the same way whichever thread is first.
+                    let values = &self.state.values;
+                    let policy = self.state.x_policy;
+                    let changes: Vec<Vec<_>> = pool.install(|| {
+                        self.state.cells[wave.cells.clone()]
+                            .par_chunks_mut(CHUNK)
+                            .map(|chunk| {
+                                let mut changed = Vec::new();
+                                for cell in chunk {
+                                    cell.evaluate(values, policy, &mut changed);
+                                }
+                                changed
+                            })
+                            .collect()
+                    });
+                    for (net, value) in changes.into_iter().flatten() {
+                        self.state.values[net] = value;
+                    }
+                }
+                _ => {
+                    for i in wave.cells.clone() {
+                        self.state.cells[i].evaluate(
+                            &self.state.values,
+                            self.state.x_policy,
+                            &mut changed,
+                        );
+                        for (net, value) in changed.drain(..) {
+                            self.state.values[net] = value;
+                        }
+                    }
+                }
+            }
+            for cells in &wave.loops {
+                self.settle_loop(cells.clone(), &mut changed)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::test::{check_same, make_chip};
+    use crate::simulator::{Bus, Simulator};
+
+    #[test]
+    fn test_netlist() {
+        let netlist = flatten(&make_chip("And.hdl")).unwrap();
+        // A NAND and the Not after it, one level each.
+        assert_eq!(netlist.cells.len(), 2);
+        assert_eq!(netlist.levels(), vec![vec![0], vec![1]]);
+        assert_eq!(netlist.waves(), vec![vec![vec![0]], vec![vec![1]]]);
+        // a, b, out, and the output of the NAND.
+        assert_eq!(netlist.nets(), 4);
+
+        let netlist = flatten(&make_chip("Bit.hdl")).unwrap();
+        assert_eq!(
+            netlist
+                .cells
+                .iter()
+                .filter(|c| matches!(c, Cell::Dff { .. }))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_netlist_engine() {
+        let engine =
+            |chip: &Chip| -> Box<dyn SimEngine> { Box::new(NetlistEngine::new(chip).unwrap()) };
+        for chip in ["Xor.hdl", "Mux8Way16.hdl", "DMux8Way.hdl", "ALU.hdl"] {
+            check_same(chip, 20, engine);
+        }
+        for chip in ["Bit.hdl", "Register.hdl", "PC.hdl", "RAM8.hdl", "CPU.hdl"] {
+            check_same(chip, 60, engine);
+        }
+    }
+
+    #[test]
+    fn test_netlist_engine_threads() {
+        // CPU and RAM8 have waves wide enough to share out.
+        let engine = |chip: &Chip| -> Box<dyn SimEngine> {
+            Box::new(NetlistEngine::new(chip).unwrap().with_threads(4).unwrap())
+        };
+        for chip in ["CPU.hdl", "RAM8.hdl"] {
+            check_same(chip, 20, engine);
+        }
+    }
+
+    #[test]
+    fn test_netlist_engine_unknown() {
+        // A flip-flop loads an unknown input at a tick, on the interpreter
+        // as on the engine.
+        let chip = make_chip("Bit.hdl");
+        let mut engine = NetlistEngine::new(&chip).unwrap();
+        let mut interpreter = Simulator::new(chip);
+        let mut inputs = BusMap::new();
+        inputs.create_bus("in", 1).unwrap();
+        inputs.create_bus("load", 1).unwrap();
+        inputs.insert_option(&Bus::from("in"), vec![None]);
+        inputs.insert_option(&Bus::from("load"), vec![Some(true)]);
+        for simulator in [&mut interpreter as &mut dyn SimEngine, &mut engine] {
+            simulator.simulate(&inputs).unwrap();
+            simulator.tick().unwrap();
+            let outputs = simulator.simulate(&inputs).unwrap();
+            assert_eq!(outputs.get_name("out"), vec![None]);
+        }
+    }
+}
@@ -0,0 +1,445 @@
+// Renders literate HDL documents: markdown with fenced code blocks that are
+// executed by the simulator. Figures are generated from the same code paths
+// as `whidl test`, so a handout can never disagree with the tools.
+//
+// ```hdl              A chip. Later blocks can use it as a part or load it.
+// ```hdl truth-table  A chip followed by its truth table.
+// ```hdl kmap out     A chip followed by a Karnaugh map of output `out`.
+// ```tst              A test script followed by a table of its outputs.
+// ```tst wave         A test script followed by a waveform of its outputs.
+//
+// The `output-file` and `compare-to` files named by test snippets are not
+// used. Chips that are not defined in the document are read from the
+// directory containing it. Syntax errors are reported at the line of the
+// document they occur on.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::busmap::BusMap;
+use crate::error::{ErrorKind, N2VError, ProviderError};
+use crate::figures::{karnaugh_map, truth_table, FigureFormat};
+use crate::parser::*;
+use crate::scanner::Scanner;
+use crate::simulator::{Bus, Chip, ChipOptions, Simulator};
+use crate::test_parser::{Instruction, NumberSystem, TestParser, TestScript};
+use crate::test_scanner::TestScanner;
+use crate::test_script::{input_bits, pressed_key};
+
+// Chips defined by the document so far, falling back to files on disk.
+struct NotebookProvider {
+    chips: HashMap<String, String>,
+    files: FileReader,
+    dir: PathBuf,
+    document: PathBuf,
+}
+
+impl HdlProvider for NotebookProvider {
+    fn get_hdl(&self, file_name: &str) -> Result<String, ProviderError> {
+        match self.chips.get(file_name) {
+            Some(source) => Ok(source.clone()),
+            None => self.files.get_hdl(file_name),
+        }
+    }
+
+    fn get_path(&self, file_name: &str) -> PathBuf {
+        self.dir.join(file_name)
+    }
+
+    fn chip_names(&self) -> Vec<String> {
+        let mut names = self.files.chip_names();
+        names.extend(
+            self.chips
+                .keys()
+                .filter_map(|f| f.strip_suffix(".hdl").map(String::from)),
+        );
+        names
+    }
+}
+
+// A fenced code block. `line` is the line of the opening fence.
+struct Block {
+    info: Vec<String>,
+    text: String,
+    line: usize,
+}
+
+// Rows of formatted values, one column per signal.
+struct Table {
+    columns: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+/// Renders a literate HDL document, inserting a figure after every chip
+/// and test snippet that asks for one.
+///
+/// `source` - Markdown text of the document
+/// `path` - Path of the document, chips it does not define are read from
+/// the same directory
+pub fn render(source: &str, path: &Path) -> Result<String, Box<dyn Error>> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut provider = NotebookProvider {
+        chips: HashMap::new(),
+        files: FileReader::new(dir.to_str().unwrap()),
+        dir: dir.to_path_buf(),
+        document: path.to_path_buf(),
+    };
+    let mut out = String::new();
+    let mut block: Option<Block> = None;
+
+    for (i, line) in source.lines().enumerate() {
+        out.push_str(line);
+        out.push('\n');
+
+        let fence = line.trim_start().strip_prefix("```");
+        match (&mut block, fence) {
+            (None, Some(info)) => {
+                block = Some(Block {
+                    info: info.split_whitespace().map(String::from).collect(),
+                    text: String::new(),
+                    line: i + 1,
+                });
+            }
+            (Some(_), Some(rest)) if rest.trim().is_empty() => {
+                let b = block.take().unwrap();
+                let figure = run_block(&b, &mut provider).map_err(|e| N2VError {
+                    msg: format!("In the block starting on line {}: {}", b.line, e),
+                    kind: ErrorKind::Other,
+                })?;
+                if let Some(figure) = figure {
+                    out.push('\n');
+                    out.push_str(&figure);
+                }
+            }
+            (Some(b), _) => {
+                b.text.push_str(line);
+                b.text.push('\n');
+            }
+            (None, None) => {}
+        }
+    }
+
+    if let Some(b) = block {
+        return Err(Box::new(N2VError {
+            msg: format!("The block starting on line {} is never closed.", b.line),
+            kind: ErrorKind::Other,
+        }));
+    }
+    Ok(out)
+}
+
+// Executes a block, returning the figure to insert after it, if any.
+fn run_block(
+    block: &Block,
+    provider: &mut NotebookProvider,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let language = block.info.first().map(String::as_str);
+    let option = block.info.get(1).map(String::as_str);
+    match (language, option) {
+        (Some("hdl"), option) => {
+            let mut scanner = Scanner::new(&block.text, provider.document.clone());
+            scanner.line = block.line as u32 + 1;
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse()?;
+            provider
+                .chips
+                .insert(format!("{}.hdl", hdl.name), block.text.clone());
+            match option {
+                None => Ok(None),
+                Some("truth-table") => {
+                    let table = truth_table(&hdl, &snapshot(provider), &ChipOptions::default())?;
+                    Ok(Some(markdown_table(&Table {
+                        columns: table.columns.iter().map(|c| c.name.clone()).collect(),
+                        rows: table
+                            .rows
+                            .iter()
+                            .map(|r| {
+                                r.iter()
+                                    .map(|bits| format_value(bits, &NumberSystem::Binary))
+                                    .collect()
+                            })
+                            .collect(),
+                    })))
+                }
+                Some("kmap") => {
+                    let output = block.info.get(2).ok_or_else(|| N2VError {
+                        msg: String::from(
+                            "A kmap block names the output to map, e.g. `hdl kmap out`.",
+                        ),
+                        kind: ErrorKind::Other,
+                    })?;
+                    let table = truth_table(&hdl, &snapshot(provider), &ChipOptions::default())?;
+                    let kmap = karnaugh_map(&table, output)?;
+                    Ok(Some(format!(
+                        "```text\n{}```\n",
+                        kmap.render(FigureFormat::Text)
+                    )))
+                }
+                Some(o) => Err(unknown_option(o)),
+            }
+        }
+        (Some("tst"), option) => {
+            let mut scanner = TestScanner::new(&block.text, provider.document.clone());
+            scanner.line = block.line as u32 + 1;
+            let mut parser = TestParser {
+                scanner: &mut scanner,
+            };
+            let script = parser.parse()?;
+            let table = run_script(&script, &snapshot(provider))?;
+            match option {
+                None => Ok(Some(markdown_table(&table))),
+                Some("wave") => Ok(Some(waveform(&table))),
+                Some(o) => Err(unknown_option(o)),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+fn unknown_option(option: &str) -> Box<dyn Error> {
+    Box::new(N2VError {
+        msg: format!("Unknown block option `{}`.", option),
+        kind: ErrorKind::Other,
+    })
+}
+
+// The provider is shared with the simulator, which needs its own handle.
+fn snapshot(provider: &NotebookProvider) -> Arc<dyn HdlProvider> {
+    Arc::new(NotebookProvider {
+        chips: provider.chips.clone(),
+        files: FileReader::new(provider.dir.to_str().unwrap()),
+        dir: provider.dir.clone(),
+        document: provider.document.clone(),
+    })
+}
+
+// Values of the output list at every `output` instruction of a test script.
+fn run_script(
+    script: &TestScript,
+    provider: &Arc<dyn HdlProvider>,
+) -> Result<Table, Box<dyn Error>> {
+    let name = script.hdl_file.file_stem().unwrap().to_str().unwrap();
+    let hdl = get_hdl(name, provider)?;
+    let chip = Chip::new(
+        &hdl,
+        provider,
+        ChipOptions {
+            generics: script.generics.clone(),
+            ..ChipOptions::default()
+        },
+    )?;
+    let widths: HashMap<String, usize> = chip
+        .ports
+        .iter()
+        .map(|(n, p)| (n.clone(), p.width))
+        .collect();
+    let mut simulator = Simulator::new(chip);
+
+    let mut table = Table {
+        columns: script
+            .output_list
+            .iter()
+            .map(|o| o.port_name.clone())
+            .collect(),
+        rows: Vec::new(),
+    };
+    let mut inputs = BusMap::new();
+    let mut outputs = BusMap::new();
+    let mut cycle = 0;
+    let mut presses = Vec::new();
+    for step in &script.steps {
+        for instruction in &step.instructions {
+            match instruction {
+                Instruction::Set(port, value) => {
+                    let width = widths.get(port).ok_or_else(|| N2VError {
+                        msg: format!(
+                            "Test script sets port {} which the chip does not have.",
+                            port
+                        ),
+                        kind: ErrorKind::Other,
+                    })?;
+                    let bits = input_bits(value, *width)?;
+                    inputs.create_bus(port, bits.len())?;
+                    inputs.insert_option(&Bus::from(port.clone()), bits);
+                }
+                Instruction::Eval | Instruction::Tick => {
+                    simulator.press_key(pressed_key(&presses, cycle));
+                    outputs = simulator.simulate(&inputs)?;
+                }
+                Instruction::Tock => {
+                    simulator.tick()?;
+                    cycle += 1;
+                    simulator.press_key(pressed_key(&presses, cycle));
+                    outputs = simulator.simulate(&inputs)?;
+                }
+                Instruction::Press(p) => presses.push(p.clone()),
+                // Checks are for grading, the table only shows outputs.
+                Instruction::Screen(_) => {}
+                Instruction::Output => {
+                    table.rows.push(
+                        script
+                            .output_list
+                            .iter()
+                            .map(|o| {
+                                let mut bits = outputs.get_name(&o.port_name);
+                                bits.reverse();
+                                format_value(&bits, &o.number_system)
+                            })
+                            .collect(),
+                    );
+                }
+            }
+        }
+    }
+    Ok(table)
+}
+
+// Formats bits, least significant first, the way nand2tetris prints them.
+fn format_value(bits: &[Option<bool>], number_system: &NumberSystem) -> String {
+    let binary: String = bits
+        .iter()
+        .rev()
+        .map(|b| match b {
+            None => '?',
+            Some(true) => '1',
+            Some(false) => '0',
+        })
+        .collect();
+    if bits.iter().any(|b| b.is_none()) {
+        return binary;
+    }
+    let value = bits
+        .iter()
+        .rev()
+        .fold(0u64, |acc, b| (acc << 1) | (b.unwrap() as u64));
+    match number_system {
+        // The nand2tetris tools print 16-bit words as two's complement.
+        NumberSystem::Decimal if bits.len() == 16 => format!("{}", value as u16 as i16),
+        NumberSystem::Decimal => format!("{}", value),
+        NumberSystem::Hex => format!("{:X}", value),
+        NumberSystem::Binary | NumberSystem::String => binary,
+    }
+}
+
+fn markdown_table(table: &Table) -> String {
+    let mut out = format!("| {} |\n", table.columns.join(" | "));
+    let rules: Vec<String> = table
+        .columns
+        .iter()
+        .map(|c| "-".repeat(c.len().max(3)))
+        .collect();
+    out.push_str(&format!(
+        "|{}|\n",
+        rules
+            .iter()
+            .map(|r| format!(" {} ", r))
+            .collect::<Vec<_>>()
+            .join("|")
+    ));
+    for row in &table.rows {
+        out.push_str(&format!("| {} |\n", row.join(" | ")));
+    }
+    out
+}
+
+// One row per signal and one column per output. Single bits are drawn as
+// high and low lines, wider signals show their values.
+fn waveform(table: &Table) -> String {
+    let name_width = table.columns.iter().map(|c| c.len()).max().unwrap_or(0);
+    let cell_width = table
+        .rows
+        .iter()
+        .flatten()
+        .map(|v| v.chars().count() + 1)
+        .max()
+        .unwrap_or(0)
+        .max(2);
+
+    let mut out = String::from("```text\n");
+    for (i, name) in table.columns.iter().enumerate() {
+        let mut line = format!("{:width$} ", name, width = name_width);
+        for row in &table.rows {
+            let cell = match row[i].as_str() {
+                "1" => "▔".repeat(cell_width),
+                "0" => "▁".repeat(cell_width),
+                v => format!("|{:width$}", v, width = cell_width - 1),
+            };
+            line.push_str(&cell);
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out.push_str("```\n");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_render() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions")
+            .join("Xor.md");
+        let document = "# Xor
+
+```hdl truth-table
+CHIP Xor2 {
+    IN a, b;
+    OUT out;
+    PARTS:
+    Nand(a=a, b=b, out=n);
+    Or(a=a, b=b, out=o);
+    And(a=n, b=o, out=out);
+}
+```
+
+```tst wave
+load Xor2.hdl, output-file Xor2.out, compare-to Xor2.cmp,
+output-list a%B1.1.1 b%B1.1.1 out%B1.1.1;
+set a 0, set b 1, eval, output;
+set a 1, set b 1, eval, output;
+```
+
+```tst
+load Inc16.hdl, output-file Inc16.out, compare-to Inc16.cmp,
+output-list in%D1.6.1 out%B1.16.1;
+set in 5, eval, output;
+```
+";
+        let rendered = render(document, &path).expect("Render error");
+        assert!(rendered.starts_with("# Xor\n\n```hdl truth-table\nCHIP Xor2 {"));
+        assert!(rendered.contains(
+            "| a | b | out |
+| --- | --- | --- |
+| 0 | 0 | 0 |
+| 0 | 1 | 1 |
+| 1 | 0 | 1 |
+| 1 | 1 | 0 |
+"
+        ));
+        assert!(rendered.contains(
+            "```text
+a   ▁▁▔▔
+b   ▔▔▔▔
+out ▔▔▁▁
+```
+"
+        ));
+
+        assert!(rendered.contains("| 5 | 0000000000000110 |"));
+
+        let broken = "```hdl\nCHIP Broken {\n```\n";
+        let e = render(broken, &path).err().unwrap().to_string();
+        assert!(e.contains("line 1"));
+    }
+}
@@ -1,15 +1,24 @@
-use crate::error::{ErrorKind, N2VError};
+use crate::builtin::{library_hdl, LIBRARY_CHIPS};
+use crate::error::{ErrorKind, N2VError, ProviderError, WhidlError};
 use crate::expr::*;
-use crate::scanner::Token;
+use crate::lint::{Level, Levels, Lint};
 use crate::scanner::TokenType;
+use crate::scanner::{Comment, Span, Token};
+use crate::visit::{walk_chip, walk_chip_children, walk_width, Visitor};
 use crate::Scanner;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fs;
-use std::path::PathBuf;
-use std::rc::Rc;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use tracing::{debug, debug_span};
 
-#[derive(Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 #[allow(clippy::large_enum_variant)]
 pub enum Part {
     Component(Component),
@@ -18,293 +27,2692 @@ pub enum Part {
 
 /// The Parse Tree for an HDL Chip.
 ///
-#[derive(Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ChipHDL {
     pub name: String,
+    pub interface: Option<Identifier>, // The `INTERFACE` keyword, for chips that only declare ports.
+    pub imports: Vec<Identifier>, // Directories from `USE "../lib";`, relative to the chip's file.
+    pub constants: Vec<Constant>, // Declared before the chip with `CONST BUSW 16;`.
     pub ports: Vec<GenericPort>,
     pub parts: Vec<Part>,
     pub path: Option<PathBuf>,
     pub generic_decls: Vec<Identifier>,
+    pub clocked: Vec<Identifier>, // Pins declared with `CLOCKED`, e.g. `CLOCKED in, load;`
+    pub builtin: Option<Identifier>, // Native implementation declared with `BUILTIN Name;`
+    pub stimulus: Option<Stimulus>, // Set for testbench chips.
+    pub table: Option<Table>,     // Behaviour written as a truth table instead of parts.
+    pub annotations: Vec<Annotation>, // Written before `CHIP`, e.g. `@doc("...")`.
+    pub comments: Comments,       // Comments before `CHIP` and after the closing brace.
+    pub body_comments: Vec<Comment>, // Comments in the chip that are not attached to a part.
+    #[serde(skip)]
+    pub shadows: Option<String>, // Set by `get_hdl` when the file hides others of the chip.
 }
 
-impl std::fmt::Display for ChipHDL {
+/// A number shared by chips, declared as `CONST BUSW 16;` before a chip or
+/// in the `defs.hdl` file of its directory. A constant can be used anywhere
+/// a number can, and its value may use the constants declared before it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Constant {
+    pub name: Identifier,
+    pub value: GenericWidth,
+}
+
+impl std::fmt::Display for Constant {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}: PORTS({:?})", self.name, self.ports)
+        write!(f, "CONST {} {};", self.name.value, hdl_expr(&self.value))
     }
 }
 
-impl ChipHDL {
-    pub fn get_port(&self, name: &str) -> Result<&GenericPort, Box<dyn Error>> {
-        let port_idx = self.ports.iter().position(|x| x.name.value == name);
+/// Metadata written before a chip or part, such as `@keep` or
+/// `@builtin("RAM8")`. Any name is accepted so that tools can add their own,
+/// those the backends understand are listed in `ANNOTATIONS`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Annotation {
+    pub name: Identifier,
+    pub args: Vec<String>, // String arguments without their quotes.
+}
 
-        match port_idx {
-            Some(idx) => Ok(&self.ports[idx]),
-            None => Err(Box::new(N2VError {
-                msg: format!("Attempt to get non-existent port {}", name),
-                kind: ErrorKind::Other,
-            })),
+/// Annotations with a meaning, and how many arguments each takes.
+/// - `@keep` on a part keeps its outputs through VHDL synthesis.
+/// - `@doc("...")` is copied into generated VHDL as a comment.
+/// - `@builtin("Name")` on a chip is the same as `BUILTIN Name;`.
+/// - `@peripheral("24577")` on a chip maps it into `Memory` at that address.
+pub const ANNOTATIONS: [(&str, usize); 4] =
+    [("keep", 0), ("doc", 1), ("builtin", 1), ("peripheral", 1)];
+
+impl std::fmt::Display for Annotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "@{}", self.name.value)?;
+        if !self.args.is_empty() {
+            let args: Vec<String> = self.args.iter().map(|a| format!("\"{}\"", a)).collect();
+            write!(f, "({})", args.join(", "))?;
         }
+        Ok(())
     }
 }
 
-pub trait HdlProvider {
-    fn get_hdl(&self, file_name: &str) -> Result<String, std::io::Error>;
-    fn get_path(&self, file_name: &str) -> PathBuf;
+pub fn find_annotation<'a>(annotations: &'a [Annotation], name: &str) -> Option<&'a Annotation> {
+    annotations.iter().find(|a| a.name.value == name)
 }
 
-pub struct FileReader {
-    base_path: PathBuf,
+/// Signals a testbench chip drives itself, declared with
+/// `STIMULUS 16 { a[16] = COUNTER; b = RANDOM 7; }`. The number is how many
+/// clock cycles the testbench runs for.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Stimulus {
+    pub cycles: usize,
+    pub signals: Vec<StimulusSignal>,
+    pub line: Option<u32>,
 }
 
-impl FileReader {
-    pub fn new(base_path: &str) -> FileReader {
-        if base_path.is_empty() {
-            panic!("empty basepath, start file paths in the same directory with ./");
-        }
-        FileReader {
-            base_path: PathBuf::from(base_path),
-        }
-    }
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StimulusSignal {
+    pub name: Identifier,
+    pub width: usize,
+    pub generator: Generator,
 }
 
-impl HdlProvider for FileReader {
-    fn get_hdl(&self, file_name: &str) -> Result<String, std::io::Error> {
-        let path = self.base_path.join(file_name);
-        let s = fs::read_to_string(&path);
-        if let Err(e) = s {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                format!(
-                    "Unable to get HDL for {:?}. {} {:?}",
-                    path, e, self.base_path
-                ),
-            ));
+/// How a stimulus signal changes from one cycle to the next.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Generator {
+    Counter,     // Counts up from zero, wrapping around.
+    Random(u64), // Pseudo-random values from an xorshift generator with this seed.
+}
+
+impl std::fmt::Display for StimulusSignal {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name.value)?;
+        if self.width != 1 {
+            write!(f, "[{}]", self.width)?;
+        }
+        match self.generator {
+            Generator::Counter => write!(f, " = COUNTER"),
+            Generator::Random(seed) => write!(f, " = RANDOM {}", seed),
         }
-        s
     }
+}
 
-    fn get_path(&self, file_name: &str) -> PathBuf {
-        self.base_path.join(file_name)
-    }
+/// The behaviour of a small chip as a truth table, written in place of its
+/// parts as `TABLE { 0 0 -> 1; 0 1 -> 1; 1 0 -> 1; 1 1 -> 0; }`. A row gives
+/// the bits of the inputs, then of the outputs, in the order the ports are
+/// declared and each port's highest bit first. Outputs are undefined for
+/// inputs that have no row.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Table {
+    pub keyword: Identifier, // The `TABLE` keyword, where errors about the table are shown.
+    pub rows: Vec<TableRow>,
 }
 
-#[derive(Serialize, Debug, Clone, PartialEq, Eq, Hash)]
-pub struct Identifier {
-    pub value: String,
-    pub path: Option<PathBuf>, // Set to None if chip not read from disk, e.g. NAND and DFF.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TableRow {
+    pub inputs: Vec<String>, // Groups of bits as written, e.g. `0 01`.
+    pub outputs: Vec<String>,
     pub line: Option<u32>,
 }
 
-impl From<Token> for Identifier {
-    fn from(t: Token) -> Self {
-        if t.token_type != TokenType::Identifier {
-            panic!("Attempt to create Identifier from non-identifier token");
-        }
+impl TableRow {
+    pub fn input_bits(&self) -> String {
+        self.inputs.concat()
+    }
 
-        Identifier {
-            value: t.lexeme,
-            path: Some(t.path),
-            line: Some(t.line),
-        }
+    pub fn output_bits(&self) -> String {
+        self.outputs.concat()
     }
 }
 
-impl From<&str> for Identifier {
-    fn from(t: &str) -> Self {
-        Identifier {
-            value: String::from(t),
-            path: None,
-            line: None,
+impl std::fmt::Display for TableRow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.inputs.join(" "), self.outputs.join(" "))
+    }
+}
+
+impl Table {
+    /// Checks that every row of the table of chip `name` has as many bits
+    /// as the inputs and outputs are wide.
+    pub fn check_widths(&self, name: &str, inputs: usize, outputs: usize) -> Result<(), String> {
+        for row in &self.rows {
+            let (i, o) = (row.input_bits().len(), row.output_bits().len());
+            if i != inputs || o != outputs {
+                return Err(format!(
+                    "The row `{}` of the TABLE of {} has {} input and {} output bits, but the chip has {} input and {} output bits.",
+                    row, name, i, o, inputs, outputs
+                ));
+            }
         }
+        Ok(())
     }
 }
 
-#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum PortDirection {
-    In,
-    Out,
+/// Comments attached to a node of the parse tree so that tools such as
+/// formatters can reproduce them.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Comments {
+    pub leading: Vec<Comment>, // Comments between the previous node and this one.
+    pub trailing: Vec<Comment>, // Comments after the node on its last line.
 }
 
-#[derive(Serialize, Clone, PartialEq, Eq, Hash, Debug)]
-pub struct GenericPort {
-    pub name: Identifier,
-    pub width: GenericWidth,
-    pub direction: PortDirection,
+impl std::fmt::Display for ChipHDL {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: PORTS({:?})", self.name, self.ports)
+    }
 }
 
-#[derive(Clone)]
-pub struct Component {
-    pub name: Identifier,
-    pub mappings: Vec<PortMapping>,
-    pub generic_params: Vec<GenericWidth>,
+// Expressions in HDL are written without parentheses, e.g. `i+1`.
+pub fn hdl_expr(w: &GenericWidth) -> String {
+    match w {
+        GenericWidth::Terminal(t) => t.to_string(),
+        GenericWidth::Expr(Op::Add, a, b) => format!("{}+{}", hdl_expr(a), hdl_expr(b)),
+        GenericWidth::Expr(Op::Sub, a, b) => format!("{}-{}", hdl_expr(a), hdl_expr(b)),
+        GenericWidth::Expr(Op::Max, a, b) => format!("max({}, {})", hdl_expr(a), hdl_expr(b)),
+        GenericWidth::Expr(Op::Min, a, b) => format!("min({}, {})", hdl_expr(a), hdl_expr(b)),
+        GenericWidth::Log2(a) => format!("log2({})", hdl_expr(a)),
+        GenericWidth::If(c, a, b) => format!(
+            "if({} {} {}, {}, {})",
+            hdl_expr(&c.left),
+            c.cmp.symbol(),
+            hdl_expr(&c.right),
+            hdl_expr(a),
+            hdl_expr(b)
+        ),
+    }
 }
 
-#[derive(Clone)]
-pub struct Loop {
-    pub start: GenericWidth,
-    pub end: GenericWidth,
-    pub iterator: Identifier,
-    pub body: Vec<Component>, // Prevent nested loops.
+// Prints a component the way it is written in HDL, without the semicolon.
+impl std::fmt::Display for Component {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.qualified_name())?;
+        if !self.generic_params.is_empty() {
+            let params: Vec<String> = self.generic_params.iter().map(|g| g.to_string()).collect();
+            write!(f, "<{}>", params.join(", "))?;
+        }
+        let mappings: Vec<String> = self
+            .mappings
+            .iter()
+            .map(|m| format!("{}={}", m.port, m.wire))
+            .collect();
+        write!(f, "({})", mappings.join(", "))
+    }
 }
 
-#[derive(Serialize, Clone, PartialEq, Eq, Hash, Debug)]
-pub struct BusHDL {
-    pub name: String,
-    pub start: Option<GenericWidth>,
-    pub end: Option<GenericWidth>,
+impl std::fmt::Display for BusHDL {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let stride = match self.stride {
+            Some(k) => format!(":{}", k),
+            None => String::new(),
+        };
+        match (&self.start, &self.end) {
+            (Some(s), Some(e)) if s == e => write!(f, "{}[{}]", self.name, hdl_expr(s)),
+            (Some(s), Some(e)) if self.descending => {
+                write!(
+                    f,
+                    "{}[{}..{}{}]",
+                    self.name,
+                    hdl_expr(e),
+                    hdl_expr(s),
+                    stride
+                )
+            }
+            (Some(s), Some(e)) => {
+                write!(
+                    f,
+                    "{}[{}..{}{}]",
+                    self.name,
+                    hdl_expr(s),
+                    hdl_expr(e),
+                    stride
+                )
+            }
+            _ => write!(f, "{}", self.name),
+        }
+    }
 }
 
-//  Not(in=sel, out=notSel); has two wires { name : "sel", port: "in" }, { name : "notSel", port: "out" }
-#[derive(Clone)]
-pub struct PortMapping {
-    pub wire_ident: Identifier,
-    pub wire: BusHDL,
-    pub port: BusHDL,
+impl ChipHDL {
+    /// The native implementation from `BUILTIN Name;` or `@builtin("Name")`.
+    pub fn builtin_name(&self) -> Option<Identifier> {
+        self.builtin.clone().or_else(|| {
+            let a = find_annotation(&self.annotations, "builtin")?;
+            Some(Identifier {
+                value: a.args.first()?.clone(),
+                ..a.name.clone()
+            })
+        })
+    }
+
+    pub fn get_port(&self, name: &str) -> Result<&GenericPort, Box<dyn Error>> {
+        let port_idx = self.ports.iter().position(|x| x.name.value == name);
+
+        match port_idx {
+            Some(idx) => Ok(&self.ports[idx]),
+            None => Err(Box::new(N2VError {
+                msg: format!(
+                    "Attempt to get non-existent port {}.{}",
+                    name,
+                    did_you_mean(name, &self.port_names())
+                ),
+                kind: ErrorKind::ElaborationError,
+            })),
+        }
+    }
+
+    pub fn port_names(&self) -> Vec<String> {
+        self.ports.iter().map(|p| p.name.value.clone()).collect()
+    }
 }
 
-/// Looks up chip definition for a chip.
-/// name is the name of the chip, not including .hdl extension
-/// provider is responsible for retrieving the HDL file (provider will have its own base path)
-pub fn get_hdl(name: &str, provider: &Rc<dyn HdlProvider>) -> Result<ChipHDL, Box<dyn Error>> {
-    if name.to_lowercase() == "nand" {
-        // Hard-coded NAND chip
-        return Ok(ChipHDL {
-            name: String::from("NAND"),
-            ports: vec![
-                GenericPort {
-                    name: Identifier::from("a"),
-                    width: GenericWidth::Terminal(Terminal::Num(1)),
-                    direction: PortDirection::In,
-                },
-                GenericPort {
-                    name: Identifier::from("b"),
-                    width: GenericWidth::Terminal(Terminal::Num(1)),
-                    direction: PortDirection::In,
-                },
-                GenericPort {
-                    name: Identifier::from("out"),
-                    width: GenericWidth::Terminal(Terminal::Num(1)),
-                    direction: PortDirection::Out,
-                },
-            ],
-            parts: Vec::new(),
-            path: None,
-            generic_decls: Vec::new(),
-        });
-    } else if name.to_lowercase() == "dff" {
-        // Hard-coded NAND chip
-        return Ok(ChipHDL {
-            name: String::from("DFF"),
-            ports: vec![
-                GenericPort {
-                    name: Identifier::from("in"),
-                    width: GenericWidth::Terminal(Terminal::Num(1)),
-                    direction: PortDirection::In,
-                },
-                GenericPort {
-                    name: Identifier::from("out"),
-                    width: GenericWidth::Terminal(Terminal::Num(1)),
-                    direction: PortDirection::Out,
-                },
-            ],
-            parts: Vec::new(),
-            path: None,
-            generic_decls: Vec::new(),
-        });
+pub trait HdlProvider: Send + Sync {
+    fn get_hdl(&self, file_name: &str) -> Result<String, ProviderError>;
+    fn get_path(&self, file_name: &str) -> PathBuf;
+
+    /// Names of the chips the provider can find, used to suggest
+    /// corrections for misspelled chip names.
+    fn chip_names(&self) -> Vec<String> {
+        Vec::new()
     }
 
-    let filename = String::from(name) + ".hdl";
-    let path = PathBuf::from(filename);
+    /// The provider for the chips of a library, used to look up qualified
+    /// names such as `std.Mux16`. Chips in a library find their own parts
+    /// there first.
+    fn library(&self, _namespace: &str) -> Option<Arc<dyn HdlProvider>> {
+        None
+    }
 
-    let contents = provider.get_hdl(path.to_str().unwrap())?;
-    let mut scanner = Scanner::new(contents.as_str(), path);
-    let mut parser = Parser {
-        scanner: &mut scanner,
-    };
-    parser.parse()
+    /// The library this provider finds chips in, if it is one.
+    fn namespace(&self) -> Option<String> {
+        None
+    }
+
+    /// The implementation chosen for an interface, e.g. `fast` for
+    /// `Alu.fast.hdl`. None leaves the choice to the project file.
+    fn implementation(&self, _interface: &str) -> Option<String> {
+        None
+    }
+
+    /// Every file the provider could read for `file_name`, the one it reads
+    /// first. More than one means the others are shadowed.
+    fn candidates(&self, file_name: &str) -> Vec<PathBuf> {
+        match self.get_hdl(file_name) {
+            Ok(_) => vec![self.get_path(file_name)],
+            Err(_) => Vec::new(),
+        }
+    }
 }
 
-pub struct Parser<'a, 'b> {
-    pub scanner: &'a mut Scanner<'b>,
+/// Extensions of chip files, in the order `FileReader` looks for them.
+pub const HDL_EXTENSIONS: [&str; 2] = ["hdl", "whidl"];
+
+/// Finds chips in a directory. A chip `Not` is read from `Not.hdl`, or
+/// failing that `Not.whidl`, or a file whose name differs only in case,
+/// such as `not.hdl`, which is what Windows and macOS would open.
+pub struct FileReader {
+    base_path: PathBuf,
+    extensions: Vec<String>,
 }
 
-impl<'a, 'b> Parser<'a, 'b> {
-    pub fn parse(&mut self) -> Result<ChipHDL, Box<dyn Error>> {
-        self.chip()
+impl FileReader {
+    /// An empty `base_path` is the current directory, e.g. the parent of
+    /// `Not.hdl`.
+    pub fn new(base_path: &str) -> FileReader {
+        let base_path = if base_path.is_empty() { "." } else { base_path };
+        FileReader {
+            base_path: PathBuf::from(base_path),
+            extensions: HDL_EXTENSIONS.map(String::from).to_vec(),
+        }
     }
 
-    fn consume(&mut self, tt: TokenType) -> Result<Token, Box<dyn Error>> {
-        let t = self.scanner.next();
-        match &t {
-            None => Err(Box::new(N2VError {
-                msg: format!("Early end of file, expected {}", tt),
-                kind: ErrorKind::ParseError(Token {
-                    lexeme: String::from(""),
-                    path: self.scanner.path.clone(),
-                    line: self.scanner.line,
-                    start: self.scanner.col,
-                    token_type: TokenType::Eof,
-                }),
-            })),
-            Some(t) => {
-                if t.token_type == tt {
-                    Ok(t.clone())
-                } else {
-                    Err(Box::new(N2VError {
-                        msg: format!(
-                            "I did not expect to see `{}`. I expected to see {}",
-                            t.lexeme, tt
-                        ),
-                        kind: ErrorKind::ParseError(t.clone()),
-                    }))
+    /// Looks for chips with these extensions instead, in order, e.g.
+    /// `["hdl"]`.
+    pub fn with_extensions(mut self, extensions: &[&str]) -> FileReader {
+        self.extensions = extensions.iter().map(|x| String::from(*x)).collect();
+        self
+    }
+
+    // The file to read for `file_name`, e.g. `Not.hdl`: the file of that
+    // name, or one with another extension, or one that differs only in
+    // case. Otherwise why there is none, naming files that nearly match.
+    fn resolve(&self, file_name: &str) -> Result<PathBuf, String> {
+        let exact = self.base_path.join(file_name);
+        if exact.is_file() {
+            return Ok(exact);
+        }
+        let stem = match file_name.strip_suffix(".hdl") {
+            Some(stem) => stem,
+            None => return Err(String::from("No such file.")),
+        };
+        let names: Vec<String> = self
+            .extensions
+            .iter()
+            .map(|x| format!("{}.{}", stem, x))
+            .collect();
+        if let Some(path) = names
+            .iter()
+            .map(|n| self.base_path.join(n))
+            .find(|p| p.is_file())
+        {
+            return Ok(path);
+        }
+
+        let files = self.files();
+        for name in &names {
+            let same: Vec<&String> = files
+                .iter()
+                .filter(|f| f.to_lowercase() == name.to_lowercase())
+                .collect();
+            match same.as_slice() {
+                [] => {}
+                [f] => return Ok(self.base_path.join(f)),
+                _ => {
+                    return Err(format!(
+                        "{} files match it when case is ignored: {}. Rename all but one.",
+                        same.len(),
+                        quoted(&same)
+                    ))
                 }
             }
         }
+        let extensions: Vec<String> = self.extensions.iter().map(|x| format!(".{}", x)).collect();
+        let mut why = format!(
+            "No file named {} ending in {} is in the directory.",
+            stem,
+            extensions.join(" or ")
+        );
+        // Files of the chip with another extension, e.g. `Not.tst` or
+        // `Not.hdl.txt`.
+        let prefix = format!("{}.", stem.to_lowercase());
+        let near: Vec<&String> = files
+            .iter()
+            .filter(|f| f.to_lowercase().starts_with(&prefix))
+            .collect();
+        if !near.is_empty() {
+            why += &format!(" Files with similar names: {}.", quoted(&near));
+        }
+        Err(why)
     }
 
-    fn chip(&mut self) -> Result<ChipHDL, Box<dyn Error>> {
-        // TODO: Print location information for token.
-        self.consume(TokenType::Chip)?;
-        let chip_name = self.consume(TokenType::Identifier)?;
+    // Names of the files in the directory, sorted.
+    fn files(&self) -> Vec<String> {
+        let mut files: Vec<String> = match fs::read_dir(&self.base_path) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.path().is_file())
+                .filter_map(|e| e.file_name().into_string().ok())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        files.sort();
+        files
+    }
+}
 
-        let generics = self.generic_decls()?;
+// Names in backquotes, separated by commas.
+fn quoted(names: &[&String]) -> String {
+    names
+        .iter()
+        .map(|n| format!("`{}`", n))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
 
-        self.consume(TokenType::LeftCurly)?;
+impl HdlProvider for FileReader {
+    fn get_hdl(&self, file_name: &str) -> Result<String, ProviderError> {
+        let path = self.resolve(file_name).map_err(|why| {
+            ProviderError::NotFound(format!(
+                "Unable to get HDL for {:?}. {}",
+                self.base_path.join(file_name),
+                why
+            ))
+        })?;
+        fs::read_to_string(&path).map_err(ProviderError::from)
+    }
 
-        self.consume(TokenType::In)?;
+    fn chip_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for f in self.files().iter().filter(|f| *f != DEFS_FILE) {
+            let name = self
+                .extensions
+                .iter()
+                .find_map(|x| f.strip_suffix(&format!(".{}", x)));
+            // Implementations of interfaces are found through the interface.
+            if let Some(name) = name.filter(|n| !n.contains('.')) {
+                if !names.iter().any(|n| n == name) {
+                    names.push(String::from(name));
+                }
+            }
+        }
+        names
+    }
 
-        let mut ports = self.port_names(PortDirection::In)?;
-        self.consume(TokenType::Out)?;
+    fn get_path(&self, file_name: &str) -> PathBuf {
+        self.resolve(file_name)
+            .unwrap_or_else(|_| self.base_path.join(file_name))
+    }
+}
 
-        ports.append(&mut self.port_names(PortDirection::Out)?);
+/// Finds chips in memory instead of on disk, for tests and for the browser.
+/// Sources are keyed by file name, e.g. `Not.hdl`.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryReader {
+    files: HashMap<String, String>,
+}
 
-        self.consume(TokenType::Parts)?;
-        self.consume(TokenType::Colon)?;
+impl MemoryReader {
+    pub fn new(files: HashMap<String, String>) -> MemoryReader {
+        MemoryReader { files }
+    }
 
-        let parts = self.parts()?;
+    /// Adds a file, replacing any file of the same name.
+    pub fn insert(&mut self, file_name: &str, source: &str) {
+        self.files
+            .insert(String::from(file_name), String::from(source));
+    }
+}
 
-        // match in ports (can out ports come before in ports?)
-        // match out ports
-        Ok(ChipHDL {
-            name: Identifier::from(chip_name).value,
-            ports,
-            parts,
-            path: Some(self.scanner.path.clone()),
-            generic_decls: generics,
-        })
+impl HdlProvider for MemoryReader {
+    fn get_hdl(&self, file_name: &str) -> Result<String, ProviderError> {
+        self.files
+            .get(file_name)
+            .cloned()
+            .ok_or_else(|| ProviderError::NotFound(format!("Unable to get HDL for {}", file_name)))
     }
 
-    fn generics(&mut self) -> Result<Vec<GenericWidth>, Box<dyn Error>> {
-        let mut res: Vec<GenericWidth> = Vec::new();
+    fn get_path(&self, file_name: &str) -> PathBuf {
+        PathBuf::from(file_name)
+    }
 
-        if self.scanner.peek().unwrap().token_type != TokenType::LeftAngle {
-            return Ok(Vec::new());
-        }
-        self.consume(TokenType::LeftAngle)?;
+    fn chip_names(&self) -> Vec<String> {
+        self.files
+            .keys()
+            .filter(|f| *f != DEFS_FILE)
+            .filter_map(|f| f.strip_suffix(".hdl").map(String::from))
+            .filter(|f| !f.contains('.'))
+            .collect()
+    }
+}
 
+/// Finds chips in a directory of a zip archive, so a project can be handed
+/// out and graded as one file. The files are read when the archive is
+/// opened. Paths are reported as the archive's path followed by the path in
+/// it, e.g. `project.zip/01/Not.hdl`.
+pub struct ZipReader {
+    base_path: PathBuf,
+    files: MemoryReader,
+}
+
+impl ZipReader {
+    /// Opens `archive` and reads the files directly in `dir`, a directory
+    /// in it such as `01`. An empty `dir` is the top of the archive.
+    pub fn open(archive: &Path, dir: &str) -> Result<ZipReader, Box<dyn Error>> {
+        let unreadable = |e: &dyn std::fmt::Display| N2VError {
+            msg: format!("Unable to read archive {}. {}", archive.display(), e),
+            kind: ErrorKind::IOError,
+        };
+        let file = fs::File::open(archive).map_err(|e| unreadable(&e))?;
+        let mut zip = zip::ZipArchive::new(file).map_err(|e| unreadable(&e))?;
+        let dir = dir.trim_matches('/');
+        let prefix = if dir.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", dir)
+        };
+
+        let mut files = MemoryReader::default();
+        for i in 0..zip.len() {
+            let mut entry = zip.by_index(i).map_err(|e| unreadable(&e))?;
+            let file_name = match entry.name().strip_prefix(&prefix) {
+                Some(f) if entry.is_file() && !f.contains('/') => f.to_string(),
+                _ => continue,
+            };
+            let mut source = String::new();
+            // Files that are not text, such as images, are left out.
+            if std::io::Read::read_to_string(&mut entry, &mut source).is_ok() {
+                files.insert(&file_name, &source);
+            }
+        }
+        Ok(ZipReader {
+            base_path: archive.join(dir),
+            files,
+        })
+    }
+}
+
+impl HdlProvider for ZipReader {
+    fn get_hdl(&self, file_name: &str) -> Result<String, ProviderError> {
+        self.files.get_hdl(file_name).map_err(|_| {
+            ProviderError::NotFound(format!(
+                "Unable to get HDL for {}. It is not in the archive.",
+                self.get_path(file_name).display()
+            ))
+        })
+    }
+
+    fn get_path(&self, file_name: &str) -> PathBuf {
+        self.base_path.join(file_name)
+    }
+
+    fn chip_names(&self) -> Vec<String> {
+        self.files.chip_names()
+    }
+}
+
+/// Finds the monitor and device chips that ship with whidl, for use as the
+/// last layer of a `ChainedProvider`.
+pub struct LibraryReader;
+
+impl HdlProvider for LibraryReader {
+    fn get_hdl(&self, file_name: &str) -> Result<String, ProviderError> {
+        file_name
+            .strip_suffix(".hdl")
+            .and_then(library_hdl)
+            .map(String::from)
+            .ok_or_else(|| {
+                ProviderError::NotFound(format!("{} is not a built-in chip.", file_name))
+            })
+    }
+
+    fn get_path(&self, file_name: &str) -> PathBuf {
+        PathBuf::from(file_name)
+    }
+
+    fn chip_names(&self) -> Vec<String> {
+        LIBRARY_CHIPS.map(String::from).to_vec()
+    }
+}
+
+/// Finds chips in the first of several named layers that has them, e.g. a
+/// student's directory, then the course's chips, then whidl's library. A
+/// chip in a layer overrides the chips of the same name in the layers after
+/// it, and is not reported as shadowing them.
+pub struct ChainedProvider {
+    layers: Vec<(String, Arc<dyn HdlProvider>)>,
+}
+
+impl ChainedProvider {
+    pub fn new(layers: Vec<(String, Arc<dyn HdlProvider>)>) -> ChainedProvider {
+        ChainedProvider { layers }
+    }
+
+    /// The name of the layer `file_name` is found in.
+    pub fn layer(&self, file_name: &str) -> Option<&str> {
+        self.find(file_name).map(|(name, _)| name.as_str())
+    }
+
+    fn find(&self, file_name: &str) -> Option<&(String, Arc<dyn HdlProvider>)> {
+        self.layers
+            .iter()
+            .find(|(_, p)| p.get_hdl(file_name).is_ok())
+    }
+}
+
+impl HdlProvider for ChainedProvider {
+    fn get_hdl(&self, file_name: &str) -> Result<String, ProviderError> {
+        for (name, provider) in &self.layers {
+            if let Ok(s) = provider.get_hdl(file_name) {
+                debug!(file_name, layer = name.as_str(), "found chip");
+                return Ok(s);
+            }
+        }
+        let names: Vec<&str> = self.layers.iter().map(|(n, _)| n.as_str()).collect();
+        Err(ProviderError::NotFound(format!(
+            "Unable to get HDL for {}. It is in none of {}.",
+            file_name,
+            names.join(", ")
+        )))
+    }
+
+    fn get_path(&self, file_name: &str) -> PathBuf {
+        match self.find(file_name).or(self.layers.first()) {
+            Some((_, p)) => p.get_path(file_name),
+            None => PathBuf::from(file_name),
+        }
+    }
+
+    fn chip_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for (_, provider) in &self.layers {
+            for name in provider.chip_names() {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    fn library(&self, namespace: &str) -> Option<Arc<dyn HdlProvider>> {
+        self.layers.iter().find_map(|(_, p)| p.library(namespace))
+    }
+
+    fn implementation(&self, interface: &str) -> Option<String> {
+        self.layers
+            .iter()
+            .find_map(|(_, p)| p.implementation(interface))
+    }
+
+    fn candidates(&self, file_name: &str) -> Vec<PathBuf> {
+        self.find(file_name)
+            .map(|(_, p)| p.candidates(file_name))
+            .unwrap_or_default()
+    }
+}
+
+/// The layers a chip in `dir` finds its parts in when there is a library
+/// path, the directories to look in after the chip's own, in order, e.g. a
+/// course's chips: `dir`, each directory of the path, then whidl's library.
+/// Each is named by its directory. None without a library path.
+pub fn lib_path_layers(dir: &str, lib_path: &[String]) -> Option<ChainedProvider> {
+    if lib_path.is_empty() {
+        return None;
+    }
+    Some(layers(dir, lib_path))
+}
+
+fn layers(dir: &str, lib_path: &[String]) -> ChainedProvider {
+    let mut layers: Vec<(String, Arc<dyn HdlProvider>)> = Vec::new();
+    for d in std::iter::once(dir).chain(lib_path.iter().map(|d| d.as_str())) {
+        layers.push((String::from(d), Arc::new(FileReader::new(d))));
+    }
+    layers.push((String::from("whidl"), Arc::new(LibraryReader)));
+    ChainedProvider::new(layers)
+}
+
+/// The provider for the parts of a chip in `dir`, through the library path
+/// if there is one.
+pub fn chip_provider(dir: &str, lib_path: &[String]) -> Arc<dyn HdlProvider> {
+    match lib_path_layers(dir, lib_path) {
+        Some(layers) => Arc::new(layers),
+        None => Arc::new(FileReader::new(dir)),
+    }
+}
+
+/// Remembers the files another provider reads and where they are, so a part
+/// used hundreds of times, such as the `Bit` of a RAM16K, is read once.
+/// `get_hdl` parses each source once already, by path and a hash of its
+/// text. Files that change after they are read are not seen again, so a
+/// cache is for one run, not for an editor.
+pub struct CachingProvider {
+    base: Arc<dyn HdlProvider>,
+    sources: Mutex<HashMap<String, Result<String, ProviderError>>>,
+    paths: Mutex<HashMap<String, PathBuf>>,
+}
+
+impl CachingProvider {
+    pub fn new(base: Arc<dyn HdlProvider>) -> CachingProvider {
+        CachingProvider {
+            base,
+            sources: Mutex::new(HashMap::new()),
+            paths: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl HdlProvider for CachingProvider {
+    fn get_hdl(&self, file_name: &str) -> Result<String, ProviderError> {
+        if let Some(read) = self.sources.lock().unwrap().get(file_name) {
+            return read.clone();
+        }
+        let read = self.base.get_hdl(file_name);
+        self.sources
+            .lock()
+            .unwrap()
+            .insert(String::from(file_name), read.clone());
+        read
+    }
+
+    fn get_path(&self, file_name: &str) -> PathBuf {
+        if let Some(path) = self.paths.lock().unwrap().get(file_name) {
+            return path.clone();
+        }
+        let path = self.base.get_path(file_name);
+        self.paths
+            .lock()
+            .unwrap()
+            .insert(String::from(file_name), path.clone());
+        path
+    }
+
+    fn chip_names(&self) -> Vec<String> {
+        self.base.chip_names()
+    }
+
+    fn library(&self, namespace: &str) -> Option<Arc<dyn HdlProvider>> {
+        self.base.library(namespace)
+    }
+
+    fn namespace(&self) -> Option<String> {
+        self.base.namespace()
+    }
+
+    fn implementation(&self, interface: &str) -> Option<String> {
+        self.base.implementation(interface)
+    }
+
+    fn candidates(&self, file_name: &str) -> Vec<PathBuf> {
+        self.base.candidates(file_name)
+    }
+}
+
+/// Finds chips with a base provider first, then in extra directories,
+/// e.g. the directories a chip imports with `USE`.
+pub struct SearchPath {
+    base: Arc<dyn HdlProvider>,
+    roots: Vec<PathBuf>,
+}
+
+impl SearchPath {
+    pub fn new(base: Arc<dyn HdlProvider>, roots: Vec<PathBuf>) -> SearchPath {
+        SearchPath { base, roots }
+    }
+
+    fn root_readers(&self) -> impl Iterator<Item = FileReader> + '_ {
+        self.roots
+            .iter()
+            .filter_map(|r| r.to_str().filter(|r| !r.is_empty()))
+            .map(FileReader::new)
+    }
+}
+
+impl HdlProvider for SearchPath {
+    fn get_hdl(&self, file_name: &str) -> Result<String, ProviderError> {
+        let e = match self.base.get_hdl(file_name) {
+            Ok(s) => return Ok(s),
+            Err(e) => e,
+        };
+        for root in self.root_readers() {
+            if let Ok(s) = root.get_hdl(file_name) {
+                return Ok(s);
+            }
+        }
+        Err(e)
+    }
+
+    fn get_path(&self, file_name: &str) -> PathBuf {
+        if self.base.get_hdl(file_name).is_err() {
+            if let Some(root) = self.root_readers().find(|r| r.get_hdl(file_name).is_ok()) {
+                return root.get_path(file_name);
+            }
+        }
+        self.base.get_path(file_name)
+    }
+
+    fn chip_names(&self) -> Vec<String> {
+        let mut names = self.base.chip_names();
+        for root in self.root_readers() {
+            names.extend(root.chip_names());
+        }
+        names
+    }
+
+    fn library(&self, namespace: &str) -> Option<Arc<dyn HdlProvider>> {
+        self.base.library(namespace)
+    }
+
+    fn namespace(&self) -> Option<String> {
+        self.base.namespace()
+    }
+
+    fn implementation(&self, interface: &str) -> Option<String> {
+        self.base.implementation(interface)
+    }
+
+    fn candidates(&self, file_name: &str) -> Vec<PathBuf> {
+        let mut paths = self.base.candidates(file_name);
+        for root in self.root_readers() {
+            for path in root.candidates(file_name) {
+                if !paths.contains(&path) {
+                    paths.push(path);
+                }
+            }
+        }
+        paths
+    }
+}
+
+/// Name of the project file that configures libraries.
+pub const PROJECT_FILE: &str = "whidl.json";
+
+#[derive(Deserialize)]
+struct ProjectFile {
+    #[serde(default)]
+    libraries: BTreeMap<String, PathBuf>,
+    #[serde(default)]
+    implementations: BTreeMap<String, String>,
+    #[serde(default)]
+    lints: BTreeMap<String, Level>,
+}
+
+// The project file for a chip in `dir` and what it holds, if there is one.
+fn read_project_file(dir: &Path) -> Result<Option<(PathBuf, ProjectFile)>, Box<dyn Error>> {
+    let path = match dir
+        .ancestors()
+        .map(|d| d.join(PROJECT_FILE))
+        .find(|p| p.is_file())
+    {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    let project: ProjectFile =
+        serde_json::from_str(&fs::read_to_string(&path)?).map_err(|e| N2VError {
+            msg: format!("Unable to read project file {}. {}", path.display(), e),
+            kind: ErrorKind::IOError,
+        })?;
+    Ok(Some((path, project)))
+}
+
+/// Sets the levels the project file for a chip in `dir` gives lints, e.g.
+/// `{ "lints": { "unused-wire": "deny" } }`, except for lints the command
+/// line has set in `levels`.
+pub fn configure_lints(dir: &Path, levels: &mut Levels) -> Result<(), Box<dyn Error>> {
+    let (path, project) = match read_project_file(dir)? {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    for (name, lint_level) in project.lints {
+        let lint: Lint = name.parse().map_err(|e| N2VError {
+            msg: format!("Unable to read project file {}. {}", path.display(), e),
+            kind: ErrorKind::IOError,
+        })?;
+        levels.set_default(lint, lint_level);
+    }
+    Ok(())
+}
+
+/// Adds the libraries of a project to a provider. Libraries are configured
+/// in a `whidl.json` file in the directory of a chip or any directory above
+/// it, e.g. `{ "libraries": { "std": "lib/std" } }`, with directories
+/// relative to the project file. The file also chooses implementations of
+/// interfaces, e.g. `{ "implementations": { "Alu": "fast" } }` uses
+/// `Alu.fast.hdl` wherever `Alu` is a part.
+pub struct Project {
+    base: Arc<dyn HdlProvider>,
+    libraries: BTreeMap<String, PathBuf>,
+    implementations: BTreeMap<String, String>,
+    namespace: Option<String>, // Set for the provider of a library.
+}
+
+impl Project {
+    /// Finds the project file for a chip in `dir`. None if there is none.
+    pub fn find(base: Arc<dyn HdlProvider>, dir: &Path) -> Result<Option<Project>, Box<dyn Error>> {
+        let (path, project) = match read_project_file(dir)? {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        let root = path.parent().unwrap();
+        Ok(Some(Project {
+            base,
+            libraries: project
+                .libraries
+                .into_iter()
+                .map(|(namespace, dir)| (namespace, root.join(dir)))
+                .collect(),
+            implementations: project.implementations,
+            namespace: None,
+        }))
+    }
+}
+
+impl HdlProvider for Project {
+    fn get_hdl(&self, file_name: &str) -> Result<String, ProviderError> {
+        self.base.get_hdl(file_name)
+    }
+
+    fn get_path(&self, file_name: &str) -> PathBuf {
+        self.base.get_path(file_name)
+    }
+
+    fn chip_names(&self) -> Vec<String> {
+        let mut names = self.base.chip_names();
+        for (namespace, dir) in &self.libraries {
+            if let Some(d) = dir.to_str().filter(|d| !d.is_empty()) {
+                let chips = FileReader::new(d).chip_names();
+                names.extend(chips.iter().map(|c| format!("{}.{}", namespace, c)));
+            }
+        }
+        names
+    }
+
+    fn library(&self, namespace: &str) -> Option<Arc<dyn HdlProvider>> {
+        let dir = self.libraries.get(namespace)?.to_str()?;
+        Some(Arc::new(Project {
+            base: Arc::new(FileReader::new(dir)),
+            libraries: self.libraries.clone(),
+            implementations: self.implementations.clone(),
+            namespace: Some(String::from(namespace)),
+        }))
+    }
+
+    fn namespace(&self) -> Option<String> {
+        self.namespace.clone()
+    }
+
+    fn implementation(&self, interface: &str) -> Option<String> {
+        self.base
+            .implementation(interface)
+            .or_else(|| self.implementations.get(interface).cloned())
+    }
+
+    fn candidates(&self, file_name: &str) -> Vec<PathBuf> {
+        self.base.candidates(file_name)
+    }
+}
+
+/// Chooses implementations of interfaces in place of those the project
+/// file chooses, e.g. to grade a chip against each reference design.
+pub struct Configuration {
+    base: Arc<dyn HdlProvider>,
+    implementations: BTreeMap<String, String>,
+}
+
+impl Configuration {
+    pub fn new(
+        base: Arc<dyn HdlProvider>,
+        implementations: BTreeMap<String, String>,
+    ) -> Configuration {
+        Configuration {
+            base,
+            implementations,
+        }
+    }
+}
+
+impl HdlProvider for Configuration {
+    fn get_hdl(&self, file_name: &str) -> Result<String, ProviderError> {
+        self.base.get_hdl(file_name)
+    }
+
+    fn get_path(&self, file_name: &str) -> PathBuf {
+        self.base.get_path(file_name)
+    }
+
+    fn chip_names(&self) -> Vec<String> {
+        self.base.chip_names()
+    }
+
+    fn library(&self, namespace: &str) -> Option<Arc<dyn HdlProvider>> {
+        let library = self.base.library(namespace)?;
+        Some(Arc::new(Configuration::new(
+            library,
+            self.implementations.clone(),
+        )))
+    }
+
+    fn namespace(&self) -> Option<String> {
+        self.base.namespace()
+    }
+
+    fn implementation(&self, interface: &str) -> Option<String> {
+        self.implementations
+            .get(interface)
+            .cloned()
+            .or_else(|| self.base.implementation(interface))
+    }
+
+    fn candidates(&self, file_name: &str) -> Vec<PathBuf> {
+        self.base.candidates(file_name)
+    }
+}
+
+/// Implementations of an interface in its directory, e.g. `fast` for
+/// `Alu.fast.hdl`.
+pub fn implementations(hdl: &ChipHDL, name: &str) -> Vec<String> {
+    let dir = match hdl.path.as_ref().and_then(|p| p.parent()) {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(_) => return Vec::new(),
+    };
+    let prefix = format!("{}.", name);
+    let mut names: Vec<String> = entries
+        .filter_map(|e| e.ok()?.file_name().into_string().ok())
+        .filter_map(|f| {
+            let variant = f.strip_prefix(&prefix)?.strip_suffix(".hdl")?;
+            (!variant.is_empty() && !variant.contains('.')).then(|| String::from(variant))
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+// Checks that an implementation declares the ports and generics of its
+// interface.
+fn check_implementation(
+    interface: &ChipHDL,
+    imp: &ChipHDL,
+    file: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mismatch = |detail: String| -> Box<dyn Error> {
+        Box::new(N2VError {
+            msg: format!(
+                "{} does not implement interface {}. {}",
+                file, interface.name, detail
+            ),
+            kind: ErrorKind::SimulationError(imp.path.clone()),
+        })
+    };
+    if imp.interface.is_some() || imp.name != interface.name {
+        return Err(mismatch(format!("It declares {} instead.", imp.name)));
+    }
+    let generics = |hdl: &ChipHDL| -> Vec<String> {
+        hdl.generic_decls.iter().map(|g| g.value.clone()).collect()
+    };
+    if generics(imp) != generics(interface) {
+        return Err(mismatch(format!(
+            "It declares generics <{}> where the interface declares <{}>.",
+            generics(imp).join(", "),
+            generics(interface).join(", ")
+        )));
+    }
+    for port in &interface.ports {
+        match imp.ports.iter().find(|p| p.name.value == port.name.value) {
+            None => {
+                return Err(mismatch(format!("It has no port {}.", port.name.value)));
+            }
+            Some(p) if p.direction != port.direction => {
+                return Err(mismatch(format!(
+                    "Port {} is not an {} port.",
+                    port.name.value,
+                    if port.direction == PortDirection::In {
+                        "input"
+                    } else {
+                        "output"
+                    }
+                )));
+            }
+            Some(p) if hdl_expr(&p.width) != hdl_expr(&port.width) => {
+                return Err(mismatch(format!(
+                    "Port {} is {} bits wide instead of {}.",
+                    port.name.value,
+                    hdl_expr(&p.width),
+                    hdl_expr(&port.width)
+                )));
+            }
+            Some(_) => {}
+        }
+    }
+    if let Some(extra) = imp
+        .ports
+        .iter()
+        .find(|p| !interface.ports.iter().any(|i| i.name.value == p.name.value))
+    {
+        return Err(mismatch(format!(
+            "Port {} is not in the interface.",
+            extra.name.value
+        )));
+    }
+    Ok(())
+}
+
+/// The implementation of a chip declared with `INTERFACE`, borrowed when
+/// the chip is not an interface. The provider's choice comes first, then
+/// that of the project file, and an interface with one implementation
+/// needs no choice.
+pub fn with_implementation<'a>(
+    hdl: &'a ChipHDL,
+    provider: &Arc<dyn HdlProvider>,
+) -> Result<Cow<'a, ChipHDL>, Box<dyn Error>> {
+    if hdl.interface.is_none() {
+        return Ok(Cow::Borrowed(hdl));
+    }
+    let name = hdl.name.rsplit('.').next().unwrap();
+    let variants = implementations(hdl, name);
+    let dir = hdl
+        .path
+        .as_ref()
+        .and_then(|p| p.parent())
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    let chosen = match provider.implementation(name) {
+        Some(v) => Some(v),
+        None => Project::find(provider.clone(), &dir)?
+            .and_then(|p| p.implementations.get(name).cloned()),
+    };
+    let variant = match (chosen, variants.as_slice()) {
+        (Some(v), _) => v,
+        (None, [only]) => only.clone(),
+        (None, _) => {
+            let example = variants.first().map_or("fast", String::as_str);
+            return Err(Box::new(N2VError {
+                msg: format!(
+                    "Chip {} is an interface with {} implementations{}. Choose one in {}, e.g. {{ \"implementations\": {{ \"{}\": \"{}\" }} }}.",
+                    name,
+                    variants.len(),
+                    if variants.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" ({})", variants.join(", "))
+                    },
+                    PROJECT_FILE,
+                    name,
+                    example
+                ),
+                kind: ErrorKind::SimulationError(hdl.path.clone()),
+            }));
+        }
+    };
+    let file = format!("{}.{}.hdl", name, variant);
+    let source = provider.get_hdl(&file).map_err(|e| N2VError {
+        msg: format!(
+            "Interface {} has no implementation {}. {}{}",
+            name,
+            variant,
+            e,
+            did_you_mean(&variant, &variants)
+        ),
+        kind: ErrorKind::IOError,
+    })?;
+    let imp = parse_cached(&source, provider.get_path(&file))?;
+    check_implementation(hdl, &imp, &file)?;
+    Ok(Cow::Owned(imp))
+}
+
+// Namespaces of the qualified component names in a chip.
+fn namespaces(hdl: &ChipHDL) -> Vec<&Identifier> {
+    hdl.parts
+        .iter()
+        .flat_map(|p| match p {
+            Part::Component(c) => std::slice::from_ref(c),
+            Part::Loop(l) => &l.body[..],
+        })
+        .filter_map(|c| c.namespace.as_ref())
+        .collect()
+}
+
+/// The provider to look up the parts of `hdl` with. Chips from a library
+/// search the library first. Chips that use qualified names also search the
+/// libraries of their project, and chips that import directories with `USE`
+/// also search those, along with any directories already on the provider's
+/// search path.
+pub fn search_path(
+    hdl: &ChipHDL,
+    provider: &Arc<dyn HdlProvider>,
+) -> Result<Arc<dyn HdlProvider>, Box<dyn Error>> {
+    let mut provider = provider.clone();
+    // `get_hdl` names chips from a library by their qualified name.
+    if let Some(library) = hdl
+        .name
+        .split_once('.')
+        .and_then(|(namespace, _)| provider.library(namespace))
+    {
+        provider = library;
+    }
+    let dir = hdl
+        .path
+        .as_ref()
+        .and_then(|p| p.parent())
+        .map(PathBuf::from)
+        .unwrap_or_default();
+
+    let missing: Vec<&Identifier> = namespaces(hdl)
+        .into_iter()
+        .filter(|n| provider.library(&n.value).is_none())
+        .collect();
+    if let Some(namespace) = missing.first() {
+        let project = Project::find(provider.clone(), &dir)?;
+        match project {
+            Some(p) if missing.iter().all(|n| p.libraries.contains_key(&n.value)) => {
+                provider = Arc::new(p);
+            }
+            _ => {
+                return Err(Box::new(N2VError {
+                    msg: format!(
+                        "Chip {} uses library {}, but no {} in its directory or above configures it.",
+                        hdl.name, namespace.value, PROJECT_FILE
+                    ),
+                    kind: ErrorKind::ParseIdentError(provider.clone(), (*namespace).clone()),
+                }));
+            }
+        }
+    }
+
+    if hdl.imports.is_empty() {
+        return Ok(provider);
+    }
+    let mut roots = Vec::new();
+    for import in &hdl.imports {
+        let root = dir.join(&import.value);
+        if !root.is_dir() {
+            return Err(Box::new(N2VError {
+                msg: format!(
+                    "Chip {} uses {}, which is not a directory.",
+                    hdl.name,
+                    root.display()
+                ),
+                kind: ErrorKind::ParseIdentError(provider.clone(), import.clone()),
+            }));
+        }
+        roots.push(root);
+    }
+    Ok(Arc::new(SearchPath::new(provider, roots)))
+}
+
+/// Name of the file that declares the constants shared by the chips in a
+/// directory.
+pub const DEFS_FILE: &str = "defs.hdl";
+
+// Variables in the widths of a chip that are neither its generics nor the
+// iterator of the loop they are in.
+#[derive(Default)]
+struct FreeVariables {
+    bound: Vec<String>,
+    free: Vec<Identifier>,
+}
+
+impl Visitor for FreeVariables {
+    fn visit_chip(&mut self, chip: &ChipHDL) {
+        self.bound = chip.generic_decls.iter().map(|g| g.value.clone()).collect();
+        walk_chip_children(self, chip);
+    }
+
+    // The bounds of a loop are outside its iterator's scope.
+    fn visit_loop(&mut self, lp: &Loop) {
+        self.visit_width(&lp.start);
+        self.visit_width(&lp.end);
+        self.bound.push(lp.iterator.value.clone());
+        for c in &lp.body {
+            self.visit_component(c);
+        }
+        self.bound.pop();
+    }
+
+    fn visit_width(&mut self, width: &GenericWidth) {
+        match width {
+            GenericWidth::Terminal(Terminal::Var(v)) => {
+                if !self.bound.contains(&v.value) {
+                    self.free.push(v.clone());
+                }
+            }
+            _ => walk_width(self, width),
+        }
+    }
+}
+
+fn free_variables(hdl: &ChipHDL) -> Vec<Identifier> {
+    let mut v = FreeVariables::default();
+    walk_chip(&mut v, hdl);
+    v.free
+}
+
+// Evaluates constants in the order they are declared into `values`, where
+// they replace any of the same name.
+fn define(
+    constants: &[Constant],
+    values: &mut HashMap<String, usize>,
+    provider: &Arc<dyn HdlProvider>,
+) -> Result<(), Box<dyn Error>> {
+    let mut declared = Vec::new();
+    for c in constants {
+        if declared.contains(&&c.name.value) {
+            return Err(Box::new(N2VError {
+                msg: format!("Constant {} is declared twice.", c.name.value),
+                kind: ErrorKind::ParseIdentError(provider.clone(), c.name.clone()),
+            }));
+        }
+        declared.push(&c.name.value);
+        let value = eval_expr_numeric(&c.value, values).map_err(|e| {
+            let names: Vec<String> = values.keys().cloned().collect();
+            let v = c.value.variables();
+            let msg = match v.iter().find(|v| !values.contains_key(*v)) {
+                Some(missing) => format!(
+                    "Constant {} uses `{}`, which is not a constant declared before it.{}",
+                    c.name.value,
+                    missing,
+                    did_you_mean(missing, &names)
+                ),
+                None => format!("Constant {}: {}", c.name.value, e.msg),
+            };
+            N2VError {
+                msg,
+                kind: ErrorKind::ParseIdentError(provider.clone(), c.name.clone()),
+            }
+        })?;
+        values.insert(c.name.value.clone(), value);
+    }
+    Ok(())
+}
+
+// Replaces variables with their values in every width of a chip. Generics
+// and loop iterators hide constants of the same name.
+fn substitute(hdl: &mut ChipHDL, values: &HashMap<String, usize>) {
+    let mut values: HashMap<String, GenericWidth> = values
+        .iter()
+        .map(|(k, v)| (k.clone(), GenericWidth::from(*v)))
+        .collect();
+    for g in &hdl.generic_decls {
+        values.remove(&g.value);
+    }
+    for port in &mut hdl.ports {
+        port.width = eval_expr(&port.width, &values);
+    }
+    for part in &mut hdl.parts {
+        match part {
+            Part::Component(c) => substitute_component(c, &values),
+            Part::Loop(l) => {
+                l.start = eval_expr(&l.start, &values);
+                l.end = eval_expr(&l.end, &values);
+                let mut values = values.clone();
+                values.remove(&l.iterator.value);
+                for c in &mut l.body {
+                    substitute_component(c, &values);
+                }
+            }
+        }
+    }
+}
+
+fn substitute_component(c: &mut Component, values: &HashMap<String, GenericWidth>) {
+    for g in &mut c.generic_params {
+        *g = g.map_width(|w| eval_expr(w, values));
+    }
+    for m in &mut c.mappings {
+        for bus in [&mut m.port, &mut m.wire] {
+            bus.start = bus.start.as_ref().map(|w| eval_expr(w, values));
+            bus.end = bus.end.as_ref().map(|w| eval_expr(w, values));
+            // A range such as `[BUSW-1..0]` is only known to run high to low
+            // once its bounds are numbers.
+            let numeric = |w: &Option<GenericWidth>| match w {
+                Some(GenericWidth::Terminal(Terminal::Num(n))) => Some(*n),
+                _ => None,
+            };
+            if let (Some(s), Some(e)) = (numeric(&bus.start), numeric(&bus.end)) {
+                if s > e {
+                    std::mem::swap(&mut bus.start, &mut bus.end);
+                    bus.descending = !bus.descending;
+                }
+            }
+        }
+    }
+}
+
+/// Replaces the names of constants in a chip with their values. A chip sees
+/// the constants declared in its file and those in the `defs.hdl` of its
+/// directory, where constants of its file take precedence. A name that is
+/// not a constant, a generic, or a loop iterator is an error.
+pub fn resolve_constants(
+    hdl: &mut ChipHDL,
+    provider: &Arc<dyn HdlProvider>,
+) -> Result<(), Box<dyn Error>> {
+    let free = free_variables(hdl);
+    if free.is_empty() {
+        return Ok(());
+    }
+    let mut values = HashMap::new();
+    let declared = |v: &Identifier| hdl.constants.iter().any(|c| c.name.value == v.value);
+    if !free.iter().all(declared) {
+        if let Ok(source) = provider.get_hdl(DEFS_FILE) {
+            let mut scanner = Scanner::new(&source, provider.get_path(DEFS_FILE));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            define(&parser.definitions()?, &mut values, provider)?;
+        }
+    }
+    define(&hdl.constants, &mut values, provider)?;
+    substitute(hdl, &values);
+
+    if let Some(v) = free_variables(hdl).into_iter().next() {
+        let mut names: Vec<String> = values.into_keys().collect();
+        names.extend(hdl.generic_decls.iter().map(|g| g.value.clone()));
+        return Err(Box::new(N2VError {
+            msg: format!(
+                "`{}` is not a constant or a generic of chip {}.{}",
+                v.value,
+                hdl.name,
+                did_you_mean(&v.value, &names)
+            ),
+            kind: ErrorKind::ParseIdentError(provider.clone(), v),
+        }));
+    }
+    Ok(())
+}
+
+/// The chip with its constants resolved by `resolve_constants`, borrowed
+/// when there is nothing to resolve.
+pub fn with_constants<'a>(
+    hdl: &'a ChipHDL,
+    provider: &Arc<dyn HdlProvider>,
+) -> Result<Cow<'a, ChipHDL>, Box<dyn Error>> {
+    if free_variables(hdl).is_empty() {
+        return Ok(Cow::Borrowed(hdl));
+    }
+    let mut hdl = hdl.clone();
+    resolve_constants(&mut hdl, provider)?;
+    Ok(Cow::Owned(hdl))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identifier {
+    pub value: String,
+    pub path: Option<PathBuf>, // Set to None if chip not read from disk, e.g. NAND and DFF.
+    pub line: Option<u32>,
+    pub span: Option<Span>,
+}
+
+impl From<Token> for Identifier {
+    fn from(t: Token) -> Self {
+        if t.token_type != TokenType::Identifier {
+            panic!("Attempt to create Identifier from non-identifier token");
+        }
+
+        Identifier {
+            span: Some(t.span()),
+            value: t.lexeme,
+            path: Some(t.path),
+            line: Some(t.line),
+        }
+    }
+}
+
+impl From<&str> for Identifier {
+    fn from(t: &str) -> Self {
+        Identifier {
+            value: String::from(t),
+            path: None,
+            line: None,
+            span: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PortDirection {
+    In,
+    Out,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct GenericPort {
+    pub name: Identifier,
+    pub width: GenericWidth,
+    pub direction: PortDirection,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Component {
+    pub name: Identifier,
+    pub namespace: Option<Identifier>, // Library of a qualified name, e.g. `std` in `std.Mux16`.
+    pub mappings: Vec<PortMapping>,
+    pub generic_params: Vec<GenericParam>,
+    pub annotations: Vec<Annotation>,
+    pub comments: Comments,
+    pub expression: Option<Assignment>, // Set on the Nand parts an expression part expands into.
+}
+
+/// An expression part such as `out = a AND (b OR NOT c);`. Operands are
+/// single bits. The parser expands it into `Nand` parts, each of which
+/// keeps a copy so that tools can print the expression as written.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Assignment {
+    pub target_ident: Identifier,
+    pub target: BusHDL,
+    pub expr: BoolExpr,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::large_enum_variant)]
+pub enum BoolExpr {
+    Wire(Identifier, BusHDL),
+    Not(Box<BoolExpr>),
+    And(Box<BoolExpr>, Box<BoolExpr>),
+    Xor(Box<BoolExpr>, Box<BoolExpr>),
+    Or(Box<BoolExpr>, Box<BoolExpr>),
+}
+
+impl BoolExpr {
+    // Operators that bind tighter have higher precedence.
+    fn precedence(&self) -> u8 {
+        match self {
+            BoolExpr::Or(..) => 0,
+            BoolExpr::Xor(..) => 1,
+            BoolExpr::And(..) => 2,
+            BoolExpr::Not(_) | BoolExpr::Wire(..) => 3,
+        }
+    }
+}
+
+// Prints an expression with only the parentheses it needs.
+impl std::fmt::Display for BoolExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let operand = |e: &BoolExpr, min: u8| {
+            if e.precedence() < min {
+                format!("({})", e)
+            } else {
+                e.to_string()
+            }
+        };
+        match self {
+            BoolExpr::Wire(_, w) => write!(f, "{}", w),
+            BoolExpr::Not(e) => write!(f, "NOT {}", operand(e, 3)),
+            BoolExpr::And(a, b) => write!(f, "{} AND {}", operand(a, 2), operand(b, 3)),
+            BoolExpr::Xor(a, b) => write!(f, "{} XOR {}", operand(a, 1), operand(b, 2)),
+            BoolExpr::Or(a, b) => write!(f, "{} OR {}", operand(a, 0), operand(b, 1)),
+        }
+    }
+}
+
+impl std::fmt::Display for Assignment {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} = {}", self.target, self.expr)
+    }
+}
+
+impl Assignment {
+    /// The `Nand` parts that compute the expression. Wires between them are
+    /// named after where the target is written, e.g. `expr_4_5_1`, and are
+    /// indexed by `iterator` inside a loop so that every copy has its own.
+    pub fn expand(&self, iterator: Option<&Identifier>) -> Vec<Component> {
+        let mut expansion = Expansion {
+            assignment: self,
+            iterator,
+            parts: Vec::new(),
+        };
+        let target = (self.target_ident.clone(), self.target.clone());
+        expansion.value(&self.expr, Some(target));
+        expansion.parts
+    }
+}
+
+// A wire as the identifier it is reported at and the bus it names.
+type ExprWire = (Identifier, BusHDL);
+
+struct Expansion<'a> {
+    assignment: &'a Assignment,
+    iterator: Option<&'a Identifier>,
+    parts: Vec<Component>,
+}
+
+impl Expansion<'_> {
+    // The wire holding the value of `e`, which drives `out` if given.
+    fn value(&mut self, e: &BoolExpr, out: Option<ExprWire>) -> ExprWire {
+        match e {
+            BoolExpr::Wire(ident, bus) if out.is_none() => (ident.clone(), bus.clone()),
+            BoolExpr::Wire(..) => {
+                let not = self.value(&BoolExpr::Not(Box::new(e.clone())), None);
+                self.nand(&not, &not, out)
+            }
+            BoolExpr::Not(inner) => match inner.as_ref() {
+                BoolExpr::Not(x) => self.value(x, out),
+                BoolExpr::And(a, b) => {
+                    let (a, b) = (self.value(a, None), self.value(b, None));
+                    self.nand(&a, &b, out)
+                }
+                x => {
+                    let x = self.value(x, None);
+                    self.nand(&x, &x, out)
+                }
+            },
+            BoolExpr::And(a, b) => {
+                let (a, b) = (self.value(a, None), self.value(b, None));
+                let n = self.nand(&a, &b, None);
+                self.nand(&n, &n, out)
+            }
+            BoolExpr::Or(a, b) => {
+                let not_a = self.value(&BoolExpr::Not(a.clone()), None);
+                let not_b = self.value(&BoolExpr::Not(b.clone()), None);
+                self.nand(&not_a, &not_b, out)
+            }
+            BoolExpr::Xor(a, b) => {
+                let (a, b) = (self.value(a, None), self.value(b, None));
+                let n = self.nand(&a, &b, None);
+                let (p, q) = (self.nand(&a, &n, None), self.nand(&b, &n, None));
+                self.nand(&p, &q, out)
+            }
+        }
+    }
+
+    // Adds `Nand(a=a, b=b, out=out)`, making a new wire for `out` if none
+    // is given.
+    fn nand(&mut self, a: &ExprWire, b: &ExprWire, out: Option<ExprWire>) -> ExprWire {
+        let target = &self.assignment.target_ident;
+        let out = out.unwrap_or_else(|| {
+            let name = format!(
+                "expr_{}_{}_{}",
+                target.line.unwrap_or(0),
+                target.span.map_or(0, |s| s.start_col),
+                self.parts.len() + 1
+            );
+            let index = self
+                .iterator
+                .map(|i| GenericWidth::Terminal(Terminal::Var(i.clone())));
+            let bus = BusHDL {
+                name,
+                start: index.clone(),
+                end: index,
+                descending: false,
+                stride: None,
+            };
+            (target.clone(), bus)
+        });
+        let mapping = |port: &str, (ident, wire): &ExprWire| PortMapping {
+            wire_ident: ident.clone(),
+            wire: wire.clone(),
+            port: BusHDL {
+                name: String::from(port),
+                start: None,
+                end: None,
+                descending: false,
+                stride: None,
+            },
+            comments: Comments::default(),
+        };
+        self.parts.push(Component {
+            name: Identifier {
+                value: String::from("Nand"),
+                span: None,
+                ..target.clone()
+            },
+            namespace: None,
+            mappings: vec![mapping("a", a), mapping("b", b), mapping("out", &out)],
+            generic_params: Vec::new(),
+            annotations: Vec::new(),
+            comments: Comments::default(),
+            expression: Some(self.assignment.clone()),
+        });
+        out
+    }
+}
+
+impl Component {
+    /// The name to look the chip up by, e.g. `std.Mux16` or `Mux16`.
+    pub fn qualified_name(&self) -> String {
+        match &self.namespace {
+            Some(n) => format!("{}.{}", n.value, self.name.value),
+            None => self.name.value.clone(),
+        }
+    }
+
+    /// Binds the generic arguments to the generics `hdl` declares, in the
+    /// order they are declared. Named arguments come after positional ones.
+    pub fn generic_args<'a>(
+        &'a self,
+        hdl: &'a ChipHDL,
+    ) -> Result<Vec<(&'a Identifier, &'a GenericValue)>, N2VError> {
+        let error = |msg: String| N2VError {
+            msg,
+            kind: ErrorKind::SimulationError(hdl.path.clone()),
+        };
+        if self.generic_params.len() != hdl.generic_decls.len() {
+            return Err(error(format!(
+                "Chip {} declares {} generics but instantiated with {}",
+                hdl.name,
+                hdl.generic_decls.len(),
+                self.generic_params.len()
+            )));
+        }
+        let mut args: Vec<Option<&GenericValue>> = vec![None; hdl.generic_decls.len()];
+        for (i, p) in self.generic_params.iter().enumerate() {
+            let position = match &p.name {
+                None if self.generic_params[..i].iter().any(|p| p.name.is_some()) => {
+                    return Err(error(format!(
+                        "Positional generic {} of {} must come before the named ones.",
+                        i + 1,
+                        hdl.name
+                    )))
+                }
+                None => i,
+                Some(n) => hdl
+                    .generic_decls
+                    .iter()
+                    .position(|d| d.value == n.value)
+                    .ok_or_else(|| {
+                        error(format!(
+                            "Chip {} has no generic named {}.",
+                            hdl.name, n.value
+                        ))
+                    })?,
+            };
+            if args[position].is_some() {
+                return Err(error(format!(
+                    "Generic {} of {} is given twice.",
+                    hdl.generic_decls[position].value, hdl.name
+                )));
+            }
+            args[position] = Some(&p.value);
+        }
+        Ok(hdl
+            .generic_decls
+            .iter()
+            .zip(args.into_iter().flatten())
+            .collect())
+    }
+}
+
+/// An argument for a generic of a part, e.g. `8` or `FILE="boot.hack"`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct GenericParam {
+    pub name: Option<Identifier>,
+    pub value: GenericValue,
+}
+
+impl GenericParam {
+    /// Applies `f` to a width, leaving strings as they are.
+    pub fn map_width(&self, f: impl FnOnce(&GenericWidth) -> GenericWidth) -> GenericParam {
+        GenericParam {
+            name: self.name.clone(),
+            value: self.value.map_width(f),
+        }
+    }
+}
+
+// Prints the argument the way it is written in HDL.
+impl std::fmt::Display for GenericParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(n) = &self.name {
+            write!(f, "{}=", n.value)?;
+        }
+        match &self.value {
+            GenericValue::Width(w) => write!(f, "{}", hdl_expr(w)),
+            GenericValue::Str(s) => write!(f, "\"{}\"", s),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Loop {
+    pub start: GenericWidth,
+    pub end: GenericWidth,
+    pub iterator: Identifier,
+    pub body: Vec<Component>, // Prevent nested loops.
+    pub comments: Comments,
+    pub array: bool, // Written as `Name[count](...)`, iterating `i` from 0.
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct BusHDL {
+    pub name: String,
+    pub start: Option<GenericWidth>,
+    pub end: Option<GenericWidth>,
+    pub descending: bool, // Written high to low, e.g. `in[7..0]`. start <= end regardless.
+    pub stride: Option<usize>, // Every k-th bit of the range, e.g. `in[0..14:2]`.
+}
+
+//  Not(in=sel, out=notSel); has two wires { name : "sel", port: "in" }, { name : "notSel", port: "out" }
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PortMapping {
+    pub wire_ident: Identifier,
+    pub wire: BusHDL,
+    pub port: BusHDL,
+    pub comments: Comments,
+}
+
+impl PortMapping {
+    /// Whether the port is deliberately left unconnected, e.g. `h=open`.
+    pub fn is_open(&self) -> bool {
+        self.wire.name == "open"
+    }
+}
+
+/// Looks up chip definition for a chip.
+/// name is the name of the chip, not including .hdl extension
+/// provider is responsible for retrieving the HDL file (provider will have its own base path)
+pub fn get_hdl(name: &str, provider: &Arc<dyn HdlProvider>) -> Result<ChipHDL, WhidlError> {
+    if let Some((namespace, short_name)) = name.split_once('.') {
+        let library = provider.library(namespace).ok_or_else(|| N2VError {
+            msg: format!(
+                "Unable to get HDL for {}. No library {} is configured in {}.",
+                name, namespace, PROJECT_FILE
+            ),
+            kind: ErrorKind::IOError,
+        })?;
+        return get_hdl(short_name, &library);
+    }
+    if name.to_lowercase() == "nand" {
+        // Hard-coded NAND chip
+        return Ok(ChipHDL {
+            name: String::from("NAND"),
+            interface: None,
+            imports: Vec::new(),
+            constants: Vec::new(),
+            ports: vec![
+                GenericPort {
+                    name: Identifier::from("a"),
+                    width: GenericWidth::Terminal(Terminal::Num(1)),
+                    direction: PortDirection::In,
+                },
+                GenericPort {
+                    name: Identifier::from("b"),
+                    width: GenericWidth::Terminal(Terminal::Num(1)),
+                    direction: PortDirection::In,
+                },
+                GenericPort {
+                    name: Identifier::from("out"),
+                    width: GenericWidth::Terminal(Terminal::Num(1)),
+                    direction: PortDirection::Out,
+                },
+            ],
+            parts: Vec::new(),
+            path: None,
+            generic_decls: Vec::new(),
+            clocked: Vec::new(),
+            builtin: None,
+            stimulus: None,
+            table: None,
+            annotations: Vec::new(),
+            comments: Comments::default(),
+            body_comments: Vec::new(),
+            shadows: None,
+        });
+    } else if name.to_lowercase() == "dff" {
+        // Hard-coded NAND chip
+        return Ok(ChipHDL {
+            name: String::from("DFF"),
+            interface: None,
+            imports: Vec::new(),
+            constants: Vec::new(),
+            ports: vec![
+                GenericPort {
+                    name: Identifier::from("in"),
+                    width: GenericWidth::Terminal(Terminal::Num(1)),
+                    direction: PortDirection::In,
+                },
+                GenericPort {
+                    name: Identifier::from("out"),
+                    width: GenericWidth::Terminal(Terminal::Num(1)),
+                    direction: PortDirection::Out,
+                },
+            ],
+            parts: Vec::new(),
+            path: None,
+            generic_decls: Vec::new(),
+            clocked: vec![Identifier::from("in")],
+            builtin: None,
+            stimulus: None,
+            table: None,
+            annotations: Vec::new(),
+            comments: Comments::default(),
+            body_comments: Vec::new(),
+            shadows: None,
+        });
+    }
+
+    let filename = String::from(name) + ".hdl";
+    let path = PathBuf::from(filename);
+
+    // Chips in the directory take precedence over the monitor library.
+    let (contents, from_file) = match (provider.get_hdl(path.to_str().unwrap()), library_hdl(name))
+    {
+        (Ok(x), _) => (x, true),
+        (Err(_), Some(x)) => (String::from(x), false),
+        (Err(e), None) => {
+            let mut names = provider.chip_names();
+            names.extend([String::from("Nand"), String::from("DFF")]);
+            names.extend(LIBRARY_CHIPS.map(String::from));
+            return Err(WhidlError::Io(N2VError {
+                msg: format!("{}{}", e, did_you_mean(name, &names)),
+                kind: ErrorKind::IOError,
+            }));
+        }
+    };
+    // The full path lets the chip find the directories it imports.
+    let used = provider.get_path(path.to_str().unwrap());
+    // The last layer of a `ChainedProvider` may read the library itself.
+    let library = library_hdl(name);
+    let shadows = if from_file && library != Some(contents.as_str()) {
+        check_shadowing(name, &used, provider, library.is_some())
+    } else {
+        None
+    };
+    let mut hdl = parse_cached(&contents, used)?;
+    hdl.shadows = shadows;
+    if hdl.interface.is_some() {
+        hdl = with_implementation(&hdl, provider)?.into_owned();
+    }
+    resolve_constants(&mut hdl, provider)?;
+    // Chips from a library are named by their qualified name, which keeps
+    // them apart from other chips of the same short name.
+    if let Some(namespace) = provider.namespace() {
+        hdl.name = format!("{}.{}", namespace, hdl.name);
+    }
+    Ok(hdl)
+}
+
+thread_local! {
+    // Shadowing found by `check_shadowing`, by the provider that found the
+    // file used and its path. Each file is checked once per provider, so a
+    // new provider, such as one for another `--lib-path`, checks again.
+    // Entries go once their provider does.
+    static SHADOWING: RefCell<Vec<(Weak<dyn HdlProvider>, Shadowing)>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+// What shadows each file a provider found, by path.
+type Shadowing = HashMap<PathBuf, Option<String>>;
+
+// What `SHADOWING` knows of `used` from `provider`.
+fn known_shadowing(provider: &Arc<dyn HdlProvider>, used: &Path) -> Option<Option<String>> {
+    SHADOWING.with(|s| {
+        let mut s = s.borrow_mut();
+        s.retain(|(p, _)| p.strong_count() > 0);
+        s.iter()
+            .find(|(p, _)| std::ptr::addr_eq(p.as_ptr(), Arc::as_ptr(provider)))
+            .and_then(|(_, found)| found.get(used).cloned())
+    })
+}
+
+fn remember_shadowing(provider: &Arc<dyn HdlProvider>, used: &Path, msg: Option<String>) {
+    SHADOWING.with(|s| {
+        let mut s = s.borrow_mut();
+        let i = match s
+            .iter()
+            .position(|(p, _)| std::ptr::addr_eq(p.as_ptr(), Arc::as_ptr(provider)))
+        {
+            Some(i) => i,
+            None => {
+                s.push((Arc::downgrade(provider), HashMap::new()));
+                s.len() - 1
+            }
+        };
+        s[i].1.insert(used.to_path_buf(), msg);
+    })
+}
+
+// What a chip that resolves to one file shadows: other directories on the
+// search path, or the built-in library, with a chip of the same name. The
+// chips that use it warn, or fail, as the shadowed-chip lint is set for
+// them.
+fn check_shadowing(
+    name: &str,
+    used: &Path,
+    provider: &Arc<dyn HdlProvider>,
+    in_library: bool,
+) -> Option<String> {
+    known_shadowing(provider, used).unwrap_or_else(|| {
+        let msg = shadowing(name, used, provider, in_library);
+        remember_shadowing(provider, used, msg.clone());
+        msg
+    })
+}
+
+// Describes the chips `used` shadows, if any.
+fn shadowing(
+    name: &str,
+    used: &Path,
+    provider: &Arc<dyn HdlProvider>,
+    in_library: bool,
+) -> Option<String> {
+    let mut shadowed: Vec<String> = provider
+        .candidates(&format!("{}.hdl", name))
+        .iter()
+        .filter(|p| p.as_path() != used)
+        .map(|p| p.display().to_string())
+        .collect();
+    if in_library {
+        shadowed.push(format!("the built-in {}", name));
+    }
+    (!shadowed.is_empty()).then(|| {
+        format!(
+            "Chip {} is defined in more than one place. Using {}, which shadows {}.",
+            name,
+            used.display(),
+            shadowed.join(" and ")
+        )
+    })
+}
+
+thread_local! {
+    // Chips parsed by `get_hdl`, by path, with a hash of the source each was
+    // parsed from. Parts used many times are parsed once, and a file that
+    // changes is parsed again and replaces what was parsed before.
+    static PARSED: RefCell<HashMap<PathBuf, (u64, ChipHDL)>> = RefCell::new(HashMap::new());
+}
+
+fn parse_cached(source: &str, path: PathBuf) -> Result<ChipHDL, Box<dyn Error>> {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    let hash = hasher.finish();
+    let cached = PARSED.with(|p| {
+        p.borrow()
+            .get(&path)
+            .filter(|(h, _)| *h == hash)
+            .map(|(_, hdl)| hdl.clone())
+    });
+    if let Some(hdl) = cached {
+        return Ok(hdl);
+    }
+    let mut scanner = Scanner::new(source, path.clone());
+    let mut parser = Parser {
+        scanner: &mut scanner,
+    };
+    let hdl = parser.parse()?;
+    PARSED.with(|p| p.borrow_mut().insert(path, (hash, hdl.clone())));
+    Ok(hdl)
+}
+
+/// A hash of a chip and of every chip it uses, down to `Nand` and `DFF`.
+/// Editing any of them, or the constants they see, changes it, so work
+/// derived from the chip can be reused while the hash stays the same.
+pub fn dependency_hash(name: &str, provider: &Arc<dyn HdlProvider>) -> Result<u64, Box<dyn Error>> {
+    dependency_hash_of(name, provider, &mut HashMap::new())
+}
+
+// `hashes` holds the chips hashed so far. A chip that uses itself sees the
+// placeholder 0 for itself.
+fn dependency_hash_of(
+    name: &str,
+    provider: &Arc<dyn HdlProvider>,
+    hashes: &mut HashMap<String, u64>,
+) -> Result<u64, Box<dyn Error>> {
+    if let Some(&h) = hashes.get(name) {
+        return Ok(h);
+    }
+    hashes.insert(String::from(name), 0);
+    let hdl = get_hdl(name, provider)?;
+    let provider = search_path(&hdl, provider)?;
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(&hdl)?.hash(&mut hasher);
+    let mut parts: Vec<String> = hdl
+        .parts
+        .iter()
+        .flat_map(|p| match p {
+            Part::Component(c) => std::slice::from_ref(c),
+            Part::Loop(l) => &l.body[..],
+        })
+        .map(|c| c.qualified_name())
+        .collect();
+    parts.sort();
+    parts.dedup();
+    for part in parts {
+        dependency_hash_of(&part, &provider, hashes)?.hash(&mut hasher);
+    }
+    let h = hasher.finish();
+    hashes.insert(String::from(name), h);
+    Ok(h)
+}
+
+/// Suggests the candidates closest to a misspelled name, e.g.
+/// ` Did you mean `Mux16`?`. Returns an empty string if none are close.
+pub fn did_you_mean(name: &str, candidates: &[String]) -> String {
+    // Allow roughly one typo for every three characters.
+    let max_distance = (name.chars().count() / 3).max(1);
+    let mut close: Vec<(usize, &String)> = candidates
+        .iter()
+        .map(|c| (edit_distance(&name.to_lowercase(), &c.to_lowercase()), c))
+        .filter(|(d, _)| *d <= max_distance)
+        .collect();
+    close.sort();
+    close.dedup_by(|a, b| a.1 == b.1);
+
+    // Only the closest candidates are worth suggesting.
+    let best = close.first().map(|(d, _)| *d);
+    let names: Vec<String> = close
+        .iter()
+        .filter(|(d, _)| Some(*d) == best)
+        .take(3)
+        .map(|(_, c)| format!("`{}`", c))
+        .collect();
+    match names.len() {
+        0 => String::new(),
+        1 => format!(" Did you mean {}?", names[0]),
+        n => format!(
+            " Did you mean {} or {}?",
+            names[..n - 1].join(", "),
+            names[n - 1]
+        ),
+    }
+}
+
+// Edit distance between two strings, counted in characters. Swapping two
+// adjacent characters counts as one edit, since it is a common typo.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+// Converts a number token to its value. Numbers may be decimal (16),
+// hexadecimal (0x10), or binary (0b10000).
+pub fn parse_number(t: &Token) -> Result<usize, Box<dyn Error>> {
+    let lexeme = t.lexeme.to_lowercase();
+    let parsed = if let Some(hex) = lexeme.strip_prefix("0x") {
+        usize::from_str_radix(&hex.replace('_', ""), 16)
+    } else if let Some(bin) = lexeme.strip_prefix("0b") {
+        usize::from_str_radix(&bin.replace('_', ""), 2)
+    } else {
+        lexeme.parse::<usize>()
+    };
+
+    parsed.map_err(|_| {
+        Box::new(N2VError {
+            msg: format!("`{}` is not a valid number.", t.lexeme),
+            kind: ErrorKind::ParseError(t.clone()),
+        }) as Box<dyn Error>
+    })
+}
+
+// Tokens that start a section of the chip declaration. Parsing resumes at
+// these after an error in the declaration.
+const SECTIONS: &[TokenType] = &[
+    TokenType::In,
+    TokenType::Out,
+    TokenType::Clocked,
+    TokenType::Builtin,
+    TokenType::Stimulus,
+    TokenType::Table,
+    TokenType::Parts,
+];
+
+/// The language chips are written in. The official nand2tetris tools do
+/// not accept whidl's extensions, such as generics and loops.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dialect {
+    Nand2Tetris,
+    #[default]
+    Whidl,
+}
+
+impl std::str::FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nand2tetris" => Ok(Dialect::Nand2Tetris),
+            "whidl" => Ok(Dialect::Whidl),
+            _ => Err(format!(
+                "`{}` is not a dialect. Use `nand2tetris` or `whidl`.",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ParserOptions {
+    pub dialect: Dialect,
+}
+
+/// Reports every whidl extension a chip uses, if it must be written in the
+/// nand2tetris dialect.
+pub fn dialect_errors(hdl: &ChipHDL, dialect: Dialect) -> Vec<N2VError> {
+    if dialect == Dialect::Whidl {
+        return Vec::new();
+    }
+    let mut uses: Vec<(&'static str, &Identifier)> = Vec::new();
+    uses.extend(hdl.interface.iter().map(|i| ("interfaces", i)));
+    uses.extend(hdl.imports.iter().map(|i| ("`USE` imports", i)));
+    uses.extend(hdl.constants.iter().map(|c| ("constants", &c.name)));
+    uses.extend(hdl.annotations.iter().map(|a| ("annotations", &a.name)));
+    uses.extend(hdl.generic_decls.first().map(|g| ("generics", g)));
+    if let Some(s) = &hdl.stimulus {
+        uses.extend(s.signals.first().map(|s| ("`STIMULUS` blocks", &s.name)));
+    }
+    if let Some(t) = &hdl.table {
+        uses.push(("`TABLE` blocks", &t.keyword));
+    }
+    for part in &hdl.parts {
+        match part {
+            Part::Component(c) => uses.extend(component_extensions(c)),
+            Part::Loop(l) if l.array => {
+                uses.push(("arrays of parts", &l.body[0].name));
+                uses.extend(l.body.iter().flat_map(component_extensions));
+            }
+            Part::Loop(l) => {
+                uses.push(("`for` loops", &l.iterator));
+                uses.extend(l.body.iter().flat_map(component_extensions));
+            }
+        }
+    }
+
+    // The parts an expression expands into are reported once.
+    uses.dedup();
+    uses.into_iter()
+        .map(|(feature, ident)| {
+            let msg = format!(
+                "nand2tetris does not support {}, which are a whidl extension.",
+                feature
+            );
+            match (&ident.path, ident.span) {
+                (Some(path), Some(span)) => N2VError {
+                    msg,
+                    kind: ErrorKind::ParseError(Token {
+                        token_type: TokenType::Identifier,
+                        lexeme: ident.value.clone(),
+                        line: span.start_line,
+                        start: span.end_col.saturating_sub(1) as usize,
+                        path: path.clone(),
+                    }),
+                },
+                _ => N2VError {
+                    msg,
+                    kind: ErrorKind::ElaborationError,
+                },
+            }
+        })
+        .collect()
+}
+
+// Extensions used by a part, and where.
+fn component_extensions(c: &Component) -> Vec<(&'static str, &Identifier)> {
+    let mut uses = Vec::new();
+    uses.extend(c.annotations.iter().map(|a| ("annotations", &a.name)));
+    if let Some(n) = &c.namespace {
+        uses.push(("libraries", n));
+    }
+    if !c.generic_params.is_empty() {
+        uses.push(("generics", &c.name));
+    }
+    if let Some(a) = &c.expression {
+        uses.push(("expression parts", &a.target_ident));
+    }
+    uses
+}
+
+pub struct Parser<'a, 'b> {
+    pub scanner: &'a mut Scanner<'b>,
+}
+
+impl<'a, 'b> Parser<'a, 'b> {
+    pub fn parse(&mut self) -> Result<ChipHDL, WhidlError> {
+        match self.parse_recovering() {
+            (Some(chip), errors) if errors.is_empty() => Ok(chip),
+            (_, errors) => Err(WhidlError::from(errors.into_iter().next().unwrap())),
+        }
+    }
+
+    /// Parses a chip written in the dialect `options` ask for.
+    pub fn parse_with(&mut self, options: &ParserOptions) -> Result<ChipHDL, Box<dyn Error>> {
+        let hdl = self.parse()?;
+        match dialect_errors(&hdl, options.dialect).into_iter().next() {
+            Some(e) => Err(Box::new(e)),
+            None => Ok(hdl),
+        }
+    }
+
+    /// Parses a chip without stopping at the first syntax error. After an
+    /// error the parser skips to the next semicolon or closing brace and
+    /// carries on, so every error in the file is reported in one pass.
+    /// The chip is `None` if the file does not get as far as the chip's
+    /// name, otherwise it holds everything that could be parsed.
+    pub fn parse_recovering(&mut self) -> (Option<ChipHDL>, Vec<N2VError>) {
+        let _span = debug_span!("parse", path = %self.scanner.path.display()).entered();
+        let mut errors = Vec::new();
+        let chip = self.chip(&mut errors);
+        debug!(errors = errors.len(), "parsed");
+        (chip, errors)
+    }
+
+    // Records an error and skips to where parsing can resume: just past
+    // the next semicolon, or at the next closing brace or `stop` token,
+    // which are left for the caller.
+    fn recover(&mut self, e: Box<dyn Error>, errors: &mut Vec<N2VError>, stop: &[TokenType]) {
+        let e = match e.downcast::<N2VError>() {
+            Ok(e) => *e,
+            Err(e) => N2VError {
+                msg: e.to_string(),
+                kind: ErrorKind::Other,
+            },
+        };
+
+        // The token that caused the error may have been consumed. Give it
+        // back if it is where parsing should resume.
+        if let ErrorKind::ParseError(t) = &e.kind {
+            if matches!(t.token_type, TokenType::Semicolon | TokenType::RightCurly)
+                || stop.contains(&t.token_type)
+            {
+                self.scanner.push_back(t.clone());
+            }
+        }
+
+        while let Some(t) = self.peek_token() {
+            if t.token_type == TokenType::RightCurly || stop.contains(&t.token_type) {
+                break;
+            }
+            self.next_token();
+            if t.token_type == TokenType::Semicolon {
+                break;
+            }
+        }
+        errors.push(e);
+    }
+
+    // Consumes a token of type `tt` if it is next, otherwise records an
+    // error and leaves the token for the caller.
+    fn expect(&mut self, tt: TokenType, errors: &mut Vec<N2VError>) -> bool {
+        if self.peek_token().map(|t| t.token_type) == Some(tt) {
+            self.next_token();
+            return true;
+        }
+        if let Err(e) = self.consume(tt) {
+            self.recover(e, errors, SECTIONS);
+        }
+        false
+    }
+
+    // The next token. Text that is not a token comes back as an `Invalid`
+    // token, which callers report like any other unexpected token.
+    fn next_token(&mut self) -> Option<Token> {
+        self.scanner.next().map(|t| t.unwrap_or_else(|e| e.token))
+    }
+
+    fn peek_token(&mut self) -> Option<Token> {
+        self.scanner.peek().map(|t| match t {
+            Ok(t) => t.clone(),
+            Err(e) => e.token.clone(),
+        })
+    }
+
+    fn consume(&mut self, tt: TokenType) -> Result<Token, Box<dyn Error>> {
+        let t = match self.scanner.next() {
+            Some(Err(e)) => return Err(Box::new(N2VError::from(e))),
+            t => t.map(Result::unwrap),
+        };
+        match &t {
+            None => Err(Box::new(N2VError {
+                msg: format!("Early end of file, expected {}", tt),
+                kind: ErrorKind::ParseError(Token {
+                    lexeme: String::from(""),
+                    path: self.scanner.path.clone(),
+                    line: self.scanner.line,
+                    start: self.scanner.col,
+                    token_type: TokenType::Eof,
+                }),
+            })),
+            Some(t) => {
+                if t.token_type == tt {
+                    Ok(t.clone())
+                } else {
+                    Err(Box::new(N2VError {
+                        msg: format!(
+                            "I did not expect to see `{}`. I expected to see {}",
+                            t.lexeme, tt
+                        ),
+                        kind: ErrorKind::ParseError(t.clone()),
+                    }))
+                }
+            }
+        }
+    }
+
+    // Comments between the last node and the next token.
+    fn leading_comments(&mut self) -> Vec<Comment> {
+        self.scanner.peek();
+        self.scanner.take_comments()
+    }
+
+    // Comments after a node that ends on `line`. Later comments are left
+    // for the next node.
+    fn trailing_comments(&mut self, line: u32) -> Vec<Comment> {
+        self.scanner.peek();
+        let (trailing, rest) = self
+            .scanner
+            .take_comments()
+            .into_iter()
+            .partition(|c| c.line == line);
+        self.scanner.comments = rest;
+        trailing
+    }
+
+    fn chip(&mut self, errors: &mut Vec<N2VError>) -> Option<ChipHDL> {
+        let imports = self.imports(errors);
+        let constants = self.constants(errors);
+        let annotations = self.annotations().unwrap_or_else(|e| {
+            self.recover(e, errors, &[TokenType::Chip, TokenType::Interface]);
+            Vec::new()
+        });
+        // TODO: Print location information for token.
+        let interface = match self.peek_token() {
+            Some(t) if t.token_type == TokenType::Interface => {
+                self.next_token();
+                Some(Identifier {
+                    span: Some(t.span()),
+                    value: t.lexeme,
+                    path: Some(t.path),
+                    line: Some(t.line),
+                })
+            }
+            _ => {
+                if let Err(e) = self.consume(TokenType::Chip) {
+                    self.recover(e, errors, &[]);
+                    return None;
+                }
+                None
+            }
+        };
+        let leading = self.leading_comments();
+        let chip_name = match self.consume(TokenType::Identifier) {
+            Ok(t) => t,
+            Err(e) => {
+                self.recover(e, errors, &[]);
+                return None;
+            }
+        };
+
+        let generics = self.generic_decls().unwrap_or_else(|e| {
+            self.recover(e, errors, &[TokenType::LeftCurly]);
+            Vec::new()
+        });
+
+        self.expect(TokenType::LeftCurly, errors);
+
+        // IN and OUT may come in either order, and a chip may leave one of
+        // them out, e.g. a constant generator has no inputs.
+        let mut ports = Vec::new();
+        let mut sections = Vec::new();
+        while let Some(t) = self
+            .peek_token()
+            .filter(|t| matches!(t.token_type, TokenType::In | TokenType::Out))
+        {
+            self.next_token();
+            let direction = if t.token_type == TokenType::In {
+                PortDirection::In
+            } else {
+                PortDirection::Out
+            };
+            if sections.contains(&direction) {
+                errors.push(N2VError {
+                    msg: format!("A chip can only have one {} section.", t.token_type),
+                    kind: ErrorKind::ParseError(t.clone()),
+                });
+            }
+            sections.push(direction);
+            match self.port_names(direction) {
+                Ok(mut p) => ports.append(&mut p),
+                Err(e) => self.recover(e, errors, SECTIONS),
+            }
+        }
+        // A testbench may drive all of its signals itself.
+        let stimulus_next = self.peek_token().map(|t| t.token_type) == Some(TokenType::Stimulus);
+        if sections.is_empty() && !stimulus_next {
+            let e = match self.next_token() {
+                Some(t) => N2VError {
+                    msg: format!(
+                        "I did not expect to see `{}`. I expected to see {} or {}",
+                        t.lexeme,
+                        TokenType::In,
+                        TokenType::Out
+                    ),
+                    kind: ErrorKind::ParseError(t),
+                },
+                None => self.end_of_file("Unexpected end of file. Expected IN or OUT."),
+            };
+            self.recover(Box::new(e), errors, SECTIONS);
+        }
+
+        let mut clocked = self.clocked_names(&ports).unwrap_or_else(|e| {
+            self.recover(e, errors, SECTIONS);
+            Vec::new()
+        });
+
+        // nand2tetris puts CLOCKED after BUILTIN, so accept it in either place.
+        let builtin = self.builtin_name().unwrap_or_else(|e| {
+            self.recover(e, errors, SECTIONS);
+            None
+        });
+        if clocked.is_empty() {
+            clocked = self.clocked_names(&ports).unwrap_or_else(|e| {
+                self.recover(e, errors, SECTIONS);
+                Vec::new()
+            });
+        }
+
+        let stimulus = self.stimulus().unwrap_or_else(|e| {
+            self.recover(e, errors, SECTIONS);
+            None
+        });
+
+        let table = self.table().unwrap_or_else(|e| {
+            self.recover(e, errors, SECTIONS);
+            None
+        });
+
+        let mut body_comments = self.scanner.take_comments();
+
+        // Builtins and interface stubs may omit their parts entirely, and
+        // a table takes their place.
+        let parts = if table.is_some() {
+            self.expect(TokenType::RightCurly, errors);
+            Vec::new()
+        } else if self.peek_token().map(|t| t.token_type) == Some(TokenType::RightCurly) {
+            self.next_token();
+            Vec::new()
+        } else {
+            if self.expect(TokenType::Parts, errors) {
+                self.expect(TokenType::Colon, errors);
+            }
+            self.parts(errors)
+        };
+        body_comments.append(&mut self.scanner.take_comments());
+
+        let has_body =
+            !parts.is_empty() || builtin.is_some() || table.is_some() || stimulus.is_some();
+        if interface.is_some() && has_body {
+            errors.push(N2VError {
+                msg: format!(
+                    "Interface {} can only declare ports. Its implementations go in files such as {}.fast.hdl.",
+                    chip_name.lexeme, chip_name.lexeme
+                ),
+                kind: ErrorKind::ParseError(chip_name.clone()),
+            });
+        }
+
+        Some(ChipHDL {
+            name: Identifier::from(chip_name).value,
+            interface,
+            imports,
+            constants,
+            ports,
+            parts,
+            path: Some(self.scanner.path.clone()),
+            generic_decls: generics,
+            clocked,
+            builtin,
+            stimulus,
+            table,
+            annotations,
+            comments: Comments {
+                leading,
+                trailing: self.leading_comments(),
+            },
+            body_comments,
+            shadows: None,
+        })
+    }
+
+    // `USE "dir";` statements before the chip.
+    fn imports(&mut self, errors: &mut Vec<N2VError>) -> Vec<Identifier> {
+        let mut imports = Vec::new();
+        while self.peek_token().map(|t| t.token_type) == Some(TokenType::Use) {
+            self.next_token();
+            let t = match self.consume(TokenType::StringLiteral) {
+                Ok(t) => t,
+                Err(e) => {
+                    self.recover(
+                        e,
+                        errors,
+                        &[TokenType::Chip, TokenType::Interface, TokenType::Use],
+                    );
+                    continue;
+                }
+            };
+            imports.push(Identifier {
+                span: Some(t.span()),
+                value: t.lexeme.trim_matches('"').to_string(),
+                path: Some(t.path),
+                line: Some(t.line),
+            });
+            if let Err(e) = self.consume(TokenType::Semicolon) {
+                self.recover(
+                    e,
+                    errors,
+                    &[TokenType::Chip, TokenType::Interface, TokenType::Use],
+                );
+            }
+        }
+        imports
+    }
+
+    // `CONST NAME value;` declarations before the chip.
+    fn constants(&mut self, errors: &mut Vec<N2VError>) -> Vec<Constant> {
+        let mut constants = Vec::new();
+        while self.peek_token().map(|t| t.token_type) == Some(TokenType::Const) {
+            match self.constant() {
+                Ok(c) => constants.push(c),
+                Err(e) => self.recover(
+                    e,
+                    errors,
+                    &[TokenType::Chip, TokenType::Interface, TokenType::Const],
+                ),
+            }
+        }
+        constants
+    }
+
+    fn constant(&mut self) -> Result<Constant, Box<dyn Error>> {
+        self.consume(TokenType::Const)?;
+        let name = Identifier::from(self.consume(TokenType::Identifier)?);
+        let value = self.expr()?;
+        self.consume(TokenType::Semicolon)?;
+        Ok(Constant { name, value })
+    }
+
+    /// Parses a file of constants, such as a project's `defs.hdl`.
+    pub fn definitions(&mut self) -> Result<Vec<Constant>, Box<dyn Error>> {
+        let mut constants = Vec::new();
+        while let Some(t) = self.peek_token() {
+            if t.token_type != TokenType::Const {
+                return Err(Box::new(N2VError {
+                    msg: format!(
+                        "I did not expect to see `{}`. A file of definitions only holds {}",
+                        t.lexeme,
+                        TokenType::Const
+                    ),
+                    kind: ErrorKind::ParseError(t),
+                }));
+            }
+            constants.push(self.constant()?);
+        }
+        Ok(constants)
+    }
+
+    fn generics(&mut self) -> Result<Vec<GenericParam>, Box<dyn Error>> {
+        let mut res: Vec<GenericParam> = Vec::new();
+
+        if self.peek_token().map(|t| t.token_type) != Some(TokenType::LeftAngle) {
+            return Ok(Vec::new());
+        }
+        self.consume(TokenType::LeftAngle)?;
+
+        let mut name = None;
         loop {
-            let next = self.scanner.next();
-            match &next {
+            let next = self.next_token();
+            let value = match &next {
                 Some(
                     t @ Token {
                         token_type: TokenType::Number,
@@ -312,20 +2720,93 @@ impl<'a, 'b> Parser<'a, 'b> {
                     },
                 ) => {
                     // Convert to number.
-                    let val: usize = t.lexeme.parse().unwrap();
-                    res.push(GenericWidth::Terminal(Terminal::Num(val)));
+                    let val = parse_number(t)?;
+                    GenericValue::Width(GenericWidth::Terminal(Terminal::Num(val)))
+                }
+                Some(
+                    t @ Token {
+                        token_type: TokenType::Identifier,
+                        ..
+                    },
+                ) => {
+                    // A named argument such as `FILE="boot.hack"`.
+                    if name.is_none()
+                        && self.peek_token().map(|t| t.token_type) == Some(TokenType::Equal)
+                    {
+                        self.next_token();
+                        name = Some(Identifier::from(t.clone()));
+                        continue;
+                    }
+                    GenericValue::Width(GenericWidth::Terminal(Terminal::Var(Identifier::from(
+                        t.clone(),
+                    ))))
+                }
+                Some(
+                    t @ Token {
+                        token_type: TokenType::StringLiteral,
+                        ..
+                    },
+                ) => GenericValue::Str(t.lexeme.trim_matches('"').to_string()),
+                Some(
+                    t @ Token {
+                        token_type: TokenType::Comma | TokenType::RightAngle,
+                        ..
+                    },
+                ) if name.is_none() => {
+                    if t.token_type == TokenType::RightAngle {
+                        return Ok(res);
+                    }
+                    continue;
+                }
+                Some(t) => {
+                    return Err(Box::new(N2VError {
+                        msg: format!(
+                            "Expected identifier, number, string, comma, or right angle, found {}",
+                            t.token_type
+                        ),
+                        kind: ErrorKind::ParseError(t.clone()),
+                    }));
+                }
+                None => {
+                    return Err(Box::new(N2VError {
+                        msg: String::from(
+                            "Unexpected end of file. Expected number, comma, or right angle.",
+                        ),
+                        kind: ErrorKind::ParseError(Token {
+                            lexeme: String::from(""),
+                            path: self.scanner.path.clone(),
+                            line: self.scanner.line,
+                            start: self.scanner.col,
+                            token_type: TokenType::Eof,
+                        }),
+                    }));
                 }
+            };
+            res.push(GenericParam {
+                name: name.take(),
+                value,
+            });
+        }
+    }
+
+    fn generic_decls(&mut self) -> Result<Vec<Identifier>, Box<dyn Error>> {
+        let mut res = Vec::new();
+
+        if self.peek_token().map(|t| t.token_type) != Some(TokenType::LeftAngle) {
+            return Ok(Vec::new());
+        }
+        self.consume(TokenType::LeftAngle)?;
+
+        loop {
+            let next = self.next_token();
+            match &next {
                 Some(
                     t @ Token {
                         token_type: TokenType::Identifier,
                         ..
                     },
                 ) => {
-                    res.push(GenericWidth::Terminal(Terminal::Var(Identifier {
-                        line: Some(t.line),
-                        path: Some(t.path.clone()),
-                        value: t.lexeme.clone(),
-                    })));
+                    res.push(Identifier::from(t.clone()));
                 }
                 Some(Token {
                     token_type: TokenType::Comma,
@@ -341,20 +2822,69 @@ impl<'a, 'b> Parser<'a, 'b> {
                 }
                 Some(t) => {
                     return Err(Box::new(N2VError {
-                        msg: format!(
-                            "Expected identifier, number, comma, or right angle, found {}",
-                            match &next {
-                                None => String::from("End of file"),
-                                Some(t) => t.token_type.to_string(),
-                            }
+                        msg: String::from("Expected identifier, comma, or right angle"),
+                        kind: ErrorKind::ParseError(t.clone()),
+                    }));
+                }
+                None => {
+                    return Err(Box::new(N2VError {
+                        msg: String::from(
+                            "Unexpected end of file. Expected identifier, comma, or right angle.",
                         ),
+                        kind: ErrorKind::ParseError(Token {
+                            lexeme: String::from(""),
+                            path: self.scanner.path.clone(),
+                            line: self.scanner.line,
+                            start: self.scanner.col,
+                            token_type: TokenType::Eof,
+                        }),
+                    }));
+                }
+            }
+        }
+    }
+
+    fn port_names(&mut self, direction: PortDirection) -> Result<Vec<GenericPort>, Box<dyn Error>> {
+        let mut res = Vec::new();
+
+        loop {
+            let next = self.next_token();
+            match &next {
+                Some(
+                    t @ Token {
+                        token_type: TokenType::Identifier,
+                        ..
+                    },
+                ) => {
+                    let p = GenericPort {
+                        name: Identifier::from(t.clone()),
+                        width: self.port_width()?,
+                        direction,
+                    };
+                    res.push(p);
+                }
+                Some(Token {
+                    token_type: TokenType::Comma,
+                    ..
+                }) => {
+                    continue;
+                }
+                Some(Token {
+                    token_type: TokenType::Semicolon,
+                    ..
+                }) => {
+                    return Ok(res);
+                }
+                Some(t) => {
+                    return Err(Box::new(N2VError {
+                        msg: String::from("Expected identifier, comma, or semicolon."),
                         kind: ErrorKind::ParseError(t.clone()),
                     }));
                 }
                 None => {
                     return Err(Box::new(N2VError {
                         msg: String::from(
-                            "Unexpected end of file. Expected number, comma, or right angle.",
+                            "Unexpected end of file. Expected identifier, comma, or semicolon.",
                         ),
                         kind: ErrorKind::ParseError(Token {
                             lexeme: String::from(""),
@@ -369,16 +2899,165 @@ impl<'a, 'b> Parser<'a, 'b> {
         }
     }
 
-    fn generic_decls(&mut self) -> Result<Vec<Identifier>, Box<dyn Error>> {
+    // Parses the optional `BUILTIN Name;` declaration.
+    fn builtin_name(&mut self) -> Result<Option<Identifier>, Box<dyn Error>> {
+        if self.peek_token().map(|t| t.token_type) != Some(TokenType::Builtin) {
+            return Ok(None);
+        }
+        self.consume(TokenType::Builtin)?;
+        let name = self.consume(TokenType::Identifier)?;
+        self.consume(TokenType::Semicolon)?;
+        Ok(Some(Identifier::from(name)))
+    }
+
+    // Parses the optional `TABLE { inputs -> outputs; ... }` block that takes
+    // the place of a chip's parts. `->` is scanned as `-` and `>`.
+    fn table(&mut self) -> Result<Option<Table>, Box<dyn Error>> {
+        if self.peek_token().map(|t| t.token_type) != Some(TokenType::Table) {
+            return Ok(None);
+        }
+        let t = self.consume(TokenType::Table)?;
+        let keyword = Identifier {
+            span: Some(t.span()),
+            value: t.lexeme,
+            path: Some(t.path),
+            line: Some(t.line),
+        };
+        self.consume(TokenType::LeftCurly)?;
+
+        let mut rows: Vec<TableRow> = Vec::new();
+        while let Some(first) = self
+            .peek_token()
+            .filter(|t| t.token_type != TokenType::RightCurly)
+        {
+            let inputs = self.table_bits(TokenType::Minus)?;
+            self.consume(TokenType::Minus)?;
+            self.consume(TokenType::RightAngle)?;
+            let outputs = self.table_bits(TokenType::Semicolon)?;
+            let semicolon = self.consume(TokenType::Semicolon)?;
+            let row = TableRow {
+                inputs,
+                outputs,
+                line: Some(first.line),
+            };
+            if rows.iter().any(|r| r.input_bits() == row.input_bits()) {
+                return Err(Box::new(N2VError {
+                    msg: format!("The inputs `{}` have more than one row.", row.input_bits()),
+                    kind: ErrorKind::ParseError(semicolon),
+                }));
+            }
+            rows.push(row);
+        }
+        self.consume(TokenType::RightCurly)?;
+
+        Ok(Some(Table { keyword, rows }))
+    }
+
+    // Groups of bits in a row of a table, up to a token of type `end`.
+    fn table_bits(&mut self, end: TokenType) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut groups = Vec::new();
+        while self.peek_token().is_some_and(|t| t.token_type != end) {
+            let t = self.consume(TokenType::Number)?;
+            if !t.lexeme.chars().all(|c| c == '0' || c == '1') {
+                return Err(Box::new(N2VError {
+                    msg: format!(
+                        "`{}` is not a group of bits. Write bits as 0 or 1, e.g. `0110`.",
+                        t.lexeme
+                    ),
+                    kind: ErrorKind::ParseError(t),
+                }));
+            }
+            groups.push(t.lexeme);
+        }
+        Ok(groups)
+    }
+
+    // Parses the optional `STIMULUS cycles { name[width] = GENERATOR; ... }`
+    // block of a testbench chip.
+    fn stimulus(&mut self) -> Result<Option<Stimulus>, Box<dyn Error>> {
+        if self.peek_token().map(|t| t.token_type) != Some(TokenType::Stimulus) {
+            return Ok(None);
+        }
+        let keyword = self.consume(TokenType::Stimulus)?;
+        let cycles = parse_number(&self.consume(TokenType::Number)?)?;
+        self.consume(TokenType::LeftCurly)?;
+
+        let mut signals: Vec<StimulusSignal> = Vec::new();
+        while self.peek_token().map(|t| t.token_type) != Some(TokenType::RightCurly) {
+            let name = Identifier::from(self.consume(TokenType::Identifier)?);
+            let mut width = 1;
+            if self.peek_token().map(|t| t.token_type) == Some(TokenType::LeftBracket) {
+                self.consume(TokenType::LeftBracket)?;
+                width = parse_number(&self.consume(TokenType::Number)?)?;
+                self.consume(TokenType::RightBracket)?;
+            }
+            self.consume(TokenType::Equal)?;
+            let generator_token = self.consume(TokenType::Identifier)?;
+            let generator = match generator_token.lexeme.as_str() {
+                "COUNTER" => Generator::Counter,
+                "RANDOM" => {
+                    let seed = parse_number(&self.consume(TokenType::Number)?)?;
+                    if seed == 0 {
+                        return Err(Box::new(N2VError {
+                            msg: String::from("A RANDOM seed cannot be zero."),
+                            kind: ErrorKind::ParseError(generator_token),
+                        }));
+                    }
+                    Generator::Random(seed as u64)
+                }
+                _ => {
+                    return Err(Box::new(N2VError {
+                        msg: format!(
+                            "`{}` is not a stimulus. Use COUNTER or RANDOM seed.",
+                            generator_token.lexeme
+                        ),
+                        kind: ErrorKind::ParseError(generator_token),
+                    }))
+                }
+            };
+            let semicolon = self.consume(TokenType::Semicolon)?;
+            if width == 0 || width > 64 {
+                return Err(Box::new(N2VError {
+                    msg: format!(
+                        "Stimulus {} is {} bits wide, but must be 1 to 64 bits.",
+                        name.value, width
+                    ),
+                    kind: ErrorKind::ParseError(semicolon),
+                }));
+            }
+            if signals.iter().any(|s| s.name.value == name.value) {
+                return Err(Box::new(N2VError {
+                    msg: format!("Stimulus {} is declared twice.", name.value),
+                    kind: ErrorKind::ParseError(semicolon),
+                }));
+            }
+            signals.push(StimulusSignal {
+                name,
+                width,
+                generator,
+            });
+        }
+        self.consume(TokenType::RightCurly)?;
+
+        Ok(Some(Stimulus {
+            cycles,
+            signals,
+            line: Some(keyword.line),
+        }))
+    }
+
+    // Parses the optional `CLOCKED a, b;` declaration. Every clocked pin
+    // must be one of the ports declared by the chip.
+    fn clocked_names(&mut self, ports: &[GenericPort]) -> Result<Vec<Identifier>, Box<dyn Error>> {
         let mut res = Vec::new();
 
-        if self.scanner.peek().unwrap().token_type != TokenType::LeftAngle {
-            return Ok(Vec::new());
+        if self.peek_token().map(|t| t.token_type) != Some(TokenType::Clocked) {
+            return Ok(res);
         }
-        self.consume(TokenType::LeftAngle)?;
+        self.consume(TokenType::Clocked)?;
 
         loop {
-            let next = self.scanner.next();
+            let next = self.next_token();
             match &next {
                 Some(
                     t @ Token {
@@ -386,11 +3065,13 @@ impl<'a, 'b> Parser<'a, 'b> {
                         ..
                     },
                 ) => {
-                    res.push(Identifier {
-                        line: Some(t.line),
-                        path: Some(t.path.clone()),
-                        value: t.lexeme.clone(),
-                    });
+                    if !ports.iter().any(|p| p.name.value == t.lexeme) {
+                        return Err(Box::new(N2VError {
+                            msg: format!("CLOCKED pin `{}` is not a port of this chip.", t.lexeme),
+                            kind: ErrorKind::ParseError(t.clone()),
+                        }));
+                    }
+                    res.push(Identifier::from(t.clone()));
                 }
                 Some(Token {
                     token_type: TokenType::Comma,
@@ -399,21 +3080,21 @@ impl<'a, 'b> Parser<'a, 'b> {
                     continue;
                 }
                 Some(Token {
-                    token_type: TokenType::RightAngle,
+                    token_type: TokenType::Semicolon,
                     ..
                 }) => {
                     return Ok(res);
                 }
                 Some(t) => {
                     return Err(Box::new(N2VError {
-                        msg: String::from("Expected identifier, comma, or right angle"),
+                        msg: String::from("Expected identifier, comma, or semicolon."),
                         kind: ErrorKind::ParseError(t.clone()),
                     }));
                 }
                 None => {
                     return Err(Box::new(N2VError {
                         msg: String::from(
-                            "Unexpected end of file. Expected identifier, comma, or right angle.",
+                            "Unexpected end of file. Expected identifier, comma, or semicolon.",
                         ),
                         kind: ErrorKind::ParseError(Token {
                             lexeme: String::from(""),
@@ -428,144 +3109,666 @@ impl<'a, 'b> Parser<'a, 'b> {
         }
     }
 
-    fn port_names(&mut self, direction: PortDirection) -> Result<Vec<GenericPort>, Box<dyn Error>> {
-        let mut res = Vec::new();
+    // Parses a list of components (parts). This list may contain for-generate loops.
+    pub fn parts(&mut self, errors: &mut Vec<N2VError>) -> Vec<Part> {
+        let mut parts: Vec<Part> = Vec::new();
 
         loop {
-            let next = self.scanner.next();
-            match &next {
-                Some(
-                    t @ Token {
-                        token_type: TokenType::Identifier,
-                        ..
-                    },
-                ) => {
-                    let p = GenericPort {
-                        name: Identifier::from(t.clone()),
-                        width: self.port_width()?,
-                        direction,
-                    };
-                    res.push(p);
-                }
+            let peeked = self.peek_token();
+            match &peeked {
                 Some(Token {
-                    token_type: TokenType::Comma,
+                    token_type: TokenType::Identifier,
+                    ..
+                }) if self.at_assignment() => match self.assignment(None) {
+                    Ok(c) => parts.extend(c.into_iter().map(Part::Component)),
+                    Err(e) => self.recover(e, errors, &[]),
+                },
+                Some(Token {
+                    token_type: TokenType::Identifier | TokenType::At,
+                    ..
+                }) => match self.instances() {
+                    Ok(p) => parts.push(p),
+                    Err(e) => self.recover(e, errors, &[]),
+                },
+                Some(Token {
+                    token_type: TokenType::For,
                     ..
                 }) => {
-                    continue;
+                    if let Some(l) = self.for_loop(errors) {
+                        parts.push(Part::Loop(l));
+                    }
                 }
                 Some(Token {
-                    token_type: TokenType::Semicolon,
+                    token_type: TokenType::RightCurly,
                     ..
                 }) => {
-                    return Ok(res);
+                    self.next_token();
+                    break;
                 }
                 Some(t) => {
-                    return Err(Box::new(N2VError {
-                        msg: String::from("Expected identifier, comma, or semicolon."),
+                    let e = Box::new(N2VError {
+                        msg: String::from("Expected identifier, FOR, or right curly."),
                         kind: ErrorKind::ParseError(t.clone()),
-                    }));
+                    });
+                    self.recover(e, errors, &[]);
                 }
                 None => {
-                    return Err(Box::new(N2VError {
-                        msg: String::from(
-                            "Unexpected end of file. Expected identifier, comma, or semicolon.",
-                        ),
-                        kind: ErrorKind::ParseError(Token {
-                            lexeme: String::from(""),
-                            path: self.scanner.path.clone(),
-                            line: self.scanner.line,
-                            start: self.scanner.col,
-                            token_type: TokenType::Eof,
-                        }),
-                    }));
+                    errors.push(self.end_of_file(
+                        "Unexpected end of file. Expected identifier, FOR, or right curly.",
+                    ));
+                    break;
                 }
             }
         }
+
+        parts
     }
 
-    // Parses a list of components (parts). This list may contain for-generate loops.
-    fn parts(&mut self) -> Result<Vec<Part>, Box<dyn Error>> {
-        let mut parts: Vec<Part> = Vec::new();
+    // Same as parts but does not allow for-generate loops. Expression parts
+    // get their own wires for each value of the loop's `iterator`.
+    fn components(&mut self, iterator: &Identifier, errors: &mut Vec<N2VError>) -> Vec<Component> {
+        let mut parts: Vec<Component> = Vec::new();
 
         loop {
-            let peeked = self.scanner.peek();
+            let peeked = self.peek_token();
             match &peeked {
                 Some(Token {
                     token_type: TokenType::Identifier,
                     ..
-                }) => {
-                    parts.push(Part::Component(self.component()?));
-                }
+                }) if self.at_assignment() => match self.assignment(Some(iterator)) {
+                    Ok(c) => parts.extend(c),
+                    Err(e) => self.recover(e, errors, &[]),
+                },
                 Some(Token {
-                    token_type: TokenType::For,
+                    token_type: TokenType::Identifier | TokenType::At,
                     ..
-                }) => {
-                    parts.push(Part::Loop(self.for_loop()?));
-                }
+                }) => match self.component() {
+                    Ok(c) => parts.push(c),
+                    Err(e) => self.recover(e, errors, &[]),
+                },
                 Some(Token {
                     token_type: TokenType::RightCurly,
                     ..
                 }) => {
-                    self.scanner.next();
+                    self.next_token();
                     break;
                 }
                 Some(t) => {
-                    return Err(Box::new(N2VError {
-                        msg: String::from("Expected identifier, FOR, or right curly."),
+                    let e = Box::new(N2VError {
+                        msg: String::from("Expected Identifier or right curly."),
                         kind: ErrorKind::ParseError(t.clone()),
-                    }));
+                    });
+                    self.recover(e, errors, &[]);
                 }
                 None => {
+                    errors.push(self.end_of_file(
+                        "Unexpected end of file. Expected identifier or right curly.",
+                    ));
+                    break;
+                }
+            }
+        }
+
+        parts
+    }
+
+    fn end_of_file(&self, msg: &str) -> N2VError {
+        N2VError {
+            msg: String::from(msg),
+            kind: ErrorKind::ParseError(Token {
+                lexeme: String::from(""),
+                path: self.scanner.path.clone(),
+                line: self.scanner.line,
+                start: self.scanner.col,
+                token_type: TokenType::Eof,
+            }),
+        }
+    }
+
+    // A loop whose header does not parse is dropped, but its body is still
+    // parsed for errors.
+    fn for_loop(&mut self, errors: &mut Vec<N2VError>) -> Option<Loop> {
+        let leading = self.leading_comments();
+        let header = (|| {
+            self.consume(TokenType::For)?;
+            let iterator = Identifier::from(self.consume(TokenType::Identifier)?);
+            self.consume(TokenType::In)?;
+            let start = self.expr()?;
+            self.consume(TokenType::To)?;
+            let end = self.expr()?;
+            self.consume(TokenType::Generate)?;
+            Ok::<_, Box<dyn Error>>((iterator, start, end))
+        })();
+        let header = match header {
+            Ok(h) => h,
+            Err(e) => {
+                self.recover(e, errors, &[TokenType::LeftCurly]);
+                if self.peek_token().map(|t| t.token_type) == Some(TokenType::LeftCurly) {
+                    self.next_token();
+                    self.components(&Identifier::from("i"), errors);
+                }
+                return None;
+            }
+        };
+        let (iterator, start, end) = header;
+        self.expect(TokenType::LeftCurly, errors);
+        let body = self.components(&iterator, errors);
+        // The scanner stops right after the closing brace.
+        let end_line = self.scanner.line;
+
+        Some(Loop {
+            start,
+            end,
+            iterator,
+            body,
+            comments: Comments {
+                leading,
+                trailing: self.trailing_comments(end_line),
+            },
+            array: false,
+        })
+    }
+
+    fn expr(&mut self) -> Result<GenericWidth, Box<dyn Error>> {
+        let t1 = self.operand()?;
+
+        let peeked = self.peek_token().map(|t| t.token_type);
+        if peeked == Some(TokenType::Plus) {
+            self.next_token();
+            let t2 = self.operand()?;
+            Ok(GenericWidth::Expr(Op::Add, Box::new(t1), Box::new(t2)))
+        } else if peeked == Some(TokenType::Minus) {
+            self.next_token();
+            let t2 = self.operand()?;
+            Ok(GenericWidth::Expr(Op::Sub, Box::new(t1), Box::new(t2)))
+        } else {
+            Ok(t1)
+        }
+    }
+
+    // A terminal or a call to one of the functions `max(a, b)`, `min(a, b)`,
+    // `log2(n)`, and `if(a > b, x, y)`, whose arguments are expressions.
+    fn operand(&mut self) -> Result<GenericWidth, Box<dyn Error>> {
+        // A generic var may share its name with a function.
+        let is_function = matches!(self.scanner.peek(), Some(Ok(t))
+            if t.token_type == TokenType::Identifier
+                && matches!(t.lexeme.as_str(), "max" | "min" | "log2" | "if"))
+            && matches!(self.scanner.peek_n(1), Some(Ok(t)) if t.token_type == TokenType::LeftParen);
+        if !is_function {
+            return Ok(GenericWidth::Terminal(self.terminal()?));
+        }
+
+        let function = self.consume(TokenType::Identifier)?;
+        self.consume(TokenType::LeftParen)?;
+        if function.lexeme == "if" {
+            let condition = self.condition()?;
+            self.consume(TokenType::Comma)?;
+            let a = self.expr()?;
+            self.consume(TokenType::Comma)?;
+            let b = self.expr()?;
+            self.consume(TokenType::RightParen)?;
+            return Ok(GenericWidth::If(
+                Box::new(condition),
+                Box::new(a),
+                Box::new(b),
+            ));
+        }
+        let a = self.expr()?;
+        let res = if function.lexeme == "log2" {
+            GenericWidth::Log2(Box::new(a))
+        } else {
+            self.consume(TokenType::Comma)?;
+            let b = self.expr()?;
+            let op = if function.lexeme == "max" {
+                Op::Max
+            } else {
+                Op::Min
+            };
+            GenericWidth::Expr(op, Box::new(a), Box::new(b))
+        };
+        self.consume(TokenType::RightParen)?;
+        Ok(res)
+    }
+
+    // A comparison such as `W >= 8`. Operators of two characters are scanned
+    // as two tokens.
+    fn condition(&mut self) -> Result<Condition, Box<dyn Error>> {
+        let left = self.expr()?;
+        let first = match self.next_token() {
+            Some(t) => t,
+            None => {
+                return Err(Box::new(self.end_of_file(
+                    "Unexpected end of file. Expected a comparison such as `>`.",
+                )))
+            }
+        };
+        let equal_follows = self.peek_token().map(|t| t.token_type) == Some(TokenType::Equal);
+        let cmp = match (first.token_type, equal_follows) {
+            (TokenType::LeftAngle, false) => Cmp::Lt,
+            (TokenType::LeftAngle, true) => Cmp::Le,
+            (TokenType::RightAngle, false) => Cmp::Gt,
+            (TokenType::RightAngle, true) => Cmp::Ge,
+            (TokenType::Equal, true) => Cmp::Eq,
+            (TokenType::Bang, true) => Cmp::Ne,
+            _ => {
+                return Err(Box::new(N2VError {
+                    msg: String::from("Expected a comparison: <, <=, >, >=, == or !=."),
+                    kind: ErrorKind::ParseError(first),
+                }))
+            }
+        };
+        if equal_follows {
+            self.next_token();
+        }
+        let right = self.expr()?;
+        Ok(Condition { cmp, left, right })
+    }
+
+    fn terminal(&mut self) -> Result<Terminal, Box<dyn Error>> {
+        let width_token = match self.next_token() {
+            Some(t) => t,
+            None => {
+                return Err(Box::new(self.end_of_file(
+                    "Unexpected end of file. Expected number or generic var.",
+                )))
+            }
+        };
+        let width = match width_token.token_type {
+            TokenType::Number => Terminal::Num(parse_number(&width_token)?),
+            TokenType::Identifier => Terminal::Var(Identifier::from(width_token)),
+            _ => {
+                return Err(Box::new(N2VError {
+                    msg: String::from("Expected number or generic var for port width."),
+                    kind: ErrorKind::ParseError(width_token),
+                }));
+            }
+        };
+        Ok(width)
+    }
+
+    // `@name` or `@name("arg", ...)` annotations before a chip or part.
+    fn annotations(&mut self) -> Result<Vec<Annotation>, Box<dyn Error>> {
+        let mut annotations = Vec::new();
+        while self.peek_token().map(|t| t.token_type) == Some(TokenType::At) {
+            self.next_token();
+            let token = self.consume(TokenType::Identifier)?;
+            let mut args = Vec::new();
+            if self.peek_token().map(|t| t.token_type) == Some(TokenType::LeftParen) {
+                self.next_token();
+                loop {
+                    let arg = self.consume(TokenType::StringLiteral)?;
+                    args.push(arg.lexeme.trim_matches('"').to_string());
+                    if self.peek_token().map(|t| t.token_type) != Some(TokenType::Comma) {
+                        break;
+                    }
+                    self.next_token();
+                }
+                self.consume(TokenType::RightParen)?;
+            }
+            if let Some((_, n)) = ANNOTATIONS.iter().find(|(a, _)| *a == token.lexeme) {
+                if args.len() != *n {
                     return Err(Box::new(N2VError {
-                        msg: String::from(
-                            "Unexpected end of file. Expected identifier, FOR, or right curly.",
+                        msg: format!(
+                            "@{} takes {} argument{}, not {}.",
+                            token.lexeme,
+                            n,
+                            if *n == 1 { "" } else { "s" },
+                            args.len()
                         ),
-                        kind: ErrorKind::ParseError(Token {
-                            lexeme: String::from(""),
-                            path: self.scanner.path.clone(),
-                            line: self.scanner.line,
-                            start: self.scanner.col,
-                            token_type: TokenType::Eof,
-                        }),
+                        kind: ErrorKind::ParseError(token),
                     }));
                 }
             }
+            annotations.push(Annotation {
+                name: Identifier::from(token),
+                args,
+            });
+        }
+        Ok(annotations)
+    }
+
+    // A component, or an array of them such as `FullAdder[8](a=a[i], ...)`,
+    // which is shorthand for `FOR i IN 0 TO 7 GENERATE { FullAdder(...); }`.
+    fn instances(&mut self) -> Result<Part, Box<dyn Error>> {
+        let (c, count) = self.component_or_array(true)?;
+        let count = match count {
+            Some(count) => count,
+            None => return Ok(Part::Component(c)),
+        };
+        let iterator = Identifier {
+            value: String::from("i"),
+            path: c.name.path.clone(),
+            line: c.name.line,
+            span: None,
+        };
+        Ok(Part::Loop(Loop {
+            start: GenericWidth::Terminal(Terminal::Num(0)),
+            end: &count - &GenericWidth::Terminal(Terminal::Num(1)),
+            iterator,
+            body: vec![c],
+            comments: Comments::default(),
+            array: true,
+        }))
+    }
+
+    // Whether the next part is an expression part such as `out = a AND b;`,
+    // which starts with a bus and `=` where a component has its name.
+    fn at_assignment(&mut self) -> bool {
+        let mut token_type = |n| {
+            self.scanner.peek_n(n).map(|t| match t {
+                Ok(t) => t.token_type,
+                Err(e) => e.token.token_type,
+            })
+        };
+        let mut n = 1;
+        if token_type(n) == Some(TokenType::LeftBracket) {
+            loop {
+                n += 1;
+                match token_type(n) {
+                    Some(TokenType::RightBracket) => break,
+                    Some(TokenType::Equal | TokenType::Semicolon | TokenType::LeftParen) | None => {
+                        return false
+                    }
+                    _ => {}
+                }
+            }
+            n += 1;
         }
+        token_type(n) == Some(TokenType::Equal)
+    }
+
+    // Parses `target = expression;` into the Nand parts that compute it.
+    // Comments around it are kept on the first part.
+    fn assignment(
+        &mut self,
+        iterator: Option<&Identifier>,
+    ) -> Result<Vec<Component>, Box<dyn Error>> {
+        let leading = self.leading_comments();
+        let t = self.consume(TokenType::Identifier)?;
+        let target_ident = Identifier::from(t.clone());
+        let target = self.bus(t.lexeme)?;
+        self.consume(TokenType::Equal)?;
+        let expr = self.bool_expr()?;
+        let semicolon = self.consume(TokenType::Semicolon)?;
 
+        let assignment = Assignment {
+            target_ident,
+            target,
+            expr,
+        };
+        let mut parts = assignment.expand(iterator);
+        parts[0].comments = Comments {
+            leading,
+            trailing: self.trailing_comments(semicolon.line),
+        };
         Ok(parts)
     }
 
-    // Same as parts but does not allow for-generate loops.
-    fn components(&mut self) -> Result<Vec<Component>, Box<dyn Error>> {
-        let mut parts: Vec<Component> = Vec::new();
+    // Whether the next token is the operator `op`, such as `AND`. Operators
+    // are scanned as identifiers, so wires cannot be named after them.
+    fn at_operator(&mut self, op: &str) -> bool {
+        self.peek_token()
+            .is_some_and(|t| t.token_type == TokenType::Identifier && t.lexeme == op)
+    }
+
+    // OR binds loosest, then XOR, then AND, then NOT.
+    fn bool_expr(&mut self) -> Result<BoolExpr, Box<dyn Error>> {
+        let mut e = self.bool_xor()?;
+        while self.at_operator("OR") {
+            self.next_token();
+            e = BoolExpr::Or(Box::new(e), Box::new(self.bool_xor()?));
+        }
+        Ok(e)
+    }
+
+    fn bool_xor(&mut self) -> Result<BoolExpr, Box<dyn Error>> {
+        let mut e = self.bool_and()?;
+        while self.at_operator("XOR") {
+            self.next_token();
+            e = BoolExpr::Xor(Box::new(e), Box::new(self.bool_and()?));
+        }
+        Ok(e)
+    }
+
+    fn bool_and(&mut self) -> Result<BoolExpr, Box<dyn Error>> {
+        let mut e = self.bool_operand()?;
+        while self.at_operator("AND") {
+            self.next_token();
+            e = BoolExpr::And(Box::new(e), Box::new(self.bool_operand()?));
+        }
+        Ok(e)
+    }
+
+    fn bool_operand(&mut self) -> Result<BoolExpr, Box<dyn Error>> {
+        if self.at_operator("NOT") {
+            self.next_token();
+            return Ok(BoolExpr::Not(Box::new(self.bool_operand()?)));
+        }
+        if self.peek_token().map(|t| t.token_type) == Some(TokenType::LeftParen) {
+            self.next_token();
+            let e = self.bool_expr()?;
+            self.consume(TokenType::RightParen)?;
+            return Ok(e);
+        }
+        let t = self.consume(TokenType::Identifier)?;
+        if ["AND", "OR", "XOR", "NOT"].contains(&t.lexeme.as_str()) {
+            return Err(Box::new(N2VError {
+                msg: format!("Expected a wire before `{}`.", t.lexeme),
+                kind: ErrorKind::ParseError(t),
+            }));
+        }
+        let ident = Identifier::from(t.clone());
+        let bus = self.bus(t.lexeme)?;
+        Ok(BoolExpr::Wire(ident, bus))
+    }
+
+    fn component(&mut self) -> Result<Component, Box<dyn Error>> {
+        Ok(self.component_or_array(false)?.0)
+    }
+
+    // Also returns the count of an array of components, when allowed.
+    fn component_or_array(
+        &mut self,
+        array: bool,
+    ) -> Result<(Component, Option<GenericWidth>), Box<dyn Error>> {
+        let leading = self.leading_comments();
+        let annotations = self.annotations()?;
+        let mut name = Identifier::from(self.consume(TokenType::Identifier)?);
+        let mut namespace = None;
+        if self.peek_token().map(|t| t.token_type) == Some(TokenType::Dot) {
+            self.consume(TokenType::Dot)?;
+            namespace = Some(name);
+            name = Identifier::from(self.consume(TokenType::Identifier)?);
+        }
+        let generic_params = self.generics()?;
+        let count = match self.peek_token() {
+            Some(t) if t.token_type == TokenType::LeftBracket => {
+                if !array {
+                    return Err(Box::new(N2VError {
+                        msg: String::from("Arrays of parts are not allowed in FOR loops."),
+                        kind: ErrorKind::ParseError(t),
+                    }));
+                }
+                self.next_token();
+                let count = self.expr()?;
+                let close = self.consume(TokenType::RightBracket)?;
+                if count == GenericWidth::Terminal(Terminal::Num(0)) {
+                    return Err(Box::new(N2VError {
+                        msg: String::from("An array of parts needs at least one part."),
+                        kind: ErrorKind::ParseError(close),
+                    }));
+                }
+                Some(count)
+            }
+            _ => None,
+        };
+        let mappings = self.port_mappings()?;
+        let semicolon = self.consume(TokenType::Semicolon)?;
+
+        let component = Component {
+            name,
+            namespace,
+            generic_params,
+            mappings,
+            expression: None,
+            annotations,
+            comments: Comments {
+                leading,
+                trailing: self.trailing_comments(semicolon.line),
+            },
+        };
+        Ok((component, count))
+    }
+
+    fn port_width(&mut self) -> Result<GenericWidth, Box<dyn Error>> {
+        if self.peek_token().map(|t| t.token_type) != Some(TokenType::LeftBracket) {
+            return Ok(GenericWidth::Terminal(Terminal::Num(1)));
+        }
+
+        self.consume(TokenType::LeftBracket)?;
+        let width = self.expr()?;
+        self.consume(TokenType::RightBracket)?;
+
+        Ok(width)
+    }
+
+    // Parses a bus name's optional index or range. A range written high to
+    // low with constant bounds, e.g. `[7..0]`, is descending. The range is
+    // always stored low to high along with a flag for the direction. A range
+    // may end with a step, e.g. `[0..14:2]` for every other bit.
+    fn bus(&mut self, name: String) -> Result<BusHDL, Box<dyn Error>> {
+        let mut bus = BusHDL {
+            name,
+            start: None,
+            end: None,
+            descending: false,
+            stride: None,
+        };
+        if self.peek_token().map(|t| t.token_type) != Some(TokenType::LeftBracket) {
+            return Ok(bus);
+        }
+
+        self.consume(TokenType::LeftBracket)?;
+        let start = self.expr()?;
+        let mut end = start.clone();
+        if self.peek_token().map(|t| t.token_type) == Some(TokenType::Dot) {
+            self.consume(TokenType::Dot)?;
+            self.consume(TokenType::Dot)?;
+            end = self.expr()?;
+            if self.peek_token().map(|t| t.token_type) == Some(TokenType::Colon) {
+                self.consume(TokenType::Colon)?;
+                let t = self.consume(TokenType::Number)?;
+                match parse_number(&t)? {
+                    0 => {
+                        return Err(Box::new(N2VError {
+                            msg: String::from("The step of a range must be at least 1."),
+                            kind: ErrorKind::ParseError(t),
+                        }))
+                    }
+                    k => bus.stride = Some(k),
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket)?;
+
+        let constants = HashMap::new();
+        if let (Ok(s), Ok(e)) = (
+            eval_expr_numeric(&start, &constants),
+            eval_expr_numeric(&end, &constants),
+        ) {
+            if s > e {
+                bus.start = Some(end);
+                bus.end = Some(start);
+                bus.descending = true;
+                return Ok(bus);
+            }
+        }
+        bus.start = Some(start);
+        bus.end = Some(end);
+        Ok(bus)
+    }
+
+    fn port_mappings(&mut self) -> Result<Vec<PortMapping>, Box<dyn Error>> {
+        let mut mappings: Vec<PortMapping> = Vec::new();
+        let mut last_line = 0;
+
+        self.consume(TokenType::LeftParen)?;
+        loop {
+            let leading = self.leading_comments();
+            let next = self.next_token();
+            match &next {
+                Some(
+                    t @ Token {
+                        token_type: TokenType::Identifier,
+                        ..
+                    },
+                ) => {
+                    let port = self.bus(t.lexeme.clone())?;
+                    self.consume(TokenType::Equal)?;
+                    let wire = self.consume(TokenType::Identifier)?;
+                    last_line = wire.line;
+                    let open = wire.clone();
+                    let wire = self.bus(wire.lexeme)?;
+                    if wire.name == "open" && wire.start.is_some() {
+                        return Err(Box::new(N2VError {
+                            msg: String::from(
+                                "`open` leaves the port unconnected and cannot take a range.",
+                            ),
+                            kind: ErrorKind::ParseError(open),
+                        }));
+                    }
+
+                    mappings.push(PortMapping {
+                        wire_ident: Identifier::from(t.clone()),
+                        wire,
+                        port,
+                        comments: Comments {
+                            leading,
+                            trailing: Vec::new(),
+                        },
+                    });
 
-        loop {
-            let peeked = self.scanner.peek();
-            match &peeked {
-                Some(Token {
-                    token_type: TokenType::Identifier,
-                    ..
-                }) => {
-                    parts.push(self.component()?);
+                    // The end of file is reported by the next iteration.
+                    match self.peek_token() {
+                        Some(Token {
+                            token_type: TokenType::Comma | TokenType::RightParen,
+                            ..
+                        })
+                        | None => {}
+                        Some(found_t) => {
+                            let found = found_t.lexeme.clone();
+                            return Err(Box::new(N2VError {
+                                msg: format!("Expected comma or right paren, found {}", found),
+                                kind: ErrorKind::ParseError(found_t),
+                            }));
+                        }
+                    }
                 }
                 Some(Token {
-                    token_type: TokenType::RightCurly,
+                    token_type: TokenType::Comma,
+                    ..
+                })
+                | Some(Token {
+                    token_type: TokenType::RightParen,
                     ..
                 }) => {
-                    self.scanner.next();
-                    break;
+                    if let Some(m) = mappings.last_mut() {
+                        let mut trailing = self.trailing_comments(last_line);
+                        m.comments.trailing.append(&mut trailing);
+                    }
+                    if next.unwrap().token_type == TokenType::RightParen {
+                        break;
+                    }
                 }
                 Some(t) => {
                     return Err(Box::new(N2VError {
-                        msg: String::from("Expected Identifier or right curly."),
+                        msg: String::from("Expected comma, or right paren"),
                         kind: ErrorKind::ParseError(t.clone()),
                     }));
                 }
                 None => {
                     return Err(Box::new(N2VError {
-                        msg: String::from(
-                            "Unexpected end of file. Expected identifier or right curly.",
-                        ),
+                        msg: String::from("Unexpected end of file. Expected comma or right paren."),
                         kind: ErrorKind::ParseError(Token {
                             lexeme: String::from(""),
                             path: self.scanner.path.clone(),
@@ -578,292 +3781,1394 @@ impl<'a, 'b> Parser<'a, 'b> {
             }
         }
 
-        Ok(parts)
+        Ok(mappings)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn read_hdl(path: &std::path::Path) -> String {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let test_file = manifest_dir.join("resources").join("tests").join(path);
+
+        fs::read_to_string(test_file).expect("Unable to read test file.")
+    }
+
+    #[test]
+    fn test_nand2tetris_solution_mux() {
+        let path = PathBuf::from("nand2tetris/solutions/Mux.hdl");
+        let contents = read_hdl(&path);
+        let mut scanner = Scanner::new(contents.as_str(), path);
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        parser.parse().expect("Parse error");
+    }
+
+    #[test]
+    fn test_nand2tetris_solution_not16() {
+        let path = PathBuf::from("nand2tetris/solutions/Not16.hdl");
+        let contents = read_hdl(&path);
+        let mut scanner = Scanner::new(contents.as_str(), path);
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        parser.parse().expect("Parse error");
+    }
+
+    #[test]
+    fn test_nand2tetris_solution_and16() {
+        let path = PathBuf::from("nand2tetris/solutions/And16.hdl");
+        let contents = read_hdl(&path);
+        let mut scanner = Scanner::new(contents.as_str(), path);
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        parser.parse().expect("Parse error");
+    }
+
+    #[test]
+    fn test_nand2tetris_solution_or8way() {
+        let path = PathBuf::from("nand2tetris/solutions/Or8Way.hdl");
+        let contents = read_hdl(&path);
+        let mut scanner = Scanner::new(contents.as_str(), path);
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        parser.parse().expect("Parse error");
+    }
+
+    #[test]
+    fn test_nand2tetris_solution_not() {
+        let path = PathBuf::from("nand2tetris/solutions/Not.hdl");
+        let contents = read_hdl(&path);
+        let mut scanner = Scanner::new(contents.as_str(), path);
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        parser.parse().expect("Parse error");
+    }
+
+    #[test]
+    fn test_nand2tetris_solution_alu() {
+        let path = PathBuf::from("nand2tetris/solutions/ALU.hdl");
+        let contents = read_hdl(&path);
+        let mut scanner = Scanner::new(contents.as_str(), path);
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        parser.parse().expect("Parse error");
+    }
+
+    #[test]
+    fn test_clocked_pins() {
+        let contents = "CHIP Bit {
+            IN in, load;
+            OUT out;
+            CLOCKED in, load;
+            PARTS:
+            Mux(a=dffout, b=in, sel=load, out=muxout);
+            DFF(in=muxout, out=dffout, out=out);
+        }";
+        let mut scanner = Scanner::new(contents, PathBuf::from("Bit.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let clocked: Vec<&str> = hdl.clocked.iter().map(|c| c.value.as_str()).collect();
+        assert_eq!(clocked, vec!["in", "load"]);
+    }
+
+    #[test]
+    fn test_builtin() {
+        let contents = "CHIP RAM8 {
+            IN in[16], load, address[3];
+            OUT out[16];
+            BUILTIN RAM8;
+            CLOCKED in, load;
+        }";
+        let mut scanner = Scanner::new(contents, PathBuf::from("RAM8.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        assert_eq!(hdl.builtin.unwrap().value, "RAM8");
+        assert_eq!(hdl.clocked.len(), 2);
+        assert!(hdl.parts.is_empty());
+    }
+
+    #[test]
+    fn test_hex_binary_literals() {
+        let contents = "CHIP Wide<W> {
+            IN in[0x10], address[0b11];
+            OUT out[0x10];
+            PARTS:
+            Not16(in=in[0x0..0b1111], out=out);
+            Foo<0x8>(in=in[0..0x7]);
+        }";
+        let mut scanner = Scanner::new(contents, PathBuf::from("Wide.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        assert_eq!(
+            hdl.ports[0].width,
+            GenericWidth::Terminal(Terminal::Num(16))
+        );
+        assert_eq!(hdl.ports[1].width, GenericWidth::Terminal(Terminal::Num(3)));
+        match &hdl.parts[1] {
+            Part::Component(c) => {
+                assert_eq!(
+                    c.generic_params,
+                    vec![GenericParam {
+                        name: None,
+                        value: GenericValue::from(8)
+                    }]
+                );
+                assert_eq!(
+                    c.mappings[0].wire.end,
+                    Some(GenericWidth::Terminal(Terminal::Num(7)))
+                );
+            }
+            _ => panic!("Expected component"),
+        }
+
+        let mut scanner = Scanner::new("CHIP Bad { IN in[0x1g]; }", PathBuf::from("Bad.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn test_descending_range() {
+        let contents = "CHIP Reverse {
+            IN in[8];
+            OUT out[8];
+            PARTS:
+            Foo(a[0..7]=in[7..0], b=in[0..7], out=out);
+        }";
+        let mut scanner = Scanner::new(contents, PathBuf::from("Reverse.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        match &hdl.parts[0] {
+            Part::Component(c) => {
+                let wire = &c.mappings[0].wire;
+                assert!(wire.descending);
+                assert_eq!(wire.start, Some(GenericWidth::Terminal(Terminal::Num(0))));
+                assert_eq!(wire.end, Some(GenericWidth::Terminal(Terminal::Num(7))));
+                assert!(!c.mappings[0].port.descending);
+                assert!(!c.mappings[1].wire.descending);
+            }
+            _ => panic!("Expected component"),
+        }
+    }
+
+    #[test]
+    fn test_strided_range() {
+        let parse = |contents: &str| {
+            let mut scanner = Scanner::new(contents, PathBuf::from("Odd.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            parser.parse()
+        };
+        let hdl = parse(
+            "CHIP Odd {
+            IN in[16];
+            OUT out[8];
+            PARTS:
+            Foo(a=in[1..15:2], b=in[14..0:2], out=out);
+        }",
+        )
+        .expect("Parse error");
+        match &hdl.parts[0] {
+            Part::Component(c) => {
+                assert_eq!(c.mappings[0].wire.stride, Some(2));
+                assert_eq!(c.mappings[0].wire.to_string(), "in[1..15:2]");
+                assert!(c.mappings[1].wire.descending);
+                assert_eq!(c.mappings[1].wire.to_string(), "in[14..0:2]");
+                assert_eq!(c.mappings[2].wire.stride, None);
+            }
+            _ => panic!("Expected component"),
+        }
+
+        let e = parse("CHIP Odd { IN in[2]; OUT out; PARTS: Foo(a=in[0..1:0], out=out); }");
+        assert!(e
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("The step of a range must be at least 1."));
+    }
+
+    #[test]
+    fn test_open_port() {
+        let parse = |contents: &str| {
+            let mut scanner = Scanner::new(contents, PathBuf::from("Low.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            parser.parse()
+        };
+        let hdl = parse(
+            "CHIP Low { IN in[2]; OUT out; PARTS: DMux(in=in[0], sel=in[1], a=out, b=open); }",
+        )
+        .expect("Parse error");
+        match &hdl.parts[0] {
+            Part::Component(c) => {
+                assert!(!c.mappings[2].is_open());
+                assert!(c.mappings[3].is_open());
+            }
+            _ => panic!("Expected component"),
+        }
+
+        let e = parse("CHIP Low { IN in; OUT out[2]; PARTS: Foo(in=in, out=open[0]); }");
+        assert!(e
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("`open` leaves the port unconnected and cannot take a range."));
+    }
+
+    #[test]
+    fn test_clocked_unknown_pin() {
+        let contents = "CHIP Bit {
+            IN in, load;
+            OUT out;
+            CLOCKED clk;
+            PARTS:
+        }";
+        let mut scanner = Scanner::new(contents, PathBuf::from("Bit.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        let contents = "CHIP Bad {
+            IN a, b;
+            OUT out;
+            CLOCKED clk;
+            PARTS:
+            Not(in=a out=x);
+            And(a=a, b=b, out=y);
+            FOR i IN 0 TO GENERATE {
+                Not(in=, out=z);
+            }
+            Or(a=x, b=y, out=out);
+        }";
+        let mut scanner = Scanner::new(contents, PathBuf::from("Bad.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let (hdl, errors) = parser.parse_recovering();
+        let lines: Vec<u32> = errors
+            .iter()
+            .map(|e| match &e.kind {
+                ErrorKind::ParseError(t) => t.line,
+                _ => 0,
+            })
+            .collect();
+        assert_eq!(lines, vec![4, 6, 8, 9]);
+
+        let hdl = hdl.expect("No partial chip");
+        assert_eq!(hdl.ports.len(), 3);
+        let parts: Vec<String> = hdl
+            .parts
+            .iter()
+            .map(|p| match p {
+                Part::Component(c) => c.name.value.clone(),
+                Part::Loop(_) => String::from("FOR"),
+            })
+            .collect();
+        assert_eq!(parts, vec!["And", "Or"]);
+    }
+
+    #[test]
+    fn test_error_recovery_end_of_file() {
+        for contents in ["CHIP", "CHIP A { IN a[", "CHIP A { IN a; OUT b; PARTS: Not"] {
+            let mut scanner = Scanner::new(contents, PathBuf::from("A.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let (_, errors) = parser.parse_recovering();
+            assert!(!errors.is_empty(), "{}", contents);
+        }
+    }
+
+    #[test]
+    fn test_arm_muxgen() {
+        let path = PathBuf::from("arm/MuxGen.hdl");
+        let contents = read_hdl(&path);
+        let mut scanner = Scanner::new(contents.as_str(), path);
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        parser.parse().expect("Parse error");
+    }
+
+    #[test]
+    fn test_comments() {
+        let contents = "// Header
+CHIP Commented {
+    IN a, b; // Inputs
+    OUT out;
+    PARTS:
+    /* Invert
+       a */
+    Not(in=a, // Wire
+        out=nota); // After not
+    // Before loop
+    FOR i IN 0 TO 0 GENERATE {
+        And(a=nota, b=b, out=out);
+    } // After loop
+    // Dangling
+}
+// Footer";
+        let mut scanner = Scanner::new(contents, PathBuf::from("Commented.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let text = |comments: &[Comment]| -> Vec<String> {
+            comments.iter().map(|c| c.text.clone()).collect()
+        };
+
+        assert_eq!(text(&hdl.comments.leading), vec!["// Header"]);
+        assert_eq!(text(&hdl.comments.trailing), vec!["// Footer"]);
+        assert_eq!(text(&hdl.body_comments), vec!["// Inputs", "// Dangling"]);
+        match &hdl.parts[0] {
+            Part::Component(c) => {
+                assert_eq!(text(&c.comments.leading), vec!["/* Invert\n       a */"]);
+                assert_eq!(c.comments.leading[0].line, 6);
+                assert_eq!(c.comments.leading[0].start, 5);
+                assert_eq!(text(&c.comments.trailing), vec!["// After not"]);
+                assert_eq!(text(&c.mappings[0].comments.trailing), vec!["// Wire"]);
+                assert!(c.mappings[1].comments.trailing.is_empty());
+            }
+            _ => panic!("Expected component"),
+        }
+        match &hdl.parts[1] {
+            Part::Loop(l) => {
+                assert_eq!(text(&l.comments.leading), vec!["// Before loop"]);
+                assert_eq!(text(&l.comments.trailing), vec!["// After loop"]);
+            }
+            _ => panic!("Expected loop"),
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let path = PathBuf::from("nand2tetris/solutions/Mux.hdl");
+        let contents = read_hdl(&path);
+        let mut scanner = Scanner::new(contents.as_str(), path);
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+
+        let json = serde_json::to_string(&hdl).expect("Serialization error");
+        let round_trip: ChipHDL = serde_json::from_str(&json).expect("Deserialization error");
+        assert_eq!(round_trip.name, "Mux");
+        assert_eq!(round_trip.ports, hdl.ports);
+        assert_eq!(round_trip.parts.len(), hdl.parts.len());
+        assert_eq!(serde_json::to_string(&round_trip).unwrap(), json);
+    }
+
+    #[test]
+    fn test_identifier_span_error() {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let contents = provider.get_hdl("Mux.hdl").unwrap();
+        let mut scanner = Scanner::new(contents.as_str(), provider.get_path("Mux.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let not = match &hdl.parts[0] {
+            Part::Component(c) => c.name.clone(),
+            _ => panic!("Expected a component"),
+        };
+        assert_eq!(
+            not.span,
+            Some(Span {
+                start_line: 18,
+                start_col: 5,
+                end_line: 18,
+                end_col: 8,
+            })
+        );
+
+        let error = N2VError {
+            msg: String::from("Unknown chip"),
+            kind: ErrorKind::ParseIdentError(provider, not),
+        };
+        let message = error.to_string();
+        let mut lines = message.lines().skip(1);
+        assert_eq!(lines.next(), Some("18|     Not(in=sel, out=Notsel);"));
+        assert_eq!(lines.next(), Some("        ^^^"));
+    }
+
+    #[test]
+    fn test_did_you_mean() {
+        let names: Vec<String> = ["Mux", "Mux16", "Mux4Way16", "DMux", "And"]
+            .iter()
+            .map(|n| String::from(*n))
+            .collect();
+        assert_eq!(did_you_mean("mux", &names), " Did you mean `Mux`?");
+        assert_eq!(
+            did_you_mean("Mux6", &names),
+            " Did you mean `Mux` or `Mux16`?"
+        );
+        assert_eq!(did_you_mean("Xor", &names), "");
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("Nto", "Not"), 1);
+    }
+
+    #[test]
+    fn test_port_sections() {
+        let parse = |hdl: &str| {
+            let mut scanner = Scanner::new(hdl, PathBuf::from("Sections.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            parser.parse_recovering()
+        };
+        let directions = |hdl: &ChipHDL| -> Vec<PortDirection> {
+            hdl.ports.iter().map(|p| p.direction).collect()
+        };
+
+        let (hdl, errors) = parse("CHIP Swap { OUT out; IN a, b; PARTS: And(a=a, b=b, out=out); }");
+        assert!(errors.is_empty());
+        assert_eq!(
+            directions(&hdl.unwrap()),
+            vec![PortDirection::Out, PortDirection::In, PortDirection::In]
+        );
+
+        let (hdl, errors) = parse("CHIP One { OUT out; PARTS: Not(in=false, out=out); }");
+        assert!(errors.is_empty());
+        assert_eq!(directions(&hdl.unwrap()), vec![PortDirection::Out]);
+
+        let (hdl, errors) = parse("CHIP Sink { IN a; PARTS: Not(in=a, out=x); }");
+        assert!(errors.is_empty());
+        assert_eq!(directions(&hdl.unwrap()), vec![PortDirection::In]);
+
+        let (_, errors) =
+            parse("CHIP Twice { IN a; IN b; OUT out; PARTS: And(a=a, b=b, out=out); }");
+        assert_eq!(errors.len(), 1);
+        let (_, errors) = parse("CHIP None { PARTS: Not(in=true, out=x); }");
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_imports() {
+        let mut scanner = Scanner::new(
+            "USE \"../lib\";\nUSE \"arith\";\nCHIP A { IN a; OUT b; }",
+            PathBuf::from("A.hdl"),
+        );
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let imports: Vec<&str> = hdl.imports.iter().map(|i| i.value.as_str()).collect();
+        assert_eq!(imports, vec!["../lib", "arith"]);
+        assert_eq!(hdl.imports[1].line, Some(2));
+
+        let mut scanner = Scanner::new("USE lib;\nCHIP A { IN a; OUT b; }", PathBuf::from("A.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let (hdl, errors) = parser.parse_recovering();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(hdl.unwrap().name, "A");
+    }
+
+    #[test]
+    fn test_shadowing() {
+        let dir = tempfile::tempdir().unwrap();
+        let lib = dir.path().join("lib");
+        fs::create_dir(&lib).unwrap();
+        let chip = "CHIP Inv { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }";
+        fs::write(dir.path().join("Inv.hdl"), chip).unwrap();
+        fs::write(lib.join("Inv.hdl"), chip).unwrap();
+        fs::write(lib.join("Buf.hdl"), chip.replace("Inv", "Buf")).unwrap();
+        fs::write(dir.path().join("Memory.hdl"), chip.replace("Inv", "Memory")).unwrap();
+        let base: Arc<dyn HdlProvider> = Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let provider: Arc<dyn HdlProvider> = Arc::new(SearchPath::new(base, vec![lib.clone()]));
+
+        // The chip in the directory wins over the one in the library.
+        let hdl = get_hdl("Inv", &provider).unwrap();
+        assert_eq!(hdl.path, Some(dir.path().join("Inv.hdl")));
+        assert_eq!(
+            shadowing("Inv", &dir.path().join("Inv.hdl"), &provider, false),
+            Some(format!(
+                "Chip Inv is defined in more than one place. Using {}, which shadows {}.",
+                dir.path().join("Inv.hdl").display(),
+                lib.join("Inv.hdl").display()
+            ))
+        );
+        assert_eq!(
+            shadowing("Buf", &lib.join("Buf.hdl"), &provider, false),
+            None
+        );
+        assert!(
+            shadowing("Memory", &dir.path().join("Memory.hdl"), &provider, true)
+                .unwrap()
+                .ends_with("which shadows the built-in Memory.")
+        );
+
+        // Chips that use it warn, or fail where the lint is denied.
+        assert_eq!(
+            hdl.shadows,
+            known_shadowing(&provider, &hdl.path.clone().unwrap()).unwrap()
+        );
+        let top = "CHIP Top { IN in; OUT out; PARTS: Inv(in=in, out=out); }";
+        fs::write(dir.path().join("Top.hdl"), top).unwrap();
+        let top = get_hdl("Top", &provider).unwrap();
+        let mut options = crate::simulator::ChipOptions {
+            elaborate: true,
+            ..Default::default()
+        };
+        assert!(crate::simulator::Chip::new(&top, &provider, options.clone()).is_ok());
+        options.lints.set(Lint::ShadowedChip, Level::Deny);
+        let e = crate::simulator::Chip::new(&top, &provider, options).unwrap_err();
+        assert!(e.to_string().contains("which shadows"));
+
+        // Another provider checks the file again, and finds it shadows
+        // nothing once the library is gone.
+        let inv = dir.path().join("Inv.hdl");
+        assert!(matches!(known_shadowing(&provider, &inv), Some(Some(_))));
+        let alone: Arc<dyn HdlProvider> = Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        get_hdl("Inv", &alone).unwrap();
+        assert_eq!(known_shadowing(&alone, &inv), Some(None));
+        drop(provider);
+        known_shadowing(&alone, &inv);
+        SHADOWING.with(|s| assert!(s.borrow().iter().all(|(p, _)| p.strong_count() > 0)));
+    }
+
+    #[test]
+    fn test_constants() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(DEFS_FILE), "CONST BUSW 16;\nCONST W 2;").unwrap();
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let parse = |source: &str| {
+            let mut scanner = Scanner::new(source, dir.path().join("A.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            parser.parse()
+        };
+
+        let mut hdl = parse(
+            "CONST TOP BUSW - 1;
+            CHIP A<W> {
+                IN a[BUSW], b[W];
+                OUT out[TOP];
+                PARTS:
+                Not16(in=a[TOP..0], out[0..BUSW-2]=out);
+                FOR BUSW IN 0 TO 1 GENERATE { Not(in=b[BUSW], out=x[BUSW]); }
+            }",
+        )
+        .expect("Parse error");
+        assert_eq!(hdl.constants[0].to_string(), "CONST TOP BUSW-1;");
+        resolve_constants(&mut hdl, &provider).expect("Unresolved constant");
+        assert_eq!(hdl.ports[0].width, GenericWidth::from(16));
+        // The generic hides the constant of the same name.
+        assert_eq!(hdl.ports[1].width.variables(), vec!["W"]);
+        assert_eq!(hdl.ports[2].width, GenericWidth::from(15));
+        let parts: Vec<String> = hdl
+            .parts
+            .iter()
+            .map(|p| match p {
+                Part::Component(c) => c.to_string(),
+                Part::Loop(l) => l.body[0].to_string(),
+            })
+            .collect();
+        assert_eq!(
+            parts,
+            vec![
+                "Not16(in=a[15..0], out[0..14]=out)",
+                "Not(in=b[BUSW], out=x[BUSW])"
+            ]
+        );
+
+        let mut hdl = parse("CHIP A { IN a[BUSX]; OUT b; }").expect("Parse error");
+        let e = resolve_constants(&mut hdl, &provider)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.contains("`BUSX` is not a constant or a generic of chip A. Did you mean `BUSW`?"),
+            "{}",
+            e
+        );
+        let mut hdl = parse("CONST A B;\nCHIP A { IN a[A]; OUT b; }").expect("Parse error");
+        let e = resolve_constants(&mut hdl, &provider)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.contains("Constant A uses `B`, which is not a constant"),
+            "{}",
+            e
+        );
+
+        let mut scanner = Scanner::new("CONST A 1;\nCHIP A { IN a; OUT b; }", PathBuf::new());
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        assert!(parser.definitions().is_err());
     }
 
-    fn for_loop(&mut self) -> Result<Loop, Box<dyn Error>> {
-        self.consume(TokenType::For)?;
-        let iterator = Identifier::from(self.consume(TokenType::Identifier)?);
-        self.consume(TokenType::In)?;
-        let start = self.expr()?;
-        self.consume(TokenType::To)?;
-        let end = self.expr()?;
-        self.consume(TokenType::Generate)?;
-        self.consume(TokenType::LeftCurly)?;
-        let body = self.components()?;
+    #[test]
+    fn test_interfaces() {
+        let parse = |source: &str| {
+            let mut scanner = Scanner::new(source, PathBuf::from("Alu.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            parser.parse_recovering()
+        };
+        let (hdl, errors) = parse("INTERFACE Alu<W> { IN x[W], y[W]; OUT out[W]; }");
+        assert!(errors.is_empty());
+        let hdl = hdl.unwrap();
+        assert_eq!(hdl.interface.as_ref().unwrap().value, "INTERFACE");
+        assert_eq!(hdl.port_names(), vec!["x", "y", "out"]);
+        assert_eq!(
+            dialect_errors(
+                &parse("INTERFACE Alu { IN x; OUT out; }").0.unwrap(),
+                Dialect::Nand2Tetris
+            )[0]
+            .msg,
+            "nand2tetris does not support interfaces, which are a whidl extension."
+        );
 
-        Ok(Loop {
-            start,
-            end,
-            iterator,
-            body,
-        })
+        let (_, errors) = parse("INTERFACE Alu { IN x; OUT out; PARTS: Not(in=x, out=out); }");
+        assert_eq!(
+            errors[0].msg,
+            "Interface Alu can only declare ports. Its implementations go in files such as Alu.fast.hdl."
+        );
     }
 
-    fn expr(&mut self) -> Result<GenericWidth, Box<dyn Error>> {
-        let t1 = self.terminal()?;
-
-        let peeked = self.scanner.peek().unwrap();
-        if peeked.token_type == TokenType::Plus {
-            self.scanner.next();
-            let t2 = self.terminal()?;
-            Ok(GenericWidth::Expr(
-                Op::Add,
-                Box::new(GenericWidth::Terminal(t1)),
-                Box::new(GenericWidth::Terminal(t2)),
-            ))
-        } else if peeked.token_type == TokenType::Minus {
-            self.scanner.next();
-            let t2 = self.terminal()?;
-            Ok(GenericWidth::Expr(
-                Op::Sub,
-                Box::new(GenericWidth::Terminal(t1)),
-                Box::new(GenericWidth::Terminal(t2)),
-            ))
-        } else {
-            Ok(GenericWidth::Terminal(t1))
+    #[test]
+    fn test_qualified_names() {
+        let mut scanner = Scanner::new(
+            "CHIP A { IN a[16]; OUT b[16]; PARTS: std.Not16(in=a, out=b); }",
+            PathBuf::from("A.hdl"),
+        );
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        match &hdl.parts[0] {
+            Part::Component(c) => {
+                assert_eq!(c.name.value, "Not16");
+                assert_eq!(c.namespace.as_ref().unwrap().value, "std");
+                assert_eq!(c.to_string(), "std.Not16(in=a, out=b)");
+            }
+            Part::Loop(_) => panic!("Expected a component"),
         }
     }
 
-    fn terminal(&mut self) -> Result<Terminal, Box<dyn Error>> {
-        let width_token = self.scanner.next().unwrap();
-        let width = match width_token.token_type {
-            TokenType::Number => Terminal::Num(width_token.lexeme.parse::<usize>().unwrap()),
-            TokenType::Identifier => Terminal::Var(Identifier::from(width_token)),
-            _ => {
-                return Err(Box::new(N2VError {
-                    msg: String::from("Expected number or generic var for port width."),
-                    kind: ErrorKind::ParseError(width_token),
-                }));
-            }
+    #[test]
+    fn test_width_functions() {
+        let mut scanner = Scanner::new(
+            "CHIP RAMGen<DEPTH, W> {
+                IN address[log2(DEPTH)], in[max(W, 1)];
+                OUT out[min(W, 16) - 1];
+                PARTS:
+            }",
+            PathBuf::from("RAMGen.hdl"),
+        );
+        let mut parser = Parser {
+            scanner: &mut scanner,
         };
-        Ok(width)
+        let hdl = parser.parse().expect("Parse error");
+        let widths: Vec<String> = hdl.ports.iter().map(|p| hdl_expr(&p.width)).collect();
+        assert_eq!(widths, vec!["log2(DEPTH)", "max(W, 1)", "min(W, 16)-1"]);
     }
 
-    fn component(&mut self) -> Result<Component, Box<dyn Error>> {
-        Ok(Component {
-            name: Identifier::from(self.scanner.next().unwrap()),
-            generic_params: self.generics()?,
-            mappings: self.port_mappings()?,
-        })
+    #[test]
+    fn test_width_conditions() {
+        let parse = |source: &str| {
+            let mut scanner = Scanner::new(source, PathBuf::from("Pick.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            parser.parse()
+        };
+        let hdl = parse(
+            "CHIP Pick<W> {
+                IN a[if(W > 8, 2, 1)], b[if(W>=8, W, 8)], c[if(W == 1, 1, W - 1)];
+                OUT out[if(W != 2, max(W, 4), 2)], low[if(W < 4, 1, 0)], high[if(W <= 4, 0, 1)];
+                PARTS:
+            }",
+        )
+        .expect("Parse error");
+        let widths: Vec<String> = hdl.ports.iter().map(|p| hdl_expr(&p.width)).collect();
+        assert_eq!(
+            widths,
+            vec![
+                "if(W > 8, 2, 1)",
+                "if(W >= 8, W, 8)",
+                "if(W == 1, 1, W-1)",
+                "if(W != 2, max(W, 4), 2)",
+                "if(W < 4, 1, 0)",
+                "if(W <= 4, 0, 1)",
+            ]
+        );
+
+        let e = parse("CHIP Pick<W> { IN a[if(W = 8, 2, 1)]; OUT out; PARTS: }");
+        assert!(e
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("Expected a comparison: <, <=, >, >=, == or !=."));
     }
 
-    fn port_width(&mut self) -> Result<GenericWidth, Box<dyn Error>> {
-        let peeked = self.scanner.peek().unwrap();
-        if peeked.token_type != TokenType::LeftBracket {
-            return Ok(GenericWidth::Terminal(Terminal::Num(1)));
+    #[test]
+    fn test_annotations() {
+        let parse = |source: &str| {
+            let mut scanner = Scanner::new(source, PathBuf::from("A.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            parser.parse()
+        };
+        let hdl = parse(
+            "@doc(\"Memory.\") @vendor(\"a\", \"b\")
+            CHIP A { IN a; OUT b; PARTS: @keep Not(in=a, out=b); }",
+        )
+        .expect("Parse error");
+        assert_eq!(hdl.annotations.len(), 2);
+        assert_eq!(hdl.annotations[1].to_string(), "@vendor(\"a\", \"b\")");
+        assert_eq!(
+            find_annotation(&hdl.annotations, "doc").unwrap().args,
+            vec!["Memory."]
+        );
+        match &hdl.parts[0] {
+            Part::Component(c) => assert_eq!(c.annotations[0].to_string(), "@keep"),
+            Part::Loop(_) => panic!("Expected a component"),
         }
 
-        self.consume(TokenType::LeftBracket)?;
-        let width = self.expr()?;
-        self.consume(TokenType::RightBracket)?;
-
-        Ok(width)
+        let e = parse("@keep(\"x\") CHIP A { IN a; OUT b; PARTS: }")
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("@keep takes 0 arguments, not 1."));
     }
 
-    fn bus_idx(&mut self) -> Result<(Option<GenericWidth>, Option<GenericWidth>), Box<dyn Error>> {
-        let peeked = self.scanner.peek();
+    #[test]
+    fn test_string_generics() {
+        let parse = |source: &str| {
+            let mut scanner = Scanner::new(source, PathBuf::from("A.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            parser.parse().expect("Parse error")
+        };
+        let mem = parse("CHIP Mem<W, FILE> { IN a; OUT b[W]; BUILTIN ROM; }");
+        let hdl = parse(
+            "CHIP A { IN a; OUT b[8], c[8]; PARTS:
+            Mem<8, FILE=\"boot.hack\">(a=a, b=b);
+            Mem<FILE=\"x.hack\", W=8>(a=a, b=c);
+            Mem<FILE=\"x.hack\", 8>(a=a, b=c);
+            }",
+        );
+        let part = |i: usize| match &hdl.parts[i] {
+            Part::Component(c) => c,
+            Part::Loop(_) => panic!("Expected a component"),
+        };
+        assert_eq!(
+            part(0).generic_params[1].value,
+            GenericValue::Str(String::from("boot.hack"))
+        );
+        assert_eq!(part(0).to_string(), "Mem<8, FILE=\"boot.hack\">(a=a, b=b)");
 
-        if let Token {
-            token_type: TokenType::LeftBracket,
-            ..
-        } = peeked.unwrap()
-        {
-            self.consume(TokenType::LeftBracket)?;
-            let start = self.expr()?;
+        let args = part(1).generic_args(&mem).unwrap();
+        assert_eq!(args[0].0.value, "W");
+        assert_eq!(*args[0].1, GenericValue::from(8));
+        assert_eq!(*args[1].1, GenericValue::Str(String::from("x.hack")));
+        let e = part(2).generic_args(&mem).err().unwrap();
+        assert!(e
+            .msg
+            .contains("Positional generic 2 of Mem must come before the named ones."));
+    }
 
-            let end = if let Token {
-                token_type: TokenType::Dot,
-                ..
-            } = self.scanner.peek().unwrap()
-            {
-                self.consume(TokenType::Dot)?;
-                self.consume(TokenType::Dot)?;
-                self.expr()?
-            } else {
-                start.clone()
+    #[test]
+    fn test_part_arrays() {
+        let parse = |source: &str| {
+            let mut scanner = Scanner::new(source, PathBuf::from("A.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
             };
+            parser.parse()
+        };
+        let hdl = parse(
+            "CHIP A<W> { IN a[W]; OUT b[W], c[8]; PARTS:
+            Not[W](in=a[i], out=b[i]);
+            Not[8](in=a[i], out=c[i]);
+            }",
+        )
+        .expect("Parse error");
+        let array = |i: usize| match &hdl.parts[i] {
+            Part::Loop(l) => l,
+            Part::Component(_) => panic!("Expected a loop"),
+        };
+        assert!(array(0).array);
+        assert_eq!(array(0).iterator.value, "i");
+        assert_eq!(hdl_expr(&array(0).end), "W-1");
+        assert_eq!(array(1).end, GenericWidth::from(7));
+        assert_eq!(array(1).body[0].to_string(), "Not(in=a[i], out=c[i])");
 
-            self.consume(TokenType::RightBracket)?;
-            Ok((Some(start), Some(end)))
-        } else {
-            Ok((None, None))
-        }
+        let e = parse("CHIP A { IN a; OUT b; PARTS: Not[0](in=a, out=b); }");
+        assert!(e
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("An array of parts needs at least one part."));
+        let e = parse(
+            "CHIP A { IN a[2]; OUT b[2]; PARTS:
+            FOR j IN 0 TO 1 GENERATE { Not[2](in=a[i], out=b[i]); }
+            }",
+        );
+        assert!(e
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("Arrays of parts are not allowed in FOR loops."));
     }
 
-    fn port_mappings(&mut self) -> Result<Vec<PortMapping>, Box<dyn Error>> {
-        let mut mappings = Vec::new();
+    #[test]
+    fn test_dialect() {
+        let source = "CHIP Mux8<W> {
+            IN a[W], b[W], sel;
+            OUT out[W];
+            PARTS:
+            FOR i IN 0 TO W-1 GENERATE {
+                std.Mux(a=a[i], b=b[i], sel=sel, out=out[i]);
+            }
+        }";
+        let nand2tetris = ParserOptions {
+            dialect: Dialect::Nand2Tetris,
+        };
+        let mut scanner = Scanner::new(source, PathBuf::from("Mux8.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser
+            .parse_with(&ParserOptions::default())
+            .expect("Parse error");
+        let errors: Vec<String> = dialect_errors(&hdl, Dialect::Nand2Tetris)
+            .into_iter()
+            .map(|e| e.msg)
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "nand2tetris does not support generics, which are a whidl extension.",
+                "nand2tetris does not support `for` loops, which are a whidl extension.",
+                "nand2tetris does not support libraries, which are a whidl extension.",
+            ]
+        );
+        let mut scanner = Scanner::new(source, PathBuf::from("Mux8.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        assert!(parser.parse_with(&nand2tetris).is_err());
 
-        self.consume(TokenType::LeftParen)?;
-        loop {
-            let next = self.scanner.next();
-            match &next {
-                Some(
-                    t @ Token {
-                        token_type: TokenType::Identifier,
-                        ..
-                    },
-                ) => {
-                    let (port_start, port_end) = self.bus_idx()?;
-                    self.consume(TokenType::Equal)?;
-                    let wire = self.consume(TokenType::Identifier)?;
-                    let (wire_start, wire_end) = self.bus_idx()?;
+        let mut scanner = Scanner::new(
+            "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }",
+            PathBuf::from("Not.hdl"),
+        );
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        assert!(parser.parse_with(&nand2tetris).is_ok());
+        assert_eq!("nand2tetris".parse(), Ok(Dialect::Nand2Tetris));
+    }
 
-                    mappings.push(PortMapping {
-                        wire_ident: Identifier::from(t.clone()),
-                        wire: BusHDL {
-                            name: wire.lexeme,
-                            start: wire_start,
-                            end: wire_end,
-                        },
-                        port: BusHDL {
-                            name: t.lexeme.clone(),
-                            start: port_start,
-                            end: port_end,
-                        },
-                    });
+    #[test]
+    fn test_stimulus() {
+        let parse = |source: &str| {
+            let mut scanner = Scanner::new(source, PathBuf::from("Bench.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            parser.parse()
+        };
+        let hdl = parse("CHIP Bench { STIMULUS 8 { a[4] = COUNTER; b = RANDOM 0x2A; } PARTS: }")
+            .expect("Parse error");
+        let stimulus = hdl.stimulus.unwrap();
+        assert_eq!(stimulus.cycles, 8);
+        assert_eq!(stimulus.signals[0].to_string(), "a[4] = COUNTER");
+        assert_eq!(stimulus.signals[1].generator, Generator::Random(42));
 
-                    let peeked_type = self.scanner.peek().unwrap().token_type;
-                    match peeked_type {
-                        TokenType::Comma | TokenType::RightParen => {}
-                        _ => {
-                            let found_t = self.scanner.peek().unwrap();
-                            let found = found_t.lexeme.clone();
-                            return Err(Box::new(N2VError {
-                                msg: format!("Expected comma or right paren, found {}", found),
-                                kind: ErrorKind::ParseError(found_t),
-                            }));
-                        }
-                    }
-                }
-                Some(Token {
-                    token_type: TokenType::Comma,
-                    ..
-                }) => {
-                    continue;
-                }
-                Some(Token {
-                    token_type: TokenType::RightParen,
-                    ..
-                }) => {
-                    break;
-                }
-                Some(t) => {
-                    return Err(Box::new(N2VError {
-                        msg: String::from("Expected comma, or right paren"),
-                        kind: ErrorKind::ParseError(t.clone()),
-                    }));
-                }
-                None => {
-                    return Err(Box::new(N2VError {
-                        msg: String::from("Unexpected end of file. Expected comma or right paren."),
-                        kind: ErrorKind::ParseError(Token {
-                            lexeme: String::from(""),
-                            path: self.scanner.path.clone(),
-                            line: self.scanner.line,
-                            start: self.scanner.col,
-                            token_type: TokenType::Eof,
-                        }),
-                    }));
-                }
+        for (source, message) in [
+            (
+                "CHIP B { STIMULUS 8 { a = RANDOM 0; } }",
+                "seed cannot be zero",
+            ),
+            (
+                "CHIP B { STIMULUS 8 { a = STEP; } }",
+                "`STEP` is not a stimulus",
+            ),
+            ("CHIP B { STIMULUS 8 { a[65] = COUNTER; } }", "1 to 64 bits"),
+        ] {
+            match parse(source) {
+                Ok(_) => panic!("Expected an error for {}", source),
+                Err(e) => assert!(e.to_string().contains(message), "{}", e),
             }
         }
-
-        self.consume(TokenType::Semicolon)?;
-
-        Ok(mappings)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    #[test]
+    fn test_expression_parts() {
+        let parse = |source: &str| {
+            let mut scanner = Scanner::new(source, PathBuf::from("Glue.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            parser.parse()
+        };
+        let hdl = parse(
+            "CHIP Glue {
+                IN a, b, c[2];
+                OUT out, x[4];
+                PARTS:
+                out = a AND (b OR NOT c[0]);
+                FOR i IN 0 TO 3 GENERATE {
+                    x[i] = NOT (a XOR b);
+                }
+            }",
+        )
+        .expect("Parse error");
+        let parts: Vec<&Component> = hdl
+            .parts
+            .iter()
+            .filter_map(|p| match p {
+                Part::Component(c) => Some(c),
+                _ => None,
+            })
+            .collect();
+        // Two for AND, and two for OR as NOT b NAND c[0].
+        assert_eq!(parts.len(), 4);
+        assert!(parts.iter().all(|c| c.name.value == "Nand"));
+        let a = parts[0].expression.as_ref().unwrap();
+        assert_eq!(a.to_string(), "out = a AND (b OR NOT c[0])");
+        assert_eq!(parts[0].to_string(), "Nand(a=b, b=b, out=expr_5_17_1)");
+        assert_eq!(
+            parts[3].to_string(),
+            "Nand(a=expr_5_17_3, b=expr_5_17_3, out=out)"
+        );
 
-    use std::env;
-    use std::fs;
-    use std::path::Path;
+        let body = match &hdl.parts[4] {
+            Part::Loop(l) => &l.body,
+            _ => panic!("Expected a loop"),
+        };
+        assert_eq!(body.len(), 5);
+        assert_eq!(
+            body[0].expression.as_ref().unwrap().to_string(),
+            "x[i] = NOT (a XOR b)"
+        );
+        assert_eq!(body[0].to_string(), "Nand(a=a, b=b, out=expr_7_21_1[i])");
 
-    fn read_hdl(path: &std::path::Path) -> String {
-        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
-        let test_file = manifest_dir.join("resources").join("tests").join(path);
+        let expr = |source: &str| -> Result<String, Box<dyn Error>> {
+            let hdl = parse(&format!(
+                "CHIP E {{ IN a, b, c; OUT out; PARTS: {} }}",
+                source
+            ))?;
+            match &hdl.parts[0] {
+                Part::Component(c) => Ok(c.expression.as_ref().unwrap().to_string()),
+                _ => panic!("Expected a component"),
+            }
+        };
+        assert_eq!(
+            expr("out = (a OR b) AND c;").unwrap(),
+            "out = (a OR b) AND c"
+        );
+        assert_eq!(expr("out = a OR b AND c;").unwrap(), "out = a OR b AND c");
+        assert_eq!(expr("out = NOT NOT a;").unwrap(), "out = NOT NOT a");
+        assert_eq!(
+            expr("out = a XOR (b XOR c);").unwrap(),
+            "out = a XOR (b XOR c)"
+        );
+        assert!(expr("out = a AND OR b;")
+            .unwrap_err()
+            .to_string()
+            .contains("Expected a wire before"));
 
-        fs::read_to_string(test_file).expect("Unable to read test file.")
+        let errors: Vec<String> = dialect_errors(&hdl, Dialect::Nand2Tetris)
+            .into_iter()
+            .map(|e| e.msg)
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "nand2tetris does not support expression parts, which are a whidl extension.",
+                "nand2tetris does not support `for` loops, which are a whidl extension.",
+                "nand2tetris does not support expression parts, which are a whidl extension.",
+            ]
+        );
     }
 
     #[test]
-    fn test_nand2tetris_solution_mux() {
-        let path = PathBuf::from("nand2tetris/solutions/Mux.hdl");
-        let contents = read_hdl(&path);
-        let mut scanner = Scanner::new(contents.as_str(), path);
-        let mut parser = Parser {
-            scanner: &mut scanner,
+    fn test_table() {
+        let parse = |source: &str| {
+            let mut scanner = Scanner::new(source, PathBuf::from("Xor.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            parser.parse()
         };
-        parser.parse().expect("Parse error");
+        let hdl = parse(
+            "CHIP Xor { IN a, b; OUT out; TABLE { 0 0 -> 0; 01 -> 1; 1 0 -> 1; 1 1 -> 0; } }",
+        )
+        .expect("Parse error");
+        assert!(hdl.parts.is_empty());
+        let table = hdl.table.unwrap();
+        assert_eq!(table.rows.len(), 4);
+        assert_eq!(table.rows[1].to_string(), "01 -> 1");
+        assert_eq!(table.rows[2].input_bits(), "10");
+        assert!(table.check_widths("Xor", 2, 1).is_ok());
+        assert!(table.check_widths("Xor", 3, 1).is_err());
+
+        for (source, message) in [
+            (
+                "CHIP B { IN a; OUT out; TABLE { 0 -> 1; 0 -> 0; } }",
+                "more than one row",
+            ),
+            (
+                "CHIP B { IN a; OUT out; TABLE { 2 -> 1; } }",
+                "`2` is not a group of bits",
+            ),
+            (
+                "CHIP B { IN a; OUT out; TABLE { 0 -> 1; } PARTS: }",
+                "I expected to see a right curly",
+            ),
+        ] {
+            match parse(source) {
+                Ok(_) => panic!("Expected an error for {}", source),
+                Err(e) => assert!(e.to_string().contains(message), "{}", e),
+            }
+        }
     }
 
     #[test]
-    fn test_nand2tetris_solution_not16() {
-        let path = PathBuf::from("nand2tetris/solutions/Not16.hdl");
-        let contents = read_hdl(&path);
-        let mut scanner = Scanner::new(contents.as_str(), path);
-        let mut parser = Parser {
-            scanner: &mut scanner,
-        };
-        parser.parse().expect("Parse error");
+    fn test_stub() {
+        for hdl in [
+            "CHIP Stub { IN a; OUT out; }",
+            "CHIP Stub { IN a; OUT out; PARTS: }",
+        ] {
+            let mut scanner = Scanner::new(hdl, PathBuf::from("Stub.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse().expect("Parse error");
+            assert_eq!(hdl.ports.len(), 2);
+            assert!(hdl.parts.is_empty());
+        }
     }
 
     #[test]
-    fn test_nand2tetris_solution_and16() {
-        let path = PathBuf::from("nand2tetris/solutions/And16.hdl");
-        let contents = read_hdl(&path);
-        let mut scanner = Scanner::new(contents.as_str(), path);
-        let mut parser = Parser {
-            scanner: &mut scanner,
+    fn test_error_kinds() {
+        let parse = |hdl: &str| {
+            let mut scanner = Scanner::new(hdl, PathBuf::from("Kind.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            parser.parse()
         };
-        parser.parse().expect("Parse error");
+        assert!(matches!(
+            parse("CHIP Kind { IN a; OUT out; /* PARTS: }"),
+            Err(WhidlError::Scan(_))
+        ));
+        assert!(matches!(
+            parse("CHIP Kind { IN a OUT out; PARTS: }"),
+            Err(WhidlError::Parse(_))
+        ));
+
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new("./"));
+        assert!(matches!(
+            get_hdl("NoSuchChip", &provider),
+            Err(WhidlError::Io(_))
+        ));
     }
 
     #[test]
-    fn test_nand2tetris_solution_or8way() {
-        let path = PathBuf::from("nand2tetris/solutions/Or8Way.hdl");
-        let contents = read_hdl(&path);
-        let mut scanner = Scanner::new(contents.as_str(), path);
-        let mut parser = Parser {
-            scanner: &mut scanner,
-        };
-        parser.parse().expect("Parse error");
+    fn test_memory_reader() {
+        let mut reader = MemoryReader::default();
+        reader.insert(
+            "Not.hdl",
+            "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }",
+        );
+        reader.insert(
+            "Buf.hdl",
+            "CHIP Buf { IN in; OUT out; PARTS: Not(in=in, out=x); Not(in=x, out=out); }",
+        );
+        let mut names = reader.chip_names();
+        names.sort();
+        assert_eq!(names, vec!["Buf", "Not"]);
+
+        let provider: Arc<dyn HdlProvider> = Arc::new(reader);
+        let hdl = get_hdl("Buf", &provider).unwrap();
+        let chip = crate::elaborate::elaborate(&hdl, &[], &provider).unwrap();
+        assert_eq!(chip.instances.len(), 2);
+        assert!(chip.warnings.is_empty());
+        assert!(matches!(
+            get_hdl("Missing", &provider),
+            Err(WhidlError::Io(_))
+        ));
     }
 
     #[test]
-    fn test_nand2tetris_solution_not() {
-        let path = PathBuf::from("nand2tetris/solutions/Not.hdl");
-        let contents = read_hdl(&path);
-        let mut scanner = Scanner::new(contents.as_str(), path);
-        let mut parser = Parser {
-            scanner: &mut scanner,
-        };
-        parser.parse().expect("Parse error");
+    fn test_zip_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("project.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        let files = [
+            (
+                "project/01/Not.hdl",
+                "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }",
+            ),
+            (
+                "project/01/Buf.hdl",
+                "CHIP Buf { IN in; OUT out; PARTS: Not(in=in, out=x); Not(in=x, out=out); }",
+            ),
+            ("project/01/Bad.hdl", "CHIP Bad { IN in OUT out; PARTS: }"),
+            (
+                "project/02/And.hdl",
+                "CHIP And { IN a, b; OUT out; PARTS: }",
+            ),
+        ];
+        for (name, source) in files {
+            zip.start_file(name, options).unwrap();
+            std::io::Write::write_all(&mut zip, source.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let reader = ZipReader::open(&archive, "project/01").unwrap();
+        let mut names = reader.chip_names();
+        names.sort();
+        assert_eq!(names, vec!["Bad", "Buf", "Not"]);
+
+        let provider: Arc<dyn HdlProvider> = Arc::new(reader);
+        let hdl = get_hdl("Buf", &provider).unwrap();
+        assert_eq!(hdl.path, Some(archive.join("project/01/Buf.hdl")));
+        let chip = crate::elaborate::elaborate(&hdl, &[], &provider).unwrap();
+        assert_eq!(chip.instances.len(), 2);
+
+        // Errors name the file in the archive.
+        match get_hdl("Bad", &provider) {
+            Err(WhidlError::Parse(e)) => match e.kind {
+                ErrorKind::ParseError(t) => {
+                    assert_eq!(t.path, archive.join("project/01/Bad.hdl"))
+                }
+                _ => panic!("Expected a parse error"),
+            },
+            _ => panic!("Expected a parse error"),
+        }
+        assert!(get_hdl("And", &provider)
+            .err()
+            .unwrap()
+            .msg()
+            .contains(&archive.join("project/01/And.hdl").display().to_string()));
+
+        assert!(ZipReader::open(&dir.path().join("missing.zip"), "").is_err());
     }
 
     #[test]
-    fn test_nand2tetris_solution_alu() {
-        let path = PathBuf::from("nand2tetris/solutions/ALU.hdl");
-        let contents = read_hdl(&path);
-        let mut scanner = Scanner::new(contents.as_str(), path);
-        let mut parser = Parser {
-            scanner: &mut scanner,
-        };
-        parser.parse().expect("Parse error");
+    fn test_chained_provider() {
+        let mut student = MemoryReader::default();
+        student.insert(
+            "Not.hdl",
+            "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }",
+        );
+        let mut course = MemoryReader::default();
+        course.insert("Not.hdl", "CHIP Not { IN in; OUT out; PARTS: }");
+        course.insert(
+            "Buf.hdl",
+            "CHIP Buf { IN in; OUT out; PARTS: Not(in=in, out=x); Not(in=x, out=out); }",
+        );
+        let chain = ChainedProvider::new(vec![
+            (String::from("student"), Arc::new(student)),
+            (String::from("course"), Arc::new(course)),
+            (String::from("whidl"), Arc::new(LibraryReader)),
+        ]);
+        assert_eq!(chain.layer("Not.hdl"), Some("student"));
+        assert_eq!(chain.layer("Buf.hdl"), Some("course"));
+        assert_eq!(chain.layer("Screen.hdl"), Some("whidl"));
+        assert_eq!(chain.layer("Missing.hdl"), None);
+        assert_eq!(chain.chip_names().iter().filter(|n| *n == "Not").count(), 1);
+
+        // The course's Buf uses the student's Not.
+        let provider: Arc<dyn HdlProvider> = Arc::new(chain);
+        assert!(provider.get_hdl("Not.hdl").unwrap().contains("Nand"));
+        let hdl = get_hdl("Buf", &provider).unwrap();
+        let chip = crate::elaborate::elaborate(&hdl, &[], &provider).unwrap();
+        assert!(chip.warnings.is_empty());
+        assert_eq!(
+            provider.get_hdl("Missing.hdl").unwrap_err().to_string(),
+            "Unable to get HDL for Missing.hdl. It is in none of student, course, whidl."
+        );
     }
 
     #[test]
-    fn test_arm_muxgen() {
-        let path = PathBuf::from("arm/MuxGen.hdl");
-        let contents = read_hdl(&path);
-        let mut scanner = Scanner::new(contents.as_str(), path);
-        let mut parser = Parser {
-            scanner: &mut scanner,
-        };
-        parser.parse().expect("Parse error");
+    fn test_parse_cached() {
+        let path = PathBuf::from("/cache/Edited.hdl");
+        let source = |n: &str| format!("CHIP Edited {{ IN a; OUT {}; PARTS: }}", n);
+        let cached = || PARSED.with(|p| p.borrow().len());
+        let before = cached();
+        for n in ["out", "b", "out", "b"] {
+            let hdl = parse_cached(&source(n), path.clone()).unwrap();
+            assert_eq!(hdl.ports[1].name.value, n);
+        }
+        // Each edit replaces the chip parsed before it.
+        assert_eq!(cached(), before + 1);
+    }
+
+    #[test]
+    fn test_lib_path() {
+        let student = tempfile::tempdir().unwrap();
+        let course = tempfile::tempdir().unwrap();
+        fs::write(
+            student.path().join("Top.hdl"),
+            "CHIP Top { IN a, b; OUT out; PARTS: And(a=a, b=b, out=x); TriState(in=x, enable=a, out=out); }",
+        )
+        .unwrap();
+        fs::write(
+            course.path().join("And.hdl"),
+            "CHIP And { IN a, b; OUT out; PARTS: Nand(a=a, b=b, out=x); Nand(a=x, b=x, out=out); }",
+        )
+        .unwrap();
+        let student_dir = student.path().to_str().unwrap();
+        let course_dir = course.path().to_str().unwrap();
+        assert!(lib_path_layers(student_dir, &[]).is_none());
+        let chain = lib_path_layers(student_dir, &[String::from(course_dir)]).unwrap();
+        assert_eq!(chain.layer("Top.hdl"), Some(student_dir));
+        assert_eq!(chain.layer("And.hdl"), Some(course_dir));
+        assert_eq!(chain.layer("TriState.hdl"), Some("whidl"));
+
+        let provider: Arc<dyn HdlProvider> = Arc::new(chain);
+        let hdl = get_hdl("Top", &provider).unwrap();
+        crate::elaborate::elaborate(&hdl, &[], &provider).unwrap();
+        // A builtin read through the library layer shadows nothing.
+        get_hdl("TriState", &provider).unwrap();
+        SHADOWING.with(|s| {
+            let s = s.borrow();
+            let (_, found) = s
+                .iter()
+                .find(|(p, _)| std::ptr::addr_eq(p.as_ptr(), Arc::as_ptr(&provider)))
+                .unwrap();
+            assert!(found.values().all(|msg| msg.is_none()));
+        });
+    }
+
+    #[test]
+    fn test_caching_provider() {
+        // Counts the files it is asked for.
+        struct Counting {
+            files: MemoryReader,
+            reads: AtomicUsize,
+        }
+        impl HdlProvider for Counting {
+            fn get_hdl(&self, file_name: &str) -> Result<String, ProviderError> {
+                self.reads.fetch_add(1, Ordering::Relaxed);
+                self.files.get_hdl(file_name)
+            }
+            fn get_path(&self, file_name: &str) -> PathBuf {
+                self.files.get_path(file_name)
+            }
+        }
+
+        let mut files = MemoryReader::default();
+        files.insert(
+            "Not.hdl",
+            "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }",
+        );
+        files.insert(
+            "Buf.hdl",
+            "CHIP Buf { IN in; OUT out; PARTS: Not(in=in, out=x); Not(in=x, out=out); }",
+        );
+        let counting = Arc::new(Counting {
+            files,
+            reads: AtomicUsize::new(0),
+        });
+        let provider: Arc<dyn HdlProvider> = Arc::new(CachingProvider::new(counting.clone()));
+        for _ in 0..3 {
+            let hdl = get_hdl("Buf", &provider).unwrap();
+            crate::elaborate::elaborate(&hdl, &[], &provider).unwrap();
+        }
+        let reads = counting.reads.load(Ordering::Relaxed);
+        assert_eq!(get_hdl("Buf", &provider).unwrap().name, "Buf");
+        assert_eq!(counting.reads.load(Ordering::Relaxed), reads);
+
+        // Missing files are remembered too.
+        assert!(provider.get_hdl("Missing.hdl").is_err());
+        let reads = counting.reads.load(Ordering::Relaxed);
+        assert!(provider.get_hdl("Missing.hdl").unwrap_err().is_not_found());
+        assert_eq!(counting.reads.load(Ordering::Relaxed), reads);
+    }
+
+    #[test]
+    fn test_file_resolution() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = [
+            (
+                "not.hdl",
+                "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }",
+            ),
+            (
+                "Buf.whidl",
+                "CHIP Buf { IN in; OUT out; PARTS: Not(in=in, out=x); Not(in=x, out=out); }",
+            ),
+            ("Mux.hdl.txt", ""),
+            ("Mux.tst", ""),
+            ("AND.hdl", ""),
+            ("and.hdl", ""),
+        ];
+        for (name, source) in files {
+            fs::write(dir.path().join(name), source).unwrap();
+        }
+        let reader = FileReader::new(dir.path().to_str().unwrap());
+        assert_eq!(reader.get_path("Not.hdl"), dir.path().join("not.hdl"));
+        assert_eq!(reader.get_path("Buf.hdl"), dir.path().join("Buf.whidl"));
+        let mut names = reader.chip_names();
+        names.sort();
+        assert_eq!(names, vec!["AND", "Buf", "and", "not"]);
+
+        let provider: Arc<dyn HdlProvider> = Arc::new(reader);
+        let hdl = get_hdl("Buf", &provider).unwrap();
+        assert_eq!(hdl.path, Some(dir.path().join("Buf.whidl")));
+        let chip = crate::elaborate::elaborate(&hdl, &[], &provider).unwrap();
+        assert!(chip.warnings.is_empty());
+
+        let error = |file_name: &str| provider.get_hdl(file_name).unwrap_err().to_string();
+        assert!(error("Mux.hdl").ends_with(
+            "No file named Mux ending in .hdl or .whidl is in the directory. \
+             Files with similar names: `Mux.hdl.txt`, `Mux.tst`."
+        ));
+        assert!(error("And.hdl").ends_with(
+            "2 files match it when case is ignored: `AND.hdl`, `and.hdl`. Rename all but one."
+        ));
+
+        let hdl_only = FileReader::new(dir.path().to_str().unwrap()).with_extensions(&["hdl"]);
+        assert!(hdl_only.get_hdl("Buf.hdl").is_err());
     }
 }
@@ -0,0 +1,808 @@
+// This module prints a parsed chip as canonical HDL. Layout comes from the
+// parse tree, while the tokens of the original source are used to keep
+// comments in place and to preserve blank lines between parts.
+
+use std::collections::HashSet;
+
+use crate::expr::{GenericWidth, Op};
+use crate::parser::*;
+use crate::scanner::{Comment, Token, TokenType};
+
+const INDENT: usize = 4;
+
+/// Formats a chip with consistent indentation. Components written over
+/// several lines get one port mapping per line with aligned comments.
+///
+/// `chip` - Parse tree of the chip, including its comments.
+/// `tokens` - Tokens of the source the chip was parsed from. May be empty,
+/// in which case blank lines are not preserved.
+pub fn format(chip: &ChipHDL, tokens: &[Token]) -> String {
+    let mut occupied: HashSet<u32> = tokens.iter().map(|t| t.line).collect();
+    for c in all_comments(chip) {
+        let lines = c.text.matches('\n').count() as u32;
+        occupied.extend(c.line..=c.line + lines);
+    }
+    let mut printer = Printer {
+        out: String::new(),
+        occupied,
+        opened: true,
+        expression: None,
+    };
+
+    let line_of = |tt: TokenType| tokens.iter().find(|t| t.token_type == tt).map(|t| t.line);
+    let parts_line = line_of(TokenType::Parts);
+    let (header_comments, footer_comments): (Vec<&Comment>, Vec<&Comment>) = chip
+        .body_comments
+        .iter()
+        .partition(|c| parts_line.is_none_or(|l| c.line < l));
+
+    // Imports and constants come first, with the comments around them in
+    // place.
+    let mut leading = chip.comments.leading.iter().peekable();
+    for import in &chip.imports {
+        let line = import.line.unwrap_or(0);
+        printer.comments(0, std::iter::from_fn(|| leading.next_if(|c| c.line < line)));
+        printer.separate(line);
+        printer.line(0, &format!("USE \"{}\";", import.value));
+    }
+    for constant in &chip.constants {
+        let line = constant.name.line.unwrap_or(0);
+        printer.comments(0, std::iter::from_fn(|| leading.next_if(|c| c.line < line)));
+        printer.separate(line);
+        printer.line(0, &constant.to_string());
+    }
+    printer.comments(0, leading);
+    printer.annotations(0, &chip.annotations);
+    if let Some(l) = line_of(TokenType::Chip).or(line_of(TokenType::Interface)) {
+        printer.separate(l);
+    }
+
+    // Comments before each line of the declaration and after it on the
+    // same line.
+    let mut header_comments = header_comments.into_iter().peekable();
+    let lines: Vec<HeaderLine> = header(chip, tokens)
+        .into_iter()
+        .map(|(source, indent, text)| {
+            let mut line = HeaderLine {
+                source,
+                text: format!("{}{}", " ".repeat(indent), text),
+                before: Vec::new(),
+                trailing: Vec::new(),
+            };
+            if let Some(s) = source {
+                line.before.extend(std::iter::from_fn(|| {
+                    header_comments.next_if(|c| c.line < s)
+                }));
+                line.trailing.extend(std::iter::from_fn(|| {
+                    header_comments.next_if(|c| c.line == s)
+                }));
+            }
+            line
+        })
+        .collect();
+    let width = lines
+        .iter()
+        .skip(1)
+        .filter(|l| !l.trailing.is_empty())
+        .map(|l| l.text.len())
+        .max()
+        .unwrap_or(0);
+    let mut after = None;
+    for (i, line) in lines.into_iter().enumerate() {
+        printer.aligned_comments(INDENT, width + 1, after, line.before.into_iter());
+        after = line.trailing.last().map(|c| c.line);
+        if let Some(s) = line.source {
+            printer.separate(s);
+        }
+        let text = if i > 0 && !line.trailing.is_empty() {
+            format!("{:width$}", line.text, width = width)
+        } else {
+            line.text
+        };
+        printer.line(0, &with_trailing(text, line.trailing.into_iter()));
+        if i == 0 {
+            printer.opened = true;
+        }
+    }
+    let rest: Vec<&Comment> = header_comments.collect();
+
+    if !chip.parts.is_empty() || parts_line.is_some() {
+        printer.comments(INDENT, rest.into_iter());
+        match parts_line {
+            Some(l) => printer.separate(l),
+            None => printer.blank(),
+        }
+        printer.line(INDENT, "PARTS:");
+        printer.opened = true;
+        for p in &chip.parts {
+            match p {
+                Part::Component(c) => printer.component(INDENT, c, None),
+                Part::Loop(l) if l.array => {
+                    printer.component(INDENT, &l.body[0], Some(&array_count(l)))
+                }
+                Part::Loop(l) => printer.for_loop(l),
+            }
+        }
+    } else {
+        printer.comments(INDENT, rest.into_iter());
+    }
+
+    printer.comments(INDENT, footer_comments.into_iter());
+    let closing = tokens
+        .iter()
+        .rev()
+        .find(|t| t.token_type == TokenType::RightCurly)
+        .map(|t| t.line);
+    let (same_line, after): (Vec<&Comment>, Vec<&Comment>) = chip
+        .comments
+        .trailing
+        .iter()
+        .partition(|c| Some(c.line) == closing);
+    printer.line(0, &with_trailing(String::from("}"), same_line.into_iter()));
+    printer.comments(0, after.into_iter());
+
+    printer.out
+}
+
+// A line of the chip declaration with the comments that go around it.
+struct HeaderLine<'a> {
+    source: Option<u32>,
+    text: String,
+    before: Vec<&'a Comment>,
+    trailing: Vec<&'a Comment>,
+}
+
+struct Printer {
+    out: String,
+    occupied: HashSet<u32>,
+    opened: bool, // The last line opened a block, so no blank line may follow.
+    expression: Option<Assignment>, // Expression part printed last, whose Nand parts are skipped.
+}
+
+impl Printer {
+    fn line(&mut self, indent: usize, text: &str) {
+        self.out.push_str(&" ".repeat(indent));
+        self.out.push_str(text);
+        self.out.push('\n');
+        self.opened = false;
+    }
+
+    fn blank(&mut self) {
+        if !self.opened && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    // Keeps a blank line before content that starts on `line` if the
+    // source had one.
+    fn separate(&mut self, line: u32) {
+        if line > 1 && !self.occupied.contains(&(line - 1)) {
+            self.blank();
+        }
+    }
+
+    fn comments<'a>(&mut self, indent: usize, comments: impl Iterator<Item = &'a Comment>) {
+        for c in comments {
+            self.separate(c.line);
+            self.line(indent, &comment_text(c));
+        }
+    }
+
+    // Comments on their own lines. Comments right below a trailing comment
+    // that ended on line `after` continue it, so they are aligned with it.
+    fn aligned_comments<'a>(
+        &mut self,
+        indent: usize,
+        column: usize,
+        mut after: Option<u32>,
+        comments: impl Iterator<Item = &'a Comment>,
+    ) {
+        for c in comments {
+            if after.is_some() && after == c.line.checked_sub(1) {
+                self.line(column, &comment_text(c));
+                after = Some(c.line);
+            } else {
+                self.separate(c.line);
+                self.line(indent, &comment_text(c));
+                after = None;
+            }
+        }
+    }
+
+    // `count` is given for an array of components.
+    fn component(&mut self, indent: usize, c: &Component, count: Option<&GenericWidth>) {
+        if let Some(a) = &c.expression {
+            // The first of the Nand parts prints the expression for all.
+            if self.expression.as_ref() != Some(a) {
+                self.part_start(indent, a.target_ident.line, &c.comments);
+                self.line(
+                    indent,
+                    &with_trailing(format!("{};", a), c.comments.trailing.iter()),
+                );
+                self.expression = Some(a.clone());
+            }
+            return;
+        }
+        self.expression = None;
+        let first = c.annotations.first().map_or(c.name.line, |a| a.name.line);
+        self.part_start(indent, first, &c.comments);
+        self.annotations(indent, &c.annotations);
+
+        let multi_line = c
+            .mappings
+            .iter()
+            .any(|m| !m.comments.leading.is_empty() || !m.comments.trailing.is_empty())
+            || c.mappings
+                .windows(2)
+                .any(|w| w[0].wire_ident.line != w[1].wire_ident.line);
+
+        let mut head = c.qualified_name();
+        if !c.generic_params.is_empty() {
+            let params: Vec<String> = c.generic_params.iter().map(|g| g.to_string()).collect();
+            head.push_str(&format!("<{}>", params.join(", ")));
+        }
+        if let Some(count) = count {
+            head.push_str(&format!("[{}]", hdl_expr(count)));
+        }
+
+        if !multi_line {
+            let mappings: Vec<String> = c
+                .mappings
+                .iter()
+                .map(|m| format!("{}={}", m.port, m.wire))
+                .collect();
+            self.line(
+                indent,
+                &with_trailing(
+                    format!("{}({});", head, mappings.join(", ")),
+                    c.comments.trailing.iter(),
+                ),
+            );
+            return;
+        }
+
+        self.line(indent, &format!("{}(", head));
+        let mappings: Vec<String> = c
+            .mappings
+            .iter()
+            .enumerate()
+            .map(|(i, m)| {
+                let comma = if i + 1 < c.mappings.len() { "," } else { "" };
+                format!("{}={}{}", m.port, m.wire, comma)
+            })
+            .collect();
+        let width = mappings.iter().map(|m| m.len()).max().unwrap_or(0);
+        let mut after = None;
+        for (m, text) in c.mappings.iter().zip(mappings) {
+            self.aligned_comments(
+                indent + INDENT,
+                indent + INDENT + width + 1,
+                after,
+                m.comments.leading.iter(),
+            );
+            after = m.comments.trailing.last().map(|c| c.line);
+            let text = if m.comments.trailing.is_empty() {
+                text
+            } else {
+                format!("{:width$}", text, width = width)
+            };
+            self.line(
+                indent + INDENT,
+                &with_trailing(text, m.comments.trailing.iter()),
+            );
+        }
+        self.line(
+            indent,
+            &with_trailing(String::from(");"), c.comments.trailing.iter()),
+        );
+    }
+
+    fn for_loop(&mut self, l: &Loop) {
+        self.part_start(INDENT, l.iterator.line, &l.comments);
+        self.line(
+            INDENT,
+            &format!(
+                "FOR {} IN {} TO {} GENERATE {{",
+                l.iterator.value,
+                hdl_expr(&l.start),
+                hdl_expr(&l.end)
+            ),
+        );
+        self.opened = true;
+        for c in &l.body {
+            self.component(INDENT * 2, c, None);
+        }
+        self.line(
+            INDENT,
+            &with_trailing(String::from("}"), l.comments.trailing.iter()),
+        );
+    }
+
+    // Annotations go on lines of their own.
+    fn annotations(&mut self, indent: usize, annotations: &[Annotation]) {
+        for a in annotations {
+            self.line(indent, &a.to_string());
+        }
+    }
+
+    // Blank line and leading comments before a part.
+    fn part_start(&mut self, indent: usize, line: Option<u32>, comments: &Comments) {
+        let first = comments.leading.iter().map(|c| c.line).chain(line).min();
+        if let Some(l) = first {
+            self.separate(l);
+        }
+        for c in &comments.leading {
+            if Some(c.line) != first {
+                self.separate(c.line);
+            }
+            self.line(indent, &comment_text(c));
+        }
+    }
+}
+
+// Lines of the chip declaration up to PARTS, with the source line each
+// one came from so comments can be placed next to it.
+fn header(chip: &ChipHDL, tokens: &[Token]) -> Vec<(Option<u32>, usize, String)> {
+    let mut lines = Vec::new();
+
+    let keyword = if chip.interface.is_some() {
+        "INTERFACE"
+    } else {
+        "CHIP"
+    };
+    let mut declaration = format!("{} {}", keyword, chip.name);
+    if !chip.generic_decls.is_empty() {
+        let decls: Vec<&str> = chip
+            .generic_decls
+            .iter()
+            .map(|g| g.value.as_str())
+            .collect();
+        declaration.push_str(&format!("<{}>", decls.join(", ")));
+    }
+    declaration.push_str(" {");
+    let open = tokens
+        .iter()
+        .find(|t| t.token_type == TokenType::LeftCurly)
+        .map(|t| t.line);
+    lines.push((open, 0, declaration));
+
+    // Sections are printed in the order they were written.
+    let mut sections: Vec<(TokenType, Option<u32>)> = Vec::new();
+    for t in tokens {
+        match t.token_type {
+            TokenType::Parts | TokenType::RightCurly => break,
+            TokenType::In | TokenType::Out | TokenType::Clocked | TokenType::Builtin
+                if !sections.iter().any(|(tt, _)| *tt == t.token_type) =>
+            {
+                sections.push((t.token_type, Some(t.line)));
+            }
+            _ => {}
+        }
+    }
+    for (tt, present) in [
+        (TokenType::In, has_ports(chip, PortDirection::In)),
+        (TokenType::Out, has_ports(chip, PortDirection::Out)),
+        (TokenType::Builtin, chip.builtin.is_some()),
+        (TokenType::Clocked, !chip.clocked.is_empty()),
+    ] {
+        if present && !sections.iter().any(|(t, _)| *t == tt) {
+            sections.push((tt, None));
+        }
+    }
+
+    for (tt, keyword_line) in sections {
+        let (keyword, names): (&str, Vec<(Option<u32>, String)>) = match tt {
+            TokenType::In | TokenType::Out => {
+                let direction = if tt == TokenType::In {
+                    PortDirection::In
+                } else {
+                    PortDirection::Out
+                };
+                let ports = chip
+                    .ports
+                    .iter()
+                    .filter(|p| p.direction == direction)
+                    .map(|p| {
+                        let name = match &p.width {
+                            w if w.is_numeric() && hdl_expr(w) == "1" => p.name.value.clone(),
+                            w => format!("{}[{}]", p.name.value, hdl_expr(w)),
+                        };
+                        (p.name.line, name)
+                    })
+                    .collect();
+                (if tt == TokenType::In { "IN" } else { "OUT" }, ports)
+            }
+            TokenType::Clocked => (
+                "CLOCKED",
+                chip.clocked
+                    .iter()
+                    .map(|c| (c.line, c.value.clone()))
+                    .collect(),
+            ),
+            _ => (
+                "BUILTIN",
+                chip.builtin
+                    .iter()
+                    .map(|b| (b.line, b.value.clone()))
+                    .collect(),
+            ),
+        };
+
+        if names.is_empty() {
+            lines.push((keyword_line, INDENT, format!("{};", keyword)));
+            continue;
+        }
+
+        // Names keep the lines they were written on, continuation lines
+        // are aligned with the first name.
+        let mut groups: Vec<(Option<u32>, Vec<String>)> = Vec::new();
+        for (line, name) in names {
+            match groups.last_mut() {
+                Some((l, g)) if *l == line => g.push(name),
+                _ => groups.push((line, vec![name])),
+            }
+        }
+        let count = groups.len();
+        for (i, (line, group)) in groups.into_iter().enumerate() {
+            let end = if i + 1 == count { ";" } else { "," };
+            let (indent, prefix) = if i == 0 {
+                (INDENT, format!("{} ", keyword))
+            } else {
+                (INDENT + keyword.len() + 1, String::new())
+            };
+            let source = if i == 0 { keyword_line.or(line) } else { line };
+            lines.push((
+                source,
+                indent,
+                format!("{}{}{}", prefix, group.join(", "), end),
+            ));
+        }
+    }
+
+    if let Some(stimulus) = &chip.stimulus {
+        lines.push((
+            stimulus.line,
+            INDENT,
+            format!("STIMULUS {} {{", stimulus.cycles),
+        ));
+        for signal in &stimulus.signals {
+            lines.push((signal.name.line, 2 * INDENT, format!("{};", signal)));
+        }
+        lines.push((None, INDENT, String::from("}")));
+    }
+
+    if let Some(table) = &chip.table {
+        lines.push((table.keyword.line, INDENT, String::from("TABLE {")));
+        for row in &table.rows {
+            lines.push((row.line, 2 * INDENT, format!("{};", row)));
+        }
+        lines.push((None, INDENT, String::from("}")));
+    }
+
+    lines
+}
+
+// The number of components in an array, which runs from 0 to `end`.
+fn array_count(l: &Loop) -> GenericWidth {
+    match &l.end {
+        GenericWidth::Expr(Op::Sub, count, one) if **one == GenericWidth::from(1) => {
+            (**count).clone()
+        }
+        end => end + &GenericWidth::from(1),
+    }
+}
+
+fn all_comments(chip: &ChipHDL) -> Vec<&Comment> {
+    let mut res: Vec<&Comment> = Vec::new();
+    res.extend(chip.comments.leading.iter().chain(&chip.comments.trailing));
+    res.extend(chip.body_comments.iter());
+    for p in &chip.parts {
+        let components = match p {
+            Part::Component(c) => std::slice::from_ref(c),
+            Part::Loop(l) => {
+                res.extend(l.comments.leading.iter().chain(&l.comments.trailing));
+                l.body.as_slice()
+            }
+        };
+        for c in components {
+            res.extend(c.comments.leading.iter().chain(&c.comments.trailing));
+            for m in &c.mappings {
+                res.extend(m.comments.leading.iter().chain(&m.comments.trailing));
+            }
+        }
+    }
+    res
+}
+
+// Comment text without trailing whitespace on any of its lines.
+fn comment_text(c: &Comment) -> String {
+    let lines: Vec<&str> = c.text.lines().map(|l| l.trim_end()).collect();
+    lines.join("\n")
+}
+
+fn with_trailing<'a>(mut text: String, comments: impl Iterator<Item = &'a Comment>) -> String {
+    for c in comments {
+        text.push(' ');
+        text.push_str(&comment_text(c));
+    }
+    text
+}
+
+fn has_ports(chip: &ChipHDL, direction: PortDirection) -> bool {
+    chip.ports.iter().any(|p| p.direction == direction)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scanner::Scanner;
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn parse(source: &str) -> (ChipHDL, Vec<Token>) {
+        let mut scanner = Scanner::new(source, PathBuf::from("Test.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let tokens = Scanner::new(source, PathBuf::from("Test.hdl"))
+            .filter_map(Result::ok)
+            .collect();
+        (hdl, tokens)
+    }
+
+    #[test]
+    fn test_part_arrays() {
+        let source = "CHIP Not8<W> { IN in[W]; OUT out[W]; PARTS:
+Not[W]  (in=in[i],out=out[i]); // one per bit
+Not[W+1](in=in[i],
+         out=out[i]);
+}
+";
+        let expected = "CHIP Not8<W> {
+    IN in[W];
+    OUT out[W];
+    PARTS:
+    Not[W](in=in[i], out=out[i]); // one per bit
+    Not[W+1](
+        in=in[i],
+        out=out[i]
+    );
+}
+";
+        let (hdl, tokens) = parse(source);
+        assert_eq!(format(&hdl, &tokens), expected);
+    }
+
+    #[test]
+    fn test_format() {
+        let source = "// Selects a bus.
+CHIP Mux4<W>{
+  IN  a[W],b[W], // inputs
+      sel;
+  OUT out[W];
+
+
+  PARTS:
+  Not(in =sel,out=notSel);  // inverted
+
+     FOR i IN 0 TO W-1 GENERATE {
+   Mux(a=a[i], b=b[i],
+       sel=sel, out=out[i]);
+  }
+  /* done */ }
+";
+        let expected = "// Selects a bus.
+CHIP Mux4<W> {
+    IN a[W], b[W], // inputs
+       sel;
+    OUT out[W];
+
+    PARTS:
+    Not(in=sel, out=notSel); // inverted
+
+    FOR i IN 0 TO W-1 GENERATE {
+        Mux(
+            a=a[i],
+            b=b[i],
+            sel=sel,
+            out=out[i]
+        );
+    }
+    /* done */
+}
+";
+        let (hdl, tokens) = parse(source);
+        assert_eq!(format(&hdl, &tokens), expected);
+    }
+
+    #[test]
+    fn test_format_imports() {
+        let source = "// Library chips.
+USE   \"../lib\" ;
+USE \"../arith\";
+// The chip.
+
+CHIP Top {
+    IN a;
+    OUT out;
+
+    PARTS: Inv(in=a, out=out);
+}
+";
+        let expected = "// Library chips.
+USE \"../lib\";
+USE \"../arith\";
+// The chip.
+
+CHIP Top {
+    IN a;
+    OUT out;
+
+    PARTS:
+    Inv(in=a, out=out);
+}
+";
+        let (hdl, tokens) = parse(source);
+        assert_eq!(format(&hdl, &tokens), expected);
+    }
+
+    #[test]
+    fn test_format_constants() {
+        let source = "USE \"../lib\";
+CONST BUSW   16 ;
+// Address bits.
+CONST ADDR log2(BUSW)+1;
+CHIP Top { IN a[BUSW]; OUT out[ADDR]; PARTS: }
+";
+        let expected = "USE \"../lib\";
+CONST BUSW 16;
+// Address bits.
+CONST ADDR log2(BUSW)+1;
+CHIP Top {
+    IN a[BUSW];
+    OUT out[ADDR];
+    PARTS:
+}
+";
+        let (hdl, tokens) = parse(source);
+        assert_eq!(format(&hdl, &tokens), expected);
+    }
+
+    #[test]
+    fn test_format_interface() {
+        let source = "// The ALU every implementation provides.
+INTERFACE Alu<W>   { IN x[W], y[W];
+  OUT out[W]; }
+";
+        let expected = "// The ALU every implementation provides.
+INTERFACE Alu<W> {
+    IN x[W], y[W];
+    OUT out[W];
+}
+";
+        let (hdl, tokens) = parse(source);
+        assert_eq!(format(&hdl, &tokens), expected);
+    }
+
+    #[test]
+    fn test_format_stimulus() {
+        let source = "CHIP Bench {
+    OUT sum[16];
+    STIMULUS 100 { a[16] = COUNTER;
+      b = RANDOM 7; }
+
+    PARTS: Add16(a=a, b[0]=b, out=sum);
+}
+";
+        let expected = "CHIP Bench {
+    OUT sum[16];
+    STIMULUS 100 {
+        a[16] = COUNTER;
+        b = RANDOM 7;
+    }
+
+    PARTS:
+    Add16(a=a, b[0]=b, out=sum);
+}
+";
+        let (hdl, tokens) = parse(source);
+        assert_eq!(format(&hdl, &tokens), expected);
+    }
+
+    #[test]
+    fn test_format_table() {
+        let source = "CHIP Nand2 { IN a, b; OUT out;
+    TABLE { 0 0 -> 1; 0 1 -> 1;
+      10->1; 11 -> 0; }
+}
+";
+        let expected = "CHIP Nand2 {
+    IN a, b;
+    OUT out;
+    TABLE {
+        0 0 -> 1;
+        0 1 -> 1;
+        10 -> 1;
+        11 -> 0;
+    }
+}
+";
+        let (hdl, tokens) = parse(source);
+        assert_eq!(format(&hdl, &tokens), expected);
+    }
+
+    #[test]
+    fn test_format_expressions() {
+        let source = "CHIP Majority { IN a, b, c; OUT out; PARTS:
+    // At least two of three.
+    out=(a AND b) OR (c AND(a OR b));
+    Not(in=out, out=x);
+}
+";
+        let expected = "CHIP Majority {
+    IN a, b, c;
+    OUT out;
+    PARTS:
+    // At least two of three.
+    out = a AND b OR c AND (a OR b);
+    Not(in=out, out=x);
+}
+";
+        let (hdl, tokens) = parse(source);
+        assert_eq!(format(&hdl, &tokens), expected);
+    }
+
+    #[test]
+    fn test_format_annotations() {
+        let source = "@doc(\"Two inverters.\")
+CHIP Twice { IN in; OUT out;
+    PARTS:
+    @keep Not(in=in, out=x);
+    Not(in=x, out=out);
+}
+";
+        let expected = "@doc(\"Two inverters.\")
+CHIP Twice {
+    IN in;
+    OUT out;
+    PARTS:
+    @keep
+    Not(in=in, out=x);
+    Not(in=x, out=out);
+}
+";
+        let (hdl, tokens) = parse(source);
+        assert_eq!(format(&hdl, &tokens), expected);
+    }
+
+    #[test]
+    fn test_format_idempotent() {
+        let tests = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests");
+        for dir in [
+            tests.join("nand2tetris").join("solutions"),
+            tests.join("arm"),
+        ] {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.extension().and_then(|e| e.to_str()) != Some("hdl") {
+                    continue;
+                }
+                let source = fs::read_to_string(&path).unwrap();
+                let (hdl, tokens) = parse(&source);
+                let formatted = format(&hdl, &tokens);
+                let (reparsed, tokens) = parse(&formatted);
+                assert_eq!(format(&reparsed, &tokens), formatted, "{:?}", path);
+                assert_eq!(
+                    all_comments(&reparsed).len(),
+                    all_comments(&hdl).len(),
+                    "{:?}",
+                    path
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,526 @@
+// Refactorings that change how a chip is decomposed: extracting a group of
+// parts into a new chip, and inlining the parts of a sub-chip into its
+// parent. Both edit the source text of the parent so that everything
+// outside of the affected parts, including comments, is left untouched.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::error::{ErrorKind, N2VError};
+use crate::expr::{eval_expr_numeric, GenericWidth, Terminal};
+use crate::parser::*;
+use crate::scanner::{Scanner, Token, TokenType};
+use crate::simulator::{infer_widths, Chip};
+
+/// Result of extracting parts of a chip into a new chip.
+pub struct Extraction {
+    pub chip: String,   // Source of the new chip.
+    pub parent: String, // Source of the parent, using the new chip in place of the parts.
+}
+
+fn refactor_error(msg: String) -> Box<dyn Error> {
+    Box::new(N2VError {
+        msg,
+        kind: ErrorKind::Other,
+    })
+}
+
+fn parse(source: &str, path: &Path) -> Result<ChipHDL, Box<dyn Error>> {
+    let mut scanner = Scanner::new(source, path.to_path_buf());
+    let mut parser = Parser {
+        scanner: &mut scanner,
+    };
+    Ok(parser.parse()?)
+}
+
+fn is_constant(wire: &str) -> bool {
+    matches!(wire.to_lowercase().as_str(), "true" | "false" | "open")
+}
+
+/// Byte ranges of the top-level parts of a chip, in the same order as
+/// `ChipHDL::parts`. Each range ends after the semicolon of a component
+/// or the closing brace of a FOR loop.
+pub fn part_spans(source: &str) -> Vec<Range<usize>> {
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    // The scanner records one-based lines and the one-based column of the
+    // last character of a token.
+    let offset = |line: u32, column: usize| -> usize {
+        let line_start = line_starts[line as usize - 1];
+        source[line_start..]
+            .char_indices()
+            .nth(column - 1)
+            .map(|(i, _)| line_start + i)
+            .unwrap_or(source.len())
+    };
+    let token_start = |t: &Token| offset(t.line, t.start + 1 - t.lexeme.chars().count());
+
+    let tokens: Vec<Token> = Scanner::new(source, PathBuf::new())
+        .filter_map(Result::ok)
+        .collect();
+    let mut spans = Vec::new();
+    let mut start = None;
+    let mut depth = 0;
+    for t in tokens
+        .iter()
+        .skip_while(|t| t.token_type != TokenType::Parts)
+        .skip(2)
+    {
+        if start.is_none() {
+            match t.token_type {
+                TokenType::Identifier | TokenType::For => start = Some(token_start(t)),
+                _ => break,
+            }
+        }
+        let end = match t.token_type {
+            TokenType::LeftCurly => {
+                depth += 1;
+                None
+            }
+            TokenType::RightCurly => {
+                depth -= 1;
+                (depth == 0).then(|| offset(t.line, t.start) + 1)
+            }
+            TokenType::Semicolon if depth == 0 => Some(offset(t.line, t.start) + 1),
+            _ => None,
+        };
+        if let Some(end) = end {
+            spans.push(start.take().unwrap()..end);
+        }
+    }
+    spans
+}
+
+// Whitespace before the text at `offset` on its line.
+fn indentation(source: &str, offset: usize) -> &str {
+    let line_start = source[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line = &source[line_start..offset];
+    &line[..line.len() - line.trim_start().len()]
+}
+
+fn port_decl(name: &str, width: usize) -> String {
+    if width == 1 {
+        String::from(name)
+    } else {
+        format!("{}[{}]", name, width)
+    }
+}
+
+/// Moves the parts in `parts` (indices into the PARTS of the chip) into a
+/// new chip called `name`. Wires that cross the boundary of the selection
+/// become the ports of the new chip: wires driven by the selected parts are
+/// outputs and all others are inputs.
+pub fn extract_chip(
+    source: &str,
+    path: &Path,
+    parts: Range<usize>,
+    name: &str,
+    provider: &Arc<dyn HdlProvider>,
+) -> Result<Extraction, Box<dyn Error>> {
+    let hdl = parse(source, path)?;
+    if !hdl.generic_decls.is_empty() {
+        return Err(refactor_error(format!(
+            "Chip {} declares generics. Extracting parts of generic chips is not supported yet.",
+            hdl.name
+        )));
+    }
+    if parts.is_empty() || parts.end > hdl.parts.len() {
+        return Err(refactor_error(format!(
+            "Chip {} has {} parts, cannot extract parts {} to {}.",
+            hdl.name,
+            hdl.parts.len(),
+            parts.start + 1,
+            parts.end
+        )));
+    }
+    if provider.get_hdl(&format!("{}.hdl", name)).is_ok() {
+        return Err(refactor_error(format!("Chip {} already exists.", name)));
+    }
+
+    let mut selected: Vec<&Component> = Vec::new();
+    for p in &hdl.parts[parts.clone()] {
+        match p {
+            Part::Component(c) => selected.push(c),
+            Part::Loop(_) => {
+                return Err(refactor_error(String::from(
+                    "FOR loops cannot be extracted yet.",
+                )))
+            }
+        }
+    }
+
+    // Wires of the selection in order of first use, and the wires the
+    // selection drives.
+    let mut wires: Vec<String> = Vec::new();
+    let mut driven_inside: HashSet<String> = HashSet::new();
+    for c in &selected {
+        let component_hdl = get_hdl(&c.qualified_name(), provider)?;
+        for m in &c.mappings {
+            if is_constant(&m.wire.name) {
+                continue;
+            }
+            if !wires.contains(&m.wire.name) {
+                wires.push(m.wire.name.clone());
+            }
+            if component_hdl.get_port(&m.port.name)?.direction == PortDirection::Out {
+                driven_inside.insert(m.wire.name.clone());
+            }
+        }
+    }
+
+    // Ports of the parent are used outside of the selection. Input ports
+    // are driven from outside.
+    let mut used_outside: HashSet<String> =
+        hdl.ports.iter().map(|p| p.name.value.clone()).collect();
+    let mut driven_outside: HashSet<String> = hdl
+        .ports
+        .iter()
+        .filter(|p| p.direction == PortDirection::In)
+        .map(|p| p.name.value.clone())
+        .collect();
+    for (i, p) in hdl.parts.iter().enumerate() {
+        if parts.contains(&i) {
+            continue;
+        }
+        let components = match p {
+            Part::Component(c) => vec![c],
+            Part::Loop(l) => l.body.iter().collect(),
+        };
+        for c in components {
+            let component_hdl = get_hdl(&c.qualified_name(), provider)?;
+            for m in &c.mappings {
+                used_outside.insert(m.wire.name.clone());
+                if component_hdl.get_port(&m.port.name)?.direction == PortDirection::Out {
+                    driven_outside.insert(m.wire.name.clone());
+                }
+            }
+        }
+    }
+
+    let components = Chip::generate_components(&hdl, &Vec::new())?;
+    let widths = infer_widths(&hdl, &components, provider, &Vec::new())?;
+    let no_variables = HashMap::new();
+    let width = |wire: &str| -> Result<usize, Box<dyn Error>> {
+        let w = match hdl.ports.iter().find(|p| p.name.value == wire) {
+            Some(p) => &p.width,
+            None => &widths[wire],
+        };
+        Ok(eval_expr_numeric(w, &no_variables)?)
+    };
+
+    let mut inputs: Vec<&String> = Vec::new();
+    let mut outputs: Vec<&String> = Vec::new();
+    for w in &wires {
+        if !used_outside.contains(w) {
+            continue;
+        }
+        if driven_inside.contains(w) {
+            if driven_outside.contains(w) {
+                return Err(refactor_error(format!(
+                    "Wire {} is driven both inside and outside of the selected parts.",
+                    w
+                )));
+            }
+            outputs.push(w);
+        } else {
+            inputs.push(w);
+        }
+    }
+    if outputs.is_empty() {
+        return Err(refactor_error(String::from(
+            "The selected parts do not drive any wire that is used outside of them.",
+        )));
+    }
+    let decls = |ports: &[&String]| -> Result<String, Box<dyn Error>> {
+        let mut res = Vec::new();
+        for p in ports {
+            res.push(port_decl(p, width(p)?));
+        }
+        Ok(res.join(", "))
+    };
+    let call_mappings: Vec<String> = inputs
+        .iter()
+        .chain(outputs.iter())
+        .map(|w| format!("{}={}", w, w))
+        .collect();
+
+    let spans = part_spans(source);
+    let mut chip = String::new();
+    chip.push_str(&format!("CHIP {} {{\n", name));
+    chip.push_str(&format!("    IN {};\n", decls(&inputs)?));
+    chip.push_str(&format!("    OUT {};\n\n", decls(&outputs)?));
+    chip.push_str("    PARTS:\n");
+    for span in &spans[parts.clone()] {
+        chip.push_str(&format!("    {}\n", &source[span.clone()]));
+    }
+    chip.push_str("}\n");
+
+    let parent = format!(
+        "{}{}({});{}",
+        &source[..spans[parts.start].start],
+        name,
+        call_mappings.join(", "),
+        &source[spans[parts.end - 1].end..]
+    );
+
+    Ok(Extraction { chip, parent })
+}
+
+/// Replaces the component at index `part` of the PARTS with the parts of
+/// the chip it instantiates. Internal wires of that chip are prefixed with
+/// its name so they cannot clash with wires of the parent.
+pub fn inline_chip(
+    source: &str,
+    path: &Path,
+    part: usize,
+    provider: &Arc<dyn HdlProvider>,
+) -> Result<String, Box<dyn Error>> {
+    let hdl = parse(source, path)?;
+    let c = match hdl.parts.get(part) {
+        Some(Part::Component(c)) => c,
+        Some(Part::Loop(_)) => {
+            return Err(refactor_error(String::from(
+                "A FOR loop cannot be inlined, select a component.",
+            )))
+        }
+        None => {
+            return Err(refactor_error(format!(
+                "Chip {} has {} parts, cannot inline part {}.",
+                hdl.name,
+                hdl.parts.len(),
+                part + 1
+            )))
+        }
+    };
+
+    let sub = get_hdl(&c.qualified_name(), provider)?;
+    if sub.parts.is_empty() {
+        return Err(refactor_error(format!(
+            "Chip {} has no parts to inline.",
+            sub.name
+        )));
+    }
+    if !sub.generic_decls.is_empty() {
+        return Err(refactor_error(format!(
+            "Chip {} declares generics. Inlining generic chips is not supported yet.",
+            sub.name
+        )));
+    }
+
+    // The parent wire connected to each port of the sub-chip. Unconnected
+    // inputs read false.
+    let mut bindings: HashMap<String, BusHDL> = HashMap::new();
+    for m in &c.mappings {
+        if m.port.start.is_some() || m.wire.descending || m.wire.stride.is_some() {
+            return Err(refactor_error(format!(
+                "Port {} of {} is only partially mapped, which cannot be inlined yet.",
+                m.port.name, c.name.value
+            )));
+        }
+        bindings.insert(m.port.name.clone(), m.wire.clone());
+    }
+    for p in &sub.ports {
+        if p.direction == PortDirection::In && !bindings.contains_key(&p.name.value) {
+            bindings.insert(
+                p.name.value.clone(),
+                BusHDL {
+                    name: String::from("false"),
+                    start: None,
+                    end: None,
+                    descending: false,
+                    stride: None,
+                },
+            );
+        }
+    }
+
+    let mut taken: HashSet<String> = hdl.ports.iter().map(|p| p.name.value.clone()).collect();
+    for p in &hdl.parts {
+        let components = match p {
+            Part::Component(c) => vec![c],
+            Part::Loop(l) => l.body.iter().collect(),
+        };
+        for c in components {
+            taken.extend(c.mappings.iter().map(|m| m.wire.name.clone()));
+        }
+    }
+    let prefix = c.name.value.to_lowercase();
+    let mut renamed: HashMap<String, String> = HashMap::new();
+
+    let no_variables = HashMap::new();
+    let mut translate = |wire: &BusHDL| -> Result<BusHDL, Box<dyn Error>> {
+        if is_constant(&wire.name) {
+            return Ok(wire.clone());
+        }
+        let binding = match bindings.get(&wire.name) {
+            Some(b) => b,
+            None => {
+                let name = renamed
+                    .entry(wire.name.clone())
+                    .or_insert_with(|| {
+                        let mut name = format!("{}_{}", prefix, wire.name);
+                        let mut i = 2;
+                        while taken.contains(&name) {
+                            name = format!("{}_{}{}", prefix, wire.name, i);
+                            i += 1;
+                        }
+                        taken.insert(name.clone());
+                        name
+                    })
+                    .clone();
+                return Ok(BusHDL {
+                    name,
+                    ..wire.clone()
+                });
+            }
+        };
+        if is_constant(&binding.name) || wire.start.is_none() {
+            return Ok(binding.clone());
+        }
+
+        // Shift the range used inside the sub-chip by the start of the
+        // range it is connected to in the parent.
+        let base = match &binding.start {
+            Some(s) => eval_expr_numeric(s, &no_variables)?,
+            None => 0,
+        };
+        let shift = |w: &Option<GenericWidth>| -> Result<Option<GenericWidth>, Box<dyn Error>> {
+            match w {
+                Some(w) => Ok(Some(GenericWidth::Terminal(Terminal::Num(
+                    base + eval_expr_numeric(w, &no_variables)?,
+                )))),
+                None => Ok(None),
+            }
+        };
+        Ok(BusHDL {
+            name: binding.name.clone(),
+            start: shift(&wire.start)?,
+            end: shift(&wire.end)?,
+            descending: wire.descending,
+            stride: wire.stride,
+        })
+    };
+
+    let mut inlined: Vec<String> = Vec::new();
+    for mut component in Chip::generate_components(&sub, &Vec::new())? {
+        for m in &mut component.mappings {
+            m.wire = translate(&m.wire)?;
+        }
+        inlined.push(format!("{};", component));
+    }
+
+    let span = &part_spans(source)[part];
+    let newline = if source.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let separator = format!("{}{}", newline, indentation(source, span.start));
+    Ok(format!(
+        "{}{}{}",
+        &source[..span.start],
+        inlined.join(&separator),
+        &source[span.end..]
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::busmap::BusMap;
+    use crate::simulator::{Bus, ChipOptions, Simulator};
+    use std::env;
+
+    fn solutions() -> (Arc<dyn HdlProvider>, PathBuf) {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        (provider, base_path)
+    }
+
+    #[test]
+    fn test_part_spans() {
+        let source = "CHIP A {
+    IN a; OUT b;
+    PARTS:
+    Not(in=a, out=x);
+    FOR i IN 0 TO 0 GENERATE { Not(in=x, out=b); }
+}";
+        let spans = part_spans(source);
+        assert_eq!(spans.len(), 2);
+        assert_eq!(&source[spans[0].clone()], "Not(in=a, out=x);");
+        assert_eq!(
+            &source[spans[1].clone()],
+            "FOR i IN 0 TO 0 GENERATE { Not(in=x, out=b); }"
+        );
+    }
+
+    #[test]
+    fn test_extract_chip() {
+        let (provider, base_path) = solutions();
+        let path = base_path.join("Mux.hdl");
+        let source = provider.get_hdl("Mux.hdl").unwrap();
+
+        // Not and both Ands of Mux.
+        let extraction =
+            extract_chip(&source, &path, 0..3, "MuxTerms", &provider).expect("Extract error");
+        let chip = parse(&extraction.chip, &base_path.join("MuxTerms.hdl")).expect("Parse error");
+        let names: Vec<&str> = chip.ports.iter().map(|p| p.name.value.as_str()).collect();
+        assert_eq!(names, vec!["sel", "a", "b", "NotselAnda", "selAndb"]);
+        assert_eq!(chip.ports[3].direction, PortDirection::Out);
+        assert_eq!(chip.parts.len(), 3);
+
+        let parent = parse(&extraction.parent, &path).expect("Parse error");
+        assert_eq!(parent.parts.len(), 2);
+        assert!(extraction
+            .parent
+            .contains("MuxTerms(sel=sel, a=a, b=b, NotselAnda=NotselAnda, selAndb=selAndb);"));
+        // Comments outside of the parts are kept.
+        assert!(extraction.parent.starts_with("// This file is part of"));
+
+        assert!(extract_chip(&source, &path, 0..1, "Not", &provider).is_err());
+    }
+
+    #[test]
+    fn test_inline_chip() {
+        let (provider, base_path) = solutions();
+        let path = base_path.join("Mux.hdl");
+        let source = provider.get_hdl("Mux.hdl").unwrap();
+
+        let inlined = inline_chip(&source, &path, 0, &provider).expect("Inline error");
+        assert!(inlined.contains("\n    Nand(a=sel, b=sel, out=Notsel);\r\n"));
+
+        // The result still behaves like a Mux.
+        let hdl = parse(&inlined, &path).expect("Parse error");
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+        let mut simulator = Simulator::new(chip);
+        let outputs = simulator
+            .simulate(
+                &BusMap::try_from([("a", vec![true]), ("b", vec![false]), ("sel", vec![false])])
+                    .unwrap(),
+            )
+            .expect("Simulation error");
+        assert_eq!(outputs.get_bus(&Bus::from("out")), vec![Some(true)]);
+
+        // Internal wires of the inlined chip are renamed.
+        let source = "CHIP Twice {
+    IN a, b;
+    OUT out;
+    PARTS:
+    And(a=a, b=b, out=out);
+}";
+        let inlined =
+            inline_chip(source, Path::new("Twice.hdl"), 0, &provider).expect("Inline error");
+        assert!(inlined
+            .contains("    Nand(a=a, b=b, out=and_nandout);\n    Not(in=and_nandout, out=out);"));
+
+        assert!(inline_chip(&inlined, Path::new("Twice.hdl"), 0, &provider).is_err());
+    }
+}
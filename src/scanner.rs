@@ -1,10 +1,13 @@
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::path::PathBuf;
 use std::str::Chars;
+use unicode_ident::{is_xid_continue, is_xid_start};
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum TokenType {
     Chip,
+    Interface,
     Identifier,
     LeftCurly,
     RightCurly,
@@ -27,8 +30,18 @@ pub enum TokenType {
     For,
     To,
     Generate,
+    Clocked,
+    Builtin,
+    Use,
+    Const,
+    Stimulus,
+    Table,
+    StringLiteral,
     Plus,
     Minus,
+    At,
+    Bang,
+    Comment, // Only returned when the scanner is asked for comment tokens.
     Eof,
 }
 
@@ -36,6 +49,7 @@ impl std::fmt::Display for TokenType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match *self {
             TokenType::Chip => write!(f, "the `CHIP` keyword (all caps)"),
+            TokenType::Interface => write!(f, "the `INTERFACE` keyword (all caps)"),
             TokenType::Identifier => write!(f, "an identifier"),
             TokenType::LeftCurly => write!(f, "a left curly brace `{{`"),
             TokenType::RightCurly => write!(f, "a right curly brace `}}`"),
@@ -51,15 +65,25 @@ impl std::fmt::Display for TokenType {
             TokenType::Out => write!(f, "the `OUT` keyword (all caps)"),
             TokenType::Comma => write!(f, "a comma `,`"),
             TokenType::Parts => write!(f, "the `PARTS` keyword (all caps)"),
-            TokenType::Number => write!(f, "a number such as `2` or `16`."),
+            TokenType::Number => write!(f, "a number such as `2`, `16`, `0x10`, or `0b1000`."),
             TokenType::Equal => write!(f, "an equal sign `=`"),
             TokenType::Dot => write!(f, "a dot `.`"),
             TokenType::Invalid => write!(f, "INVALID TOKEN SOMETHING BAD HERE BE DRAGONS"),
             TokenType::For => write!(f, "the `FOR` keyword (all caps)"),
             TokenType::To => write!(f, "the `TO` keyword (all caps)"),
             TokenType::Generate => write!(f, "the `GENERATE` keyword (all caps)"),
+            TokenType::Clocked => write!(f, "the `CLOCKED` keyword (all caps)"),
+            TokenType::Builtin => write!(f, "the `BUILTIN` keyword (all caps)"),
+            TokenType::Use => write!(f, "the `USE` keyword (all caps)"),
+            TokenType::Const => write!(f, "the `CONST` keyword (all caps)"),
+            TokenType::Stimulus => write!(f, "the `STIMULUS` keyword (all caps)"),
+            TokenType::Table => write!(f, "the `TABLE` keyword (all caps)"),
+            TokenType::StringLiteral => write!(f, "a quoted string such as `\"../lib\"`"),
             TokenType::Plus => write!(f, "a plus sign `+`"),
             TokenType::Minus => write!(f, "a minus sign `-`"),
+            TokenType::At => write!(f, "an at sign `@`"),
+            TokenType::Bang => write!(f, "an exclamation mark `!`"),
+            TokenType::Comment => write!(f, "a comment"),
             TokenType::Eof => write!(f, "the end of the file `EOF`"),
         }
     }
@@ -70,17 +94,94 @@ pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
     pub line: u32,
-    pub start: usize,
+    pub start: usize, // One-based column of the last character.
     pub path: PathBuf,
 }
 
+/// A region of source text. Lines and columns are one-based; `end_col` is
+/// one past the last character, so an empty span has `start_col == end_col`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start_line: u32,
+    pub start_col: u32,
+    pub end_line: u32,
+    pub end_col: u32,
+}
+
+impl Token {
+    /// The text covered by the token. Tokens never span lines, except for
+    /// block comments, which are covered up to the end of their first line.
+    pub fn span(&self) -> Span {
+        let first_line = self.lexeme.split('\n').next().unwrap_or_default();
+        let len = first_line.chars().count() as u32;
+        let end = self.start as u32 + 1;
+        Span {
+            start_line: self.line,
+            start_col: end.saturating_sub(len),
+            end_line: self.line,
+            end_col: end,
+        }
+    }
+}
+
+/// Whether `c` can start a name. Names follow Unicode's identifier syntax
+/// (XID), so they may be written in any script, plus a leading underscore.
+pub fn is_name_start(c: char) -> bool {
+    c == '_' || is_xid_start(c)
+}
+
+/// Whether `c` can continue a name, e.g. a digit, letter, or combining mark.
+pub fn is_name_continue(c: char) -> bool {
+    is_xid_continue(c)
+}
+
+/// Source text that is not a token, such as a stray character or a string
+/// that is never closed. `token` covers the text and has type `Invalid`.
+#[derive(Clone)]
+pub struct ScanError {
+    pub msg: String,
+    pub token: Token,
+}
+
+impl std::fmt::Display for ScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let span = self.token.span();
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.token.path.display(),
+            span.start_line,
+            span.start_col,
+            self.msg
+        )
+    }
+}
+
+impl std::fmt::Debug for ScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl std::error::Error for ScanError {}
+
+/// A `//` or `/* */` comment, including its delimiters.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Comment {
+    pub text: String,
+    pub line: u32,    // Line of the first character.
+    pub start: usize, // One-based column of the first character.
+}
+
 pub struct Scanner<'a> {
     source_chars: std::iter::Peekable<Chars<'a>>,
     pub line: u32,
     pub col: usize,
     keywords: HashMap<&'a str, TokenType>,
-    peeked: Option<Token>,
+    lookahead: VecDeque<Result<Token, ScanError>>, // Scanned, but not yet returned by `next`.
     pub path: PathBuf,
+    pub comments: Vec<Comment>, // Comments scanned so far that nobody has taken yet.
+    pub comment_tokens: bool,   // Return comments as tokens instead of keeping them in `comments`.
 }
 
 impl<'a> Scanner<'a> {
@@ -90,32 +191,72 @@ impl<'a> Scanner<'a> {
         // Keywords are case-insensitive
         let keywords = HashMap::from([
             ("CHIP", TokenType::Chip),
+            ("INTERFACE", TokenType::Interface),
             ("PARTS", TokenType::Parts),
             ("IN", TokenType::In),
             ("OUT", TokenType::Out),
             ("FOR", TokenType::For),
             ("TO", TokenType::To),
             ("GENERATE", TokenType::Generate),
+            ("CLOCKED", TokenType::Clocked),
+            ("BUILTIN", TokenType::Builtin),
+            ("USE", TokenType::Use),
+            ("CONST", TokenType::Const),
+            ("STIMULUS", TokenType::Stimulus),
+            ("TABLE", TokenType::Table),
         ]);
 
         Scanner {
             source_chars,
             line: 1,
-            col: 1,
+            col: 0,
             keywords,
-            peeked: None,
+            lookahead: VecDeque::new(),
             path: source_path,
+            comments: Vec::new(),
+            comment_tokens: false,
+        }
+    }
+
+    /// The token that the next call to `next` returns.
+    pub fn peek(&mut self) -> Option<&Result<Token, ScanError>> {
+        self.peek_n(0)
+    }
+
+    /// The token `n` places ahead, so `peek_n(0)` is the same as `peek`.
+    /// Comments before it are scanned too, so `comments` may include
+    /// comments that come after tokens which have not been returned yet.
+    pub fn peek_n(&mut self, n: usize) -> Option<&Result<Token, ScanError>> {
+        while self.lookahead.len() <= n {
+            let t = self.scan_token()?;
+            self.lookahead.push_back(t);
         }
+        self.lookahead.get(n)
     }
 
-    pub fn peek(&mut self) -> Option<Token> {
-        if self.peeked.is_none() {
-            self.peeked = self.scan_token();
+    /// Returns a token so that the next call to `next` or `peek` yields it
+    /// again.
+    pub fn push_back(&mut self, t: Token) {
+        self.lookahead.push_front(Ok(t));
+    }
+
+    fn scan_token(&mut self) -> Option<Result<Token, ScanError>> {
+        let t = self.scan()?;
+        if t.token_type != TokenType::Invalid {
+            return Some(Ok(t));
         }
-        self.peeked.clone()
+        let msg = if t.lexeme == "/*" {
+            String::from("This comment is never closed with `*/`.")
+        } else if t.lexeme.starts_with('"') {
+            String::from("This string is not closed before the end of the line.")
+        } else {
+            format!("I did not expect the character `{}` here.", t.lexeme)
+        };
+        Some(Err(ScanError { msg, token: t }))
     }
 
-    pub fn scan_token(&mut self) -> Option<Token> {
+    // The next token, or an `Invalid` token for text that is not a token.
+    fn scan(&mut self) -> Option<Token> {
         let mut token: Option<Token> = None;
 
         while token.is_none() && self.source_chars.peek().is_some() {
@@ -228,6 +369,21 @@ impl<'a> Scanner<'a> {
                         start: self.col,
                         path: self.path.clone(),
                     }),
+                    '@' => Some(Token {
+                        token_type: TokenType::At,
+                        lexeme: c.to_string(),
+                        line: self.line,
+                        start: self.col,
+                        path: self.path.clone(),
+                    }),
+                    '!' => Some(Token {
+                        token_type: TokenType::Bang,
+                        lexeme: c.to_string(),
+                        line: self.line,
+                        start: self.col,
+                        path: self.path.clone(),
+                    }),
+                    '"' => Some(self.finish_string()),
                     '\n' => {
                         self.line += 1;
                         self.col = 0;
@@ -248,10 +404,27 @@ impl<'a> Scanner<'a> {
                             Some(c2) => *c2,
                         };
 
-                        if followup == '/' {
-                            self.finish_single_comment();
-                        } else if followup == '*' {
-                            self.finish_multi_comment();
+                        if followup == '/' || followup == '*' {
+                            let mut comment = Comment {
+                                text: c.to_string(),
+                                line: self.line,
+                                start: self.col,
+                            };
+                            if followup == '/' {
+                                self.finish_single_comment(&mut comment.text);
+                            } else if !self.finish_multi_comment(&mut comment.text) {
+                                return Some(Token {
+                                    lexeme: String::from("/*"),
+                                    line: comment.line,
+                                    start: comment.start + 1,
+                                    path: self.path.clone(),
+                                    token_type: TokenType::Invalid,
+                                });
+                            }
+                            if self.comment_tokens {
+                                return Some(self.comment_token(comment));
+                            }
+                            self.comments.push(comment);
                         } else {
                             return Some(Token {
                                 lexeme: c.to_string(),
@@ -264,9 +437,9 @@ impl<'a> Scanner<'a> {
                         None
                     }
                     _ => {
-                        if c.is_alphabetic() || c == '_' {
+                        if is_name_start(c) {
                             Some(self.finish_identifier(c))
-                        } else if c.is_numeric() {
+                        } else if c.is_ascii_digit() {
                             Some(self.finish_number(c))
                         } else {
                             Some(Token {
@@ -284,7 +457,33 @@ impl<'a> Scanner<'a> {
         token
     }
 
-    fn finish_single_comment(&mut self) {
+    // Strings end at the closing quote and cannot span lines. The lexeme
+    // keeps its quotes.
+    fn finish_string(&mut self) -> Token {
+        let mut lexeme = String::from('"');
+        let mut token_type = TokenType::Invalid;
+        while let Some(c) = self.source_chars.peek() {
+            if *c == '\n' {
+                break;
+            }
+            lexeme.push(*c);
+            self.source_chars.next();
+            self.col += 1;
+            if lexeme.len() > 1 && lexeme.ends_with('"') {
+                token_type = TokenType::StringLiteral;
+                break;
+            }
+        }
+        Token {
+            token_type,
+            lexeme,
+            line: self.line,
+            start: self.col,
+            path: self.path.clone(),
+        }
+    }
+
+    fn finish_single_comment(&mut self, text: &mut String) {
         loop {
             let next = self.source_chars.next();
             match next {
@@ -294,44 +493,81 @@ impl<'a> Scanner<'a> {
                     self.col = 0;
                     break;
                 }
-                _ => {}
+                Some('\r') => {}
+                Some(c) => text.push(c),
             }
         }
     }
 
-    fn finish_multi_comment(&mut self) {
+    // Returns false if the file ends before the comment is closed.
+    fn finish_multi_comment(&mut self, text: &mut String) -> bool {
+        // The opening `*` cannot also close the comment, as in `/*/`.
+        self.source_chars.next();
+        self.col += 1;
+        text.push('*');
+
         loop {
             let next = self.source_chars.next();
             self.col += 1;
 
             match next {
-                None => {
-                    break;
-                }
+                None => return false,
                 Some('\n') => {
+                    text.push('\n');
                     self.line += 1;
                     self.col = 0;
                 }
-                Some('*') => match self.source_chars.peek() {
-                    None => {
-                        break;
-                    }
-                    Some('/') => {
+                Some('*') => {
+                    text.push('*');
+                    if self.source_chars.peek() == Some(&'/') {
+                        text.push('/');
                         self.source_chars.next();
-                        break;
+                        self.col += 1;
+                        return true;
                     }
-                    _ => {}
-                },
-                _ => {}
+                }
+                Some(c) => text.push(c),
             }
         }
     }
 
+    // A comment token is positioned like any other token on the comment's
+    // first line, and its lexeme holds the whole comment.
+    fn comment_token(&self, comment: Comment) -> Token {
+        let first_line = comment.text.split('\n').next().unwrap_or_default();
+        Token {
+            token_type: TokenType::Comment,
+            line: comment.line,
+            start: comment.start + first_line.chars().count() - 1,
+            lexeme: comment.text,
+            path: self.path.clone(),
+        }
+    }
+
+    /// Takes the comments scanned so far, leaving none pending.
+    pub fn take_comments(&mut self) -> Vec<Comment> {
+        std::mem::take(&mut self.comments)
+    }
+
     fn finish_number(&mut self, start: char) -> Token {
         let mut lexeme = start.to_string();
 
+        // Hexadecimal (0x10) and binary (0b1000) literals. Digits are
+        // validated when the parser converts the lexeme to a value.
+        let mut prefixed = false;
+        if start == '0' {
+            if let Some(c) = self.source_chars.peek() {
+                if matches!(c, 'x' | 'X' | 'b' | 'B') {
+                    lexeme.push(*c);
+                    self.source_chars.next();
+                    self.col += 1;
+                    prefixed = true;
+                }
+            }
+        }
+
         while let Some(c) = self.source_chars.peek() {
-            if c.is_numeric() {
+            if c.is_ascii_digit() || (prefixed && (c.is_ascii_alphanumeric() || *c == '_')) {
                 lexeme.push(*c);
                 self.source_chars.next();
                 self.col += 1;
@@ -353,7 +589,7 @@ impl<'a> Scanner<'a> {
         let mut lexeme = start.to_string();
 
         while let Some(c) = self.source_chars.peek() {
-            if c.is_alphanumeric() || c == &'_' {
+            if is_name_continue(*c) {
                 lexeme.push(*c);
                 self.source_chars.next();
                 self.col += 1;
@@ -382,15 +618,12 @@ impl<'a> Scanner<'a> {
 }
 
 impl<'a> Iterator for Scanner<'a> {
-    type Item = Token;
+    type Item = Result<Token, ScanError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.peeked.is_some() {
-            let p = self.peeked.clone();
-            self.peeked = None;
-            p
-        } else {
-            self.scan_token()
+        match self.lookahead.pop_front() {
+            Some(t) => Some(t),
+            None => self.scan_token(),
         }
     }
 }
@@ -419,7 +652,7 @@ mod test {
         let contents = fs::read_to_string(test_file).expect("Unable to read test file.");
 
         let scanner = Scanner::new(contents.as_str(), PathBuf::from(""));
-        let actual_types: Vec<_> = scanner.map(|x| x.token_type).collect();
+        let actual_types: Vec<_> = scanner.map(|x| x.unwrap().token_type).collect();
 
         let expected_types = vec![
             TokenType::Chip,
@@ -456,7 +689,7 @@ mod test {
         let contents = fs::read_to_string(test_file).expect("Unable to read test file.");
 
         let scanner = Scanner::new(contents.as_str(), PathBuf::from(""));
-        let actual_types: Vec<_> = scanner.map(|x| x.token_type).collect();
+        let actual_types: Vec<_> = scanner.map(|x| x.unwrap().token_type).collect();
 
         let expected_types = vec![
             TokenType::Chip,
@@ -502,7 +735,7 @@ mod test {
         let contents = fs::read_to_string(test_file).expect("Unable to read test file.");
 
         let scanner = Scanner::new(contents.as_str(), PathBuf::from(""));
-        let actual_types: Vec<_> = scanner.map(|x| x.token_type).collect();
+        let actual_types: Vec<_> = scanner.map(|x| x.unwrap().token_type).collect();
 
         let expected_types = vec![
             TokenType::Chip,
@@ -540,7 +773,7 @@ mod test {
         let contents = fs::read_to_string(test_file).expect("Unable to read test file.");
 
         let scanner = Scanner::new(contents.as_str(), PathBuf::from(""));
-        let actual_types: Vec<_> = scanner.map(|x| x.token_type).collect();
+        let actual_types: Vec<_> = scanner.map(|x| x.unwrap().token_type).collect();
 
         let expected_types = vec![
             TokenType::Chip,
@@ -619,4 +852,159 @@ mod test {
 
         assert_eq!(expected_types, actual_types);
     }
+
+    #[test]
+    fn test_token_spans() {
+        let scanner = Scanner::new("CHIP Mux {\n  IN sel;", PathBuf::from(""));
+        let spans: Vec<(String, Span)> = scanner
+            .map(Result::unwrap)
+            .map(|t| (t.lexeme.clone(), t.span()))
+            .collect();
+        let span = |line, start_col, end_col| Span {
+            start_line: line,
+            start_col,
+            end_line: line,
+            end_col,
+        };
+        assert_eq!(
+            spans,
+            vec![
+                (String::from("CHIP"), span(1, 1, 5)),
+                (String::from("Mux"), span(1, 6, 9)),
+                (String::from("{"), span(1, 10, 11)),
+                (String::from("IN"), span(2, 3, 5)),
+                (String::from("sel"), span(2, 6, 9)),
+                (String::from(";"), span(2, 9, 10)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_string() {
+        let scanner = Scanner::new("USE \"../lib\";\n\"open", PathBuf::from(""));
+        let tokens: Vec<(TokenType, String, Span)> = scanner
+            .map(|t| t.unwrap_or_else(|e| e.token))
+            .map(|t| (t.token_type, t.lexeme.clone(), t.span()))
+            .collect();
+        assert_eq!(tokens[0].0, TokenType::Use);
+        assert_eq!(tokens[1].0, TokenType::StringLiteral);
+        assert_eq!(tokens[1].1, "\"../lib\"");
+        assert_eq!(tokens[1].2.start_col, 5);
+        assert_eq!(tokens[1].2.end_col, 13);
+        assert_eq!(tokens[2].0, TokenType::Semicolon);
+        assert_eq!(tokens[3].0, TokenType::Invalid);
+        assert_eq!(tokens[3].1, "\"open");
+    }
+
+    #[test]
+    fn test_peek_n() {
+        let mut scanner = Scanner::new("a b $ c \"d", PathBuf::from("Test.hdl"));
+        assert_eq!(scanner.peek_n(1).unwrap().as_ref().unwrap().lexeme, "b");
+        let e = scanner.peek_n(2).unwrap().as_ref().err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "Test.hdl:1:5: I did not expect the character `$` here."
+        );
+        assert_eq!(scanner.peek().unwrap().as_ref().unwrap().lexeme, "a");
+        scanner.next();
+        scanner.next();
+        assert_eq!(scanner.next().unwrap().err().unwrap().token.lexeme, "$");
+        assert_eq!(scanner.next().unwrap().unwrap().lexeme, "c");
+        let e = scanner.next().unwrap().err().unwrap();
+        assert_eq!(
+            e.msg,
+            "This string is not closed before the end of the line."
+        );
+        assert!(scanner.peek_n(3).is_none());
+        assert!(scanner.next().is_none());
+    }
+
+    #[test]
+    fn test_block_comments() {
+        let source = "a /* one\ntwo */ b /*/ still a comment */ c\n/* open";
+        let mut scanner = Scanner::new(source, PathBuf::from(""));
+        let tokens: Vec<(TokenType, String, Span)> = scanner
+            .by_ref()
+            .map(|t| t.unwrap_or_else(|e| e.token))
+            .map(|t| (t.token_type, t.lexeme.clone(), t.span()))
+            .collect();
+        let lexemes: Vec<&str> = tokens.iter().map(|t| t.1.as_str()).collect();
+        assert_eq!(lexemes, vec!["a", "b", "c", "/*"]);
+        assert_eq!(tokens[1].2.start_col, 8);
+        assert_eq!(tokens[2].2.start_col, 33);
+        assert_eq!(tokens[3].0, TokenType::Invalid);
+        assert_eq!(tokens[3].2.start_line, 3);
+        let comments: Vec<String> = scanner
+            .take_comments()
+            .into_iter()
+            .map(|c| c.text)
+            .collect();
+        assert_eq!(comments, vec!["/* one\ntwo */", "/*/ still a comment */"]);
+
+        let mut scanner = Scanner::new("a // note\n/* x\ny */ b", PathBuf::from(""));
+        scanner.comment_tokens = true;
+        let tokens: Vec<Token> = scanner.by_ref().map(Result::unwrap).collect();
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.token_type).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Identifier,
+                TokenType::Comment,
+                TokenType::Comment,
+                TokenType::Identifier
+            ]
+        );
+        assert_eq!(tokens[1].lexeme, "// note");
+        assert_eq!(tokens[2].lexeme, "/* x\ny */");
+        assert_eq!(
+            tokens[2].span(),
+            Span {
+                start_line: 2,
+                start_col: 1,
+                end_line: 2,
+                end_col: 5,
+            }
+        );
+        assert!(scanner.comments.is_empty());
+    }
+
+    #[test]
+    fn test_unicode_names() {
+        // `é` is written as `e` and a combining accent.
+        let scanner = Scanner::new(
+            "CHIP Größe {\n  IN 名前[2], _b, e\u{301}t, ٣;",
+            PathBuf::from(""),
+        );
+        let tokens: Vec<(TokenType, String, Span)> = scanner
+            .map(|t| t.unwrap_or_else(|e| e.token))
+            .map(|t| (t.token_type, t.lexeme.clone(), t.span()))
+            .collect();
+        let span = |line, start_col, end_col| Span {
+            start_line: line,
+            start_col,
+            end_line: line,
+            end_col,
+        };
+        assert_eq!(
+            tokens[1],
+            (TokenType::Identifier, String::from("Größe"), span(1, 6, 11))
+        );
+        assert_eq!(
+            tokens[4],
+            (TokenType::Identifier, String::from("名前"), span(2, 6, 8))
+        );
+        assert_eq!(tokens[5].2, span(2, 8, 9));
+        assert_eq!(tokens[9].1, "_b");
+        assert_eq!(
+            tokens[11],
+            (
+                TokenType::Identifier,
+                String::from("e\u{301}t"),
+                span(2, 17, 20)
+            )
+        );
+        // Digits of other scripts are not numbers.
+        assert_eq!(tokens[13].0, TokenType::Invalid);
+        assert_eq!(tokens[13].2, span(2, 22, 23));
+    }
 }
@@ -1,19 +1,37 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
+use std::fs;
 use std::ops::Range;
-use std::rc::Rc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
 
 use petgraph::algo::kosaraju_scc;
 use petgraph::graph::{EdgeIndex, NodeIndex};
-use petgraph::visit::EdgeRef;
+use petgraph::visit::{EdgeFiltered, EdgeRef};
 use petgraph::Graph;
 use serde::ser::{SerializeStruct, Serializer};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, debug_span, trace_span};
 
+use crate::bitwise;
+use crate::builtin::{get_builtin, get_memory, get_rom, get_table, shared_bit, Builtin};
 use crate::busmap::BusMap;
-use crate::error::{ErrorKind, N2VError};
+use crate::combinational::combinational_loops;
+use crate::diagnostics::Output;
+use crate::elaborate::{
+    bind, connect, declared_at, drives_tristate, report_warnings, shadowed_chip, BoundChip,
+    LITERAL_WIDTH,
+};
+use crate::engine::SimEngine;
+use crate::error::{ErrorKind, N2VError, WhidlError};
 use crate::expr::*;
+use crate::lint::{hdl_warnings, Levels};
+use crate::logic::{Logic, XPolicy};
+use crate::netlist::{Cell, Netlist, Pin};
 use crate::parser::*;
 
 /// The main graph connecting components of a chip together.
@@ -22,7 +40,20 @@ type Circuit = Graph<Chip, Wire>;
 
 /// Used to facilitate lazy elaboration of components.
 /// Outputs of chips are cached for given inputs.
-type Cache = HashMap<InputCacheEntry, BusMap>;
+#[derive(Clone, Default)]
+pub struct Cache {
+    entries: HashMap<InputCacheEntry, BusMap>,
+    // Lookups since the simulator was created, for status displays.
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Cache {
+    /// Forgets the cached outputs. The counts of lookups are kept.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
 
 trait TryMap {
     fn try_map();
@@ -34,28 +65,234 @@ pub struct Bus {
     pub range: Option<Range<usize>>,
 }
 
-#[derive(Hash, Eq, PartialEq)]
+/// Version of the snapshot format written by this whidl. Snapshots from
+/// older versions can still be loaded.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// The state of a simulation, saved so that a long run can be resumed later
+/// or attached to a bug report.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Snapshot {
+    pub version: u32,
+    pub whidl: String, // Version of whidl that wrote the snapshot.
+    pub chip: String,
+    #[serde(default)]
+    pub cycle: u64, // Clock cycles run before the snapshot was taken.
+    #[serde(default)]
+    pub key: u16,
+    pub state: ChipState,
+}
+
+/// State held by a chip and its parts. Parts without state are left out.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct ChipState {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<u64>, // Bit of a DFF, or the state of a builtin.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub parts: BTreeMap<usize, ChipState>, // By index in the circuit, or device of a builtin.
+}
+
+impl ChipState {
+    fn is_empty(&self) -> bool {
+        self.values.is_empty() && self.parts.is_empty()
+    }
+}
+
+impl Snapshot {
+    /// Reads a snapshot saved by this or an older version of whidl.
+    pub fn read(path: &Path) -> Result<Snapshot, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        let value: serde_json::Value = serde_json::from_str(&contents)?;
+        let version = value.get("version").and_then(|v| v.as_u64());
+        match version {
+            Some(v) if v <= SNAPSHOT_VERSION as u64 => Ok(serde_json::from_value(value)?),
+            Some(v) => Err(Box::new(N2VError {
+                msg: format!(
+                    "{} is a version {} snapshot, but this whidl reads up to version {}.",
+                    path.display(),
+                    v,
+                    SNAPSHOT_VERSION
+                ),
+                kind: ErrorKind::IOError,
+            })),
+            None => Err(Box::new(N2VError {
+                msg: format!("{} is not a whidl snapshot.", path.display()),
+                kind: ErrorKind::IOError,
+            })),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+#[derive(Hash, Eq, PartialEq, Clone)]
 pub struct InputCacheEntry {
     name: String,
     signals: BusMap,
 }
 
+/// Passes over its parts a chip may take to settle before it is reported
+/// as oscillating, unless set otherwise.
+pub const DEFAULT_SETTLE_LIMIT: usize = 1000;
+
+// Passes run after the limit to see which signals are still changing.
+const OSCILLATION_PASSES: usize = 4;
+
+/// Parts a chip may elaborate to, counting those of its parts all the way
+/// down, before elaboration is refused, unless set otherwise. This stops a
+/// mistake such as a RAM16K in a loop from taking all of a machine's memory.
+pub const DEFAULT_MAX_INSTANCES: usize = 10_000_000;
+
+/// Bits of the signals a chip may elaborate to, counted the same way.
+pub const DEFAULT_MAX_BUS_BITS: usize = 100_000_000;
+
 pub struct Simulator {
     pub input_cache: Cache,
-    pub dirty_dffs: Vec<*mut Chip>,
+    pub dirty_dffs: Vec<Vec<NodeIndex>>, // Paths of the flip-flops to latch at the next tick.
     pub chip: Chip,
+    // Passes over its parts a chip may take to settle after its inputs
+    // change. Only combinational loops take more than a few.
+    pub settle_limit: usize,
+    // Dependency hashes of the chip's parts when it was last built by
+    // `rebind`, by qualified name.
+    dependencies: Option<HashMap<String, u64>>,
+    // Runs the chip in place of the interpreter, if the simulator was made
+    // with one.
+    engine: Option<Box<dyn SimEngine>>,
 }
 
 impl Simulator {
     pub fn new(chip: Chip) -> Simulator {
         Simulator {
-            input_cache: HashMap::new(),
+            input_cache: Cache::default(),
             dirty_dffs: Vec::new(),
+            settle_limit: chip.tree.options.settle_limit,
             chip,
+            dependencies: None,
+            engine: None,
+        }
+    }
+
+    /// A simulator that has `engine` run the chip, which the engine was
+    /// made from. Simulating, ticking, probing and resetting go to the
+    /// engine, and the chip's ports show what it computed. Snapshots, the
+    /// keyboard and the screen are the interpreter's, so they are left out.
+    pub fn with_engine(chip: Chip, engine: Box<dyn SimEngine>) -> Simulator {
+        Simulator {
+            engine: Some(engine),
+            ..Simulator::new(chip)
+        }
+    }
+
+    // Refuses to do what only the interpreter can do, when another engine
+    // runs the chip.
+    fn interpreted(&self, what: &str) -> Result<(), Box<dyn Error>> {
+        match self.engine {
+            None => Ok(()),
+            Some(_) => Err(Box::new(N2VError {
+                msg: format!(
+                    "Chip {} is run by another engine than the interpreter, so it cannot {}.",
+                    self.chip.name, what
+                ),
+                kind: ErrorKind::SimulationError(None),
+            })),
+        }
+    }
+
+    /// Builds the chip again with new generic arguments, e.g. for each step
+    /// of a sweep over widths. Parsed chips are reused, and so are parts of
+    /// the old chip that have the same chip, generic arguments, and
+    /// dependency hash in the new one, along with everything they have
+    /// elaborated. Parts holding flip-flops or memories are built again so
+    /// that every build starts from the same state.
+    pub fn rebind(&mut self, generics: &[usize]) -> Result<(), Box<dyn Error>> {
+        self.interpreted("be built again with new generic arguments")?;
+        let hdl = match &self.chip.hdl {
+            Some(hdl) => hdl.clone(),
+            None => {
+                return Err(Box::new(N2VError {
+                    msg: format!("Chip {} has no generics to bind.", self.chip.name),
+                    kind: ErrorKind::ElaborationError,
+                }))
+            }
+        };
+        // Chips are not hashed until the first rebind, so the first one
+        // trusts that nothing was edited since the chip was built.
+        let before = match self.dependencies.take() {
+            Some(d) => d,
+            None => self.chip.dependency_hashes()?,
+        };
+        let provider = self.chip.hdl_provider.clone();
+        // The new chip is built the way the old one was.
+        let options = ChipOptions {
+            generics: generics.to_vec(),
+            elaborate: false,
+            ..self.chip.tree.options.clone()
+        };
+        let chip = Chip::new(&hdl, &provider, options)?;
+        let mut old = std::mem::replace(&mut self.chip, chip);
+        *self.chip.tree.limits.lock().unwrap() = *old.tree.limits.lock().unwrap();
+        self.input_cache.clear();
+        self.dirty_dffs.clear();
+        self.chip.elaborate().map_err(|e| self.chip.size_error(e))?;
+        let after = self.chip.dependency_hashes()?;
+
+        let mut spare = old.reusable_parts()?;
+        spare.retain(|(key, _)| {
+            before
+                .get(&key.0)
+                .is_some_and(|h| after.get(&key.0) == Some(h))
+        });
+        for (key, node) in self.chip.reusable_parts()? {
+            if let Some(i) = spare.iter().position(|(k, _)| *k == key) {
+                let (_, old_node) = spare.swap_remove(i);
+                let part = &mut self.chip.circuit[node];
+                std::mem::swap(part, &mut old.circuit[old_node]);
+                *self.chip.tree.size.lock().unwrap() += part.total_size();
+                debug!(part = %part.name, "reused");
+            }
         }
+        // Reused parts still have the places they had in the old chip.
+        self.chip.adopt_parts();
+        self.dependencies = Some(after);
+        Ok(())
     }
 
     pub fn simulate(&mut self, inputs: &BusMap) -> Result<BusMap, Box<dyn Error>> {
+        let _span = trace_span!("simulate", chip = %self.chip.name).entered();
+        let mut unset: Vec<&String> = self
+            .chip
+            .ports
+            .iter()
+            .filter(|(name, port)| {
+                port.direction == PortDirection::In && inputs.get_width(name).is_none()
+            })
+            .map(|(name, _)| name)
+            .collect();
+        if !unset.is_empty() {
+            unset.sort();
+            let names: Vec<&str> = unset.iter().map(|n| n.as_str()).collect();
+            let (inputs, were) = match names.len() {
+                1 => ("input", "was"),
+                _ => ("inputs", "were"),
+            };
+            return Err(Box::new(N2VError {
+                msg: format!(
+                    "Chip {} was simulated before its {} {} {} set.",
+                    self.chip.name,
+                    inputs,
+                    names.join(", "),
+                    were
+                ),
+                kind: ErrorKind::SimulationError(
+                    self.chip.hdl.as_ref().and_then(|h| h.path.clone()),
+                ),
+            }));
+        }
         let ports = self.chip.ports.clone();
         for (port_name, port) in ports {
             if port.direction == PortDirection::Out {
@@ -70,54 +307,249 @@ impl Simulator {
             self.chip.signals.insert_option(&bus_idx, port_input)
         }
 
+        if let Some(engine) = &mut self.engine {
+            let outputs = engine.simulate(inputs)?;
+            for (port_name, port) in &self.chip.ports {
+                if port.direction == PortDirection::Out {
+                    let bus = Bus {
+                        name: port_name.clone(),
+                        range: Some(0..port.width),
+                    };
+                    self.chip.signals.insert_option(&bus, outputs.get_bus(&bus));
+                }
+            }
+            return Ok(outputs);
+        }
+
         self.chip.dirty = true;
         self.chip
-            .compute(&mut self.input_cache, &mut self.dirty_dffs)?;
+            .compute(
+                &mut self.input_cache,
+                &mut self.dirty_dffs,
+                self.settle_limit,
+            )
+            .map_err(|e| self.chip.size_error(e))?;
 
         Ok(self.chip.get_port_values())
     }
 
+    /// Sets how large the chip may elaborate to, in place of the limits
+    /// it was built with.
+    pub fn set_size_limits(&mut self, max_instances: usize, max_bus_bits: usize) {
+        *self.chip.tree.limits.lock().unwrap() = Some(Size {
+            instances: max_instances,
+            bus_bits: max_bus_bits,
+        });
+    }
+
+    /// Holds down a key on every `Keyboard` in the chip, as a nand2tetris
+    /// key code. 0 releases it. Takes effect at the next `simulate`.
+    pub fn press_key(&mut self, key: u16) {
+        if key != self.chip.tree.key.load(Ordering::Relaxed) {
+            self.chip.tree.key.store(key, Ordering::Relaxed);
+            self.chip.press_key(key);
+        }
+    }
+
+    /// Memory of the screen in the chip, if it has one.
+    pub fn screen(&self) -> Option<&[u64]> {
+        self.chip.screen()
+    }
+
+    /// Saves the flip-flops, memories and devices of the chip.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            whidl: String::from(env!("CARGO_PKG_VERSION")),
+            chip: self.chip.name.clone(),
+            cycle: 0,
+            key: self.chip.tree.key.load(Ordering::Relaxed),
+            state: self.chip.save_state(),
+        }
+    }
+
+    /// Restores a snapshot of the same chip, normally into a new simulator.
+    /// Outputs reflect it from the next `simulate`.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), Box<dyn Error>> {
+        self.interpreted("restore a snapshot")?;
+        if snapshot.chip != self.chip.name {
+            return Err(Box::new(N2VError {
+                msg: format!(
+                    "The snapshot is of chip {}, not {}.",
+                    snapshot.chip, self.chip.name
+                ),
+                kind: ErrorKind::SimulationError(None),
+            }));
+        }
+        self.restore_state(&snapshot.state)?;
+        self.press_key(snapshot.key);
+        Ok(())
+    }
+
+    fn restore_state(&mut self, state: &ChipState) -> Result<(), Box<dyn Error>> {
+        self.input_cache.clear();
+        self.chip
+            .restore_state(state, &mut self.dirty_dffs)
+            .map_err(|e| self.chip.size_error(e))
+    }
+
     // Tick advances the clock without changing the inputs to the chip.
     pub fn tick(&mut self) -> Result<(), Box<dyn Error>> {
-        let dffs_this_tick = self.dirty_dffs.clone();
-        self.dirty_dffs.clear();
+        let _span = trace_span!("tick", chip = %self.chip.name).entered();
+        if let Some(engine) = &mut self.engine {
+            return engine.tick();
+        }
+        let mut dffs_this_tick = std::mem::take(&mut self.dirty_dffs);
+        // Builtins may be queued more than once and must only tick once.
+        dffs_this_tick.sort();
+        dffs_this_tick.dedup();
         let mut parents = Vec::new();
-        for dff_ref in dffs_this_tick {
-            let mut dff = unsafe { dff_ref.as_mut().unwrap() };
+        for path in dffs_this_tick {
+            let dff = self.chip.descendant_mut(&path);
 
-            dff.signals.insert_option(
-                &Bus {
-                    name: String::from("out"),
-                    range: Some(0..1),
-                },
-                dff.signals.get_bus(&Bus {
-                    name: String::from("in"),
-                    range: Some(0..1),
-                }),
-            );
+            if let Some(builtin) = dff.builtin.as_mut() {
+                builtin.tick(&dff.signals);
+            } else {
+                let value = dff.signals.get_bit("in", 0);
+                dff.signals.set_bit("out", 0, value);
+            }
             dff.dirty = true;
+            // A top-level builtin has no parent to recompute it.
+            let top_builtin = dff.builtin.is_some() && path.is_empty();
 
             // chase parents up to the top level chip
             // mark everything along the way as dirty, no cache.
-            let mut parent = dff.parent;
-            while !parent.is_null() {
-                let parent_chip;
-                unsafe {
-                    parent_chip = &mut *parent;
-                }
+            for depth in (0..path.len()).rev() {
+                let parent_chip = self.chip.descendant_mut(&path[..depth]);
                 parent_chip.cache = false;
                 parent_chip.dirty = true;
-                parent = parent_chip.parent;
-                parents.push(parent_chip);
+                parents.push(path[..depth].to_vec());
+            }
+
+            if top_builtin {
+                parents.push(path);
             }
         }
 
-        for parent_chip in parents {
-            parent_chip.compute(&mut self.input_cache, &mut self.dirty_dffs)?;
+        for path in parents {
+            self.chip
+                .descendant_mut(&path)
+                .compute(
+                    &mut self.input_cache,
+                    &mut self.dirty_dffs,
+                    self.settle_limit,
+                )
+                .map_err(|e| self.chip.size_error(e))?;
         }
 
         Ok(())
     }
+
+    /// Value of a port of the chip or a wire between its parts as of the
+    /// last `simulate`, highest bit first.
+    pub fn probe(&self, signal: &str) -> Option<Vec<Option<bool>>> {
+        match &self.engine {
+            Some(engine) => engine.probe(signal),
+            None => self.chip.probe(signal),
+        }
+    }
+
+    /// Puts the chip back in the state it started in. The interpreter builds
+    /// it again with the same generic arguments, keeping parts without
+    /// state as `rebind` does.
+    pub fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(engine) = &mut self.engine {
+            return engine.reset();
+        }
+        let hdl = match &self.chip.hdl {
+            Some(hdl) => hdl.clone(),
+            // A top-level NAND or DFF.
+            None => {
+                let chip = match self.chip.name.as_str() {
+                    "DFF" => make_dff_chip(&self.chip.hdl_provider),
+                    _ => make_nand_chip(&self.chip.hdl_provider),
+                };
+                *self = Simulator::new(chip);
+                return Ok(());
+            }
+        };
+        let generics = hdl
+            .generic_decls
+            .iter()
+            .map(|g| self.chip.variables.get(&g.value).copied())
+            .collect::<Option<Vec<usize>>>()
+            .ok_or_else(|| {
+                Box::new(N2VError {
+                    msg: format!(
+                        "Chip {} has generic arguments that are not widths, so it cannot be reset.",
+                        self.chip.name
+                    ),
+                    kind: ErrorKind::SimulationError(None),
+                })
+            })?;
+        self.rebind(&generics)
+    }
+}
+
+impl SimEngine for Simulator {
+    fn simulate(&mut self, inputs: &BusMap) -> Result<BusMap, Box<dyn Error>> {
+        Simulator::simulate(self, inputs)
+    }
+
+    fn tick(&mut self) -> Result<(), Box<dyn Error>> {
+        Simulator::tick(self)
+    }
+
+    fn probe(&self, signal: &str) -> Option<Vec<Option<bool>>> {
+        Simulator::probe(self, signal)
+    }
+
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        Simulator::reset(self)
+    }
+}
+
+/// A copy of the simulator with the same state, which simulates on its own
+/// from then on. Parts already elaborated are copied rather than built
+/// again.
+impl Clone for Simulator {
+    fn clone(&self) -> Simulator {
+        let mut chip = self.chip.copy();
+        chip.tree = Arc::new(self.chip.tree.copy());
+        chip.adopt_parts();
+        Simulator {
+            input_cache: self.input_cache.clone(),
+            dirty_dffs: self.dirty_dffs.clone(),
+            chip,
+            settle_limit: self.settle_limit,
+            dependencies: self.dependencies.clone(),
+            engine: self.engine.clone(),
+        }
+    }
+}
+
+/// Hands out simulators of one chip, e.g. to grade many submissions against
+/// the same reference design. The chip is elaborated once, when the pool is
+/// made, and each simulator is a copy of it in its initial state. A pool can
+/// be shared between threads, and simulators it hands out moved to them.
+pub struct SimulatorPool {
+    prototype: Simulator,
+}
+
+impl SimulatorPool {
+    pub fn new(chip: Chip) -> Result<SimulatorPool, Box<dyn Error>> {
+        let mut prototype = Simulator::new(chip);
+        prototype
+            .chip
+            .elaborate_all()
+            .map_err(|e| prototype.chip.size_error(e))?;
+        Ok(SimulatorPool { prototype })
+    }
+
+    /// A new simulator of the chip.
+    pub fn get(&self) -> Simulator {
+        self.prototype.clone()
+    }
 }
 
 impl Serialize for Chip {
@@ -143,15 +575,22 @@ pub struct Port {
 // A chip constructed from parsed HDL.
 pub struct Chip {
     pub name: String,
-    hdl: Option<ChipHDL>, // This should probably be a reference. We don't need to have a zillion copies of the HDL.
+    hdl: Option<Arc<ChipHDL>>, // Shared by copies of the chip.
     pub circuit: Circuit,
     pub ports: HashMap<String, Port>,
     input_port_nodes: Vec<NodeIndex>,
     output_port_nodes: Vec<NodeIndex>,
     pub signals: BusMap,
     elaborated: bool,
-    parent: *mut Chip,
-    pub components: Vec<Component>, // Constructed from HDL parts which may contain for-generate loops.
+    // Where the chip is in the tree: the node of each part leading down to
+    // it from the top-level chip, which has an empty path.
+    path: Vec<NodeIndex>,
+    // The chips above this one, for finding parts that contain themselves.
+    above: Option<Arc<Lineage>>,
+    // What the chip shares with every chip in its tree.
+    tree: Arc<Tree>,
+    pub components: Arc<Vec<Component>>, // Constructed from HDL parts which may contain for-generate loops.
+    part_nodes: Vec<(NodeIndex, Option<usize>)>, // Node of each component, and its lane if it shares one.
 
     dirty: bool,
     cache: bool,
@@ -160,10 +599,104 @@ pub struct Chip {
     // for lazily elaborating itself. This is reference counted because
     // elaboration makes new chips with HdlProviders and we don't know
     // size at compilation time.
-    hdl_provider: Rc<dyn HdlProvider>,
+    hdl_provider: Arc<dyn HdlProvider>,
 
     // Values of variables (generics and iterators)
     variables: HashMap<String, usize>,
+
+    // Native implementation for chips declared with `BUILTIN`.
+    builtin: Option<Box<dyn Builtin>>,
+
+    // What the chip has elaborated itself, not counting what its parts
+    // have.
+    size: Size,
+
+    // Where each bit of each signal comes from, so that wires between
+    // parts can be probed. Only kept by the top-level chip.
+    sources: HashMap<String, Vec<Option<(NodeIndex, Bus)>>>,
+}
+
+// Parts and bits of signals in a chip and everything it has elaborated.
+#[derive(Clone, Copy, Default)]
+struct Size {
+    instances: usize,
+    bus_bits: usize,
+}
+
+impl std::ops::AddAssign for Size {
+    fn add_assign(&mut self, other: Size) {
+        self.instances += other.instances;
+        self.bus_bits += other.bus_bits;
+    }
+}
+
+// State kept once for a top-level chip and all of its parts, which each
+// hold a reference to it.
+#[derive(Default)]
+struct Tree {
+    // What the whole tree has elaborated so far.
+    size: Mutex<Size>,
+    // How large the tree may grow, if not as its options say.
+    limits: Mutex<Option<Size>>,
+    // Key held down on the keyboard.
+    key: AtomicU16,
+    // How the top-level chip was built, which its parts follow too.
+    options: ChipOptions,
+}
+
+impl Tree {
+    // A tree of the same size, limits and key, for a copy of a chip.
+    fn copy(&self) -> Tree {
+        Tree {
+            size: Mutex::new(*self.size.lock().unwrap()),
+            limits: Mutex::new(*self.limits.lock().unwrap()),
+            key: AtomicU16::new(self.key.load(Ordering::Relaxed)),
+            options: self.options.clone(),
+        }
+    }
+}
+
+// A tree that has grown past one of its limits. The top-level chip turns
+// this into an error naming where the tree is largest.
+#[derive(Debug)]
+struct TooLarge {
+    measure: fn(&Size) -> usize,
+    limit: usize,
+    what: &'static str,
+    option: &'static str,
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The chip has grown past the limit of {} {}. Raise the limit with {} if the chip really is this large.",
+            self.limit, self.what, self.option
+        )
+    }
+}
+
+impl Error for TooLarge {}
+
+// A chip with parts, as its parts see it from below, and the chips above it.
+struct Lineage {
+    name: String,
+    path: Option<PathBuf>,
+    variables: HashMap<String, usize>,
+    display_name: String,
+    up: Option<Arc<Lineage>>,
+}
+
+impl Lineage {
+    fn of(chip: &Chip) -> Lineage {
+        Lineage {
+            name: chip.name.clone(),
+            path: chip.hdl.as_ref().and_then(|h| h.path.clone()),
+            variables: chip.variables.clone(),
+            display_name: chip.display_name(),
+            up: chip.above.clone(),
+        }
+    }
 }
 
 impl fmt::Debug for Chip {
@@ -181,131 +714,198 @@ pub struct Wire {
     pub target: Bus,
 }
 
+/// How `Chip::new` builds a chip.
+#[derive(Clone, Debug)]
+pub struct ChipOptions {
+    /// Generic arguments of the chip, e.g. `[16]` for `Mux<16>`.
+    pub generics: Vec<usize>,
+    /// Elaborate the chip's parts right away rather than when it is first
+    /// simulated. Parts of parts are still elaborated lazily.
+    pub elaborate: bool,
+    /// Levels of the lints the chip and its parts are checked for.
+    pub lints: Levels,
+    /// Parts the chip may elaborate to, counting those of its parts all the
+    /// way down, before elaboration is refused.
+    pub max_instances: usize,
+    /// Bits of the signals the chip may elaborate to, counted the same way.
+    pub max_bus_bits: usize,
+    /// Times a chip may be a part of itself, through any chain of parts,
+    /// with different generic arguments each time. A chip whose arguments
+    /// shrink until a loop in it has nothing to generate stops recursing on
+    /// its own; any other recursion never finishes.
+    pub max_recursion: usize,
+    /// Passes over its parts a chip may take to settle before it is
+    /// reported as oscillating. Simulators of the chip start with it.
+    pub settle_limit: usize,
+    /// How unknown bits spread through NANDs.
+    pub x_policy: XPolicy,
+    /// How warnings found building and simulating the chip are printed.
+    pub output: Output,
+}
+
+impl Default for ChipOptions {
+    fn default() -> Self {
+        ChipOptions {
+            generics: Vec::new(),
+            elaborate: false,
+            lints: Levels::default(),
+            max_instances: DEFAULT_MAX_INSTANCES,
+            max_bus_bits: DEFAULT_MAX_BUS_BITS,
+            max_recursion: 0,
+            settle_limit: DEFAULT_SETTLE_LIMIT,
+            x_policy: XPolicy::Pessimistic,
+            output: Output::default(),
+        }
+    }
+}
+
 impl Chip {
-    /// Constructs a Chip from the parse tree.
+    /// The options the top-level chip of this one's tree was built with.
+    pub fn options(&self) -> &ChipOptions {
+        &self.tree.options
+    }
+
+    /// Constructs a top-level Chip from the parse tree.
     pub fn new(
         hdl: &ChipHDL,
-        parent: *mut Chip,
-        hdl_provider: &Rc<dyn HdlProvider>,
-        elaborate: bool,
-        generics: &Vec<usize>, // generic args when this chip is being created.
-    ) -> Result<Chip, Box<dyn Error>> {
-        let circuit = Circuit::new();
+        hdl_provider: &Arc<dyn HdlProvider>,
+        options: ChipOptions,
+    ) -> Result<Chip, WhidlError> {
+        let generics: Vec<GenericValue> = options
+            .generics
+            .iter()
+            .map(|g| GenericValue::from(*g))
+            .collect();
+        Ok(Self::with_generics(hdl, hdl_provider, &generics, &options)?)
+    }
 
-        if hdl.name.to_uppercase() == "NAND" {
-            return Ok(make_nand_chip(parent, hdl_provider));
-        } else if hdl.name.to_uppercase() == "DFF" {
-            return Ok(make_dff_chip(parent, hdl_provider));
+    /// Constructs a top-level Chip whose generic arguments may be strings as
+    /// well as widths. Widths must already be numbers.
+    pub fn with_generics(
+        hdl: &ChipHDL,
+        hdl_provider: &Arc<dyn HdlProvider>,
+        generics: &[GenericValue],
+        options: &ChipOptions,
+    ) -> Result<Chip, Box<dyn Error>> {
+        let tree = Arc::new(Tree {
+            options: options.clone(),
+            ..Tree::default()
+        });
+        let mut chip = Self::build(hdl, hdl_provider, generics, &tree, true)?;
+        if options.elaborate && chip.builtin.is_none() {
+            chip.elaborate().map_err(|e| chip.size_error(e))?;
         }
+        Ok(chip)
+    }
 
-        // Assign values to generic variables.
-        if generics.len() != hdl.generic_decls.len() {
-            return Err(Box::new(N2VError {
-                msg: format!(
-                    "Chip {} declares {} generics but instantiated with {}",
-                    hdl.name,
-                    hdl.generic_decls.len(),
-                    generics.len()
-                ),
-                kind: ErrorKind::SimulationError(hdl.path.clone()),
-            }));
-        }
-        let mut variables = HashMap::new();
+    // Constructs a chip of the tree without elaborating it. Only the
+    // top-level chip looks for combinational loops, since it does so for its
+    // whole tree.
+    fn build(
+        hdl: &ChipHDL,
+        hdl_provider: &Arc<dyn HdlProvider>,
+        generics: &[GenericValue],
+        tree: &Arc<Tree>,
+        top: bool,
+    ) -> Result<Chip, Box<dyn Error>> {
+        let circuit = Circuit::new();
 
-        #[allow(clippy::needless_range_loop)]
-        for gv in 0..hdl.generic_decls.len() {
-            variables.insert(hdl.generic_decls[gv].value.clone(), generics[gv]);
+        if hdl.name.to_uppercase() == "NAND" || hdl.name.to_uppercase() == "DFF" {
+            let mut chip = if hdl.name.to_uppercase() == "NAND" {
+                make_nand_chip(hdl_provider)
+            } else {
+                make_dff_chip(hdl_provider)
+            };
+            chip.tree = Arc::clone(tree);
+            return Ok(chip);
         }
-
-        // Signals for this component.
-        // Circuit graph holds the actual signal data.
-        let mut signals = BusMap::new();
-
-        // Create port signals
-        for port in &hdl.ports {
-            let width = eval_expr_numeric(&port.width, &variables)?;
-
-            if let Err(e) = signals.create_bus(&port.name.value, width) {
-                return Err(Box::new(N2VError {
-                    msg: { format!("Cannot create port {}: {}", port.name.value, e) },
-                    kind: ErrorKind::ParseIdentError(hdl_provider.clone(), port.name.clone()),
-                }));
-            }
+        // Parts are checked for shadowing where they are connected.
+        if top {
+            let shadowed = shadowed_chip(hdl).into_iter().collect();
+            report_warnings(shadowed, hdl_provider, &tree.options)?;
         }
-
-        // Create component definitions (expand for-generate loops).
-        let components = Self::generate_components(hdl, generics)?;
-
-        let general_generics: Vec<GenericWidth> = generics
-            .iter()
-            .map(|x| GenericWidth::Terminal(Terminal::Num(*x)))
-            .collect();
-        let inferred_widths = infer_widths(hdl, &components, hdl_provider, &general_generics)?;
-
-        // Create disconnected internal signals.
-        // These are connected below.
-        for (n, iw) in inferred_widths {
-            // To create a full chip we need only numeric expressions.
-            if let GenericWidth::Terminal(Terminal::Num(x)) = iw {
-                if let Err(e) = signals.create_bus(&n, x) {
+        let BoundChip {
+            hdl,
+            provider,
+            variables,
+            strings,
+            ports,
+            signals,
+            components,
+        } = bind(hdl, generics, hdl_provider)?;
+        let hdl = Arc::new(hdl);
+        let hdl_provider = &provider;
+
+        // Use a native implementation if one is registered, otherwise fall
+        // back to the structural parts list. Tables are always evaluated
+        // directly.
+        let builtin = match (&hdl.table, hdl.builtin_name()) {
+            (Some(t), _) => Some(get_table(&hdl, t, &signals)?),
+            (None, None) => None,
+            // ROM contents come from a file rather than from the name.
+            (None, Some(b)) if b.value == "ROM" => Some(get_rom(&hdl, strings.get("FILE"))?),
+            // Memory maps in the peripherals found next to the chip.
+            (None, Some(b)) if b.value == "Memory" => {
+                Some(get_memory(hdl_provider, &tree.options)?)
+            }
+            (None, Some(b)) => {
+                let native = get_builtin(&b.value);
+                if native.is_none() && hdl.parts.is_empty() {
                     return Err(Box::new(N2VError {
-                        msg: {
-                            format!(
-                                "{:?} Cannot create internal signal {} of width {}. {}",
-                                hdl.path, n, iw, e
-                            )
-                        },
-                        kind: ErrorKind::SimulationError(hdl.path.clone()),
+                        msg: format!(
+                            "No builtin implementation registered for {} and chip has no parts.",
+                            b.value
+                        ),
+                        kind: ErrorKind::ParseIdentError(hdl_provider.clone(), b.clone()),
                     }));
                 }
-            } else {
-                return Err(Box::new(N2VError {
-                    msg: {
-                        format!(
-                            "{:?} Cannot create internal signal {} of width {}.",
-                            hdl.path, n, iw
-                        )
-                    },
-                    kind: ErrorKind::SimulationError(hdl.path.clone()),
-                }));
+                native
             }
+        };
+        if hdl.builtin_name().is_none() && hdl.table.is_none() && hdl.parts.is_empty() {
+            return Err(Box::new(N2VError {
+                msg: format!(
+                    "Chip {} is an interface stub with no parts, so it cannot be simulated.",
+                    hdl.name
+                ),
+                kind: ErrorKind::SimulationError(hdl.path.clone()),
+            }));
         }
+        let sequential = builtin.as_ref().is_some_and(|b| b.is_sequential());
 
-        let ports = hdl
-            .ports
-            .iter()
-            .map(|x| {
-                let pw = eval_expr_numeric(&x.width, &variables)?;
-                Ok((
-                    x.name.value.clone(),
-                    Port {
-                        direction: x.direction,
-                        name: x.name.clone(),
-                        width: pw,
-                    },
-                ))
-            })
-            .collect::<Result<HashMap<String, Port>, N2VError>>()?;
-
-        let mut chip = Chip {
+        let chip = Chip {
             name: hdl.name.clone(),
             ports,
             signals,
             hdl: Some(hdl.clone()),
             elaborated: false,
             circuit,
-            dirty: false,
+            // Sequential builtins are computed once even if none of their
+            // inputs change, since a keyboard has no inputs at all.
+            dirty: sequential,
             input_port_nodes: Vec::new(),
             output_port_nodes: Vec::new(),
-            cache: true,
-            parent,
-            hdl_provider: Rc::clone(hdl_provider),
+            // Outputs of a chip with CLOCKED pins depend on internal state,
+            // not just on the current inputs, so they can never be cached.
+            cache: hdl.clocked.is_empty() && !sequential,
+            path: Vec::new(),
+            above: None,
+            tree: Arc::clone(tree),
+            hdl_provider: Arc::clone(hdl_provider),
             variables,
-            components,
+            components: Arc::new(components),
+            part_nodes: Vec::new(),
+            builtin,
+            size: Size::default(),
+            sources: HashMap::new(),
         };
 
-        if elaborate {
-            chip.elaborate()?;
+        // The top chip looks for combinational loops in its whole tree
+        // before anything is simulated.
+        if top && chip.builtin.is_none() {
+            let loops =
+                combinational_loops(&hdl, generics, hdl_provider, tree.options.max_recursion);
+            report_warnings(loops, hdl_provider, &tree.options)?;
         }
 
         Ok(chip)
@@ -314,12 +914,10 @@ impl Chip {
     // This expands for-generate loops into components for the chip. This
     // cannot be done during parsing because the values of generic variables
     // may not be known until elaboration.
-    fn generate_components(
+    pub fn generate_components(
         hdl: &ChipHDL,
         generics: &Vec<usize>,
     ) -> Result<Vec<Component>, N2VError> {
-        let mut res = Vec::new();
-
         // Assign values to generic variables.
         if generics.len() != hdl.generic_decls.len() {
             return Err(N2VError {
@@ -332,21 +930,28 @@ impl Chip {
                 kind: ErrorKind::SimulationError(hdl.path.clone()),
             });
         }
-        let mut variables = HashMap::new();
-
-        #[allow(clippy::needless_range_loop)]
-        for gv in 0..hdl.generic_decls.len() {
-            variables.insert(hdl.generic_decls[gv].value.clone(), generics[gv]);
-        }
+        let variables: HashMap<String, usize> = hdl
+            .generic_decls
+            .iter()
+            .map(|d| d.value.clone())
+            .zip(generics.iter().cloned())
+            .collect();
+        Self::expand_loops(hdl, &variables)
+    }
 
+    pub fn expand_loops(
+        hdl: &ChipHDL,
+        variables: &HashMap<String, usize>,
+    ) -> Result<Vec<Component>, N2VError> {
+        let mut res = Vec::new();
         for part in &hdl.parts {
             match part {
                 Part::Component(c) => {
                     res.push(c.clone());
                 }
                 Part::Loop(l) => {
-                    let start = eval_expr_numeric(&l.start, &variables)?;
-                    let end = eval_expr_numeric(&l.end, &variables)?;
+                    let start = eval_expr_numeric(&l.start, variables)?;
+                    let end = eval_expr_numeric(&l.end, variables)?;
 
                     // Replace any instances of iterator with current iterator value.
                     for i in start..(end + 1) {
@@ -367,8 +972,11 @@ impl Chip {
                                 m.wire.end = m.wire.end.as_ref().map(replace);
                             }
 
-                            new_c.generic_params =
-                                new_c.generic_params.iter().map(replace).collect();
+                            new_c.generic_params = new_c
+                                .generic_params
+                                .iter()
+                                .map(|g| g.map_width(replace))
+                                .collect();
 
                             res.push(new_c);
                         }
@@ -380,12 +988,210 @@ impl Chip {
         Ok(res)
     }
 
-    fn elaborate(&mut self) -> Result<(), Box<dyn Error>> {
-        let self_ptr = self as *mut Chip;
-        self.elaborated = true;
-        if self.hdl.is_none() {
-            return Ok(());
+    // The indices in the expanded components of the copies of each part in
+    // a loop body, in the order `expand_loops` makes them.
+    fn loop_copies(
+        hdl: &ChipHDL,
+        variables: &HashMap<String, usize>,
+    ) -> Result<Vec<Vec<usize>>, N2VError> {
+        let mut copies = Vec::new();
+        let mut next = 0;
+        for part in &hdl.parts {
+            match part {
+                Part::Component(_) => next += 1,
+                Part::Loop(l) => {
+                    let start = eval_expr_numeric(&l.start, variables)?;
+                    let end = eval_expr_numeric(&l.end, variables)?;
+                    let iterations = (end + 1).saturating_sub(start);
+                    let body = l.body.len();
+                    for b in 0..body {
+                        copies.push((0..iterations).map(|i| next + i * body + b).collect());
+                    }
+                    next += iterations * body;
+                }
+            }
         }
+        Ok(copies)
+    }
+
+    // Groups of loop copies of a one-bit gate that can be evaluated side by
+    // side, as indices in `components`. Copies are grouped up to a word at a
+    // time, and only when they have the same generic arguments and none of
+    // them reads a bit that another in the group drives.
+    fn lane_groups(&self) -> Result<Vec<Vec<usize>>, Box<dyn Error>> {
+        let hdl = match &self.hdl {
+            Some(hdl) => hdl,
+            None => return Ok(Vec::new()),
+        };
+        let mut groups = Vec::new();
+        for copies in Self::loop_copies(hdl, &self.variables)? {
+            for group in copies.chunks(bitwise::MAX_LANES) {
+                if group.len() < 2 {
+                    continue;
+                }
+                let first = &self.components[group[0]];
+                let part_hdl = part_hdl(first, &self.hdl_provider)?;
+                let generics = resolve_generics(first, &part_hdl, &self.variables)?;
+                let same_generics = group.iter().all(|&i| {
+                    resolve_generics(&self.components[i], &part_hdl, &self.variables)
+                        .is_ok_and(|g| g == generics)
+                });
+                if same_generics
+                    && self.lanes_independent(group, &part_hdl)
+                    && bitwise::lane_gate(
+                        &part_hdl,
+                        &self.hdl_provider,
+                        &generics,
+                        &self.tree.options,
+                    )
+                    .is_some()
+                {
+                    groups.push(group.to_vec());
+                }
+            }
+        }
+        Ok(groups)
+    }
+
+    // Whether none of the parts reads a signal bit that one of them drives.
+    // Mappings that cannot be evaluated are left for elaboration to report.
+    fn lanes_independent(&self, group: &[usize], part_hdl: &ChipHDL) -> bool {
+        let mut driven = HashSet::new();
+        let mut read = HashSet::new();
+        for &i in group {
+            for m in &self.components[i].mappings {
+                let port = match part_hdl.get_port(&m.port.name) {
+                    Ok(p) => p,
+                    Err(_) => return false,
+                };
+                if m.is_open() {
+                    continue;
+                }
+                let bits = match eval_bus_bits(
+                    &m.wire,
+                    1,
+                    &self.variables,
+                    &self.hdl_provider,
+                    &m.wire_ident,
+                ) {
+                    Ok(bits) => bits,
+                    Err(_) => return false,
+                };
+                let set = match port.direction {
+                    PortDirection::In => &mut read,
+                    PortDirection::Out => &mut driven,
+                };
+                set.extend(bits.into_iter().map(|b| (m.wire.name.clone(), b)));
+            }
+        }
+        driven.is_disjoint(&read)
+    }
+
+    // Dependency hashes of the chips used as parts, by qualified name.
+    fn dependency_hashes(&self) -> Result<HashMap<String, u64>, Box<dyn Error>> {
+        let mut hashes = HashMap::new();
+        for part in self.components.iter() {
+            if let Entry::Vacant(e) = hashes.entry(part.qualified_name()) {
+                let h = dependency_hash(e.key(), &self.hdl_provider)?;
+                e.insert(h);
+            }
+        }
+        Ok(hashes)
+    }
+
+    // Nodes of the parts that could move to a new build of the chip, with
+    // the chip and generic arguments each was built from. Parts that share
+    // a node or hold state are left out.
+    #[allow(clippy::type_complexity)]
+    fn reusable_parts(
+        &self,
+    ) -> Result<Vec<((String, Vec<GenericValue>), NodeIndex)>, Box<dyn Error>> {
+        let mut res = Vec::new();
+        for (part, &(node, lane)) in self.components.iter().zip(&self.part_nodes) {
+            if lane.is_some() || !self.circuit[node].state().is_empty() {
+                continue;
+            }
+            let part_hdl = part_hdl(part, &self.hdl_provider)?;
+            let generics = resolve_generics(part, &part_hdl, &self.variables)?;
+            res.push(((part.qualified_name(), generics), node));
+        }
+        Ok(res)
+    }
+
+    // The part at `path` below this chip.
+    fn descendant_mut(&mut self, path: &[NodeIndex]) -> &mut Chip {
+        path.iter()
+            .fold(self, |chip, &node| &mut chip.circuit[node])
+    }
+
+    // Places every chip below this one in its tree, after it elaborates or
+    // parts have moved between trees or the tree has been copied.
+    fn adopt_parts(&mut self) {
+        if self.circuit.node_count() == 0 {
+            return;
+        }
+        let above = Arc::new(Lineage::of(self));
+        for node in self.circuit.node_indices() {
+            let mut path = self.path.clone();
+            path.push(node);
+            let part = &mut self.circuit[node];
+            part.path = path;
+            part.tree = Arc::clone(&self.tree);
+            part.above = Some(Arc::clone(&above));
+            part.adopt_parts();
+        }
+    }
+
+    // Copies the chip and every part it has elaborated, sharing the parsed
+    // HDL and components. The copy shares the tree of the original until
+    // whoever keeps it gives it one of its own and adopts its parts.
+    fn copy(&self) -> Chip {
+        let circuit = self.circuit.map(|_, c| c.copy(), |_, w| w.clone());
+        Chip {
+            name: self.name.clone(),
+            hdl: self.hdl.clone(),
+            circuit,
+            ports: self.ports.clone(),
+            input_port_nodes: self.input_port_nodes.clone(),
+            output_port_nodes: self.output_port_nodes.clone(),
+            signals: self.signals.clone(),
+            elaborated: self.elaborated,
+            path: self.path.clone(),
+            above: self.above.clone(),
+            tree: self.tree.clone(),
+            components: self.components.clone(),
+            part_nodes: self.part_nodes.clone(),
+            dirty: self.dirty,
+            cache: self.cache,
+            hdl_provider: self.hdl_provider.clone(),
+            variables: self.variables.clone(),
+            builtin: self.builtin.clone(),
+            size: self.size,
+            sources: self.sources.clone(),
+        }
+    }
+
+    // Elaborates every part that is not builtin, all the way down.
+    fn elaborate_all(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.builtin.is_some() {
+            return Ok(());
+        }
+        if !self.elaborated {
+            self.elaborate()?;
+        }
+        for part in self.circuit.node_weights_mut() {
+            part.elaborate_all()?;
+        }
+        Ok(())
+    }
+
+    fn elaborate(&mut self) -> Result<(), Box<dyn Error>> {
+        self.elaborated = true;
+        if self.hdl.is_none() {
+            return Ok(());
+        }
+        let _span = debug_span!("elaborate", chip = %self.name).entered();
+        let above = Arc::new(Lineage::of(self));
 
         // Where each bit of the signal source comes from.
         let mut signal_sources: HashMap<String, Vec<Option<(NodeIndex, Bus)>>> = HashMap::new();
@@ -395,7 +1201,7 @@ impl Chip {
             if port.direction == PortDirection::Out {
                 continue;
             }
-            let port_chip = make_port_chip(port_name, port.width, self_ptr, &self.hdl_provider);
+            let port_chip = make_port_chip(port_name, port.width, &self.hdl_provider);
             let port_node = self.circuit.add_node(port_chip);
             self.input_port_nodes.push(port_node);
 
@@ -411,198 +1217,120 @@ impl Chip {
             signal_sources.insert(port.name.value.clone(), source);
         }
 
-        let mut need_true_literal = false;
-        let mut need_false_literal = false;
+        // Every mapping is checked before anything is built.
+        let mut warnings = self.hdl.as_deref().map(hdl_warnings).unwrap_or_default();
+        let instances = connect(
+            &self.components,
+            &self.ports,
+            &self.signals,
+            &self.variables,
+            &self.hdl_provider,
+            &mut warnings,
+        )?;
+        report_warnings(warnings, &self.hdl_provider, &self.tree.options)?;
+        let uses_literal = |literal: &str| {
+            instances
+                .iter()
+                .flat_map(|i| &i.connections)
+                .any(|c| c.wire == literal)
+        };
+        let need_true_literal = uses_literal("true");
+        let need_false_literal = uses_literal("false");
 
         // Create components and handle out ports from components into signals
-        // indices of created_components needs to match order of parts
-        // Also checks if true/false literals are used.
-        let mut created_components: Vec<NodeIndex> = Vec::new();
-        for (_, part) in self.components.iter().enumerate() {
-            let part_hdl = get_hdl(&part.name.value, &self.hdl_provider)?;
-
-            // Convert generics with vars to concrete generics for component.
-            // e.g. Mux<W> needs to become Mux<4> if W=4. At this point
-            // we need actual bus widths.
-            let mut resolved_generics: Vec<usize> = Vec::new();
-            for g in &part.generic_params {
-                if let GenericWidth::Terminal(Terminal::Num(w)) = g {
-                    resolved_generics.push(*w);
-                } else if let GenericWidth::Terminal(Terminal::Var(w)) = g {
-                    let rw = self.variables.get(&w.value).unwrap();
-                    resolved_generics.push(*rw);
-                }
-            }
-
-            let part_chip = Chip::new(
-                &part_hdl,
-                self_ptr,
-                &Rc::clone(&self.hdl_provider),
-                false, // Only elaborate one level deep.
-                &resolved_generics,
+        // indices of part_nodes needs to match order of parts
+        // Copies of a one-bit gate made by a loop share one node, where each
+        // copy's ports are one bit of the node's ports.
+        let lane_groups = self.lane_groups()?;
+        let lane_of: HashMap<usize, (usize, usize)> = lane_groups
+            .iter()
+            .enumerate()
+            .flat_map(|(g, group)| group.iter().enumerate().map(move |(k, &i)| (i, (g, k))))
+            .collect();
+        let mut lane_nodes: HashMap<usize, NodeIndex> = HashMap::new();
+        let mut part_nodes: Vec<(NodeIndex, Option<usize>)> = Vec::new();
+        for (part_idx, instance) in instances.iter().enumerate() {
+            // Only elaborate one level deep.
+            let mut part_chip = Chip::build(
+                &instance.hdl,
+                &Arc::clone(&self.hdl_provider),
+                &instance.generics,
+                &self.tree,
+                false,
             )?;
-            let part_variables = part_chip.variables.clone();
-
-            let mut used_port_buses: BusMap = BusMap::new();
-            for (port_name, port) in &part_chip.ports {
-                used_port_buses.create_bus(port_name, port.width)?;
+            self.check_recursion(&part_chip, &above, &self.components[part_idx].name)?;
+            let lane = lane_of.get(&part_idx).copied();
+            // Parts that apply one gate to every bit of a word are evaluated
+            // a word at a time. Top-level chips keep their circuits so their
+            // internal signals can still be inspected.
+            if lane.is_none() && part_chip.builtin.is_none() && instance.hdl.clocked.is_empty() {
+                part_chip.builtin = bitwise::recognize(
+                    &instance.hdl,
+                    &self.hdl_provider,
+                    &instance.generics,
+                    &part_chip.ports,
+                    &self.tree.options,
+                );
             }
 
-            let part_node = self.circuit.add_node(part_chip);
-            created_components.push(part_node);
-
-            for m in &part.mappings {
-                let signal_name = &m.wire.name;
-                let port = match part_hdl.get_port(&m.port.name) {
-                    Ok(x) => x,
-                    Err(_) => {
-                        let err_more_info = N2VError {
-                            kind: ErrorKind::ParseIdentError(
-                                self.hdl_provider.clone(),
-                                m.wire_ident.clone(),
-                            ),
-                            msg: format!("Attempt to get non-existent port {}.", &m.port.name),
-                        };
-
-                        return Err(Box::new(err_more_info));
-                    }
-                };
-                if signal_name == "true" {
-                    need_true_literal = true;
-                }
-                if signal_name == "false" {
-                    need_false_literal = true;
-                }
-
-                let port_width = eval_expr_numeric(&port.width, &part_variables)?;
-                let port_start = match &m.port.start {
-                    None => 0,
-                    Some(x) => eval_expr_numeric(x, &self.variables)?,
-                };
-                let port_end = match &m.port.end {
-                    None => port_width - 1,
-                    Some(x) => eval_expr_numeric(x, &self.variables)?,
-                };
-                // Convert inclusive range in HDL to exclusive Range in Rust
-                let port_range: Range<usize> = Range {
-                    start: port_start,
-                    end: port_end + 1,
-                };
-
-                // Insert port range for the pupose of verifying that we have
-                // inputs for all of the input pins. Skip the rest of the loop.
-                if port.direction == PortDirection::In {
-                    let used_bus = Bus {
-                        name: m.port.name.clone(),
-                        range: Some(port_range.clone()),
+            let (part_node, lane) = match lane {
+                None => (self.circuit.add_node(part_chip), None),
+                Some((g, k)) => {
+                    let node = match lane_nodes.get(&g) {
+                        Some(&node) => node,
+                        None => {
+                            let count = lane_groups[g].len();
+                            let gate = bitwise::lane_gate(
+                                &instance.hdl,
+                                &self.hdl_provider,
+                                &instance.generics,
+                                &self.tree.options,
+                            )
+                            .unwrap();
+                            debug!(part = %part_chip.name, lanes = count, "evaluating loop copies side by side");
+                            let lanes_chip = make_lanes_chip(&part_chip, count, gate);
+                            let node = self.circuit.add_node(lanes_chip);
+                            lane_nodes.insert(g, node);
+                            node
+                        }
                     };
-                    used_port_buses.insert_option(&used_bus, vec![Some(true); port_range.len()]);
-
-                    continue;
+                    (node, Some(k))
                 }
+            };
+            part_nodes.push((part_node, lane));
+            let offset = lane.unwrap_or(0);
 
-                let wire_start = match &m.wire.start {
-                    None => 0,
-                    Some(x) => eval_expr_numeric(x, &self.variables)?,
-                };
-                let wire_end = match &m.wire.end {
-                    None => port_width - 1,
-                    Some(x) => eval_expr_numeric(x, &self.variables)?,
-                };
-                // Convert inclusive range in HDL to exclusive Range in Rust
-                let wire_range: Range<usize> = Range {
-                    start: wire_start,
-                    end: wire_end + 1,
-                };
-
-                if signal_sources.get(signal_name).is_none() {
-                    signal_sources.insert(
-                        signal_name.clone(),
-                        vec![None; self.signals.get_width(signal_name).unwrap()],
-                    );
+            for c in &instance.connections {
+                if c.direction == PortDirection::In {
+                    continue;
                 }
-
-                let mut i = port_range.start;
-                let mut j = wire_range.start;
-                while i < port_range.end {
-                    // Check here to see if a bit already has a source.
-                    // If it does we have an error in the HDL.
-                    if signal_sources.get(signal_name).unwrap()[j].is_some() {
-                        return Err(Box::new(N2VError {
-                            kind: ErrorKind::ParseIdentError(
-                                self.hdl_provider.clone(),
-                                m.wire_ident.clone(),
-                            ),
-                            msg: format!("Duplicate source for signal name {}.", signal_name),
-                        }));
-                    }
-
-                    signal_sources.get_mut(signal_name).unwrap()[j] = Some((
+                let width = self.signals.get_width(&c.wire).unwrap();
+                let sources = signal_sources
+                    .entry(c.wire.clone())
+                    .or_insert_with(|| vec![None; width]);
+                for &(i, j) in &c.bits {
+                    sources[j] = Some((
                         part_node,
                         Bus {
-                            name: port.name.value.clone(),
-                            range: Some(i..i + 1),
+                            name: c.port.clone(),
+                            range: Some(i + offset..i + offset + 1),
                         },
                     ));
-                    i += 1;
-                    j += 1;
-                }
-            }
-
-            // Make sure we have inputs for every port bit.
-            // for each port in the used busmap
-            // if any bit is None, then warn.
-            for port in part_hdl.ports {
-                if port.direction == PortDirection::Out {
-                    continue;
-                }
-
-                let used_bits = used_port_buses.get_name(&port.name.value);
-                for b in used_bits {
-                    if b.is_none() {
-                        return Err(Box::new(N2VError {
-                            kind: ErrorKind::ParseIdentError(
-                                self.hdl_provider.clone(),
-                                part.name.clone(),
-                            ),
-                            msg: format!(
-                                "Component does not provide inputs for all bits of {}.",
-                                &port.name.value
-                            ),
-                        }));
-                    }
                 }
             }
         }
 
         // Create true/false literals only if a port mapping requires it.
-        let false_chip;
-        let false_node;
-        if need_false_literal {
-            false_chip = make_literal_chip(Some(false), self_ptr, &self.hdl_provider);
-            false_node = self.circuit.add_node(false_chip);
-            let false_vector: Vec<_> = (0..16)
-                .map(|i| {
-                    Some((
-                        false_node,
-                        Bus {
-                            name: String::from("out"),
-                            range: Some(i..i + 1),
-                        },
-                    ))
-                })
-                .collect();
-            signal_sources.insert(String::from("false"), false_vector);
-        }
-        let true_chip;
-        let true_node;
-        if need_true_literal {
-            true_chip = make_literal_chip(Some(true), self_ptr, &self.hdl_provider);
-            true_node = self.circuit.add_node(true_chip);
-            let true_vector: Vec<_> = (0..16)
+        for (value, needed) in [(false, need_false_literal), (true, need_true_literal)] {
+            if !needed {
+                continue;
+            }
+            let literal_chip = make_literal_chip(Some(value), &self.hdl_provider);
+            let literal_node = self.circuit.add_node(literal_chip);
+            let literal_vector: Vec<_> = (0..LITERAL_WIDTH)
                 .map(|i| {
                     Some((
-                        true_node,
+                        literal_node,
                         Bus {
                             name: String::from("out"),
                             range: Some(i..i + 1),
@@ -610,125 +1338,86 @@ impl Chip {
                     ))
                 })
                 .collect();
-            signal_sources.insert(String::from("true"), true_vector);
+            signal_sources.insert(value.to_string(), literal_vector);
         }
 
-        // Closure for retrieving signal source and returning an error if the source is not found.
-        let get_signal_source = |signal_name: &str, idx: usize, relevant_ident: &Identifier| {
-            if signal_sources.contains_key(signal_name) {
-                let signal_source = signal_sources.get(signal_name).unwrap();
-
-                if idx >= signal_source.len() {
-                    return Err(N2VError {
-                        msg: format!(
-                            "Bit {} for signal name {} is out of range.",
-                            idx, signal_name
-                        ),
-                        kind: ErrorKind::ParseIdentError(
-                            self.hdl_provider.clone(),
-                            relevant_ident.clone(),
-                        ),
-                    });
+        // Bits that several tri-state buffers drive get a node of their own,
+        // which resolves the bit from what each of them drives. A part
+        // counts as a buffer if its output only ever comes from TriState
+        // parts further down.
+        let mut shared: BTreeMap<(&str, usize), Vec<(NodeIndex, Bus)>> = BTreeMap::new();
+        for (instance, &(part_node, lane)) in instances.iter().zip(&part_nodes) {
+            let offset = lane.unwrap_or(0);
+            for c in &instance.connections {
+                if c.direction == PortDirection::In
+                    || !drives_tristate(&instance.hdl, &c.port, &self.hdl_provider)
+                {
+                    continue;
                 }
-
-                match &signal_source[idx] {
-                    None => Err(N2VError {
-                        msg: format!("Bit {} for signal name {} is undefined.", idx, signal_name),
-                        kind: ErrorKind::ParseIdentError(
-                            self.hdl_provider.clone(),
-                            relevant_ident.clone(),
-                        ),
-                    }),
-                    Some(x) => Ok(Some(x)),
+                for &(i, j) in &c.bits {
+                    let bus = Bus {
+                        name: c.port.clone(),
+                        range: Some(i + offset..i + offset + 1),
+                    };
+                    shared
+                        .entry((&c.wire, j))
+                        .or_default()
+                        .push((part_node, bus));
                 }
-            } else {
-                Err(N2VError {
-                    msg: format!("No source for signal name {}.", signal_name),
-                    kind: ErrorKind::ParseIdentError(
-                        self.hdl_provider.clone(),
-                        relevant_ident.clone(),
-                    ),
-                })
             }
-        };
+        }
+        for ((wire, j), drivers) in shared {
+            if drivers.len() < 2 {
+                continue;
+            }
+            let shared_chip = make_shared_bit_chip(drivers.len(), &self.hdl_provider);
+            let node = self.circuit.add_node(shared_chip);
+            // Bit k of the node's input is what the kth buffer drives.
+            for (k, (driver, bus)) in drivers.into_iter().enumerate() {
+                let wire = Wire {
+                    source: bus,
+                    target: Bus {
+                        name: String::from("in"),
+                        range: Some(k..k + 1),
+                    },
+                };
+                self.circuit.add_edge(driver, node, wire);
+            }
+            signal_sources.get_mut(wire).unwrap()[j] = Some((
+                node,
+                Bus {
+                    name: String::from("out"),
+                    range: Some(0..1),
+                },
+            ));
+        }
 
-        for (part_idx, part) in self.components.iter().enumerate() {
-            let part_hdl = get_hdl(&part.name.value, &self.hdl_provider)?;
+        // Bits without a source, which `connect` has warned about, get no
+        // wire and stay unknown.
+        let source = |signal_name: &str, idx: usize| {
+            signal_sources.get(signal_name).and_then(|s| s[idx].clone())
+        };
 
-            // Handle in ports from signals to components
-            for m in &part.mappings {
-                let signal_name = &m.wire.name;
-                let port_idx = part_hdl
-                    .ports
-                    .iter()
-                    .position(|x| x.name.value == m.port.name)
-                    .unwrap();
-                let port = &part_hdl.ports[port_idx];
-                if port.direction == PortDirection::Out {
+        // Handle in ports from signals to components
+        for (instance, &(part_node, lane)) in instances.iter().zip(&part_nodes) {
+            let offset = lane.unwrap_or(0);
+            for c in &instance.connections {
+                if c.direction == PortDirection::Out {
                     continue;
                 }
-
-                let port_width = self.eval_port_width(port, &part_hdl, part)?;
-                let port_start = match &m.port.start {
-                    None => 0,
-                    Some(x) => eval_expr_numeric(x, &self.variables)?,
-                };
-                let port_end = match &m.port.end {
-                    None => port_width - 1,
-                    Some(x) => eval_expr_numeric(x, &self.variables)?,
-                };
-                // Convert inclusive range in HDL to exclusive Range in Rust
-                let port_range: Range<usize> = Range {
-                    start: port_start,
-                    end: port_end + 1,
-                };
-
-                let wire_start = match &m.wire.start {
-                    None => 0,
-                    Some(x) => eval_expr_numeric(x, &self.variables)?,
-                };
-                let wire_end = match &m.wire.end {
-                    None => port_width - 1,
-                    Some(x) => eval_expr_numeric(x, &self.variables)?,
-                };
-                // Convert inclusive range in HDL to exclusive Range in Rust
-                let wire_range: Range<usize> = Range {
-                    start: wire_start,
-                    end: wire_end + 1,
-                };
-
-                let mut i = wire_range.start;
-                let mut j = port_range.start;
-
-                // Wire and port ranges must be equal.
-                // Loop over the wire. For each wire bit look up the bit
-                while j < port_range.end {
-                    // source_node is the graph node for the chip feeding into signal
-                    // source_bus is the port/range creating this particular bit.
-                    // signal_idx is the index of bit in the signal.
-                    let (source_node, source_bus) =
-                        match get_signal_source(signal_name, i, &m.wire_ident)? {
-                            Some(x) => x,
-                            None => {
-                                i += 1;
-                                j += 1;
-                                continue;
-                            }
-                        };
-
+                for &(j, i) in &c.bits {
+                    let (source_node, source_bus) = match source(&c.wire, i) {
+                        Some(x) => x,
+                        None => continue,
+                    };
                     let wire = Wire {
-                        source: source_bus.clone(),
+                        source: source_bus,
                         target: Bus {
-                            name: port.name.value.clone(),
-                            range: Some(j..j + 1),
+                            name: c.port.clone(),
+                            range: Some(j + offset..j + offset + 1),
                         },
                     };
-
-                    self.circuit
-                        .add_edge(*source_node, created_components[part_idx], wire);
-
-                    i += 1;
-                    j += 1;
+                    self.circuit.add_edge(source_node, part_node, wire);
                 }
             }
         }
@@ -737,80 +1426,672 @@ impl Chip {
             if port.direction == PortDirection::In {
                 continue;
             }
-            let port_chip = make_port_chip(port_name, port.width, self_ptr, &self.hdl_provider);
+            let port_chip = make_port_chip(port_name, port.width, &self.hdl_provider);
             let port_node = self.circuit.add_node(port_chip);
             self.output_port_nodes.push(port_node);
 
             for j in 0..port.width {
-                let (source_node, source_bus) = match get_signal_source(port_name, j, &port.name)? {
+                let (source_node, source_bus) = match source(port_name, j) {
                     Some(x) => x,
-                    None => {
-                        continue;
-                    }
+                    None => continue,
                 };
                 let wire = Wire {
-                    source: source_bus.clone(),
+                    source: source_bus,
                     target: Bus {
                         name: String::from("in"),
                         range: Some(j..j + 1),
                     },
                 };
-                self.circuit.add_edge(*source_node, port_node, wire);
+                self.circuit.add_edge(source_node, port_node, wire);
             }
         }
 
+        self.part_nodes = part_nodes;
         optimize_circuit(&mut self.circuit);
+        self.adopt_parts();
+        debug!(nodes = self.circuit.node_count(), "elaborated");
 
-        Ok(())
+        let grown = Size {
+            instances: self.part_nodes.len(),
+            bus_bits: signal_sources.values().map(Vec::len).sum(),
+        };
+        if self.path.is_empty() {
+            self.sources = signal_sources;
+        }
+        self.grow(grown)
     }
 
-    pub fn get_port_values_for_direction(&self, direction: PortDirection) -> BusMap {
-        // Return output signals as a BusMap
-        let mut values = BusMap::new();
-        let ports = self.ports.clone();
-        for (port_name, port) in ports {
-            if port.direction != direction {
-                continue;
+    // Refuses a part that is the same chip as this one or one above it,
+    // unless its generic arguments differ from every one of them and
+    // `--max-recursion` allows that many.
+    // `above` is the lineage of this chip.
+    fn check_recursion(
+        &self,
+        part: &Chip,
+        above: &Arc<Lineage>,
+        ident: &Identifier,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = part.hdl.as_ref().and_then(|h| h.path.clone());
+        let mut chain = vec![above];
+        while let Some(up) = chain.last().and_then(|c| c.up.as_ref()) {
+            chain.push(up);
+        }
+        chain.reverse();
+        let same: Vec<&&Arc<Lineage>> = chain
+            .iter()
+            .filter(|c| c.name == part.name && c.path == path)
+            .collect();
+        if same.is_empty() {
+            return Ok(());
+        }
+        let max = self.tree.options.max_recursion;
+        let repeated = same.iter().any(|c| c.variables == part.variables);
+        if !repeated && same.len() <= max {
+            return Ok(());
+        }
+        let chain = chain
+            .iter()
+            .map(|c| c.display_name.clone())
+            .chain([part.display_name()])
+            .collect::<Vec<_>>()
+            .join(" > ");
+        let msg = if repeated {
+            format!(
+                "Chip {} is a part of itself with the same generic arguments, so it would never finish elaborating: {}.",
+                part.name, chain
+            )
+        } else if max == 0 {
+            format!(
+                "Chip {} is a part of itself: {}. A generic chip whose arguments shrink each time can recurse with --max-recursion.",
+                part.name, chain
+            )
+        } else {
+            format!(
+                "Chip {} is a part of itself more than the --max-recursion of {} allows: {}.",
+                part.name, max, chain
+            )
+        };
+        Err(Box::new(N2VError {
+            msg,
+            kind: ErrorKind::ParseIdentError(self.hdl_provider.clone(), ident.clone()),
+        }))
+    }
+
+    // The name of the chip with its generic arguments, e.g. `Tree<8>`.
+    fn display_name(&self) -> String {
+        let hdl = match &self.hdl {
+            Some(h) if !h.generic_decls.is_empty() => h,
+            _ => return self.name.clone(),
+        };
+        let args: Vec<String> = hdl
+            .generic_decls
+            .iter()
+            .map(|g| match self.variables.get(&g.value) {
+                Some(v) => v.to_string(),
+                None => g.value.clone(),
+            })
+            .collect();
+        format!("{}<{}>", self.name, args.join(", "))
+    }
+
+    // Adds what a chip has just elaborated to its size and that of its
+    // tree, and refuses to go on if the tree is now larger than the limits
+    // allow.
+    fn grow(&mut self, size: Size) -> Result<(), Box<dyn Error>> {
+        self.size += size;
+        let total = {
+            let mut total = self.tree.size.lock().unwrap();
+            *total += size;
+            *total
+        };
+        let limits = self.tree.limits.lock().unwrap().unwrap_or(Size {
+            instances: self.tree.options.max_instances,
+            bus_bits: self.tree.options.max_bus_bits,
+        });
+        let too_large = if total.instances > limits.instances {
+            TooLarge {
+                measure: |s| s.instances,
+                limit: limits.instances,
+                what: "parts",
+                option: "--max-instances",
             }
+        } else if total.bus_bits > limits.bus_bits {
+            TooLarge {
+                measure: |s| s.bus_bits,
+                limit: limits.bus_bits,
+                what: "bits of signals",
+                option: "--max-bus-bits",
+            }
+        } else {
+            return Ok(());
+        };
+        Err(Box::new(too_large))
+    }
 
-            let idx = Bus {
-                name: port_name.clone(),
-                range: Some(0..port.width),
+    // Says where a top-level chip that has grown too large is largest, if
+    // that is the error. Other errors are returned as they are.
+    fn size_error(&self, e: Box<dyn Error>) -> Box<dyn Error> {
+        let e = match e.downcast::<TooLarge>() {
+            Ok(e) => e,
+            Err(e) => return e,
+        };
+        Box::new(N2VError {
+            msg: format!(
+                "Chip {} has grown past the limit of {} {}. {} Raise the limit with {} if the chip really is this large.",
+                self.name,
+                e.limit,
+                e.what,
+                self.largest(e.measure),
+                e.option
+            ),
+            kind: ErrorKind::SimulationError(self.hdl.as_ref().and_then(|h| h.path.clone())),
+        })
+    }
+
+    // What the chip and every part below it have elaborated.
+    fn total_size(&self) -> Size {
+        let mut size = self.size;
+        for part in self.circuit.node_weights() {
+            size += part.total_size();
+        }
+        size
+    }
+
+    // Names the parts that hold most of a chip's size. Starting from the
+    // chip, it follows the part, or group of copies of one chip, holding
+    // more than half of what is left.
+    fn largest(&self, measure: fn(&Size) -> usize) -> String {
+        let mut path = vec![self.name.clone()];
+        let mut chip = self;
+        loop {
+            let mut groups: Vec<(&str, usize, Vec<&Chip>)> = Vec::new();
+            for part in chip.circuit.node_weights() {
+                match groups.iter_mut().find(|g| g.0 == part.name) {
+                    Some(g) => {
+                        g.1 += measure(&part.total_size());
+                        g.2.push(part);
+                    }
+                    None => groups.push((&part.name, measure(&part.total_size()), vec![part])),
+                }
+            }
+            let size = measure(&chip.total_size());
+            let (name, amount, copies) = match groups.into_iter().max_by_key(|g| g.1) {
+                Some(g) if g.1 * 2 > size => g,
+                _ => return format!("{} of them are in {}.", size, path.join(" > ")),
             };
-            values.create_bus(&idx.name, port.width).unwrap();
-            values.insert_option(&idx, self.signals.get_bus(&idx));
+            if copies.len() > 1 {
+                return format!(
+                    "{} of them are in {} copies of {} in {}.",
+                    amount,
+                    copies.len(),
+                    name,
+                    path.join(" > ")
+                );
+            }
+            path.push(String::from(name));
+            chip = copies[0];
         }
-        values
     }
 
-    fn get_port_values(&self) -> BusMap {
-        // Return output signals as a BusMap
-        let mut values = BusMap::new();
-        let ports = self.ports.clone();
-        for (port_name, port) in ports {
-            let idx = Bus {
-                name: port_name.clone(),
-                range: Some(0..port.width),
+    /// Values held by the chip's flip-flops and sequential builtins, which
+    /// identify the state of a sequential chip. Flip-flops are printed as
+    /// bits and builtins as decimal values in brackets, e.g. `01[12]`.
+    pub fn state(&self) -> String {
+        if let Some(b) = &self.builtin {
+            return b.state().iter().map(|v| format!("[{}]", v)).collect();
+        }
+        if self.hdl.is_none() && self.name == "DFF" {
+            return match self.signals.get_name("out").first() {
+                Some(Some(true)) => String::from("1"),
+                Some(Some(false)) => String::from("0"),
+                _ => String::from("?"),
             };
-            values.create_bus(&port_name, port.width).unwrap();
-            values.insert_option(&idx, self.signals.get_bus(&idx));
         }
-        values
+        self.circuit.node_weights().map(|c| c.state()).collect()
     }
 
-    fn insert_cache_entry(&mut self, input_cache: &mut Cache) {
-        let inputs = self.get_port_values_for_direction(PortDirection::In);
-        let cache_entry = InputCacheEntry {
+    /// Summarizes the elaborated design, listing the parts of each chip once
+    /// per chip name, e.g. `Mux: And x2, Not x1, Or x1`.
+    pub fn netlist(&self) -> String {
+        let mut seen = HashSet::new();
+        let mut lines = Vec::new();
+        self.netlist_lines(&mut seen, &mut lines);
+        lines.join("\n")
+    }
+
+    fn netlist_lines(&self, seen: &mut HashSet<String>, lines: &mut Vec<String>) {
+        if !seen.insert(self.name.clone()) {
+            return;
+        }
+        if let Some(b) = &self.builtin {
+            lines.push(format!("{}: builtin", self.name));
+            for d in b.devices() {
+                d.chip.netlist_lines(seen, lines);
+            }
+            return;
+        }
+        if self.hdl.is_none() {
+            return;
+        }
+        if !self.elaborated {
+            lines.push(format!("{}: not elaborated", self.name));
+            return;
+        }
+        let parts: Vec<&Chip> = self
+            .circuit
+            .node_indices()
+            .filter(|i| !self.input_port_nodes.contains(i) && !self.output_port_nodes.contains(i))
+            .map(|i| &self.circuit[i])
+            .collect();
+        let mut counts = BTreeMap::new();
+        for p in &parts {
+            *counts.entry(p.name.as_str()).or_insert(0) += 1;
+        }
+        let counts: Vec<String> = counts
+            .iter()
+            .map(|(name, count)| format!("{} x{}", name, count))
+            .collect();
+        lines.push(format!("{}: {}", self.name, counts.join(", ")));
+        for p in parts {
+            p.netlist_lines(seen, lines);
+        }
+    }
+
+    /// The chip as NANDs, flip-flops and builtins wired by nets, for
+    /// `netlist::flatten`. A copy of the chip is elaborated all the way
+    /// down for this, so the chip itself is left as it was.
+    pub fn flatten(&self) -> Result<Netlist, Box<dyn Error>> {
+        let mut chip = self.copy();
+        chip.tree = Arc::new(self.tree.copy());
+        chip.adopt_parts();
+        chip.elaborate_all().map_err(|e| chip.size_error(e))?;
+
+        let mut netlist = Netlist::default();
+        let mut inputs = HashMap::new();
+        for (name, port) in &chip.ports {
+            if port.direction == PortDirection::In {
+                let nets = (0..port.width).map(|_| netlist.net(None)).collect();
+                inputs.insert(name.clone(), nets);
+            }
+        }
+        let outputs = if chip.builtin.is_some() || chip.hdl.is_none() {
+            chip.flatten_into(&mut netlist, &inputs)?
+        } else {
+            let driven = chip.flatten_parts(&mut netlist, &inputs)?;
+            for (name, sources) in &chip.sources {
+                let nets = sources
+                    .iter()
+                    .map(|source| {
+                        let (node, bus) = source.as_ref()?;
+                        let bit = bus.range.as_ref()?.start;
+                        Some(driven[node][&bus.name][bit])
+                    })
+                    .collect();
+                netlist.signals.insert(name.clone(), nets);
+            }
+            chip.output_nets(&driven)
+        };
+        for (name, nets) in inputs.iter().chain(&outputs) {
+            let nets = nets.iter().map(|&n| Some(n)).collect();
+            netlist.signals.insert(name.clone(), nets);
+        }
+        netlist.inputs = inputs.into_iter().collect();
+        netlist.outputs = outputs.into_iter().collect();
+        netlist.finish();
+        Ok(netlist)
+    }
+
+    // Adds the chip to `netlist`, reading the nets in `inputs` by port and bit.
+    // Returns the nets its output ports drive.
+    fn flatten_into(
+        &self,
+        netlist: &mut Netlist,
+        inputs: &HashMap<String, Vec<usize>>,
+    ) -> Result<HashMap<String, Vec<usize>>, Box<dyn Error>> {
+        if self.builtin.is_none() && self.hdl.is_some() {
+            let driven = self.flatten_parts(netlist, inputs)?;
+            return Ok(self.output_nets(&driven));
+        }
+        // Outputs start as they are in the chip, e.g. a DFF at 0.
+        let mut outputs: HashMap<String, Vec<usize>> = HashMap::new();
+        for (name, port) in &self.ports {
+            if port.direction == PortDirection::Out {
+                let values = self.signals.get_name(name);
+                let nets = values.iter().rev().map(|&v| netlist.net(v)).collect();
+                outputs.insert(name.clone(), nets);
+            }
+        }
+        let cell = match &self.builtin {
+            Some(builtin) => Cell::Builtin {
+                builtin: builtin.clone(),
+                signals: self.signals.clone(),
+                pins: self
+                    .ports
+                    .iter()
+                    .map(|(name, port)| Pin {
+                        name: name.clone(),
+                        direction: port.direction,
+                        nets: match port.direction {
+                            PortDirection::In => inputs[name].clone(),
+                            PortDirection::Out => outputs[name].clone(),
+                        },
+                    })
+                    .collect(),
+            },
+            None if self.name == "DFF" => Cell::Dff {
+                d: inputs["in"][0],
+                q: outputs["out"][0],
+            },
+            None => Cell::Nand {
+                a: inputs["a"][0],
+                b: inputs["b"][0],
+                out: outputs["out"][0],
+            },
+        };
+        netlist.cells.push(cell);
+        Ok(outputs)
+    }
+
+    // Adds the parts of an elaborated chip to `netlist`. Returns the nets each
+    // node of the circuit drives, by bus and bit.
+    #[allow(clippy::type_complexity)]
+    fn flatten_parts(
+        &self,
+        netlist: &mut Netlist,
+        inputs: &HashMap<String, Vec<usize>>,
+    ) -> Result<HashMap<NodeIndex, HashMap<String, Vec<usize>>>, Box<dyn Error>> {
+        let is_port =
+            |node| self.input_port_nodes.contains(&node) || self.output_port_nodes.contains(&node);
+        // What every node drives has a net before any wire is followed,
+        // since wires loop back to parts through flip-flops.
+        let mut driven = HashMap::new();
+        for node in self.circuit.node_indices() {
+            let part = &self.circuit[node];
+            let buses = if self.input_port_nodes.contains(&node) {
+                HashMap::from([(String::from("in"), inputs[&part.name].clone())])
+            } else if self.output_port_nodes.contains(&node) {
+                continue;
+            } else if part.ports.is_empty() {
+                // A literal.
+                let values = part.signals.get_name("out");
+                let nets = values.iter().rev().map(|&v| netlist.net(v)).collect();
+                HashMap::from([(String::from("out"), nets)])
+            } else {
+                part.ports
+                    .iter()
+                    .filter(|(_, p)| p.direction == PortDirection::Out)
+                    .map(|(name, p)| {
+                        (
+                            name.clone(),
+                            (0..p.width).map(|_| netlist.net(None)).collect(),
+                        )
+                    })
+                    .collect()
+            };
+            driven.insert(node, buses);
+        }
+
+        // Bits of inputs that nothing drives get nets of their own, which
+        // stay unknown.
+        let mut read: HashMap<NodeIndex, HashMap<String, Vec<usize>>> = HashMap::new();
+        for node in self.circuit.node_indices() {
+            let part = &self.circuit[node];
+            let buses = if self.input_port_nodes.contains(&node) {
+                continue;
+            } else if self.output_port_nodes.contains(&node) {
+                let width = part.signals.get_width("in").unwrap_or(0);
+                HashMap::from([(
+                    String::from("in"),
+                    (0..width).map(|_| netlist.net(None)).collect(),
+                )])
+            } else {
+                part.ports
+                    .iter()
+                    .filter(|(_, p)| p.direction == PortDirection::In)
+                    .map(|(name, p)| {
+                        (
+                            name.clone(),
+                            (0..p.width).map(|_| netlist.net(None)).collect(),
+                        )
+                    })
+                    .collect()
+            };
+            read.insert(node, buses);
+        }
+        for edge in self.circuit.edge_references() {
+            let wire = edge.weight();
+            let (source, target) = match (&wire.source.range, &wire.target.range) {
+                (Some(s), Some(t)) => (s.clone(), t.clone()),
+                _ => continue,
+            };
+            let nets = &driven[&edge.source()][&wire.source.name];
+            let reads = read
+                .get_mut(&edge.target())
+                .and_then(|r| r.get_mut(&wire.target.name))
+                .unwrap();
+            for (i, j) in source.zip(target) {
+                reads[j] = nets[i];
+            }
+        }
+
+        for node in self.circuit.node_indices() {
+            let part = &self.circuit[node];
+            if is_port(node) || part.ports.is_empty() {
+                continue;
+            }
+            let outputs = part.flatten_into(netlist, &read[&node])?;
+            for (name, nets) in outputs {
+                for (&a, b) in driven[&node][&name].iter().zip(nets) {
+                    netlist.join(a, b);
+                }
+            }
+        }
+        for &node in &self.output_port_nodes {
+            driven.insert(node, read.remove(&node).unwrap());
+        }
+        Ok(driven)
+    }
+
+    // Nets of each output port, from what the nodes of the circuit drive.
+    fn output_nets(
+        &self,
+        driven: &HashMap<NodeIndex, HashMap<String, Vec<usize>>>,
+    ) -> HashMap<String, Vec<usize>> {
+        self.output_port_nodes
+            .iter()
+            .map(|node| (self.circuit[*node].name.clone(), driven[node]["in"].clone()))
+            .collect()
+    }
+
+    fn save_state(&self) -> ChipState {
+        let mut state = ChipState {
+            name: self.name.clone(),
+            ..ChipState::default()
+        };
+        if let Some(b) = &self.builtin {
+            state.values = b.state();
+            state.parts = b
+                .devices()
+                .iter()
+                .map(|d| d.chip.save_state())
+                .enumerate()
+                .filter(|(_, s)| !s.is_empty())
+                .collect();
+        } else if self.hdl.is_none() && self.name == "DFF" {
+            state.values = match self.signals.get_name("out").first() {
+                Some(Some(b)) => vec![*b as u64],
+                _ => Vec::new(),
+            };
+        } else {
+            state.parts = self
+                .circuit
+                .node_weights()
+                .map(|c| c.save_state())
+                .enumerate()
+                .filter(|(_, s)| !s.is_empty())
+                .collect();
+        }
+        state
+    }
+
+    fn restore_state(
+        &mut self,
+        state: &ChipState,
+        dirty_dffs: &mut Vec<Vec<NodeIndex>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let name = self.name.clone();
+        let mismatch = |msg: String| -> Box<dyn Error> {
+            Box::new(N2VError {
+                msg: format!("The snapshot does not match chip {}: {}", name, msg),
+                kind: ErrorKind::SimulationError(None),
+            })
+        };
+        if state.name != self.name {
+            return Err(mismatch(format!("it has {} instead.", state.name)));
+        }
+        self.dirty = true;
+        self.cache = false;
+
+        if let Some(b) = &mut self.builtin {
+            b.set_state(&state.values);
+            let mut devices = b.devices_mut();
+            for (i, s) in &state.parts {
+                match devices.get_mut(*i) {
+                    Some(d) => d.restore_state(s)?,
+                    None => return Err(mismatch(format!("it has no device {}.", i))),
+                }
+            }
+        } else if self.hdl.is_none() && self.name == "DFF" {
+            let out = state.values.first().map(|v| *v == 1);
+            self.signals.insert_option(&Bus::from("out"), vec![out]);
+            // Latch the input at the next tick if it differs.
+            if self.signals.get_name("in") != vec![out] {
+                dirty_dffs.push(self.path.clone());
+            }
+        } else if !state.parts.is_empty() {
+            if !self.elaborated {
+                self.elaborate()?;
+            }
+            for (i, s) in &state.parts {
+                match self.circuit.node_weight_mut(NodeIndex::new(*i)) {
+                    Some(c) => c.restore_state(s, dirty_dffs)?,
+                    None => return Err(mismatch(format!("it has no part {}.", i))),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Protocol violations that monitor chips anywhere in this chip have
+    /// found since the last call, each prefixed with the monitor's name.
+    pub fn take_violations(&mut self) -> Vec<String> {
+        if let Some(b) = &mut self.builtin {
+            return b
+                .take_violations()
+                .into_iter()
+                .map(|v| format!("{} {}", self.name, v))
+                .collect();
+        }
+        self.circuit
+            .node_weights_mut()
+            .flat_map(|c| c.take_violations())
+            .collect()
+    }
+
+    // Passes a new key to keyboards in this chip, marking them and the
+    // chips above them dirty. Returns whether there were any.
+    fn press_key(&mut self, key: u16) -> bool {
+        let found = match &mut self.builtin {
+            Some(b) => b.set_key(key),
+            None => {
+                let mut found = false;
+                for c in self.circuit.node_weights_mut() {
+                    found |= c.press_key(key);
+                }
+                found
+            }
+        };
+        if found {
+            self.dirty = true;
+            self.cache = false;
+        }
+        found
+    }
+
+    // Memory of the first screen found in this chip.
+    fn screen(&self) -> Option<&[u64]> {
+        match &self.builtin {
+            Some(b) => b.screen(),
+            None => self.circuit.node_weights().find_map(|c| c.screen()),
+        }
+    }
+
+    /// Value of a port or of a wire between the chip's parts as of the last
+    /// time it was computed, highest bit first as in a `BusMap`. Wires can
+    /// only be probed in a top-level chip that has elaborated.
+    pub fn probe(&self, signal: &str) -> Option<Vec<Option<bool>>> {
+        if self.ports.contains_key(signal) {
+            return Some(self.signals.get_name(signal));
+        }
+        let sources = self.sources.get(signal)?;
+        let value = sources
+            .iter()
+            .rev()
+            .map(|source| {
+                let (node, bus) = source.as_ref()?;
+                self.circuit[*node].signals.get_bus(bus)[0]
+            })
+            .collect();
+        Some(value)
+    }
+
+    pub fn get_port_values_for_direction(&self, direction: PortDirection) -> BusMap {
+        // Return output signals as a BusMap
+        let mut values = BusMap::new();
+        let ports = self.ports.clone();
+        for (port_name, port) in ports {
+            if port.direction != direction {
+                continue;
+            }
+
+            let idx = Bus {
+                name: port_name.clone(),
+                range: Some(0..port.width),
+            };
+            values.create_bus(&idx.name, port.width).unwrap();
+            values.insert_packed(&idx, &self.signals.get_packed(&idx));
+        }
+        values
+    }
+
+    fn get_port_values(&self) -> BusMap {
+        // Return output signals as a BusMap
+        let mut values = BusMap::new();
+        let ports = self.ports.clone();
+        for (port_name, port) in ports {
+            let idx = Bus {
+                name: port_name.clone(),
+                range: Some(0..port.width),
+            };
+            values.create_bus(&port_name, port.width).unwrap();
+            values.insert_packed(&idx, &self.signals.get_packed(&idx));
+        }
+        values
+    }
+
+    fn insert_cache_entry(&mut self, input_cache: &mut Cache) {
+        let inputs = self.get_port_values_for_direction(PortDirection::In);
+        let cache_entry = InputCacheEntry {
             name: self.name.clone(),
             signals: inputs,
         };
-        input_cache.insert(
+        input_cache.entries.insert(
             cache_entry,
             self.get_port_values_for_direction(PortDirection::Out),
         );
     }
 
-    fn mark_neighbors(&mut self, component_idx: NodeIndex, dirty_dffs: &mut Vec<*mut Chip>) {
+    fn mark_neighbors(&mut self, component_idx: NodeIndex, dirty_dffs: &mut Vec<Vec<NodeIndex>>) {
         // Mark neighbors dirty if we have changed any of their inputs.
         let mut neighbors = self.circuit.neighbors(component_idx).detach();
         while let Some(wire_idx) = neighbors.next_edge(&self.circuit) {
@@ -819,68 +2100,64 @@ impl Chip {
             let endpoints = self.circuit.edge_endpoints(wire_idx).unwrap();
             let neighbor_idx = endpoints.1;
 
-            let neighbor_current_vals = self
-                .circuit
-                .node_weight_mut(neighbor_idx)
-                .unwrap()
-                .signals
-                .get_bus(&wire.target);
+            let neighbor_new_vals = self.circuit[component_idx].signals.get_packed(&wire.source);
 
-            let neighbor_new_vals = self
-                .circuit
-                .node_weight_mut(component_idx)
-                .unwrap()
+            let neighbor_component = self.circuit.node_weight_mut(neighbor_idx).unwrap();
+            if neighbor_component
                 .signals
-                .get_bus(&wire.source);
-
-            if neighbor_new_vals != neighbor_current_vals {
-                let neighbor_component = self.circuit.node_weight_mut(endpoints.1).unwrap();
+                .insert_packed(&wire.target, &neighbor_new_vals)
+            {
                 neighbor_component.dirty = true;
                 self.dirty = true;
-                neighbor_component
-                    .signals
-                    .insert_option(&wire.target, neighbor_new_vals);
                 if neighbor_component.name == "DFF" {
-                    dirty_dffs.push(neighbor_component as *mut Chip);
+                    dirty_dffs.push(neighbor_component.path.clone());
                 }
             }
         }
     }
 
+    // Returns whether the chip or a part of it now holds state its outputs
+    // do not show yet, so that the chips above it cannot be cached.
     fn compute(
         &mut self,
         input_cache: &mut Cache,
-        dirty_dffs: &mut Vec<*mut Chip>,
-    ) -> Result<(), Box<dyn Error>> {
+        dirty_dffs: &mut Vec<Vec<NodeIndex>>,
+        settle_limit: usize,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut pending = false;
+        let mut passes = 0;
+        // Values driven by parts after each pass beyond the limit.
+        let mut trace = Vec::new();
         while self.dirty {
             self.dirty = false;
 
             if self.name.to_uppercase() == "NAND" {
-                let a = self.signals.get_bus(&Bus::from("a"))[0];
-                let b = self.signals.get_bus(&Bus::from("b"))[0];
-                let new_value = vec![nand(a, b)];
-                self.signals.insert_option(&Bus::from("out"), new_value);
-                return Ok(());
+                let a = self.signals.get_bit("a", 0);
+                let b = self.signals.get_bit("b", 0);
+                self.signals
+                    .set_bit("out", 0, nand(a, b, self.tree.options.x_policy));
+                return Ok(false);
             } else if self.name.to_uppercase() == "DFF" {
-                let current_value = self.signals.get_bus(&Bus::from("out"))[0];
-                let new_value = self.signals.get_bus(&Bus::from("in"))[0];
+                let current_value = self.signals.get_bit("out", 0);
+                let new_value = self.signals.get_bit("in", 0);
 
                 if new_value.is_none() || current_value == new_value {
-                    return Ok(());
+                    return Ok(false);
                 }
 
-                // chase parents up to the top level chip
-                // mark everything along the way as no cache because
-                // those chips now depend on a DFF with a pending write.
-                let mut parent = self.parent;
-                while !parent.is_null() {
-                    let parent_chip;
-                    unsafe {
-                        parent_chip = &mut *parent;
-                    }
-                    parent_chip.cache = false;
-                    parent = parent_chip.parent;
+                // Chips above this one now depend on a DFF with a pending
+                // write.
+                pending = true;
+            } else if let Some(builtin) = self.builtin.as_mut() {
+                builtin.set_key(self.tree.key.load(Ordering::Relaxed));
+                builtin.eval(&mut self.signals);
+                if builtin.is_sequential() {
+                    // Same as a DFF, this chip needs a tick and everything
+                    // above it depends on state.
+                    dirty_dffs.push(self.path.clone());
+                    return Ok(true);
                 }
+                return Ok(false);
             }
 
             let cache_entry = InputCacheEntry {
@@ -888,9 +2165,18 @@ impl Chip {
                 signals: self.get_port_values_for_direction(PortDirection::In),
             };
 
-            if !self.elaborated && self.cache && input_cache.contains_key(&cache_entry) {
-                let cached_outputs = input_cache.get(&cache_entry).unwrap();
-
+            let cached = match input_cache.entries.get(&cache_entry) {
+                _ if self.elaborated || !self.cache => None,
+                Some(outputs) => {
+                    input_cache.hits += 1;
+                    Some(outputs)
+                }
+                None => {
+                    input_cache.misses += 1;
+                    None
+                }
+            };
+            if let Some(cached_outputs) = cached {
                 // set output signals directly
                 for o in cached_outputs.signals() {
                     let width = cached_outputs.get_width(&o).unwrap();
@@ -898,10 +2184,10 @@ impl Chip {
                         name: o.clone(),
                         range: Some(0..width),
                     };
-                    let value = cached_outputs.get_bus(&bus);
-                    self.signals.insert_option(&bus, value);
+                    self.signals
+                        .insert_packed(&bus, &cached_outputs.get_packed(&bus));
                 }
-                return Ok(());
+                return Ok(false);
             }
 
             if !self.elaborated {
@@ -911,78 +2197,275 @@ impl Chip {
             // copy chip inputs into dummy subcomponents as graph entry points
             for &port_idx in &self.input_port_nodes {
                 let port_component = self.circuit.node_weight_mut(port_idx).unwrap();
-                let new_val = self.signals.get_name(&port_component.name);
-                port_component.signals.insert_option(
-                    &Bus {
-                        name: String::from("in"),
-                        range: Some(0..new_val.len()),
-                    },
-                    new_val,
-                );
+                let new_val = self
+                    .signals
+                    .get_packed(&Bus::from(port_component.name.clone()));
+                port_component
+                    .signals
+                    .insert_packed(&Bus::from("in"), &new_val);
             }
 
             // Compute our value by computing subcomponents.
             // Pick subcomponents in SCC topo order
-            let mut sccs = kosaraju_scc(&self.circuit);
-            sccs.reverse();
+            let sccs = self.evaluation_order();
 
             for scc in &sccs {
                 for &component_idx in scc {
                     // Compute component bus values.
-                    {
-                        let component = self.circuit.node_weight_mut(component_idx).unwrap();
-                        component.compute(input_cache, dirty_dffs)?;
+                    let component = self.circuit.node_weight_mut(component_idx).unwrap();
+                    if component.compute(input_cache, dirty_dffs, settle_limit)? {
+                        // Mark everything up to the top level chip as no
+                        // cache.
+                        self.cache = false;
+                        pending = true;
                     }
 
                     self.mark_neighbors(component_idx, dirty_dffs);
                 }
             }
+
+            passes += 1;
+            if passes > settle_limit && self.dirty {
+                trace.push(self.driven_values());
+                if trace.len() > OSCILLATION_PASSES {
+                    return Err(Box::new(self.oscillation_error(settle_limit, &trace)));
+                }
+            }
         }
 
         // populate output buses
         for &port_idx in &self.output_port_nodes {
-            let port_component = self.circuit.node_weight_mut(port_idx).unwrap();
-            let new_val = port_component.signals.get_name("in");
-            self.signals.insert_option(
-                &Bus {
-                    name: port_component.name.clone(),
-                    range: Some(0..new_val.len()),
-                },
-                new_val,
-            );
+            let port_component = &self.circuit[port_idx];
+            let new_val = port_component.signals.get_packed(&Bus::from("in"));
+            self.signals
+                .insert_packed(&Bus::from(port_component.name.clone()), &new_val);
         }
 
         if self.cache {
             self.insert_cache_entry(input_cache)
         }
 
-        Ok(())
+        Ok(pending)
     }
 
-    pub fn eval_port_width(
-        &self,
-        port: &GenericPort,
-        component_hdl: &ChipHDL,
-        component: &Component,
-    ) -> Result<usize, N2VError> {
-        let mut resolved_generics: Vec<usize> = Vec::new();
-        for g in &component.generic_params {
-            if let GenericWidth::Terminal(Terminal::Num(w)) = g {
-                resolved_generics.push(*w);
-            } else if let GenericWidth::Terminal(Terminal::Var(w)) = g {
-                let rw = self.variables.get(&w.value).unwrap();
-                resolved_generics.push(*rw);
+    // Parts in the order they are computed, with the parts of a loop
+    // together. A wire into a CLOCKED pin only matters at a tick, so it does
+    // not order a part after the part driving the pin, and a loop through one
+    // is not combinational.
+    fn evaluation_order(&self) -> Vec<Vec<NodeIndex>> {
+        let graph = EdgeFiltered::from_fn(&self.circuit, |e| {
+            !self.circuit[e.target()].is_clocked(&e.weight().target.name)
+        });
+        let mut sccs = kosaraju_scc(&graph);
+        sccs.reverse();
+        sccs
+    }
+
+    // Whether `port` is read only at a tick: a pin the chip declares
+    // CLOCKED, or the input of a flip-flop.
+    fn is_clocked(&self, port: &str) -> bool {
+        match &self.hdl {
+            Some(hdl) => hdl.clocked.iter().any(|c| c.value == port),
+            None => self.name == "DFF" && port == "in",
+        }
+    }
+
+    // The value of each wire that a part drives, named as it is written in
+    // PARTS, with the highest bit first.
+    fn driven_values(&self) -> Vec<(String, String)> {
+        let mut values = Vec::new();
+        for (part, &(node, lane)) in self.components.iter().zip(&self.part_nodes) {
+            let chip = &self.circuit[node];
+            for m in &part.mappings {
+                let is_output = chip
+                    .ports
+                    .get(&m.port.name)
+                    .is_some_and(|p| p.direction == PortDirection::Out);
+                if !is_output || m.is_open() {
+                    continue;
+                }
+                let bits = match lane {
+                    Some(k) => vec![k],
+                    None => {
+                        let width = chip.signals.get_width(&m.port.name).unwrap_or(0);
+                        match eval_bus_bits(
+                            &m.port,
+                            width,
+                            &self.variables,
+                            &self.hdl_provider,
+                            &m.wire_ident,
+                        ) {
+                            Ok(bits) => bits,
+                            Err(_) => continue,
+                        }
+                    }
+                };
+                let value = bits
+                    .iter()
+                    .rev()
+                    .map(|&i| {
+                        let bus = Bus {
+                            name: m.port.name.clone(),
+                            range: Some(i..i + 1),
+                        };
+                        match chip.signals.get_bus(&bus)[0] {
+                            Some(true) => '1',
+                            Some(false) => '0',
+                            None => '?',
+                        }
+                    })
+                    .collect();
+                values.push((m.wire.to_string(), value));
             }
         }
+        values
+    }
 
-        let component_variables: HashMap<String, usize> = component_hdl
-            .generic_decls
-            .iter()
-            .map(|x| x.value.clone())
-            .zip(resolved_generics)
-            .collect();
+    // Reports the wires whose values changed over `trace`, the values
+    // driven after each of the passes beyond the limit.
+    fn oscillation_error(&self, settle_limit: usize, trace: &[Vec<(String, String)>]) -> N2VError {
+        let mut changing = Vec::new();
+        for (i, (wire, _)) in trace[0].iter().enumerate() {
+            let mut seen: Vec<&str> = Vec::new();
+            for values in trace {
+                let value = values[i].1.as_str();
+                if !seen.contains(&value) {
+                    seen.push(value);
+                }
+            }
+            if seen.len() > 1 {
+                changing.push(format!("{} ({})", wire, seen.join(", ")));
+            }
+        }
+        let signals = if changing.is_empty() {
+            String::new()
+        } else {
+            format!(" Still changing: {}.", changing.join("; "))
+        };
+        N2VError {
+            msg: format!(
+                "Chip {} did not settle after {} passes over its parts, so a combinational loop is oscillating.{} The limit is set with --settle-limit.",
+                self.name, settle_limit, signals
+            ),
+            kind: ErrorKind::SimulationError(self.hdl.as_ref().and_then(|h| h.path.clone())),
+        }
+    }
+}
+
+// Evaluates the generic arguments of a part, in the order its chip declares
+// them.
+pub fn resolve_generics(
+    part: &Component,
+    part_hdl: &ChipHDL,
+    variables: &HashMap<String, usize>,
+) -> Result<Vec<GenericValue>, N2VError> {
+    let mut res = Vec::new();
+    for (_, g) in part.generic_args(part_hdl)? {
+        res.push(match g {
+            GenericValue::Width(w) => GenericValue::from(eval_expr_numeric(w, variables)?),
+            GenericValue::Str(_) => g.clone(),
+        });
+    }
+    Ok(res)
+}
+
+/// Evaluates the range of a bus in a port mapping. The inclusive HDL range
+/// becomes an exclusive Rust range. A bus without a range covers `width` bits.
+pub fn eval_bus_range(
+    bus: &BusHDL,
+    width: usize,
+    variables: &HashMap<String, usize>,
+    provider: &Arc<dyn HdlProvider>,
+    ident: &Identifier,
+) -> Result<Range<usize>, N2VError> {
+    let start = match &bus.start {
+        None => 0,
+        Some(x) => eval_expr_numeric(x, variables)?,
+    };
+    let end = match &bus.end {
+        None => width - 1,
+        Some(x) => eval_expr_numeric(x, variables)?,
+    };
+
+    if start > end {
+        return Err(range_order_error(&bus.name, start, end, provider, ident));
+    }
+
+    Ok(start..end + 1)
+}
+
+fn range_order_error(
+    name: &str,
+    start: usize,
+    end: usize,
+    provider: &Arc<dyn HdlProvider>,
+    ident: &Identifier,
+) -> N2VError {
+    N2VError {
+        msg: format!(
+            "Range {}[{}..{}] starts after it ends. Descending ranges must have constant bounds, e.g. {}[7..0].",
+            name, start, end, name
+        ),
+        kind: ErrorKind::ParseIdentError(provider.clone(), ident.clone()),
+    }
+}
+
+// Rejects ranges whose bounds evaluate to numbers with start after end.
+fn check_range_order(
+    name: &str,
+    start: &Option<GenericWidth>,
+    end: &Option<GenericWidth>,
+    provider: &Arc<dyn HdlProvider>,
+    ident: &Identifier,
+) -> Result<(), N2VError> {
+    if let (
+        Some(GenericWidth::Terminal(Terminal::Num(s))),
+        Some(GenericWidth::Terminal(Terminal::Num(e))),
+    ) = (start, end)
+    {
+        if s > e {
+            return Err(range_order_error(name, *s, *e, provider, ident));
+        }
+    }
+    Ok(())
+}
+
+/// Index of the k-th bit of a range, counting from the top of the range
+/// when one side of a mapping is descending.
+/// The bits a bus selects, in order from its start. A step skips bits,
+/// e.g. `in[0..6:2]` selects bits 0, 2, 4 and 6.
+pub fn eval_bus_bits(
+    bus: &BusHDL,
+    width: usize,
+    variables: &HashMap<String, usize>,
+    provider: &Arc<dyn HdlProvider>,
+    ident: &Identifier,
+) -> Result<Vec<usize>, N2VError> {
+    let range = eval_bus_range(bus, width, variables, provider, ident)?;
+    let step = bus.stride.unwrap_or(1);
+    if (range.len() - 1) % step != 0 {
+        return Err(N2VError {
+            msg: format!(
+                "Range {}[{}..{}:{}] must end on a step, every {} bits from its start.",
+                bus.name,
+                range.start,
+                range.end - 1,
+                step,
+                step
+            ),
+            kind: ErrorKind::ParseIdentError(provider.clone(), ident.clone()),
+        });
+    }
+    Ok(range.step_by(step).collect())
+}
 
-        eval_expr_numeric(&port.width, &component_variables)
+/// The k-th of the bits a bus selects, counting from the other end when
+/// the mapping reverses bit order.
+pub fn reversed_bit(bits: &[usize], k: usize, reversed: bool) -> usize {
+    if reversed {
+        bits[bits.len() - 1 - k]
+    } else {
+        bits[k]
     }
 }
 
@@ -1100,11 +2583,7 @@ fn optimize_circuit(circuit: &mut Circuit) {
 }
 
 /// Creates chips for 16-bit true/false literals.
-fn make_literal_chip(
-    value: Option<bool>,
-    parent: *mut Chip,
-    hdl_provider: &Rc<dyn HdlProvider>,
-) -> Chip {
+fn make_literal_chip(value: Option<bool>, hdl_provider: &Arc<dyn HdlProvider>) -> Chip {
     let circuit = Circuit::new();
 
     let mut signals = BusMap::new();
@@ -1127,15 +2606,21 @@ fn make_literal_chip(
         input_port_nodes: Vec::new(),
         output_port_nodes: Vec::new(),
         cache: false,
-        parent,
-        hdl_provider: Rc::clone(hdl_provider),
+        path: Vec::new(),
+        above: None,
+        tree: Arc::default(),
+        hdl_provider: Arc::clone(hdl_provider),
         variables: HashMap::new(),
-        components: Vec::new(),
+        components: Arc::default(),
+        part_nodes: Vec::new(),
+        builtin: None,
+        size: Size::default(),
+        sources: HashMap::new(),
     }
 }
 
 // cache lookup will always return correct output for nands.
-fn make_nand_chip(parent: *mut Chip, hdl_provider: &Rc<dyn HdlProvider>) -> Chip {
+fn make_nand_chip(hdl_provider: &Arc<dyn HdlProvider>) -> Chip {
     let circuit = Circuit::new();
     let mut signals = BusMap::new();
     signals.create_bus("a", 1).unwrap();
@@ -1179,53 +2664,143 @@ fn make_nand_chip(parent: *mut Chip, hdl_provider: &Rc<dyn HdlProvider>) -> Chip
         input_port_nodes: Vec::new(),
         output_port_nodes: Vec::new(),
         cache: false,
-        parent,
-        hdl_provider: Rc::clone(hdl_provider),
+        path: Vec::new(),
+        above: None,
+        tree: Arc::default(),
+        hdl_provider: Arc::clone(hdl_provider),
         variables: HashMap::new(),
-        components: Vec::new(),
+        components: Arc::default(),
+        part_nodes: Vec::new(),
+        builtin: None,
+        size: Size::default(),
+        sources: HashMap::new(),
     }
 }
 
-fn make_port_chip(
-    name: &str,
-    width: usize,
-    parent: *mut Chip,
-    hdl_provider: &Rc<dyn HdlProvider>,
-) -> Chip {
-    let circuit = Circuit::new();
-    let ports = HashMap::new();
+// A node for `count` copies of a one-bit part, evaluated together by their
+// gate. Ports are `count` bits wide, one bit for each copy.
+fn make_lanes_chip(part: &Chip, count: usize, gate: bitwise::LaneGate) -> Chip {
     let mut signals = BusMap::new();
-    signals.create_bus("in", width).unwrap();
-    signals.create_bus("out", width).unwrap();
+    let ports = part
+        .ports
+        .iter()
+        .map(|(name, port)| {
+            signals.create_bus(name, count).unwrap();
+            let port = Port {
+                width: count,
+                ..port.clone()
+            };
+            (name.clone(), port)
+        })
+        .collect();
 
     Chip {
-        name: String::from(name),
+        name: format!("{}[{}]", part.name, count),
         ports,
         signals,
         hdl: None,
-        elaborated: true,
-        circuit,
+        elaborated: false,
+        circuit: Circuit::new(),
         dirty: false,
         input_port_nodes: Vec::new(),
         output_port_nodes: Vec::new(),
         cache: false,
-        parent,
-        hdl_provider: Rc::clone(hdl_provider),
+        path: Vec::new(),
+        above: None,
+        tree: Arc::default(),
+        hdl_provider: Arc::clone(&part.hdl_provider),
         variables: HashMap::new(),
-        components: Vec::new(),
+        components: Arc::default(),
+        part_nodes: Vec::new(),
+        builtin: Some(bitwise::lanes(gate, count)),
+        size: Size::default(),
+        sources: HashMap::new(),
     }
 }
 
-fn make_dff_chip(parent: *mut Chip, hdl_provider: &Rc<dyn HdlProvider>) -> Chip {
-    let circuit = Circuit::new();
+// A node for a bit that `count` tri-state buffers share. Bit k of `in` is
+// what the kth buffer drives.
+fn make_shared_bit_chip(count: usize, hdl_provider: &Arc<dyn HdlProvider>) -> Chip {
     let mut signals = BusMap::new();
-    signals.create_bus("in", 1).unwrap();
-    signals.create_bus("out", 1).unwrap();
-    signals.insert_option(
-        &Bus {
-            name: String::from("in"),
-            range: Some(0..1),
-        },
+    let mut ports = HashMap::new();
+    for (name, width, direction) in [
+        ("in", count, PortDirection::In),
+        ("out", 1, PortDirection::Out),
+    ] {
+        signals.create_bus(name, width).unwrap();
+        let port = Port {
+            name: Identifier::from(name),
+            width,
+            direction,
+        };
+        ports.insert(String::from(name), port);
+    }
+
+    Chip {
+        name: format!("TriState[{}]", count),
+        ports,
+        signals,
+        hdl: None,
+        elaborated: false,
+        circuit: Circuit::new(),
+        dirty: false,
+        input_port_nodes: Vec::new(),
+        output_port_nodes: Vec::new(),
+        cache: false,
+        path: Vec::new(),
+        above: None,
+        tree: Arc::default(),
+        hdl_provider: Arc::clone(hdl_provider),
+        variables: HashMap::new(),
+        components: Arc::default(),
+        part_nodes: Vec::new(),
+        builtin: Some(shared_bit()),
+        size: Size::default(),
+        sources: HashMap::new(),
+    }
+}
+
+fn make_port_chip(name: &str, width: usize, hdl_provider: &Arc<dyn HdlProvider>) -> Chip {
+    let circuit = Circuit::new();
+    let ports = HashMap::new();
+    let mut signals = BusMap::new();
+    signals.create_bus("in", width).unwrap();
+    signals.create_bus("out", width).unwrap();
+
+    Chip {
+        name: String::from(name),
+        ports,
+        signals,
+        hdl: None,
+        elaborated: true,
+        circuit,
+        dirty: false,
+        input_port_nodes: Vec::new(),
+        output_port_nodes: Vec::new(),
+        cache: false,
+        path: Vec::new(),
+        above: None,
+        tree: Arc::default(),
+        hdl_provider: Arc::clone(hdl_provider),
+        variables: HashMap::new(),
+        components: Arc::default(),
+        part_nodes: Vec::new(),
+        builtin: None,
+        size: Size::default(),
+        sources: HashMap::new(),
+    }
+}
+
+fn make_dff_chip(hdl_provider: &Arc<dyn HdlProvider>) -> Chip {
+    let circuit = Circuit::new();
+    let mut signals = BusMap::new();
+    signals.create_bus("in", 1).unwrap();
+    signals.create_bus("out", 1).unwrap();
+    signals.insert_option(
+        &Bus {
+            name: String::from("in"),
+            range: Some(0..1),
+        },
         vec![Some(false)],
     );
     signals.insert_option(
@@ -1264,10 +2839,32 @@ fn make_dff_chip(parent: *mut Chip, hdl_provider: &Rc<dyn HdlProvider>) -> Chip
         input_port_nodes: Vec::new(),
         output_port_nodes: Vec::new(),
         cache: false,
-        parent,
-        hdl_provider: Rc::clone(hdl_provider),
+        path: Vec::new(),
+        above: None,
+        tree: Arc::default(),
+        hdl_provider: Arc::clone(hdl_provider),
         variables: HashMap::new(),
-        components: Vec::new(),
+        components: Arc::default(),
+        part_nodes: Vec::new(),
+        builtin: None,
+        size: Size::default(),
+        sources: HashMap::new(),
+    }
+}
+
+// The HDL of a part's chip. A chip that cannot be found is reported at the
+// part, while parse errors in the chip's own file are reported as is.
+pub fn part_hdl(
+    part: &Component,
+    provider: &Arc<dyn HdlProvider>,
+) -> Result<ChipHDL, Box<dyn Error>> {
+    match get_hdl(&part.qualified_name(), provider) {
+        Ok(x) => Ok(x),
+        Err(WhidlError::Io(e)) => Err(Box::new(N2VError {
+            kind: ErrorKind::ParseIdentError(provider.clone(), part.name.clone()),
+            msg: e.msg,
+        })),
+        Err(e) => Err(Box::new(e)),
     }
 }
 
@@ -1286,8 +2883,8 @@ fn make_dff_chip(parent: *mut Chip, hdl_provider: &Rc<dyn HdlProvider>) -> Chip
 pub fn infer_widths(
     hdl: &ChipHDL,
     components: &Vec<Component>,
-    provider: &Rc<dyn HdlProvider>,
-    generics: &Vec<GenericWidth>,
+    provider: &Arc<dyn HdlProvider>,
+    generics: &[GenericValue],
 ) -> Result<HashMap<String, GenericWidth>, Box<dyn Error>> {
     // Assign values to generic variables.
     if generics.len() > hdl.generic_decls.len() {
@@ -1302,10 +2899,10 @@ pub fn infer_widths(
         }));
     }
     let mut variables = HashMap::new();
-
-    #[allow(clippy::needless_range_loop)]
-    for gv in 0..generics.len() {
-        variables.insert(hdl.generic_decls[gv].value.clone(), generics[gv].clone());
+    for (decl, g) in hdl.generic_decls.iter().zip(generics) {
+        if let Some(w) = g.width() {
+            variables.insert(decl.value.clone(), w.clone());
+        }
     }
 
     let mut inferred_widths: HashMap<String, GenericWidth> = HashMap::new();
@@ -1314,26 +2911,22 @@ pub fn infer_widths(
     // with every mapping.
     for _ in 0..2 {
         for part in components {
-            let component_hdl = get_hdl(&part.name.value, provider)?;
+            let component_hdl = part_hdl(part, provider)?;
             // Convert generics with vars to concrete generics for component.
             // e.g. Mux<W> needs to become Mux<4> if W=4. At this point
             // we need actual bus widths.
-            let generic_params: Vec<GenericWidth> = part
-                .generic_params
-                .iter()
-                .map(|g| eval_expr(g, &variables))
-                .collect();
-
             // Do not create a component chip here because that will
             // trigger elaboration of the entire component tree.
             // We only need the ports, and ports cannot be created with
             // for generate loops, so this is sufficient enough to get
             // the variables map for looking up port widths.
-            let component_variables: HashMap<String, GenericWidth> = component_hdl
-                .generic_decls
-                .iter()
-                .map(|x| x.value.clone())
-                .zip(generic_params)
+            let component_variables: HashMap<String, GenericWidth> = part
+                .generic_args(&component_hdl)
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(decl, g)| {
+                    Some((decl.value.clone(), eval_expr(g.width()?, &variables)))
+                })
                 .collect();
 
             for m in &part.mappings {
@@ -1354,10 +2947,29 @@ pub fn infer_widths(
                     .iter()
                     .position(|x| x.name.value == m.port.name)
                     .ok_or(N2VError {
-                        msg: format!("Non-existent port {}", &m.port.name),
-                        kind: ErrorKind::ParseIdentError(provider.clone(), part.name.clone()),
+                        msg: format!(
+                            "Non-existent port {}.{}",
+                            &m.port.name,
+                            did_you_mean(&m.port.name, &component_hdl.port_names())
+                        ),
+                        kind: ErrorKind::ParseIdentError(provider.clone(), m.wire_ident.clone()),
                     })?;
                 let port = &component_hdl.ports[port_idx];
+                if m.is_open() {
+                    if port.direction == PortDirection::In {
+                        return Err(Box::new(N2VError {
+                            msg: format!(
+                                "Input {} of {} cannot be left open.",
+                                m.port.name, component_hdl.name
+                            ),
+                            kind: ErrorKind::ParseIdentError(
+                                provider.clone(),
+                                m.wire_ident.clone(),
+                            ),
+                        }));
+                    }
+                    continue;
+                }
 
                 // Get the width of the port referred to in the mapping.
                 // This uses the component chip variables because the width of the port is defined inside the component
@@ -1365,6 +2977,13 @@ pub fn infer_widths(
 
                 let wire_start = m.wire.start.as_ref().map(|x| eval_expr(x, &variables));
                 let wire_end = m.wire.end.as_ref().map(|x| eval_expr(x, &variables));
+                check_range_order(
+                    &m.wire.name,
+                    &wire_start,
+                    &wire_end,
+                    provider,
+                    &m.wire_ident,
+                )?;
 
                 // Convert inclusive range in HDL to exclusive Range in Rust
                 let wire_range: Option<Range<GenericWidth>> = wire_start.map(|ws| Range {
@@ -1373,12 +2992,30 @@ pub fn infer_widths(
                 });
                 let port_start = m.port.start.as_ref().map(|x| eval_expr(x, &variables));
                 let port_end = m.port.end.as_ref().map(|x| eval_expr(x, &variables));
+                check_range_order(
+                    &m.port.name,
+                    &port_start,
+                    &port_end,
+                    provider,
+                    &m.wire_ident,
+                )?;
+
                 // Convert inclusive range in HDL to exclusive Range in Rust
                 let port_range: Option<Range<GenericWidth>> = port_start.map(|ps| Range {
                     start: ps,
                     end: port_end.unwrap() + GenericWidth::Terminal(Terminal::Num(1)),
                 });
 
+                // Number of bits each range selects.
+                let wire_len = match &wire_range {
+                    Some(r) => selected_len(r, &m.wire, provider, &m.wire_ident)?,
+                    None => GenericWidth::from(0),
+                };
+                let port_len = match &port_range {
+                    Some(r) => selected_len(r, &m.port, provider, &m.wire_ident)?,
+                    None => GenericWidth::from(0),
+                };
+
                 match (&wire_range, &port_range, inferred_widths.get(&m.wire.name)) {
                     // wire range none, port range none, width none => use port width
                     (None, None, None) => {
@@ -1388,8 +3025,8 @@ pub fn infer_widths(
                     // wire range none, port range none, width some => verify width = port width
                     (None, None, Some(w)) => {
                         if w.is_numeric() && w != &port_width {
-                            return Err(Box::new(N2VError { msg: format!("Chip {} component {} inferred width of signal {} is {}, not equal to width of port {} which is {}.", 
-                                &hdl.name, &component_hdl.name, &m.wire.name, w, &m.port.name, &port_width
+                            return Err(Box::new(N2VError { msg: format!("Chip {} component {} inferred width of signal {} is {}, not equal to width of port {} which is {}{}.", 
+                                &hdl.name, &component_hdl.name, &m.wire.name, w, &m.port.name, &port_width, declared_at(&port.name)
                             ),
                             kind: ErrorKind::ParseIdentError(provider.clone(), m.wire_ident.clone()),
                         }));
@@ -1397,15 +3034,15 @@ pub fn infer_widths(
                     }
 
                     // wire range none, port range some, width none => use len of port range
-                    (None, Some(pr), None) => {
-                        inferred_widths.insert(m.wire.name.clone(), &pr.end - &pr.start);
+                    (None, Some(_), None) => {
+                        inferred_widths.insert(m.wire.name.clone(), port_len);
                     }
 
                     // wire range none, port range some, width some => verify width same as port range
-                    (None, Some(pr), Some(w)) => {
-                        if w.is_numeric() && w != &(&pr.end - &pr.start) {
-                            return Err(Box::new(N2VError { msg: format!("Chip {} component {} inferred width of signal {} is {}, not equal to width of port {} range which is {}.",
-                                &hdl.name, &component_hdl.name, &m.wire.name, w, &m.port.name, &pr.end - &pr.start
+                    (None, Some(_), Some(w)) => {
+                        if w.is_numeric() && w != &port_len {
+                            return Err(Box::new(N2VError { msg: format!("Chip {} component {} inferred width of signal {} is {}, not equal to width of port {} range which is {}{}.",
+                                &hdl.name, &component_hdl.name, &m.wire.name, w, &m.port.name, port_len, declared_at(&port.name)
                             ),
                             kind: ErrorKind::ParseIdentError(provider.clone(), m.wire_ident.clone()),
                         }));
@@ -1414,12 +3051,9 @@ pub fn infer_widths(
 
                     // wire range some, port range none, width none => verify wire range = port width. Use wire max index as wire width.
                     (Some(wr), None, None) => {
-                        if wr.end.is_numeric()
-                            && wr.start.is_numeric()
-                            && (&wr.end - &wr.start) != port_width
-                        {
-                            return Err(Box::new(N2VError { msg: format!("Chip {} component {} inferred width of signal {} is {}, not equal to width of port {} width which is {}.",
-                                &hdl.name, &component_hdl.name, &m.wire.name, (&wr.end - &wr.start), &m.port.name, port_width
+                        if wr.end.is_numeric() && wr.start.is_numeric() && wire_len != port_width {
+                            return Err(Box::new(N2VError { msg: format!("Chip {} component {} inferred width of signal {} is {}, not equal to width of port {} width which is {}{}.",
+                                &hdl.name, &component_hdl.name, &m.wire.name, wire_len, &m.port.name, port_width, declared_at(&port.name)
                             ),
                             kind: ErrorKind::ParseIdentError(provider.clone(), m.wire_ident.clone()),
                         }));
@@ -1429,12 +3063,9 @@ pub fn infer_widths(
 
                     // wire range some, port range none, width some => verify wire range = port width. Use max(wire max index, existing width).
                     (Some(wr), None, Some(w)) => {
-                        if wr.end.is_numeric()
-                            && wr.start.is_numeric()
-                            && (&wr.end - &wr.start) != port_width
-                        {
-                            return Err(Box::new(N2VError { msg: format!("Chip `{}` component `{}` wire range of signal `{}` is {}, not equal port `{}` width, which is {}.",
-                                &hdl.name, &component_hdl.name, &m.wire.name, (&wr.end - &wr.start), &m.port.name, &port_width
+                        if wr.end.is_numeric() && wr.start.is_numeric() && wire_len != port_width {
+                            return Err(Box::new(N2VError { msg: format!("Chip `{}` component `{}` wire range of signal `{}` is {}, not equal port `{}` width, which is {}{}.",
+                                &hdl.name, &component_hdl.name, &m.wire.name, wire_len, &m.port.name, &port_width, declared_at(&port.name)
                             ),
                             kind: ErrorKind::ParseIdentError(provider.clone(), m.wire_ident.clone()),
                         }));
@@ -1456,10 +3087,10 @@ pub fn infer_widths(
                             && wr.start.is_numeric()
                             && pr.end.is_numeric()
                             && pr.start.is_numeric()
-                            && (&wr.end - &wr.start) != (&pr.end - &pr.start)
+                            && wire_len != port_len
                         {
-                            return Err(Box::new(N2VError { msg: format!("Chip {} component {} inferred width of signal {} is {}, not equal to width of port {} range which is {}.",
-                                &hdl.name, &component_hdl.name, &m.wire.name, (&wr.end - &wr.start), &m.port.name, (&pr.end - &pr.start)
+                            return Err(Box::new(N2VError { msg: format!("Chip {} component {} inferred width of signal {} is {}, not equal to width of port {} range which is {}{}.",
+                                &hdl.name, &component_hdl.name, &m.wire.name, wire_len, &m.port.name, port_len, declared_at(&port.name)
                             ),
                             kind: ErrorKind::ParseIdentError(provider.clone(), m.wire_ident.clone()),
                         }));
@@ -1473,10 +3104,10 @@ pub fn infer_widths(
                             && wr.start.is_numeric()
                             && pr.end.is_numeric()
                             && pr.start.is_numeric()
-                            && (&wr.end - &wr.start) != (&pr.end - &pr.start)
+                            && wire_len != port_len
                         {
-                            return Err(Box::new(N2VError { msg: format!("Chip {} component {} inferred width of signal {} is {}, not equal to width of port {} range which is {}.",
-                                &hdl.name, &component_hdl.name, &m.wire.name, (&wr.end - &wr.start), &m.port.name, (&pr.end - &pr.start)
+                            return Err(Box::new(N2VError { msg: format!("Chip {} component {} inferred width of signal {} is {}, not equal to width of port {} range which is {}{}.",
+                                &hdl.name, &component_hdl.name, &m.wire.name, wire_len, &m.port.name, port_len, declared_at(&port.name)
                             ),
                             //line: m.wire_ident.line,
                             kind: ErrorKind::ParseIdentError(provider.clone(), m.wire_ident.clone()),
@@ -1501,22 +3132,45 @@ pub fn infer_widths(
     Ok(inferred_widths)
 }
 
-fn nand(a: Option<bool>, b: Option<bool>) -> Option<bool> {
-    if a.is_none() || b.is_none() {
-        return None;
+// The number of bits in `range`, which skips bits if `bus` has a step.
+fn selected_len(
+    range: &Range<GenericWidth>,
+    bus: &BusHDL,
+    provider: &Arc<dyn HdlProvider>,
+    ident: &Identifier,
+) -> Result<GenericWidth, N2VError> {
+    let step = match bus.stride {
+        None | Some(1) => return Ok(&range.end - &range.start),
+        Some(k) => k,
+    };
+    match (&range.start, &range.end) {
+        (GenericWidth::Terminal(Terminal::Num(s)), GenericWidth::Terminal(Terminal::Num(e))) => {
+            Ok(GenericWidth::from((e - s - 1) / step + 1))
+        }
+        _ => Err(N2VError {
+            msg: format!(
+                "Range {} has a step, so its bounds must be numbers here.",
+                bus
+            ),
+            kind: ErrorKind::ParseIdentError(provider.clone(), ident.clone()),
+        }),
     }
+}
 
-    Some(!(a.unwrap() && b.unwrap()))
+/// The NAND of two bits, with unknown bits spreading as `policy` says.
+pub fn nand(a: Option<bool>, b: Option<bool>, policy: XPolicy) -> Option<bool> {
+    Option::from(Logic::from(a).nand(Logic::from(b), policy))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    use crate::engine::{EngineKind, SimOptions};
     use crate::scanner::Scanner;
     use std::env;
-    use std::path::Path;
-    use std::ptr;
+    use std::fs;
+    use std::path::{Path, PathBuf};
 
     fn make_simulator(file_name: &str) -> Simulator {
         let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
@@ -1529,15 +3183,14 @@ mod test {
                 .to_str()
                 .unwrap(),
         );
-        let provider: Rc<dyn HdlProvider> = Rc::new(FileReader::new(&base_path));
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(&base_path));
         let contents = provider.get_hdl(file_name).unwrap();
         let mut scanner = Scanner::new(contents.as_str(), provider.get_path(file_name));
         let mut parser = Parser {
             scanner: &mut scanner,
         };
         let hdl = parser.parse().expect("Parse error");
-        let chip = Chip::new(&hdl, ptr::null_mut(), &provider, false, &Vec::new())
-            .expect("Chip creation error");
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
         Simulator::new(chip)
     }
 
@@ -2060,15 +3713,22 @@ mod test {
                 .to_str()
                 .unwrap(),
         );
-        let provider: Rc<dyn HdlProvider> = Rc::new(FileReader::new(&base_path));
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(&base_path));
         let contents = provider.get_hdl("And16.hdl").unwrap();
         let mut scanner = Scanner::new(contents.as_str(), provider.get_path("And16.hdl"));
         let mut parser = Parser {
             scanner: &mut scanner,
         };
         let hdl = parser.parse().expect("Parse error");
-        let chip = Chip::new(&hdl, ptr::null_mut(), &provider, true, &Vec::new())
-            .expect("Chip creation error");
+        let chip = Chip::new(
+            &hdl,
+            &provider,
+            ChipOptions {
+                elaborate: true,
+                ..ChipOptions::default()
+            },
+        )
+        .expect("Chip creation error");
         assert_eq!(chip.circuit.edge_count(), 48);
     }
 
@@ -2084,7 +3744,7 @@ mod test {
                 .to_str()
                 .unwrap(),
         );
-        let provider: Rc<dyn HdlProvider> = Rc::new(FileReader::new(&base_path));
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(&base_path));
         let contents = "CHIP And {
       IN a[16], b[16];
       OUT out[16];
@@ -2098,8 +3758,15 @@ mod test {
             scanner: &mut scanner,
         };
         let hdl = parser.parse().expect("Parse error");
-        let chip = Chip::new(&hdl, ptr::null_mut(), &provider, true, &Vec::new())
-            .expect("Chip creation error");
+        let chip = Chip::new(
+            &hdl,
+            &provider,
+            ChipOptions {
+                elaborate: true,
+                ..ChipOptions::default()
+            },
+        )
+        .expect("Chip creation error");
         assert_eq!(chip.circuit.edge_count(), 3);
     }
 
@@ -2115,15 +3782,22 @@ mod test {
                 .to_str()
                 .unwrap(),
         );
-        let provider: Rc<dyn HdlProvider> = Rc::new(FileReader::new(&base_path));
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(&base_path));
         let contents = provider.get_hdl("Inc16.hdl").unwrap();
         let mut scanner = Scanner::new(contents.as_str(), provider.get_path("Inc16.hdl"));
         let mut parser = Parser {
             scanner: &mut scanner,
         };
         let hdl = parser.parse().expect("Parse error");
-        let chip = Chip::new(&hdl, ptr::null_mut(), &provider, true, &Vec::new())
-            .expect("Chip creation error");
+        let chip = Chip::new(
+            &hdl,
+            &provider,
+            ChipOptions {
+                elaborate: true,
+                ..ChipOptions::default()
+            },
+        )
+        .expect("Chip creation error");
         assert_eq!(chip.circuit.edge_count(), 4);
     }
 
@@ -2140,17 +3814,1017 @@ mod test {
                 .to_str()
                 .unwrap(),
         );
-        let provider: Rc<dyn HdlProvider> = Rc::new(FileReader::new(&base_path));
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(&base_path));
         let contents = provider.get_hdl("TwoAssign.hdl").unwrap();
         let mut scanner = Scanner::new(contents.as_str(), provider.get_path("TwoAssign.hdl"));
         let mut parser = Parser {
             scanner: &mut scanner,
         };
         let hdl = parser.parse().expect("Parse error");
-        let chip = Chip::new(&hdl, ptr::null_mut(), &provider, true, &Vec::new());
+        let chip = Chip::new(
+            &hdl,
+            &provider,
+            ChipOptions {
+                elaborate: true,
+                ..ChipOptions::default()
+            },
+        );
         assert!(chip.is_err());
     }
 
+    #[test]
+    fn test_no_inputs() {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let mut scanner = Scanner::new(
+            "CHIP One { OUT out; PARTS: Not(in=false, out=out); }",
+            PathBuf::from("One.hdl"),
+        );
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+        let mut simulator = Simulator::new(chip);
+        let outputs = simulator
+            .simulate(&BusMap::new())
+            .expect("Simulation error");
+        assert_eq!(outputs.get_name("out"), vec![Some(true)]);
+    }
+
+    #[test]
+    fn test_clocked_order() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Register.hdl"),
+            "CHIP Register { IN in[16], load; OUT out[16]; CLOCKED in, load; BUILTIN Register; }",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("Inc16.hdl"),
+            "CHIP Inc16 { IN in[16]; OUT out[16]; BUILTIN Inc16; }",
+        )
+        .unwrap();
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let mut scanner = Scanner::new(
+            "CHIP Counter { OUT out[16]; PARTS:
+                Register(in=next, load=true, out=out, out=now);
+                Inc16(in=now, out=next);
+            }",
+            dir.path().join("Counter.hdl"),
+        );
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+        let mut simulator = Simulator::new(chip);
+        simulator
+            .simulate(&BusMap::new())
+            .expect("Simulation error");
+
+        // The register reads the incrementer only at a tick, so the two do
+        // not form a loop and the register is computed first.
+        let order = simulator.chip.evaluation_order();
+        assert!(order.iter().all(|scc| scc.len() == 1));
+        let position = |name: &str| {
+            order
+                .iter()
+                .position(|scc| simulator.chip.circuit[scc[0]].name == name)
+                .unwrap()
+        };
+        assert!(position("Register") < position("Inc16"));
+
+        for count in 1..4 {
+            simulator.tick().expect("Tick error");
+            let outputs = simulator
+                .simulate(&BusMap::new())
+                .expect("Simulation error");
+            assert_eq!(outputs.get_num("out"), Some(count));
+        }
+    }
+
+    #[test]
+    fn test_constants() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("defs.hdl"), "CONST W 4;").unwrap();
+        fs::write(
+            dir.path().join("Inv.hdl"),
+            "CHIP Inv { IN in[W]; OUT out[W]; PARTS:
+                FOR i IN 0 TO W - 1 GENERATE { Nand(a=in[i], b=in[i], out=out[i]); }
+            }",
+        )
+        .unwrap();
+        let top = "CONST LOW 2;
+            CHIP Top { IN a[W]; OUT out[W], low[LOW]; PARTS:
+                Inv(in=a, out=out, out[0..LOW-1]=low);
+            }";
+
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let mut scanner = Scanner::new(top, dir.path().join("Top.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+        let mut simulator = Simulator::new(chip);
+        let inputs = BusMap::try_from([("a", vec![true, false, true, true])]).unwrap();
+        let outputs = simulator.simulate(&inputs).expect("Simulation error");
+        assert_eq!(
+            outputs.get_name("out"),
+            vec![Some(false), Some(true), Some(false), Some(false)]
+        );
+        assert_eq!(outputs.get_name("low"), vec![Some(false), Some(false)]);
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let chip = crate::event::test::make_chip("And.hdl");
+        let engine = crate::netlist::NetlistEngine::new(&chip).unwrap();
+        let mut simulator = Simulator::with_engine(chip, Box::new(engine));
+        let err = WhidlError::from(simulator.rebind(&[1]).unwrap_err());
+        assert!(matches!(err, WhidlError::Simulation(_)), "{:?}", err);
+        let hdl = simulator.chip.hdl.clone().unwrap();
+        let err = WhidlError::from(hdl.get_port("c").unwrap_err());
+        assert!(matches!(err, WhidlError::Elaboration(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_tristate() {
+        let dir = tempfile::tempdir().unwrap();
+        // The same bus, with its buffers as parts and wrapped in chips of
+        // their own, two deep.
+        fs::write(
+            dir.path().join("Driver.hdl"),
+            "CHIP Driver { IN in, enable; OUT out; PARTS: TriState(in=in, enable=enable, out=out); }",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("Outer.hdl"),
+            "CHIP Outer { IN in, enable; OUT out; PARTS: Driver(in=in, enable=enable, out=out); }",
+        )
+        .unwrap();
+        let tops = [
+            "CHIP Top { IN a, b, ea, eb; OUT bus, alone; PARTS:
+                TriState(in=a, enable=ea, out=bus);
+                TriState(in=b, enable=eb, out=bus);
+                TriState(in=a, enable=ea, out=alone);
+            }",
+            "CHIP Top { IN a, b, ea, eb; OUT bus, alone; PARTS:
+                TriState(in=a, enable=ea, out=bus);
+                Outer(in=b, enable=eb, out=bus);
+                Driver(in=a, enable=ea, out=alone);
+            }",
+        ];
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let kinds = [
+            EngineKind::Interpreter,
+            EngineKind::Event,
+            EngineKind::Netlist,
+            EngineKind::Compiled,
+            #[cfg(feature = "jit")]
+            EngineKind::Jit,
+        ];
+
+        use Logic::*;
+        // Values of a, b, ea and eb, and of bus and alone.
+        let rows = [
+            ([0, 1, 1, 0], Zero, Zero),
+            ([0, 1, 0, 1], One, Z),
+            ([1, 1, 1, 1], One, One),
+            ([0, 1, 1, 1], X, Zero),
+            ([0, 1, 0, 0], Z, Z),
+        ];
+        for top in tops {
+            let mut scanner = Scanner::new(top, dir.path().join("Top.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse().expect("Parse error");
+            for kind in kinds {
+                let chip = Chip::new(&hdl, &provider, ChipOptions::default())
+                    .expect("Chip creation error");
+                let mut simulator = crate::engine::simulator(
+                    chip,
+                    &SimOptions {
+                        engine: kind,
+                        ..SimOptions::default()
+                    },
+                )
+                .unwrap();
+                for (values, bus, alone) in rows {
+                    let bits: Vec<bool> = values.iter().map(|&v| v == 1).collect();
+                    let inputs = BusMap::try_from([
+                        ("a", vec![bits[0]]),
+                        ("b", vec![bits[1]]),
+                        ("ea", vec![bits[2]]),
+                        ("eb", vec![bits[3]]),
+                    ])
+                    .unwrap();
+                    let outputs = simulator.simulate(&inputs).expect("Simulation error");
+                    let context = format!("{:?} {:?}\n{}", kind, values, top);
+                    assert_eq!(outputs.get_logic("bus"), vec![bus], "{}", context);
+                    assert_eq!(outputs.get_logic("alone"), vec![alone], "{}", context);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_x_policy() {
+        // A floating bit into a NAND whose other input decides the output.
+        let top = "CHIP Top { IN a, e; OUT out; PARTS:
+            TriState(in=a, enable=e, out=z);
+            Nand(a=z, b=a, out=out);
+        }";
+        let dir = tempfile::tempdir().unwrap();
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let mut scanner = Scanner::new(top, dir.path().join("Top.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let inputs = BusMap::try_from([("a", vec![false]), ("e", vec![false])]).unwrap();
+        let kinds = [
+            EngineKind::Interpreter,
+            EngineKind::Event,
+            EngineKind::Netlist,
+            EngineKind::Compiled,
+            #[cfg(feature = "jit")]
+            EngineKind::Jit,
+        ];
+        for (x_policy, out) in [
+            (XPolicy::Pessimistic, Logic::X),
+            (XPolicy::Optimistic, Logic::One),
+        ] {
+            for kind in kinds {
+                let options = ChipOptions {
+                    x_policy,
+                    ..ChipOptions::default()
+                };
+                let chip = Chip::new(&hdl, &provider, options).expect("Chip creation error");
+                let mut simulator = crate::engine::simulator(
+                    chip,
+                    &SimOptions {
+                        engine: kind,
+                        ..SimOptions::default()
+                    },
+                )
+                .unwrap();
+                let outputs = simulator.simulate(&inputs).expect("Simulation error");
+                assert_eq!(
+                    outputs.get_logic("out"),
+                    vec![out],
+                    "{:?} {:?}",
+                    x_policy,
+                    kind
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_interfaces() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Inv.hdl"),
+            "INTERFACE Inv { IN in; OUT out; }",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("Inv.nand.hdl"),
+            "CHIP Inv { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }",
+        )
+        .unwrap();
+        // Not an inverter at all, so the two are told apart.
+        fs::write(
+            dir.path().join("Inv.buffer.hdl"),
+            "CHIP Inv { IN in; OUT out; PARTS:
+                Nand(a=in, b=true, out=x);
+                Nand(a=x, b=true, out=out);
+            }",
+        )
+        .unwrap();
+        let top = "CHIP Top { IN a; OUT out; PARTS: Inv(in=a, out=out); }";
+        let run = |provider: Arc<dyn HdlProvider>| -> Result<Vec<Option<bool>>, Box<dyn Error>> {
+            let mut scanner = Scanner::new(top, dir.path().join("Top.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse()?;
+            let chip = Chip::new(&hdl, &provider, ChipOptions::default())?;
+            let mut simulator = Simulator::new(chip);
+            let inputs = BusMap::try_from([("a", vec![true])]).unwrap();
+            Ok(simulator.simulate(&inputs)?.get_name("out"))
+        };
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+
+        let e = run(provider.clone()).unwrap_err().to_string();
+        assert!(e.contains("2 implementations (buffer, nand)"), "{}", e);
+
+        fs::write(
+            dir.path().join(PROJECT_FILE),
+            r#"{ "implementations": { "Inv": "nand" } }"#,
+        )
+        .unwrap();
+        assert_eq!(run(provider.clone()).unwrap(), vec![Some(false)]);
+
+        // A configuration overrides the project file.
+        let configured: Arc<dyn HdlProvider> = Arc::new(Configuration::new(
+            provider.clone(),
+            BTreeMap::from([(String::from("Inv"), String::from("buffer"))]),
+        ));
+        assert_eq!(run(configured).unwrap(), vec![Some(true)]);
+
+        fs::write(
+            dir.path().join("Inv.wide.hdl"),
+            "CHIP Inv { IN in[2]; OUT out; PARTS: Nand(a=in[0], b=in[1], out=out); }",
+        )
+        .unwrap();
+        let configured: Arc<dyn HdlProvider> = Arc::new(Configuration::new(
+            provider.clone(),
+            BTreeMap::from([(String::from("Inv"), String::from("wide"))]),
+        ));
+        let e = run(configured).unwrap_err().to_string();
+        assert!(
+            e.contains("Inv.wide.hdl does not implement interface Inv. Port in is 2 bits wide instead of 1."),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Cell.hdl"),
+            "CHIP Cell { IN a, b; OUT out; PARTS: Nand(a=a, b=b, out=x); Nand(a=x, b=x, out=out); }",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("Bank.hdl"),
+            "CHIP Bank { IN in[4]; OUT out[4]; PARTS:
+                Cell(a=in[0], b=in[1], out=out[0]);
+                Cell(a=in[1], b=in[2], out=out[1]);
+                Cell(a=in[2], b=in[3], out=out[2]);
+                Cell(a=in[3], b=in[0], out=out[3]);
+            }",
+        )
+        .unwrap();
+        let top = "CHIP Top { IN a[4]; OUT out[4], b; PARTS:
+            Bank(in=a, out=out);
+            Nand(a=a[0], b=a[1], out=b);
+        }";
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let mut scanner = Scanner::new(top, dir.path().join("Top.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+        let inputs = BusMap::try_from([("a", vec![true, false, true, false])]).unwrap();
+
+        let mut simulator = Simulator::new(chip);
+        let mut copy = simulator.clone();
+        simulator.set_size_limits(10, 1000);
+        simulator.simulate(&inputs).expect("Simulation error");
+
+        // Two of the cells are simulated from the cache, so ten parts are
+        // elaborated in all.
+        copy.set_size_limits(9, 1000);
+        let e = copy.simulate(&inputs).unwrap_err().to_string();
+        assert!(
+            e.contains(
+                "Chip Top has grown past the limit of 9 parts. 8 of them are in Top > Bank."
+            ),
+            "{}",
+            e
+        );
+
+        // Limits can also be set when the chip is built, and are kept for
+        // each part.
+        let options = ChipOptions {
+            max_instances: 9,
+            ..ChipOptions::default()
+        };
+        let chip = Chip::new(&hdl, &provider, options).expect("Chip creation error");
+        let e = Simulator::new(chip).simulate(&inputs).unwrap_err();
+        assert!(
+            e.to_string().contains("grown past the limit of 9 parts"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn test_recursion() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Loop.hdl"),
+            "CHIP Loop { IN in; OUT out; PARTS: Wrap(in=in, out=out); }",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("Wrap.hdl"),
+            "CHIP Wrap { IN in; OUT out; PARTS: Loop(in=in, out=out); }",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("Chain.hdl"),
+            "CHIP Chain<W> { IN in[W]; OUT out; PARTS:
+                FOR i IN W - 1 TO W - 1 GENERATE { Chain<i>(in=in[0..i - 1], out=out); }
+            }",
+        )
+        .unwrap();
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let message = |name: &str, generics: Vec<usize>, max_recursion: usize| {
+            let hdl = get_hdl(name, &provider).unwrap();
+            let chip = Chip::new(
+                &hdl,
+                &provider,
+                ChipOptions {
+                    generics: generics.clone(),
+                    max_recursion,
+                    ..ChipOptions::default()
+                },
+            )
+            .unwrap();
+            let mut simulator = Simulator::new(chip);
+            let e = simulator.chip.elaborate_all().unwrap_err();
+            e.downcast::<N2VError>().unwrap().msg
+        };
+
+        assert_eq!(
+            message("Loop", vec![], 0),
+            "Chip Loop is a part of itself with the same generic arguments, so it would never finish elaborating: Loop > Wrap > Loop."
+        );
+        assert_eq!(
+            message("Chain", vec![6], 0),
+            "Chip Chain is a part of itself: Chain<6> > Chain<5>. A generic chip whose arguments shrink each time can recurse with --max-recursion."
+        );
+        assert_eq!(
+            message("Chain", vec![6], 2),
+            "Chip Chain is a part of itself more than the --max-recursion of 2 allows: Chain<6> > Chain<5> > Chain<4> > Chain<3>."
+        );
+    }
+
+    #[test]
+    fn test_rebind() {
+        let dir = tempfile::tempdir().unwrap();
+        let inv = "CHIP Inv { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }";
+        fs::write(dir.path().join("Inv.hdl"), inv).unwrap();
+        fs::write(
+            dir.path().join("NotW.hdl"),
+            "CHIP NotW<W> { IN in[W]; OUT out[W]; PARTS:
+                FOR i IN 0 TO W - 1 GENERATE { Inv(in=in[i], out=out[i]); }
+            }",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("Hold.hdl"),
+            "CHIP Hold { IN in; OUT out; PARTS: DFF(in=in, out=out); }",
+        )
+        .unwrap();
+        let top = "CHIP Top<W> {
+            IN a[W], b;
+            OUT out[W], nb, held;
+            PARTS:
+            NotW<W>(in=a, out=out);
+            Inv(in=b, out=nb);
+            Hold(in=b, out=held);
+        }";
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let mut scanner = Scanner::new(top, dir.path().join("Top.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let chip = Chip::new(
+            &hdl,
+            &provider,
+            ChipOptions {
+                generics: vec![2],
+                ..ChipOptions::default()
+            },
+        )
+        .expect("Chip creation error");
+        let mut simulator = Simulator::new(chip);
+        let run = |simulator: &mut Simulator, w: usize| {
+            let inputs = BusMap::try_from([("a", vec![true; w]), ("b", vec![true])]).unwrap();
+            let outputs = simulator.simulate(&inputs).expect("Simulation error");
+            simulator.tick().expect("Tick error");
+            assert_eq!(outputs.get_name("out"), vec![Some(false); w]);
+            assert_eq!(outputs.get_name("nb"), vec![Some(false)]);
+        };
+        run(&mut simulator, 2);
+        // Only parts that are already elaborated show whether they moved.
+        // NotW is evaluated a word at a time, so it never is.
+        let elaborated = |simulator: &Simulator| -> Vec<bool> {
+            let chip = &simulator.chip;
+            chip.part_nodes
+                .iter()
+                .map(|&(n, _)| chip.circuit[n].elaborated)
+                .collect()
+        };
+        assert_eq!(elaborated(&simulator), vec![false, true, true]);
+
+        simulator.rebind(&[3]).expect("Rebind error");
+        // Hold holds a flip-flop, so it starts again.
+        assert_eq!(elaborated(&simulator), vec![false, true, false]);
+        run(&mut simulator, 3);
+
+        // An edit to a part's file keeps it from being reused.
+        fs::write(dir.path().join("Inv.hdl"), format!("// Edited.\n{}", inv)).unwrap();
+        simulator.rebind(&[4]).expect("Rebind error");
+        assert_eq!(elaborated(&simulator), vec![false, false, false]);
+        run(&mut simulator, 4);
+    }
+
+    #[test]
+    fn test_clone() {
+        let out = |simulator: &Simulator| {
+            simulator
+                .chip
+                .get_port_values_for_direction(PortDirection::Out)
+                .get_name("out")
+        };
+        let mut simulator = make_simulator("Bit.hdl");
+        simulator
+            .simulate(&BusMap::try_from([("in", vec![true]), ("load", vec![true])]).unwrap())
+            .expect("simulation failure");
+        // The copy has the flip-flop waiting to tick too.
+        let mut copy = simulator.clone();
+        simulator.tick().expect("Tick failure");
+        assert_eq!(out(&simulator), vec![Some(true)]);
+        assert_eq!(out(&copy), vec![Some(false)]);
+        copy.tick().expect("Tick failure");
+        assert_eq!(out(&copy), vec![Some(true)]);
+
+        simulator
+            .simulate(&BusMap::try_from([("in", vec![false]), ("load", vec![true])]).unwrap())
+            .expect("simulation failure");
+        simulator.tick().expect("Tick failure");
+        assert_eq!(out(&simulator), vec![Some(false)]);
+        assert_eq!(out(&copy), vec![Some(true)]);
+    }
+
+    #[test]
+    fn test_pool() {
+        let pool = SimulatorPool::new(make_simulator("Register.hdl").chip).expect("Pool error");
+        let inputs =
+            |v: bool| BusMap::try_from([("in", vec![v; 16]), ("load", vec![true])]).unwrap();
+        let mut a = pool.get();
+        let mut b = pool.get();
+        a.simulate(&inputs(true)).expect("simulation failure");
+        a.tick().expect("Tick failure");
+        b.simulate(&inputs(false)).expect("simulation failure");
+        b.tick().expect("Tick failure");
+        let out = |s: &Simulator| {
+            s.chip
+                .get_port_values_for_direction(PortDirection::Out)
+                .get_name("out")
+        };
+        assert_eq!(out(&a), vec![Some(true); 16]);
+        assert_eq!(out(&b), vec![Some(false); 16]);
+        // Each simulator starts from the same state.
+        let c = pool.get();
+        assert_ne!(out(&c), out(&a));
+    }
+
+    #[test]
+    fn test_threads() {
+        fn send_sync<T: Send + Sync>() {}
+        send_sync::<Chip>();
+        send_sync::<Simulator>();
+
+        let pool = SimulatorPool::new(make_simulator("Register.hdl").chip).expect("Pool error");
+        let outputs: Vec<Vec<Option<bool>>> = std::thread::scope(|s| {
+            let threads: Vec<_> = [true, false, true]
+                .into_iter()
+                .map(|v| {
+                    let mut simulator = pool.get();
+                    s.spawn(move || {
+                        let inputs =
+                            BusMap::try_from([("in", vec![v; 16]), ("load", vec![true])]).unwrap();
+                        simulator.simulate(&inputs).expect("simulation failure");
+                        simulator.tick().expect("Tick failure");
+                        simulator
+                            .simulate(&inputs)
+                            .expect("simulation failure")
+                            .get_name("out")
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert_eq!(
+            outputs,
+            vec![
+                vec![Some(true); 16],
+                vec![Some(false); 16],
+                vec![Some(true); 16]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_engines() {
+        let set = BusMap::try_from([("in", vec![true]), ("load", vec![true])]).unwrap();
+        let hold = BusMap::try_from([("in", vec![false]), ("load", vec![false])]).unwrap();
+
+        let mut simulator = make_simulator("Bit.hdl");
+        simulator.simulate(&set).expect("simulation failure");
+        simulator.tick().expect("Tick failure");
+        let outputs = simulator.simulate(&hold).expect("simulation failure");
+        assert_eq!(outputs.get_name("out"), vec![Some(true)]);
+        assert_eq!(simulator.probe("out"), Some(vec![Some(true)]));
+        assert_eq!(simulator.probe("load"), Some(vec![Some(false)]));
+        assert_eq!(simulator.probe("dffOut"), Some(vec![Some(true)]));
+        assert_eq!(simulator.probe("muxOut"), Some(vec![Some(true)]));
+        assert_eq!(simulator.probe("nothing"), None);
+
+        // Wires are in the same order as ports.
+        let mut alu = make_simulator("ALU.hdl");
+        let mut x = vec![Some(false); 16];
+        x[15] = Some(true);
+        let mut inputs = BusMap::try_from([("y", vec![false; 16])]).unwrap();
+        for flag in ["zx", "nx", "zy", "ny", "f", "no"] {
+            inputs.create_bus(flag, 1).unwrap();
+            inputs.insert_option(&Bus::from(flag), vec![Some(false)]);
+        }
+        inputs.create_bus("x", 16).unwrap();
+        inputs.insert_option(&Bus::from("x"), x.clone());
+        alu.simulate(&inputs).expect("simulation failure");
+        assert_eq!(alu.probe("x"), Some(x.clone()));
+        assert_eq!(alu.probe("xOrZeroOut"), Some(x));
+
+        simulator.reset().expect("Reset failure");
+        let outputs = simulator.simulate(&hold).expect("simulation failure");
+        assert_eq!(outputs.get_name("out"), vec![Some(false)]);
+        assert_eq!(simulator.probe("muxOut"), Some(vec![Some(false)]));
+
+        // Another simulator standing in for an engine.
+        let engine = Box::new(make_simulator("Bit.hdl"));
+        let mut simulator = Simulator::with_engine(make_simulator("Bit.hdl").chip, engine);
+        simulator.simulate(&set).expect("simulation failure");
+        simulator.tick().expect("Tick failure");
+        simulator.simulate(&hold).expect("simulation failure");
+        assert_eq!(simulator.chip.signals.get_name("out"), vec![Some(true)]);
+        assert_eq!(simulator.probe("dffOut"), Some(vec![Some(true)]));
+        let snapshot = simulator.snapshot();
+        assert!(simulator.restore(&snapshot).is_err());
+        assert!(simulator.rebind(&[]).is_err());
+        simulator.reset().expect("Reset failure");
+        let outputs = simulator.simulate(&hold).expect("simulation failure");
+        assert_eq!(outputs.get_name("out"), vec![Some(false)]);
+    }
+
+    #[test]
+    fn test_imports() {
+        // app/Top.hdl uses lib/Inv.hdl, which uses lib/Nand2.hdl.
+        let dir = tempfile::tempdir().unwrap();
+        let app = dir.path().join("app");
+        let lib = dir.path().join("lib");
+        fs::create_dir(&app).unwrap();
+        fs::create_dir(&lib).unwrap();
+        fs::write(
+            lib.join("Inv.hdl"),
+            "CHIP Inv { IN in; OUT out; PARTS: Nand2(a=in, b=in, out=out); }",
+        )
+        .unwrap();
+        fs::write(
+            lib.join("Nand2.hdl"),
+            "CHIP Nand2 { IN a, b; OUT out; PARTS: Nand(a=a, b=b, out=out); }",
+        )
+        .unwrap();
+        let top = "USE \"../lib\";\nCHIP Top { IN a; OUT out; PARTS: Inv(in=a, out=out); }";
+
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(app.to_str().unwrap()));
+        let mut scanner = Scanner::new(top, app.join("Top.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+        let mut simulator = Simulator::new(chip);
+        let mut inputs = BusMap::new();
+        inputs.create_bus("a", 1).unwrap();
+        inputs.insert(Bus::from("a"), vec![false]);
+        let outputs = simulator.simulate(&inputs).expect("Simulation error");
+        assert_eq!(outputs.get_name("out"), vec![Some(true)]);
+
+        // Without the import the chip cannot be found.
+        let mut scanner = Scanner::new(&top[top.find('\n').unwrap()..], app.join("Top.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        assert!(Chip::new(&hdl, &provider, ChipOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_conditional_widths() {
+        // Low is at most 8 bits wide.
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Low.hdl"),
+            "CHIP Low<W> {
+                IN in[if(W > 8, 8, W)];
+                OUT out[if(W > 8, 8, W)];
+                PARTS:
+                FOR i IN 0 TO if(W > 8, 8, W) - 1 GENERATE {
+                    Nand(a=in[i], b=in[i], out=out[i]);
+                }
+            }",
+        )
+        .unwrap();
+        let top = "CHIP Top {
+            IN a[4], b[8];
+            OUT x[4], y[8];
+            PARTS:
+            Low<4>(in=a, out=x);
+            Low<12>(in=b, out=y);
+        }";
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let mut scanner = Scanner::new(top, dir.path().join("Top.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+        let mut simulator = Simulator::new(chip);
+        let outputs = simulator
+            .simulate(&BusMap::try_from([("a", vec![false; 4]), ("b", vec![true; 8])]).unwrap())
+            .expect("Simulation error");
+        assert_eq!(outputs.get_name("x"), vec![Some(true); 4]);
+        assert_eq!(outputs.get_name("y"), vec![Some(false); 8]);
+    }
+
+    #[test]
+    fn test_libraries() {
+        // The library has its own Not, which passes its input through, and
+        // an Inv that uses it. Both coexist with the Not next to Top.
+        let dir = tempfile::tempdir().unwrap();
+        let std_dir = dir.path().join("lib").join("std");
+        fs::create_dir_all(&std_dir).unwrap();
+        fs::write(
+            dir.path().join("whidl.json"),
+            "{ \"libraries\": { \"std\": \"lib/std\" } }",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("Not.hdl"),
+            "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=out); }",
+        )
+        .unwrap();
+        fs::write(
+            std_dir.join("Not.hdl"),
+            "CHIP Not { IN in; OUT out; PARTS: Nand(a=in, b=in, out=x); Nand(a=x, b=x, out=out); }",
+        )
+        .unwrap();
+        fs::write(
+            std_dir.join("Inv.hdl"),
+            "CHIP Inv { IN in; OUT out; PARTS: Not(in=in, out=out); }",
+        )
+        .unwrap();
+        let top = "CHIP Top {
+            IN in;
+            OUT a, b, c;
+            PARTS:
+            Not(in=in, out=a);
+            std.Not(in=in, out=b);
+            std.Inv(in=in, out=c);
+        }";
+
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let mut scanner = Scanner::new(top, dir.path().join("Top.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+        let mut simulator = Simulator::new(chip);
+        let outputs = simulator
+            .simulate(&BusMap::try_from([("in", true)]).unwrap())
+            .expect("Simulation error");
+        assert_eq!(outputs.get_name("a"), vec![Some(false)]);
+        assert_eq!(outputs.get_name("b"), vec![Some(true)]);
+        assert_eq!(outputs.get_name("c"), vec![Some(true)]);
+
+        // Libraries must be configured in a project file.
+        fs::remove_file(dir.path().join("whidl.json")).unwrap();
+        let e = match Chip::new(&hdl, &provider, ChipOptions::default()) {
+            Ok(_) => panic!("Expected an error"),
+            Err(e) => e,
+        };
+        assert!(e.to_string().contains("Chip Top uses library std"));
+    }
+
+    #[test]
+    fn test_builtin_annotation() {
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new("."));
+        let mut scanner = Scanner::new(
+            "@builtin(\"Inc16\") CHIP Plus1 { IN in[16]; OUT out[16]; }",
+            PathBuf::from("Plus1.hdl"),
+        );
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+        let mut simulator = Simulator::new(chip);
+        let mut inputs = BusMap::new();
+        inputs.create_bus("in", 16).unwrap();
+        let mut bits = vec![false; 16];
+        bits[15] = true;
+        inputs.insert(Bus::from("in"), bits);
+        let outputs = simulator.simulate(&inputs).expect("Simulation error");
+        let mut expected = vec![Some(false); 16];
+        expected[14] = Some(true);
+        assert_eq!(outputs.get_name("out"), expected);
+    }
+
+    #[test]
+    fn test_string_generics() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("ROM.hdl"),
+            "CHIP ROM<FILE> { IN address[2]; OUT out[4]; BUILTIN ROM; }",
+        )
+        .unwrap();
+        fs::write(dir.path().join("boot.hack"), "0001\n0010\n0100\n1000\n").unwrap();
+        fs::write(dir.path().join("other.hack"), "1111\n1110\n").unwrap();
+        let top = "CHIP Top {
+            IN a[2];
+            OUT x[4], y[4];
+            PARTS:
+            ROM<FILE=\"boot.hack\">(address=a, out=x);
+            ROM<\"other.hack\">(address=a, out=y);
+        }";
+        fs::write(dir.path().join("Top.hdl"), top).unwrap();
+
+        let provider: Arc<dyn HdlProvider> =
+            Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+        let hdl = get_hdl("Top", &provider).expect("Parse error");
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+        let mut simulator = Simulator::new(chip);
+        let mut inputs = BusMap::new();
+        inputs.create_bus("a", 2).unwrap();
+        inputs.insert(Bus::from("a"), vec![false, true]);
+        let outputs = simulator.simulate(&inputs).expect("Simulation error");
+        let bits = |s: &str| s.chars().map(|c| Some(c == '1')).collect::<Vec<_>>();
+        assert_eq!(outputs.get_name("x"), bits("0010"));
+        assert_eq!(outputs.get_name("y"), bits("1110"));
+    }
+
+    #[test]
+    fn test_part_arrays() {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let mut scanner = Scanner::new(
+            "CHIP And4 { IN a[4], b[4]; OUT out[4]; PARTS: And[4](a=a[i], b=b[i], out=out[i]); }",
+            PathBuf::from("And4.hdl"),
+        );
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+        let mut simulator = Simulator::new(chip);
+        let mut inputs = BusMap::new();
+        inputs.create_bus("a", 4).unwrap();
+        inputs.create_bus("b", 4).unwrap();
+        inputs.insert(Bus::from("a"), vec![true, true, false, true]);
+        inputs.insert(Bus::from("b"), vec![true, false, true, true]);
+        let outputs = simulator.simulate(&inputs).expect("Simulation error");
+        assert_eq!(
+            outputs.get_name("out"),
+            vec![Some(true), Some(false), Some(false), Some(true)]
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let hdl = get_hdl("PC", &provider).expect("Parse error");
+        let new_simulator = || {
+            Simulator::new(
+                Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error"),
+            )
+        };
+        let mut inputs = BusMap::new();
+        inputs.create_bus("in", 16).unwrap();
+        inputs.insert(Bus::from("in"), vec![false; 16]);
+        for (name, value) in [("load", false), ("inc", true), ("reset", false)] {
+            inputs.create_bus(name, 1).unwrap();
+            inputs.insert(Bus::from(name), vec![value]);
+        }
+
+        let mut simulator = new_simulator();
+        for _ in 0..5 {
+            simulator.simulate(&inputs).unwrap();
+            simulator.tick().unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pc.json");
+        simulator.snapshot().write(&path).unwrap();
+        let snapshot = Snapshot::read(&path).unwrap();
+        assert_eq!(snapshot.version, SNAPSHOT_VERSION);
+
+        let mut resumed = new_simulator();
+        resumed.restore(&snapshot).expect("Restore error");
+        for _ in 0..3 {
+            let expected = simulator.simulate(&inputs).unwrap();
+            assert_eq!(resumed.simulate(&inputs).unwrap(), expected);
+            simulator.tick().unwrap();
+            resumed.tick().unwrap();
+        }
+        assert_eq!(simulator.chip.state(), resumed.chip.state());
+
+        fs::write(&path, "{\"version\": 99}").unwrap();
+        let e = Snapshot::read(&path).err().unwrap();
+        assert!(e
+            .to_string()
+            .contains("is a version 99 snapshot, but this whidl reads up to version 1."));
+    }
+
+    #[test]
+    fn test_stub() {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let mut scanner = Scanner::new("CHIP Stub { IN a; OUT out; }", PathBuf::from("Stub.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let e = match Chip::new(&hdl, &provider, ChipOptions::default()) {
+            Ok(_) => panic!("Stubs should not simulate"),
+            Err(e) => e,
+        };
+        assert!(e.to_string().contains("Chip Stub is an interface stub"));
+    }
+
+    #[test]
+    fn test_did_you_mean() {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let error = |hdl: &str| {
+            let mut scanner = Scanner::new(hdl, PathBuf::from("Typo.hdl"));
+            let mut parser = Parser {
+                scanner: &mut scanner,
+            };
+            let hdl = parser.parse().expect("Parse error");
+            match Chip::new(
+                &hdl,
+                &provider,
+                ChipOptions {
+                    elaborate: true,
+                    ..ChipOptions::default()
+                },
+            ) {
+                Err(WhidlError::Elaboration(e)) => e.msg,
+                Err(e) => panic!("Not an elaboration error: {}", e),
+                Ok(_) => panic!("Expected an error"),
+            }
+        };
+
+        let msg = error("CHIP Typo { IN a; OUT out; PARTS: Nto(in=a, out=out); }");
+        assert!(msg.ends_with("Did you mean `Not`?"), "{}", msg);
+        let msg = error("CHIP Typo { IN a; OUT out; PARTS: Not(inn=a, out=out); }");
+        assert!(msg.ends_with("Did you mean `in`?"), "{}", msg);
+    }
+
     // Tests that multiple assignments to the same bit of a signal produce
     // an error. See https://github.com/whidl/whidl/issues/9
     #[test]
@@ -2164,18 +4838,25 @@ mod test {
                 .to_str()
                 .unwrap(),
         );
-        let provider: Rc<dyn HdlProvider> = Rc::new(FileReader::new(&base_path));
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(&base_path));
         let contents = provider.get_hdl("TwoAssignOK.hdl").unwrap();
         let mut scanner = Scanner::new(contents.as_str(), provider.get_path("TwoAssignOK.hdl"));
         let mut parser = Parser {
             scanner: &mut scanner,
         };
         let hdl = parser.parse().expect("Parse error");
-        let chip = Chip::new(&hdl, ptr::null_mut(), &provider, true, &Vec::new());
+        let chip = Chip::new(
+            &hdl,
+            &provider,
+            ChipOptions {
+                elaborate: true,
+                ..ChipOptions::default()
+            },
+        );
         assert!(chip.is_ok());
     }
 
-    // Tests that component instantiations provide inputs for all bits of component input ports.
+    // Tests that inputs component instantiations leave unconnected read as unknown.
     #[test]
     fn test_disconnected_component_inputs() {
         let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
@@ -2187,14 +4868,499 @@ mod test {
                 .to_str()
                 .unwrap(),
         );
-        let provider: Rc<dyn HdlProvider> = Rc::new(FileReader::new(&base_path));
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(&base_path));
         let contents = provider.get_hdl("Disconnected.hdl").unwrap();
         let mut scanner = Scanner::new(contents.as_str(), provider.get_path("TwoAssign.hdl"));
         let mut parser = Parser {
             scanner: &mut scanner,
         };
         let hdl = parser.parse().expect("Parse error");
-        let chip = Chip::new(&hdl, ptr::null_mut(), &provider, true, &Vec::new());
-        assert!(chip.is_err());
+        let chip = Chip::new(
+            &hdl,
+            &provider,
+            ChipOptions {
+                elaborate: true,
+                ..ChipOptions::default()
+            },
+        )
+        .unwrap();
+        let mut simulator = Simulator::new(chip);
+        let mut inputs = BusMap::new();
+        inputs.create_bus("a", 1).unwrap();
+        inputs.create_bus("b", 1).unwrap();
+        inputs.insert_option(&Bus::from("a"), vec![Some(true)]);
+        inputs.insert_option(&Bus::from("b"), vec![Some(true)]);
+        let outputs = simulator.simulate(&inputs).unwrap();
+        assert_eq!(outputs.get_name("out"), vec![None]);
+
+        let elaborated = crate::elaborate::elaborate(&hdl, &[], &provider).unwrap();
+        assert_eq!(
+            elaborated.warnings[0].msg,
+            "Part And does not connect input b."
+        );
+    }
+
+    fn make_inline_simulator(contents: &str) -> Result<Simulator, Box<dyn Error>> {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let base_path = String::from(
+            manifest_dir
+                .join("resources")
+                .join("tests")
+                .join("nand2tetris")
+                .join("solutions")
+                .to_str()
+                .unwrap(),
+        );
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(&base_path));
+        let mut scanner = Scanner::new(contents, PathBuf::from("Inline.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse()?;
+        let chip = Chip::new(&hdl, &provider, ChipOptions::default())?;
+        Ok(Simulator::new(chip))
+    }
+
+    #[test]
+    fn test_builtin_ram8() {
+        let mut simulator = make_inline_simulator(
+            "CHIP RAM8 {
+                IN in[16], load, address[3];
+                OUT out[16];
+                BUILTIN RAM8;
+                CLOCKED in, load;
+            }",
+        )
+        .expect("Chip creation error");
+        let b = Bus::from("out");
+
+        let outputs = simulator
+            .simulate(
+                &BusMap::try_from([
+                    ("in", vec![true; 16]),
+                    ("load", vec![true]),
+                    ("address", vec![false, true, false]),
+                ])
+                .unwrap(),
+            )
+            .expect("simulation failure");
+        assert_eq!(outputs.get_bus(&b), vec![Some(false); 16]);
+        simulator.tick().expect("Tick failure");
+        let outputs = simulator
+            .chip
+            .get_port_values_for_direction(PortDirection::Out);
+        assert_eq!(outputs.get_bus(&b), vec![Some(true); 16]);
+
+        let outputs = simulator
+            .simulate(
+                &BusMap::try_from([
+                    ("in", vec![false; 16]),
+                    ("load", vec![false]),
+                    ("address", vec![false, false, false]),
+                ])
+                .unwrap(),
+            )
+            .expect("simulation failure");
+        assert_eq!(outputs.get_bus(&b), vec![Some(false); 16]);
+    }
+
+    #[test]
+    fn test_builtin_component() {
+        // Register is builtin, but Bit is elaborated from its parts.
+        let mut simulator = make_inline_simulator(
+            "CHIP Wrapper {
+                IN in[16], load;
+                OUT out[16];
+                PARTS:
+                Register(in=in, load=load, out=out);
+            }",
+        )
+        .expect("Chip creation error");
+        simulator
+            .simulate(&BusMap::try_from([("in", vec![true; 16]), ("load", vec![true])]).unwrap())
+            .expect("simulation failure");
+        simulator.tick().expect("Tick failure");
+        let outputs = simulator
+            .chip
+            .get_port_values_for_direction(PortDirection::Out);
+        assert_eq!(outputs.get_bus(&Bus::from("out")), vec![Some(true); 16]);
+    }
+
+    #[test]
+    fn test_memory_monitor() {
+        // A single register standing in for a memory ignores the address.
+        let mut simulator = make_inline_simulator(
+            "CHIP BadMemory {
+                IN in[16], load, address[2];
+                OUT out[16];
+                PARTS:
+                Register(in=in, load=load, out=out);
+                MemoryMonitor<2, 16>(address=address, in=in, load=load, out=out);
+            }",
+        )
+        .expect("Chip creation error");
+        let mut step = |value: bool, load: bool, address: bool| {
+            simulator
+                .simulate(
+                    &BusMap::try_from([
+                        ("in", vec![value; 16]),
+                        ("load", vec![load]),
+                        ("address", vec![false, address]),
+                    ])
+                    .unwrap(),
+                )
+                .expect("simulation failure");
+            simulator.tick().expect("Tick failure");
+            simulator.chip.take_violations()
+        };
+        assert!(step(true, true, false).is_empty());
+        assert!(step(false, true, true).is_empty());
+        assert_eq!(
+            step(false, false, false),
+            vec!["MemoryMonitor cycle 3: read 0 from address 0, but 65535 was written there."]
+        );
+        assert!(step(false, false, true).is_empty());
+    }
+
+    #[test]
+    fn test_builtin_fallback() {
+        let mut simulator = make_inline_simulator(
+            "CHIP MyNot {
+                IN in;
+                OUT out;
+                BUILTIN MyNot;
+                PARTS:
+                Nand(a=in, b=in, out=out);
+            }",
+        )
+        .expect("Chip creation error");
+        let outputs = simulator
+            .simulate(&BusMap::try_from([("in", false)]).unwrap())
+            .expect("simulation failure");
+        assert_eq!(outputs.get_bus(&Bus::from("out")), vec![Some(true)]);
+
+        assert!(make_inline_simulator(
+            "CHIP MyNot {
+                IN in;
+                OUT out;
+                BUILTIN MyNot;
+            }"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_table() {
+        // The row for `1 1` is left out, so its outputs are undefined.
+        let mut simulator = make_inline_simulator(
+            "CHIP HalfAdder {
+                IN a, b;
+                OUT sum, carry;
+                TABLE {
+                    0 0 -> 0 0;
+                    0 1 -> 1 0;
+                    1 0 -> 1 0;
+                }
+            }",
+        )
+        .expect("Chip creation error");
+        let mut run = |a, b| {
+            let outputs = simulator
+                .simulate(&BusMap::try_from([("a", a), ("b", b)]).unwrap())
+                .expect("simulation failure");
+            (
+                outputs.get_bus(&Bus::from("sum"))[0],
+                outputs.get_bus(&Bus::from("carry"))[0],
+            )
+        };
+        assert_eq!(run(false, true), (Some(true), Some(false)));
+        assert_eq!(run(false, false), (Some(false), Some(false)));
+        assert_eq!(run(true, true), (None, None));
+
+        let e = make_inline_simulator(
+            "CHIP Wide {
+                IN a[2];
+                OUT out;
+                TABLE { 0 -> 1; }
+            }",
+        )
+        .err()
+        .unwrap();
+        assert!(
+            e.to_string().contains("has 1 input and 1 output bits"),
+            "{}",
+            e
+        );
+    }
+
+    // Reads an undefined input as 0, so a loop through it never settles.
+    #[derive(Clone)]
+    struct Toggle {}
+
+    impl Builtin for Toggle {
+        fn eval(&mut self, signals: &mut BusMap) {
+            let x = signals.get_name("in")[0].unwrap_or(false);
+            signals.insert_option(&Bus::from("out"), vec![Some(!x)]);
+        }
+    }
+
+    #[test]
+    fn test_settle_limit() {
+        let mut simulator = make_inline_simulator(
+            "CHIP Ring {
+                IN in;
+                OUT out;
+                PARTS:
+                Not(in=x, out=x);
+                And(a=x, b=in, out=out);
+            }",
+        )
+        .expect("Chip creation error");
+        simulator.chip.elaborate().unwrap();
+        for c in simulator.chip.circuit.node_weights_mut() {
+            if c.name == "Not" {
+                c.builtin = Some(Box::new(Toggle {}));
+                c.dirty = true;
+            }
+        }
+        simulator.settle_limit = 10;
+        let e = simulator
+            .simulate(&BusMap::try_from([("in", true)]).unwrap())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(e.contains("did not settle after 10 passes"), "{}", e);
+        assert!(e.contains("Still changing: x (1, 0); out (1, 0)."), "{}", e);
+
+        // Simulators and engines start with the limit the chip was built
+        // with.
+        let hdl = simulator.chip.hdl.clone().unwrap();
+        let options = ChipOptions {
+            settle_limit: 7,
+            ..ChipOptions::default()
+        };
+        let chip = Chip::new(&hdl, &simulator.chip.hdl_provider, options).unwrap();
+        let engine = crate::netlist::NetlistEngine::new(&chip).unwrap();
+        assert_eq!(crate::netlist::FlatEngine::state(&engine).settle_limit, 7);
+        assert_eq!(Simulator::new(chip).settle_limit, 7);
+    }
+
+    #[test]
+    fn test_loop_lanes() {
+        let mut simulator = make_inline_simulator(
+            "CHIP Lanes {
+                IN a[16], b[16], sel;
+                OUT out[16], ripple;
+                PARTS:
+                FOR i IN 0 TO 15 GENERATE {
+                    Xor(a=a[i], b=b[i], out=x[i]);
+                    Mux(a=x[i], b=a[i], sel=sel, out=out[i]);
+                }
+                // Each And reads the one before it, so they stay separate.
+                And(a=a[0], b=b[0], out=c[0]);
+                FOR i IN 1 TO 15 GENERATE {
+                    And(a=c[i - 1], b=a[i], out=c[i]);
+                }
+                Or(a=c[15], b=false, out=ripple);
+            }",
+        )
+        .expect("Chip creation error");
+        let mut run = |a: u16, b: u16, sel: bool| {
+            let mut inputs = BusMap::new();
+            inputs.create_bus("a", 16).unwrap();
+            inputs.create_bus("b", 16).unwrap();
+            inputs.create_bus("sel", 1).unwrap();
+            // Buses hold their highest bit first.
+            let bits = |w: u16| (0..16).rev().map(|i| Some(w >> i & 1 == 1)).collect();
+            inputs.insert_option(&Bus::from("a"), bits(a));
+            inputs.insert_option(&Bus::from("b"), bits(b));
+            inputs.insert_option(&Bus::from("sel"), vec![Some(sel)]);
+            let outputs = simulator.simulate(&inputs).expect("simulation failure");
+            let out = outputs
+                .get_name("out")
+                .iter()
+                .fold(0u16, |acc, b| acc << 1 | b.unwrap() as u16);
+            (out, outputs.get_name("ripple")[0].unwrap())
+        };
+        assert_eq!(run(0xF0F0, 0xFF00, false), (0x0FF0, false));
+        assert_eq!(run(0xF0F0, 0xFF00, true), (0xF0F0, false));
+
+        let netlist = simulator.chip.netlist();
+        assert!(
+            netlist.starts_with("Lanes: And x16, Mux[16] x1, Or x1, Xor[16] x1"),
+            "{}",
+            netlist
+        );
+        assert!(netlist.contains("Xor[16]: builtin"), "{}", netlist);
+    }
+
+    #[test]
+    fn test_expression_parts() {
+        let mut simulator = make_inline_simulator(
+            "CHIP Expressions {
+                IN a, b, c, v[4];
+                OUT majority, parity, out[4];
+                PARTS:
+                majority = a AND b OR c AND (a OR b);
+                parity = a XOR b XOR NOT NOT c;
+                FOR i IN 0 TO 3 GENERATE {
+                    out[i] = NOT (v[i] AND a);
+                }
+            }",
+        )
+        .expect("Chip creation error");
+        for row in 0..8 {
+            let (a, b, c) = (row & 4 != 0, row & 2 != 0, row & 1 != 0);
+            let mut inputs = BusMap::new();
+            for (name, value) in [("a", a), ("b", b), ("c", c)] {
+                inputs.create_bus(name, 1).unwrap();
+                inputs.insert_option(&Bus::from(name), vec![Some(value)]);
+            }
+            inputs.create_bus("v", 4).unwrap();
+            inputs.insert_option(
+                &Bus::from("v"),
+                vec![Some(true), Some(false), Some(true), Some(false)],
+            );
+            let outputs = simulator.simulate(&inputs).expect("simulation failure");
+            let count = a as u8 + b as u8 + c as u8;
+            assert_eq!(outputs.get_name("majority"), vec![Some(count >= 2)]);
+            assert_eq!(outputs.get_name("parity"), vec![Some(count % 2 == 1)]);
+            assert_eq!(
+                outputs.get_name("out"),
+                vec![Some(!a), Some(true), Some(!a), Some(true)]
+            );
+        }
+    }
+
+    #[test]
+    fn test_descending_range() {
+        let mut simulator = make_inline_simulator(
+            "CHIP Reverse {
+                IN in[4];
+                OUT out[4];
+                PARTS:
+                Not16(in[0..3]=in[3..0], in[4..15]=false, out[0..3]=out);
+            }",
+        )
+        .expect("Chip creation error");
+        let outputs = simulator
+            .simulate(&BusMap::try_from([("in", vec![false, false, false, true])]).unwrap())
+            .expect("simulation failure");
+        assert_eq!(
+            outputs.get_bus(&Bus::from("out")),
+            vec![Some(false), Some(true), Some(true), Some(true)]
+        );
+    }
+
+    #[test]
+    fn test_strided_range() {
+        // Separates the even and odd bits of in, inverted.
+        let mut simulator = make_inline_simulator(
+            "CHIP Deinterleave {
+                IN in[8];
+                OUT even[4], odd[4];
+                PARTS:
+                Not16(in[0..3]=in[0..6:2], in[4..7]=in[7..1:2], in[8..15]=false,
+                      out[0..3]=even, out[4..7]=odd);
+            }",
+        )
+        .expect("Chip creation error");
+        let bits = |s: &str| s.chars().map(|c| c == '1').collect::<Vec<bool>>();
+        let outputs = simulator
+            .simulate(&BusMap::try_from([("in", bits("11001010"))]).unwrap())
+            .expect("simulation failure");
+        let values = |s: &str| s.chars().map(|c| Some(c == '1')).collect::<Vec<_>>();
+        assert_eq!(outputs.get_bus(&Bus::from("even")), values("0111"));
+        assert_eq!(outputs.get_bus(&Bus::from("odd")), values("0010"));
+
+        let mut simulator = make_inline_simulator(
+            "CHIP Uneven {
+                IN in[8];
+                OUT out[4];
+                PARTS:
+                Not16(in[0..3]=in[0..7:2], in[4..15]=false, out[0..3]=out);
+            }",
+        )
+        .expect("Chip creation error");
+        let inputs = BusMap::try_from([("in", vec![false; 8])]).unwrap();
+        let err = simulator
+            .simulate(&inputs)
+            .err()
+            .expect("Expected step error");
+        assert!(err.to_string().contains("must end on a step"));
+    }
+
+    #[test]
+    fn test_open_ports() {
+        let mut simulator = make_inline_simulator(
+            "CHIP FirstTwo {
+                IN in, sel[3];
+                OUT a, b;
+                PARTS:
+                DMux8Way(in=in, sel=sel, a=a, b=b, c=open, d=open, e=open, f=open, g=open, h=open);
+            }",
+        )
+        .expect("Chip creation error");
+        let outputs = simulator
+            .simulate(
+                &BusMap::try_from([("in", vec![true]), ("sel", vec![false, false, true])]).unwrap(),
+            )
+            .expect("simulation failure");
+        assert_eq!(outputs.get_name("a"), vec![Some(false)]);
+        assert_eq!(outputs.get_name("b"), vec![Some(true)]);
+
+        let err = match make_inline_simulator(
+            "CHIP OpenInput {
+                IN in;
+                OUT out;
+                PARTS:
+                And(a=in, b=open, out=out);
+            }",
+        ) {
+            Ok(_) => panic!("Open input elaborated"),
+            Err(e) => e,
+        };
+        assert!(err
+            .to_string()
+            .contains("Input b of And cannot be left open."));
+    }
+
+    #[test]
+    fn test_range_start_after_end() {
+        let simulator = make_inline_simulator(
+            "CHIP Backwards {
+                IN in[2];
+                OUT out;
+                PARTS:
+                FOR i IN 0 TO 0 GENERATE {
+                    Or(a=in[i+1..i], b=false, out=out);
+                }
+            }",
+        );
+        let err = simulator.err().expect("Expected range error");
+        assert!(err.to_string().contains("starts after it ends"));
+    }
+
+    #[test]
+    fn test_unset_input() {
+        let mut simulator = make_inline_simulator(
+            "CHIP Both {
+                IN a, b, c;
+                OUT out;
+                PARTS:
+                And(a=a, b=b, out=ab);
+                And(a=ab, b=c, out=out);
+            }",
+        )
+        .expect("Chip creation error");
+        let err = simulator
+            .simulate(&BusMap::try_from([("b", vec![true])]).unwrap())
+            .expect_err("Expected unset input error");
+        assert!(
+            err.to_string()
+                .contains("Chip Both was simulated before its inputs a, c were set."),
+            "{}",
+            err
+        );
     }
 }
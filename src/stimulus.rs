@@ -0,0 +1,242 @@
+// Runs testbench chips, which drive their own signals from a `STIMULUS`
+// block instead of from a test script:
+//
+//     CHIP AddBench {
+//         OUT sum[16];
+//         STIMULUS 100 {
+//             a[16] = COUNTER;
+//             b[16] = RANDOM 42;
+//         }
+//         PARTS:
+//         Add16(a=a, b=b, out=sum);
+//     }
+//
+// Every cycle the stimulus signals take their next value, the chip is
+// evaluated, and then the clock ticks. VHDL synthesis turns the block into a
+// process that produces the same values.
+
+use std::error::Error;
+use std::sync::Arc;
+
+use crate::busmap::BusMap;
+use crate::error::{ErrorKind, N2VError};
+use crate::expr::{GenericWidth, Terminal};
+use crate::parser::{
+    ChipHDL, Generator, GenericPort, HdlProvider, PortDirection, Stimulus, StimulusSignal,
+};
+use crate::simulator::{Bus, Chip, ChipOptions, Simulator};
+
+/// One step of the xorshift generator behind `RANDOM`.
+pub fn xorshift(mut x: u64) -> u64 {
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    x
+}
+
+/// Produces the value of every stimulus signal, one cycle at a time.
+pub struct Sources<'a> {
+    stimulus: &'a Stimulus,
+    cycle: u64,
+    states: Vec<u64>, // Generator state for each signal.
+}
+
+impl<'a> Sources<'a> {
+    pub fn new(stimulus: &'a Stimulus) -> Sources<'a> {
+        let states = stimulus
+            .signals
+            .iter()
+            .map(|s| match s.generator {
+                Generator::Counter => 0,
+                Generator::Random(seed) => seed,
+            })
+            .collect();
+        Sources {
+            stimulus,
+            cycle: 0,
+            states,
+        }
+    }
+
+    /// Values for the next cycle.
+    pub fn next_values(&mut self) -> BusMap {
+        let mut values = BusMap::new();
+        for (signal, state) in self.stimulus.signals.iter().zip(&mut self.states) {
+            let value = match signal.generator {
+                Generator::Counter => self.cycle,
+                Generator::Random(_) => {
+                    *state = xorshift(*state);
+                    *state
+                }
+            };
+            let bits = (0..signal.width).rev().map(|i| (value >> i) & 1 == 1);
+            values.create_bus(&signal.name.value, signal.width).unwrap();
+            values.insert(Bus::from(signal.name.value.as_str()), bits.collect());
+        }
+        self.cycle += 1;
+        values
+    }
+}
+
+/// The testbench as an ordinary chip whose stimulus signals are inputs.
+pub fn bench_hdl(hdl: &ChipHDL) -> Result<ChipHDL, Box<dyn Error>> {
+    let stimulus = stimulus(hdl)?;
+    let mut bench = hdl.clone();
+    for StimulusSignal { name, width, .. } in &stimulus.signals {
+        if hdl.ports.iter().any(|p| p.name.value == name.value) {
+            return Err(Box::new(N2VError {
+                msg: format!(
+                    "Stimulus {} has the same name as a port of {}.",
+                    name.value, hdl.name
+                ),
+                kind: ErrorKind::ElaborationError,
+            }));
+        }
+        bench.ports.push(GenericPort {
+            name: name.clone(),
+            width: GenericWidth::Terminal(Terminal::Num(*width)),
+            direction: PortDirection::In,
+        });
+    }
+    Ok(bench)
+}
+
+fn stimulus(hdl: &ChipHDL) -> Result<&Stimulus, Box<dyn Error>> {
+    hdl.stimulus.as_ref().ok_or_else(|| {
+        Box::new(N2VError {
+            msg: format!("Chip {} is not a testbench, it has no STIMULUS.", hdl.name),
+            kind: ErrorKind::ElaborationError,
+        }) as Box<dyn Error>
+    })
+}
+
+/// Simulates a testbench for all of its cycles. Returns the stimulus
+/// signals and ports of the chip for each cycle, before the clock ticks.
+/// The testbench is built with `options`.
+pub fn run(
+    hdl: &ChipHDL,
+    provider: &Arc<dyn HdlProvider>,
+    options: &ChipOptions,
+) -> Result<Vec<BusMap>, Box<dyn Error>> {
+    let bench = bench_hdl(hdl)?;
+    let chip = Chip::new(&bench, provider, options.clone())?;
+    let mut simulator = Simulator::new(chip);
+    let stimulus = stimulus(hdl)?;
+    let mut sources = Sources::new(stimulus);
+
+    let mut cycles = Vec::new();
+    for _ in 0..stimulus.cycles {
+        let inputs = sources.next_values();
+        cycles.push(simulator.simulate(&inputs)?);
+        simulator.tick()?;
+    }
+    Ok(cycles)
+}
+
+/// A table with a row for each cycle, values written most significant bit
+/// first. Stimulus signals come first, then the ports of the chip.
+pub fn table(hdl: &ChipHDL, cycles: &[BusMap]) -> String {
+    let mut names = vec![String::from("cycle")];
+    if let Some(s) = &hdl.stimulus {
+        names.extend(s.signals.iter().map(|s| s.name.value.clone()));
+    }
+    names.extend(hdl.ports.iter().map(|p| p.name.value.clone()));
+
+    let mut rows = vec![names.clone()];
+    for (i, signals) in cycles.iter().enumerate() {
+        let mut row = vec![i.to_string()];
+        for name in &names[1..] {
+            row.push(
+                signals
+                    .get_name(name)
+                    .iter()
+                    .map(|b| match b {
+                        None => '?',
+                        Some(true) => '1',
+                        Some(false) => '0',
+                    })
+                    .collect(),
+            );
+        }
+        rows.push(row);
+    }
+
+    let widths: Vec<usize> = (0..names.len())
+        .map(|c| rows.iter().map(|r| r[c].len()).max().unwrap())
+        .collect();
+    rows.iter()
+        .map(|r| {
+            let cells: Vec<String> = r
+                .iter()
+                .zip(&widths)
+                .map(|(cell, w)| format!(" {:>w$} ", cell, w = w))
+                .collect();
+            format!("|{}|\n", cells.join("|"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::{FileReader, Parser};
+    use crate::scanner::Scanner;
+    use std::path::Path;
+
+    const ADD_BENCH: &str = "CHIP AddBench {
+        OUT sum[4];
+        STIMULUS 4 {
+            a[4] = COUNTER;
+            b[4] = RANDOM 1;
+        }
+        PARTS:
+        Add16(a[0..3]=a, a[4..15]=false, b[0..3]=b, b[4..15]=false, out[0..3]=sum);
+    }";
+
+    fn add_bench() -> (ChipHDL, Arc<dyn HdlProvider>) {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let mut scanner = Scanner::new(ADD_BENCH, base_path.join("AddBench.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        (parser.parse().expect("Parse error"), provider)
+    }
+
+    #[test]
+    fn test_run() {
+        let (hdl, provider) = add_bench();
+        let cycles = run(&hdl, &provider, &ChipOptions::default()).expect("Simulation error");
+        assert_eq!(
+            table(&hdl, &cycles),
+            "| cycle |    a |    b |  sum |
+|     0 | 0000 | 0001 | 0001 |
+|     1 | 0001 | 0001 | 0010 |
+|     2 | 0010 | 1001 | 1011 |
+|     3 | 0011 | 0101 | 1000 |
+"
+        );
+    }
+
+    #[test]
+    fn test_vhdl() {
+        let (hdl, provider) = add_bench();
+        let entities = crate::vhdl::synth_vhdl(&hdl, &provider).expect("VHDL error");
+        let vhdl = &entities["AddBench"];
+        assert!(vhdl.contains("use ieee.numeric_std.all;"));
+        assert!(vhdl.contains("signal a : std_logic_vector(3 downto 0);"));
+        assert!(vhdl
+            .contains("\t\tvariable b_state : unsigned(63 downto 0) := x\"0000000000000001\";\n"));
+        assert!(vhdl.contains(
+            "\t\tfor cycle in 0 to 3 loop
+\t\t\ta <= std_logic_vector(a_state(3 downto 0));
+\t\t\ta_state := a_state + 1;
+\t\t\tb_state := b_state xor shift_left(b_state, 13);"
+        ));
+        assert!(vhdl.contains("\t\t\tb <= std_logic_vector(b_state(3 downto 0));"));
+    }
+}
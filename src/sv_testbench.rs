@@ -0,0 +1,221 @@
+// This module generates a SystemVerilog testbench from a nand2tetris test
+// script. The testbench replays the test vectors against the Verilog
+// produced from the FIRRTL backend and a small scoreboard compares the
+// outputs against the .cmp file, so the same vectors that verify the
+// simulator can be used in commercial simulators.
+
+use std::error::Error;
+use std::fmt::Write;
+
+use crate::error::{ErrorKind, N2VError};
+use crate::parser::*;
+use crate::simulator::Port;
+use crate::test_parser::*;
+use crate::test_script::{device_error, input_bits, load_test_vectors, TestVectors};
+
+// Scoreboard shared by every generated testbench. Values are compared with
+// a mask so that wildcard columns in the .cmp file are ignored.
+const SCOREBOARD: &str = "  class scoreboard;
+    int checks = 0;
+    int errors = 0;
+
+    function void check(int step, string port, logic [63:0] actual, logic [63:0] expected, logic [63:0] mask);
+      checks++;
+      if ((actual & mask) !== (expected & mask)) begin
+        errors++;
+        $display(\"Step %0d: %s expected %b, got %b\", step, port, expected & mask, actual & mask);
+      end
+    endfunction
+
+    function void report();
+      if (errors == 0)
+        $display(\"PASS: %0d checks\", checks);
+      else
+        $display(\"FAIL: %0d of %0d checks failed\", errors, checks);
+    endfunction
+  endclass
+";
+
+/// Generates a SystemVerilog testbench for a nand2tetris test script.
+///
+/// `test_script_path` - Path to the .tst file. The HDL and .cmp files it
+/// references are resolved relative to it.
+/// `lib_path` - Directories to look for the chip's parts in after its own.
+pub fn synth_sv_testbench(
+    test_script_path: &str,
+    lib_path: &[String],
+) -> Result<String, Box<dyn Error>> {
+    let vectors = load_test_vectors(test_script_path, lib_path)?;
+    synth_testbench(&vectors)
+}
+
+// SystemVerilog literal for a vector of bits, most significant bit first.
+// Undefined bits become `x`.
+fn sv_literal(bits: &[Option<bool>]) -> String {
+    let digits: String = bits
+        .iter()
+        .map(|b| match b {
+            Some(true) => '1',
+            Some(false) => '0',
+            None => 'x',
+        })
+        .collect();
+    format!("{}'b{}", bits.len(), digits)
+}
+
+fn sv_type(width: usize) -> String {
+    if width == 1 {
+        String::from("logic")
+    } else {
+        format!("logic [{}:0]", width - 1)
+    }
+}
+
+fn synth_testbench(vectors: &TestVectors) -> Result<String, Box<dyn Error>> {
+    let hdl = &vectors.hdl;
+    let ports = &vectors.ports;
+    let expected = &vectors.expected;
+    let mut tb = String::new();
+    let inputs: Vec<&Port> = hdl
+        .ports
+        .iter()
+        .map(|p| &ports[&p.name.value])
+        .filter(|p| p.direction == PortDirection::In)
+        .collect();
+    let outputs: Vec<&Port> = hdl
+        .ports
+        .iter()
+        .map(|p| &ports[&p.name.value])
+        .filter(|p| p.direction == PortDirection::Out)
+        .collect();
+
+    writeln!(&mut tb, "`timescale 1ns/1ps")?;
+    writeln!(&mut tb)?;
+    writeln!(&mut tb, "module {}_tb;", hdl.name)?;
+    write!(&mut tb, "{}", SCOREBOARD)?;
+    writeln!(&mut tb)?;
+    writeln!(&mut tb, "  logic clock = 0;")?;
+    for p in &inputs {
+        writeln!(&mut tb, "  {} {} = '0;", sv_type(p.width), p.name.value)?;
+    }
+    for p in &outputs {
+        writeln!(&mut tb, "  {} {};", sv_type(p.width), p.name.value)?;
+    }
+    writeln!(&mut tb)?;
+
+    let connections: Vec<String> = std::iter::once(String::from(".clock(clock)"))
+        .chain(
+            inputs
+                .iter()
+                .chain(outputs.iter())
+                .map(|p| format!(".{}({})", p.name.value, p.name.value)),
+        )
+        .collect();
+    writeln!(&mut tb, "  {} dut ({});", hdl.name, connections.join(", "))?;
+    writeln!(&mut tb)?;
+
+    // Outputs are sampled just before the rising edge.
+    writeln!(&mut tb, "  default clocking cb @(posedge clock);")?;
+    for p in &outputs {
+        writeln!(&mut tb, "    input {};", p.name.value)?;
+    }
+    writeln!(&mut tb, "  endclocking")?;
+    writeln!(&mut tb)?;
+
+    writeln!(&mut tb, "  scoreboard sb = new();")?;
+    writeln!(&mut tb)?;
+    writeln!(&mut tb, "  initial begin")?;
+
+    let mut cmp_idx = 0;
+    for step in &vectors.script.steps {
+        for instruction in &step.instructions {
+            match instruction {
+                Instruction::Set(port, value) => {
+                    let width = match ports.get(port) {
+                        Some(p) => p.width,
+                        None => {
+                            return Err(Box::new(N2VError {
+                                msg: format!(
+                                    "Test script sets port {} which the chip does not have.",
+                                    port
+                                ),
+                                kind: ErrorKind::Other,
+                            }));
+                        }
+                    };
+                    let bits = input_bits(value, width)?;
+                    writeln!(&mut tb, "    {} = {};", port, sv_literal(&bits))?;
+                }
+                Instruction::Eval | Instruction::Tick => {
+                    writeln!(&mut tb, "    #1;")?;
+                }
+                Instruction::Tock => {
+                    writeln!(&mut tb, "    clock = 1;")?;
+                    writeln!(&mut tb, "    #1;")?;
+                    writeln!(&mut tb, "    clock = 0;")?;
+                    writeln!(&mut tb, "    #1;")?;
+                }
+                Instruction::Press(_) => return Err(device_error("SystemVerilog", "keyboard")),
+                Instruction::Screen(_) => return Err(device_error("SystemVerilog", "screen")),
+                Instruction::Output => {
+                    if cmp_idx >= expected.len() {
+                        return Err(Box::new(N2VError {
+                            msg: String::from("Test script has more outputs than the .cmp file."),
+                            kind: ErrorKind::Other,
+                        }));
+                    }
+                    let step_expected = &expected[cmp_idx];
+                    for name in step_expected.signals() {
+                        let bits = step_expected.get_name(&name);
+                        let mask: Vec<Option<bool>> =
+                            bits.iter().map(|b| Some(b.is_some())).collect();
+                        let value: Vec<Option<bool>> =
+                            bits.iter().map(|b| Some(b.unwrap_or(false))).collect();
+                        writeln!(
+                            &mut tb,
+                            "    sb.check({}, \"{}\", {}, {}, {});",
+                            cmp_idx + 1,
+                            name,
+                            name,
+                            sv_literal(&value),
+                            sv_literal(&mask)
+                        )?;
+                    }
+                    cmp_idx += 1;
+                }
+            }
+        }
+    }
+
+    writeln!(&mut tb, "    sb.report();")?;
+    writeln!(&mut tb, "    $finish;")?;
+    writeln!(&mut tb, "  end")?;
+    writeln!(&mut tb, "endmodule")?;
+
+    Ok(tb)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::path::Path;
+
+    #[test]
+    fn test_nand2tetris_solution_bit() {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let path = manifest_dir
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions")
+            .join("Bit.tst");
+        let tb = synth_sv_testbench(path.to_str().unwrap(), &[]).expect("Testbench error");
+
+        assert!(tb.contains("module Bit_tb;"));
+        assert!(tb.contains("  Bit dut (.clock(clock), .in(in), .load(load), .out(out));"));
+        assert!(tb.contains("    load = 1'b1;"));
+        assert!(tb.contains("    sb.check(1, \"out\", out, 1'b0, 1'b1);"));
+        assert!(tb.ends_with("endmodule\n"));
+    }
+}
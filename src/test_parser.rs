@@ -1,3 +1,4 @@
+use crate::builtin::{key_code, SCREEN_HEIGHT, SCREEN_WIDTH};
 use crate::error::{ErrorKind, N2VError};
 use crate::test_scanner::{TestScanner, Token, TokenType};
 use std::path::PathBuf;
@@ -22,6 +23,68 @@ pub enum Instruction {
     Output,
     Tick,
     Tock,
+    Press(KeyPress),
+    Screen(ScreenCheck),
+}
+
+/// `at tick 1000 press 'A' for 50 ticks` holds a key down on the keyboard
+/// for clock cycles 1000 to 1049, counting from the start of the script.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyPress {
+    pub at: u64,
+    pub key: u16, // nand2tetris key code.
+    pub ticks: u64,
+}
+
+/// `at tick 5000 expect pixel 10 20 is 1` checks the screen after 5000
+/// clock cycles. `region X Y WIDTH HEIGHT` checks a rectangle of pixels and
+/// `screen` all of them, either against one value or a hash of the pixels.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScreenCheck {
+    pub at: u64,
+    pub region: Region,
+    pub expected: ScreenValue,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Region {
+    pub fn screen() -> Region {
+        Region {
+            x: 0,
+            y: 0,
+            width: SCREEN_WIDTH,
+            height: SCREEN_HEIGHT,
+        }
+    }
+}
+
+impl std::fmt::Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if *self == Region::screen() {
+            write!(f, "the screen")
+        } else if self.width == 1 && self.height == 1 {
+            write!(f, "pixel {} {}", self.x, self.y)
+        } else {
+            write!(
+                f,
+                "region {} {} {} {}",
+                self.x, self.y, self.width, self.height
+            )
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScreenValue {
+    Fill(bool), // Every pixel is on, or every pixel is off.
+    Hash(u64),
 }
 
 #[derive(Clone)]
@@ -33,6 +96,7 @@ pub struct InputValue {
 #[derive(Clone)]
 pub struct Step {
     pub instructions: Vec<Instruction>,
+    pub line: u32, // Line of the first instruction.
 }
 
 #[derive(Clone)]
@@ -79,7 +143,17 @@ pub struct TestParser<'a, 'b> {
 
 impl<'a, 'b> TestParser<'a, 'b> {
     pub fn parse(&mut self) -> Result<TestScript, N2VError> {
-        self.test_script()
+        let script = self.test_script();
+        self.scanned(script)
+    }
+
+    // Text that is not a token is reported instead of the parse error it
+    // leads to.
+    fn scanned<T>(&mut self, result: Result<T, N2VError>) -> Result<T, N2VError> {
+        match self.scanner.error.take() {
+            Some(e) => Err(e),
+            None => result,
+        }
     }
 
     fn consume(&mut self, tt: TokenType) -> Result<Token, N2VError> {
@@ -110,23 +184,41 @@ impl<'a, 'b> TestParser<'a, 'b> {
         }
     }
 
+    // An error at `next`, or at the end of the file if there is no next token.
+    fn unexpected(&self, next: Option<Token>, msg: String) -> N2VError {
+        let token = next.unwrap_or_else(|| Token {
+            lexeme: String::from(""),
+            path: self.scanner.path.clone(),
+            line: self.scanner.line,
+            token_type: TokenType::Eof,
+        });
+        N2VError {
+            msg,
+            kind: ErrorKind::TestParseError(token),
+        }
+    }
+
     fn test_script(&mut self) -> Result<TestScript, N2VError> {
         // Load cannot be a keyword because it is used as a port name.
-        if self.consume(TokenType::Identifier).unwrap().lexeme != "load" {
-            panic!("Expected load.");
+        let load = self.consume(TokenType::Identifier)?;
+        if load.lexeme != "load" {
+            return Err(N2VError {
+                msg: format!("Expected `load`, found `{}`.", load.lexeme),
+                kind: ErrorKind::TestParseError(load),
+            });
         }
 
         let generics = self.generics()?;
 
-        let hdl_file = PathBuf::from(self.consume(TokenType::Identifier).unwrap().lexeme);
+        let hdl_file = PathBuf::from(self.consume(TokenType::Identifier)?.lexeme);
         self.consume(TokenType::Comma)?;
 
         self.consume(TokenType::OutputFile)?;
-        let output_file = PathBuf::from(self.consume(TokenType::Identifier).unwrap().lexeme);
+        let output_file = PathBuf::from(self.consume(TokenType::Identifier)?.lexeme);
         self.consume(TokenType::Comma)?;
 
         self.consume(TokenType::CompareTo)?;
-        let compare_file = PathBuf::from(self.consume(TokenType::Identifier).unwrap().lexeme);
+        let compare_file = PathBuf::from(self.consume(TokenType::Identifier)?.lexeme);
         self.consume(TokenType::Comma)?;
 
         let output_list = self.output_list()?;
@@ -148,10 +240,7 @@ impl<'a, 'b> TestParser<'a, 'b> {
 
     fn steps(&mut self) -> Result<Vec<Step>, N2VError> {
         let mut res: Vec<Step> = Vec::new();
-        loop {
-            if self.scanner.peek().is_none() {
-                break;
-            }
+        while let Some(first) = self.scanner.peek() {
             let mut instructions: Vec<Instruction> = Vec::new();
             loop {
                 let token = self.scanner.next();
@@ -160,19 +249,19 @@ impl<'a, 'b> TestParser<'a, 'b> {
                         token_type: TokenType::Set,
                         ..
                     }) => {
-                        instructions.push(self.set());
+                        instructions.push(self.set()?);
                     }
                     Some(Token {
                         token_type: TokenType::Eval,
                         ..
                     }) => {
-                        instructions.push(self.eval());
+                        instructions.push(Instruction::Eval);
                     }
                     Some(Token {
                         token_type: TokenType::Output,
                         ..
                     }) => {
-                        instructions.push(self.output());
+                        instructions.push(Instruction::Output);
                     }
                     Some(Token {
                         token_type: TokenType::Tick,
@@ -186,57 +275,236 @@ impl<'a, 'b> TestParser<'a, 'b> {
                     }) => {
                         instructions.push(Instruction::Tock);
                     }
-                    _ => {
-                        panic!("Unknown instruction type {:?}.", token);
+                    Some(t) if t.token_type == TokenType::Identifier && t.lexeme == "at" => {
+                        instructions.push(self.at()?);
+                    }
+                    Some(t) => {
+                        return Err(N2VError {
+                            msg: format!("Unknown instruction `{}`.", t.lexeme),
+                            kind: ErrorKind::TestParseError(t),
+                        });
+                    }
+                    None => {
+                        return Err(self.unexpected(
+                            None,
+                            String::from("Early end of file, expected an instruction."),
+                        ));
                     }
                 }
-                if self.scanner.peek().unwrap().token_type == TokenType::Comma {
+                if self.scanner.peek().map(|t| t.token_type) == Some(TokenType::Comma) {
                     self.consume(TokenType::Comma)?;
                 } else {
                     self.consume(TokenType::Semicolon)?;
                     break;
                 }
             }
-            res.push(Step { instructions });
+            res.push(Step {
+                instructions,
+                line: first.line,
+            });
         }
 
         Ok(res)
     }
 
-    fn set(&mut self) -> Instruction {
-        let port = self.consume(TokenType::Identifier).unwrap().lexeme;
-        let format = self.scanner.peek().unwrap().token_type;
-        let number_system = match format {
-            TokenType::Number => NumberSystem::Decimal,
-            TokenType::BinaryFormatSpecifier => {
+    fn set(&mut self) -> Result<Instruction, N2VError> {
+        let port = self.consume(TokenType::Identifier)?.lexeme;
+        let next = self.scanner.peek();
+        let number_system = match next.as_ref().map(|t| t.token_type) {
+            Some(TokenType::Number) => NumberSystem::Decimal,
+            Some(TokenType::BinaryFormatSpecifier) => {
                 self.scanner.next();
                 NumberSystem::Binary
             }
-            TokenType::HexFormatSpecifier => {
+            Some(TokenType::HexFormatSpecifier) => {
                 self.scanner.next();
                 NumberSystem::Hex
             }
             _ => {
-                panic!("Unexpected format specifier.");
+                return Err(self.unexpected(
+                    next,
+                    format!("Expected a value for `{}`, such as 5 or %B101.", port),
+                ));
             }
         };
-        let value = self.consume(TokenType::Number).unwrap().lexeme;
+        let value = self.consume(TokenType::Number)?.lexeme;
 
-        Instruction::Set(
+        Ok(Instruction::Set(
             port,
             InputValue {
                 number_system,
                 value,
             },
-        )
+        ))
+    }
+
+    /// Key presses separated by semicolons, the way `whidl run` reads
+    /// them from a file.
+    pub fn key_presses(&mut self) -> Result<Vec<KeyPress>, N2VError> {
+        let presses = self.presses();
+        self.scanned(presses)
+    }
+
+    fn presses(&mut self) -> Result<Vec<KeyPress>, N2VError> {
+        let mut presses = Vec::new();
+        while self.scanner.peek().is_some() {
+            let at = self.consume(TokenType::Identifier)?;
+            if at.lexeme != "at" {
+                return Err(N2VError {
+                    msg: format!("Expected `at tick`, found `{}`.", at.lexeme),
+                    kind: ErrorKind::TestParseError(at),
+                });
+            }
+            self.consume(TokenType::Tick)?;
+            let tick = self.count()?;
+            self.keyword("press")?;
+            presses.push(self.key_press(tick)?);
+            self.consume(TokenType::Semicolon)?;
+        }
+        Ok(presses)
+    }
+
+    // The rest of a key press or screen check after `at`.
+    fn at(&mut self) -> Result<Instruction, N2VError> {
+        self.consume(TokenType::Tick)?;
+        let tick = self.count()?;
+        let t = self.consume(TokenType::Identifier)?;
+        match t.lexeme.as_str() {
+            "press" => Ok(Instruction::Press(self.key_press(tick)?)),
+            "expect" => Ok(Instruction::Screen(self.screen_check(tick)?)),
+            _ => Err(N2VError {
+                msg: format!("Expected `press` or `expect`, found `{}`.", t.lexeme),
+                kind: ErrorKind::TestParseError(t),
+            }),
+        }
+    }
+
+    // The rest of `at tick 1000 press 'A' for 50 ticks` after `press`. Keys
+    // are a quoted character, a name such as `newline`, or a key code.
+    fn key_press(&mut self, at: u64) -> Result<KeyPress, N2VError> {
+        let key = self.scanner.next();
+        let code = match &key {
+            Some(t) if t.token_type == TokenType::Character => {
+                t.lexeme.chars().next().map(|c| c as u32)
+            }
+            Some(t) if t.token_type == TokenType::Identifier => key_code(&t.lexeme).map(u32::from),
+            Some(t) if t.token_type == TokenType::Number => t.lexeme.parse().ok(),
+            _ => None,
+        };
+        let key = match (code, key) {
+            (Some(c @ 1..=0xFFFF), _) => c as u16,
+            (_, Some(t)) => {
+                return Err(N2VError {
+                    msg: format!(
+                        "`{}` is not a key. Use a character such as 'A', a name such as newline, or a key code.",
+                        t.lexeme
+                    ),
+                    kind: ErrorKind::TestParseError(t),
+                })
+            }
+            (_, None) => return Err(self.consume(TokenType::Character).unwrap_err()),
+        };
+        self.keyword("for")?;
+        let ticks = self.count()?;
+        if self.scanner.peek().map(|t| t.token_type) == Some(TokenType::Tick) {
+            self.scanner.next();
+        } else {
+            self.keyword("ticks")?;
+        }
+        Ok(KeyPress { at, key, ticks })
+    }
+
+    // The rest of `at tick 5000 expect region 0 0 16 8 is 1` after `expect`.
+    fn screen_check(&mut self, at: u64) -> Result<ScreenCheck, N2VError> {
+        let t = self.consume(TokenType::Identifier)?;
+        let region = match t.lexeme.as_str() {
+            "screen" => Region::screen(),
+            "pixel" => Region {
+                x: self.number("a column")? as usize,
+                y: self.number("a row")? as usize,
+                width: 1,
+                height: 1,
+            },
+            "region" => Region {
+                x: self.number("a column")? as usize,
+                y: self.number("a row")? as usize,
+                width: self.number("a width")? as usize,
+                height: self.number("a height")? as usize,
+            },
+            _ => {
+                return Err(N2VError {
+                    msg: format!(
+                        "Expected `screen`, `pixel`, or `region`, found `{}`.",
+                        t.lexeme
+                    ),
+                    kind: ErrorKind::TestParseError(t),
+                })
+            }
+        };
+        if region.width == 0
+            || region.height == 0
+            || region.x + region.width > SCREEN_WIDTH
+            || region.y + region.height > SCREEN_HEIGHT
+        {
+            return Err(N2VError {
+                msg: format!(
+                    "The {} is not on the {} by {} screen.",
+                    region, SCREEN_WIDTH, SCREEN_HEIGHT
+                ),
+                kind: ErrorKind::TestParseError(t),
+            });
+        }
+
+        let t = self.consume(TokenType::Identifier)?;
+        let expected = match t.lexeme.as_str() {
+            "is" => match self.number("0 or 1")? {
+                0 => ScreenValue::Fill(false),
+                1 => ScreenValue::Fill(true),
+                _ => {
+                    return Err(N2VError {
+                        msg: String::from("Pixels are 0 or 1."),
+                        kind: ErrorKind::TestParseError(t),
+                    })
+                }
+            },
+            "hash" => ScreenValue::Hash(self.number("a hash")?),
+            _ => {
+                return Err(N2VError {
+                    msg: format!("Expected `is` or `hash`, found `{}`.", t.lexeme),
+                    kind: ErrorKind::TestParseError(t),
+                })
+            }
+        };
+        Ok(ScreenCheck {
+            at,
+            region,
+            expected,
+        })
     }
 
-    fn eval(&mut self) -> Instruction {
-        Instruction::Eval
+    // Words that are only keywords in key presses and screen checks, so they
+    // can still be port names.
+    fn keyword(&mut self, word: &str) -> Result<(), N2VError> {
+        let t = self.consume(TokenType::Identifier)?;
+        if t.lexeme != word {
+            return Err(N2VError {
+                msg: format!("Expected `{}`, found `{}`.", word, t.lexeme),
+                kind: ErrorKind::TestParseError(t),
+            });
+        }
+        Ok(())
     }
 
-    fn output(&mut self) -> Instruction {
-        Instruction::Output
+    fn count(&mut self) -> Result<u64, N2VError> {
+        self.number("a number of ticks")
+    }
+
+    fn number(&mut self, what: &str) -> Result<u64, N2VError> {
+        let t = self.consume(TokenType::Number)?;
+        t.lexeme.parse().map_err(|_| N2VError {
+            msg: format!("Expected {}, found `{}`.", what, t.lexeme),
+            kind: ErrorKind::TestParseError(t),
+        })
     }
 
     fn output_list(&mut self) -> Result<Vec<OutputFormat>, N2VError> {
@@ -271,30 +539,21 @@ impl<'a, 'b> TestParser<'a, 'b> {
                             token_type: TokenType::StringFormatSpecifier,
                             ..
                         }) => NumberSystem::String,
-                        _ => {
-                            panic!()
+                        other => {
+                            return Err(self.unexpected(
+                                other,
+                                format!(
+                                    "Expected a format such as %B1.16.1 after `{}`.",
+                                    port_name.lexeme
+                                ),
+                            ));
                         }
                     };
-                    let space_before = self
-                        .consume(TokenType::Number)
-                        .unwrap()
-                        .lexeme
-                        .parse()
-                        .unwrap();
+                    let space_before = self.number("a column width")? as usize;
                     self.consume(TokenType::Dot)?;
-                    let output_columns = self
-                        .consume(TokenType::Number)
-                        .unwrap()
-                        .lexeme
-                        .parse()
-                        .unwrap();
+                    let output_columns = self.number("a column width")? as usize;
                     self.consume(TokenType::Dot)?;
-                    let space_after = self
-                        .consume(TokenType::Number)
-                        .unwrap()
-                        .lexeme
-                        .parse()
-                        .unwrap();
+                    let space_after = self.number("a column width")? as usize;
 
                     res.push(OutputFormat {
                         port_name: port_name.lexeme.clone(),
@@ -311,7 +570,10 @@ impl<'a, 'b> TestParser<'a, 'b> {
                     break;
                 }
                 _ => {
-                    panic!("Expected format specifier.")
+                    return Err(self.unexpected(
+                        next,
+                        String::from("Expected a port in the output list or a semicolon."),
+                    ));
                 }
             }
         }
@@ -321,7 +583,7 @@ impl<'a, 'b> TestParser<'a, 'b> {
     fn generics(&mut self) -> Result<Vec<usize>, N2VError> {
         let mut res = Vec::new();
 
-        if self.scanner.peek().unwrap().token_type != TokenType::LeftAngle {
+        if self.scanner.peek().map(|t| t.token_type) != Some(TokenType::LeftAngle) {
             return Ok(Vec::new());
         }
         self.consume(TokenType::LeftAngle)?;
@@ -335,8 +597,10 @@ impl<'a, 'b> TestParser<'a, 'b> {
                         ..
                     },
                 ) => {
-                    // Convert to number.
-                    let val: usize = t.lexeme.parse().unwrap();
+                    let val = t.lexeme.parse().map_err(|_| N2VError {
+                        msg: format!("Expected a generic argument, found `{}`.", t.lexeme),
+                        kind: ErrorKind::TestParseError(t.clone()),
+                    })?;
                     res.push(val);
                 }
                 Some(Token {
@@ -352,7 +616,10 @@ impl<'a, 'b> TestParser<'a, 'b> {
                     return Ok(res);
                 }
                 _ => {
-                    panic!("Expected number, comma, or right angle, found {:?}", next);
+                    return Err(self.unexpected(
+                        next,
+                        String::from("Expected a number, comma, or `>` in the generic arguments."),
+                    ));
                 }
             }
         }
@@ -395,4 +662,112 @@ mod test {
         };
         parser.parse().expect("Parse failure");
     }
+
+    #[test]
+    fn test_key_presses() {
+        let mut scanner = TestScanner::new(
+            "at tick 10 press 'a' for 5 ticks; at tick 20 press f2 for 1 tick;",
+            PathBuf::from("keys.txt"),
+        );
+        let mut parser = TestParser {
+            scanner: &mut scanner,
+        };
+        let presses = parser.key_presses().expect("Parse failure");
+        assert_eq!(
+            presses,
+            vec![
+                KeyPress {
+                    at: 10,
+                    key: 97,
+                    ticks: 5
+                },
+                KeyPress {
+                    at: 20,
+                    key: 142,
+                    ticks: 1
+                }
+            ]
+        );
+
+        let mut scanner = TestScanner::new("at tick 1 press enter for 1 tick;", PathBuf::new());
+        let mut parser = TestParser {
+            scanner: &mut scanner,
+        };
+        let e = parser.key_presses().err().unwrap();
+        assert!(e.msg.starts_with("`enter` is not a key."));
+    }
+
+    #[test]
+    fn test_screen_checks() {
+        let check = |source: &str| {
+            let mut scanner = TestScanner::new(source, PathBuf::from("screen.tst"));
+            let mut parser = TestParser {
+                scanner: &mut scanner,
+            };
+            match parser.at() {
+                Ok(Instruction::Screen(c)) => Ok(c),
+                Ok(_) => panic!("Expected a screen check"),
+                Err(e) => Err(e.msg),
+            }
+        };
+        assert_eq!(
+            check("tick 100 expect pixel 10 20 is 1"),
+            Ok(ScreenCheck {
+                at: 100,
+                region: Region {
+                    x: 10,
+                    y: 20,
+                    width: 1,
+                    height: 1
+                },
+                expected: ScreenValue::Fill(true)
+            })
+        );
+        let c = check("tick 5 expect screen hash 12345").unwrap();
+        assert_eq!(c.region, Region::screen());
+        assert_eq!(c.expected, ScreenValue::Hash(12345));
+        assert_eq!(
+            check("tick 5 expect region 500 0 16 1 is 0"),
+            Err(String::from(
+                "The region 500 0 16 1 is not on the 512 by 256 screen."
+            ))
+        );
+        assert_eq!(
+            check("tick 5 expect pixel 0 0 is 2"),
+            Err(String::from("Pixels are 0 or 1."))
+        );
+    }
+
+    #[test]
+    fn test_malformed_scripts() {
+        let header = "load And.hdl, output-file And.out, compare-to And.cmp, output-list a%B3.1.3;";
+        for (script, message) in [
+            ("lod And.hdl,", "Expected `load`, found `lod`."),
+            ("load And.hdl, output-file", "Early end of file"),
+            (
+                "load And.hdl, output-file And.out, compare-to And.cmp, output-list a%Q3.1.3;",
+                "`%Q` is not a format.",
+            ),
+            (
+                "load And.hdl, output-file And.out, compare-to And.cmp, output-list a 3.1.3;",
+                "Expected a format such as %B1.16.1 after `a`.",
+            ),
+            ("load<4, x> And.hdl,", "in the generic arguments"),
+            (
+                &format!("{} set a, eval;", header),
+                "Expected a value for `a`",
+            ),
+            (&format!("{} set a 1 # eval;", header), "the character `#`"),
+            (&format!("{} /* set a 1;", header), "never closed"),
+        ] {
+            let mut scanner = TestScanner::new(script, PathBuf::from("And.tst"));
+            let mut parser = TestParser {
+                scanner: &mut scanner,
+            };
+            match parser.parse() {
+                Ok(_) => panic!("Expected an error for {}", script),
+                Err(e) => assert!(e.msg.contains(message), "{}: {}", script, e.msg),
+            }
+        }
+    }
 }
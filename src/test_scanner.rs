@@ -1,3 +1,5 @@
+use crate::error::{ErrorKind, N2VError};
+use crate::scanner::{is_name_continue, is_name_start};
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::str::Chars;
@@ -23,7 +25,9 @@ pub enum TokenType {
     Eval,
     LeftAngle,
     RightAngle,
+    Character,
     Eof,
+    Invalid, // Text that is not a token. The scanner records why in `error`.
 }
 
 #[derive(Clone, Debug)]
@@ -40,6 +44,8 @@ pub struct TestScanner<'a> {
     keywords: HashMap<&'a str, TokenType>,
     peeked: Option<Token>,
     pub path: PathBuf,
+    /// The first text that is not a token, if the scanner has seen any.
+    pub error: Option<N2VError>,
 }
 
 impl<'a> TestScanner<'a> {
@@ -63,6 +69,7 @@ impl<'a> TestScanner<'a> {
             keywords,
             peeked: None,
             path: source_path,
+            error: None,
         }
     }
 
@@ -122,19 +129,19 @@ impl<'a> TestScanner<'a> {
                         path: self.path.clone(),
                     }),
                     '%' => {
-                        let followup: char = match self.source_chars.peek() {
-                            None => panic!(),
-                            Some(c) => *c,
-                        };
+                        let followup = self.source_chars.next();
+                        let lexeme: String = std::iter::once('%').chain(followup).collect();
                         let token_type = match followup {
-                            'B' => TokenType::BinaryFormatSpecifier,
-                            'D' => TokenType::DecimalFormatSpecifier,
-                            'X' => TokenType::HexFormatSpecifier,
-                            'S' => TokenType::StringFormatSpecifier,
-                            _ => panic!(),
+                            Some('B') => TokenType::BinaryFormatSpecifier,
+                            Some('D') => TokenType::DecimalFormatSpecifier,
+                            Some('X') => TokenType::HexFormatSpecifier,
+                            Some('S') => TokenType::StringFormatSpecifier,
+                            _ => {
+                                let msg =
+                                    format!("`{}` is not a format. Use %B, %D, %X, or %S.", lexeme);
+                                return Some(self.invalid(lexeme, msg));
+                            }
                         };
-                        self.source_chars.next();
-                        let lexeme = String::from("%") + &followup.to_string();
                         Some(Token {
                             token_type,
                             lexeme,
@@ -142,33 +149,27 @@ impl<'a> TestScanner<'a> {
                             path: self.path.clone(),
                         })
                     }
+                    '\'' => Some(self.finish_character()),
                     '\n' => {
                         self.line += 1;
                         None
                     }
                     ' ' | '\t' | '\r' => None,
-                    '/' => {
-                        let followup: char = match self.source_chars.peek() {
-                            None => panic!(),
-                            Some(c2) => *c2,
-                        };
-
-                        if followup == '/' {
+                    '/' => match self.source_chars.peek() {
+                        Some('/') => {
                             self.finish_single_comment();
-                        } else if followup == '*' {
-                            self.finish_multi_comment();
-                        } else {
-                            panic!();
+                            None
                         }
-                        None
-                    }
+                        Some('*') => self.finish_multi_comment(),
+                        _ => Some(self.unexpected_character(c)),
+                    },
                     _ => {
-                        if c.is_alphabetic() {
+                        if is_name_start(c) {
                             Some(self.finish_identifier(c))
-                        } else if c.is_numeric() || c == '-' {
+                        } else if c.is_ascii_digit() || c == '-' {
                             Some(self.finish_number(c))
                         } else {
-                            panic!("Unexpected character: {}", c)
+                            Some(self.unexpected_character(c))
                         }
                     }
                 },
@@ -191,33 +192,81 @@ impl<'a> TestScanner<'a> {
         }
     }
 
-    fn finish_multi_comment(&mut self) {
+    // Skips a comment after its `/`. A comment that is never closed is
+    // an `Invalid` token at the line it starts on.
+    fn finish_multi_comment(&mut self) -> Option<Token> {
+        let line = self.line;
         loop {
             let next = self.source_chars.next();
 
             match next {
-                None => panic!(),
+                None => {
+                    let t = self.invalid(
+                        String::from("/*"),
+                        String::from("This comment is never closed with `*/`."),
+                    );
+                    return Some(Token { line, ..t });
+                }
                 Some('\n') => {
                     self.line += 1;
                 }
-                Some('*') => match self.source_chars.peek() {
-                    None => panic!("Unterminated comment."),
-                    Some('/') => {
-                        self.source_chars.next();
-                        break;
-                    }
-                    _ => {}
-                },
+                Some('*') if self.source_chars.peek() == Some(&'/') => {
+                    self.source_chars.next();
+                    return None;
+                }
                 _ => {}
             }
         }
     }
 
+    // A quoted character such as `'A'`. The lexeme is the character
+    // without its quotes.
+    fn finish_character(&mut self) -> Token {
+        let lexeme: String = self.source_chars.next().into_iter().collect();
+        if self.source_chars.next() != Some('\'') {
+            return self.invalid(
+                format!("'{}", lexeme),
+                String::from("Expected a single character between quotes."),
+            );
+        }
+        Token {
+            token_type: TokenType::Character,
+            lexeme,
+            line: self.line,
+            path: self.path.clone(),
+        }
+    }
+
+    fn unexpected_character(&mut self, c: char) -> Token {
+        self.invalid(
+            c.to_string(),
+            format!("I did not expect the character `{}` here.", c),
+        )
+    }
+
+    // An `Invalid` token for `lexeme`, recording `msg` as the error if it
+    // is the first.
+    fn invalid(&mut self, lexeme: String, msg: String) -> Token {
+        let t = Token {
+            token_type: TokenType::Invalid,
+            lexeme,
+            line: self.line,
+            path: self.path.clone(),
+        };
+        if self.error.is_none() {
+            self.error = Some(N2VError {
+                msg,
+                kind: ErrorKind::TestParseError(t.clone()),
+            });
+        }
+        t
+    }
+
     fn finish_number(&mut self, start: char) -> Token {
         let mut lexeme = start.to_string();
 
         while let Some(c) = self.source_chars.peek() {
-            if c.is_numeric() {
+            if c.is_ascii_digit() {
                 lexeme.push(*c);
                 self.source_chars.next();
             } else {
@@ -237,7 +286,7 @@ impl<'a> TestScanner<'a> {
         let mut lexeme = start.to_string();
 
         while let Some(c) = self.source_chars.peek() {
-            if c.is_alphanumeric() || c == &'-' || c == &'.' {
+            if is_name_continue(*c) || c == &'-' || c == &'.' {
                 lexeme.push(*c);
                 self.source_chars.next();
             } else {
@@ -381,4 +430,14 @@ mod test {
 
         assert_eq!(expected_types, actual_types);
     }
+
+    #[test]
+    fn test_unicode_names() {
+        let scanner = TestScanner::new("set 入力_a 1, output-file Größe.out;", PathBuf::from(""));
+        let lexemes: Vec<String> = scanner.map(|t| t.lexeme).collect();
+        assert_eq!(
+            lexemes,
+            vec!["set", "入力_a", "1", ",", "output-file", "Größe.out", ";"]
+        );
+    }
 }
@@ -1,75 +1,101 @@
+use crate::builtin::pixel;
 use crate::busmap::BusMap;
-use crate::error::{ErrorKind, N2VError};
+use crate::elaborate::{report_warnings, Warning};
+use crate::engine::{self, SimOptions};
+use crate::error::{ErrorKind, N2VError, WhidlError};
+use crate::lint::Lint;
+use crate::logic::Logic;
+use crate::monitor::Progress;
 use crate::parser::*;
 use crate::scanner::Scanner;
-use crate::simulator::{Bus, Chip, Port, Simulator};
+use crate::simulator::{Bus, Chip, ChipOptions, Port, Simulator};
 use crate::test_parser::*;
 /// For dealing with nand2tetris tests
 use crate::test_scanner::TestScanner;
 use bitvec::prelude::*;
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fs;
-use std::io::{prelude::*, BufReader};
-use std::path::PathBuf;
-use std::ptr;
-use std::rc::Rc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::{debug, debug_span};
 
-fn test_input_to_bitvec(input: &InputValue) -> BitVec<u16, Msb0> {
+// The bits of a value in a test script or .cmp file, highest first. A binary
+// value may have an x for a bit that is not known and a z for one nothing
+// drives, such as the output of a disabled tri-state buffer.
+fn test_input_to_logic(input: &InputValue) -> Result<Vec<Logic>, N2VError> {
+    let error = |msg: String| N2VError {
+        msg,
+        kind: ErrorKind::Other,
+    };
     match input.number_system {
         NumberSystem::Decimal => {
-            let num: i16 = input.value.parse().unwrap();
+            let num: i16 = input.value.parse().map_err(|_| {
+                error(format!(
+                    "`{}` is not a decimal number from -32768 to 32767.",
+                    input.value
+                ))
+            })?;
             let mut raw = [0u16; 1];
             raw.view_bits_mut::<Msb0>().store_le(num);
             let bits = raw.view_bits::<Msb0>();
-            bits.to_bitvec()
+            Ok(bits.iter().map(|b| Logic::from(*b)).collect())
         }
         NumberSystem::Binary => {
-            let bools: Vec<bool> = input
+            input
                 .value
                 .chars()
                 .map(|c| match c {
-                    '0' => false,
-                    '1' => true,
-                    _ => {
-                        panic!("expected 0 or 1");
-                    }
+                    '0' => Ok(Logic::Zero),
+                    '1' => Ok(Logic::One),
+                    'x' | 'X' => Ok(Logic::X),
+                    'z' | 'Z' => Ok(Logic::Z),
+                    _ => Err(error(format!(
+                        "`{}` is not a binary number of 0s and 1s, with x for an unknown bit and z for a floating one.",
+                        input.value
+                    ))),
                 })
-                .collect();
-            BitVec::from_iter(bools)
-        }
-        NumberSystem::Hex => {
-            panic!("hex number system not supported yet.");
-        }
-        NumberSystem::String => {
-            // this isn't a bit vector - what to do?
-            panic!("string number system not supported yet.");
+                .collect()
         }
+        NumberSystem::Hex => Err(error(format!(
+            "`{}` is a hex value, which test scripts do not support yet.",
+            input.value
+        ))),
+        // this isn't a bit vector - what to do?
+        NumberSystem::String => Err(error(format!(
+            "`{}` is a string value, which test scripts do not support yet.",
+            input.value
+        ))),
     }
 }
 
-fn bitvec_to_vecbool(bv: BitVec<u16, Msb0>) -> Vec<Option<bool>> {
-    let mut res = Vec::new();
-    for bit in bv {
-        res.push(Some(bit));
-    }
-    res
-}
-
 /// Reads a nand2tetris cmp file and returns a busmap of values
 fn read_cmp(
     path: &PathBuf,
     test_script: &TestScript,
     ports: &HashMap<String, Port>,
 ) -> Result<Vec<BusMap>, N2VError> {
-    let mut res: Vec<BusMap> = Vec::new();
-    let file = fs::File::open(path).unwrap_or_else(|_| panic!("No such cmp file {:?}", path));
-    let buf = BufReader::new(file);
+    let source = fs::read_to_string(path).map_err(|e| N2VError {
+        msg: format!("No such cmp file {:?}. {}", path, e),
+        kind: ErrorKind::IOError,
+    })?;
+    parse_cmp(&source, path, test_script, ports)
+}
 
-    let mut lines = buf.lines();
+// The expected outputs in `source`, the text of the .cmp file at `path`.
+fn parse_cmp(
+    source: &str,
+    path: &PathBuf,
+    test_script: &TestScript,
+    ports: &HashMap<String, Port>,
+) -> Result<Vec<BusMap>, N2VError> {
+    let mut res: Vec<BusMap> = Vec::new();
+    let mut lines = source.lines();
 
     // Read header line and determine order of ports
-    let mut header = lines.next().unwrap().expect("Corrupted cmp file");
+    let mut header = String::from(lines.next().unwrap_or_default());
     header.retain(|c| !c.is_whitespace());
 
     // We need at least three characters for a valid header line:
@@ -86,12 +112,12 @@ fn read_cmp(
         .map(|p| p.to_string())
         .collect();
 
-    while let Some(Ok(l)) = lines.next() {
+    for l in lines {
         if l.is_empty() {
             continue;
         }
         let mut step_result = BusMap::new();
-        let mut line = l.clone();
+        let mut line = String::from(l);
         line.retain(|c| !c.is_whitespace());
 
         if line.len() < 3 {
@@ -123,12 +149,10 @@ fn read_cmp(
                 continue;
             }
 
-            let bitvec_value = test_input_to_bitvec(&InputValue {
+            let mut value = test_input_to_logic(&InputValue {
                 number_system: number_system.clone(),
                 value: v.to_string(),
-            });
-
-            let mut value = bitvec_to_vecbool(bitvec_value);
+            })?;
             value.reverse();
 
             if i >= port_order.len() {
@@ -153,12 +177,8 @@ fn read_cmp(
 
             value.truncate(portw.width);
             value.reverse();
-            step_result.create_bus(&port_order[i], value.len()).unwrap();
-            let bus = Bus {
-                name: port_order[i].clone(),
-                range: Some(0..value.len()),
-            };
-            step_result.insert_option(&bus, value);
+            step_result.create_bus(&port_order[i], value.len())?;
+            step_result.insert_logic(&port_order[i], &value);
         }
         res.push(step_result);
     }
@@ -166,132 +186,513 @@ fn read_cmp(
     Ok(res)
 }
 
-pub fn run_test(test_script_path: &str) -> Result<(), Box<dyn Error>> {
-    // Parse the test script
+/// A parsed test script along with the chip it tests and the expected
+/// outputs from its .cmp file. Used by the testbench generators.
+pub struct TestVectors {
+    pub hdl: ChipHDL,
+    pub ports: HashMap<String, Port>,
+    pub script: TestScript,
+    pub expected: Vec<BusMap>,
+    pub provider: Arc<dyn HdlProvider>, // Loads the chip's components.
+}
+
+/// Reads a test script and the HDL and .cmp files it references, which are
+/// resolved relative to the test script. The chip's parts are looked for
+/// through `lib_path` too, as `chip_provider` does.
+pub fn load_test_vectors(
+    test_script_path: &str,
+    lib_path: &[String],
+) -> Result<TestVectors, Box<dyn Error>> {
     let test_pathbuf = PathBuf::from(test_script_path);
     let test_contents = read_test(&test_pathbuf)?;
-    let mut test_scanner = TestScanner::new(test_contents.as_str(), test_pathbuf.clone());
+    let script = parse_script(&test_contents, &test_pathbuf)?;
+    let dir = test_pathbuf.parent().unwrap_or(&test_pathbuf);
+    let hdl_path = dir.join(&script.hdl_file);
+
+    let (base_path, hdl_file) = match (
+        hdl_path.parent().and_then(|p| p.to_str()),
+        hdl_path.file_name().and_then(|f| f.to_str()),
+    ) {
+        (Some(base_path), Some(hdl_file)) => (base_path, hdl_file),
+        _ => {
+            return Err(Box::new(N2VError {
+                msg: format!("{} is not the path of an HDL file.", hdl_path.display()),
+                kind: ErrorKind::IOError,
+            }))
+        }
+    };
+    let provider: Arc<dyn HdlProvider> =
+        Arc::new(CachingProvider::new(chip_provider(base_path, lib_path)));
+    let compare_path = dir.join(&script.compare_file);
+    test_vectors(script, provider, hdl_file, |script, ports| {
+        read_cmp(&compare_path, script, ports)
+    })
+}
+
+/// Loads a test script that `provider` finds, such as one in a `ZipReader`,
+/// along with its chip and .cmp file, which `provider` must find too.
+pub fn load_test_vectors_from(
+    test_file: &str,
+    provider: Arc<dyn HdlProvider>,
+) -> Result<TestVectors, Box<dyn Error>> {
+    let script = parse_script(&provider.get_hdl(test_file)?, &provider.get_path(test_file))?;
+    let name = |path: &PathBuf| path.to_string_lossy().into_owned();
+    let (hdl_file, compare_file) = (name(&script.hdl_file), name(&script.compare_file));
+    let compare = provider.get_hdl(&compare_file)?;
+    let compare_path = provider.get_path(&compare_file);
+    test_vectors(script, provider, &hdl_file, |script, ports| {
+        parse_cmp(&compare, &compare_path, script, ports)
+    })
+}
+
+fn parse_script(source: &str, path: &Path) -> Result<TestScript, Box<dyn Error>> {
+    let mut test_scanner = TestScanner::new(source, path.to_path_buf());
     let mut test_parser = TestParser {
         scanner: &mut test_scanner,
     };
-    let test_script = test_parser.parse().expect("Parse failure");
-    let hdl_path = test_pathbuf.parent().unwrap().join(&test_script.hdl_file);
-
-    // Create simulator for HDL file referenced by test script.
-    let base_path = hdl_path.parent().unwrap().to_str().unwrap();
-    let hdl_file = hdl_path.file_name().unwrap().to_str().unwrap();
-    let provider: Rc<dyn HdlProvider> = Rc::new(FileReader::new(base_path));
-    let contents = provider.get_hdl(hdl_file).unwrap();
+    Ok(test_parser.parse()?)
+}
+
+// The chip `hdl_file` that `provider` finds, and the outputs `expected`
+// reads for its ports.
+fn test_vectors(
+    script: TestScript,
+    provider: Arc<dyn HdlProvider>,
+    hdl_file: &str,
+    expected: impl FnOnce(&TestScript, &HashMap<String, Port>) -> Result<Vec<BusMap>, N2VError>,
+) -> Result<TestVectors, Box<dyn Error>> {
+    let contents = provider.get_hdl(hdl_file)?;
     let mut scanner = Scanner::new(contents.as_str(), provider.get_path(hdl_file));
     let mut parser = Parser {
         scanner: &mut scanner,
     };
-    let hdl = match parser.parse() {
-        Ok(x) => x,
-        Err(x) => {
-            println!("{}", x);
-            std::process::exit(1);
-        }
-    };
+    let hdl = parser.parse()?;
 
-    let chip = match Chip::new(
+    // Only used to resolve port widths.
+    let chip = Chip::new(
         &hdl,
-        ptr::null_mut(),
         &provider,
-        false,
-        &test_script.generics,
-    ) {
-        Ok(x) => x,
-        Err(x) => {
-            println!("{}", x);
-            std::process::exit(1);
+        ChipOptions {
+            generics: script.generics.clone(),
+            ..ChipOptions::default()
+        },
+    )?;
+
+    let expected = expected(&script, &chip.ports)?;
+
+    Ok(TestVectors {
+        hdl,
+        ports: chip.ports,
+        script,
+        expected,
+        provider,
+    })
+}
+
+/// Bits for a `set` instruction, truncated to the width of the port.
+pub fn input_bits(value: &InputValue, width: usize) -> Result<Vec<Option<bool>>, N2VError> {
+    let mut bits = test_input_to_logic(value)?;
+    bits.reverse();
+    bits.truncate(width);
+    Ok(bits.into_iter().rev().map(Option::from).collect())
+}
+
+/// Whether `value` has more bits than a port `width` bits wide holds. Cut
+/// off bits that are zeros fit, and so do ones that repeat the sign of a
+/// negative decimal.
+pub fn truncates(value: &InputValue, width: usize) -> Result<bool, N2VError> {
+    let bits = test_input_to_logic(value)?;
+    if bits.len() <= width {
+        return Ok(false);
+    }
+    let (cut, kept) = bits.split_at(bits.len() - width);
+    let all = |bit| cut.iter().all(|b| *b == bit);
+    let negative =
+        value.number_system == NumberSystem::Decimal && kept.first() == Some(&Logic::One);
+    Ok(!(all(Logic::Zero) || negative && all(Logic::One)))
+}
+
+/// The key held down in clock cycle `cycle`, or 0 if none is. Later
+/// presses win when they overlap.
+pub fn pressed_key(presses: &[KeyPress], cycle: u64) -> u16 {
+    presses
+        .iter()
+        .rev()
+        .find(|p| p.at <= cycle && cycle - p.at < p.ticks)
+        .map_or(0, |p| p.key)
+}
+
+/// Key presses and screen checks use the simulated keyboard and screen,
+/// which testbenches for other simulators do not have.
+pub fn device_error(target: &str, device: &str) -> Box<dyn Error> {
+    Box::new(N2VError {
+        msg: format!(
+            "Test scripts that use the {} cannot be translated to {}, the {} only exists in the whidl simulator.",
+            device, target, device
+        ),
+        kind: ErrorKind::Other,
+    })
+}
+
+/// FNV-1a hash of the pixels in a region of the screen, row by row, so
+/// whole images can be checked without listing every pixel.
+pub fn screen_hash(screen: &[u64], region: &Region) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for y in region.y..region.y + region.height {
+        for x in region.x..region.x + region.width {
+            hash ^= pixel(screen, x, y) as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
         }
+    }
+    hash
+}
+
+/// Checks the screen of a chip, returning why it fails if it does.
+pub fn check_screen(screen: Option<&[u64]>, check: &ScreenCheck) -> Option<String> {
+    let region = &check.region;
+    let screen = match screen {
+        Some(s) => s,
+        None => return Some(format!("Tick {}: the chip has no screen.", check.at)),
     };
+    match check.expected {
+        ScreenValue::Fill(on) => {
+            let wrong = (region.y..region.y + region.height)
+                .flat_map(|y| (region.x..region.x + region.width).map(move |x| (x, y)))
+                .filter(|(x, y)| pixel(screen, *x, *y) != on)
+                .count();
+            (wrong > 0).then(|| {
+                format!(
+                    "Tick {}: expected {} to be {}, but {} of its {} pixels are not.",
+                    check.at,
+                    region,
+                    on as u8,
+                    wrong,
+                    region.width * region.height
+                )
+            })
+        }
+        ScreenValue::Hash(expected) => {
+            let hash = screen_hash(screen, region);
+            (hash != expected).then(|| {
+                format!(
+                    "Tick {}: expected {} to hash to {}, found {}.",
+                    check.at, region, expected, hash
+                )
+            })
+        }
+    }
+}
 
-    let mut simulator = Simulator::new(chip);
+/// Outcome of a test run. Serialized as the JSON output of `whidl test`,
+/// so renaming fields is a breaking change.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TestStatus {
+    Passed,
+    Failed,
+    Cancelled,
+}
 
-    let hdl_contents = fs::read_to_string(hdl_path.clone()).expect("Unable to read HDL file.");
-    let mut scanner = Scanner::new(hdl_contents.as_str(), hdl_path);
-    let mut parser = Parser {
-        scanner: &mut scanner,
-    };
-    let hdl = parser.parse().expect("Parse error");
-    let chip = Chip::new(
-        &hdl,
-        ptr::null_mut(),
-        &provider,
-        false,
-        &test_script.generics,
+/// Signal values after one step of a test script. Values are written most
+/// significant bit first, with `?` for unknown bits.
+#[derive(Serialize, Clone, Debug)]
+pub struct StepResult {
+    pub step: usize, // Numbered from 1.
+    pub line: u32,
+    pub passed: bool,
+    pub inputs: BTreeMap<String, String>,
+    pub outputs: BTreeMap<String, String>,
+    pub expected: BTreeMap<String, String>, // Empty if the step has no `output`.
+    pub violations: Vec<String>, // From monitor chips and screen checks. These fail the step.
+}
+
+/// Results of running a test script, step by step.
+#[derive(Serialize, Clone, Debug)]
+pub struct TestReport {
+    pub test: PathBuf,
+    pub chip: String,
+    pub status: TestStatus,
+    pub failures: usize,
+    pub steps: Vec<StepResult>,
+}
+
+/// Runs a test script and records the result of every step.
+///
+/// `last_step` - Stop after this step, numbered from 1, to inspect the
+/// signals at that point. Runs every step if `None`.
+/// `cancel` - Checked before each step. Once set, the run stops and the
+/// report has status `Cancelled`.
+pub fn run_test_report(
+    test_script_path: &str,
+    last_step: Option<usize>,
+    cancel: &AtomicBool,
+) -> Result<TestReport, Box<dyn Error>> {
+    run_test_progress(
+        test_script_path,
+        last_step,
+        cancel,
+        &Progress::default(),
+        &TestOptions::default(),
     )
-    .expect("Chip creation error");
+}
 
-    let ports = chip.ports;
-    let compare_path = test_pathbuf
-        .parent()
-        .unwrap()
-        .join(&test_script.compare_file);
-    let expected = read_cmp(&compare_path, &test_script, &ports)?;
+/// How a test script builds and runs its chip.
+#[derive(Clone, Debug, Default)]
+pub struct TestOptions {
+    /// Options for the chip under test. Its generic arguments come from the
+    /// script instead.
+    pub chip: ChipOptions,
+    /// Directories the chip's parts are looked for in after its own, as
+    /// `chip_provider` takes them.
+    pub lib_path: Vec<String>,
+    /// How the chip is simulated.
+    pub sim: SimOptions,
+}
 
+/// Runs a test script like `run_test_report`, publishing the step it is on
+/// and the simulator's counters to `progress` as it goes.
+pub fn run_test_progress(
+    test_script_path: &str,
+    last_step: Option<usize>,
+    cancel: &AtomicBool,
+    progress: &Progress,
+    options: &TestOptions,
+) -> Result<TestReport, Box<dyn Error>> {
+    let vectors = load_test_vectors(test_script_path, &options.lib_path)?;
+    run_vectors(
+        vectors,
+        Path::new(test_script_path),
+        last_step,
+        cancel,
+        progress,
+        options,
+    )
+}
+
+/// Runs a test script loaded by `load_test_vectors_from`, like
+/// `run_test_progress`. `test_script_path` names it in the report.
+pub fn run_vectors(
+    vectors: TestVectors,
+    test_script_path: &Path,
+    last_step: Option<usize>,
+    cancel: &AtomicBool,
+    progress: &Progress,
+    options: &TestOptions,
+) -> Result<TestReport, Box<dyn Error>> {
+    let _span = debug_span!("test", script = %test_script_path.display()).entered();
+    let chip = Chip::new(
+        &vectors.hdl,
+        &vectors.provider,
+        ChipOptions {
+            generics: vectors.script.generics.clone(),
+            ..options.chip.clone()
+        },
+    )?;
+    let mut simulator = engine::simulator(chip, &options.sim)?;
+
+    let mut report = TestReport {
+        test: test_script_path.to_path_buf(),
+        chip: vectors.hdl.name.clone(),
+        status: TestStatus::Passed,
+        failures: 0,
+        steps: Vec::new(),
+    };
     let mut inputs = BusMap::new();
     let mut cmp_idx = 0;
-    let mut failures = 0;
-    for step in &test_script.steps {
+    let mut cycle = 0;
+    let mut presses = Vec::new();
+    let mut checks: Vec<ScreenCheck> = Vec::new(); // Screen checks for later ticks.
+    progress
+        .steps
+        .store(vectors.script.steps.len(), Ordering::Relaxed);
+    for (i, step) in vectors.script.steps.iter().enumerate() {
+        if last_step.is_some_and(|l| i >= l) {
+            break;
+        }
+        if cancel.load(Ordering::Relaxed) {
+            report.status = TestStatus::Cancelled;
+            break;
+        }
+        let _span = debug_span!("test_step", step = i + 1, line = step.line).entered();
+
         let mut outputs = BusMap::new();
+        let mut result = StepResult {
+            step: i + 1,
+            line: step.line,
+            passed: true,
+            inputs: BTreeMap::new(),
+            outputs: BTreeMap::new(),
+            expected: BTreeMap::new(),
+            violations: Vec::new(),
+        };
         for instruction in &step.instructions {
             match instruction {
                 Instruction::Set(port, value) => {
-                    let width = ports
-                        .get(port)
-                        .unwrap_or_else(|| panic!("No width for port {}", port))
-                        .width;
-                    let mut bool_values = bitvec_to_vecbool(test_input_to_bitvec(value));
-                    bool_values.reverse();
-                    bool_values.truncate(width);
-                    bool_values.reverse();
-                    inputs.create_bus(port, bool_values.len()).unwrap();
-                    inputs.insert_option(&Bus::from(port.clone()), bool_values);
+                    let width = match vectors.ports.get(port) {
+                        Some(p) => p.width,
+                        None => {
+                            return Err(Box::new(N2VError {
+                                msg: format!(
+                                    "Test script sets port {} which the chip does not have.",
+                                    port
+                                ),
+                                kind: ErrorKind::Other,
+                            }));
+                        }
+                    };
+                    if truncates(value, width)? {
+                        let warning = Warning {
+                            ident: crate::parser::Identifier {
+                                value: port.clone(),
+                                path: Some(test_script_path.to_path_buf()),
+                                line: Some(step.line),
+                                span: None,
+                            },
+                            msg: format!(
+                                "{} does not fit port {}, which is {} bits wide, so only its low bits are set.",
+                                value.value, port, width
+                            ),
+                            lint: Lint::TruncatedConstant,
+                        };
+                        report_warnings(vec![warning], &vectors.provider, &options.chip)?;
+                    }
+                    let bits = input_bits(value, width)?;
+                    inputs.create_bus(port, bits.len())?;
+                    inputs.insert_option(&Bus::from(port.clone()), bits);
+                }
+                Instruction::Eval | Instruction::Tick => {
+                    simulator.press_key(pressed_key(&presses, cycle));
+                    outputs = simulator.simulate(&inputs)?;
+                    run_screen_checks(&mut checks, cycle, &simulator, &mut result.violations);
                 }
-                Instruction::Eval => {
+                Instruction::Tock => {
+                    simulator.tick()?;
+                    cycle += 1;
+                    simulator.press_key(pressed_key(&presses, cycle));
                     outputs = simulator.simulate(&inputs)?;
-                    print!(".");
+                    run_screen_checks(&mut checks, cycle, &simulator, &mut result.violations);
                 }
+                Instruction::Press(p) => presses.push(p.clone()),
+                Instruction::Screen(c) if c.at >= cycle => checks.push(c.clone()),
+                Instruction::Screen(c) => result.violations.push(format!(
+                    "Tick {}: the screen check comes after tick {}.",
+                    c.at, cycle
+                )),
                 Instruction::Output => {
-                    if !(expected[cmp_idx] <= outputs.clone()) {
-                        println!("❌ Step: {}", cmp_idx + 1);
-                        println!("Expected: {}", expected[cmp_idx]);
-                        println!("Actual: {}", outputs);
-                        println!();
-                        failures += 1;
-                    }
+                    let expected = match vectors.expected.get(cmp_idx) {
+                        Some(e) => e,
+                        None => {
+                            return Err(Box::new(N2VError {
+                                msg: String::from(
+                                    "Test script has more outputs than the .cmp file.",
+                                ),
+                                kind: ErrorKind::Other,
+                            }));
+                        }
+                    };
+                    // Outputs may include signals the .cmp file leaves out.
+                    result.passed &= expected.satisfied_by(&outputs);
+                    result.expected = bit_strings(expected);
                     cmp_idx += 1;
                 }
-                Instruction::Tick => {
-                    outputs = simulator.simulate(&inputs).expect("simulation failure");
-                }
-                Instruction::Tock => {
-                    simulator.tick().expect("Tick failure");
-                    outputs = simulator.simulate(&inputs).expect("simulation failure");
-                }
             }
         }
+        result.inputs = bit_strings(&inputs);
+        result.outputs = bit_strings(&outputs);
+        result.violations.extend(simulator.chip.take_violations());
+        result.passed &= result.violations.is_empty();
+        if !result.passed {
+            debug!(violations = result.violations.len(), "step failed");
+            report.failures += 1;
+            report.status = TestStatus::Failed;
+        }
+        report.steps.push(result);
+        progress.update(&simulator, cycle);
+        progress.step.store(i + 1, Ordering::Relaxed);
+    }
+
+    // Checks of ticks the script never reaches fail its last step.
+    let finished = last_step.is_none() && report.status != TestStatus::Cancelled;
+    if let Some(last) = report.steps.last_mut().filter(|_| finished) {
+        for c in &checks {
+            last.violations.push(format!(
+                "Tick {}: the script ends before the screen check runs.",
+                c.at
+            ));
+        }
+        if last.passed && !checks.is_empty() {
+            last.passed = false;
+            report.failures += 1;
+            report.status = TestStatus::Failed;
+        }
+    }
+
+    Ok(report)
+}
+
+// Runs the screen checks due at `cycle`. The screen is checked once the
+// chip has been simulated, so its parts have been built.
+fn run_screen_checks(
+    checks: &mut Vec<ScreenCheck>,
+    cycle: u64,
+    simulator: &Simulator,
+    violations: &mut Vec<String>,
+) {
+    for c in checks.iter().filter(|c| c.at == cycle) {
+        violations.extend(check_screen(simulator.screen(), c));
     }
+    checks.retain(|c| c.at != cycle);
+}
+
+fn bit_strings(buses: &BusMap) -> BTreeMap<String, String> {
+    buses
+        .signals()
+        .into_iter()
+        .map(|name| {
+            let bits = buses.get_logic(&name).iter().map(|b| b.to_char()).collect();
+            (name, bits)
+        })
+        .collect()
+}
+
+pub fn run_test(test_script_path: &str) -> Result<(), WhidlError> {
+    let report = run_test_report(test_script_path, None, &AtomicBool::new(false))?;
+    Ok(print_report(&report)?)
+}
 
-    if failures > 0 {
+/// Prints the failed steps of a report and a summary. Fails if any step did.
+pub fn print_report(report: &TestReport) -> Result<(), Box<dyn Error>> {
+    for step in report.steps.iter().filter(|s| !s.passed) {
+        println!("❌ Step: {}", step.step);
+        for violation in &step.violations {
+            println!("{}", violation);
+        }
+        println!("Expected:");
+        for (name, bits) in &step.expected {
+            println!("{}: {}", name, bits);
+        }
+        println!("Actual:");
+        for (name, bits) in &step.outputs {
+            println!("{}: {}", name, bits);
+        }
+        println!();
+    }
+
+    if report.failures > 0 {
         println!(
             "❌️️️ {} failures, {} successes, {} total. ",
-            failures,
-            test_script.steps.len() - failures,
-            test_script.steps.len()
+            report.failures,
+            report.steps.len() - report.failures,
+            report.steps.len()
         );
 
         return Err(Box::new(N2VError {
             msg: String::from("Test failed."),
-            kind: ErrorKind::Other,
+            kind: ErrorKind::TestFailure,
         }));
     }
 
-    println!();
-    println!("✔️️️    {} tests passed.", test_script.steps.len());
+    println!("✔️️️    {} tests passed.", report.steps.len());
     Ok(())
 }
 
@@ -302,6 +703,7 @@ fn read_test(path: &PathBuf) -> Result<String, Box<dyn Error>> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::engine::EngineKind;
     use std::path::Path;
 
     fn construct_path(path: &PathBuf) -> PathBuf {
@@ -476,4 +878,207 @@ mod test {
         let path = construct_path(&PathBuf::from("arm/Mux8Way3.tst"));
         assert!(run_test(path.to_str().unwrap()).is_ok());
     }
+
+    #[test]
+    fn test_report_json() {
+        let path = construct_path(&PathBuf::from("nand2tetris/solutions/Bit.tst"));
+        let report = run_test_report(path.to_str().unwrap(), Some(3), &AtomicBool::new(false))
+            .expect("Test error");
+        assert_eq!(report.status, TestStatus::Passed);
+        assert_eq!(report.steps.len(), 3);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["chip"], "Bit");
+        assert_eq!(json["status"], "passed");
+        assert_eq!(json["steps"][2]["step"], 3);
+        assert_eq!(json["steps"][2]["inputs"]["load"], "1");
+        assert_eq!(json["steps"][2]["expected"]["out"], "0");
+    }
+
+    #[test]
+    fn test_key_presses() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("KeyEcho.hdl"),
+            "CHIP KeyEcho { OUT out[16]; PARTS: Keyboard(out=out); }",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("KeyEcho.tst"),
+            "load KeyEcho.hdl, output-file KeyEcho.out, compare-to KeyEcho.cmp,
+            output-list out%B1.16.1;
+            at tick 1 press 'A' for 2 ticks;
+            at tick 2 press newline for 1 tick;
+            tick, output; tock;
+            tick, output; tock;
+            tick, output; tock;
+            tick, output;",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("KeyEcho.cmp"),
+            "|       out        |
+            | 0000000000000000 |
+            | 0000000001000001 |
+            | 0000000010000000 |
+            | 0000000000000000 |",
+        )
+        .unwrap();
+        let path = dir.path().join("KeyEcho.tst");
+        let report = run_test_report(path.to_str().unwrap(), None, &AtomicBool::new(false))
+            .expect("Test error");
+        assert_eq!(report.status, TestStatus::Passed);
+        assert_eq!(report.steps.len(), 9);
+    }
+
+    #[test]
+    fn test_floating() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Bus.hdl"),
+            "CHIP Bus { IN a, b, ea, eb; OUT out; PARTS:
+                TriState(in=a, enable=ea, out=out);
+                TriState(in=b, enable=eb, out=out);
+            }",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("Bus.tst"),
+            "load Bus.hdl, output-file Bus.out, compare-to Bus.cmp,
+            output-list out%B1.1.1;
+            set a 1, set b 0, set ea 1, set eb 0, eval, output;
+            set ea 0, eval, output;
+            set ea 1, set eb 1, eval, output;",
+        )
+        .unwrap();
+        let path = dir.path().join("Bus.tst");
+        let kinds = [
+            EngineKind::Interpreter,
+            EngineKind::Event,
+            EngineKind::Netlist,
+            EngineKind::Compiled,
+            #[cfg(feature = "jit")]
+            EngineKind::Jit,
+        ];
+        // Every engine floats and resolves the bus the same way.
+        for engine in kinds {
+            let run = |cmp: &str| {
+                fs::write(dir.path().join("Bus.cmp"), cmp).unwrap();
+                let options = TestOptions {
+                    sim: SimOptions {
+                        engine,
+                        ..SimOptions::default()
+                    },
+                    ..TestOptions::default()
+                };
+                run_test_progress(
+                    path.to_str().unwrap(),
+                    None,
+                    &AtomicBool::new(false),
+                    &Progress::default(),
+                    &options,
+                )
+                .expect("Test error")
+            };
+            // Nothing drives the bus, then two drivers disagree.
+            let report = run("|out|\n| 1 |\n| z |\n| x |");
+            assert_eq!(report.status, TestStatus::Passed, "{:?}", engine);
+            let outputs: Vec<_> = report
+                .steps
+                .iter()
+                .filter(|s| !s.expected.is_empty())
+                .collect();
+            assert_eq!(outputs[1].expected["out"], "z");
+            assert_eq!(outputs[2].expected["out"], "?");
+            // A floating bit is not a 0, nor an unknown one.
+            let failed = |cmp| run(cmp).status == TestStatus::Failed;
+            assert!(failed("|out|\n| 1 |\n| 0 |\n| x |"), "{:?}", engine);
+            assert!(failed("|out|\n| 1 |\n| x |\n| x |"), "{:?}", engine);
+        }
+    }
+
+    #[test]
+    fn test_screen_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Paint.hdl"),
+            "CHIP Paint {
+                IN in[16], load, address[13];
+                OUT out[16];
+                PARTS:
+                Screen(in=in, load=load, address=address, out=out);
+            }",
+        )
+        .unwrap();
+        fs::write(dir.path().join("Paint.cmp"), "|  out  |").unwrap();
+        let mut frame = vec![0; 8192];
+        frame[33] = 3;
+        let hash = screen_hash(&frame, &Region::screen());
+        let run = |checks: &str| {
+            fs::write(
+                dir.path().join("Paint.tst"),
+                format!(
+                    "load Paint.hdl, output-file Paint.out, compare-to Paint.cmp,
+                    output-list out%D1.6.1;
+                    {}
+                    set address 33, set in 3, set load 1, tick, tock;
+                    set load 0, tick, tock;",
+                    checks
+                ),
+            )
+            .unwrap();
+            let path = dir.path().join("Paint.tst");
+            run_test_report(path.to_str().unwrap(), None, &AtomicBool::new(false))
+                .expect("Test error")
+        };
+
+        let report = run(&format!(
+            "at tick 0 expect screen is 0;
+            at tick 1 expect pixel 17 1 is 1, at tick 1 expect region 18 1 494 255 is 0;
+            at tick 2 expect screen hash {};",
+            hash
+        ));
+        assert_eq!(report.status, TestStatus::Passed);
+
+        let report = run("at tick 1 expect region 16 0 8 2 is 1; at tick 3 expect screen is 0;");
+        assert_eq!(report.status, TestStatus::Failed);
+        assert_eq!(
+            report.steps[2].violations,
+            vec!["Tick 1: expected region 16 0 8 2 to be 1, but 14 of its 16 pixels are not."]
+        );
+        assert_eq!(
+            report.steps[3].violations,
+            vec!["Tick 3: the script ends before the screen check runs."]
+        );
+    }
+
+    #[test]
+    fn test_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("project.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+        let solutions = construct_path(&PathBuf::from("nand2tetris/solutions"));
+        for file in ["And.hdl", "And.tst", "And.cmp", "Not.hdl"] {
+            zip.start_file(format!("project/01/{}", file), Default::default())
+                .unwrap();
+            std::io::Write::write_all(&mut zip, &fs::read(solutions.join(file)).unwrap()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let provider = Arc::new(ZipReader::open(&archive, "project/01").unwrap());
+        let path = provider.get_path("And.tst");
+        let vectors = load_test_vectors_from("And.tst", provider).expect("Test error");
+        assert_eq!(vectors.expected.len(), 4);
+        let report = run_vectors(
+            vectors,
+            &path,
+            None,
+            &AtomicBool::new(false),
+            &Progress::default(),
+            &TestOptions::default(),
+        )
+        .expect("Test error");
+        assert_eq!(report.status, TestStatus::Passed);
+        assert_eq!(report.test, archive.join("project/01/And.tst"));
+    }
 }
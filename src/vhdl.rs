@@ -1,17 +1,17 @@
 // This module is responsible for taking a parsed Chip as input and
 // producing equivalent VHDL code.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::error::Error;
 use std::fmt::Write;
 use std::fs;
 use std::fs::File;
 use std::io::Write as OtherWrite;
 use std::path::Path;
-use std::rc::Rc;
+use std::sync::Arc;
 
-use crate::error::N2VError;
-use crate::expr::{eval_expr, GenericWidth, Op, Terminal};
+use crate::error::{ErrorKind, N2VError};
+use crate::expr::{eval_expr, GenericValue, GenericWidth, Op, Terminal};
 use crate::parser::*;
 use crate::simulator::infer_widths;
 
@@ -337,12 +337,16 @@ fn ports(chip: &ChipHDL) -> String {
         ports.push(port_vhdl);
     }
 
-    writeln!(
-        &mut vhdl,
-        "port (CLOCK_50 : in std_logic; {});",
-        ports.join(";\n")
-    )
-    .unwrap();
+    if ports.is_empty() {
+        writeln!(&mut vhdl, "port (CLOCK_50 : in std_logic);").unwrap();
+    } else {
+        writeln!(
+            &mut vhdl,
+            "port (CLOCK_50 : in std_logic; {});",
+            ports.join(";\n")
+        )
+        .unwrap();
+    }
 
     vhdl
 }
@@ -355,6 +359,15 @@ fn port_mapping(
     let port_width = &hdl.get_port(&mapping.port.name)?.width;
     let vhdl_port_name = keyw(&mapping.port.name);
 
+    if mapping.port.descending != mapping.wire.descending {
+        return Err(Box::new(N2VError {
+            msg: format!(
+                "Mapping {}={} reverses bit order, which the VHDL backend does not support yet.",
+                mapping.port.name, mapping.wire.name
+            ),
+            kind: ErrorKind::Other,
+        }));
+    }
     let port_range = match &mapping.port.start {
         None => {
             if &GenericWidth::Terminal(Terminal::Num(1)) != port_width {
@@ -426,8 +439,104 @@ fn port_mapping(
     Ok((vhdl_port_name, port_range, wire_name, wire_range))
 }
 
+// A mapping that selects every k-th bit of the port or the wire, e.g.
+// `in=a[0..6:2]`. VHDL has no stepped ranges, so the bits are listed one by
+// one: an input reads a concatenation such as `a(4) & a(2) & a(0)`, and an
+// output drives `redirect`, whose bits are assigned to the selected ones.
+// Returns the associations for the port map and the assignments.
+fn strided_mapping(
+    hdl: &ChipHDL,
+    mapping: &PortMapping,
+    variables: &HashMap<String, GenericWidth>,
+    inferred_widths: &HashMap<String, GenericWidth>,
+    redirect: &str,
+    redirected: &mut HashSet<String>,
+) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
+    let port = hdl.get_port(&mapping.port.name)?;
+    let numeric =
+        |w: &GenericWidth, variables: &HashMap<String, GenericWidth>| match eval_expr(w, variables)
+        {
+            GenericWidth::Terminal(Terminal::Num(n)) => Some(n),
+            _ => None,
+        };
+    let port_width = numeric(&port.width, variables);
+    let bits = |bus: &BusHDL, width: Option<usize>| -> Option<Vec<usize>> {
+        let (start, end) = match (&bus.start, &bus.end) {
+            (Some(s), Some(e)) => (numeric(s, &HashMap::new())?, numeric(e, &HashMap::new())?),
+            _ => (0, width?.checked_sub(1)?),
+        };
+        Some((start..=end).step_by(bus.stride.unwrap_or(1)).collect())
+    };
+    let wire_width = inferred_widths
+        .get(&mapping.wire.name)
+        .and_then(|w| numeric(w, &HashMap::new()));
+    let (port_bits, wire_bits) = match (bits(&mapping.port, port_width), bits(&mapping.wire, wire_width)) {
+        (Some(p), Some(w)) if p.len() == w.len() => (p, w),
+        _ => {
+            return Err(Box::new(N2VError {
+                msg: format!(
+                    "Mapping {}={} selects bits with a step, which the VHDL backend can only list when the widths and bounds are numbers.",
+                    mapping.port, mapping.wire
+                ),
+                kind: ErrorKind::Other,
+            }))
+        }
+    };
+
+    let port_name = keyw(&mapping.port.name);
+    let one_bit = port_width == Some(1);
+    let port_bit = |name: &str, i: usize| {
+        if one_bit {
+            String::from(name)
+        } else {
+            format!("{}({})", name, i)
+        }
+    };
+    let wire_bit = |j: usize| format!("{}({})", keyw(&mapping.wire.name), j);
+
+    let mut associations = Vec::new();
+    let mut assignments = Vec::new();
+    if port.direction == PortDirection::In {
+        if mapping.port.stride.is_some() {
+            for (&i, &j) in port_bits.iter().zip(&wire_bits) {
+                associations.push(format!("{} => {}", port_bit(&port_name, i), wire_bit(j)));
+            }
+        } else {
+            let formal = match &mapping.port.start {
+                None => port_name,
+                Some(_) if port_bits.len() == 1 => port_bit(&port_name, port_bits[0]),
+                Some(_) => format!(
+                    "{}({} downto {})",
+                    port_name,
+                    port_bits[port_bits.len() - 1],
+                    port_bits[0]
+                ),
+            };
+            let actual: Vec<String> = wire_bits.iter().rev().map(|&j| wire_bit(j)).collect();
+            associations.push(format!("{} => {}", formal, actual.join(" & ")));
+        }
+    } else {
+        if redirected.insert(port_name.clone()) {
+            associations.push(format!("{} => {}", port_name, redirect));
+        }
+        for (&i, &j) in port_bits.iter().zip(&wire_bits) {
+            assignments.push(format!("{} <= {};", wire_bit(j), port_bit(redirect, i)));
+        }
+    }
+    Ok((associations, assignments))
+}
+
+// The association of a port left `open`. Ranges of a port are skipped, as
+// VHDL only leaves whole ports open and unassociated outputs are open anyway.
+fn open_port(mapping: &PortMapping) -> Option<String> {
+    match mapping.port.start {
+        Some(_) => None,
+        None => Some(format!("{} => open", keyw(&mapping.port.name))),
+    }
+}
+
 // VHDL keywords that we can't use.
-fn keyw(name: &str) -> String {
+pub fn keyw(name: &str) -> String {
     match name.to_lowercase().as_str() {
         "in" => String::from("in_n2v"),
         "out" => String::from("out_n2v"),
@@ -439,7 +548,8 @@ fn keyw(name: &str) -> String {
         "nor" => String::from("nor_n2v"),
         "dff" => String::from("DFF_n2v"),
         "register" => String::from("register_n2v"),
-        _ => String::from(name),
+        // Qualified names of library chips, e.g. `std.Mux16`.
+        _ => name.replace('.', "_"),
     }
 }
 
@@ -450,11 +560,38 @@ fn keyw(name: &str) -> String {
 /// `generic_params` - Instantiate the top-level chip with this parameter list.
 pub fn synth_vhdl(
     hdl: &ChipHDL,
-    provider: &Rc<dyn HdlProvider>,
+    provider: &Arc<dyn HdlProvider>,
 ) -> Result<HashMap<String, String>, Box<dyn Error>> {
     // We don't want to make a chip for simulation, because we might have
     // top-level generics. We aren't simulating the chip, we are translating
     // the HDL to VHDL.
+    let hdl = &*with_implementation(hdl, provider)?;
+    let provider = &search_path(hdl, provider)?;
+    let hdl = &*with_constants(hdl, provider)?;
+
+    if let Some(table) = &hdl.table {
+        return Ok(HashMap::from([(
+            hdl.name.clone(),
+            table_entity(hdl, table)?,
+        )]));
+    }
+    if let Some(b) = hdl.builtin_name() {
+        if b.value == "ROM" && hdl.parts.is_empty() {
+            return Ok(HashMap::from([(hdl.name.clone(), rom_entity(hdl)?)]));
+        }
+        if b.value == "TriState" && hdl.parts.is_empty() {
+            return Ok(HashMap::from([(hdl.name.clone(), tristate_entity(hdl))]));
+        }
+        if hdl.parts.is_empty() {
+            return Err(Box::new(N2VError {
+                msg: format!(
+                    "Chip {} is BUILTIN {} and has no parts to synthesize.",
+                    hdl.name, b.value
+                ),
+                kind: ErrorKind::Other,
+            }));
+        }
+    }
 
     // Component name -> component definition
     let mut entities = HashMap::new();
@@ -475,8 +612,14 @@ pub fn synth_vhdl(
     // Declare components
     let mut component_decls: HashSet<String> = HashSet::new();
 
+    // In a chip with CLOCKED pins, flip-flops are written as one process
+    // sensitive only to the clock instead of as DFF components.
+    let clocked_dff =
+        |c: &Component| !hdl.clocked.is_empty() && c.name.value.eq_ignore_ascii_case("dff");
+
     for part in &hdl.parts {
         match part {
+            Part::Component(c) if clocked_dff(c) => {}
             Part::Component(c) => {
                 // Generate the VHDL definitions for each type of component.
                 let generated_definitions = generate_component_definition(c, provider)?;
@@ -534,20 +677,57 @@ pub fn synth_vhdl(
     };
 
     let mut signals: HashSet<String> = HashSet::new();
+    // Outputs of parts annotated with `@keep`.
+    let mut kept: BTreeSet<String> = BTreeSet::new();
+    // Assignments made at the rising edge of the clock.
+    let mut clocked_vhdl: String = String::new();
 
     for (component_counter, part) in hdl.parts.iter().enumerate() {
         match part {
+            Part::Component(c) if clocked_dff(c) => {
+                let component_hdl = get_hdl(&c.qualified_name(), provider)?;
+                let component_id = format!("nand2v_c{}", component_counter);
+                arch_vhdl.push_str(&doc_comment(&c.annotations));
+                let mut input = String::from("'0'");
+                let mut outputs = Vec::new();
+                for mapping in c.mappings.iter().filter(|m| !m.is_open()) {
+                    if &mapping.wire.name != "true" && &mapping.wire.name != "false" {
+                        let wire_width = inferred_widths.get(&mapping.wire.name).unwrap();
+                        signals.insert(print_signal(&mapping.wire.name, wire_width));
+                    }
+                    let (vhdl_port_name, _, wire_name, wire_range) =
+                        port_mapping(&component_hdl, mapping, &inferred_widths)?;
+                    if component_hdl.get_port(&mapping.port.name)?.direction == PortDirection::In {
+                        input = format!("{}{}", wire_name, wire_range);
+                    } else {
+                        let redirect_signal = format!("{}_{}", component_id, vhdl_port_name);
+                        writeln!(
+                            &mut arch_vhdl,
+                            "{}{} <= {};",
+                            wire_name, wire_range, redirect_signal
+                        )?;
+                        outputs.push(redirect_signal);
+                    }
+                }
+                // Every output of the flip-flop is the same bit.
+                outputs.dedup();
+                for redirect_signal in outputs {
+                    writeln!(&mut clocked_vhdl, "\t\t{} <= {};", redirect_signal, input)?;
+                    signals.insert(print_signal(
+                        &redirect_signal,
+                        &GenericWidth::Terminal(Terminal::Num(1)),
+                    ));
+                    if find_annotation(&c.annotations, "keep").is_some() {
+                        kept.insert(redirect_signal);
+                    }
+                }
+            }
             Part::Component(c) => {
-                let component_hdl = get_hdl(&c.name.value, provider)?;
+                let component_hdl = get_hdl(&c.qualified_name(), provider)?;
                 let component_id = format!("nand2v_c{}", component_counter);
 
                 // Parameters assigned to generic variables.
-                let component_variables: HashMap<String, GenericWidth> = component_hdl
-                    .generic_decls
-                    .iter()
-                    .map(|x| x.value.clone())
-                    .zip(c.generic_params.clone())
-                    .collect();
+                let component_variables = generic_widths(c, &component_hdl)?;
                 let vhdl_generic_params: Vec<String> = component_variables
                     .iter()
                     .map(|(var, val)| format!("{} => {}", var, val))
@@ -561,10 +741,15 @@ pub fn synth_vhdl(
                     )?;
                 }
 
+                arch_vhdl.push_str(&doc_comment(&c.annotations));
                 let mut port_map: Vec<String> = Vec::new();
 
                 let mut redirected_ports: HashSet<String> = HashSet::new();
                 for mapping in c.mappings.iter() {
+                    if mapping.is_open() {
+                        port_map.extend(open_port(mapping));
+                        continue;
+                    }
                     // Print the declaration for the signal required for this mapping.
                     if &mapping.wire.name != "true" && &mapping.wire.name != "false" {
                         let wire_width = inferred_widths.get(&mapping.wire.name).unwrap();
@@ -575,7 +760,32 @@ pub fn synth_vhdl(
                         signals.insert(sig);
                     }
 
-                    let port_direction = &component_hdl.get_port(&mapping.port.name)?.direction;
+                    let port = component_hdl.get_port(&mapping.port.name)?;
+                    let port_direction = &port.direction;
+                    if mapping.port.stride.is_some() || mapping.wire.stride.is_some() {
+                        let redirect_signal =
+                            format!("{}_{}", component_id, keyw(&mapping.port.name));
+                        let (associations, assignments) = strided_mapping(
+                            &component_hdl,
+                            mapping,
+                            &component_variables,
+                            &inferred_widths,
+                            &redirect_signal,
+                            &mut redirected_ports,
+                        )?;
+                        port_map.extend(associations);
+                        for assignment in assignments {
+                            writeln!(&mut arch_vhdl, "{}", assignment)?;
+                        }
+                        if port_direction == &PortDirection::Out {
+                            let sig = print_signal(
+                                &redirect_signal,
+                                &eval_expr(&port.width, &component_variables),
+                            );
+                            signals.insert(sig);
+                        }
+                        continue;
+                    }
                     let (vhdl_port_name, port_range, wire_name, wire_range) =
                         port_mapping(&component_hdl, mapping, &inferred_widths)?;
 
@@ -605,6 +815,9 @@ pub fn synth_vhdl(
                             &eval_expr(wire_width, &component_variables),
                         );
                         signals.insert(sig);
+                        if find_annotation(&c.annotations, "keep").is_some() {
+                            kept.insert(redirect_signal);
+                        }
                     }
                 }
 
@@ -612,7 +825,7 @@ pub fn synth_vhdl(
                     &mut arch_vhdl,
                     "{} : {}\n\t{}port map ({}, CLOCK_50 => CLOCK_50);\n",
                     component_id,
-                    keyw(&c.name.value),
+                    keyw(&c.qualified_name()),
                     generic_map,
                     port_map.join(", ")
                 )
@@ -626,16 +839,11 @@ pub fn synth_vhdl(
                     .enumerate()
                     .map(|(i, c)| {
                         let mut body_vhdl = String::new();
-                        let component_hdl = get_hdl(&c.name.value, provider).unwrap();
+                        let component_hdl = get_hdl(&c.qualified_name(), provider).unwrap();
                         let component_id = format!("n2vc{}_lp{}", component_counter, i);
 
                         // Parameters assigned to generic variables.
-                        let component_variables: HashMap<String, GenericWidth> = component_hdl
-                            .generic_decls
-                            .iter()
-                            .map(|x| x.value.clone())
-                            .zip(c.generic_params.clone())
-                            .collect();
+                        let component_variables = generic_widths(c, &component_hdl).unwrap();
                         let vhdl_generic_params: Vec<String> = component_variables
                             .iter()
                             .map(|(var, val)| format!("{} => {}", var, val))
@@ -650,10 +858,15 @@ pub fn synth_vhdl(
                             .unwrap();
                         }
 
+                        body_vhdl.push_str(&doc_comment(&c.annotations));
                         let mut port_map: Vec<String> = Vec::new();
 
                         let mut redirected_ports: HashSet<String> = HashSet::new();
                         for mapping in c.mappings.iter() {
+                            if mapping.is_open() {
+                                port_map.extend(open_port(mapping));
+                                continue;
+                            }
                             // Print the declaration for the signal required for this mapping.
                             if &mapping.wire.name != "true" && &mapping.wire.name != "false" {
                                 let wire_width = inferred_widths.get(&mapping.wire.name).unwrap();
@@ -664,8 +877,32 @@ pub fn synth_vhdl(
                                 signals.insert(sig);
                             }
 
-                            let port_direction =
-                                &component_hdl.get_port(&mapping.port.name)?.direction;
+                            let port = component_hdl.get_port(&mapping.port.name)?;
+                            let port_direction = &port.direction;
+                            if mapping.port.stride.is_some() || mapping.wire.stride.is_some() {
+                                let redirect_signal =
+                                    format!("{}_{}", component_id, keyw(&mapping.port.name));
+                                let (associations, assignments) = strided_mapping(
+                                    &component_hdl,
+                                    mapping,
+                                    &component_variables,
+                                    &inferred_widths,
+                                    &redirect_signal,
+                                    &mut redirected_ports,
+                                )?;
+                                port_map.extend(associations);
+                                for assignment in assignments {
+                                    writeln!(&mut body_vhdl, "{}", assignment)?;
+                                }
+                                if port_direction == &PortDirection::Out {
+                                    let sig = print_signal(
+                                        &redirect_signal,
+                                        &eval_expr(&port.width, &component_variables),
+                                    );
+                                    signals.insert(sig);
+                                }
+                                continue;
+                            }
                             let (vhdl_port_name, port_range, wire_name, wire_range) =
                                 port_mapping(&component_hdl, mapping, &inferred_widths)?;
 
@@ -698,6 +935,9 @@ pub fn synth_vhdl(
                                     &eval_expr(wire_width, &component_variables),
                                 );
                                 signals.insert(sig);
+                                if find_annotation(&c.annotations, "keep").is_some() {
+                                    kept.insert(redirect_signal);
+                                }
                             } else {
                                 port_map.push(format!(
                                     "{}{} => {}",
@@ -710,7 +950,7 @@ pub fn synth_vhdl(
                             &mut body_vhdl,
                             "{} : {}\n\t{}port map ({}, CLOCK_50 => CLOCK_50);\n",
                             component_id,
-                            keyw(&c.name.value),
+                            keyw(&c.qualified_name()),
                             generic_map,
                             port_map.join(", ")
                         )
@@ -736,16 +976,46 @@ pub fn synth_vhdl(
     for s in &signals {
         writeln!(&mut signal_vhdl, "{}", s).unwrap();
     }
+    // Stops synthesis tools from optimizing the signals away.
+    if !kept.is_empty() {
+        writeln!(&mut signal_vhdl, "attribute keep : boolean;").unwrap();
+    }
+    for s in &kept {
+        writeln!(
+            &mut signal_vhdl,
+            "attribute keep of {} : signal is true;",
+            s
+        )
+        .unwrap();
+    }
 
     // Actual chip definition
     top_level_vhdl = top_level_vhdl + &signal_vhdl;
     writeln!(&mut top_level_vhdl, "begin").unwrap();
     top_level_vhdl = top_level_vhdl + &arch_vhdl;
+    if !clocked_vhdl.is_empty() {
+        writeln!(&mut top_level_vhdl, "clocked : process(CLOCK_50)")?;
+        writeln!(&mut top_level_vhdl, "begin")?;
+        writeln!(&mut top_level_vhdl, "\tif rising_edge(CLOCK_50) then")?;
+        top_level_vhdl += &clocked_vhdl;
+        writeln!(&mut top_level_vhdl, "\tend if;")?;
+        writeln!(&mut top_level_vhdl, "end process;")?;
+    }
+    if let Some(stimulus) = &hdl.stimulus {
+        top_level_vhdl += &stimulus_process(stimulus);
+    }
     writeln!(&mut top_level_vhdl, "end architecture arch;").unwrap();
 
     let mut header_vhdl = String::new();
     writeln!(&mut header_vhdl, "library ieee;").unwrap();
     writeln!(&mut header_vhdl, "use ieee.std_logic_1164.all;").unwrap();
+    if hdl.stimulus.is_some() {
+        writeln!(&mut header_vhdl, "use ieee.numeric_std.all;").unwrap();
+    }
+    // Widths that use log2 need real valued math.
+    if top_level_vhdl.contains("log2(real(") {
+        writeln!(&mut header_vhdl, "use ieee.math_real.all;").unwrap();
+    }
     writeln!(&mut header_vhdl).unwrap();
     top_level_vhdl = header_vhdl + &top_level_vhdl;
 
@@ -754,12 +1024,365 @@ pub fn synth_vhdl(
     Ok(entities)
 }
 
+// The STIMULUS block of a testbench becomes a process that drives each
+// stimulus signal for the given number of clock cycles, generating the same
+// values as the simulator.
+fn stimulus_process(stimulus: &Stimulus) -> String {
+    let mut variables = String::new();
+    let mut body = String::new();
+    for signal in &stimulus.signals {
+        let name = keyw(&signal.name.value);
+        let state = format!("{}_state", name);
+        let (state_width, init) = match signal.generator {
+            Generator::Counter => (signal.width, String::from("(others => '0')")),
+            Generator::Random(seed) => (64, format!("x\"{:016X}\"", seed)),
+        };
+        writeln!(
+            variables,
+            "\t\tvariable {} : unsigned({} downto 0) := {};",
+            state,
+            state_width - 1,
+            init
+        )
+        .unwrap();
+        if let Generator::Random(_) = signal.generator {
+            for (shift, amount) in [("left", 13), ("right", 7), ("left", 17)] {
+                writeln!(
+                    body,
+                    "\t\t\t{} := {} xor shift_{}({}, {});",
+                    state, state, shift, state, amount
+                )
+                .unwrap();
+            }
+        }
+        let value = if signal.width == 1 {
+            format!("{}(0)", state)
+        } else {
+            format!("std_logic_vector({}({} downto 0))", state, signal.width - 1)
+        };
+        writeln!(body, "\t\t\t{} <= {};", name, value).unwrap();
+        if signal.generator == Generator::Counter {
+            writeln!(body, "\t\t\t{} := {} + 1;", state, state).unwrap();
+        }
+    }
+
+    let mut vhdl = String::new();
+    writeln!(vhdl, "\tstimulus : process").unwrap();
+    vhdl.push_str(&variables);
+    writeln!(vhdl, "\tbegin").unwrap();
+    writeln!(
+        vhdl,
+        "\t\tfor cycle in 0 to {} loop",
+        stimulus.cycles as i64 - 1
+    )
+    .unwrap();
+    vhdl.push_str(&body);
+    writeln!(vhdl, "\t\t\twait until rising_edge(CLOCK_50);").unwrap();
+    writeln!(vhdl, "\t\tend loop;").unwrap();
+    writeln!(vhdl, "\t\twait;").unwrap();
+    writeln!(vhdl, "\tend process;").unwrap();
+    vhdl
+}
+
+// A `BUILTIN ROM` chip becomes a constant array holding its contents,
+// indexed by the inputs concatenated in the order they are declared.
+fn rom_entity(hdl: &ChipHDL) -> Result<String, Box<dyn Error>> {
+    let mut widths = Vec::new();
+    for p in &hdl.ports {
+        match p.width {
+            GenericWidth::Terminal(Terminal::Num(w)) => widths.push((p, w)),
+            _ => {
+                return Err(Box::new(N2VError {
+                    msg: format!("ROM {} ports must have numeric widths.", hdl.name),
+                    kind: ErrorKind::Other,
+                }))
+            }
+        }
+    }
+    let address_width: usize = widths
+        .iter()
+        .filter(|(p, _)| p.direction == PortDirection::In)
+        .map(|(_, w)| w)
+        .sum();
+    let word_width: usize = widths
+        .iter()
+        .filter(|(p, _)| p.direction == PortDirection::Out)
+        .map(|(_, w)| w)
+        .sum();
+    if word_width == 0 {
+        return Err(Box::new(N2VError {
+            msg: format!("ROM {} has no outputs.", hdl.name),
+            kind: ErrorKind::Other,
+        }));
+    }
+    let path = hdl.path.clone().unwrap_or_default().with_extension("rom");
+    let words = crate::builtin::read_rom(&path, word_width)?;
+
+    let mut vhdl = String::new();
+    writeln!(&mut vhdl, "library ieee;")?;
+    writeln!(&mut vhdl, "use ieee.std_logic_1164.all;")?;
+    writeln!(&mut vhdl, "use ieee.numeric_std.all;")?;
+    writeln!(&mut vhdl)?;
+    write_top_level_entity(hdl, &mut vhdl);
+    writeln!(&mut vhdl, "architecture arch of {} is", keyw(&hdl.name))?;
+    writeln!(
+        &mut vhdl,
+        "type rom_t is array (0 to {}) of std_logic_vector({} downto 0);",
+        (1usize << address_width) - 1,
+        word_width - 1
+    )?;
+    let contents: Vec<String> = words
+        .iter()
+        .map(|w| {
+            let bits: String = w.iter().map(|b| if *b { '1' } else { '0' }).collect();
+            format!("\"{}\"", bits)
+        })
+        .collect();
+    let others = if words.len() < 1 << address_width {
+        vec![String::from("others => (others => '0')")]
+    } else {
+        Vec::new()
+    };
+    writeln!(
+        &mut vhdl,
+        "constant contents : rom_t := ({});",
+        contents
+            .into_iter()
+            .enumerate()
+            .map(|(i, w)| format!("{} => {}", i, w))
+            .chain(others)
+            .collect::<Vec<String>>()
+            .join(",\n")
+    )?;
+    if address_width > 0 {
+        writeln!(
+            &mut vhdl,
+            "signal address : std_logic_vector({} downto 0);",
+            address_width - 1
+        )?;
+    }
+    writeln!(
+        &mut vhdl,
+        "signal word : std_logic_vector({} downto 0);",
+        word_width - 1
+    )?;
+    writeln!(&mut vhdl, "begin")?;
+
+    // Slices of the address and word that belong to each port, most
+    // significant first.
+    let mut address_high = address_width;
+    let mut word_high = word_width;
+    for (p, w) in &widths {
+        if p.direction == PortDirection::In {
+            writeln!(
+                &mut vhdl,
+                "{} <= {};",
+                slice("address", address_high - 1, *w),
+                keyw(&p.name.value)
+            )?;
+            address_high -= w;
+        } else {
+            writeln!(
+                &mut vhdl,
+                "{} <= {};",
+                keyw(&p.name.value),
+                slice("word", word_high - 1, *w)
+            )?;
+            word_high -= w;
+        }
+    }
+    if address_width > 0 {
+        writeln!(
+            &mut vhdl,
+            "word <= contents(to_integer(unsigned(address)));"
+        )?;
+    } else {
+        writeln!(&mut vhdl, "word <= contents(0);")?;
+    }
+    writeln!(&mut vhdl, "end architecture arch;")?;
+    Ok(vhdl)
+}
+
+// A `BUILTIN TriState` chip drives 'Z' while it is disabled. Its outputs
+// may share a signal, which std_logic resolves.
+fn tristate_entity(hdl: &ChipHDL) -> String {
+    let mut vhdl = String::new();
+    writeln!(&mut vhdl, "library ieee;").unwrap();
+    writeln!(&mut vhdl, "use ieee.std_logic_1164.all;").unwrap();
+    writeln!(&mut vhdl).unwrap();
+    write_top_level_entity(hdl, &mut vhdl);
+    writeln!(&mut vhdl, "architecture arch of {} is", keyw(&hdl.name)).unwrap();
+    writeln!(&mut vhdl, "begin").unwrap();
+    writeln!(
+        &mut vhdl,
+        "{} <= {} when {} = '1' else 'Z';",
+        keyw("out"),
+        keyw("in"),
+        keyw("enable")
+    )
+    .unwrap();
+    writeln!(&mut vhdl, "end architecture arch;").unwrap();
+    vhdl
+}
+
+// `width` bits of `signal` from bit `high` down.
+fn slice(signal: &str, high: usize, width: usize) -> String {
+    if width == 1 {
+        format!("{}({})", signal, high)
+    } else {
+        format!("{}({} downto {})", signal, high, high + 1 - width)
+    }
+}
+
+// A `TABLE` chip becomes a combinational process with a case for each row,
+// on the inputs concatenated in the order they are declared. Inputs that
+// have no row drive the outputs to 'X'.
+fn table_entity(hdl: &ChipHDL, table: &Table) -> Result<String, Box<dyn Error>> {
+    let mut widths = Vec::new();
+    for p in &hdl.ports {
+        match p.width {
+            GenericWidth::Terminal(Terminal::Num(w)) => widths.push((p, w)),
+            _ => {
+                return Err(Box::new(N2VError {
+                    msg: format!("TABLE chip {} ports must have numeric widths.", hdl.name),
+                    kind: ErrorKind::Other,
+                }))
+            }
+        }
+    }
+    let width = |direction: PortDirection| -> usize {
+        widths
+            .iter()
+            .filter(|(p, _)| p.direction == direction)
+            .map(|(_, w)| w)
+            .sum()
+    };
+    let (input_width, output_width) = (width(PortDirection::In), width(PortDirection::Out));
+    table
+        .check_widths(&hdl.name, input_width, output_width)
+        .map_err(|msg| N2VError {
+            msg,
+            kind: ErrorKind::Other,
+        })?;
+    if output_width == 0 {
+        return Err(Box::new(N2VError {
+            msg: format!("TABLE chip {} has no outputs.", hdl.name),
+            kind: ErrorKind::Other,
+        }));
+    }
+
+    let mut vhdl = String::new();
+    writeln!(&mut vhdl, "library ieee;")?;
+    writeln!(&mut vhdl, "use ieee.std_logic_1164.all;")?;
+    writeln!(&mut vhdl)?;
+    write_top_level_entity(hdl, &mut vhdl);
+    writeln!(&mut vhdl, "architecture arch of {} is", keyw(&hdl.name))?;
+    if input_width > 0 {
+        writeln!(
+            &mut vhdl,
+            "signal inputs : std_logic_vector({} downto 0);",
+            input_width - 1
+        )?;
+    }
+    writeln!(
+        &mut vhdl,
+        "signal outputs : std_logic_vector({} downto 0);",
+        output_width - 1
+    )?;
+    writeln!(&mut vhdl, "begin")?;
+
+    let mut input_high = input_width;
+    let mut output_high = output_width;
+    for (p, w) in &widths {
+        if p.direction == PortDirection::In {
+            writeln!(
+                &mut vhdl,
+                "{} <= {};",
+                slice("inputs", input_high - 1, *w),
+                keyw(&p.name.value)
+            )?;
+            input_high -= w;
+        } else {
+            writeln!(
+                &mut vhdl,
+                "{} <= {};",
+                keyw(&p.name.value),
+                slice("outputs", output_high - 1, *w)
+            )?;
+            output_high -= w;
+        }
+    }
+
+    if input_width == 0 {
+        match table.rows.first() {
+            Some(row) => writeln!(&mut vhdl, "outputs <= \"{}\";", row.output_bits())?,
+            None => writeln!(&mut vhdl, "outputs <= (others => 'X');")?,
+        }
+    } else {
+        writeln!(&mut vhdl, "truth_table : process(inputs)")?;
+        writeln!(&mut vhdl, "begin")?;
+        writeln!(&mut vhdl, "\tcase inputs is")?;
+        for row in &table.rows {
+            writeln!(
+                &mut vhdl,
+                "\t\twhen \"{}\" => outputs <= \"{}\";",
+                row.input_bits(),
+                row.output_bits()
+            )?;
+        }
+        writeln!(&mut vhdl, "\t\twhen others => outputs <= (others => 'X');")?;
+        writeln!(&mut vhdl, "\tend case;")?;
+        writeln!(&mut vhdl, "end process;")?;
+    }
+    writeln!(&mut vhdl, "end architecture arch;")?;
+    Ok(vhdl)
+}
+
+// The widths passed to the generics of a part. Entities only declare
+// generics as integers, so string generics cannot be synthesized.
+fn generic_widths(
+    c: &Component,
+    component_hdl: &ChipHDL,
+) -> Result<HashMap<String, GenericWidth>, N2VError> {
+    let mut widths = HashMap::new();
+    for (decl, g) in c.generic_args(component_hdl)? {
+        match g {
+            GenericValue::Width(w) => {
+                widths.insert(decl.value.clone(), w.clone());
+            }
+            GenericValue::Str(_) => {
+                return Err(N2VError {
+                    msg: format!(
+                        "String generic {} of {} cannot be synthesized to VHDL.",
+                        decl.value, component_hdl.name
+                    ),
+                    kind: ErrorKind::Other,
+                })
+            }
+        }
+    }
+    Ok(widths)
+}
+
+// `@doc` text as VHDL comments.
+fn doc_comment(annotations: &[Annotation]) -> String {
+    match find_annotation(annotations, "doc") {
+        Some(a) => format!("-- {}\n", a.args[0]),
+        None => String::new(),
+    }
+}
+
 fn write_top_level_entity(hdl: &ChipHDL, top_level_vhdl: &mut String) {
+    top_level_vhdl.push_str(&doc_comment(&hdl.annotations));
     writeln!(top_level_vhdl, "entity {} is", keyw(&hdl.name)).unwrap();
     if !hdl.generic_decls.is_empty() {
         writeln!(top_level_vhdl, "{}", generics(hdl)).unwrap();
     }
     writeln!(top_level_vhdl, "{}", ports(hdl)).unwrap();
+    if !hdl.clocked.is_empty() {
+        let clocked: Vec<String> = hdl.clocked.iter().map(|c| keyw(&c.value)).collect();
+        writeln!(top_level_vhdl, "-- CLOCKED: {}", clocked.join(", ")).unwrap();
+    }
     writeln!(top_level_vhdl, "end entity {};", keyw(&hdl.name)).unwrap();
     writeln!(top_level_vhdl).unwrap();
 }
@@ -769,7 +1392,7 @@ fn write_top_level_entity(hdl: &ChipHDL, top_level_vhdl: &mut String) {
 /// for that type of chip.
 fn generate_component_definition(
     component: &Component,
-    provider: &Rc<dyn HdlProvider>,
+    provider: &Arc<dyn HdlProvider>,
 ) -> Result<HashMap<String, String>, Box<dyn Error>> {
     // We skip NAND because that is hard-coded and will be copied separately.
     if &component.name.value.to_lowercase() == "nand" {
@@ -779,14 +1402,17 @@ fn generate_component_definition(
         return Ok(HashMap::new());
     }
 
-    let component_hdl = get_hdl(&component.name.value, provider).unwrap();
+    let component_hdl = get_hdl(&component.qualified_name(), provider).unwrap();
     synth_vhdl(&component_hdl, provider)
 }
 
 /// Generates the declaration for a component that can be included in the VHDL.
 /// of another chip that uses this component.
-fn generate_component_declaration(component: &Component, provider: &Rc<dyn HdlProvider>) -> String {
-    let component_hdl = get_hdl(&component.name.value, provider).unwrap();
+fn generate_component_declaration(
+    component: &Component,
+    provider: &Arc<dyn HdlProvider>,
+) -> String {
+    let component_hdl = get_hdl(&component.qualified_name(), provider).unwrap();
     let mut component_decl = String::new();
     writeln!(
         &mut component_decl,
@@ -828,7 +1454,7 @@ fn generate_components(hdl: &ChipHDL) -> Result<Vec<Component>, N2VError> {
                         new_c.generic_params = new_c
                             .generic_params
                             .iter()
-                            .map(|x| eval_expr(x, &variables))
+                            .map(|x| x.map_width(|w| eval_expr(w, &variables)))
                             .collect();
 
                         res.push(new_c);
@@ -875,11 +1501,236 @@ mod test {
                 .to_str()
                 .unwrap(),
         );
-        let provider: Rc<dyn HdlProvider> = Rc::new(FileReader::new(&base_path));
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(&base_path));
         let entities = crate::vhdl::synth_vhdl(&hdl, &provider).unwrap();
         let temp_dir = tempdir().unwrap();
         let quartus_dir = temp_dir.path().join("dummy");
         crate::vhdl::create_quartus_project(&hdl, entities, &quartus_dir)
             .expect("Unable to create project");
     }
+
+    #[test]
+    fn test_annotations() {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let mut scanner = Scanner::new(
+            "@doc(\"Inverts twice.\")
+            CHIP Twice {
+                IN in;
+                OUT out;
+                PARTS:
+                @keep @doc(\"Kept for probing.\")
+                Not(in=in, out=x);
+                Not(in=x, out=out);
+            }",
+            base_path.join("Twice.hdl"),
+        );
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let entities = synth_vhdl(&hdl, &provider).expect("VHDL error");
+        let vhdl = &entities["Twice"];
+        assert!(vhdl.contains("-- Inverts twice.\nentity Twice is"));
+        assert!(vhdl.contains("-- Kept for probing.\nx <= nand2v_c0_out_n2v;\nnand2v_c0 : not_n2v"));
+        assert!(vhdl.contains(
+            "attribute keep : boolean;\nattribute keep of nand2v_c0_out_n2v : signal is true;\n"
+        ));
+    }
+
+    #[test]
+    fn test_table() {
+        let mut scanner = Scanner::new(
+            "CHIP Mux2 {
+                IN a, b, sel;
+                OUT out;
+                TABLE {
+                    0 1 0 -> 0;
+                    01 1 -> 1;
+                }
+            }",
+            PathBuf::from("Mux2.hdl"),
+        );
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new("."));
+        let entities = synth_vhdl(&hdl, &provider).expect("VHDL error");
+        let vhdl = &entities["Mux2"];
+        assert!(
+            vhdl.contains("inputs(2) <= a;\ninputs(1) <= b;\ninputs(0) <= sel;"),
+            "{}",
+            vhdl
+        );
+        assert!(vhdl.contains("out_n2v <= outputs(0);"), "{}", vhdl);
+        assert!(vhdl.contains("truth_table : process(inputs)"), "{}", vhdl);
+        assert!(
+            vhdl.contains("\t\twhen \"011\" => outputs <= \"1\";\n"),
+            "{}",
+            vhdl
+        );
+        assert!(
+            vhdl.contains("when others => outputs <= (others => 'X');"),
+            "{}",
+            vhdl
+        );
+    }
+
+    #[test]
+    fn test_clocked() {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let mut scanner = Scanner::new(
+            "CHIP Delay {
+                IN in[2];
+                OUT out, first;
+                CLOCKED in;
+                PARTS:
+                DFF(in=in[1], out=out);
+                Not(in=in[0], out=x);
+                DFF(in=x, out=first, out=y);
+            }",
+            base_path.join("Delay.hdl"),
+        );
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let entities = synth_vhdl(&hdl, &provider).expect("VHDL error");
+        let vhdl = &entities["Delay"];
+        // The flip-flops are one process on the clock, and the rest of the
+        // chip stays outside it.
+        assert!(
+            vhdl.contains(
+                "clocked : process(CLOCK_50)\nbegin\n\tif rising_edge(CLOCK_50) then\n\t\tnand2v_c0_out_n2v <= in_n2v(1);\n\t\tnand2v_c2_out_n2v <= x;\n\tend if;\nend process;"
+            ),
+            "{}",
+            vhdl
+        );
+        assert!(
+            vhdl.contains("first <= nand2v_c2_out_n2v;\ny <= nand2v_c2_out_n2v;"),
+            "{}",
+            vhdl
+        );
+        assert!(vhdl.contains("nand2v_c1 : not_n2v"), "{}", vhdl);
+        assert!(!vhdl.contains("DFF_n2v"), "{}", vhdl);
+    }
+
+    #[test]
+    fn test_tristate() {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let mut scanner = Scanner::new(
+            "CHIP Shared {
+                IN a, b, sel;
+                OUT out;
+                PARTS:
+                Not(in=sel, out=nsel);
+                TriState(in=a, enable=nsel, out=out);
+                TriState(in=b, enable=sel, out=out);
+            }",
+            base_path.join("Shared.hdl"),
+        );
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let entities = synth_vhdl(&hdl, &provider).expect("VHDL error");
+        let tristate = &entities["TriState"];
+        assert!(
+            tristate.contains("out_n2v <= in_n2v when enable = '1' else 'Z';"),
+            "{}",
+            tristate
+        );
+        // Both buffers drive out, and std_logic resolves it.
+        let vhdl = &entities["Shared"];
+        assert!(vhdl.contains("out_n2v <= nand2v_c1_out_n2v;"), "{}", vhdl);
+        assert!(vhdl.contains("out_n2v <= nand2v_c2_out_n2v;"), "{}", vhdl);
+    }
+
+    #[test]
+    fn test_strided() {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let mut scanner = Scanner::new(
+            "CHIP Strided {
+                IN a[8];
+                OUT out[8], even[4];
+                PARTS:
+                Not16(in[0..3]=a[1..7:2], out[0..3]=even);
+                Not16(in[0..7]=a, out[0..6:2]=out[0..3], out[1..7:2]=out[4..7]);
+            }",
+            base_path.join("Strided.hdl"),
+        );
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let vhdl = &synth_vhdl(&hdl, &provider).expect("VHDL error")["Strided"];
+        assert!(
+            vhdl.contains("in_n2v(3 downto 0) => a(7) & a(5) & a(3) & a(1)"),
+            "{}",
+            vhdl
+        );
+        assert!(vhdl.contains("out_n2v => nand2v_c1_out_n2v"), "{}", vhdl);
+        assert!(
+            vhdl.contains("out_n2v(0) <= nand2v_c1_out_n2v(0);"),
+            "{}",
+            vhdl
+        );
+        assert!(
+            vhdl.contains("out_n2v(3) <= nand2v_c1_out_n2v(6);"),
+            "{}",
+            vhdl
+        );
+        assert!(
+            vhdl.contains("out_n2v(7) <= nand2v_c1_out_n2v(7);"),
+            "{}",
+            vhdl
+        );
+    }
+
+    #[test]
+    fn test_open_port() {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join("tests")
+            .join("nand2tetris")
+            .join("solutions");
+        let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(base_path.to_str().unwrap()));
+        let mut scanner = Scanner::new(
+            "CHIP Low {
+                IN in, sel;
+                OUT out;
+                PARTS:
+                DMux(in=in, sel=sel, a=out, b=open);
+            }",
+            base_path.join("Low.hdl"),
+        );
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        let hdl = parser.parse().expect("Parse error");
+        let entities = synth_vhdl(&hdl, &provider).expect("VHDL error");
+        let vhdl = &entities["Low"];
+        assert!(vhdl.contains("b => open"), "{}", vhdl);
+        assert!(!vhdl.contains("signal open"), "{}", vhdl);
+    }
 }
@@ -0,0 +1,223 @@
+// Traversal of parse trees. Tools that only care about a few kinds of nodes
+// implement `Visitor`, override the callbacks they need, and start the walk
+// with `walk_chip`. Each callback's default implementation walks the node's
+// children, so an override that wants to keep descending calls the matching
+// `walk_*` function, and one that does not simply returns.
+
+use crate::expr::{GenericValue, GenericWidth};
+use crate::parser::*;
+
+/// Callbacks for the nodes of a chip, called in source order.
+pub trait Visitor {
+    fn visit_chip(&mut self, chip: &ChipHDL) {
+        walk_chip_children(self, chip);
+    }
+
+    fn visit_port(&mut self, port: &GenericPort) {
+        walk_port(self, port);
+    }
+
+    /// Called for every part, then for the component or loop it holds.
+    fn visit_part(&mut self, part: &Part) {
+        walk_part(self, part);
+    }
+
+    /// Called for top-level parts and for the parts in loop bodies.
+    fn visit_component(&mut self, component: &Component) {
+        walk_component(self, component);
+    }
+
+    fn visit_loop(&mut self, lp: &Loop) {
+        walk_loop(self, lp);
+    }
+
+    fn visit_mapping(&mut self, mapping: &PortMapping) {
+        walk_mapping(self, mapping);
+    }
+
+    /// Called for every width expression and, by default, for each of its
+    /// subexpressions.
+    fn visit_width(&mut self, width: &GenericWidth) {
+        walk_width(self, width);
+    }
+}
+
+/// Walks a whole chip, starting with `visit_chip`.
+pub fn walk_chip<V: Visitor + ?Sized>(visitor: &mut V, chip: &ChipHDL) {
+    visitor.visit_chip(chip);
+}
+
+/// Visits the ports, then the parts of a chip.
+pub fn walk_chip_children<V: Visitor + ?Sized>(visitor: &mut V, chip: &ChipHDL) {
+    for port in &chip.ports {
+        visitor.visit_port(port);
+    }
+    for part in &chip.parts {
+        visitor.visit_part(part);
+    }
+}
+
+pub fn walk_port<V: Visitor + ?Sized>(visitor: &mut V, port: &GenericPort) {
+    visitor.visit_width(&port.width);
+}
+
+pub fn walk_part<V: Visitor + ?Sized>(visitor: &mut V, part: &Part) {
+    match part {
+        Part::Component(c) => visitor.visit_component(c),
+        Part::Loop(l) => visitor.visit_loop(l),
+    }
+}
+
+/// Visits the generic arguments, then the port mappings of a component.
+pub fn walk_component<V: Visitor + ?Sized>(visitor: &mut V, component: &Component) {
+    for param in &component.generic_params {
+        if let GenericValue::Width(w) = &param.value {
+            visitor.visit_width(w);
+        }
+    }
+    for mapping in &component.mappings {
+        visitor.visit_mapping(mapping);
+    }
+}
+
+/// Visits the bounds, then the body of a loop.
+pub fn walk_loop<V: Visitor + ?Sized>(visitor: &mut V, lp: &Loop) {
+    visitor.visit_width(&lp.start);
+    visitor.visit_width(&lp.end);
+    for component in &lp.body {
+        visitor.visit_component(component);
+    }
+}
+
+/// Visits the indices of the port, then those of the wire.
+pub fn walk_mapping<V: Visitor + ?Sized>(visitor: &mut V, mapping: &PortMapping) {
+    for bus in [&mapping.port, &mapping.wire] {
+        for w in [&bus.start, &bus.end].into_iter().flatten() {
+            visitor.visit_width(w);
+        }
+    }
+}
+
+pub fn walk_width<V: Visitor + ?Sized>(visitor: &mut V, width: &GenericWidth) {
+    match width {
+        GenericWidth::Expr(_, a, b) => {
+            visitor.visit_width(a);
+            visitor.visit_width(b);
+        }
+        GenericWidth::Log2(a) => visitor.visit_width(a),
+        GenericWidth::If(c, a, b) => {
+            visitor.visit_width(&c.left);
+            visitor.visit_width(&c.right);
+            visitor.visit_width(a);
+            visitor.visit_width(b);
+        }
+        GenericWidth::Terminal(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::expr::Terminal;
+    use crate::scanner::Scanner;
+    use std::path::PathBuf;
+
+    fn parse(source: &str) -> ChipHDL {
+        let mut scanner = Scanner::new(source, PathBuf::from("Test.hdl"));
+        let mut parser = Parser {
+            scanner: &mut scanner,
+        };
+        parser.parse().expect("Parse error")
+    }
+
+    #[derive(Default)]
+    struct Collector {
+        chips: Vec<String>,
+        components: Vec<String>,
+        loops: usize,
+        mappings: usize,
+        variables: Vec<String>,
+    }
+
+    impl Visitor for Collector {
+        fn visit_chip(&mut self, chip: &ChipHDL) {
+            self.chips.push(chip.name.clone());
+            walk_chip_children(self, chip);
+        }
+
+        fn visit_component(&mut self, component: &Component) {
+            self.components.push(component.name.value.clone());
+            walk_component(self, component);
+        }
+
+        fn visit_loop(&mut self, lp: &Loop) {
+            self.loops += 1;
+            walk_loop(self, lp);
+        }
+
+        fn visit_mapping(&mut self, mapping: &PortMapping) {
+            self.mappings += 1;
+            walk_mapping(self, mapping);
+        }
+
+        fn visit_width(&mut self, width: &GenericWidth) {
+            if let GenericWidth::Terminal(Terminal::Var(v)) = width {
+                self.variables.push(v.value.clone());
+            }
+            walk_width(self, width);
+        }
+    }
+
+    #[test]
+    fn test_walk_chip() {
+        let hdl = parse(
+            "CHIP Wide<N> {
+                IN a[N], b[N];
+                OUT out[N];
+                PARTS:
+                Not(in=a[0], out=na);
+                FOR i IN 0 TO N - 1 GENERATE {
+                    And(a=a[i], b=b[i], out=out[i]);
+                }
+            }",
+        );
+        let mut collector = Collector::default();
+        walk_chip(&mut collector, &hdl);
+        assert_eq!(collector.chips, vec!["Wide"]);
+        assert_eq!(collector.components, vec!["Not", "And"]);
+        assert_eq!(collector.loops, 1);
+        assert_eq!(collector.mappings, 5);
+        // Three port widths, the loop end, then the loop body's indices.
+        assert_eq!(
+            collector.variables,
+            vec!["N", "N", "N", "N", "i", "i", "i", "i", "i", "i"]
+        );
+    }
+
+    #[test]
+    fn test_skip_loops() {
+        struct TopLevel(Vec<String>);
+        impl Visitor for TopLevel {
+            fn visit_component(&mut self, component: &Component) {
+                self.0.push(component.name.value.clone());
+            }
+
+            fn visit_loop(&mut self, _: &Loop) {}
+        }
+
+        let hdl = parse(
+            "CHIP Two {
+                IN a[2];
+                OUT out[2];
+                PARTS:
+                FOR i IN 0 TO 1 GENERATE {
+                    Not(in=a[i], out=out[i]);
+                }
+                Nand(a=a[0], b=a[1], out=x);
+            }",
+        );
+        let mut top = TopLevel(Vec::new());
+        walk_chip(&mut top, &hdl);
+        assert_eq!(top.0, vec!["Nand"]);
+    }
+}
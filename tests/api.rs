@@ -0,0 +1,61 @@
+// Uses the library the way another crate would, through its exports only.
+
+use std::fs;
+use std::sync::Arc;
+
+use whidl::{
+    check_directory, get_hdl, run_test, Chip, ChipOptions, FileReader, HdlProvider, Severity,
+    WhidlError,
+};
+
+const SOLUTIONS: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/resources/tests/nand2tetris/solutions"
+);
+
+#[test]
+fn test_chip() {
+    let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(SOLUTIONS));
+    let hdl = get_hdl("And", &provider).expect("Parse error");
+    let chip = Chip::new(&hdl, &provider, ChipOptions::default()).expect("Chip creation error");
+    assert_eq!(chip.name, "And");
+    run_test(&format!("{}/And.tst", SOLUTIONS)).expect("Test failure");
+}
+
+#[test]
+fn test_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("Broken.hdl"),
+        "CHIP Broken { IN a; OUT out; PARTS: Not(in=a out=out); }",
+    )
+    .unwrap();
+    fs::write(
+        dir.path().join("Unknown.hdl"),
+        "CHIP Unknown { IN a; OUT out; PARTS: Missing(in=a, out=out); }",
+    )
+    .unwrap();
+    let provider: Arc<dyn HdlProvider> = Arc::new(FileReader::new(dir.path().to_str().unwrap()));
+
+    match get_hdl("Broken", &provider) {
+        Err(WhidlError::Parse(e)) => assert!(!e.msg.is_empty()),
+        other => panic!("Expected a parse error, got {:?}", other.err()),
+    }
+    let hdl = get_hdl("Unknown", &provider).expect("Parse error");
+    assert!(Chip::new(&hdl, &provider, ChipOptions::default()).is_err());
+
+    let diagnostics = check_directory(dir.path());
+    let files: Vec<_> = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .filter_map(|d| d.file.as_ref()?.file_name())
+        .collect();
+    assert_eq!(files, ["Broken.hdl", "Unknown.hdl"]);
+    assert_eq!(diagnostics[0].code, "parse");
+}
+
+#[test]
+fn test_send_sync() {
+    fn send_sync<T: Send + Sync>() {}
+    send_sync::<WhidlError>();
+}